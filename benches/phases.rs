@@ -0,0 +1,136 @@
+// Benchmarks of the compiler's phases, each measured on its own:
+//
+//   $ cargo bench
+//   $ cargo bench --bench phases -- parse/       # one phase
+//   $ cargo bench --bench phases -- many_functions
+//
+// Each phase gets the output of the ones before it, prepared outside
+// the measurement. The inputs are generated so that they stress one
+// thing each: long expressions, many small functions, and deeply
+// nested statements and expressions.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use r9cc::internals::gen_ir::{gen_ir, CodegenContext};
+use r9cc::internals::opt::optimize;
+use r9cc::internals::parse::{parse, MAX_DEPTH};
+use r9cc::internals::preprocess::Preprocessor;
+use r9cc::internals::regalloc::alloc_regs;
+use r9cc::internals::sema::sema;
+use r9cc::internals::target::{self, Target};
+use r9cc::internals::timer::Timer;
+use r9cc::internals::token::{lex_str, tokenize_str};
+use r9cc::Std;
+
+use std::fmt::Write;
+
+// Long expressions of additions, multiplications and shifts. Each
+// operator nests the tree a level deeper, so they stay within the
+// parser's limit on nesting.
+fn big_expressions() -> String {
+    let mut s = String::from("int main() {\n  int x = 1;\n");
+    for _ in 0..20 {
+        s.push_str("  x = x");
+        for i in 0..200 {
+            let op = ["+", "*", "-", "<<", "^"][i % 5];
+            let _ = write!(s, " {} {}", op, i % 7 + 1);
+            if i % 10 == 9 {
+                s.push_str("\n     ");
+            }
+        }
+        s.push_str(";\n");
+    }
+    s.push_str("  return x;\n}\n");
+    s
+}
+
+// Many small functions with locals, loops and calls.
+fn many_functions() -> String {
+    let mut s = String::from("int f0(int a, int b) { return a + b; }\n");
+    for i in 1..500 {
+        let _ = writeln!(
+            s,
+            "int f{}(int a, int b) {{\n  int ary[4];\n  int sum = 0;\n  \
+             for (int j = 0; j < 4; j++)\n    ary[j] = f{}(a, j) * b;\n  \
+             for (int j = 0; j < 4; j++)\n    sum += ary[j];\n  \
+             if (sum > {}) return sum - a;\n  return \"str{}\"[0] + sum;\n}}",
+            i,
+            i - 1,
+            i * 3,
+            i
+        );
+    }
+    s.push_str("int main() { return f499(1, 2); }\n");
+    s
+}
+
+// Nested blocks, ifs and parenthesized expressions, within the
+// parser's limit on nesting. The parentheses nest to the left, which
+// needs no more registers the deeper it goes.
+fn deep_nesting() -> String {
+    let depth = 60;
+    let mut s = String::from("int main() {\n  int x = 0;\n");
+    for i in 0..depth {
+        let _ = writeln!(s, "if (x < {}) {{ int y{} = x;", i + 1, i);
+    }
+    s.push_str("x = ");
+    s.push_str(&"(".repeat(depth));
+    s.push('x');
+    s.push_str(&" + 1)".repeat(depth));
+    s.push_str(";\n");
+    s.push_str(&"}\n".repeat(depth));
+    s.push_str("  return x;\n}\n");
+    s
+}
+
+fn bench_phases(c: &mut Criterion) {
+    let target = target::lookup("x86_64-linux").unwrap();
+    let target: &dyn Target = &*target;
+    let inputs = [
+        ("big_expressions", big_expressions()),
+        ("many_functions", many_functions()),
+        ("deep_nesting", deep_nesting()),
+    ];
+
+    for (name, source) in &inputs {
+        c.bench_function(&format!("lex/{}", name), |b| {
+            b.iter(|| lex_str("bench.c".into(), source))
+        });
+
+        let tokenize = || {
+            let mut pp = Preprocessor::for_target(target);
+            tokenize_str("bench.c".into(), source, &mut pp)
+        };
+        c.bench_function(&format!("preprocess/{}", name), |b| b.iter(tokenize));
+
+        let tokens = tokenize();
+        c.bench_function(&format!("parse/{}", name), |b| {
+            b.iter(|| parse(&tokens, target, MAX_DEPTH, Std::C11).unwrap())
+        });
+
+        let nodes = parse(&tokens, target, MAX_DEPTH, Std::C11).unwrap();
+        c.bench_function(&format!("sema/{}", name), |b| {
+            b.iter_batched(|| nodes.clone(), |n| sema(n, false), BatchSize::LargeInput)
+        });
+
+        // IR generation, optimization at -O1, register allocation and
+        // assembly, on one thread.
+        let (nodes, globals) = sema(nodes, false);
+        c.bench_function(&format!("codegen/{}", name), |b| {
+            b.iter_batched(
+                || (nodes.clone(), globals.clone()),
+                |(nodes, mut globals)| {
+                    let mut ctx = CodegenContext::new();
+                    let mut fns = gen_ir(&mut ctx, nodes, false);
+                    globals.append(&mut ctx.globals);
+                    optimize(&mut fns, 1, 1, &mut Timer::new(false));
+                    alloc_regs(&mut fns, target, 1);
+                    target.gen(&mut ctx, globals, fns)
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+}
+
+criterion_group!(benches, bench_phases);
+criterion_main!(benches);
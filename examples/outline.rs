@@ -0,0 +1,73 @@
+// Lists what a C file defines, as an editor's outline view would, with
+// r9cc's frontend as a library (see src/frontend.rs):
+//
+//   $ cargo run --example outline -- test/test.c
+//   test/test.c:3: add(int x, int y)
+//   ...
+
+use r9cc::frontend::{self, NodeType, Phase};
+use r9cc::Options;
+
+use std::env;
+use std::fs;
+use std::panic;
+use std::process;
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: outline <file>");
+            process::exit(2);
+        }
+    };
+    let source = fs::read_to_string(&path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+    // Sema raises errors as panics, which Rust's own hook would print
+    // as it caught them.
+    panic::set_hook(Box::new(frontend::hook));
+    let opts = Options::default().filename(path.clone());
+    let outline = frontend::analyze(&source, &opts, Phase::Sema, |a| {
+        let mut lines = vec![];
+        // Sema makes string literals globals too, named .L.str0 and on.
+        for var in a.globals.iter().filter(|var| !var.name.starts_with('.')) {
+            lines.push(format!(
+                "{}: {}",
+                path,
+                frontend::declaration(&var.ty, &var.name)
+            ));
+        }
+        for f in a.functions() {
+            let line = f.span.map_or(0, |span| span.line);
+            let params: Vec<String> = f
+                .params
+                .iter()
+                .map(|p| match p.op {
+                    NodeType::Vardef(name, ..) => frontend::declaration(&p.ty, &name.as_str()),
+                    _ => frontend::declaration(&p.ty, ""),
+                })
+                .collect();
+            lines.push(format!(
+                "{}:{}: {}({})",
+                path,
+                line,
+                f.name,
+                params.join(", ")
+            ));
+        }
+        lines
+    });
+    match outline {
+        Ok(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
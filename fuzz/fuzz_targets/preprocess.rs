@@ -0,0 +1,20 @@
+// Runs only the preprocessor on arbitrary input, which gets through to
+// more of it than whole compilations do.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use r9cc::{preprocess, CompileError, Options};
+
+fuzz_target!(
+    init: {
+        std::panic::set_hook(Box::new(r9cc::internals::ice::hook));
+    },
+    |data: &[u8]| {
+        if let Ok(source) = std::str::from_utf8(data) {
+            if let Err(CompileError::Internal(ice)) = preprocess(source, &Options::default()) {
+                eprintln!("{}", ice);
+                std::process::abort();
+            }
+        }
+    }
+);
@@ -0,0 +1,45 @@
+// Prints whatever parses with --dump-ast and parses the output again,
+// which must give the same tree, and so the same output. A difference
+// is a precedence or associativity bug in the printer or the parser.
+//
+//   $ cargo fuzz run roundtrip -- -dict=fuzz/c.dict
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use r9cc::{dump_ast, CompileError, Options};
+
+fuzz_target!(
+    init: {
+        std::panic::set_hook(Box::new(r9cc::internals::ice::hook));
+    },
+    |data: &[u8]| {
+        let source = match std::str::from_utf8(data) {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let opts = Options::default();
+        let printed = match dump_ast(source, &opts) {
+            Ok(printed) => printed,
+            Err(CompileError::Internal(ice)) => {
+                eprintln!("{}", ice);
+                std::process::abort();
+            }
+            Err(_) => return,
+        };
+        match dump_ast(&printed, &opts) {
+            Ok(again) if again == printed => {}
+            // The printed program needn't nest as deep in the parser as
+            // the input did, so one at the limit may go over it.
+            Err(CompileError::Invalid(ref diags))
+                if diags.iter().any(|d| d.message == "too deeply nested") => {}
+            result => {
+                let reparsed = result.unwrap_or_else(|e| e.to_string());
+                eprintln!(
+                    "{}\n--- printed as ---\n{}\n--- which printed as ---\n{}",
+                    source, printed, reparsed
+                );
+                std::process::abort();
+            }
+        }
+    }
+);
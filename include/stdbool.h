@@ -0,0 +1,8 @@
+/* <stdbool.h>, bundled with r9cc. r9cc has no _Bool, so bool is an
+   int, which keeps what is stored in it instead of making it 0 or 1. */
+#pragma once
+
+#define bool int
+#define true 1
+#define false 0
+#define __bool_true_false_are_defined 1
@@ -0,0 +1,9 @@
+/* <stddef.h>, bundled with r9cc. */
+#pragma once
+
+typedef unsigned long size_t;
+typedef long ptrdiff_t;
+typedef int wchar_t;
+
+#define NULL ((void *)0)
+#define offsetof(type, member) __builtin_offsetof(type, member)
@@ -0,0 +1,55 @@
+// Runtime for -fsanitize=address. Like runtime/ubsan.c, `r9cc -o`
+// links this in automatically; with -S or -c, link it in yourself.
+//
+// r9cc keeps one shadow byte for every 8 bytes of memory, at
+// (addr >> 3) + SHADOW_OFFSET, and poisons the shadow of the redzones
+// around stack arrays. This file maps the shadow memory before main
+// runs and reports accesses that hit a redzone.
+
+#define _GNU_SOURCE
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <sys/mman.h>
+
+// Must match gen_ir::ASAN_SHADOW_OFFSET.
+#define SHADOW_OFFSET 0x7fff8000UL
+
+// Enough shadow for a 47-bit address space. Pages are only backed by
+// memory once the program writes to them.
+#define SHADOW_SIZE (1UL << 44)
+
+__attribute__((constructor)) static void init_shadow(void) {
+  void *p = mmap((void *)SHADOW_OFFSET, SHADOW_SIZE, PROT_READ | PROT_WRITE,
+                 MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE |
+                     MAP_FIXED_NOREPLACE,
+                 -1, 0);
+  if (p != (void *)SHADOW_OFFSET) {
+    fprintf(stderr, "AddressSanitizer: cannot map shadow memory\n");
+    abort();
+  }
+}
+
+static void report(const char *loc, uintptr_t addr, const char *kind,
+                   int size) {
+  int8_t shadow = *(int8_t *)((addr >> 3) + SHADOW_OFFSET);
+  // 0xf1 marks the redzone below an array, 0xf3 the one above it.
+  const char *bug = (uint8_t)shadow == 0xf1 ? "stack-buffer-underflow"
+                                             : "stack-buffer-overflow";
+  fprintf(stderr, "%s: AddressSanitizer: %s on address %#lx\n", loc, bug,
+          (unsigned long)addr);
+  fprintf(stderr, "%s of size %d\n", kind, size);
+  abort();
+}
+
+#define HANDLERS(size)                                                       \
+  void __r9cc_asan_report_load##size(const char *loc, uintptr_t addr) {     \
+    report(loc, addr, "READ", size);                                         \
+  }                                                                          \
+  void __r9cc_asan_report_store##size(const char *loc, uintptr_t addr) {    \
+    report(loc, addr, "WRITE", size);                                        \
+  }
+
+HANDLERS(1)
+HANDLERS(4)
+HANDLERS(8)
@@ -0,0 +1,54 @@
+// Runtime for --coverage. Like runtime/ubsan.c, `r9cc -o` links this
+// in automatically; with -c, link it in yourself.
+//
+// Each file compiled with --coverage has an array of counters, one
+// for every basic block, and a string with the "file:line" of each
+// block on a line of its own. The first function of the file to run
+// registers them here; counter 0 records that it has. When the program
+// exits, we write "file:line count" for every block to r9cc.cov, or to
+// $R9CC_COVERAGE_FILE, for `r9cc --coverage-report` to read.
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+struct unit {
+  long *counters;
+  const char *blocks;
+  struct unit *next;
+};
+
+static struct unit *units;
+
+static void dump(void) {
+  const char *path = getenv("R9CC_COVERAGE_FILE");
+  if (!path)
+    path = "r9cc.cov";
+  FILE *out = fopen(path, "w");
+  if (!out) {
+    perror(path);
+    return;
+  }
+  for (struct unit *u = units; u; u = u->next) {
+    long *count = u->counters + 1;
+    for (const char *p = u->blocks; *p; count++) {
+      const char *end = strchr(p, '\n');
+      fprintf(out, "%.*s %ld\n", (int)(end - p), p, *count);
+      p = end + 1;
+    }
+  }
+  fclose(out);
+}
+
+void __r9cc_cov_register(long *counters, const char *blocks) {
+  struct unit *u = malloc(sizeof(*u));
+  if (!u)
+    return;
+  if (!units)
+    atexit(dump);
+  counters[0] = 1;
+  u->counters = counters;
+  u->blocks = blocks;
+  u->next = units;
+  units = u;
+}
@@ -0,0 +1,64 @@
+// Runtime for -fsanitize. This file is compiled by the system C
+// compiler and linked with programs built with -fsanitize. `r9cc -o`
+// does that automatically; with -S or -c, link it in yourself.
+//
+// r9cc calls a handler when a check fails, passing the source location
+// as a "file:line" string and the operands. Each handler prints a
+// diagnostic and aborts.
+
+#include <stdio.h>
+#include <stdlib.h>
+
+static void report(const char *loc, const char *msg) {
+  fprintf(stderr, "%s: runtime error: %s\n", loc, msg);
+  abort();
+}
+
+static void overflow(const char *loc, long lhs, const char *op, long rhs) {
+  char buf[128];
+  snprintf(buf, sizeof(buf),
+           "signed integer overflow: %ld %s %ld cannot be represented in "
+           "type 'int'",
+           lhs, op, rhs);
+  report(loc, buf);
+}
+
+void __r9cc_ubsan_add_overflow(const char *loc, long lhs, long rhs) {
+  overflow(loc, lhs, "+", rhs);
+}
+
+void __r9cc_ubsan_sub_overflow(const char *loc, long lhs, long rhs) {
+  overflow(loc, lhs, "-", rhs);
+}
+
+void __r9cc_ubsan_mul_overflow(const char *loc, long lhs, long rhs) {
+  overflow(loc, lhs, "*", rhs);
+}
+
+void __r9cc_ubsan_negate_overflow(const char *loc, long val) {
+  char buf[128];
+  snprintf(buf, sizeof(buf),
+           "negation of %ld cannot be represented in type 'int'", val);
+  report(loc, buf);
+}
+
+void __r9cc_ubsan_divrem_overflow(const char *loc, long lhs, long rhs) {
+  char buf[128];
+  if (rhs == 0)
+    snprintf(buf, sizeof(buf), "division by zero");
+  else
+    snprintf(buf, sizeof(buf),
+             "division of %ld by %ld cannot be represented in type 'int'",
+             lhs, rhs);
+  report(loc, buf);
+}
+
+void __r9cc_ubsan_shift_out_of_bounds(const char *loc, long lhs, long rhs) {
+  char buf[128];
+  if (rhs < 0)
+    snprintf(buf, sizeof(buf), "shift exponent %ld is negative", rhs);
+  else
+    snprintf(buf, sizeof(buf),
+             "shift exponent %ld is too large for 32-bit type 'int'", rhs);
+  report(loc, buf);
+}
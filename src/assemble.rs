@@ -0,0 +1,1034 @@
+// Built-in x86-64 assembler.
+//
+// This pass turns the Intel-syntax assembly produced by gen_x86 into
+// machine code, so that `-c` can write an object file without running
+// an external assembler. It only understands the instructions and
+// directives our code generator emits, not the whole of GNU as.
+//
+// All branches and calls are encoded with 32-bit displacements. That
+// wastes a few bytes compared to a real assembler, but it means that
+// every instruction has a known size on the first pass, and jumps to
+// labels can simply be patched once the whole input has been read.
+
+use crate::elf::{Object, Reloc, RelocTarget, RelocType, Section, Symbol, SymbolType};
+
+use std::collections::{HashMap, HashSet};
+
+pub fn assemble(src: &str) -> Object {
+    let mut asm = Assembler::new();
+    for line in src.lines() {
+        asm.line(line);
+    }
+    asm.finish()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Register {
+    num: u8,
+    size: u8,
+}
+
+#[derive(Debug, Clone)]
+struct Memory {
+    base: Option<Register>,
+    index: Option<(Register, u8)>, // (index, scale)
+    disp: i64,
+    sym: Option<String>,
+    size: Option<u8>, // From a `BYTE PTR`-style prefix
+    fs: bool,         // `fs:` segment override, for thread-local data
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Reg(Register),
+    Imm(i64),
+    Mem(Memory),
+    Sym(String),
+}
+
+const REGS64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const REGS32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const REGS16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w",
+    "r14w", "r15w",
+];
+const REGS8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+
+// Used to spill variadic arguments and to compute with floating-point
+// values.
+const XMMREGS: [&str; 16] = [
+    "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7", "xmm8", "xmm9", "xmm10",
+    "xmm11", "xmm12", "xmm13", "xmm14", "xmm15",
+];
+
+fn parse_reg(s: &str) -> Option<Register> {
+    for (size, names) in &[
+        (8, REGS64),
+        (4, REGS32),
+        (2, REGS16),
+        (1, REGS8),
+        (16, XMMREGS),
+    ] {
+        if let Some(num) = names.iter().position(|r| *r == s) {
+            return Some(Register {
+                num: num as u8,
+                size: *size,
+            });
+        }
+    }
+    None
+}
+
+fn parse_int(s: &str) -> Option<i64> {
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    // -9223372036854775808 is in range, though its magnitude isn't.
+    let val = if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()?
+    } else if s.chars().all(|c| c.is_ascii_digit()) && !s.is_empty() {
+        s.parse::<u64>().ok()?
+    } else {
+        return None;
+    } as i64;
+    Some(if neg { val.wrapping_neg() } else { val })
+}
+
+fn parse_mem(s: &str, size: Option<u8>, fs: bool) -> Memory {
+    let inner = &s[1..s.len() - 1];
+    let mut mem = Memory {
+        base: None,
+        index: None,
+        disp: 0,
+        sym: None,
+        size,
+        fs,
+    };
+
+    // Split "a+b-c" into signed terms.
+    let mut terms = vec![];
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        if (c == '+' || c == '-') && i != 0 {
+            terms.push(&inner[start..i]);
+            start = i;
+        }
+    }
+    terms.push(&inner[start..]);
+
+    for term in terms {
+        let term = term.trim();
+        let (neg, body) = match term.strip_prefix('-') {
+            Some(rest) => (true, rest.trim()),
+            None => (false, term.trim_start_matches('+').trim()),
+        };
+        if let Some(val) = parse_int(body) {
+            mem.disp += if neg { -val } else { val };
+        } else if let Some(star) = body.find('*') {
+            let reg = parse_reg(&body[..star]).expect("bad index register");
+            let scale = parse_int(&body[star + 1..]).expect("bad scale") as u8;
+            mem.index = Some((reg, scale));
+        } else if let Some(reg) = parse_reg(body) {
+            if mem.base.is_none() {
+                mem.base = Some(reg);
+            } else {
+                mem.index = Some((reg, 1));
+            }
+        } else if body == "rip" {
+            // `[rip+sym]` is the same as a plain symbol reference.
+        } else {
+            mem.sym = Some(body.to_string());
+        }
+    }
+    mem
+}
+
+fn parse_operand(s: &str) -> Operand {
+    let s = s.trim();
+    let (size, rest) = match s.find(" PTR ") {
+        Some(i) => {
+            let size = match &s[..i] {
+                "BYTE" => 1,
+                "WORD" => 2,
+                "DWORD" => 4,
+                "QWORD" => 8,
+                _ => panic!("bad operand size: {}", s),
+            };
+            (Some(size), s[i + 5..].trim())
+        }
+        None => (None, s),
+    };
+
+    let (fs, rest) = match rest.strip_prefix("fs:") {
+        Some(rest) => (true, rest.trim()),
+        None => (false, rest),
+    };
+    if rest.starts_with('[') && rest.ends_with(']') {
+        return Operand::Mem(parse_mem(rest, size, fs));
+    }
+    if let Some(reg) = parse_reg(rest) {
+        return Operand::Reg(reg);
+    }
+    if let Some(val) = parse_int(rest) {
+        return Operand::Imm(val);
+    }
+    Operand::Sym(rest.to_string())
+}
+
+// Splits operands at top-level commas.
+fn split_operands(s: &str) -> Vec<&str> {
+    let mut v = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                v.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    if !s[start..].trim().is_empty() {
+        v.push(s[start..].trim());
+    }
+    v
+}
+
+// Removes a trailing `#` comment, leaving string literals alone.
+// Splits "a, b" into ("a", "b").
+fn split_pair(args: &str) -> (&str, &str) {
+    match args.find(',') {
+        Some(i) => (args[..i].trim(), args[i + 1..].trim()),
+        None => panic!("expected two operands: {}", args),
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_str = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if in_str {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_str = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => in_str = true,
+            '#' => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn parse_string(s: &str) -> Vec<u8> {
+    let s = s.trim();
+    if !s.starts_with('"') || !s.ends_with('"') || s.len() < 2 {
+        panic!("string literal expected: {}", s);
+    }
+    let chars: Vec<char> = s[1..s.len() - 1].chars().collect();
+    let mut v = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        if c != '\\' {
+            let mut buf = [0; 4];
+            v.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let c = chars[i];
+        i += 1;
+        match c {
+            'n' => v.push(b'\n'),
+            'r' => v.push(b'\r'),
+            't' => v.push(b'\t'),
+            'b' => v.push(8),
+            'f' => v.push(12),
+            '0'..='7' => {
+                let mut val = c.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.get(i).and_then(|c| c.to_digit(8)) {
+                        Some(d) => {
+                            val = val * 8 + d;
+                            i += 1;
+                        }
+                        None => break,
+                    }
+                }
+                v.push(val as u8);
+            }
+            c => v.push(c as u8),
+        }
+    }
+    v
+}
+
+fn cond_code(cc: &str) -> Option<u8> {
+    let code = match cc {
+        "o" => 0,
+        "no" => 1,
+        "b" | "c" | "nae" => 2,
+        "ae" | "nb" | "nc" => 3,
+        "e" | "z" => 4,
+        "ne" | "nz" => 5,
+        "be" | "na" => 6,
+        "a" | "nbe" => 7,
+        "s" => 8,
+        "ns" => 9,
+        "p" => 10,
+        "np" => 11,
+        "l" | "nge" => 12,
+        "ge" | "nl" => 13,
+        "le" | "ng" => 14,
+        "g" | "nle" => 15,
+        _ => return None,
+    };
+    Some(code)
+}
+
+fn fits_i8(x: i64) -> bool {
+    x >= i8::MIN as i64 && x <= i8::MAX as i64
+}
+
+fn fits_i32(x: i64) -> bool {
+    x >= i32::MIN as i64 && x <= i32::MAX as i64
+}
+
+// A single encoded instruction, possibly with one symbolic field.
+#[derive(Default)]
+struct Inst {
+    bytes: Vec<u8>,
+    fixup: Option<(usize, String, RelocType, i64)>, // (pos, sym, type, addend)
+}
+
+impl Inst {
+    fn byte(&mut self, b: u8) {
+        self.bytes.push(b);
+    }
+
+    fn imm(&mut self, val: i64, size: u8) {
+        let bytes = val.to_le_bytes();
+        self.bytes.extend_from_slice(&bytes[..size as usize]);
+    }
+
+    fn rel32(&mut self, sym: &str, ty: RelocType) {
+        self.fixup = Some((self.bytes.len(), sym.to_string(), ty, 0));
+        self.imm(0, 4);
+    }
+
+    // Emits optional 0x66/REX prefixes, the opcode and a ModRM (plus
+    // SIB/displacement) that encodes `rm` with `reg` in the reg field.
+    fn modrm(&mut self, opcode: &[u8], size: u8, reg: u8, reg_is_byte: bool, rm: &Operand) {
+        if let Operand::Mem(Memory { fs: true, .. }) = rm {
+            self.byte(0x64);
+        }
+        if size == 2 {
+            self.byte(0x66);
+        }
+
+        let mut rex = 0x40;
+        if size == 8 {
+            rex |= 0x08;
+        }
+        if reg >= 8 {
+            rex |= 0x04;
+        }
+        // spl/bpl/sil/dil are only reachable with a REX prefix.
+        let mut force = reg_is_byte && (4..8).contains(&reg);
+        match rm {
+            Operand::Reg(r) => {
+                if r.num >= 8 {
+                    rex |= 0x01;
+                }
+                if r.size == 1 && (4..8).contains(&r.num) {
+                    force = true;
+                }
+            }
+            Operand::Mem(m) => {
+                if m.base.map(|b| b.num >= 8) == Some(true) {
+                    rex |= 0x01;
+                }
+                if let Some((idx, _)) = m.index {
+                    if idx.num >= 8 {
+                        rex |= 0x02;
+                    }
+                }
+            }
+            _ => panic!("bad r/m operand: {:?}", rm),
+        }
+        if rex != 0x40 || force {
+            self.byte(rex);
+        }
+        self.bytes.extend_from_slice(opcode);
+
+        let reg = (reg & 7) << 3;
+        let m = match rm {
+            Operand::Reg(r) => {
+                self.byte(0xc0 | reg | (r.num & 7));
+                return;
+            }
+            Operand::Mem(m) => m,
+            _ => unreachable!(),
+        };
+
+        let base = match m.base {
+            Some(base) => base,
+            None => {
+                if let Some((idx, scale)) = m.index {
+                    // [index*scale + disp32], absolute address.
+                    self.byte(reg | 4);
+                    self.byte(Self::scale_bits(scale) | (idx.num & 7) << 3 | 5);
+                    match m.sym {
+                        Some(ref sym) => {
+                            self.fixup =
+                                Some((self.bytes.len(), sym.clone(), RelocType::Abs32S, m.disp));
+                            self.imm(0, 4);
+                        }
+                        None => self.imm(m.disp, 4),
+                    }
+                    return;
+                }
+                if m.sym.is_none() {
+                    // [disp32], absolute address. Only useful with a
+                    // segment override.
+                    self.byte(reg | 4);
+                    self.byte(0x25);
+                    self.imm(m.disp, 4);
+                    return;
+                }
+                // RIP-relative reference to a symbol.
+                self.byte(reg | 5);
+                let sym = m.sym.clone().expect("memory operand without base");
+                let (sym, ty) = match sym.strip_suffix("@gottpoff") {
+                    Some(sym) => (sym.to_string(), RelocType::GotTpOff),
+                    None => (sym, RelocType::Pc32),
+                };
+                self.fixup = Some((self.bytes.len(), sym, ty, m.disp));
+                self.imm(0, 4);
+                return;
+            }
+        };
+
+        let md = if m.disp == 0 && base.num & 7 != 5 {
+            0x00
+        } else if fits_i8(m.disp) {
+            0x40
+        } else {
+            0x80
+        };
+        if let Some((idx, scale)) = m.index {
+            self.byte(md | reg | 4);
+            self.byte(Self::scale_bits(scale) | (idx.num & 7) << 3 | (base.num & 7));
+        } else if base.num & 7 == 4 {
+            self.byte(md | reg | 4);
+            self.byte(0x24);
+        } else {
+            self.byte(md | reg | (base.num & 7));
+        }
+        match md {
+            0x40 => self.imm(m.disp, 1),
+            0x80 => self.imm(m.disp, 4),
+            _ => (),
+        }
+    }
+
+    // SSE instructions have a mandatory prefix, which goes before REX.
+    fn sse(&mut self, prefix: u8, opcode: u8, size: u8, reg: u8, rm: &Operand) {
+        self.byte(prefix);
+        self.modrm(&[0x0f, opcode], size, reg, false, rm);
+    }
+
+    fn scale_bits(scale: u8) -> u8 {
+        match scale {
+            1 => 0x00,
+            2 => 0x40,
+            4 => 0x80,
+            8 => 0xc0,
+            _ => panic!("bad scale: {}", scale),
+        }
+    }
+}
+
+fn operand_size(op: &Operand) -> Option<u8> {
+    match op {
+        Operand::Reg(r) => Some(r.size),
+        Operand::Mem(m) => m.size,
+        _ => None,
+    }
+}
+
+// Encodes one instruction. Panics on anything gen_x86 never produces.
+fn encode(mnemonic: &str, ops: &[Operand]) -> Inst {
+    use self::Operand::*;
+    let mut inst = Inst::default();
+    let bad = || -> ! { panic!("unsupported instruction: {} {:?}", mnemonic, ops) };
+
+    // add, or, and, sub, xor, cmp share one encoding scheme.
+    let alu = match mnemonic {
+        "add" => Some(0),
+        "or" => Some(1),
+        "and" => Some(4),
+        "sub" => Some(5),
+        "xor" => Some(6),
+        "cmp" => Some(7),
+        _ => None,
+    };
+    if let Some(ext) = alu {
+        match (&ops[0], &ops[1]) {
+            (dst, Reg(src)) => {
+                let op = if src.size == 1 { 0x00 } else { 0x01 };
+                inst.modrm(&[ext << 3 | op], src.size, src.num, src.size == 1, dst);
+            }
+            (Reg(dst), src @ Mem(_)) => {
+                let op = if dst.size == 1 { 0x02 } else { 0x03 };
+                inst.modrm(&[ext << 3 | op], dst.size, dst.num, dst.size == 1, src);
+            }
+            (dst, Imm(val)) => {
+                let size = operand_size(dst).unwrap_or_else(|| bad());
+                if size == 1 {
+                    inst.modrm(&[0x80], size, ext, false, dst);
+                    inst.imm(*val, 1);
+                } else if fits_i8(*val) {
+                    inst.modrm(&[0x83], size, ext, false, dst);
+                    inst.imm(*val, 1);
+                } else {
+                    inst.modrm(&[0x81], size, ext, false, dst);
+                    inst.imm(*val, size.min(4));
+                }
+            }
+            _ => bad(),
+        }
+        return inst;
+    }
+
+    // Shifts: shl, shr, sar by cl or by an immediate.
+    let shift = match mnemonic {
+        "shl" | "sal" => Some(4),
+        "shr" => Some(5),
+        "sar" => Some(7),
+        _ => None,
+    };
+    if let Some(ext) = shift {
+        let size = operand_size(&ops[0]).unwrap_or_else(|| bad());
+        let byte = size == 1;
+        match ops[1] {
+            Reg(Register { num: 1, size: 1 }) => {
+                inst.modrm(&[if byte { 0xd2 } else { 0xd3 }], size, ext, false, &ops[0])
+            }
+            Imm(val) => {
+                inst.modrm(&[if byte { 0xc0 } else { 0xc1 }], size, ext, false, &ops[0]);
+                inst.imm(val, 1);
+            }
+            _ => bad(),
+        }
+        return inst;
+    }
+
+    // Unary group 3: not, neg, mul, imul, div, idiv.
+    let unary = match mnemonic {
+        "not" => Some(2),
+        "neg" => Some(3),
+        "mul" => Some(4),
+        "div" => Some(6),
+        "idiv" => Some(7),
+        "imul" if ops.len() == 1 => Some(5),
+        _ => None,
+    };
+    if let Some(ext) = unary {
+        let size = operand_size(&ops[0]).unwrap_or_else(|| bad());
+        let op = if size == 1 { 0xf6 } else { 0xf7 };
+        inst.modrm(&[op], size, ext, false, &ops[0]);
+        return inst;
+    }
+
+    // Scalar SSE instructions taking an xmm register or a general-purpose
+    // one, whose size decides REX.W: the prefix and the opcode.
+    let sse = match mnemonic {
+        "addsd" => Some((0xf2, 0x58)),
+        "mulsd" => Some((0xf2, 0x59)),
+        "subsd" => Some((0xf2, 0x5c)),
+        "divsd" => Some((0xf2, 0x5e)),
+        "ucomisd" => Some((0x66, 0x2e)),
+        "cvtsi2sd" => Some((0xf2, 0x2a)),
+        "cvttsd2si" => Some((0xf2, 0x2c)),
+        "cvtss2sd" => Some((0xf3, 0x5a)),
+        "cvtsd2ss" => Some((0xf2, 0x5a)),
+        _ => None,
+    };
+    if let Some((prefix, op)) = sse {
+        match (&ops[0], &ops[1]) {
+            (Reg(dst), src) if dst.size == 16 => {
+                let size = operand_size(src).unwrap_or_else(|| bad());
+                inst.sse(prefix, op, size, dst.num, src);
+            }
+            (Reg(dst), src @ Reg(_)) => inst.sse(prefix, op, dst.size, dst.num, src),
+            _ => bad(),
+        }
+        return inst;
+    }
+
+    if let Some(cc) = mnemonic.strip_prefix("set").and_then(cond_code) {
+        inst.modrm(&[0x0f, 0x90 | cc], 1, 0, false, &ops[0]);
+        return inst;
+    }
+
+    if mnemonic != "jmp" {
+        if let Some(cc) = mnemonic.strip_prefix('j').and_then(cond_code) {
+            match ops[0] {
+                Sym(ref sym) => {
+                    inst.byte(0x0f);
+                    inst.byte(0x80 | cc);
+                    inst.rel32(sym, RelocType::Pc32);
+                }
+                _ => bad(),
+            }
+            return inst;
+        }
+    }
+
+    match (mnemonic, ops) {
+        ("ret", []) => inst.byte(0xc3),
+        ("cqo", []) => {
+            inst.byte(0x48);
+            inst.byte(0x99);
+        }
+        ("cdq", []) => inst.byte(0x99),
+        ("nop", []) => inst.byte(0x90),
+        ("int3", []) => inst.byte(0xcc),
+        ("syscall", []) => {
+            inst.byte(0x0f);
+            inst.byte(0x05);
+        }
+        ("ud2", []) => {
+            inst.byte(0x0f);
+            inst.byte(0x0b);
+        }
+        ("push", [Reg(r)]) | ("pop", [Reg(r)]) => {
+            if r.num >= 8 {
+                inst.byte(0x41);
+            }
+            let base = if mnemonic == "push" { 0x50 } else { 0x58 };
+            inst.byte(base | (r.num & 7));
+        }
+        ("call", [Sym(sym)]) => {
+            inst.byte(0xe8);
+            inst.rel32(sym, RelocType::Plt32);
+        }
+        ("jmp", [Sym(sym)]) => {
+            inst.byte(0xe9);
+            inst.rel32(sym, RelocType::Pc32);
+        }
+        ("call", [rm @ Reg(_)]) | ("call", [rm @ Mem(_)]) => inst.modrm(&[0xff], 4, 2, false, rm),
+        ("jmp", [rm @ Reg(_)]) | ("jmp", [rm @ Mem(_)]) => inst.modrm(&[0xff], 4, 4, false, rm),
+        ("lea", [Reg(dst), Sym(sym)]) => {
+            let mem = Memory {
+                base: None,
+                index: None,
+                disp: 0,
+                sym: Some(sym.clone()),
+                size: None,
+                fs: false,
+            };
+            inst.modrm(&[0x8d], dst.size, dst.num, false, &Mem(mem));
+        }
+        ("lea", [Reg(dst), src @ Mem(_)]) => inst.modrm(&[0x8d], dst.size, dst.num, false, src),
+        ("xchg", [dst, Reg(src)]) => {
+            let op = if src.size == 1 { 0x86 } else { 0x87 };
+            inst.modrm(&[op], src.size, src.num, src.size == 1, dst);
+        }
+        ("xadd", [dst, Reg(src)]) => {
+            let op = if src.size == 1 { 0xc0 } else { 0xc1 };
+            inst.modrm(&[0x0f, op], src.size, src.num, src.size == 1, dst);
+        }
+        ("mov", [dst, Reg(src)]) => {
+            let op = if src.size == 1 { 0x88 } else { 0x89 };
+            inst.modrm(&[op], src.size, src.num, src.size == 1, dst);
+        }
+        ("mov", [Reg(dst), src @ Mem(_)]) => {
+            let op = if dst.size == 1 { 0x8a } else { 0x8b };
+            inst.modrm(&[op], dst.size, dst.num, dst.size == 1, src);
+        }
+        ("mov", [Reg(dst), Imm(val)]) if dst.size == 8 && !fits_i32(*val) => {
+            inst.byte(if dst.num >= 8 { 0x49 } else { 0x48 });
+            inst.byte(0xb8 | (dst.num & 7));
+            inst.imm(*val, 8);
+        }
+        ("mov", [dst, Imm(val)]) => {
+            let size = operand_size(dst).unwrap_or_else(|| bad());
+            let op = if size == 1 { 0xc6 } else { 0xc7 };
+            inst.modrm(&[op], size, 0, false, dst);
+            inst.imm(*val, size.min(4));
+        }
+        ("movabs", [Reg(dst), Imm(val)]) => {
+            inst.byte(if dst.num >= 8 { 0x49 } else { 0x48 });
+            inst.byte(0xb8 | (dst.num & 7));
+            inst.imm(*val, 8);
+        }
+        ("movzb", [Reg(dst), src]) | ("movzx", [Reg(dst), src]) if operand_size(src) != Some(2) => {
+            inst.modrm(&[0x0f, 0xb6], dst.size, dst.num, false, src)
+        }
+        ("movzw", [Reg(dst), src]) | ("movzx", [Reg(dst), src]) => {
+            inst.modrm(&[0x0f, 0xb7], dst.size, dst.num, false, src)
+        }
+        ("movsb", [Reg(dst), src]) | ("movsx", [Reg(dst), src]) if operand_size(src) != Some(2) => {
+            inst.modrm(&[0x0f, 0xbe], dst.size, dst.num, false, src)
+        }
+        ("movsw", [Reg(dst), src]) | ("movsx", [Reg(dst), src]) => {
+            inst.modrm(&[0x0f, 0xbf], dst.size, dst.num, false, src)
+        }
+        ("movsxd", [Reg(dst), src]) => inst.modrm(&[0x63], 8, dst.num, false, src),
+        ("imul", [Reg(dst), src]) => inst.modrm(&[0x0f, 0xaf], dst.size, dst.num, false, src),
+        ("movaps", [dst @ Mem(_), Reg(src)]) if src.size == 16 => {
+            inst.modrm(&[0x0f, 0x29], src.size, src.num, false, dst)
+        }
+        // Between an xmm register and a general-purpose one.
+        ("movq", [Reg(dst), src @ Reg(r)]) | ("movd", [Reg(dst), src @ Reg(r)])
+            if dst.size == 16 =>
+        {
+            inst.sse(0x66, 0x6e, r.size, dst.num, src)
+        }
+        ("movq", [dst @ Reg(r), Reg(src)]) | ("movd", [dst @ Reg(r), Reg(src)])
+            if src.size == 16 =>
+        {
+            inst.sse(0x66, 0x7e, r.size, src.num, dst)
+        }
+        ("test", [dst, Reg(src)]) => {
+            let op = if src.size == 1 { 0x84 } else { 0x85 };
+            inst.modrm(&[op], src.size, src.num, src.size == 1, dst);
+        }
+        _ => bad(),
+    }
+    inst
+}
+
+struct Fixup {
+    section: usize,
+    offset: usize, // Offset of the 4- or 8-byte field
+    sym: String,
+    ty: RelocType,
+    addend: i64,
+}
+
+struct Assembler {
+    obj: Object,
+    cur: usize,
+    labels: HashMap<String, (usize, usize)>, // name -> (section, offset)
+    globals: HashSet<String>,
+    types: HashMap<String, SymbolType>,
+    sizes: HashMap<String, usize>,
+    fixups: Vec<Fixup>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        let mut asm = Assembler {
+            obj: Object::default(),
+            cur: 0,
+            labels: HashMap::new(),
+            globals: HashSet::new(),
+            types: HashMap::new(),
+            sizes: HashMap::new(),
+            fixups: vec![],
+        };
+        asm.switch_section(".text");
+        asm
+    }
+
+    fn switch_section(&mut self, name: &str) {
+        if let Some(i) = self.obj.sections.iter().position(|s| s.name == name) {
+            self.cur = i;
+            return;
+        }
+        self.obj.sections.push(Section::new(name));
+        self.cur = self.obj.sections.len() - 1;
+    }
+
+    fn data(&mut self) -> &mut Vec<u8> {
+        &mut self.obj.sections[self.cur].data
+    }
+
+    fn line(&mut self, line: &str) {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            return;
+        }
+
+        if line.ends_with(':') && !line.contains(' ') {
+            let name = &line[..line.len() - 1];
+            let pos = (self.cur, self.data().len());
+            if self.labels.insert(name.to_string(), pos).is_some() {
+                panic!("symbol redefined: {}", name);
+            }
+            return;
+        }
+
+        let (head, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+
+        if head.starts_with('.') {
+            self.directive(head, rest);
+            return;
+        }
+
+        // `lock` is a prefix of the instruction that follows it.
+        let (lock, head, rest) = match head {
+            "lock" => match rest.find(char::is_whitespace) {
+                Some(i) => (true, &rest[..i], rest[i..].trim()),
+                None => (true, rest, ""),
+            },
+            _ => (false, head, rest),
+        };
+
+        let ops: Vec<Operand> = split_operands(rest)
+            .into_iter()
+            .map(parse_operand)
+            .collect();
+        let mut inst = encode(head, &ops);
+        if lock {
+            inst.bytes.insert(0, 0xf0);
+            if let Some((ref mut pos, ..)) = inst.fixup {
+                *pos += 1;
+            }
+        }
+        let start = self.data().len();
+        if let Some((pos, sym, ty, addend)) = inst.fixup {
+            // PC-relative fields are relative to the end of the instruction,
+            // which may lie past the field itself when an immediate follows.
+            let addend = if ty.is_pc_relative() {
+                addend - (inst.bytes.len() - pos) as i64
+            } else {
+                addend
+            };
+            self.fixups.push(Fixup {
+                section: self.cur,
+                offset: start + pos,
+                sym,
+                ty,
+                addend,
+            });
+        }
+        self.data().extend_from_slice(&inst.bytes);
+    }
+
+    fn directive(&mut self, name: &str, args: &str) {
+        match name {
+            ".intel_syntax" => (),
+            // We don't write debug sections, so -g has no effect with -c.
+            ".file" | ".loc" => (),
+            ".text" | ".data" | ".bss" => self.switch_section(name),
+            ".section" => {
+                let sec = args.split(',').next().unwrap().trim();
+                self.switch_section(sec);
+            }
+            ".global" | ".globl" => {
+                for sym in args.split(',') {
+                    self.globals.insert(sym.trim().to_string());
+                }
+            }
+            ".local" => {
+                for sym in args.split(',') {
+                    self.globals.remove(sym.trim());
+                }
+            }
+            ".type" => {
+                let (sym, ty) = split_pair(args);
+                let ty = match ty {
+                    "@function" => SymbolType::Func,
+                    "@object" => SymbolType::Object,
+                    "@tls_object" => SymbolType::Tls,
+                    _ => panic!("unsupported symbol type: {}", ty),
+                };
+                self.types.insert(sym.to_string(), ty);
+            }
+            ".size" => {
+                let (sym, expr) = split_pair(args);
+                let size = self.size_expr(expr);
+                self.sizes.insert(sym.to_string(), size);
+            }
+            ".ascii" => {
+                let bytes = parse_string(args);
+                self.data().extend_from_slice(&bytes);
+            }
+            ".asciz" | ".string" => {
+                let bytes = parse_string(args);
+                self.data().extend_from_slice(&bytes);
+                self.data().push(0);
+            }
+            ".zero" => {
+                let n = parse_int(args).expect("bad .zero") as usize;
+                let len = self.data().len();
+                self.data().resize(len + n, 0);
+            }
+            ".byte" | ".short" | ".value" | ".long" | ".quad" => {
+                let size = match name {
+                    ".byte" => 1,
+                    ".short" | ".value" => 2,
+                    ".long" => 4,
+                    _ => 8,
+                };
+                for arg in args.split(',') {
+                    self.data_value(arg.trim(), size);
+                }
+            }
+            ".align" | ".p2align" | ".balign" => {
+                let n = parse_int(args.split(',').next().unwrap().trim()).expect("bad .align");
+                let align = if name == ".p2align" { 1 << n } else { n } as usize;
+                let sec = &mut self.obj.sections[self.cur];
+                sec.align = sec.align.max(align);
+                let fill = if sec.is_exec() { 0x90 } else { 0 };
+                while !sec.data.len().is_multiple_of(align) {
+                    sec.data.push(fill);
+                }
+            }
+            _ => panic!("unsupported directive: {} {}", name, args),
+        }
+    }
+
+    // Evaluates the second operand of `.size`, which is either a number
+    // or `.-sym` for everything emitted since the label `sym`.
+    fn size_expr(&mut self, expr: &str) -> usize {
+        if let Some(n) = parse_int(expr) {
+            return n as usize;
+        }
+        let sym = match expr.strip_prefix('.') {
+            Some(rest) => rest.trim_start().strip_prefix('-').map(str::trim),
+            None => None,
+        };
+        match sym.and_then(|sym| self.labels.get(sym)) {
+            Some(&(sec, off)) if sec == self.cur => self.data().len() - off,
+            _ => panic!("unsupported .size expression: {}", expr),
+        }
+    }
+
+    fn data_value(&mut self, arg: &str, size: usize) {
+        if let Some(val) = parse_int(arg) {
+            let bytes = val.to_le_bytes();
+            self.data().extend_from_slice(&bytes[..size]);
+            return;
+        }
+
+        // `sym`, `sym+N` or `sym-N`
+        let (sym, addend) = match arg.rfind(['+', '-']) {
+            Some(i) if i > 0 => (
+                arg[..i].trim(),
+                parse_int(&arg[i..].replace('+', "")).expect("bad addend"),
+            ),
+            _ => (arg, 0),
+        };
+        let ty = match size {
+            8 => RelocType::Abs64,
+            4 => RelocType::Abs32S,
+            _ => panic!("bad data relocation: {}", arg),
+        };
+        let offset = self.data().len();
+        self.fixups.push(Fixup {
+            section: self.cur,
+            offset,
+            sym: sym.to_string(),
+            ty,
+            addend,
+        });
+        let len = self.data().len();
+        self.data().resize(len + size, 0);
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        !self.globals.contains(name)
+    }
+
+    fn finish(mut self) -> Object {
+        for fix in std::mem::take(&mut self.fixups) {
+            let target = self.labels.get(&fix.sym).cloned();
+
+            // Branches within a section to a local label need no relocation.
+            if let Some((sec, off)) = target {
+                if fix.ty.is_pc_relative() && sec == fix.section && self.is_local(&fix.sym) {
+                    let val = off as i64 + fix.addend - fix.offset as i64;
+                    let data = &mut self.obj.sections[sec].data;
+                    data[fix.offset..fix.offset + 4].copy_from_slice(&(val as i32).to_le_bytes());
+                    continue;
+                }
+            }
+
+            // The linker needs the symbol itself to set up a GOT entry.
+            let reloc = match target {
+                Some((sec, off)) if self.is_local(&fix.sym) && fix.ty != RelocType::GotTpOff => {
+                    Reloc {
+                        offset: fix.offset,
+                        target: RelocTarget::Section(sec),
+                        ty: if fix.ty == RelocType::Plt32 {
+                            RelocType::Pc32
+                        } else {
+                            fix.ty
+                        },
+                        addend: fix.addend + off as i64,
+                    }
+                }
+                _ => Reloc {
+                    offset: fix.offset,
+                    target: RelocTarget::Symbol(fix.sym.clone()),
+                    ty: fix.ty,
+                    addend: fix.addend,
+                },
+            };
+            if let RelocTarget::Symbol(ref name) = reloc.target {
+                // Anything referenced but not defined is an external symbol.
+                if target.is_none() {
+                    self.globals.insert(name.clone());
+                    // The linker checks that TLS references agree with
+                    // the definition.
+                    if fix.ty == RelocType::GotTpOff {
+                        self.types.insert(name.clone(), SymbolType::Tls);
+                    }
+                }
+            }
+            self.obj.sections[fix.section].relocs.push(reloc);
+        }
+
+        let mut names: Vec<&String> = self.labels.keys().chain(self.globals.iter()).collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            if name.starts_with(".L") && !self.globals.contains(name) {
+                continue;
+            }
+            let (section, value) = match self.labels.get(name) {
+                Some((sec, off)) => (Some(*sec), *off),
+                None => (None, 0),
+            };
+            self.obj.symbols.push(Symbol {
+                name: name.clone(),
+                section,
+                value,
+                size: self.sizes.get(name).cloned().unwrap_or(0),
+                global: self.globals.contains(name),
+                ty: self.types.get(name).cloned().unwrap_or(SymbolType::NoType),
+            });
+        }
+
+        // Tell the linker that we don't need an executable stack.
+        self.switch_section(".note.GNU-stack");
+        self.obj
+    }
+}
@@ -0,0 +1,188 @@
+// A cache of compiled units on disk (--cache-dir), like ccache's.
+//
+// A unit is looked up by a hash of what decides its output once it is
+// preprocessed: the tokens, with where each came from, since debug
+// info, coverage and the sanitizers record it; the options; the
+// -fprofile-use profile; and the r9cc that compiles it. Comments,
+// unused macros and changes to headers that don't reach the tokens
+// still hit.
+//
+// A unit that got warnings isn't stored, so that they are reported
+// each time it's compiled. Neither are the outputs of options that
+// report on compiling itself, such as -ftime-report.
+//
+// The directory holds an entry for each unit, named by its hash, and
+// "stats", the hits and misses so far, which `r9cc --cache-stats`
+// prints. Compilations running at once may each write an entry, which
+// is the same, and may lose a count.
+
+use crate::token::Token;
+use crate::{opt, preprocess, Options, Unit};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+const MAGIC: &str = "r9cc cache 1";
+
+// Whether units compiled with `opts` may be cached.
+pub fn usable(opts: &Options) -> bool {
+    opts.cache_dir.is_some()
+        && !(opts.dump_ir1
+            || opts.dump_ir2
+            || opts.time_report
+            || opts.memory_stats
+            || opts.stack_usage)
+}
+
+// The name of the entry for `tokens`, compiled with `opts`.
+pub fn key(tokens: &[Token], opts: &Options) -> String {
+    let mut text = preprocess::to_source(tokens);
+    // Lines are counted from the last token of the same buffer.
+    let mut lines: HashMap<*const Vec<char>, (usize, usize)> = HashMap::new();
+    for t in tokens {
+        let (pos, line) = lines.entry(Rc::as_ptr(&t.buf)).or_insert((0, 1));
+        if t.start < *pos {
+            *pos = 0;
+            *line = 1;
+        }
+        *line += t.buf[*pos..t.start].iter().filter(|&&c| c == '\n').count();
+        *pos = t.start;
+        text.push_str(&format!("{}:{}:{}\n", t.filename, line, t.start));
+    }
+    // Where the output goes, and how fast, doesn't change it.
+    let opts = Options {
+        output: None,
+        jobs: 1,
+        cache_dir: None,
+        ..opts.clone()
+    };
+    text.push_str(&format!("{:?}\n", opts));
+    if let Some(path) = &opts.profile_use {
+        text.push_str(&fs::read_to_string(path).unwrap_or_default());
+    }
+    // A new r9cc may compile the same unit differently.
+    if let Ok(meta) = env::current_exe().and_then(fs::metadata) {
+        text.push_str(&format!("{:?} {}\n", meta.modified().ok(), meta.len()));
+    }
+    // Two hashes, for 128 bits.
+    let hash = |seed: u8| {
+        let mut h = DefaultHasher::new();
+        seed.hash(&mut h);
+        text.hash(&mut h);
+        h.finish()
+    };
+    format!("{:016x}{:016x}", hash(0), hash(1))
+}
+
+// The unit stored as `key` in `dir`, if there is one. What the
+// preprocessor found is the caller's, and the statistics are empty,
+// since it wasn't compiled.
+pub fn load(dir: &str, key: &str, opts: &Options) -> Option<Unit> {
+    let data = fs::read_to_string(Path::new(dir).join(key)).ok()?;
+    let rest = data.strip_prefix(MAGIC)?.strip_prefix('\n')?;
+    let (n, rest) = rest.split_once('\n')?;
+    let n: usize = n.parse().ok()?;
+    let mut lines = rest.splitn(n + 1, '\n');
+    let symbols: Vec<String> = lines.by_ref().take(n).map(String::from).collect();
+    if symbols.len() < n {
+        return None;
+    }
+    Some(Unit {
+        asm: lines.next().unwrap_or("").to_string(),
+        symbols,
+        stats: opt::Stats {
+            level: opts.opt_level,
+            passes: vec![],
+        },
+        warnings: vec![],
+        includes: vec![],
+        phases: vec![],
+        memory: None,
+        stack_usage: vec![],
+        cached: true,
+    })
+}
+
+// Stores `unit` as `key` in `dir`, unless it has warnings.
+pub fn store(dir: &str, key: &str, unit: &Unit) -> io::Result<()> {
+    if !unit.warnings.is_empty() {
+        return Ok(());
+    }
+    let mut data = format!("{}\n{}\n", MAGIC, unit.symbols.len());
+    for symbol in &unit.symbols {
+        data.push_str(symbol);
+        data.push('\n');
+    }
+    data.push_str(&unit.asm);
+    write_atomic(&Path::new(dir).join(key), &data)
+}
+
+// How often the cache in a directory was used.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Stats {
+    pub fn read(dir: &str) -> Stats {
+        let data = fs::read_to_string(Path::new(dir).join("stats")).unwrap_or_default();
+        let mut stats = Stats::default();
+        for line in data.lines() {
+            match line.split_once(' ') {
+                Some(("hits", n)) => stats.hits = n.parse().unwrap_or(0),
+                Some(("misses", n)) => stats.misses = n.parse().unwrap_or(0),
+                _ => (),
+            }
+        }
+        stats
+    }
+
+    // Counts a lookup in `dir`.
+    pub fn count(dir: &str, hit: bool) -> io::Result<()> {
+        let mut stats = Stats::read(dir);
+        if hit {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+        let data = format!("hits {}\nmisses {}\n", stats.hits, stats.misses);
+        write_atomic(&Path::new(dir).join("stats"), &data)
+    }
+
+    // Formats the statistics as `r9cc --cache-stats` prints them:
+    //
+    //   cache directory    /tmp/cache
+    //   hits                       12
+    //   misses                      4
+    //   hit rate                75.0%
+    pub fn report(&self, dir: &str) -> String {
+        let total = self.hits + self.misses;
+        let rate = if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 * 100.0 / total as f64
+        };
+        format!(
+            "cache directory    {}\nhits      {:>16}\nmisses    {:>16}\nhit rate  {:>15.1}%\n",
+            dir, self.hits, self.misses, rate
+        )
+    }
+}
+
+// Writes `data` to `path` through a file next to it, so that a
+// compilation reading it at the same time sees all of it or nothing.
+fn write_atomic(path: &Path, data: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
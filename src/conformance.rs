@@ -0,0 +1,314 @@
+// Conformance reports (--conformance).
+//
+// Runs an external C test suite through r9cc to measure how much of the
+// language it handles. Two layouts are recognized:
+//
+//   c-testsuite   tests/single-exec/*.c, each with the output it must
+//                 print in a .c.expected file next to it
+//   GCC torture   gcc/testsuite/gcc.c-torture/execute/*.c, which call
+//                 abort() when they fail
+//
+// A directory holding either kind of test directly works too. Each test
+// is compiled by running the r9cc binary, so that a crash or a hang in
+// the compiler only fails that test, and the executable is run with a
+// time limit. Tests listed in an xfail file are expected to fail; a
+// failure of any other test is a regression.
+
+use crate::driver::TempDir;
+use crate::parallel;
+
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long compiling or running one test may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Suite {
+    CTestsuite,
+    GccTorture,
+}
+
+impl Suite {
+    pub fn name(self) -> &'static str {
+        match self {
+            Suite::CTestsuite => "c-testsuite",
+            Suite::GccTorture => "gcc-torture",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub name: String, // File name, as in the xfail list
+    pub path: PathBuf,
+    pub expected: Option<PathBuf>, // c-testsuite's expected output
+}
+
+// Finds the tests under `dir`, in order of name.
+pub fn discover(dir: &Path) -> Result<(Suite, Vec<Case>), String> {
+    let ctestsuite = dir.join("tests/single-exec");
+    let torture = dir.join("gcc/testsuite/gcc.c-torture/execute");
+    let dir = if ctestsuite.is_dir() {
+        ctestsuite
+    } else if torture.is_dir() {
+        torture
+    } else {
+        dir.to_path_buf()
+    };
+
+    let entries =
+        fs::read_dir(&dir).map_err(|e| format!("cannot read {}: {}", dir.display(), e))?;
+    let mut cases = vec![];
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("cannot read {}: {}", dir.display(), e))?
+            .path();
+        if path.extension().is_none_or(|ext| ext != "c") {
+            continue;
+        }
+        let expected = PathBuf::from(format!("{}.expected", path.display()));
+        cases.push(Case {
+            name: path.file_name().unwrap().to_string_lossy().into_owned(),
+            expected: if expected.exists() {
+                Some(expected)
+            } else {
+                None
+            },
+            path,
+        });
+    }
+    if cases.is_empty() {
+        return Err(format!("no tests in {}", dir.display()));
+    }
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+    let suite = if cases.iter().any(|c| c.expected.is_some()) {
+        Suite::CTestsuite
+    } else {
+        Suite::GccTorture
+    };
+    Ok((suite, cases))
+}
+
+// What happened to a test.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Pass,
+    CompileError(String),    // The first error r9cc printed
+    InternalError(String),   // Likewise
+    CompilerCrashed(String), // How r9cc exited
+    Failed(String),          // How the test exited
+    WrongOutput,
+    Timeout(&'static str), // "compiling" or "running"
+}
+
+impl Outcome {
+    fn describe(&self) -> String {
+        match self {
+            Outcome::Pass => "passed".into(),
+            Outcome::CompileError(msg) => format!("compile error: {}", msg),
+            Outcome::InternalError(msg) => msg.clone(),
+            Outcome::CompilerCrashed(how) => format!("r9cc {}", how),
+            Outcome::Failed(how) => how.clone(),
+            Outcome::WrongOutput => "wrong output".into(),
+            Outcome::Timeout(what) => format!("timed out {}", what),
+        }
+    }
+}
+
+pub(crate) fn exit_description(status: ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("exited with status {}", code),
+        None => "was killed by a signal".into(),
+    }
+}
+
+// Runs `cmd` with its stdout and stderr going to files in `dir`, which
+// can't fill up and block it the way pipes can. Returns None if it
+// takes longer than TIMEOUT.
+pub(crate) fn run(
+    cmd: &mut Command,
+    dir: &Path,
+) -> Result<Option<(ExitStatus, String, String)>, String> {
+    let out = dir.join("stdout");
+    let err = dir.join("stderr");
+    let create = |path: &Path| {
+        File::create(path).map_err(|e| format!("cannot create {}: {}", path.display(), e))
+    };
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(create(&out)?)
+        .stderr(create(&err)?)
+        .spawn()
+        .map_err(|e| format!("cannot execute {:?}: {}", cmd.get_program(), e))?;
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break status;
+        }
+        if start.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    let read =
+        |path: &Path| String::from_utf8_lossy(&fs::read(path).unwrap_or_default()).into_owned();
+    Ok(Some((status, read(&out), read(&err))))
+}
+
+// Compiles and runs one test with the r9cc binary at `r9cc`, which gets
+// `flags` too.
+pub fn run_case(r9cc: &Path, flags: &[String], case: &Case) -> Result<Outcome, String> {
+    let tmp = TempDir::new().map_err(|e| e.to_string())?;
+    let exe = tmp.path.join("a.out");
+
+    let mut compile = Command::new(r9cc);
+    compile
+        .args(flags)
+        .arg(&case.path)
+        .arg("-o")
+        .arg(&exe)
+        .arg("-fno-diagnostics-color");
+    let (status, _, stderr) = match run(&mut compile, &tmp.path)? {
+        Some(result) => result,
+        None => return Ok(Outcome::Timeout("compiling")),
+    };
+    // The exit statuses of the r9cc binary; see main.rs.
+    let first_error = || {
+        stderr
+            .lines()
+            .find(|l| l.contains("error:"))
+            .unwrap_or("")
+            .to_string()
+    };
+    match status.code() {
+        Some(0) => (),
+        Some(1) => return Ok(Outcome::CompileError(first_error())),
+        Some(4) => return Ok(Outcome::InternalError(first_error())),
+        _ => return Ok(Outcome::CompilerCrashed(exit_description(status))),
+    }
+
+    let (status, stdout, _) = match run(&mut Command::new(&exe), &tmp.path)? {
+        Some(result) => result,
+        None => return Ok(Outcome::Timeout("running")),
+    };
+    if !status.success() {
+        return Ok(Outcome::Failed(exit_description(status)));
+    }
+    if let Some(ref path) = case.expected {
+        let expected = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        if stdout != expected {
+            return Ok(Outcome::WrongOutput);
+        }
+    }
+    Ok(Outcome::Pass)
+}
+
+// Reads an xfail file: a test name per line, with # starting a comment.
+pub fn read_xfail(text: &str) -> BTreeSet<String> {
+    text.lines()
+        .map(|l| l.split('#').next().unwrap().trim())
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+pub struct Report {
+    pub suite: Suite,
+    pub results: Vec<(Case, Outcome)>,
+    pub xfail: BTreeSet<String>,
+}
+
+impl Report {
+    // Tests that failed, whether expected to or not.
+    pub fn failures(&self) -> impl Iterator<Item = &str> {
+        self.results
+            .iter()
+            .filter(|(_, o)| *o != Outcome::Pass)
+            .map(|(c, _)| &*c.name)
+    }
+
+    // Failures not in the xfail list.
+    pub fn regressions(&self) -> usize {
+        self.failures().filter(|n| !self.xfail.contains(*n)).count()
+    }
+
+    // Formats the results like DejaGnu's summary, listing the tests
+    // that didn't do what the xfail list says they would:
+    //
+    //   FAIL: 00040.c: compile error: 00040.c:3:1: error: ...
+    //   XPASS: 00052.c
+    //
+    //   c-testsuite: 220 tests
+    //     # of expected passes        150
+    //     # of unexpected failures      2
+    //     ...
+    pub fn format(&self) -> String {
+        let mut out = String::new();
+        let (mut pass, mut fail, mut xfail, mut xpass) = (0, 0, 0, 0);
+        for (case, outcome) in &self.results {
+            let listed = self.xfail.contains(&case.name);
+            match (outcome, listed) {
+                (Outcome::Pass, false) => pass += 1,
+                (Outcome::Pass, true) => {
+                    xpass += 1;
+                    let _ = writeln!(out, "XPASS: {}", case.name);
+                }
+                (_, true) => xfail += 1,
+                (_, false) => {
+                    fail += 1;
+                    let _ = writeln!(out, "FAIL: {}: {}", case.name, outcome.describe());
+                }
+            }
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        let total = self.results.len();
+        let _ = writeln!(out, "{}: {} tests", self.suite.name(), total);
+        for (what, n) in [
+            ("expected passes", pass),
+            ("unexpected failures", fail),
+            ("expected failures", xfail),
+            ("unexpected successes", xpass),
+        ] {
+            if n > 0 {
+                let _ = writeln!(out, "  # of {:<22}{:>6}", what, n);
+            }
+        }
+        let _ = writeln!(
+            out,
+            "  {:.1}% passing",
+            (pass + xpass) as f64 * 100.0 / total as f64
+        );
+        out
+    }
+}
+
+// Runs every test in `dir` on `jobs` threads.
+pub fn run_suite(
+    r9cc: &Path,
+    flags: &[String],
+    dir: &Path,
+    xfail: BTreeSet<String>,
+    jobs: usize,
+) -> Result<Report, String> {
+    let (suite, cases) = discover(dir)?;
+    let results = parallel::map(cases, jobs, |case| {
+        let outcome = run_case(r9cc, flags, &case);
+        outcome.map(|o| (case, o))
+    });
+    Ok(Report {
+        suite,
+        results: results.into_iter().collect::<Result<_, _>>()?,
+        xfail,
+    })
+}
@@ -0,0 +1,94 @@
+// Coverage reports (--coverage-report) and profiles (-fprofile-use).
+//
+// A program compiled with --coverage writes "file:line count" for each
+// basic block when it exits (see runtime/coverage.c). We print each
+// source file annotated with those counts, in the format of gcov:
+// "-" for lines that start no block and "#####" for lines that never
+// ran.
+//
+// Compiling the program again with -fprofile-use reads the counts back
+// for the optimizer: gen_ir marks each block that starts at a counted
+// line with how often it ran (IROp::Count).
+
+use std::collections::BTreeMap;
+use std::fs;
+
+// Parses the counts written by the runtime. A line with several blocks,
+// such as a loop on a single line, gets the largest of their counts.
+fn parse_counts(dump: &str) -> Result<BTreeMap<String, BTreeMap<usize, u64>>, String> {
+    let mut files: BTreeMap<String, BTreeMap<usize, u64>> = BTreeMap::new();
+    for entry in dump.lines() {
+        let malformed = || format!("malformed coverage data: {}", entry);
+        let (loc, count) = entry.rsplit_once(' ').ok_or_else(malformed)?;
+        let (file, line) = loc.rsplit_once(':').ok_or_else(malformed)?;
+        let line = line.parse().map_err(|_| malformed())?;
+        let count = count.parse().map_err(|_| malformed())?;
+        let max = files
+            .entry(file.to_string())
+            .or_default()
+            .entry(line)
+            .or_insert(0);
+        *max = (*max).max(count);
+    }
+    Ok(files)
+}
+
+// The counts of a profile, by file and line. Files are known by their
+// key(), as the program may have been built from another directory.
+#[derive(Debug, Clone, Default)]
+pub struct Profile(BTreeMap<String, BTreeMap<usize, u64>>);
+
+impl Profile {
+    // Reads a file written by runtime/coverage.c.
+    pub fn read(path: &str) -> Result<Self, String> {
+        let dump = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let files = parse_counts(&dump)?;
+        Ok(Profile(
+            files
+                .into_iter()
+                .map(|(file, counts)| (Self::key(&file), counts))
+                .collect(),
+        ))
+    }
+
+    // The name of `file` in profiles: its absolute path, if it exists.
+    pub fn key(file: &str) -> String {
+        fs::canonicalize(file).map_or_else(|_| file.into(), |p| p.to_string_lossy().into_owned())
+    }
+
+    // How often the block starting at `line` of the file with `key`
+    // ran, if the profile has it.
+    pub fn count(&self, key: &str, line: usize) -> Option<u64> {
+        self.0.get(key)?.get(&line).copied()
+    }
+
+    // How often the busiest block ran.
+    pub fn max(&self) -> u64 {
+        self.0
+            .values()
+            .flat_map(BTreeMap::values)
+            .max()
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+// Returns the annotated sources of the files in `dump`, the contents
+// of a file written by runtime/coverage.c.
+pub fn report(dump: &str) -> Result<String, String> {
+    let mut out = String::new();
+    for (file, counts) in parse_counts(dump)? {
+        let source =
+            fs::read_to_string(&file).map_err(|e| format!("cannot read {}: {}", file, e))?;
+        out.push_str(&format!("{:>9}:{:>5}:Source:{}\n", "-", 0, file));
+        for (i, text) in source.lines().enumerate() {
+            let count = match counts.get(&(i + 1)) {
+                Some(0) => "#####".to_string(),
+                Some(n) => n.to_string(),
+                None => "-".to_string(),
+            };
+            out.push_str(&format!("{:>9}:{:>5}:{}\n", count, i + 1, text));
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,505 @@
+// Errors and warnings about the program being compiled.
+//
+// The parser returns its errors as ParseErrors (see parse.rs), having
+// gone on past each one to find the others. The phases after it report
+// errors by panicking with a Diagnostic as the payload (see error()).
+// Sema catches them with recover() and goes on with the next statement,
+// so that one compilation can report several errors; the others stop
+// at the first one. Either way compile() turns them into a
+// CompileError.
+//
+// Warnings and the errors recovered from are collected in the thread's
+// Diagnostics, which counts the errors and stops compilation after
+// -fmax-errors of them. They are returned along with the result, after
+// the -W flags in Warnings have picked which warnings to keep.
+
+use crate::util::json_string;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::panic::{self, AssertUnwindSafe};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Option<&'static str>, // A warning's name, or an error's code
+    pub message: String,
+    pub loc: Option<Location>, // None if not about a particular place
+    pub notes: Vec<String>,    // More about the problem, printed after it
+}
+
+// Where in the source a diagnostic points at.
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub filename: String,
+    pub line: usize,        // 1-based
+    pub col: Option<usize>, // 1-based, or None if only the line is known
+    pub len: usize,         // Number of characters to underline from col
+    pub text: String,       // The source line
+}
+
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const MAGENTA: &str = "\x1b[1;35m";
+const GREEN: &str = "\x1b[1;32m";
+const CYAN: &str = "\x1b[1;36m";
+const RESET: &str = "\x1b[0m";
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: String, loc: Option<Location>) -> Self {
+        Diagnostic {
+            severity,
+            code: None,
+            message,
+            loc,
+            notes: vec![],
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    // Suggests `name`, if any, for what was misspelled.
+    pub fn with_suggestion(self, name: Option<String>) -> Self {
+        match name {
+            Some(name) => self.with_note(format!("did you mean '{}'?", name)),
+            None => self,
+        }
+    }
+
+    fn severity_name(&self) -> &'static str {
+        match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    // Formats the diagnostic like GCC does, with the source line and a
+    // caret under the place it's about:
+    //
+    //   foo.c:1:22: error: unknown symbol
+    //       1 | int main() { return 1 @ 2; }
+    //         |                     ^
+    //
+    // `color` adds the usual terminal colors.
+    pub fn render(&self, color: bool) -> String {
+        let paint = |code: &'static str| if color { code } else { "" };
+        let pos = match self.loc {
+            Some(ref loc) => match loc.col {
+                Some(col) => format!("{}:{}:{}", loc.filename, loc.line, col),
+                None => format!("{}:{}", loc.filename, loc.line),
+            },
+            None => "r9cc".into(),
+        };
+        let code = match self.severity {
+            Severity::Error => RED,
+            Severity::Warning => MAGENTA,
+        };
+        let option = match (self.severity, self.code) {
+            (Severity::Warning, Some(name)) => format!(" [-W{}]", name),
+            (Severity::Error, Some(name)) if !ERRORS.contains(&name) => {
+                format!(" [-Werror={}]", name)
+            }
+            _ => String::new(),
+        };
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{}{}:{} {}{}:{} {}{}{}{}",
+            paint(BOLD),
+            pos,
+            paint(RESET),
+            paint(code),
+            self.severity_name(),
+            paint(RESET),
+            paint(BOLD),
+            self.message,
+            paint(RESET),
+            option
+        );
+        if let Some(ref loc) = self.loc {
+            let _ = writeln!(out, "{:>5} | {}", loc.line, loc.text);
+            if let Some(col) = loc.col {
+                // Keep tabs so that the caret lines up with the text.
+                let indent: String = loc
+                    .text
+                    .chars()
+                    .take(col - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                let _ = writeln!(
+                    out,
+                    "{:>5} | {}{}^{}{}",
+                    "",
+                    indent,
+                    paint(GREEN),
+                    "~".repeat(loc.len.saturating_sub(1)),
+                    paint(RESET)
+                );
+            }
+        }
+        for note in &self.notes {
+            let _ = writeln!(
+                out,
+                "{}{}:{} {}note:{} {}",
+                paint(BOLD),
+                pos,
+                paint(RESET),
+                paint(CYAN),
+                paint(RESET),
+                note
+            );
+        }
+        out
+    }
+
+    // Formats the diagnostic as a single line of JSON, for tools that
+    // read r9cc's output (--diagnostics-format=json):
+    //
+    //   {"severity":"error","code":null,"message":"unknown symbol",
+    //    "span":{"file":"foo.c","line":1,"column":22,"length":1,
+    //    "text":"int main() { return 1 @ 2; }"},"notes":[]}
+    //
+    // "span" is null if the diagnostic isn't about a particular place,
+    // and "column" if only the line is known.
+    pub fn to_json(&self) -> String {
+        let code = self.code.map_or("null".into(), json_string);
+        let span = match self.loc {
+            Some(ref loc) => format!(
+                "{{\"file\":{},\"line\":{},\"column\":{},\"length\":{},\"text\":{}}}",
+                json_string(&loc.filename),
+                loc.line,
+                loc.col.map_or("null".into(), |col| col.to_string()),
+                loc.len,
+                json_string(&loc.text)
+            ),
+            None => "null".into(),
+        };
+        let notes: Vec<String> = self.notes.iter().map(|n| json_string(n)).collect();
+        format!(
+            "{{\"severity\":\"{}\",\"code\":{},\"message\":{},\"span\":{},\"notes\":[{}]}}",
+            self.severity_name(),
+            code,
+            json_string(&self.message),
+            span,
+            notes.join(",")
+        )
+    }
+}
+
+// Stops compilation with an error.
+pub fn error(message: String, loc: Option<Location>) -> ! {
+    raise(Diagnostic::new(Severity::Error, message, loc))
+}
+
+// Like error(), for one of the errors in ERRORS.
+pub fn error_with_code(code: &'static str, message: String, loc: Option<Location>) -> ! {
+    debug_assert!(ERRORS.contains(&code));
+    raise(Diagnostic::new(Severity::Error, message, loc).with_code(code))
+}
+
+// Stops compilation with `d`, an error that error() can't make, such
+// as one with notes.
+pub fn raise(d: Diagnostic) -> ! {
+    panic::panic_any(d)
+}
+
+// Every warning, by the name used in -W<name> and -Wno-<name>, and
+// whether it is on by default. A warning's code is its name.
+pub const WARNINGS: &[(&str, bool)] = &[
+    ("attributes", true),
+    ("c11-extensions", true),
+    ("c99-extensions", true),
+    ("excess-initializers", true),
+    ("implicit-function-declaration", true),
+    ("int-conversion", true),
+    ("invalid-noreturn", true),
+    ("not-a-function", true),
+    ("return-type", true),
+    ("unknown-pragmas", true),
+];
+
+// The warning named `name`, as in -W<name>, if there is one.
+pub fn warning(name: &str) -> Option<&'static str> {
+    WARNINGS.iter().map(|(n, _)| *n).find(|n| *n == name)
+}
+
+// The codes of errors that tools may want to tell apart, such as an
+// editor offering to declare an undeclared name. They don't change
+// from one version to the next. Other errors have no code.
+pub const ERRORS: &[&str] = &[
+    "expected-token",
+    "nesting-too-deep",
+    "no-member",
+    "stray-character",
+    "undeclared-identifier",
+    "unknown-directive",
+];
+
+// A change "#pragma GCC diagnostic" makes to how warnings are reported,
+// from the line after it to the end of its file. Included files have
+// their own, so that a header can't silence warnings in what includes
+// it.
+#[derive(Debug, Clone)]
+pub enum Pragma {
+    Push,                     // Saves the levels of the warnings
+    Pop,                      // Restores them, or the -W flags' if none was saved
+    Set(&'static str, Level), // Of the warning with that name
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Ignored, // Not reported
+    Warning, // Reported as the -W flags say
+    Error,
+}
+
+// What has been reported so far in a compilation.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    list: Vec<Diagnostic>,
+    errors: usize,
+    max_errors: usize,                     // -fmax-errors, or 0 for no limit
+    pragmas: Vec<(String, usize, Pragma)>, // With their file and line
+}
+
+impl Diagnostics {
+    pub fn new(max_errors: usize) -> Self {
+        Diagnostics {
+            max_errors,
+            ..Default::default()
+        }
+    }
+
+    // Adds `d`. Returns false if it is an error that reaches the limit,
+    // after which compilation must stop.
+    pub fn report(&mut self, mut d: Diagnostic) -> bool {
+        if d.severity == Severity::Error {
+            self.errors += 1;
+            if self.errors == self.max_errors {
+                d = d.with_note(format!(
+                    "compilation terminated due to -fmax-errors={}",
+                    self.max_errors
+                ));
+                self.list.push(d);
+                return false;
+            }
+        }
+        self.list.push(d);
+        true
+    }
+
+    // The level the pragmas before `d` gave its warning, if they set
+    // one.
+    fn level(&self, d: &Diagnostic) -> Option<Level> {
+        let (name, loc) = match (d.code, &d.loc) {
+            (Some(name), Some(loc)) => (name, loc),
+            _ => return None,
+        };
+        let mut levels = BTreeMap::new();
+        let mut saved = vec![];
+        let pragmas = self
+            .pragmas
+            .iter()
+            .filter(|(file, line, _)| *file == loc.filename && *line < loc.line);
+        for (_, _, pragma) in pragmas {
+            match *pragma {
+                Pragma::Push => saved.push(levels.clone()),
+                Pragma::Pop => levels = saved.pop().unwrap_or_default(),
+                Pragma::Set(name, level) => {
+                    levels.insert(name, level);
+                }
+            }
+        }
+        levels.get(name).copied()
+    }
+
+    // The number of errors reported.
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+
+    // Returns what was reported since the last call.
+    pub fn take(&mut self) -> Vec<Diagnostic> {
+        self.errors = 0;
+        self.list.split_off(0)
+    }
+}
+
+// The payload of the panic that stops a compilation whose errors have
+// all been reported already.
+#[derive(Debug)]
+pub struct Stop;
+
+thread_local! {
+    static DIAGNOSTICS: RefCell<Diagnostics> = RefCell::new(Diagnostics::default());
+}
+
+// Starts reporting for a new compilation on this thread.
+pub fn reset(max_errors: usize) {
+    DIAGNOSTICS.with(|d| *d.borrow_mut() = Diagnostics::new(max_errors));
+}
+
+pub fn warn(mut d: Diagnostic) {
+    debug_assert!(WARNINGS.iter().any(|(name, _)| Some(*name) == d.code));
+    DIAGNOSTICS.with(|w| {
+        let mut w = w.borrow_mut();
+        match w.level(&d) {
+            Some(Level::Ignored) => return,
+            Some(Level::Error) => d.severity = Severity::Error,
+            _ => (),
+        }
+        w.report(d);
+    });
+}
+
+// Records a "#pragma GCC diagnostic" at `loc`, for the warnings after
+// it.
+pub fn pragma(loc: &Location, pragma: Pragma) {
+    DIAGNOSTICS.with(|w| {
+        w.borrow_mut()
+            .pragmas
+            .push((loc.filename.clone(), loc.line, pragma))
+    });
+}
+
+// Keeps `d`, an error found without panicking, such as a ParseError,
+// to be returned with the others. Returns false if it reaches the
+// -fmax-errors limit, after which no more are kept.
+pub fn report(d: Diagnostic) -> bool {
+    DIAGNOSTICS.with(|w| w.borrow_mut().report(d))
+}
+
+// Runs `f`, which may report an error with error(). If it does, the
+// error is kept to be returned with the others, and None returned for
+// the caller to go on with something else.
+pub fn recover<T>(f: impl FnOnce() -> T) -> Option<T> {
+    let payload = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(x) => return Some(x),
+        Err(payload) => payload,
+    };
+    match payload.downcast::<Diagnostic>() {
+        Ok(d) => {
+            if !report(*d) {
+                panic::panic_any(Stop);
+            }
+            None
+        }
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+
+// Stops compilation if errors have been recovered from, once a phase
+// has found all it can.
+pub fn stop_if_errors() {
+    if DIAGNOSTICS.with(|w| w.borrow().errors()) > 0 {
+        panic::panic_any(Stop);
+    }
+}
+
+// Returns the warnings and errors reported since the last call.
+pub fn take() -> Vec<Diagnostic> {
+    DIAGNOSTICS.with(|w| w.borrow_mut().take())
+}
+
+// Which warnings to report, and which to make errors, as selected by
+// -W flags. The last flag about a warning wins, like with gcc.
+#[derive(Debug, Clone, Default)]
+pub struct Warnings {
+    pub silent: bool,                      // -w
+    pub all_errors: bool,                  // -Werror
+    enabled: BTreeMap<&'static str, bool>, // -W<name> and -Wno-<name>
+    errors: BTreeMap<&'static str, bool>,  // -Werror=<name> and -Wno-error=<name>
+}
+
+impl Warnings {
+    // Applies a -W or -w command line flag.
+    pub fn set(&mut self, flag: &str) -> Result<(), String> {
+        let unknown = || format!("unknown warning option: {}", flag);
+        let name = match flag.strip_prefix("-W") {
+            Some(name) => name,
+            None if flag == "-w" => {
+                self.silent = true;
+                return Ok(());
+            }
+            None => return Err(unknown()),
+        };
+        match name {
+            "all" | "extra" => {
+                for (name, _) in WARNINGS {
+                    self.enabled.insert(name, true);
+                }
+            }
+            "error" => self.all_errors = true,
+            "no-error" => self.all_errors = false,
+            _ => {
+                let (name, on, error) = if let Some(name) = name.strip_prefix("error=") {
+                    (name, true, true)
+                } else if let Some(name) = name.strip_prefix("no-error=") {
+                    (name, false, true)
+                } else if let Some(name) = name.strip_prefix("no-") {
+                    (name, false, false)
+                } else {
+                    (name, true, false)
+                };
+                let name = warning(name).ok_or_else(unknown)?;
+                if error {
+                    self.errors.insert(name, on);
+                    // -Werror=<name> also turns the warning on.
+                    if on {
+                        self.enabled.insert(name, true);
+                    }
+                } else {
+                    self.enabled.insert(name, on);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn enabled(&self, name: &str) -> bool {
+        !self.silent
+            && match self.enabled.get(name) {
+                Some(on) => *on,
+                None => WARNINGS.iter().any(|(n, on)| *n == name && *on),
+            }
+    }
+
+    pub fn is_error(&self, name: &str) -> bool {
+        *self.errors.get(name).unwrap_or(&self.all_errors)
+    }
+
+    // Drops the warnings that are off, and turns those given to
+    // -Werror into errors.
+    pub fn apply(&self, diags: Vec<Diagnostic>) -> Vec<Diagnostic> {
+        diags
+            .into_iter()
+            .filter_map(|mut d| {
+                if let (Severity::Warning, Some(name)) = (d.severity, d.code) {
+                    if !self.enabled(name) {
+                        return None;
+                    }
+                    if self.is_error(name) {
+                        d.severity = Severity::Error;
+                    }
+                }
+                Some(d)
+            })
+            .collect()
+    }
+}
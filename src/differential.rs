@@ -0,0 +1,346 @@
+// Differential testing (--differential).
+//
+// Compiles each program with r9cc and with a reference compiler such
+// as gcc or clang, runs both executables and compares what they did:
+// how they exited and what they printed. The programs are files, or
+// random ones from generate(), which keeps to the C that r9cc supports
+// and has no undefined behavior, so that any difference is a bug.
+
+use crate::conformance::{exit_description, run};
+use crate::driver::TempDir;
+use crate::parallel;
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+pub struct Program {
+    pub name: String, // The file, or "seed <n>" for a generated one
+    pub source: String,
+    pub seed: Option<u64>,
+}
+
+impl Program {
+    pub fn generated(seed: u64) -> Self {
+        Program {
+            name: format!("seed {}", seed),
+            source: generate(seed),
+            seed: Some(seed),
+        }
+    }
+}
+
+// What an executable did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Behavior {
+    pub exit: String,
+    pub stdout: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Same,
+    Differ(Behavior, Behavior), // (r9cc's, the reference's)
+    // A compiler rejected the program, with the first error it printed.
+    CompileError(&'static str, String),
+}
+
+// Compiles `source` with `compiler` into `exe` and runs it. Errs with
+// the first error the compiler printed if it fails.
+fn build_and_run(
+    mut compiler: Command,
+    source: &Path,
+    exe: &Path,
+    dir: &Path,
+) -> Result<Result<Behavior, String>, String> {
+    compiler.arg(source).arg("-o").arg(exe);
+    let (status, _, stderr) = match run(&mut compiler, dir)? {
+        Some(result) => result,
+        None => return Ok(Err("timed out".into())),
+    };
+    if !status.success() {
+        let first = stderr.lines().find(|l| l.contains("error"));
+        return Ok(Err(first.unwrap_or(&exit_description(status)).into()));
+    }
+    Ok(Ok(match run(&mut Command::new(exe), dir)? {
+        Some((status, stdout, _)) => Behavior {
+            exit: exit_description(status),
+            stdout,
+        },
+        None => Behavior {
+            exit: "timed out".into(),
+            stdout: String::new(),
+        },
+    }))
+}
+
+// Runs `program` compiled by the r9cc binary at `r9cc`, which gets
+// `flags` too, and by `reference`, a command such as "gcc -O2".
+pub fn compare(
+    r9cc: &Path,
+    flags: &[String],
+    reference: &str,
+    program: &Program,
+) -> Result<Verdict, String> {
+    let tmp = TempDir::new().map_err(|e| e.to_string())?;
+    let source = tmp.path.join("prog.c");
+    fs::write(&source, &program.source)
+        .map_err(|e| format!("cannot write {}: {}", source.display(), e))?;
+
+    let mut cmd = Command::new(r9cc);
+    cmd.args(flags).arg("-fno-diagnostics-color");
+    let ours = match build_and_run(cmd, &source, &tmp.path.join("r9cc.out"), &tmp.path)? {
+        Ok(b) => b,
+        Err(e) => return Ok(Verdict::CompileError("r9cc", e)),
+    };
+
+    let mut words = reference.split_whitespace();
+    let mut cmd = Command::new(words.next().unwrap_or("cc"));
+    // Signed overflow wraps in r9cc's output, and is fine in the
+    // generated programs.
+    cmd.args(words).arg("-w").arg("-fwrapv");
+    let theirs = match build_and_run(cmd, &source, &tmp.path.join("ref.out"), &tmp.path)? {
+        Ok(b) => b,
+        Err(e) => return Ok(Verdict::CompileError("reference", e)),
+    };
+
+    Ok(if ours == theirs {
+        Verdict::Same
+    } else {
+        Verdict::Differ(ours, theirs)
+    })
+}
+
+pub struct Report {
+    pub reference: String,
+    pub results: Vec<(Program, Verdict)>,
+}
+
+impl Report {
+    pub fn failures(&self) -> impl Iterator<Item = &(Program, Verdict)> {
+        self.results.iter().filter(|(_, v)| *v != Verdict::Same)
+    }
+
+    // Lists the programs that behaved differently or didn't compile,
+    // then counts them:
+    //
+    //   DIFF: seed 17
+    //     r9cc: exited with status 3, printed "1\n-4\n"
+    //     gcc:  exited with status 3, printed "1\n4\n"
+    //   ERROR: foo.c: r9cc: foo.c:3:1: error: ...
+    //
+    //   200 programs: 198 same, 1 different, 1 not compiled
+    pub fn format(&self) -> String {
+        let mut out = String::new();
+        let name = self.reference.split_whitespace().next().unwrap_or("cc");
+        let (mut same, mut differ, mut error) = (0, 0, 0);
+        for (program, verdict) in &self.results {
+            match verdict {
+                Verdict::Same => same += 1,
+                Verdict::Differ(ours, theirs) => {
+                    differ += 1;
+                    let _ = writeln!(out, "DIFF: {}", program.name);
+                    for (who, b) in [("r9cc", ours), (name, theirs)] {
+                        let _ = writeln!(
+                            out,
+                            "  {:<6}{}, printed {:?}",
+                            format!("{}:", who),
+                            b.exit,
+                            b.stdout
+                        );
+                    }
+                }
+                Verdict::CompileError(who, msg) => {
+                    error += 1;
+                    let who = if *who == "r9cc" { "r9cc" } else { name };
+                    let _ = writeln!(out, "ERROR: {}: {}: {}", program.name, who, msg);
+                }
+            }
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        let _ = writeln!(
+            out,
+            "{} programs: {} same, {} different, {} not compiled",
+            self.results.len(),
+            same,
+            differ,
+            error
+        );
+        out
+    }
+}
+
+// Compares every program on `jobs` threads.
+pub fn run_all(
+    r9cc: &Path,
+    flags: &[String],
+    reference: &str,
+    programs: Vec<Program>,
+    jobs: usize,
+) -> Result<Report, String> {
+    let results = parallel::map(programs, jobs, |program| {
+        let verdict = compare(r9cc, flags, reference, &program);
+        verdict.map(|v| (program, v))
+    });
+    Ok(Report {
+        reference: reference.into(),
+        results: results.into_iter().collect::<Result<_, _>>()?,
+    })
+}
+
+// xorshift64*, which is plenty for making up programs and gives the
+// same ones for a seed everywhere.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The state must not be zero.
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // A number in 0..n.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() >> 33) as usize % n
+    }
+}
+
+// How many variables main has, and how many functions it may call.
+const VARS: usize = 4;
+const FUNCS: usize = 3;
+
+struct Generator {
+    rng: Rng,
+    vars: Vec<&'static str>, // The ones in scope
+    funcs: usize,            // How many of f0, f1, ... may be called
+}
+
+impl Generator {
+    // An int expression at most `depth` operators deep. Expressions are
+    // kept shallow so that r9cc doesn't run out of registers.
+    fn expr(&mut self, out: &mut String, depth: usize) {
+        let choice = if depth == 0 { 0 } else { self.rng.below(10) };
+        match choice {
+            0..=2 => {
+                if self.rng.below(3) == 0 {
+                    let _ = write!(out, "{}", self.rng.below(20) as i32 - 5);
+                } else {
+                    let v = self.vars[self.rng.below(self.vars.len())];
+                    out.push_str(v);
+                }
+            }
+            3..=6 => {
+                let op = [
+                    "+", "-", "*", "&", "|", "^", "<", "<=", "==", "!=", ">", ">=", "&&", "||",
+                ][self.rng.below(14)];
+                out.push('(');
+                self.expr(out, depth - 1);
+                let _ = write!(out, " {} ", op);
+                self.expr(out, depth - 1);
+                out.push(')');
+            }
+            7 => {
+                let op = ["/", "%"][self.rng.below(2)];
+                out.push('(');
+                self.expr(out, depth - 1);
+                let _ = write!(out, " {} {})", op, self.rng.below(9) + 1);
+            }
+            8 => {
+                let op = ["-", "!", ""][self.rng.below(3)];
+                if op.is_empty() {
+                    out.push('(');
+                    self.expr(out, depth - 1);
+                    let _ = write!(out, " >> {})", self.rng.below(4) + 1);
+                } else {
+                    out.push_str(op);
+                    out.push('(');
+                    self.expr(out, depth - 1);
+                    out.push(')');
+                }
+            }
+            _ if self.funcs > 0 => {
+                let _ = write!(out, "f{}(", self.rng.below(self.funcs));
+                self.expr(out, 1);
+                out.push_str(", ");
+                self.expr(out, 1);
+                out.push(')');
+            }
+            _ => {
+                out.push('(');
+                self.expr(out, depth - 1);
+                out.push_str(" ? ");
+                self.expr(out, depth - 1);
+                out.push_str(" : ");
+                self.expr(out, depth - 1);
+                out.push(')');
+            }
+        }
+    }
+
+    fn assign(&mut self, out: &mut String, indent: &str) {
+        // Not the loop counter, so that loops end.
+        let v = self.vars[self.rng.below(VARS)];
+        let _ = write!(out, "{}{} = ", indent, v);
+        self.expr(out, 3);
+        out.push_str(";\n");
+    }
+}
+
+// A random program that prints some numbers and exits with another,
+// the same one for each seed.
+pub fn generate(seed: u64) -> String {
+    let mut g = Generator {
+        rng: Rng::new(seed),
+        vars: vec!["a", "b"],
+        funcs: 0,
+    };
+    let mut out = String::from("int printf(char *fmt, ...);\n\n");
+    for i in 0..FUNCS {
+        let _ = write!(out, "int f{}(int a, int b) {{\n  return ", i);
+        g.expr(&mut out, 3);
+        out.push_str(";\n}\n\n");
+        g.funcs += 1;
+    }
+
+    g.vars = vec!["v0", "v1", "v2", "v3"];
+    out.push_str("int main() {\n");
+    for i in 0..VARS {
+        let init = g.rng.below(100) as i32 - 50;
+        let _ = writeln!(out, "  int {} = {};", g.vars[i], init);
+    }
+    for _ in 0..g.rng.below(3) + 1 {
+        g.assign(&mut out, "  ");
+    }
+    let _ = writeln!(
+        out,
+        "  for (int i = 0; i < {}; i++) {{",
+        g.rng.below(10) + 1
+    );
+    g.vars.push("i");
+    for _ in 0..g.rng.below(3) + 1 {
+        if g.rng.below(2) == 0 {
+            out.push_str("    if (");
+            g.expr(&mut out, 2);
+            out.push_str(")\n");
+            g.assign(&mut out, "      ");
+        } else {
+            g.assign(&mut out, "    ");
+        }
+    }
+    g.vars.pop();
+    out.push_str("  }\n");
+    for v in &g.vars {
+        let _ = writeln!(out, "  printf(\"%d\\n\", {});", v);
+    }
+    out.push_str("  return (v0 ^ v1 ^ v2 ^ v3) & 127;\n}\n");
+    out
+}
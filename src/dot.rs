@@ -0,0 +1,159 @@
+// The syntax tree as Graphviz graphs (--dump-ast=dot), one for each
+// function, for seeing how a tricky declarator or expression was
+// parsed:
+//
+//   $ r9cc --dump-ast=dot foo.c | dot -Tsvg > foo.svg
+//
+// Each node is labeled with its kind, what it holds, such as a number
+// or a variable's name, and the type sema gave it. Its children are
+// drawn below it in the order they are written.
+
+use crate::parse::{Node, NodeType};
+use crate::pretty;
+use crate::Scope;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+pub fn render(nodes: &[Node]) -> String {
+    let mut out = String::new();
+    for node in nodes {
+        if let NodeType::Func(name, ..) = node.op {
+            let mut graph = Graph {
+                out: String::new(),
+                ids: 0,
+                locals: HashMap::new(),
+            };
+            graph.name_locals(node);
+            graph.node(node);
+            let _ = writeln!(out, "digraph \"{}\" {{", name);
+            let _ = writeln!(out, "  node [shape=box, fontname=monospace];");
+            let _ = writeln!(out, "  ordering=out;");
+            out.push_str(&graph.out);
+            out.push_str("}\n");
+        }
+    }
+    out
+}
+
+struct Graph {
+    out: String,
+    ids: usize,
+    // The names of the function's locals, by offset, since sema leaves
+    // only that in the references to them.
+    locals: HashMap<usize, String>,
+}
+
+impl Graph {
+    fn name_locals(&mut self, node: &Node) {
+        if let NodeType::Vardef(name, _, Scope::Local(offset)) = node.op {
+            self.locals.insert(offset, name.to_string());
+        }
+        for child in node.children() {
+            self.name_locals(child);
+        }
+    }
+
+    // Writes `node` and what is below it, returning its id.
+    fn node(&mut self, node: &Node) -> usize {
+        let id = self.ids;
+        self.ids += 1;
+        let mut label = self.label(node);
+        if has_value(&node.op) {
+            label = format!("{}\n{}", label, pretty::declaration(&node.ty, ""));
+        }
+        let _ = writeln!(self.out, "  n{} [label=\"{}\"];", id, escape(&label));
+        for child in node.children() {
+            let child = self.node(child);
+            let _ = writeln!(self.out, "  n{} -> n{};", id, child);
+        }
+        id
+    }
+
+    fn label(&self, node: &Node) -> String {
+        use self::NodeType::*;
+        let local = |offset: &usize| match self.locals.get(offset) {
+            Some(name) => name.clone(),
+            None => format!("[{}]", offset),
+        };
+        match node.op {
+            Num(val) => format!("Num {}", val),
+            FNum(val) => format!("FNum {:?}", val),
+            Str(ref data, _) => format!("Str {:?}", data.trim_end_matches('\0')),
+            Ident(name) => format!("Ident {}", name),
+            Decl(name) => format!("Decl {}", name),
+            Vardef(name, _, _) => format!("Vardef {}\n{}", name, pretty::declaration(&node.ty, "")),
+            Lvar(Scope::Local(ref offset)) => format!("Lvar {}", local(offset)),
+            Lvar(_) => "Lvar".into(),
+            Gvar(ref name, ..) => format!("Gvar {}", name),
+            BinOp(ref op, ..) => format!("BinOp {}", pretty::binop(op).0),
+            If(..) => "If".into(),
+            Ternary(..) => "Ternary".into(),
+            For(..) => "For".into(),
+            Break => "Break".into(),
+            Switch(..) => "Switch".into(),
+            Case(..) => "Case".into(),
+            Default(_) => "Default".into(),
+            DoWhile(..) => "DoWhile".into(),
+            Addr(_) => "Addr".into(),
+            Deref(_) => "Deref".into(),
+            Dot(_, name, offset) => format!("Dot .{} +{}", name, offset),
+            Exclamation(_) => "Exclamation".into(),
+            Tilde(_) => "Tilde".into(),
+            Neg(_) => "Neg".into(),
+            Cast(_) => "Cast".into(),
+            PostInc(_) => "PostInc".into(),
+            PostDec(_) => "PostDec".into(),
+            Return(_) => "Return".into(),
+            Sizeof(_) => "Sizeof".into(),
+            Alignof(_) => "Alignof".into(),
+            Offsetof(_) => "Offsetof".into(),
+            Call(name, ..) => format!("Call {}", name),
+            CallPtr(..) => "CallPtr".into(),
+            Func(name, ..) => format!("Func {}\n{}", name, pretty::declaration(&node.ty, "")),
+            CompStmt(_) => "CompStmt".into(),
+            VecStmt(_) => "VecStmt".into(),
+            InitList(_) => "InitList".into(),
+            Designator(..) => "Designator".into(),
+            ExprStmt(_) => "ExprStmt".into(),
+            StmtExpr(_) => "StmtExpr".into(),
+            Asm(ref code) => format!("Asm {:?}", code),
+            Builtin(kind, _) => format!("Builtin {:?}", kind),
+            Null => "Null".into(),
+        }
+    }
+}
+
+// Whether `op` is an expression, whose type is worth showing.
+// Statements have one too, but it means nothing.
+fn has_value(op: &NodeType) -> bool {
+    use self::NodeType::*;
+    !matches!(
+        op,
+        Decl(_)
+            | Vardef(..)
+            | If(..)
+            | For(..)
+            | Break
+            | Switch(..)
+            | Case(..)
+            | Default(_)
+            | DoWhile(..)
+            | Return(_)
+            | Func(..)
+            | CompStmt(_)
+            | VecStmt(_)
+            | InitList(_)
+            | Designator(..)
+            | ExprStmt(_)
+            | Asm(_)
+            | Null
+    )
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
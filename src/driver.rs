@@ -0,0 +1,167 @@
+// Driver for the external toolchain.
+//
+// r9cc itself only produces assembly (or, with -c, an object file).
+// To get an executable in one step, we write the assembly of each
+// translation unit to a temporary directory, run the system assembler
+// on it and hand the resulting object files to the C compiler driver
+// for linking.
+
+use crate::gen_ir::Sanitize;
+
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug)]
+pub enum DriverError {
+    Io(String, io::Error),       // (what we were doing, cause)
+    Spawn(String, io::Error),    // tool could not be started
+    Failed(String, Option<i32>), // tool exited unsuccessfully, exit code
+}
+
+impl fmt::Display for DriverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DriverError::Io(what, e) => write!(f, "{}: {}", what, e),
+            DriverError::Spawn(tool, e) => write!(f, "cannot execute '{}': {}", tool, e),
+            DriverError::Failed(tool, Some(code)) => {
+                write!(f, "{} failed with exit code {}", tool, code)
+            }
+            DriverError::Failed(tool, None) => write!(f, "{} terminated by signal", tool),
+        }
+    }
+}
+
+// A scratch directory that is removed when it goes out of scope,
+// whether or not the build succeeded.
+pub(crate) struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    // The directory is always a new one, that only this user can use,
+    // so that nobody else can put files in it or links to elsewhere. If
+    // the name is taken, as anyone could have done in a shared /tmp,
+    // another one with a random part is tried.
+    pub fn new() -> Result<Self, DriverError> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let mut name = format!("r9cc-{}-{}", process::id(), n);
+        loop {
+            let path = env::temp_dir().join(&name);
+            let mut builder = fs::DirBuilder::new();
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+            match builder.create(&path) {
+                Ok(()) => return Ok(TempDir { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let random = RandomState::new().build_hasher().finish();
+                    name = format!("r9cc-{}-{}-{:016x}", process::id(), n, random);
+                }
+                Err(e) => {
+                    return Err(DriverError::Io(
+                        format!("cannot create {}", path.display()),
+                        e,
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// Tools can be overridden from the environment. We deliberately don't
+// read $CC here, since r9cc itself may well be $CC.
+fn tool(var: &str, default: &str) -> String {
+    env::var(var).unwrap_or_else(|_| default.to_string())
+}
+
+fn run(cmd: &mut Command, name: &str) -> Result<(), DriverError> {
+    let status = cmd
+        .status()
+        .map_err(|e| DriverError::Spawn(name.to_string(), e))?;
+    if !status.success() {
+        return Err(DriverError::Failed(name.to_string(), status.code()));
+    }
+    Ok(())
+}
+
+// Handlers called by code compiled with -fsanitize.
+const UBSAN_RUNTIME: &str = include_str!("../runtime/ubsan.c");
+const ASAN_RUNTIME: &str = include_str!("../runtime/asan.c");
+// Collects the counters of code compiled with --coverage.
+const COVERAGE_RUNTIME: &str = include_str!("../runtime/coverage.c");
+
+// Assembles each of `asms`, one per translation unit, with $R9CC_AS
+// and links them into an executable with $R9CC_CC, along with the
+// runtimes `sanitize` and `coverage` need. With `profile`, the C
+// library's profiling startup code is linked in, which writes
+// gmon.out for gprof.
+pub fn build_executable(
+    asms: &[String],
+    output: &str,
+    sanitize: Sanitize,
+    coverage: bool,
+    profile: bool,
+) -> Result<(), DriverError> {
+    let tmp = TempDir::new()?;
+    let assembler = tool("R9CC_AS", "as");
+    let mut obj_paths = vec![];
+    for (i, asm) in asms.iter().enumerate() {
+        let asm_path = tmp.path.join(format!("out{}.s", i));
+        let obj_path = tmp.path.join(format!("out{}.o", i));
+        fs::write(&asm_path, asm)
+            .map_err(|e| DriverError::Io(format!("cannot write {}", asm_path.display()), e))?;
+        run(
+            Command::new(&assembler)
+                .arg("-o")
+                .arg(&obj_path)
+                .arg(&asm_path),
+            &assembler,
+        )?;
+        obj_paths.push(obj_path);
+    }
+
+    let mut runtimes = vec![];
+    if sanitize.undefined() {
+        runtimes.push(("ubsan.c", UBSAN_RUNTIME));
+    }
+    if sanitize.address {
+        runtimes.push(("asan.c", ASAN_RUNTIME));
+    }
+    if coverage {
+        runtimes.push(("coverage.c", COVERAGE_RUNTIME));
+    }
+    let mut runtime_paths = vec![];
+    for (name, source) in runtimes {
+        let path = tmp.path.join(name);
+        fs::write(&path, source)
+            .map_err(|e| DriverError::Io(format!("cannot write {}", path.display()), e))?;
+        runtime_paths.push(path);
+    }
+
+    // gen_x86 takes addresses of globals with absolute `lea`, which
+    // can't be relocated in a position-independent executable.
+    let linker = tool("R9CC_CC", "cc");
+    let mut cmd = Command::new(&linker);
+    cmd.arg("-no-pie")
+        .arg("-o")
+        .arg(output)
+        .args(&obj_paths)
+        .args(&runtime_paths);
+    if profile {
+        cmd.arg("-pg");
+    }
+    run(&mut cmd, &linker)
+}
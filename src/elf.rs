@@ -0,0 +1,395 @@
+// Relocatable ELF64 object writer.
+//
+// This module knows nothing about x86 instructions. It takes sections
+// that have already been filled with machine code or data by the
+// assembler, plus the symbols and relocations that refer to them, and
+// lays them out as an `ET_REL` object file that a system linker accepts.
+
+use std::collections::HashMap;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+const SHF_TLS: u64 = 0x400;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_TLS: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelocType {
+    Abs64,  // R_X86_64_64
+    Pc32,   // R_X86_64_PC32
+    Plt32,  // R_X86_64_PLT32
+    Abs32S, // R_X86_64_32S
+    // R_X86_64_GOTTPOFF, the GOT entry with a thread-local variable's
+    // offset from the thread pointer
+    GotTpOff,
+}
+
+impl RelocType {
+    fn code(self) -> u64 {
+        match self {
+            RelocType::Abs64 => 1,
+            RelocType::Pc32 => 2,
+            RelocType::Plt32 => 4,
+            RelocType::Abs32S => 11,
+            RelocType::GotTpOff => 22,
+        }
+    }
+
+    pub fn is_pc_relative(self) -> bool {
+        match self {
+            RelocType::Pc32 | RelocType::Plt32 | RelocType::GotTpOff => true,
+            RelocType::Abs64 | RelocType::Abs32S => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RelocTarget {
+    Symbol(String), // Named symbol (defined or undefined)
+    Section(usize), // Section symbol, index into Object::sections
+}
+
+#[derive(Debug, Clone)]
+pub struct Reloc {
+    pub offset: usize,
+    pub target: RelocTarget,
+    pub ty: RelocType,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub data: Vec<u8>, // For NOBITS sections only the length matters.
+    pub relocs: Vec<Reloc>,
+    pub align: usize,
+    nobits: bool,
+    flags: u64,
+}
+
+impl Section {
+    pub fn new(name: &str) -> Self {
+        let (nobits, flags) = match name {
+            ".text" => (false, SHF_ALLOC | SHF_EXECINSTR),
+            ".data" => (false, SHF_ALLOC | SHF_WRITE),
+            ".bss" => (true, SHF_ALLOC | SHF_WRITE),
+            ".rodata" => (false, SHF_ALLOC),
+            ".tdata" => (false, SHF_ALLOC | SHF_WRITE | SHF_TLS),
+            ".tbss" => (true, SHF_ALLOC | SHF_WRITE | SHF_TLS),
+            ".note.GNU-stack" => (false, 0),
+            _ if name.starts_with(".text.") => (false, SHF_ALLOC | SHF_EXECINSTR),
+            _ if name.starts_with(".rodata.") => (false, SHF_ALLOC),
+            _ if name.starts_with(".bss.") => (true, SHF_ALLOC | SHF_WRITE),
+            _ => (false, SHF_ALLOC | SHF_WRITE),
+        };
+        Section {
+            name: name.to_string(),
+            data: vec![],
+            relocs: vec![],
+            align: 1,
+            nobits,
+            flags,
+        }
+    }
+
+    pub fn is_exec(&self) -> bool {
+        self.flags & SHF_EXECINSTR != 0
+    }
+
+    // Whether the section is loaded into memory when the program runs.
+    pub fn is_alloc(&self) -> bool {
+        self.flags & SHF_ALLOC != 0
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.flags & SHF_TLS != 0
+    }
+}
+
+// What a symbol names, as set by the `.type` directive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolType {
+    NoType,
+    Object,
+    Func,
+    Tls,
+}
+
+impl SymbolType {
+    fn code(self) -> u8 {
+        match self {
+            SymbolType::NoType => STT_NOTYPE,
+            SymbolType::Object => STT_OBJECT,
+            SymbolType::Func => STT_FUNC,
+            SymbolType::Tls => STT_TLS,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Option<usize>, // None if undefined
+    pub value: usize,
+    pub size: usize,
+    pub global: bool,
+    pub ty: SymbolType,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+}
+
+// String table builder. Index 0 is always the empty string.
+struct StrTab {
+    buf: Vec<u8>,
+}
+
+impl StrTab {
+    fn new() -> Self {
+        StrTab { buf: vec![0] }
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        if s.is_empty() {
+            return 0;
+        }
+        let off = self.buf.len() as u32;
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+        off
+    }
+}
+
+struct Shdr {
+    name: u32,
+    ty: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+fn put16(v: &mut Vec<u8>, x: u16) {
+    v.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put32(v: &mut Vec<u8>, x: u32) {
+    v.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put64(v: &mut Vec<u8>, x: u64) {
+    v.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_sym(v: &mut Vec<u8>, name: u32, info: u8, shndx: u16, value: u64, size: u64) {
+    put32(v, name);
+    v.push(info);
+    v.push(0); // st_other
+    put16(v, shndx);
+    put64(v, value);
+    put64(v, size);
+}
+
+fn align_to(v: &mut Vec<u8>, align: usize) {
+    while !v.len().is_multiple_of(align) {
+        v.push(0);
+    }
+}
+
+pub fn write_elf(obj: &Object) -> Vec<u8> {
+    let mut shstrtab = StrTab::new();
+    let mut strtab = StrTab::new();
+    let mut symtab = vec![];
+    let mut shdrs = vec![];
+    let mut out = vec![0; 64]; // ELF header is filled in at the end.
+
+    // Null section header.
+    shdrs.push(Shdr {
+        name: 0,
+        ty: 0,
+        flags: 0,
+        offset: 0,
+        size: 0,
+        link: 0,
+        info: 0,
+        align: 0,
+        entsize: 0,
+    });
+
+    // Section contents. User section i becomes section header i + 1.
+    for sec in &obj.sections {
+        align_to(&mut out, sec.align.max(1));
+        let offset = out.len() as u64;
+        if !sec.nobits {
+            out.extend_from_slice(&sec.data);
+        }
+        shdrs.push(Shdr {
+            name: shstrtab.add(&sec.name),
+            ty: if sec.nobits { SHT_NOBITS } else { SHT_PROGBITS },
+            flags: sec.flags,
+            offset,
+            size: sec.data.len() as u64,
+            link: 0,
+            info: 0,
+            align: sec.align as u64,
+            entsize: 0,
+        });
+    }
+
+    // Symbol table: null, section symbols, locals, then globals.
+    let mut sym_index = HashMap::new();
+    put_sym(&mut symtab, 0, 0, 0, 0, 0);
+    for i in 0..obj.sections.len() {
+        put_sym(&mut symtab, 0, STT_SECTION, (i + 1) as u16, 0, 0);
+    }
+    let mut nsyms = 1 + obj.sections.len();
+    for global in &[false, true] {
+        for sym in obj.symbols.iter().filter(|s| s.global == *global) {
+            let bind = if sym.global { STB_GLOBAL } else { STB_LOCAL };
+            let shndx = sym.section.map(|i| i + 1).unwrap_or(0) as u16;
+            let name = strtab.add(&sym.name);
+            put_sym(
+                &mut symtab,
+                name,
+                bind << 4 | sym.ty.code(),
+                shndx,
+                sym.value as u64,
+                sym.size as u64,
+            );
+            sym_index.insert(sym.name.clone(), nsyms);
+            nsyms += 1;
+        }
+    }
+    let first_global = 1 + obj.sections.len() + obj.symbols.iter().filter(|s| !s.global).count();
+
+    // Section header indices of the trailing bookkeeping sections.
+    let nrela = obj.sections.iter().filter(|s| !s.relocs.is_empty()).count();
+    let symtab_idx = 1 + obj.sections.len() + nrela;
+
+    // Relocation sections.
+    for (i, sec) in obj.sections.iter().enumerate() {
+        if sec.relocs.is_empty() {
+            continue;
+        }
+        align_to(&mut out, 8);
+        let offset = out.len() as u64;
+        for rel in &sec.relocs {
+            let sym = match rel.target {
+                RelocTarget::Symbol(ref name) => sym_index[name],
+                RelocTarget::Section(idx) => idx + 1,
+            };
+            put64(&mut out, rel.offset as u64);
+            put64(&mut out, (sym as u64) << 32 | rel.ty.code());
+            put64(&mut out, rel.addend as u64);
+        }
+        shdrs.push(Shdr {
+            name: shstrtab.add(&format!(".rela{}", sec.name)),
+            ty: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            offset,
+            size: out.len() as u64 - offset,
+            link: symtab_idx as u32,
+            info: (i + 1) as u32,
+            align: 8,
+            entsize: 24,
+        });
+    }
+
+    align_to(&mut out, 8);
+    shdrs.push(Shdr {
+        name: shstrtab.add(".symtab"),
+        ty: SHT_SYMTAB,
+        flags: 0,
+        offset: out.len() as u64,
+        size: symtab.len() as u64,
+        link: symtab_idx as u32 + 1,
+        info: first_global as u32,
+        align: 8,
+        entsize: 24,
+    });
+    out.extend_from_slice(&symtab);
+
+    shdrs.push(Shdr {
+        name: shstrtab.add(".strtab"),
+        ty: SHT_STRTAB,
+        flags: 0,
+        offset: out.len() as u64,
+        size: strtab.buf.len() as u64,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+    out.extend_from_slice(&strtab.buf);
+
+    let shstrndx = shdrs.len();
+    let name = shstrtab.add(".shstrtab");
+    shdrs.push(Shdr {
+        name,
+        ty: SHT_STRTAB,
+        flags: 0,
+        offset: out.len() as u64,
+        size: 0,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+    shdrs[shstrndx].size = shstrtab.buf.len() as u64;
+    out.extend_from_slice(&shstrtab.buf);
+
+    // Section header table.
+    align_to(&mut out, 8);
+    let shoff = out.len() as u64;
+    for sh in &shdrs {
+        put32(&mut out, sh.name);
+        put32(&mut out, sh.ty);
+        put64(&mut out, sh.flags);
+        put64(&mut out, 0); // sh_addr
+        put64(&mut out, sh.offset);
+        put64(&mut out, sh.size);
+        put32(&mut out, sh.link);
+        put32(&mut out, sh.info);
+        put64(&mut out, sh.align);
+        put64(&mut out, sh.entsize);
+    }
+
+    // ELF header.
+    let mut hdr = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0];
+    hdr.resize(16, 0);
+    put16(&mut hdr, 1); // ET_REL
+    put16(&mut hdr, 62); // EM_X86_64
+    put32(&mut hdr, 1); // EV_CURRENT
+    put64(&mut hdr, 0); // e_entry
+    put64(&mut hdr, 0); // e_phoff
+    put64(&mut hdr, shoff);
+    put32(&mut hdr, 0); // e_flags
+    put16(&mut hdr, 64); // e_ehsize
+    put16(&mut hdr, 0); // e_phentsize
+    put16(&mut hdr, 0); // e_phnum
+    put16(&mut hdr, 64); // e_shentsize
+    put16(&mut hdr, shdrs.len() as u16);
+    put16(&mut hdr, shstrndx as u16);
+    out[..64].copy_from_slice(&hdr);
+    out
+}
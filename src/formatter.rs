@@ -0,0 +1,521 @@
+// Formatting C source (r9cc fmt).
+//
+// The formatter lays out the tokens of a file again, as the lexer finds
+// them with the comments kept: a statement to a line, two spaces of
+// indentation for each level of braces, `} else {` and spaces around
+// binary operators but not after unary ones, as --dump-ast prints.
+// What the author chose is kept where there is a choice: line breaks
+// within a statement, one blank line where there were any, comments
+// where they were, and preprocessor directives as written, since they
+// are not C yet.
+//
+// Whether `*` multiplies or declares a pointer, or a parenthesis is a
+// cast, depends on which names are types. The parser knows, having
+// parsed the program with its headers first, which also rejects a
+// program that isn't C before anything is changed. Then the output is
+// checked: it must have the same tokens and parse to a tree of the same
+// shape, or the formatter has a bug. (The trees can't be compared as
+// they are, since __LINE__ changes.)
+
+use crate::parse::parse_with_typedefs;
+use crate::token::{lex_with_comments, tokenize_str, Token};
+use crate::{diagnostic, parse_failed, preprocessor, target, CompileError, Options, TokenType};
+
+use std::collections::HashSet;
+use std::panic::{self, AssertUnwindSafe};
+
+pub fn format(source: &str, opts: &Options) -> Result<String, CompileError> {
+    let target = target::lookup(&opts.target)
+        .ok_or_else(|| CompileError::UnknownTarget(opts.target.clone()))?;
+    let parse = |source: &str| {
+        let mut pp = preprocessor(&*target, opts);
+        let tokens = tokenize_str(opts.filename.clone(), source, &mut pp);
+        let (nodes, typedefs) = parse_with_typedefs(&tokens, &*target, opts.max_depth, opts.std)?;
+        let size: usize = nodes.iter().map(|n| n.count()).sum();
+        Ok((size, typedefs))
+    };
+    let (size, typedefs) = parse(source).map_err(|errors| parse_failed(errors, opts))?;
+    let typedefs = typedefs.iter().map(|name| name.to_string()).collect();
+
+    let tokens = lex_with_comments(opts.filename.clone(), source, opts.trigraphs);
+    let mut f = Formatter::new(&typedefs);
+    f.run(&tokens, source);
+    let out = f.out;
+
+    let text = |tokens: &[Token]| -> Vec<(TokenType, String)> {
+        tokens
+            .iter()
+            .filter(|t| t.ty != TokenType::NewLine)
+            .map(|t| (t.ty.clone(), t.tokstr()))
+            .collect()
+    };
+    let check = || {
+        let formatted = lex_with_comments(opts.filename.clone(), &out, opts.trigraphs);
+        if text(&tokens) != text(&formatted) {
+            return Some(false);
+        }
+        parse(&out).ok().map(|(n, _)| n == size)
+    };
+    match panic::catch_unwind(AssertUnwindSafe(check)) {
+        Ok(Some(true)) => (),
+        Ok(Some(false)) => panic!("r9cc fmt changed the program"),
+        Ok(None) | Err(_) => panic!("r9cc fmt made a program that doesn't parse"),
+    }
+    // Warnings are for compiling the program.
+    diagnostic::take();
+    Ok(out)
+}
+
+// What is open around a token.
+#[derive(Clone, Copy, PartialEq)]
+enum Open {
+    Paren(Paren),
+    Bracket,
+    // A brace around statements or members, and the indentation of what
+    // is in it.
+    Block(Block, usize),
+    Init, // An initializer list, which stays on its line
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Paren {
+    Control, // After if, for, while or switch
+    Call,    // Arguments or parameters
+    Sizeof,
+    Group,
+    Cast,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Block {
+    Code,
+    Do,     // The body of a do-while, which `while` follows
+    Struct, // Which declarators may follow
+}
+
+// The last token written, as far as the layout of the next one goes.
+struct Prev {
+    ty: TokenType,
+    text: String,
+    unary: bool,
+    is_type: bool,        // A typedef name or a struct tag
+    opened: Option<Open>, // What a (, [ or { opened
+    closed: Option<Open>, // What a ), ] or } closed
+    head: bool,           // Ends the head of a statement, such as `if (x)`
+    label: bool,          // The colon of `case 1:`
+}
+
+impl Prev {
+    // Whether the token ends an operand, after which `*` multiplies
+    // and `++` increments what came before.
+    fn ends_operand(&self) -> bool {
+        match self.ty {
+            TokenType::Ident(_) => !self.is_type,
+            TokenType::Num(..) | TokenType::FloatNum => true,
+            TokenType::Str | TokenType::RightBracket => true,
+            TokenType::RightParen => matches!(
+                self.closed,
+                Some(Open::Paren(Paren::Call | Paren::Sizeof | Paren::Group))
+            ),
+            TokenType::Inc | TokenType::Dec => !self.unary,
+            _ => false,
+        }
+    }
+}
+
+struct Formatter<'a> {
+    out: String,
+    typedefs: &'a HashSet<String>,
+    stack: Vec<Open>,
+    prev: Option<Prev>,
+    // The line is done: the next token starts a statement.
+    need_break: bool,
+    // A comment ended the line, within a statement or not.
+    comment_break: bool,
+    // After a comment on the same line.
+    spaced: bool,
+    // Statements of if, for, while, else or do without braces, each of
+    // which indents what follows until the statement ends.
+    bodies: usize,
+    // In a `case` label, the `?` still waiting for their `:`.
+    label: Option<usize>,
+}
+
+fn is_type_keyword(ty: &TokenType) -> bool {
+    use crate::TokenType::*;
+    matches!(
+        ty,
+        Int | Char | Long | Signed | Unsigned | Float | Double | Void | Struct | Atomic
+    )
+}
+
+impl<'a> Formatter<'a> {
+    fn new(typedefs: &'a HashSet<String>) -> Self {
+        Formatter {
+            out: String::new(),
+            typedefs,
+            stack: vec![],
+            prev: None,
+            need_break: false,
+            comment_break: false,
+            spaced: false,
+            bodies: 0,
+            label: None,
+        }
+    }
+
+    fn run(&mut self, tokens: &[Token], source: &str) {
+        let lines: Vec<&str> = source.lines().collect();
+        let mut line = 0; // Of the source, from 0
+        let mut newlines = 0; // Since the last token
+        let mut i = 0;
+        while i < tokens.len() {
+            let t = &tokens[i];
+            match t.ty {
+                TokenType::NewLine => {
+                    line += 1;
+                    newlines += 1;
+                }
+                TokenType::Eof => break,
+                // A directive, which stays as it was, and so do the lines
+                // it continues with a backslash.
+                TokenType::HashMark if i == 0 || tokens[i - 1].ty == TokenType::NewLine => {
+                    let last = continued(&lines, line);
+                    self.start_line(0, newlines);
+                    self.out.push_str(lines[line].trim());
+                    for l in &lines[line + 1..=last] {
+                        self.out.push('\n');
+                        self.out.push_str(l.trim_end());
+                    }
+                    self.need_break = true;
+                    // Its lines were joined, and the newlines that joined
+                    // them moved after it.
+                    while tokens
+                        .get(i + 1)
+                        .is_some_and(|t| t.ty != TokenType::NewLine)
+                    {
+                        i += 1;
+                    }
+                    i = skip_newlines(tokens, i, last - line);
+                    line = last;
+                    newlines = 0;
+                }
+                TokenType::Comment => {
+                    let mut text = t.tokstr();
+                    let last = continued(&lines, line);
+                    // So does a line comment continued with a backslash,
+                    // which the lexer sees without them.
+                    let column = t.buf[..t.start]
+                        .iter()
+                        .rev()
+                        .take_while(|&&c| c != '\n')
+                        .count();
+                    let written: String = lines[line].chars().skip(column).collect();
+                    if last > line && text.starts_with("//") && written.starts_with("//") {
+                        text = written.trim_end().to_string();
+                        for l in &lines[line + 1..=last] {
+                            text.push('\n');
+                            text.push_str(l.trim_end());
+                        }
+                        self.comment(&text, newlines);
+                        i = skip_newlines(tokens, i, last - line);
+                        line = last;
+                    } else {
+                        self.comment(&text, newlines);
+                        line += text.matches('\n').count();
+                    }
+                    newlines = 0;
+                }
+                _ => {
+                    self.token(t, newlines);
+                    newlines = 0;
+                }
+            }
+            i += 1;
+        }
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+    }
+
+    // How far statements are indented here.
+    fn base(&self) -> usize {
+        let block = self.stack.iter().rev().find_map(|open| match open {
+            Open::Block(_, indent) => Some(*indent),
+            _ => None,
+        });
+        block.unwrap_or(0) + self.bodies
+    }
+
+    // Ends the line, if anything is on it, and indents the next one.
+    // `newlines` is how many the source had, so that a blank line stays.
+    fn start_line(&mut self, indent: usize, newlines: usize) {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+            let after_open = matches!(
+                self.prev,
+                Some(Prev {
+                    ty: TokenType::LeftBrace,
+                    ..
+                })
+            );
+            if newlines >= 2 && !after_open {
+                self.out.push('\n');
+            }
+        }
+        for _ in 0..indent {
+            self.out.push_str("  ");
+        }
+        self.comment_break = false;
+        self.spaced = false;
+    }
+
+    fn comment(&mut self, text: &str, newlines: usize) {
+        if newlines == 0 && !self.out.is_empty() && !self.out.ends_with('\n') {
+            self.out.push(' ');
+        } else {
+            let indent = if self.need_break || self.prev.is_none() {
+                self.base()
+            } else {
+                self.base() + 2
+            };
+            self.start_line(indent, newlines);
+        }
+        self.out.push_str(text);
+        if text.starts_with("//") || newlines > 0 || self.need_break {
+            self.comment_break = true;
+        } else {
+            self.spaced = true;
+        }
+    }
+
+    fn token(&mut self, t: &Token, newlines: usize) {
+        use crate::TokenType::*;
+        let text = t.tokstr();
+        let prev = self.prev.take();
+        let ends_operand = prev.as_ref().is_some_and(Prev::ends_operand);
+        let statement_start = self.need_break || prev.as_ref().is_none_or(|p| p.head || p.label);
+
+        let is_type = match t.ty {
+            Ident(name) => {
+                self.typedefs.contains(&*name.as_str())
+                    || prev.as_ref().is_some_and(|p| p.ty == Struct)
+            }
+            _ => false,
+        };
+        // Parentheses that start with a type are a cast's.
+        if (is_type || is_type_keyword(&t.ty)) && prev.as_ref().is_some_and(|p| p.ty == LeftParen) {
+            if let Some(open @ Open::Paren(Paren::Group)) = self.stack.last_mut() {
+                *open = Open::Paren(Paren::Cast);
+            }
+        }
+
+        let mut unary = false;
+        let mut label = false;
+        let mut closed = None;
+        let mut opened = None;
+        match t.ty {
+            Plus | Minus | Mul | And | Inc | Dec => unary = !ends_operand,
+            Exclamation | Tilde => unary = true,
+            Case | Default if statement_start => self.label = Some(0),
+            Question => {
+                if let Some(n) = self.label.as_mut() {
+                    *n += 1;
+                }
+            }
+            Colon => match self.label {
+                Some(0) => {
+                    label = true;
+                    self.label = None;
+                }
+                Some(ref mut n) => *n -= 1,
+                None => (),
+            },
+            LeftParen => {
+                let kind = match prev.as_ref().map(|p| &p.ty) {
+                    Some(If | For | While | Switch) => Paren::Control,
+                    Some(Sizeof | Alignof) => Paren::Sizeof,
+                    Some(Asm) => Paren::Call,
+                    _ if ends_operand => Paren::Call,
+                    _ => Paren::Group,
+                };
+                opened = Some(Open::Paren(kind));
+            }
+            LeftBracket => opened = Some(Open::Bracket),
+            LeftBrace => {
+                let in_init = self.stack.last() == Some(&Open::Init);
+                let before = prev.as_ref().map(|p| &p.ty);
+                opened = Some(match before {
+                    Some(Equal) => Open::Init,
+                    Some(Comma | LeftBrace) if in_init => Open::Init,
+                    Some(RightParen)
+                        if prev.as_ref().unwrap().closed == Some(Open::Paren(Paren::Cast)) =>
+                    {
+                        Open::Init
+                    }
+                    Some(Do) => Open::Block(Block::Do, 0),
+                    Some(Struct) => Open::Block(Block::Struct, 0),
+                    Some(Ident(_)) if prev.as_ref().unwrap().is_type => {
+                        Open::Block(Block::Struct, 0)
+                    }
+                    _ => Open::Block(Block::Code, 0),
+                });
+            }
+            RightParen | RightBracket | RightBrace => closed = self.stack.pop(),
+            _ => (),
+        }
+        let block_close = matches!(closed, Some(Open::Block(..)));
+
+        // Where it goes: on a line of its own, or after the last token.
+        let same_line_after_block = match prev.as_ref() {
+            // An empty block stays `{}`.
+            Some(p) if block_close && matches!(p.opened, Some(Open::Block(..))) => true,
+            Some(p) if matches!(p.closed, Some(Open::Block(..))) => match t.ty {
+                Else | Semicolon | RightParen | Comma => true,
+                While => p.closed == Some(Open::Block(Block::Do, 0)),
+                _ => false,
+            },
+            _ => false,
+        };
+        if self.out.is_empty() {
+            self.start_line(self.base(), 0);
+        } else if same_line_after_block {
+            if matches!(t.ty, Else | While) {
+                self.out.push(' ');
+            }
+        } else if self.need_break {
+            let indent = match closed {
+                Some(Open::Block(_, indent)) => indent - 1,
+                _ if self.label == Some(0) && matches!(t.ty, Case | Default) => {
+                    self.base().saturating_sub(1)
+                }
+                _ => self.base(),
+            };
+            self.start_line(indent, newlines);
+        } else if (newlines > 0 || self.comment_break) && !matches!(opened, Some(Open::Block(..))) {
+            let indent = match prev.as_ref() {
+                Some(p) if p.head => {
+                    self.bodies += 1;
+                    self.base()
+                }
+                Some(p) if p.label => self.base(),
+                _ if block_close || closed == Some(Open::Init) => self.base(),
+                _ => self.base() + 2,
+            };
+            self.start_line(indent, newlines);
+        } else if self.spaced
+            || prev
+                .as_ref()
+                .is_some_and(|p| space(p, &t.ty, &text, unary, label, closed))
+        {
+            self.out.push(' ');
+        }
+        self.out.push_str(&text);
+        self.spaced = false;
+        self.comment_break = false;
+        self.need_break = false;
+
+        if let Some(open) = opened {
+            self.stack.push(match open {
+                Open::Block(kind, _) => {
+                    let indent = self.base() + 1;
+                    self.bodies = 0;
+                    self.need_break = true;
+                    Open::Block(kind, indent)
+                }
+                open => open,
+            });
+        }
+        let in_block = matches!(self.stack.last(), None | Some(Open::Block(..)));
+        match t.ty {
+            Semicolon if in_block => {
+                self.need_break = true;
+                self.bodies = 0;
+                self.label = None;
+            }
+            RightBrace if block_close => {
+                self.need_break = !matches!(closed, Some(Open::Block(Block::Struct, _)));
+                if self.need_break {
+                    self.bodies = 0;
+                }
+            }
+            _ => (),
+        }
+        let head = match t.ty {
+            Else | Do => true,
+            RightParen => closed == Some(Open::Paren(Paren::Control)),
+            _ => false,
+        };
+        self.prev = Some(Prev {
+            ty: t.ty.clone(),
+            text,
+            unary,
+            is_type,
+            opened: opened.map(|open| match open {
+                Open::Block(kind, _) => Open::Block(kind, 0),
+                open => open,
+            }),
+            closed: closed.map(|open| match open {
+                Open::Block(kind, _) => Open::Block(kind, 0),
+                open => open,
+            }),
+            head,
+            label,
+        });
+    }
+}
+
+// The last line of what starts on `line`, which continues to the next
+// while it ends with a backslash.
+fn continued(lines: &[&str], line: usize) -> usize {
+    let mut last = line;
+    while last + 1 < lines.len() && lines[last].trim_end().ends_with('\\') {
+        last += 1;
+    }
+    last
+}
+
+// Skips up to `n` newline tokens after `tokens[i]`, which the lexer
+// moved there when it joined `n` continued lines, and returns where it
+// stopped.
+fn skip_newlines(tokens: &[Token], mut i: usize, n: usize) -> usize {
+    for _ in 0..n {
+        if tokens
+            .get(i + 1)
+            .is_some_and(|t| t.ty == TokenType::NewLine)
+        {
+            i += 1;
+        }
+    }
+    i
+}
+
+// Whether a space goes between `prev` and a token of type `ty`,
+// spelled `text`, on the same line.
+fn space(
+    prev: &Prev,
+    ty: &TokenType,
+    text: &str,
+    unary: bool,
+    label: bool,
+    closed: Option<Open>,
+) -> bool {
+    use crate::TokenType::*;
+    match (&prev.ty, ty) {
+        (LeftParen | LeftBracket, _) => false,
+        (_, RightParen | RightBracket | Comma | Semicolon) => false,
+        _ if prev.opened == Some(Open::Init) || closed == Some(Open::Init) => false,
+        (Comma | Semicolon, _) => true,
+        (_, Dot | Arrow) | (Dot | Arrow, _) => false,
+        (_, Inc | Dec) if !unary => false,
+        (_, Colon) if label => false,
+        // `- -x` isn't `--x`.
+        _ if prev.unary => {
+            prev.text.ends_with(['+', '-', '&']) && text.starts_with(&prev.text[..1])
+        }
+        (RightParen, _) if prev.closed == Some(Open::Paren(Paren::Cast)) => false,
+        (Ident(_) | RightParen | RightBracket | Sizeof | Alignof | Asm, LeftParen) => false,
+        (_, LeftBracket) => false,
+        _ => true,
+    }
+}
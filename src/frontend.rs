@@ -0,0 +1,135 @@
+// The frontend as a library: preprocessing, parsing and type checking,
+// for tools that need a program but not code for it, such as a language
+// server, a formatter or a static analyzer.
+//
+//   let names = r9cc::frontend::analyze(source, &opts, Phase::Sema, |a| {
+//       a.functions().map(|f| f.name.to_string()).collect::<Vec<_>>()
+//   })?;
+//
+// What such a tool works with is re-exported here: tokens, which know
+// their Location; the syntax tree, whose statements and definitions
+// carry a Span, and whose expressions a Pos; types and variables, which
+// declaration() prints as C declares them; and diagnostics. The tree
+// names things with Symbols, which are interned per thread (see
+// symbol.rs), so it can't leave the compilation thread that built it.
+// analyze() hands it to a closure on that thread instead, which returns
+// what the tool wants of it.
+//
+// The parser returns its errors, but sema reports them by panicking,
+// and catches them itself. A tool should install hook, as the r9cc
+// binary does, or Rust's default hook prints each one as it happens.
+
+pub use crate::diagnostic::{Diagnostic, Location, Severity};
+pub use crate::ice::hook;
+pub use crate::parse::{Found, Node, NodeType, ParseError};
+pub use crate::pretty::declaration;
+pub use crate::symbol::Symbol;
+pub use crate::token::{Pos, Span, Token};
+pub use crate::{CompileError, Ctype, Options, Reloc, Scope, Std, TokenType, Type, Var};
+
+use crate::parse::parse;
+use crate::sema::sema;
+use crate::timer::Timer;
+use crate::token::tokenize_timed;
+use crate::{catch_errors, diagnostic, parse_failed, preprocessor, target};
+
+// How far analyze() goes. A formatter needs no more than the tree as
+// written, which Parse gives even for programs sema would reject.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Preprocess,
+    Parse,
+    Sema,
+}
+
+// A program, as far as the frontend took it. What a phase makes is
+// empty if analyze() stopped before it.
+pub struct Analysis {
+    pub tokens: Vec<Token>, // Preprocessed
+    pub ast: Vec<Node>,     // As parsed
+    // As sema left it: types are set, names are resolved to variables,
+    // and global variables are moved to `globals`.
+    pub checked: Vec<Node>,
+    pub globals: Vec<Var>,
+    pub includes: Vec<String>, // The files it #includes
+    pub warnings: Vec<Diagnostic>,
+}
+
+// A function definition in the tree.
+pub struct Function<'a> {
+    pub name: Symbol,
+    pub params: &'a [Node],
+    pub body: &'a Node,
+    pub is_static: bool,
+    pub span: Option<&'a Span>,
+}
+
+impl Analysis {
+    // The functions the program defines, in the order it does, from
+    // `checked` if sema ran and `ast` if not.
+    pub fn functions(&self) -> impl Iterator<Item = Function<'_>> {
+        let nodes = if self.checked.is_empty() {
+            &self.ast
+        } else {
+            &self.checked
+        };
+        nodes.iter().filter_map(|node| match node.op {
+            NodeType::Func(name, ref params, ref body, _, is_static) => Some(Function {
+                name,
+                params,
+                body,
+                is_static,
+                span: node.span.as_ref(),
+            }),
+            _ => None,
+        })
+    }
+}
+
+// Runs the frontend on `source` up to `phase` and calls `f` with the
+// result, on the same thread. Errors in the program are returned, as
+// compile() returns them, and `f` isn't called.
+pub fn analyze<R: Send>(
+    source: &str,
+    opts: &Options,
+    phase: Phase,
+    f: impl FnOnce(&Analysis) -> R + Send,
+) -> Result<R, CompileError> {
+    catch_errors(opts, || {
+        let target = target::lookup(&opts.target)
+            .ok_or_else(|| CompileError::UnknownTarget(opts.target.clone()))?;
+        let mut timer = Timer::new(false);
+        let mut pp = preprocessor(&*target, opts);
+        let tokens = tokenize_timed(opts.filename.clone(), source, &mut pp, &mut timer);
+        let mut analysis = Analysis {
+            tokens,
+            ast: vec![],
+            checked: vec![],
+            globals: vec![],
+            includes: pp.includes,
+            warnings: vec![],
+        };
+        if phase >= Phase::Parse {
+            analysis.ast = timer
+                .phase("parse", || {
+                    parse(&analysis.tokens, &*target, opts.max_depth, opts.std)
+                })
+                .map_err(|errors| parse_failed(errors, opts))?;
+        }
+        if phase >= Phase::Sema {
+            let ast = analysis.ast.clone();
+            let (checked, globals) = timer.phase("sema", || sema(ast, opts.sanitize.address));
+            analysis.checked = checked;
+            analysis.globals = globals;
+        }
+        analysis.warnings = opts.warnings.apply(diagnostic::take());
+        if analysis
+            .warnings
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+        {
+            return Err(CompileError::Invalid(analysis.warnings));
+        }
+        Ok(f(&analysis))
+    })
+}
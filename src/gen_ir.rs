@@ -10,30 +10,249 @@
 // > Such infinite number of registers are mapped to a finite registers
 // > in a later pass.
 
-use crate::parse::{Node, NodeType};
-use crate::{Ctype, Scope, TokenType, Type};
+use crate::coverage::Profile;
+use crate::diagnostic;
+use crate::ice;
+use crate::parse::{Builtin, Node, NodeType};
+use crate::sema::REDZONE;
+use crate::token::Span;
+use crate::util::roundup;
+use crate::{Ctype, Scope, TokenType, Type, Var};
 
-use std::sync::Mutex;
+use smallvec::{smallvec, SmallVec};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::mem;
 
-lazy_static! {
-    static ref NUM_REGS: Mutex<usize> = Mutex::new(0);
-    static ref NLABEL: Mutex<usize> = Mutex::new(1);
-    static ref RETURN_LABEL: Mutex<usize> = Mutex::new(0);
-    static ref RETURN_REG: Mutex<usize> = Mutex::new(0);
-    static ref BREAK_LABEL: Mutex<usize> = Mutex::new(0);
-    static ref CODE: Mutex<Vec<IR>> = Mutex::new(vec![]);
+// State of one compilation, threaded through IR generation and the
+// backends instead of living in globals, so that compiling one program
+// never affects the output for the next.
+pub struct CodegenContext {
+    nlabel: usize,
+    num_regs: usize,
+    return_label: usize,
+    return_reg: usize,
+    break_label: usize,
+    case_labels: Vec<usize>,
+    default_label: usize,
+    // Local arrays of the current function, as (offset, size).
+    arrays: Vec<(usize, usize)>,
+    // Size of the struct returned by the current function, if any.
+    struct_ret: Option<usize>,
+    // Annotate statements with their source lines (-fverbose-asm).
+    pub verbose_asm: bool,
+    // Call mcount on entry to each function (-pg).
+    pub profile: bool,
+    last_comment: String,
+    // Record source lines for the debugger (-g).
+    pub debug_info: bool,
+    last_loc: Option<(String, usize)>,
+    // Runtime checks to insert (-fsanitize=...).
+    pub sanitize: Sanitize,
+    // Threads the backend may emit functions on.
+    pub jobs: usize,
+    // Lay out branches for the way they are likely to go (-O2).
+    pub layout: bool,
+    // Code of the current function that is unlikely to run, to be put
+    // after the rest of it.
+    cold: Vec<IR>,
+    // Bytes the backend emitted for each function, in order.
+    pub asm_sizes: Vec<usize>,
+    // Bytes of stack each function uses, in order, for backends that
+    // lay out stack frames.
+    pub frame_sizes: Vec<usize>,
+    // Where the current statement is, for -fsanitize diagnostics.
+    filename: String,
+    line: usize,
+    // Variables created during IR generation, such as the source
+    // locations passed to -fsanitize handlers. They must be emitted along with
+    // the ones sema returns.
+    pub globals: Vec<Var>,
+    // The labels of the location strings among them, by "file:line".
+    locations: HashMap<String, String>,
+    // The source file when instrumenting for --coverage. It names the
+    // counters, which must not clash with those of other files.
+    pub coverage: Option<String>,
+    pub profile_use: Option<Profile>, // -fprofile-use
+    profile_key: String,              // Of `filename`, in it
+    // Whether the last instruction ended a basic block, so that the
+    // next statement needs a counter of its own.
+    new_block: bool,
+    // "file:line" of each counter.
+    counters: Vec<String>,
+    code: Vec<IR>,
+    // Length of the last function's IR, to size the next one's by.
+    last_len: usize,
+    // Reused for text that is usually thrown away, such as a location
+    // that has been seen before.
+    scratch: String,
 }
 
-fn add(op: IROp, lhs: Option<usize>, rhs: Option<usize>) {
-    let ir = IR::new(op, lhs, rhs);
-    CODE.lock().unwrap().push(ir.clone());
+impl CodegenContext {
+    pub fn new() -> Self {
+        CodegenContext {
+            // Label 0 means "none" for return_label and break_label.
+            nlabel: 1,
+            num_regs: 0,
+            return_label: 0,
+            return_reg: 0,
+            break_label: 0,
+            case_labels: vec![],
+            default_label: 0,
+            arrays: vec![],
+            struct_ret: None,
+            verbose_asm: false,
+            profile: false,
+            last_comment: String::new(),
+            debug_info: false,
+            last_loc: None,
+            sanitize: Sanitize::default(),
+            jobs: 1,
+            layout: false,
+            cold: vec![],
+            asm_sizes: vec![],
+            frame_sizes: vec![],
+            filename: String::new(),
+            line: 0,
+            globals: vec![],
+            locations: HashMap::new(),
+            coverage: None,
+            profile_use: None,
+            profile_key: String::new(),
+            new_block: false,
+            counters: vec![],
+            code: vec![],
+            last_len: 0,
+            scratch: String::new(),
+        }
+    }
+
+    // Returns a label number that is unique within this compilation.
+    pub fn new_label(&mut self) -> usize {
+        let x = self.nlabel;
+        self.nlabel += 1;
+        x
+    }
+
+    fn new_reg(&mut self) -> usize {
+        let r = self.num_regs;
+        self.num_regs += 1;
+        r
+    }
+
+    // Appends the functions a backend emitted to `out`, noting their
+    // sizes.
+    pub fn append_functions(&mut self, out: &mut String, bodies: Vec<String>) {
+        self.asm_sizes = bodies.iter().map(String::len).collect();
+        out.push_str(&bodies.concat());
+    }
+
+    // Like append_functions, for backends that lay out stack frames:
+    // each function comes with the bytes of stack it uses.
+    pub fn append_framed(&mut self, out: &mut String, emitted: Vec<(String, usize)>) {
+        let (bodies, frames) = emitted.into_iter().unzip();
+        self.frame_sizes = frames;
+        self.append_functions(out, bodies);
+    }
+}
+
+// Labels a backend makes up while emitting a function, such as for its
+// epilogue or jump tables. They are named after the function rather
+// than numbered with new_label(), so that functions can be emitted
+// independently of each other, on different threads.
+pub struct FnLabels {
+    prefix: String,
+    n: usize,
+}
+
+impl FnLabels {
+    // `local` is the assembler's prefix for local labels, such as ".L".
+    pub fn new(local: &str, func: &str) -> Self {
+        FnLabels {
+            prefix: format!("{}{}.", local, func),
+            n: 0,
+        }
+    }
+
+    pub fn new_label(&mut self) -> String {
+        self.n += 1;
+        format!("{}{}", self.prefix, self.n - 1)
+    }
+}
+
+// The source files Loc instructions refer to, in the order they first
+// appear. File i is numbered i + 1 in `.file` and `.loc` directives.
+pub fn debug_files(fns: &[Function]) -> Vec<String> {
+    let mut files: Vec<String> = vec![];
+    for ir in fns.iter().flat_map(|f| &f.ir) {
+        if let IROp::Loc(ref file, _) = ir.op {
+            if !files.contains(file) {
+                files.push(file.clone());
+            }
+        }
+    }
+    files
+}
+
+pub fn debug_file(files: &[String], file: &str) -> usize {
+    files.iter().position(|f| f == file).unwrap() + 1
+}
+
+impl Default for CodegenContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Undefined behavior checked at runtime, as selected by -fsanitize=.
+// A failed check calls a handler in runtime/ubsan.c or runtime/asan.c,
+// which prints a diagnostic and aborts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sanitize {
+    pub signed_overflow: bool, // signed-integer-overflow
+    pub divide_by_zero: bool,  // integer-divide-by-zero
+    pub shift: bool,           // shift
+    pub address: bool,         // address
+}
+
+impl Sanitize {
+    // Turns the checks in a comma-separated -fsanitize= list on or off.
+    pub fn set(&mut self, list: &str, on: bool) -> Result<(), String> {
+        for name in list.split(',') {
+            match name {
+                "undefined" => {
+                    self.signed_overflow = on;
+                    self.divide_by_zero = on;
+                    self.shift = on;
+                }
+                "signed-integer-overflow" => self.signed_overflow = on,
+                "integer-divide-by-zero" => self.divide_by_zero = on,
+                "shift" => self.shift = on,
+                "address" => self.address = on,
+                _ => return Err(format!("unsupported sanitizer: {}", name)),
+            }
+        }
+        Ok(())
+    }
+
+    // Whether any of the checks in -fsanitize=undefined is on.
+    pub fn undefined(&self) -> bool {
+        self.signed_overflow || self.divide_by_zero || self.shift
+    }
+}
+
+fn add(ctx: &mut CodegenContext, op: IROp, lhs: Option<usize>, rhs: Option<usize>) {
+    if let IROp::Label | IROp::Jmp | IROp::If | IROp::Unless | IROp::JumpTable(_) = op {
+        ctx.new_block = true;
+    }
+    ctx.code.push(IR::new(op, lhs, rhs));
 }
 
 #[derive(Clone, Debug)]
 pub enum IRType {
     Noarg,
     Reg,
-    Imm,
     Mem,
     Jmp,
     Label,
@@ -50,18 +269,112 @@ pub struct Function {
     pub name: String,
     pub ir: Vec<IR>,
     pub stacksize: usize,
+    // Whether the backend should guard the return address with a
+    // stack canary (-fstack-protector).
+    pub stack_protector: bool,
+    // Whether the backend should call mcount on entry, for gprof (-pg).
+    pub profile: bool,
+    // Size of the returned struct, for functions returning one.
+    pub struct_ret: Option<usize>,
+    // Size of the value returned, 0 for void.
+    pub ret_size: usize,
+    // Whether it returns a float or a double.
+    pub ret_float: bool,
+    // Whether the function takes "..." after its named parameters.
+    pub variadic: bool,
+    pub span: Option<Span>, // Where it is defined
+    // Whether it is static, and so not visible to other files.
+    pub is_static: bool,
 }
 
 impl Function {
-    fn new(name: String, ir: Vec<IR>, stacksize: usize) -> Self {
+    fn new(
+        name: String,
+        ir: Vec<IR>,
+        stacksize: usize,
+        stack_protector: bool,
+        profile: bool,
+        struct_ret: Option<usize>,
+        variadic: bool,
+    ) -> Self {
         Function {
             name,
             ir,
             stacksize,
+            stack_protector,
+            profile,
+            struct_ret,
+            ret_size: 8,
+            ret_float: false,
+            variadic,
+            span: None,
+            is_static: false,
+        }
+    }
+
+    // How often the function ran, if it was compiled with -fprofile-use
+    // and the profile has its first block.
+    pub fn entry_count(&self) -> Option<u64> {
+        self.ir.iter().find_map(|ir| match ir.op {
+            IROp::Count(n) => Some(n),
+            _ => None,
+        })
+    }
+
+    // Whether it computes with floating-point values, which not every
+    // backend can.
+    pub fn uses_float(&self) -> bool {
+        use self::IROp::*;
+        self.ret_float
+            || self.ir.iter().any(|ir| match ir.op {
+                Call(_, ref args, returns_float) => {
+                    returns_float || args.iter().any(|arg| matches!(arg, CallArg::Float(_)))
+                }
+                FAdd | FSub | FMul | FDiv | FEQ | FNE | FLT | FLE | I2F | F2I | FExt | FTrunc
+                | StoreFloatArg(_) | VaArgFloat => true,
+                _ => false,
+            })
+    }
+}
+
+// Where ELF backends put the functions that never ran in the profile
+// (-fprofile-use), away from the code that did, as gcc does.
+pub const COLD_SECTION: &str = ".section .text.unlikely,\"ax\",@progbits\n";
+
+// An argument of a call. Struct values are represented by their
+// address, and it's up to the backend to pass them by value the way
+// the target's calling convention says.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallArg {
+    Val(usize),           // register
+    Float(usize),         // register holding the bits of a double
+    Struct(usize, usize), // register holding the address, size
+    Ret(usize, usize),    // where to store the returned struct, size
+}
+
+// Most calls pass few enough arguments to keep them inline.
+pub type CallArgs = SmallVec<[CallArg; 6]>;
+
+impl CallArg {
+    pub fn reg(&self) -> usize {
+        match *self {
+            CallArg::Val(r) | CallArg::Float(r) | CallArg::Struct(r, _) | CallArg::Ret(r, _) => r,
+        }
+    }
+
+    pub fn with_reg(&self, r: usize) -> Self {
+        match *self {
+            CallArg::Val(_) => CallArg::Val(r),
+            CallArg::Float(_) => CallArg::Float(r),
+            CallArg::Struct(_, size) => CallArg::Struct(r, size),
+            CallArg::Ret(_, size) => CallArg::Ret(r, size),
         }
     }
 }
 
+// Comparisons and bitwise operations are spelled like 9cc's, as
+// ULT and AND.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum IROp {
     Add,
@@ -71,30 +384,77 @@ pub enum IROp {
     Mul,
     MulImm,
     Div,
+    UDiv,
     Imm,
     Bprel,
     Mov,
     Return,
-    Call(String, usize, [usize; 6]),
+    ReturnStruct, // return the struct at lhs
+    // rhs is the address of the function if the name is empty. The bool
+    // says whether it returns a float or a double.
+    Call(String, CallArgs, bool),
     Label,
     LabelAddr(String),
+    TlsAddr(String), // lhs = address of this thread's copy of a variable
     EQ,
     NE,
     LE,
     LT,
+    ULE,
+    ULT,
     AND,
     OR,
     XOR,
     SHL,
-    SHR,
+    SHR, // Logical
+    SAR, // Arithmetic
     Mod,
+    UMod,
     Neg,
+    Not,      // lhs = ~lhs
+    Sext(u8), // lhs = the low bytes of lhs, sign-extended
+    Zext(u8), // lhs = the low bytes of lhs, zero-extended
     Jmp,
     If,
     Unless,
+    JumpTable(Vec<usize>), // goto table[lhs], or to label rhs if out of range
     Load(u8),
     Store(u8),
+    Copy(usize), // *lhs = *rhs, a struct of this size
     StoreArg(u8),
+    StoreStructArg(usize), // like StoreArg, with the size of a struct
+    VaStart,               // initialize the va_list at lhs
+    // lhs = address of the next argument of the va_list at rhs. Has
+    // the size of a struct argument, or None for a scalar.
+    VaArg(Option<usize>),
+    AtomicLoad(u8),  // lhs = *rhs
+    AtomicStore(u8), // *lhs = rhs
+    AtomicAdd(u8),   // *lhs += rhs; lhs = the old value of *lhs
+    // lhs = the address of rhs bytes on the stack, aligned to 16 and
+    // freed when the function returns
+    Alloca,
+    Asm(String),
+    Comment(String),    // Source annotation for -fverbose-asm
+    Loc(String, usize), // What follows is for this file and line (-g)
+    Count(u64),         // What follows ran this many times (-fprofile-use)
+    Trap,
+    Unreachable,
+    // Floating-point values are kept as the bits of a double, and a
+    // float's is rounded to what a float can hold.
+    FAdd,
+    FSub,
+    FMul,
+    FDiv,
+    FEQ,
+    FNE,
+    FLT,
+    FLE,
+    I2F,    // lhs = the signed long in lhs, as a double
+    F2I,    // lhs = the double in lhs, truncated to a signed long
+    FExt,   // lhs = the float in the low 32 bits of lhs, as a double
+    FTrunc, // lhs = the low 32 bits hold the double in lhs, as a float
+    StoreFloatArg(u8),
+    VaArgFloat, // like VaArg, for a double
     Kill,
     Nop,
 }
@@ -115,6 +475,9 @@ impl From<TokenType> for IROp {
             TokenType::Minus => IROp::Sub,
             TokenType::Mul => IROp::Mul,
             TokenType::Div => IROp::Div,
+            TokenType::EQ => IROp::EQ,
+            TokenType::NE => IROp::NE,
+            TokenType::LE => IROp::LE,
             TokenType::LeftAngleBracket | TokenType::RightAngleBracket => IROp::LT,
             e => panic!("cannot convert: {:?}", e),
         }
@@ -134,28 +497,85 @@ impl IR {
     }
 }
 
-fn kill(r: Option<usize>) {
-    add(IROp::Kill, r, None);
+fn kill(ctx: &mut CodegenContext, r: Option<usize>) {
+    add(ctx, IROp::Kill, r, None);
 }
 
-fn label(x: Option<usize>) {
-    add(IROp::Label, x, None);
+fn label(ctx: &mut CodegenContext, x: Option<usize>) {
+    add(ctx, IROp::Label, x, None);
 }
 
-fn jmp(x: Option<usize>) {
-    add(IROp::Jmp, x, None);
+fn jmp(ctx: &mut CodegenContext, x: Option<usize>) {
+    add(ctx, IROp::Jmp, x, None);
 }
 
-fn load(ty: &Type, dst: Option<usize>, src: Option<usize>) {
-    add(IROp::Load(ty.size as u8), dst, src);
+// Struct values are represented by their address, so loading one is
+// a no-op.
+fn load(ctx: &mut CodegenContext, ty: &Type, dst: Option<usize>, src: Option<usize>) {
+    if let Ctype::Struct(_) = ty.ty {
+        if dst != src {
+            add(ctx, IROp::Mov, dst, src);
+        }
+        return;
+    }
+    let size = ty.size as u8;
+    let op = if ty.is_atomic {
+        IROp::AtomicLoad(size)
+    } else {
+        IROp::Load(size)
+    };
+    add(ctx, op, dst, src);
+    // Backends sign-extend an int and zero-extend a char.
+    match (ty.size, ty.is_unsigned) {
+        _ if matches!(ty.ty, Ctype::Float) => add(ctx, IROp::FExt, dst, None),
+        (4, true) => add(ctx, IROp::Zext(4), dst, None),
+        (1, false) => add(ctx, IROp::Sext(1), dst, None),
+        _ => {}
+    }
+}
+
+// Storing a struct copies it from the address in `src`.
+fn store(ctx: &mut CodegenContext, ty: &Type, dst: Option<usize>, src: Option<usize>) {
+    if let Ctype::Struct(_) = ty.ty {
+        add(ctx, IROp::Copy(ty.size), dst, src);
+        return;
+    }
+    // A float is held as a double, which must not be changed.
+    if matches!(ty.ty, Ctype::Float) {
+        let t = Some(ctx.new_reg());
+        add(ctx, IROp::Mov, t, src);
+        add(ctx, IROp::FTrunc, t, None);
+        add(ctx, IROp::Store(4), dst, t);
+        kill(ctx, t);
+        return;
+    }
+    let size = ty.size as u8;
+    let op = if ty.is_atomic {
+        IROp::AtomicStore(size)
+    } else {
+        IROp::Store(size)
+    };
+    add(ctx, op, dst, src);
 }
 
-fn store(ty: &Type, dst: Option<usize>, src: Option<usize>) {
-    add(IROp::Store(ty.size as u8), dst, src);
+// *addr += val, atomically, leaving the old value of *addr in addr.
+fn atomic_add(ctx: &mut CodegenContext, ty: &Type, addr: Option<usize>, val: Option<usize>) {
+    add(ctx, IROp::AtomicAdd(ty.size as u8), addr, val);
+    if ty.size == 4 && ty.is_unsigned {
+        add(ctx, IROp::Zext(4), addr, None);
+    }
 }
 
-fn store_arg(ty: &Type, bpoff: Option<usize>, argreg: Option<usize>) {
-    add(IROp::StoreArg(ty.size as u8), bpoff, argreg);
+fn store_arg(ctx: &mut CodegenContext, ty: &Type, bpoff: Option<usize>, argreg: Option<usize>) {
+    if let Ctype::Struct(_) = ty.ty {
+        add(ctx, IROp::StoreStructArg(ty.size), bpoff, argreg);
+        return;
+    }
+    if ty.is_float() {
+        add(ctx, IROp::StoreFloatArg(ty.size as u8), bpoff, argreg);
+        return;
+    }
+    add(ctx, IROp::StoreArg(ty.size as u8), bpoff, argreg);
 }
 
 // Quoted from 9cc
@@ -177,38 +597,354 @@ fn store_arg(ty: &Type, bpoff: Option<usize>, argreg: Option<usize>) {
 //
 // > This function evaluates a given node as an lvalue.
 
-fn gen_lval(node: Box<Node>) -> Option<usize> {
+fn gen_lval(ctx: &mut CodegenContext, node: Node) -> Option<usize> {
     match node.op {
-        NodeType::Deref(expr) => gen_expr(expr),
-        NodeType::Dot(ref expr, _, ref offset) => {
-            let r = gen_lval(expr.clone());
-            add(IROp::AddImm, r, Some(*offset));
+        NodeType::Deref(expr) => gen_expr(ctx, *expr),
+        NodeType::Dot(expr, _, offset) => {
+            let r = gen_lval(ctx, *expr);
+            add(ctx, IROp::AddImm, r, Some(offset));
             r
         }
         NodeType::Lvar(Scope::Local(offset)) => {
-            let r = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
-            add(IROp::Bprel, r, Some(offset));
+            let r = Some(ctx.new_reg());
+            add(ctx, IROp::Bprel, r, Some(offset));
             r
         }
-        NodeType::Gvar(name, _, _) => {
-            let r = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
-            add(IROp::LabelAddr(name), r, None);
+        NodeType::Gvar(name, _, _, is_tls) => {
+            let r = Some(ctx.new_reg());
+            if is_tls {
+                add(ctx, IROp::TlsAddr(name), r, None);
+            } else {
+                add(ctx, IROp::LabelAddr(name), r, None);
+            }
             r
         }
+        // A call returning a struct, e.g. `f().x`
+        NodeType::BinOp(TokenType::Comma, lhs, rhs) => {
+            let r = gen_expr(ctx, *lhs);
+            kill(ctx, r);
+            gen_lval(ctx, *rhs)
+        }
+        // A struct read by va_arg, e.g. `va_arg(ap, struct foo).x`
+        NodeType::Builtin(Builtin::VaArg, args) => gen_va_arg(ctx, &node.ty, args),
         _ => unreachable!(),
     }
 }
 
-fn gen_binop(ty: IROp, lhs: Box<Node>, rhs: Box<Node>) -> Option<usize> {
-    let r1 = gen_expr(lhs);
-    let r2 = gen_expr(rhs);
-    add(ty, r1, r2);
-    kill(r2);
+fn gen_binop(ctx: &mut CodegenContext, op: IROp, ty: &Type, lhs: Node, rhs: Node) -> Option<usize> {
+    let r1 = gen_expr(ctx, lhs);
+    let r2 = gen_expr(ctx, rhs);
+    gen_arith(ctx, op, ty, r1, r2);
+    kill(ctx, r2);
     r1
 }
 
+// Returns a register holding the address of a "file:line" string for
+// the current statement. Passing the location as one argument leaves
+// more registers for the expression being checked.
+fn gen_location(ctx: &mut CodegenContext) -> Option<usize> {
+    ctx.scratch.clear();
+    let _ = write!(ctx.scratch, "{}:{}", ctx.filename, ctx.line);
+    let name = match ctx.locations.get(&ctx.scratch) {
+        Some(name) => name.clone(),
+        None => {
+            let loc = ctx.scratch.clone();
+            let name = format!(".L.loc{}", ctx.globals.len());
+            let len = loc.len() + 1;
+            let ty = Type::ary_of(Type::char_ty(), len);
+            let var = Var::new_global(
+                ty,
+                name.clone(),
+                loc.clone().into_bytes(),
+                len,
+                false,
+                false,
+            );
+            ctx.globals.push(var);
+            ctx.locations.insert(loc, name.clone());
+            name
+        }
+    };
+    let r = Some(ctx.new_reg());
+    add(ctx, IROp::LabelAddr(name), r, None);
+    r
+}
+
+// Calls __r9cc_<handler>(location, operands...) unless `cond` is
+// zero. The handler doesn't return.
+fn gen_check(ctx: &mut CodegenContext, cond: Option<usize>, handler: &str, operands: &[usize]) {
+    let ok = Some(ctx.new_label());
+    add(ctx, IROp::Unless, cond, ok);
+    kill(ctx, cond);
+
+    let loc = gen_location(ctx);
+    let mut args: CallArgs = smallvec![CallArg::Val(loc.unwrap())];
+    args.extend(operands.iter().map(|&r| CallArg::Val(r)));
+    let r = Some(ctx.new_reg());
+    let name = format!("__r9cc_{}", handler);
+    add(ctx, IROp::Call(name, args, false), r, None);
+    kill(ctx, r);
+    kill(ctx, loc);
+    label(ctx, ok);
+}
+
+// Returns a register that is nonzero if `r` is outside [min, max].
+// `max < r` and `min <= r` are both true above the range and both
+// false below it.
+fn gen_out_of_range(
+    ctx: &mut CodegenContext,
+    r: Option<usize>,
+    min: i64,
+    max: i64,
+) -> Option<usize> {
+    let t = Some(ctx.new_reg());
+    let u = Some(ctx.new_reg());
+    add(ctx, IROp::Imm, t, Some(max as usize));
+    add(ctx, IROp::LT, t, r);
+    add(ctx, IROp::Imm, u, Some(min as usize));
+    add(ctx, IROp::LE, u, r);
+    add(ctx, IROp::EQ, t, u);
+    kill(ctx, u);
+    t
+}
+
+// Returns a register that is nonzero if `r` equals `val`.
+fn gen_equals(ctx: &mut CodegenContext, r: Option<usize>, val: i64) -> Option<usize> {
+    let t = Some(ctx.new_reg());
+    add(ctx, IROp::Imm, t, Some(val as usize));
+    add(ctx, IROp::EQ, t, r);
+    t
+}
+
+// The version of `op` for operands of type `ty`. Division, remainder
+// and comparisons are unsigned for unsigned integers and pointers, and
+// a right shift copies the sign bit only into signed ones. Floating
+// types have operations of their own.
+fn typed_op(op: IROp, ty: &Type) -> IROp {
+    if ty.is_float() {
+        return match op {
+            IROp::Add => IROp::FAdd,
+            IROp::Sub => IROp::FSub,
+            IROp::Mul => IROp::FMul,
+            IROp::Div => IROp::FDiv,
+            IROp::EQ => IROp::FEQ,
+            IROp::NE => IROp::FNE,
+            IROp::LT => IROp::FLT,
+            IROp::LE => IROp::FLE,
+            op => op,
+        };
+    }
+    let unsigned = ty.is_unsigned || matches!(ty.ty, Ctype::Ptr(_));
+    match op {
+        IROp::Div if unsigned => IROp::UDiv,
+        IROp::Mod if unsigned => IROp::UMod,
+        IROp::LT if unsigned => IROp::ULT,
+        IROp::LE if unsigned => IROp::ULE,
+        IROp::SAR if unsigned => IROp::SHR,
+        op => op,
+    }
+}
+
+// lhs = lhs op rhs, for values of type `ty`. An unsigned int lives
+// zero-extended in a 64-bit register, so what carries out of its 32
+// bits is cleared, and a float is rounded to what a float can hold.
+fn gen_arith(
+    ctx: &mut CodegenContext,
+    op: IROp,
+    ty: &Type,
+    lhs: Option<usize>,
+    rhs: Option<usize>,
+) {
+    let op = typed_op(op, ty);
+    if ty.is_float() {
+        let rounds = matches!(op, IROp::FAdd | IROp::FSub | IROp::FMul | IROp::FDiv);
+        add(ctx, op, lhs, rhs);
+        if rounds && matches!(ty.ty, Ctype::Float) {
+            add(ctx, IROp::FTrunc, lhs, None);
+            add(ctx, IROp::FExt, lhs, None);
+        }
+        return;
+    }
+    let wraps = matches!(op, IROp::Add | IROp::Sub | IROp::Mul | IROp::SHL);
+    gen_checked_arith(ctx, op, ty, lhs, rhs);
+    if wraps && ty.size == 4 && ty.is_unsigned {
+        add(ctx, IROp::Zext(4), lhs, None);
+    }
+}
+
+// lhs = lhs op rhs, with the checks -fsanitize asks for. Values of
+// type int live sign-extended in 64-bit registers, so a sum or product
+// that doesn't fit in an int is still exact and can be range-checked
+// after the fact. Pointer arithmetic is not checked, and neither is
+// overflow of a long, which has no wider register to be exact in, nor
+// of an unsigned type, which wraps around.
+fn gen_checked_arith(
+    ctx: &mut CodegenContext,
+    op: IROp,
+    ty: &Type,
+    lhs: Option<usize>,
+    rhs: Option<usize>,
+) {
+    let s = ctx.sanitize;
+    if !s.undefined() || !ty.is_integer() {
+        add(ctx, op, lhs, rhs);
+        return;
+    }
+    let operands = [lhs.unwrap(), rhs.unwrap()];
+    let is_long = matches!(ty.ty, Ctype::Long);
+
+    match op {
+        IROp::Add | IROp::Sub | IROp::Mul if s.signed_overflow && !is_long && !ty.is_unsigned => {
+            let handler = match op {
+                IROp::Add => "ubsan_add_overflow",
+                IROp::Sub => "ubsan_sub_overflow",
+                _ => "ubsan_mul_overflow",
+            };
+            let r = Some(ctx.new_reg());
+            add(ctx, IROp::Mov, r, lhs);
+            add(ctx, op, r, rhs);
+            let t = gen_out_of_range(ctx, r, i32::MIN as i64, i32::MAX as i64);
+            gen_check(ctx, t, handler, &operands);
+            add(ctx, IROp::Mov, lhs, r);
+            kill(ctx, r);
+        }
+        IROp::Div | IROp::Mod | IROp::UDiv | IROp::UMod => {
+            if s.divide_by_zero {
+                let t = gen_equals(ctx, rhs, 0);
+                gen_check(ctx, t, "ubsan_divrem_overflow", &operands);
+            }
+            // INT_MIN / -1
+            if s.signed_overflow && op == IROp::Div {
+                let min = if is_long { i64::MIN } else { i32::MIN as i64 };
+                let t = gen_equals(ctx, lhs, min);
+                let u = gen_equals(ctx, rhs, -1);
+                add(ctx, IROp::AND, t, u);
+                kill(ctx, u);
+                gen_check(ctx, t, "ubsan_divrem_overflow", &operands);
+            }
+            add(ctx, op, lhs, rhs);
+        }
+        IROp::SHL | IROp::SHR | IROp::SAR if s.shift => {
+            let t = gen_out_of_range(ctx, rhs, 0, if is_long { 63 } else { 31 });
+            gen_check(ctx, t, "ubsan_shift_out_of_bounds", &operands);
+            add(ctx, op, lhs, rhs);
+        }
+        _ => add(ctx, op, lhs, rhs),
+    }
+}
+
+// -fsanitize=address keeps a shadow byte for every 8 bytes of memory,
+// at (addr >> 3) + ASAN_SHADOW_OFFSET. 0 means all 8 bytes are
+// addressable, 1 to 7 that only that many leading bytes are, and a
+// negative value that none are. runtime/asan.c maps the shadow memory.
+pub const ASAN_SHADOW_OFFSET: usize = 0x7fff_8000;
+
+// r = the shadow address of r
+fn gen_shadow_addr(ctx: &mut CodegenContext, r: Option<usize>) {
+    let t = Some(ctx.new_reg());
+    add(ctx, IROp::Imm, t, Some(3));
+    add(ctx, IROp::SHR, r, t);
+    kill(ctx, t);
+    add(ctx, IROp::AddImm, r, Some(ASAN_SHADOW_OFFSET));
+}
+
+// Sets `size` shadow bytes, starting at the shadow byte of the stack
+// slot at `offset`, to `val`.
+fn gen_shadow_store(ctx: &mut CodegenContext, offset: usize, size: u8, val: i32) {
+    let r = Some(ctx.new_reg());
+    let v = Some(ctx.new_reg());
+    add(ctx, IROp::Bprel, r, Some(offset));
+    gen_shadow_addr(ctx, r);
+    add(ctx, IROp::Imm, v, Some(val as usize));
+    add(ctx, IROp::Store(size), r, v);
+    kill(ctx, v);
+    kill(ctx, r);
+}
+
+// Poisons the redzones sema left around the function's arrays on
+// entry, and unpoisons them again before it returns, since whatever
+// uses this stack memory next may not know about them.
+fn gen_redzones(ctx: &mut CodegenContext, body: Vec<IR>) -> Vec<IR> {
+    let arrays = mem::take(&mut ctx.arrays);
+    let zone = (REDZONE / 8) as u8;
+
+    for &(offset, size) in &arrays {
+        gen_shadow_store(ctx, offset + REDZONE, zone, 0xf1f1_f1f1_u32 as i32);
+        if size % 8 != 0 {
+            gen_shadow_store(ctx, offset - size / 8 * 8, 1, (size % 8) as i32);
+        }
+        gen_shadow_store(ctx, offset - roundup(size, 8), zone, 0xf3f3_f3f3_u32 as i32);
+    }
+
+    let unpoison = |ctx: &mut CodegenContext| {
+        for &(offset, size) in &arrays {
+            let mut offset = offset + REDZONE;
+            let mut n = (roundup(size, 8) + 2 * REDZONE) / 8;
+            while n > 0 {
+                let chunk = [8, 4, 1].iter().cloned().find(|&c| c <= n).unwrap();
+                gen_shadow_store(ctx, offset, chunk as u8, 0);
+                offset -= chunk * 8;
+                n -= chunk;
+            }
+        }
+    };
+
+    for ir in body {
+        if ir.op == IROp::Return || ir.op == IROp::ReturnStruct {
+            unpoison(ctx);
+        }
+        ctx.code.push(ir);
+    }
+    // The function may also return by falling off its end.
+    unpoison(ctx);
+    ctx.arrays = arrays;
+    mem::take(&mut ctx.code)
+}
+
+// Whether an lvalue is reached through a pointer, so that accessing
+// it may overrun an array.
+fn is_indirect(node: &Node) -> bool {
+    matches!(node.op, NodeType::Deref(_) | NodeType::Dot(_, _, _))
+}
+
+// Reports a load or store of a value of type `ty` at `addr` that
+// touches a redzone. An access within one 8-byte granule is fine if
+// its shadow byte is 0, or if the access ends before the first
+// unaddressable byte. Depending on whether the target extends chars
+// with zeros or with the sign bit, poison loads as a large or a
+// negative number, so check for both.
+fn gen_access_check(ctx: &mut CodegenContext, addr: Option<usize>, ty: &Type, is_store: bool) {
+    if !ctx.sanitize.address {
+        return;
+    }
+    if let Ctype::Struct(_) = ty.ty {
+        return;
+    }
+    let ok = Some(ctx.new_label());
+    let s = Some(ctx.new_reg());
+    add(ctx, IROp::Mov, s, addr);
+    gen_shadow_addr(ctx, s);
+    add(ctx, IROp::Load(1), s, s);
+    add(ctx, IROp::Unless, s, ok);
+
+    let t = Some(ctx.new_reg());
+    let u = Some(ctx.new_reg());
+    add(ctx, IROp::Mov, t, addr);
+    add(ctx, IROp::Imm, u, Some(7));
+    add(ctx, IROp::AND, t, u);
+    add(ctx, IROp::AddImm, t, Some(ty.size - 1));
+    add(ctx, IROp::LT, u, s);
+    add(ctx, IROp::LE, s, t);
+    add(ctx, IROp::OR, s, u);
+    kill(ctx, u);
+    kill(ctx, t);
+
+    let kind = if is_store { "store" } else { "load" };
+    let handler = format!("asan_report_{}{}", kind, ty.size);
+    gen_check(ctx, s, &handler, &[addr.unwrap()]);
+    label(ctx, ok);
+}
+
 fn get_inc_scale(ty: &Type) -> usize {
     match ty.ty {
         Ctype::Ptr(ref ptr_to) => ptr_to.size,
@@ -216,29 +952,139 @@ fn get_inc_scale(ty: &Type) -> usize {
     }
 }
 
-fn gen_pre_inc(ty: &Type, expr: Box<Node>, num: i32) -> i32 {
-    let addr = gen_lval(expr);
-    let val = *NUM_REGS.lock().unwrap();
-    *NUM_REGS.lock().unwrap() += 1;
-    load(ty, Some(val), addr);
-    add(
-        IROp::AddImm,
-        Some(val),
-        Some(num as usize * get_inc_scale(ty)),
-    );
-    store(ty, addr, Some(val));
-    kill(addr);
+fn gen_pre_inc(ctx: &mut CodegenContext, ty: &Type, expr: Node, num: i32) -> i32 {
+    let indirect = is_indirect(&expr);
+    let addr = gen_lval(ctx, expr);
+    if indirect {
+        gen_access_check(ctx, addr, ty, true);
+    }
+    let delta = (num as i64 * get_inc_scale(ty) as i64) as usize;
+    let val = ctx.new_reg();
+    if ty.is_atomic {
+        add(ctx, IROp::Imm, Some(val), Some(delta));
+        atomic_add(ctx, ty, addr, Some(val));
+        add(ctx, IROp::Mov, Some(val), addr);
+        add(ctx, IROp::AddImm, Some(val), Some(delta));
+    } else {
+        load(ctx, ty, Some(val), addr);
+        add(ctx, IROp::AddImm, Some(val), Some(delta));
+        store(ctx, ty, addr, Some(val));
+    }
+    kill(ctx, addr);
     val as i32
 }
 
-fn gen_post_inc(ty: &Type, expr: Box<Node>, num: i32) -> i32 {
-    let val = gen_pre_inc(ty, expr, num);
+fn gen_post_inc(ctx: &mut CodegenContext, ty: &Type, expr: Node, num: i32) -> i32 {
+    let val = gen_pre_inc(ctx, ty, expr, num);
     add(
+        ctx,
         IROp::SubImm,
         Some(val as usize),
         Some(num as usize * get_inc_scale(ty)),
     );
-    val as i32
+    val
+}
+
+// x++ and x-- of a float or a double. The old value is kept aside,
+// since subtracting 1 again might not give it back.
+fn gen_float_inc(ctx: &mut CodegenContext, ty: &Type, expr: Node, num: f64) -> Option<usize> {
+    let indirect = is_indirect(&expr);
+    let addr = gen_lval(ctx, expr);
+    if indirect {
+        gen_access_check(ctx, addr, ty, true);
+    }
+    let old = Some(ctx.new_reg());
+    let val = Some(ctx.new_reg());
+    let one = Some(ctx.new_reg());
+    load(ctx, ty, old, addr);
+    add(ctx, IROp::Mov, val, old);
+    add(ctx, IROp::Imm, one, Some(num.to_bits() as usize));
+    gen_arith(ctx, IROp::Add, ty, val, one);
+    kill(ctx, one);
+    store(ctx, ty, addr, val);
+    kill(ctx, val);
+    kill(ctx, addr);
+    old
+}
+
+// Converts the value in `r` from type `from` to type `to`, at least one
+// of which is a floating type. I2F and F2I work on signed longs, so an
+// unsigned long with its top bit set takes a detour.
+fn gen_convert(ctx: &mut CodegenContext, r: Option<usize>, from: &Type, to: &Type) {
+    let is_ulong = |ty: &Type| ty.size == 8 && ty.is_unsigned;
+    // 2^63, as a double
+    let two63 = Some(0x43e0_0000_0000_0000);
+    match (from.is_float(), to.is_float()) {
+        (true, true) => {
+            if matches!(to.ty, Ctype::Float) {
+                add(ctx, IROp::FTrunc, r, None);
+                add(ctx, IROp::FExt, r, None);
+            }
+        }
+        (false, true) => {
+            if is_ulong(from) {
+                // Halve it, keeping the low bit so that it rounds the
+                // same, and double it again once converted.
+                let big = Some(ctx.new_label());
+                let end = Some(ctx.new_label());
+                let t = Some(ctx.new_reg());
+                let u = Some(ctx.new_reg());
+                add(ctx, IROp::Mov, t, r);
+                add(ctx, IROp::Imm, u, Some(0));
+                add(ctx, IROp::LT, t, u);
+                add(ctx, IROp::If, t, big);
+                add(ctx, IROp::I2F, r, None);
+                jmp(ctx, end);
+                label(ctx, big);
+                add(ctx, IROp::Mov, t, r);
+                add(ctx, IROp::Imm, u, Some(1));
+                add(ctx, IROp::AND, t, u);
+                add(ctx, IROp::SHR, r, u);
+                add(ctx, IROp::OR, r, t);
+                add(ctx, IROp::I2F, r, None);
+                add(ctx, IROp::FAdd, r, r);
+                label(ctx, end);
+                kill(ctx, u);
+                kill(ctx, t);
+            } else {
+                add(ctx, IROp::I2F, r, None);
+            }
+            if matches!(to.ty, Ctype::Float) {
+                add(ctx, IROp::FTrunc, r, None);
+                add(ctx, IROp::FExt, r, None);
+            }
+        }
+        (true, false) => {
+            if is_ulong(to) {
+                // Take 2^63 off what doesn't fit in a signed long, and
+                // put it back as the top bit.
+                let big = Some(ctx.new_label());
+                let end = Some(ctx.new_label());
+                let t = Some(ctx.new_reg());
+                add(ctx, IROp::Imm, t, two63);
+                add(ctx, IROp::FLE, t, r);
+                add(ctx, IROp::If, t, big);
+                add(ctx, IROp::F2I, r, None);
+                jmp(ctx, end);
+                label(ctx, big);
+                add(ctx, IROp::Imm, t, two63);
+                add(ctx, IROp::FSub, r, t);
+                add(ctx, IROp::F2I, r, None);
+                add(ctx, IROp::Imm, t, Some(1 << 63));
+                add(ctx, IROp::XOR, r, t);
+                label(ctx, end);
+                kill(ctx, t);
+            } else {
+                add(ctx, IROp::F2I, r, None);
+            }
+            match (to.size, to.is_unsigned) {
+                (8, _) => {}
+                (size, true) => add(ctx, IROp::Zext(size as u8), r, None),
+                (size, false) => add(ctx, IROp::Sext(size as u8), r, None),
+            }
+        }
+        (false, false) => unreachable!(),
+    }
 }
 
 fn to_assign_op(op: &TokenType) -> IROp {
@@ -250,7 +1096,7 @@ fn to_assign_op(op: &TokenType) -> IROp {
         AddEQ => IROp::Add,
         SubEQ => IROp::Sub,
         ShlEQ => IROp::SHL,
-        ShrEQ => IROp::SHR,
+        ShrEQ => IROp::SAR,
         BitandEQ => IROp::AND,
         XorEQ => IROp::XOR,
         BitorEQ => IROp::OR,
@@ -258,313 +1104,1006 @@ fn to_assign_op(op: &TokenType) -> IROp {
     }
 }
 
-fn gen_assign_op(op: &TokenType, ty: &Type, lhs: Box<Node>, rhs: Box<Node>) -> Option<usize> {
-    let src = gen_expr(rhs);
-    let dst = gen_lval(lhs);
-    let val = Some(*NUM_REGS.lock().unwrap());
-    *NUM_REGS.lock().unwrap() += 1;
+fn gen_assign_op(
+    ctx: &mut CodegenContext,
+    op: &TokenType,
+    ty: &Type,
+    lhs: Node,
+    rhs: Node,
+) -> Option<usize> {
+    let rhs_ty = rhs.ty.clone();
+    let src = gen_expr(ctx, rhs);
+    let indirect = is_indirect(&lhs);
+    let dst = gen_lval(ctx, lhs);
+    if indirect {
+        gen_access_check(ctx, dst, ty, true);
+    }
+    // `+=` and `-=` of an _Atomic object add atomically. Sema rejects
+    // the other operators, which would take compare-and-swap.
+    if ty.is_atomic && matches!(op, TokenType::AddEQ | TokenType::SubEQ) {
+        if *op == TokenType::SubEQ {
+            add(ctx, IROp::Neg, src, None);
+        }
+        atomic_add(ctx, ty, dst, src);
+        gen_arith(ctx, IROp::Add, ty, dst, src);
+        kill(ctx, src);
+        return dst;
+    }
+    let val = Some(ctx.new_reg());
 
-    load(ty, val, dst);
-    add(to_assign_op(op), val, src);
-    kill(src);
-    store(ty, dst, val);
-    kill(dst);
+    load(ctx, ty, val, dst);
+    // Sema converted the rhs to the type the operation is done in, if
+    // that is a floating type.
+    if rhs_ty.is_float() {
+        gen_convert(ctx, val, ty, &rhs_ty);
+        gen_arith(ctx, to_assign_op(op), &rhs_ty, val, src);
+        gen_convert(ctx, val, &rhs_ty, ty);
+    } else {
+        gen_arith(ctx, to_assign_op(op), ty, val, src);
+    }
+    kill(ctx, src);
+    store(ctx, ty, dst, val);
+    kill(ctx, dst);
     val
 }
 
-fn gen_expr(node: Box<Node>) -> Option<usize> {
-    let node = *node;
-    match node.op {
-        NodeType::Num(val) => {
-            let r = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
-            add(IROp::Imm, r, Some(val as usize));
+// Returns the address of the next variadic argument, of type `ty`.
+// Where it is depends on the calling convention, so that is left to
+// the backend.
+fn gen_va_arg(ctx: &mut CodegenContext, ty: &Type, args: Vec<Node>) -> Option<usize> {
+    let ap = gen_expr(ctx, args.into_iter().next().unwrap());
+    let size = match ty.ty {
+        Ctype::Struct(_) => Some(ty.size),
+        _ => None,
+    };
+    let r = Some(ctx.new_reg());
+    if ty.is_float() {
+        add(ctx, IROp::VaArgFloat, r, ap);
+    } else {
+        add(ctx, IROp::VaArg(size), r, ap);
+    }
+    kill(ctx, ap);
+    r
+}
+
+// memcpy and memset are expanded into a byte-at-a-time loop.
+fn gen_builtin(
+    ctx: &mut CodegenContext,
+    kind: Builtin,
+    args: Vec<Node>,
+    ty: &Type,
+) -> Option<usize> {
+    match kind {
+        Builtin::Memcpy | Builtin::Memset => {
+            let mut args = args.into_iter();
+            let dst = gen_expr(ctx, args.next().unwrap());
+            let src = gen_expr(ctx, args.next().unwrap()); // fill value for memset
+            let n = gen_expr(ctx, args.next().unwrap());
+
+            // Both return dst, so advance a copy of it.
+            let p = Some(ctx.new_reg());
+            add(ctx, IROp::Mov, p, dst);
+
+            let x = Some(ctx.new_label());
+            let y = Some(ctx.new_label());
+
+            label(ctx, x);
+            add(ctx, IROp::Unless, n, y);
+            if kind == Builtin::Memcpy {
+                let r = Some(ctx.new_reg());
+                add(ctx, IROp::Load(1), r, src);
+                add(ctx, IROp::Store(1), p, r);
+                kill(ctx, r);
+                add(ctx, IROp::AddImm, src, Some(1));
+            } else {
+                add(ctx, IROp::Store(1), p, src);
+            }
+            add(ctx, IROp::AddImm, p, Some(1));
+            add(ctx, IROp::SubImm, n, Some(1));
+            jmp(ctx, x);
+            label(ctx, y);
+
+            kill(ctx, p);
+            kill(ctx, src);
+            kill(ctx, n);
+            dst
+        }
+        Builtin::Trap | Builtin::Unreachable => {
+            if kind == Builtin::Trap {
+                add(ctx, IROp::Trap, None, None);
+            } else {
+                add(ctx, IROp::Unreachable, None, None);
+            }
+            // Both are void, but every expression needs a register.
+            let r = Some(ctx.new_reg());
+            add(ctx, IROp::Imm, r, Some(0));
+            r
+        }
+        Builtin::Expect => {
+            // The hint is used where the value decides a branch (see
+            // likely()), so this is just the value.
+            let mut args = args.into_iter();
+            let r = gen_expr(ctx, args.next().unwrap());
+            let expected = gen_expr(ctx, args.next().unwrap());
+            kill(ctx, expected);
             r
         }
-        NodeType::Lvar(_) | NodeType::Dot(_, _, _) | NodeType::Gvar(_, _, _) => {
-            let r = gen_lval(Box::new(node.clone()));
-            load(&node.ty, r, r);
+        Builtin::Alloca => {
+            let n = gen_expr(ctx, args.into_iter().next().unwrap());
+            let r = Some(ctx.new_reg());
+            add(ctx, IROp::Alloca, r, n);
+            kill(ctx, n);
             r
         }
-        NodeType::Call(name, args) => {
-            let mut args_ir: [usize; 6] = [0; 6];
-            for i in 0..args.len() {
-                args_ir[i] = gen_expr(Box::new(args[i].clone())).unwrap();
+        Builtin::VaStart => {
+            let ap = gen_expr(ctx, args.into_iter().next().unwrap());
+            add(ctx, IROp::VaStart, ap, None);
+            ap
+        }
+        Builtin::VaArg => {
+            let r = gen_va_arg(ctx, ty, args);
+            load(ctx, ty, r, r);
+            r
+        }
+        Builtin::AtomicLoad | Builtin::AtomicStore | Builtin::AtomicAdd => {
+            let size = match args[0].ty.ty {
+                Ctype::Ptr(ref ty) => ty.size as u8,
+                _ => unreachable!(),
+            };
+            let mut args = args.into_iter();
+            let addr = gen_expr(ctx, args.next().unwrap());
+            if kind == Builtin::AtomicLoad {
+                let r = Some(ctx.new_reg());
+                add(ctx, IROp::AtomicLoad(size), r, addr);
+                kill(ctx, addr);
+                return r;
             }
 
-            let r = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
+            let val = gen_expr(ctx, args.next().unwrap());
+            if kind == Builtin::AtomicStore {
+                // Stores are void; return the value stored.
+                add(ctx, IROp::AtomicStore(size), addr, val);
+                kill(ctx, addr);
+                return val;
+            }
+            add(ctx, IROp::AtomicAdd(size), addr, val);
+            kill(ctx, val);
+            addr
+        }
+    }
+}
+
+// Calls `name`, or the function whose address is in `callee`, with
+// `args`, returning a value of type `ty`.
+fn gen_call(
+    ctx: &mut CodegenContext,
+    ty: &Type,
+    name: String,
+    callee: Option<usize>,
+    args: Vec<Node>,
+) -> Option<usize> {
+    // sema passes the buffer for a returned struct as the first
+    // argument.
+    let struct_ret = match ty.ty {
+        Ctype::Struct(_) => Some(ty.size),
+        _ => None,
+    };
+    let mut args_ir = CallArgs::new();
+    for (i, arg) in args.into_iter().enumerate() {
+        let ty = arg.ty.clone();
+        let r = gen_expr(ctx, arg).unwrap();
+        // A float is passed as a float.
+        if matches!(ty.ty, Ctype::Float) {
+            add(ctx, IROp::FTrunc, Some(r), None);
+        }
+        args_ir.push(match (i, struct_ret, &ty.ty) {
+            (0, Some(size), _) => CallArg::Ret(r, size),
+            (_, _, Ctype::Struct(_)) => CallArg::Struct(r, ty.size),
+            _ if ty.is_float() => CallArg::Float(r),
+            _ => CallArg::Val(r),
+        });
+    }
+
+    let r = Some(ctx.new_reg());
+
+    let regs: SmallVec<[usize; 6]> = args_ir.iter().map(|arg| arg.reg()).collect();
+    add(ctx, IROp::Call(name, args_ir, ty.is_float()), r, callee);
+    if matches!(ty.ty, Ctype::Float) {
+        add(ctx, IROp::FExt, r, None);
+    }
 
-            add(IROp::Call(name, args.len(), args_ir), r, None);
+    for reg in regs {
+        kill(ctx, Some(reg));
+    }
+    r
+}
 
-            for arg in args_ir.iter().take(args.len()) {
-                kill(Some(*arg));
+fn gen_expr(ctx: &mut CodegenContext, node: Node) -> Option<usize> {
+    match node.op {
+        NodeType::Num(val) => {
+            let r = Some(ctx.new_reg());
+            add(ctx, IROp::Imm, r, Some(val as usize));
+            r
+        }
+        NodeType::FNum(val) => {
+            let r = Some(ctx.new_reg());
+            add(ctx, IROp::Imm, r, Some(val.to_bits() as usize));
+            r
+        }
+        NodeType::Cast(expr) if node.ty.is_float() || expr.ty.is_float() => {
+            let from = expr.ty.clone();
+            let r = gen_expr(ctx, *expr);
+            gen_convert(ctx, r, &from, &node.ty);
+            r
+        }
+        // Between ints and longs. The narrower type's bits are
+        // extended the way it says.
+        NodeType::Cast(expr) => {
+            // A value is kept extended as its type says, so a cast to a
+            // type as wide or narrower extends as that type does.
+            let narrower = if node.ty.size <= expr.ty.size {
+                node.ty.clone()
+            } else {
+                expr.ty.clone()
+            };
+            let r = gen_expr(ctx, *expr);
+            match (narrower.size, narrower.is_unsigned) {
+                (8, _) => {}
+                (size, true) => add(ctx, IROp::Zext(size as u8), r, None),
+                (size, false) => add(ctx, IROp::Sext(size as u8), r, None),
             }
             r
         }
-        NodeType::Addr(expr) => gen_lval(expr),
+        NodeType::Lvar(_) | NodeType::Dot(_, _, _) | NodeType::Gvar(_, _, _, _) => {
+            let indirect = is_indirect(&node);
+            let ty = node.ty.clone();
+            let r = gen_lval(ctx, node);
+            if indirect {
+                gen_access_check(ctx, r, &ty, false);
+            }
+            load(ctx, &ty, r, r);
+            r
+        }
+        NodeType::Builtin(kind, args) => gen_builtin(ctx, kind, args, &node.ty),
+        NodeType::Call(name, args) => gen_call(ctx, &node.ty, name.to_string(), None, args),
+        // The address of the function is in a register, which the
+        // call instruction has as its rhs.
+        NodeType::CallPtr(callee, args) => {
+            let callee = gen_expr(ctx, *callee);
+            let r = gen_call(ctx, &node.ty, String::new(), callee, args);
+            kill(ctx, callee);
+            r
+        }
+        NodeType::Addr(expr) => gen_lval(ctx, *expr),
         NodeType::Deref(expr) => {
-            let r = gen_expr(expr);
-            load(&node.ty, r, r);
+            let r = gen_expr(ctx, *expr);
+            gen_access_check(ctx, r, &node.ty, false);
+            load(ctx, &node.ty, r, r);
             r
         }
-        NodeType::StmtExpr(body) => {
-            let orig_label = *RETURN_LABEL.lock().unwrap();
-            let orig_reg = *RETURN_REG.lock().unwrap();
-            *RETURN_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
-            *NLABEL.lock().unwrap() += 1;
-            let r = *NUM_REGS.lock().unwrap();
-            *NUM_REGS.lock().unwrap() += 1;
-            *RETURN_REG.lock().unwrap() = r;
+        NodeType::StmtExpr(mut body) => {
+            // The last expression is the value, like a `return`.
+            if let NodeType::CompStmt(ref mut stmts) = body.op {
+                if let Some(last) = stmts.last_mut() {
+                    last.op = match mem::replace(&mut last.op, NodeType::Null) {
+                        NodeType::ExprStmt(expr) => NodeType::Return(expr),
+                        op => op,
+                    };
+                }
+            }
+            let orig_label = ctx.return_label;
+            let orig_reg = ctx.return_reg;
+            ctx.return_label = ctx.new_label();
+            let r = ctx.new_reg();
+            ctx.return_reg = r;
 
-            gen_stmt(*body);
-            label(Some(*RETURN_LABEL.lock().unwrap()));
+            gen_stmt(ctx, *body);
+            label(ctx, Some(ctx.return_label));
 
-            *RETURN_LABEL.lock().unwrap() = orig_label;
-            *RETURN_REG.lock().unwrap() = orig_reg;
+            ctx.return_label = orig_label;
+            ctx.return_reg = orig_reg;
             Some(r)
         }
         NodeType::BinOp(op, lhs, rhs) => {
             use self::TokenType::*;
             match op {
                 Equal => {
-                    let rhs = gen_expr(rhs);
-                    let lhs = gen_lval(lhs);
-                    store(&node.ty, lhs, rhs);
-                    kill(lhs);
+                    let rhs = gen_expr(ctx, *rhs);
+                    let indirect = is_indirect(&lhs);
+                    let lhs = gen_lval(ctx, *lhs);
+                    if indirect {
+                        gen_access_check(ctx, lhs, &node.ty, true);
+                    }
+                    store(ctx, &node.ty, lhs, rhs);
+                    kill(ctx, lhs);
                     rhs
                 }
-                Plus => gen_binop(IROp::Add, lhs, rhs),
-                Minus => gen_binop(IROp::Sub, lhs, rhs),
+                Plus => gen_binop(ctx, IROp::Add, &node.ty, *lhs, *rhs),
+                Minus => gen_binop(ctx, IROp::Sub, &node.ty, *lhs, *rhs),
                 Logand => {
-                    let x = Some(*NLABEL.lock().unwrap());
-                    *NLABEL.lock().unwrap() += 1;
-
-                    let r1 = gen_expr(lhs);
-                    add(IROp::Unless, r1, x);
-                    let r2 = gen_expr(rhs);
-                    add(IROp::Mov, r1, r2);
-                    kill(r2);
-                    add(IROp::Unless, r1, x);
-                    add(IROp::Imm, r1, Some(1));
-                    label(x);
+                    let x = Some(ctx.new_label());
+
+                    let r1 = gen_expr(ctx, *lhs);
+                    add(ctx, IROp::Unless, r1, x);
+                    let r2 = gen_expr(ctx, *rhs);
+                    add(ctx, IROp::Mov, r1, r2);
+                    kill(ctx, r2);
+                    add(ctx, IROp::Unless, r1, x);
+                    add(ctx, IROp::Imm, r1, Some(1));
+                    label(ctx, x);
                     r1
                 }
                 Logor => {
-                    let x = Some(*NLABEL.lock().unwrap());
-                    *NLABEL.lock().unwrap() += 1;
-                    let y = Some(*NLABEL.lock().unwrap());
-                    *NLABEL.lock().unwrap() += 1;
-
-                    let r1 = gen_expr(lhs);
-                    add(IROp::Unless, r1, x);
-                    add(IROp::Imm, r1, Some(1));
-                    jmp(y);
-                    label(x);
-
-                    let r2 = gen_expr(rhs);
-                    add(IROp::Mov, r1, r2);
-                    kill(r2);
-                    add(IROp::Unless, r1, y);
-                    add(IROp::Imm, r1, Some(1));
-                    label(y);
+                    let x = Some(ctx.new_label());
+                    let y = Some(ctx.new_label());
+
+                    let r1 = gen_expr(ctx, *lhs);
+                    add(ctx, IROp::Unless, r1, x);
+                    add(ctx, IROp::Imm, r1, Some(1));
+                    jmp(ctx, y);
+                    label(ctx, x);
+
+                    let r2 = gen_expr(ctx, *rhs);
+                    add(ctx, IROp::Mov, r1, r2);
+                    kill(ctx, r2);
+                    add(ctx, IROp::Unless, r1, y);
+                    add(ctx, IROp::Imm, r1, Some(1));
+                    label(ctx, y);
                     r1
                 }
                 MulEQ | DivEQ | ModEQ | AddEQ | SubEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ
-                | BitorEQ => gen_assign_op(&op, &node.ty, lhs, rhs),
-                EQ => gen_binop(IROp::EQ, lhs, rhs),
-                NE => gen_binop(IROp::NE, lhs, rhs),
-                LE => gen_binop(IROp::LE, lhs, rhs),
-                And => gen_binop(IROp::AND, lhs, rhs),
-                VerticalBar => gen_binop(IROp::OR, lhs, rhs),
-                Hat => gen_binop(IROp::XOR, lhs, rhs),
-                SHL => gen_binop(IROp::SHL, lhs, rhs),
-                SHR => gen_binop(IROp::SHR, lhs, rhs),
-                Mod => gen_binop(IROp::Mod, lhs, rhs),
+                | BitorEQ => gen_assign_op(ctx, &op, &node.ty, *lhs, *rhs),
+                // Whether a comparison is signed, or of floating
+                // values, depends on what it compares.
+                EQ | NE | LE | LeftAngleBracket | RightAngleBracket => {
+                    let ty = lhs.ty.clone();
+                    gen_binop(ctx, IROp::from(op), &ty, *lhs, *rhs)
+                }
+                And => gen_binop(ctx, IROp::AND, &node.ty, *lhs, *rhs),
+                VerticalBar => gen_binop(ctx, IROp::OR, &node.ty, *lhs, *rhs),
+                Hat => gen_binop(ctx, IROp::XOR, &node.ty, *lhs, *rhs),
+                SHL => gen_binop(ctx, IROp::SHL, &node.ty, *lhs, *rhs),
+                SHR => gen_binop(ctx, IROp::SAR, &node.ty, *lhs, *rhs),
+                Mod => gen_binop(ctx, IROp::Mod, &node.ty, *lhs, *rhs),
                 Comma => {
-                    kill(gen_expr(lhs));
-                    gen_expr(rhs)
+                    let r = gen_expr(ctx, *lhs);
+                    kill(ctx, r);
+                    gen_expr(ctx, *rhs)
                 }
-                _ => gen_binop(IROp::from(op), lhs, rhs),
+                _ => gen_binop(ctx, IROp::from(op), &node.ty, *lhs, *rhs),
             }
         }
+        // Flipping the sign bit also negates zeros and NaNs.
+        NodeType::Neg(expr) if node.ty.is_float() => {
+            let r = gen_expr(ctx, *expr);
+            let t = Some(ctx.new_reg());
+            add(ctx, IROp::Imm, t, Some(1 << 63));
+            add(ctx, IROp::XOR, r, t);
+            kill(ctx, t);
+            r
+        }
         NodeType::Neg(expr) => {
-            let r = gen_expr(expr);
-            add(IROp::Neg, r, None);
+            let r = gen_expr(ctx, *expr);
+            let ty = &node.ty;
+            if ctx.sanitize.signed_overflow && ty.is_integer() && !ty.is_unsigned {
+                let min = match ty.ty {
+                    Ctype::Long => i64::MIN,
+                    _ => i32::MIN as i64,
+                };
+                let t = gen_equals(ctx, r, min);
+                gen_check(ctx, t, "ubsan_negate_overflow", &[r.unwrap()]);
+            }
+            add(ctx, IROp::Neg, r, None);
+            if ty.size == 4 && ty.is_unsigned {
+                add(ctx, IROp::Zext(4), r, None);
+            }
+            r
+        }
+        NodeType::Tilde(expr) => {
+            let r = gen_expr(ctx, *expr);
+            add(ctx, IROp::Not, r, None);
+            if node.ty.size == 4 && node.ty.is_unsigned {
+                add(ctx, IROp::Zext(4), r, None);
+            }
             r
         }
-        NodeType::PostInc(expr) => Some(gen_post_inc(&node.ty, expr, 1) as usize),
-        NodeType::PostDec(expr) => Some(gen_post_inc(&node.ty, expr, -1) as usize),
+        NodeType::PostInc(expr) if node.ty.is_float() => gen_float_inc(ctx, &node.ty, *expr, 1.0),
+        NodeType::PostDec(expr) if node.ty.is_float() => gen_float_inc(ctx, &node.ty, *expr, -1.0),
+        NodeType::PostInc(expr) => Some(gen_post_inc(ctx, &node.ty, *expr, 1) as usize),
+        NodeType::PostDec(expr) => Some(gen_post_inc(ctx, &node.ty, *expr, -1) as usize),
         NodeType::Ternary(cond, then, els) => {
             //      cond then els  then
             // return 1 ? 3 : 5; => 3
-            let x = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let y = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let r = gen_expr(cond);
-
-            add(IROp::Unless, r, x);
-            let r2 = gen_expr(then);
-            add(IROp::Mov, r, r2);
-            kill(r2);
-            jmp(y);
-
-            label(x);
-            let r3 = gen_expr(els);
-            add(IROp::Mov, r, r3);
-            kill(r3);
-            label(y);
+            let x = Some(ctx.new_label());
+            let y = Some(ctx.new_label());
+            let r = gen_expr(ctx, *cond);
+
+            add(ctx, IROp::Unless, r, x);
+            let r2 = gen_expr(ctx, *then);
+            add(ctx, IROp::Mov, r, r2);
+            kill(ctx, r2);
+            jmp(ctx, y);
+
+            label(ctx, x);
+            let r3 = gen_expr(ctx, *els);
+            add(ctx, IROp::Mov, r, r3);
+            kill(ctx, r3);
+            label(ctx, y);
             r
         }
         NodeType::Exclamation(expr) => {
-            let lhs = gen_expr(expr);
-            let rhs = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
-            add(IROp::Imm, rhs, Some(0));
-            add(IROp::EQ, lhs, rhs);
-            kill(rhs);
+            let lhs = gen_expr(ctx, *expr);
+            let rhs = Some(ctx.new_reg());
+            add(ctx, IROp::Imm, rhs, Some(0));
+            add(ctx, IROp::EQ, lhs, rhs);
+            kill(ctx, rhs);
             lhs
         }
         e => unreachable!("{:?}", e),
     }
 }
 
-fn gen_stmt(node: Node) {
+// A switch is lowered to a jump table if its case values are dense
+// enough, and to a balanced tree of comparisons otherwise. Either way,
+// control ends up at one of the case labels or at `default`.
+fn gen_switch(
+    ctx: &mut CodegenContext,
+    r: Option<usize>,
+    mut cases: Vec<(i64, usize)>,
+    default: usize,
+) {
+    cases.sort();
+    if cases.is_empty() {
+        jmp(ctx, Some(default));
+        return;
+    }
+
+    // The table starts at the smallest value, which is subtracted as an
+    // immediate, so it must fit in 32 bits.
+    let min = cases[0].0;
+    let max = cases[cases.len() - 1].0;
+    let range = max as i128 - min as i128 + 1;
+    if cases.len() >= 4 && range <= 3 * cases.len() as i128 && i32::try_from(min).is_ok() {
+        let mut table = vec![default; range as usize];
+        for (val, label) in cases {
+            table[(val - min) as usize] = label;
+        }
+        add(ctx, IROp::SubImm, r, Some(min as i32 as usize));
+        add(ctx, IROp::JumpTable(table), r, Some(default));
+        return;
+    }
+    gen_case_tree(ctx, r, &cases, default);
+}
+
+// if (r == val) goto label;
+fn gen_case_cmp(ctx: &mut CodegenContext, r: Option<usize>, val: i64, label: usize) {
+    let t = Some(ctx.new_reg());
+    add(ctx, IROp::Imm, t, Some(val as usize));
+    add(ctx, IROp::EQ, t, r);
+    add(ctx, IROp::If, t, Some(label));
+    kill(ctx, t);
+}
+
+// `cases` must be sorted by value.
+fn gen_case_tree(
+    ctx: &mut CodegenContext,
+    r: Option<usize>,
+    cases: &[(i64, usize)],
+    default: usize,
+) {
+    if cases.len() <= 3 {
+        for &(val, target) in cases {
+            gen_case_cmp(ctx, r, val, target);
+        }
+        jmp(ctx, Some(default));
+        return;
+    }
+
+    let mid = cases.len() / 2;
+    let (val, target) = cases[mid];
+    gen_case_cmp(ctx, r, val, target);
+
+    // if (r < val) goto left;
+    let left = Some(ctx.new_label());
+    let t = Some(ctx.new_reg());
+    let u = Some(ctx.new_reg());
+    add(ctx, IROp::Mov, t, r);
+    add(ctx, IROp::Imm, u, Some(val as usize));
+    add(ctx, IROp::LT, t, u);
+    kill(ctx, u);
+    add(ctx, IROp::If, t, left);
+    kill(ctx, t);
+
+    gen_case_tree(ctx, r, &cases[mid + 1..], default);
+    label(ctx, left);
+    gen_case_tree(ctx, r, &cases[..mid], default);
+}
+
+// Name of the counters of the file being compiled with --coverage.
+fn counters_name(ctx: &CodegenContext) -> String {
+    let path = ctx.coverage.as_ref().unwrap();
+    let mangled: String = path
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("__r9cc_cov_{}", mangled)
+}
+
+// Registers the file's counters with runtime/coverage.c the first time
+// one of its functions runs. The runtime sets counter 0 when it does,
+// and writes out the others when the program exits.
+fn gen_coverage_init(ctx: &mut CodegenContext) {
+    let skip = Some(ctx.new_label());
+    let c = ctx.new_reg();
+    let t = Some(ctx.new_reg());
+    add(ctx, IROp::LabelAddr(counters_name(ctx)), Some(c), None);
+    add(ctx, IROp::Load(8), t, Some(c));
+    add(ctx, IROp::If, t, skip);
+    kill(ctx, t);
+
+    let d = ctx.new_reg();
+    add(ctx, IROp::LabelAddr(".L.cov".into()), Some(d), None);
+    let r = Some(ctx.new_reg());
+    let args = smallvec![CallArg::Val(c), CallArg::Val(d)];
+    add(
+        ctx,
+        IROp::Call("__r9cc_cov_register".into(), args, false),
+        r,
+        None,
+    );
+    kill(ctx, r);
+    kill(ctx, Some(d));
+    label(ctx, skip);
+    kill(ctx, Some(c));
+}
+
+// Counts how often the basic block starting at the current statement
+// runs (--coverage).
+fn gen_counter(ctx: &mut CodegenContext) {
+    ctx.counters.push(format!("{}:{}", ctx.filename, ctx.line));
+    let r = Some(ctx.new_reg());
+    let t = Some(ctx.new_reg());
+    add(ctx, IROp::LabelAddr(counters_name(ctx)), r, None);
+    add(ctx, IROp::AddImm, r, Some(ctx.counters.len() * 8));
+    add(ctx, IROp::Load(8), t, r);
+    add(ctx, IROp::AddImm, t, Some(1));
+    add(ctx, IROp::Store(8), r, t);
+    kill(ctx, t);
+    kill(ctx, r);
+}
+
+// Defines the counters, and the "file:line" of each one as a string
+// with one per line, for runtime/coverage.c to write out.
+fn add_coverage_globals(ctx: &mut CodegenContext) {
+    let len = (ctx.counters.len() + 1) * 8;
+    let ty = Type::ary_of(Type::ptr_to(Type::void_ty()), ctx.counters.len() + 1);
+    let var = Var::new_global(ty, counters_name(ctx), vec![], len, false, false);
+    ctx.globals.push(var);
+
+    let desc: String = ctx.counters.iter().map(|c| format!("{}\n", c)).collect();
+    let len = desc.len() + 1;
+    let ty = Type::ary_of(Type::char_ty(), len);
+    let var = Var::new_global(ty, ".L.cov".into(), desc.into_bytes(), len, false, false);
+    ctx.globals.push(var);
+}
+
+fn gen_stmt(ctx: &mut CodegenContext, node: Node) {
+    if let Some(ref span) = node.span {
+        if span.filename != ctx.filename {
+            ctx.filename.clone_from(&span.filename);
+            if ctx.profile_use.is_some() {
+                ctx.profile_key = Profile::key(&ctx.filename);
+            }
+        }
+        ctx.line = span.line;
+    }
+    if ctx.verbose_asm {
+        if let Some(ref span) = node.span {
+            // Statements sharing a line, such as a case label and its
+            // body, get a single comment.
+            ctx.scratch.clear();
+            let _ = write!(ctx.scratch, "{}", span);
+            match node.op {
+                NodeType::Null | NodeType::CompStmt(_) => (),
+                _ if ctx.scratch == ctx.last_comment => (),
+                _ => {
+                    ctx.last_comment.clone_from(&ctx.scratch);
+                    add(ctx, IROp::Comment(ctx.scratch.clone()), None, None);
+                }
+            }
+        }
+    }
+    if ctx.debug_info {
+        if let Some(ref span) = node.span {
+            let same =
+                |(file, line): &(String, usize)| *file == span.filename && *line == span.line;
+            match node.op {
+                NodeType::Null | NodeType::CompStmt(_) => (),
+                _ if ctx.last_loc.as_ref().is_some_and(same) => (),
+                _ => {
+                    ctx.last_loc = Some((span.filename.clone(), span.line));
+                    add(ctx, IROp::Loc(span.filename.clone(), span.line), None, None);
+                }
+            }
+        }
+    }
+    if ctx.new_block {
+        match node.op {
+            NodeType::Null
+            | NodeType::CompStmt(_)
+            | NodeType::Case(_, _)
+            | NodeType::Default(_) => (),
+            _ => {
+                ctx.new_block = false;
+                let profile = ctx.profile_use.as_ref();
+                if let Some(n) = profile.and_then(|p| p.count(&ctx.profile_key, ctx.line)) {
+                    add(ctx, IROp::Count(n), None, None);
+                }
+                if ctx.coverage.is_some() {
+                    gen_counter(ctx);
+                }
+            }
+        }
+    }
+
     match node.op {
-        NodeType::Null => return,
+        NodeType::Null => (),
+        NodeType::Asm(text) => add(ctx, IROp::Asm(text), None, None),
         NodeType::Vardef(_, init_may, Scope::Local(offset)) => {
+            if let Ctype::Ary(_, _) = node.ty.ty {
+                ctx.arrays.push((offset, node.ty.size));
+            }
             if let Some(init) = init_may {
-                let rhs = gen_expr(init);
-                let lhs = Some(*NUM_REGS.lock().unwrap());
-                *NUM_REGS.lock().unwrap() += 1;
-                add(IROp::Bprel, lhs, Some(offset));
-                store(&node.ty, lhs, rhs);
-                kill(lhs);
-                kill(rhs);
+                let rhs = gen_expr(ctx, *init);
+                let lhs = Some(ctx.new_reg());
+                add(ctx, IROp::Bprel, lhs, Some(offset));
+                store(ctx, &node.ty, lhs, rhs);
+                kill(ctx, lhs);
+                kill(ctx, rhs);
             }
-            return;
         }
         NodeType::If(cond, then, els_may) => {
+            // Statement expressions keep registers live around their
+            // statements, so those can't be moved out of line.
+            let hint = if ctx.layout && ctx.return_label == 0 {
+                likely(ctx, &cond, &then, els_may.as_deref())
+            } else {
+                None
+            };
+            match hint {
+                // The then branch goes out of line, and the rest falls
+                // through.
+                Some(false) => {
+                    let x = Some(ctx.new_label());
+                    let y = Some(ctx.new_label());
+                    let r = gen_expr(ctx, *cond);
+                    add(ctx, IROp::If, r, x);
+                    kill(ctx, r);
+                    if let Some(els) = els_may {
+                        gen_stmt(ctx, *els);
+                    }
+                    label(ctx, y);
+                    gen_cold(ctx, x, *then, y);
+                    return;
+                }
+                // The else branch does, and the then branch falls
+                // through to what follows the if.
+                Some(true) if els_may.is_some() => {
+                    let x = Some(ctx.new_label());
+                    let y = Some(ctx.new_label());
+                    let r = gen_expr(ctx, *cond);
+                    add(ctx, IROp::Unless, r, x);
+                    kill(ctx, r);
+                    gen_stmt(ctx, *then);
+                    label(ctx, y);
+                    gen_cold(ctx, x, *els_may.unwrap(), y);
+                    return;
+                }
+                _ => (),
+            }
+
             if let Some(els) = els_may {
-                let x = Some(*NLABEL.lock().unwrap());
-                *NLABEL.lock().unwrap() += 1;
-                let y = Some(*NLABEL.lock().unwrap());
-                *NLABEL.lock().unwrap() += 1;
-                let r = gen_expr(cond.clone());
-                add(IROp::Unless, r, x);
-                kill(r);
-                gen_stmt(*then.clone());
-                jmp(y);
-                label(x);
-                gen_stmt(*els);
-                label(y);
+                let x = Some(ctx.new_label());
+                let y = Some(ctx.new_label());
+                let r = gen_expr(ctx, *cond);
+                add(ctx, IROp::Unless, r, x);
+                kill(ctx, r);
+                gen_stmt(ctx, *then);
+                jmp(ctx, y);
+                label(ctx, x);
+                gen_stmt(ctx, *els);
+                label(ctx, y);
                 return;
             }
 
-            let x = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let r = gen_expr(cond);
-            add(IROp::Unless, r, x);
-            kill(r);
-            gen_stmt(*then);
-            label(x);
+            let x = Some(ctx.new_label());
+            let r = gen_expr(ctx, *cond);
+            add(ctx, IROp::Unless, r, x);
+            kill(ctx, r);
+            gen_stmt(ctx, *then);
+            label(ctx, x);
+        }
+        // With the condition at the bottom, each iteration takes one
+        // branch rather than two.
+        NodeType::For(init, cond, inc, body) if ctx.layout && !cond.is_null() => {
+            let x = Some(ctx.new_label());
+            let y = Some(ctx.new_label());
+            let orig = ctx.break_label;
+            ctx.break_label = ctx.new_label();
+            let line = ctx.line;
+
+            gen_stmt(ctx, *init);
+            jmp(ctx, y);
+            label(ctx, x);
+            gen_stmt(ctx, *body);
+            ctx.line = line;
+            if !inc.is_null() {
+                gen_stmt(ctx, *inc);
+            }
+            label(ctx, y);
+            let r = gen_expr(ctx, *cond);
+            add(ctx, IROp::If, r, x);
+            kill(ctx, r);
+            label(ctx, Some(ctx.break_label));
+            ctx.break_label = orig;
         }
         NodeType::For(init, cond, inc, body) => {
-            let x = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let y = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let orig = *BREAK_LABEL.lock().unwrap();
-            *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
-            *NLABEL.lock().unwrap() += 1;
-
-            gen_stmt(*init);
-            label(x);
+            let x = Some(ctx.new_label());
+            let y = Some(ctx.new_label());
+            let orig = ctx.break_label;
+            ctx.break_label = ctx.new_label();
+            let line = ctx.line;
+
+            gen_stmt(ctx, *init);
+            label(ctx, x);
             if !cond.is_null() {
-                let r2 = gen_expr(cond);
-                add(IROp::Unless, r2, y);
-                kill(r2);
+                let r2 = gen_expr(ctx, *cond);
+                add(ctx, IROp::Unless, r2, y);
+                kill(ctx, r2);
             }
-            gen_stmt(*body);
+            gen_stmt(ctx, *body);
             if !inc.is_null() {
-                gen_stmt(*inc);
+                // The increment is on the line of the "for", not on
+                // the last line of the body.
+                ctx.line = line;
+                gen_stmt(ctx, *inc);
             }
-            jmp(x);
-            label(y);
-            label(Some(*BREAK_LABEL.lock().unwrap()));
-            *BREAK_LABEL.lock().unwrap() = orig;
+            jmp(ctx, x);
+            label(ctx, y);
+            label(ctx, Some(ctx.break_label));
+            ctx.break_label = orig;
         }
         NodeType::DoWhile(body, cond) => {
-            let x = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let orig = *BREAK_LABEL.lock().unwrap();
-            *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
-            *NLABEL.lock().unwrap() += 1;
-            label(x);
-            gen_stmt(*body);
-            let r = gen_expr(cond);
-            add(IROp::If, r, x);
-            kill(r);
-            label(Some(*BREAK_LABEL.lock().unwrap()));
-            *BREAK_LABEL.lock().unwrap() = orig;
+            let x = Some(ctx.new_label());
+            let orig = ctx.break_label;
+            ctx.break_label = ctx.new_label();
+            label(ctx, x);
+            gen_stmt(ctx, *body);
+            let r = gen_expr(ctx, *cond);
+            add(ctx, IROp::If, r, x);
+            kill(ctx, r);
+            label(ctx, Some(ctx.break_label));
+            ctx.break_label = orig;
+        }
+        NodeType::Switch(cond, body, vals, has_default) => {
+            let r = gen_expr(ctx, *cond);
+            let labels: Vec<usize> = vals.iter().map(|_| ctx.new_label()).collect();
+            let break_label = ctx.new_label();
+            let default = if has_default {
+                ctx.new_label()
+            } else {
+                break_label
+            };
+            let vals = vals.iter().map(|val| match val.op {
+                NodeType::Num(val) => val,
+                _ => unreachable!(),
+            });
+            let cases = vals.zip(labels.iter().cloned()).collect();
+            gen_switch(ctx, r, cases, default);
+            kill(ctx, r);
+
+            let orig_break = mem::replace(&mut ctx.break_label, break_label);
+            let orig_cases = mem::replace(&mut ctx.case_labels, labels);
+            let orig_default = mem::replace(&mut ctx.default_label, default);
+            gen_stmt(ctx, *body);
+            label(ctx, Some(break_label));
+            ctx.break_label = orig_break;
+            ctx.case_labels = orig_cases;
+            ctx.default_label = orig_default;
+        }
+        NodeType::Case(idx, body) => {
+            let x = Some(ctx.case_labels[idx]);
+            label(ctx, x);
+            gen_stmt(ctx, *body);
+        }
+        NodeType::Default(body) => {
+            let x = Some(ctx.default_label);
+            label(ctx, x);
+            gen_stmt(ctx, *body);
         }
         NodeType::Break => {
-            let break_label = *BREAK_LABEL.lock().unwrap();
+            let break_label = ctx.break_label;
             if break_label == 0 {
-                panic!("stray 'break' statement");
+                diagnostic::error("stray 'break' statement".into(), None);
             }
-            jmp(Some(break_label));
+            jmp(ctx, Some(break_label));
         }
         NodeType::Return(expr) => {
-            let r = gen_expr(expr);
+            let is_float = matches!(expr.ty.ty, Ctype::Float);
+            let r = gen_expr(ctx, *expr);
 
             // Statement expression (GNU extension)
-            if *RETURN_LABEL.lock().unwrap() != 0 {
-                add(IROp::Mov, Some(*RETURN_REG.lock().unwrap()), r);
-                kill(r);
-                jmp(Some(*RETURN_LABEL.lock().unwrap()));
+            if ctx.return_label != 0 {
+                add(ctx, IROp::Mov, Some(ctx.return_reg), r);
+                kill(ctx, r);
+                jmp(ctx, Some(ctx.return_label));
                 return;
             }
 
-            add(IROp::Return, r, None);
-            kill(r);
+            // A float is returned as a float.
+            if is_float {
+                add(ctx, IROp::FTrunc, r, None);
+            }
+
+            if ctx.struct_ret.is_some() {
+                add(ctx, IROp::ReturnStruct, r, None);
+            } else {
+                add(ctx, IROp::Return, r, None);
+            }
+            kill(ctx, r);
         }
         NodeType::ExprStmt(expr) => {
-            let r = gen_expr(expr);
-            kill(r);
+            let r = gen_expr(ctx, *expr);
+            kill(ctx, r);
         }
         NodeType::VecStmt(stmts) | NodeType::CompStmt(stmts) => {
             for n in stmts {
-                gen_stmt(n);
+                gen_stmt(ctx, n);
             }
         }
         e => panic!("unknown node: {:?}", e),
     }
 }
 
-pub fn gen_ir(nodes: Vec<Node>) -> Vec<Function> {
+// Which way an if statement is likely to go: whether `cond` is likely
+// to be true, as __builtin_expect or, with -fprofile-use, how often
+// each branch ran says. The branch that ran less is the unlikely one.
+// None if nothing says.
+fn likely(ctx: &CodegenContext, cond: &Node, then: &Node, els: Option<&Node>) -> Option<bool> {
+    if let NodeType::Builtin(Builtin::Expect, ref args) = cond.op {
+        if let NodeType::Num(expected) = args[1].op {
+            return Some(expected != 0);
+        }
+    }
+
+    // The if itself has a count if a block starts on its line.
+    let profile = ctx.profile_use.as_ref()?;
+    let total = profile.count(&ctx.profile_key, ctx.line);
+    if total == Some(0) {
+        return None;
+    }
+    // A count on the line of the if is for the if, not for a branch.
+    let count = |line: usize| match line {
+        line if line == ctx.line => None,
+        line => profile.count(&ctx.profile_key, line),
+    };
+    let then = first_line(then).and_then(count);
+    // Without an else branch, what doesn't run the then branch falls
+    // through to what follows the if.
+    let els = match els {
+        Some(els) => first_line(els).and_then(count),
+        None => total.zip(then).map(|(total, n)| total.saturating_sub(n)),
+    };
+    match (then, els) {
+        (Some(0), _) => Some(false),
+        (_, Some(0)) => Some(true),
+        (Some(then), Some(els)) if then != els => Some(then > els),
+        _ => None,
+    }
+}
+
+// The line of the first statement in `node`, which its count is for.
+fn first_line(node: &Node) -> Option<usize> {
+    match node.op {
+        NodeType::CompStmt(ref stmts) | NodeType::VecStmt(ref stmts) => first_line(stmts.first()?),
+        _ => node.span.as_ref().map(|span| span.line),
+    }
+}
+
+// Generates `stmt`, labeled `x`, out of line: after the rest of the
+// function, from where it jumps back to `join`.
+fn gen_cold(ctx: &mut CodegenContext, x: Option<usize>, stmt: Node, join: Option<usize>) {
+    // What comes before it in the end isn't what comes before it now.
+    let last_comment = mem::take(&mut ctx.last_comment);
+    let last_loc = ctx.last_loc.take();
+
+    let start = ctx.code.len();
+    label(ctx, x);
+    gen_stmt(ctx, stmt);
+    jmp(ctx, join);
+    let mut code = ctx.code.split_off(start);
+    ctx.cold.append(&mut code);
+
+    ctx.last_comment = last_comment;
+    ctx.last_loc = last_loc;
+}
+
+// Like gcc, -fstack-protector only protects functions that have
+// local arrays, since those are what buffer overflows usually hit.
+pub fn gen_ir(ctx: &mut CodegenContext, nodes: Vec<Node>, stack_protector: bool) -> Vec<Function> {
     let mut v = vec![];
     for node in nodes {
         match node.op {
-            NodeType::Func(name, args, body, stacksize) => {
-                *CODE.lock().unwrap() = vec![];
-                // *NUM_REGS.lock().unwrap() = 0;
+            NodeType::Func(name, args, body, stacksize, is_static) => {
+                ice::set_function(&name.as_str());
+                ctx.arrays.clear();
+                ctx.last_loc = None;
+                let variadic;
+                let ret_size;
+                let ret_float;
+                ctx.struct_ret = match node.ty.ty {
+                    Ctype::Func(ref ret, _, is_variadic, _) => {
+                        variadic = is_variadic;
+                        ret_size = ret.size;
+                        ret_float = ret.is_float();
+                        match ret.ty {
+                            Ctype::Struct(_) => Some(ret.size),
+                            _ => None,
+                        }
+                    }
+                    _ => unreachable!(),
+                };
 
                 for (i, arg) in args.iter().enumerate() {
                     if let NodeType::Vardef(_, _, Scope::Local(offset)) = arg.op {
-                        store_arg(&arg.ty, Some(offset), Some(i));
+                        store_arg(ctx, &arg.ty, Some(offset), Some(i));
                     } else {
                         unreachable!();
                     }
                 }
-                gen_stmt(*body);
+                // Functions in a file tend to be alike in size.
+                ctx.code.reserve(ctx.last_len);
+                ctx.new_block = true;
+                if ctx.coverage.is_some() {
+                    gen_coverage_init(ctx);
+                }
+                gen_stmt(ctx, *body);
+                if !ctx.cold.is_empty() {
+                    // Falling off the end skips the code out of line.
+                    let end = Some(ctx.new_label());
+                    jmp(ctx, end);
+                    ctx.code.append(&mut ctx.cold);
+                    label(ctx, end);
+                }
+                ctx.last_len = ctx.code.len();
 
-                v.push(Function::new(name, CODE.lock().unwrap().clone(), stacksize));
+                let protect = stack_protector && !ctx.arrays.is_empty();
+                let mut code = mem::take(&mut ctx.code);
+                if ctx.sanitize.address && !ctx.arrays.is_empty() {
+                    code = gen_redzones(ctx, code);
+                }
+                let struct_ret = ctx.struct_ret;
+                let mut f = Function::new(
+                    name.to_string(),
+                    code,
+                    stacksize,
+                    protect,
+                    ctx.profile,
+                    struct_ret,
+                    variadic,
+                );
+                f.span = node.span;
+                f.is_static = is_static;
+                f.ret_size = ret_size;
+                f.ret_float = ret_float;
+                v.push(f);
             }
             NodeType::Vardef(_, _, _) => (),
             _ => panic!("parse error."),
         }
     }
+    if ctx.coverage.is_some() && !v.is_empty() {
+        add_coverage_globals(ctx);
+    }
     v
 }
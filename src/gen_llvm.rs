@@ -0,0 +1,1246 @@
+use crate::gen_ir::{CallArg, CodegenContext, Function, IROp};
+use crate::ice;
+use crate::parallel;
+use crate::target::Target;
+use crate::util::{emit, hex_escape, roundup};
+use crate::{Reloc, Scope, Var};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+// This pass prints textual LLVM IR, so that r9cc can be used as a
+// frontend for LLVM's optimizer and code generators
+// (e.g. `r9cc --emit=llvm-ir foo.c | llc -O2`).
+//
+// Our IR is not in SSA form: after register allocation, the same
+// register is assigned many times. Rather than building SSA ourselves,
+// we do what most LLVM frontends do and give every register its own
+// stack slot (`alloca`). LLVM's mem2reg pass turns them back into SSA
+// values.
+//
+// Local variables live in a single byte array, %frame. %fp points just
+// past its end, so that `Bprel` can compute `%fp - offset` exactly like
+// `rbp - offset` on x86. All values, including pointers, are i64, and
+// are converted with inttoptr right before memory accesses.
+//
+// Undefined functions are declared variadic, since we don't know their
+// prototypes; on the common ABIs this also works for non-variadic
+// functions.
+//
+// LLVM leaves it to the frontend to lower struct arguments and return
+// values to what the target's C ABI expects, so that is done here,
+// the same way clang does it.
+
+fn int_type(size: u8) -> &'static str {
+    match size {
+        1 => "i8",
+        4 => "i32",
+        8 => "i64",
+        _ => unreachable!(),
+    }
+}
+
+// C ABIs, as far as structs are concerned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Abi {
+    SysV,  // x86-64 Linux and macOS
+    Win64, // x86-64 Windows
+    Lp64,  // RISC-V
+    Wasm,
+}
+
+impl Abi {
+    fn new(triple: &str) -> Self {
+        if triple.starts_with("riscv") {
+            Abi::Lp64
+        } else if triple.starts_with("wasm") {
+            Abi::Wasm
+        } else if triple.contains("windows") {
+            Abi::Win64
+        } else {
+            Abi::SysV
+        }
+    }
+
+    // Whether a struct of `size` bytes is small enough to be passed in
+    // integer registers.
+    fn in_regs(self, size: usize) -> bool {
+        match self {
+            Abi::SysV | Abi::Lp64 => size <= 16,
+            Abi::Win64 => [1, 2, 4, 8].contains(&size),
+            Abi::Wasm => false,
+        }
+    }
+
+    // LLVM type of a struct argument of `size` bytes that isn't passed
+    // in registers. System V copies it onto the stack, while the other
+    // ABIs pass a pointer to a copy made by the caller.
+    fn mem_arg_type(self, size: usize) -> String {
+        match self {
+            Abi::SysV => format!("[{} x i8]*", size),
+            _ => "i8*".to_string(),
+        }
+    }
+
+    // Same as mem_arg_type(), with the attributes of the parameter.
+    fn mem_arg(self, size: usize) -> String {
+        match self {
+            Abi::SysV => format!("[{} x i8]* byval([{} x i8]) align 8", size, size),
+            _ => self.mem_arg_type(size),
+        }
+    }
+
+    // How va_arg finds a variadic argument of a struct of `size` bytes
+    // (or a scalar if None) on ABIs where va_list is a pointer to it:
+    // (alignment, bytes to skip, whether the slot holds a pointer to
+    // the argument). System V is handled separately.
+    fn va_slot(self, size: Option<usize>) -> (usize, usize, bool) {
+        match (self, size) {
+            (Abi::Win64, Some(size)) => (8, 8, !self.in_regs(size)),
+            (Abi::Lp64, Some(size)) if size <= 16 => (8, roundup(size, 8), false),
+            (Abi::Lp64, Some(_)) => (8, 8, true),
+            // A pointer is 4 bytes on wasm32.
+            (Abi::Wasm, Some(_)) => (4, 4, true),
+            (_, None) => (8, 8, false),
+            (Abi::SysV, Some(_)) => unreachable!(),
+        }
+    }
+
+    // LLVM return type of a function returning a struct of `size`
+    // bytes, or None if it's returned through a hidden pointer.
+    fn ret_type(self, size: usize) -> Option<&'static str> {
+        if !self.in_regs(size) {
+            return None;
+        }
+        if size > 8 {
+            Some("{ i64, i64 }")
+        } else {
+            Some("i64")
+        }
+    }
+}
+
+// Sizes of the eightbytes a struct of `size` bytes is split into.
+fn pieces(size: usize) -> Vec<usize> {
+    (0..size)
+        .step_by(8)
+        .map(|off| (size - off).min(8))
+        .collect()
+}
+
+// LLVM return type of a function returning a struct of `ret` bytes
+// (or an integer if None).
+fn ret_type(abi: Abi, ret: Option<usize>) -> &'static str {
+    match ret {
+        Some(size) => abi.ret_type(size).unwrap_or("void"),
+        None => "i64",
+    }
+}
+
+// Counts the integer registers left for arguments. System V passes a
+// struct that doesn't fit in the remaining registers on the stack
+// instead of splitting it, and LLVM only sees the pieces, so we have
+// to decide that ourselves.
+struct ArgRegs {
+    abi: Abi,
+    left: usize,
+}
+
+impl ArgRegs {
+    fn new(abi: Abi) -> Self {
+        ArgRegs { abi, left: 6 }
+    }
+
+    fn scalar(&mut self) {
+        self.left = self.left.saturating_sub(1);
+    }
+
+    // Whether a struct argument of `size` bytes is passed in registers.
+    fn struct_arg(&mut self, size: usize) -> bool {
+        let n = pieces(size).len();
+        if !self.abi.in_regs(size) || (self.abi == Abi::SysV && n > self.left) {
+            return false;
+        }
+        self.left = self.left.saturating_sub(n);
+        true
+    }
+}
+
+// A global with addresses in its initializer is a packed struct of its
+// bytes with a pointer-sized integer for each address, since LLVM can't
+// put a pointer in the middle of an array. The pieces are the byte
+// ranges and addresses in order.
+fn pieces_with_relocs(
+    len: usize,
+    relocs: &[Reloc],
+    ptr_size: usize,
+) -> Vec<Result<(usize, usize), &Reloc>> {
+    let mut pieces = vec![];
+    let mut pos = 0;
+    for reloc in relocs {
+        if pos < reloc.offset {
+            pieces.push(Ok((pos, reloc.offset)));
+        }
+        pieces.push(Err(reloc));
+        pos = reloc.offset + ptr_size;
+    }
+    if pos < len {
+        pieces.push(Ok((pos, len)));
+    }
+    pieces
+}
+
+fn packed_type(len: usize, relocs: &[Reloc], ptr_size: usize) -> String {
+    let fields: Vec<String> = pieces_with_relocs(len, relocs, ptr_size)
+        .into_iter()
+        .map(|piece| match piece {
+            Ok((start, end)) => format!("[{} x i8]", end - start),
+            Err(_) => int_type(ptr_size as u8).to_string(),
+        })
+        .collect();
+    format!("<{{ {} }}>", fields.join(", "))
+}
+
+// `types` must have the type of every symbol that `relocs` refer to.
+fn packed_init(
+    data: &[u8],
+    len: usize,
+    relocs: &[Reloc],
+    ptr_size: usize,
+    types: &HashMap<String, String>,
+) -> String {
+    let int = int_type(ptr_size as u8);
+    let fields: Vec<String> = pieces_with_relocs(len, relocs, ptr_size)
+        .into_iter()
+        .map(|piece| match piece {
+            Ok((start, end)) => {
+                let bytes = data.get(start..).unwrap_or(&[]);
+                format!(
+                    "[{} x i8] c\"{}\"",
+                    end - start,
+                    hex_escape(bytes, end - start)
+                )
+            }
+            Err(reloc) => {
+                let addr = format!(
+                    "ptrtoint ({}* @{} to {})",
+                    types[&reloc.name], reloc.name, int
+                );
+                match reloc.addend {
+                    0 => format!("{} {}", int, addr),
+                    addend => format!("{} add ({} {}, {} {})", int, int, addr, int, addend),
+                }
+            }
+        })
+        .collect();
+    format!("<{{ {} }}>", fields.join(", "))
+}
+
+// LLVM types of the parameters of `f`.
+fn param_types(abi: Abi, f: &Function) -> Vec<String> {
+    let mut regs = ArgRegs::new(abi);
+    let mut types = vec![];
+    if let Some(size) = f.struct_ret {
+        if abi.ret_type(size).is_none() {
+            regs.scalar();
+            types.push(format!("[{} x i8]*", size));
+        }
+    }
+    for ir in &f.ir {
+        match ir.op {
+            IROp::StoreArg(_) => {
+                regs.scalar();
+                types.push("i64".to_string());
+            }
+            IROp::StoreStructArg(size) if regs.struct_arg(size) => {
+                types.extend(pieces(size).iter().map(|_| "i64".to_string()))
+            }
+            IROp::StoreStructArg(size) => types.push(abi.mem_arg_type(size)),
+            _ => (),
+        }
+    }
+    types
+}
+
+// Return type and parameter list of an undefined function we call.
+// sret can't be a variadic argument, so the struct return pointer has
+// to be declared.
+fn undef_func_sig(abi: Abi, ret: Option<usize>) -> (&'static str, String) {
+    match ret {
+        Some(size) if abi.ret_type(size).is_none() => ("void", format!("([{} x i8]*, ...)", size)),
+        _ => (ret_type(abi, ret), "(...)".to_string()),
+    }
+}
+
+struct Gen<'a> {
+    out: String,
+    globals: &'a HashMap<String, String>, // name -> LLVM type
+    funcs: &'a HashSet<String>,           // defined functions
+    // Types of defined variadic functions, which calls have to spell
+    // out.
+    variadic: &'a HashMap<String, String>,
+    nregs: usize,
+    asm_dialect: &'static str,
+    abi: Abi,
+    ntmp: usize,
+    nblock: usize,
+}
+
+impl<'a> Gen<'a> {
+    fn tmp(&mut self) -> String {
+        self.ntmp += 1;
+        format!("%t{}", self.ntmp)
+    }
+
+    // Starts a fresh basic block. Used after terminators, since LLVM
+    // requires every block to end with exactly one of them.
+    fn new_block(&mut self) {
+        self.nblock += 1;
+        let _ = writeln!(self.out, "B{}:", self.nblock);
+    }
+
+    fn load_reg(&mut self, r: usize) -> String {
+        let t = self.tmp();
+        emit!(self.out, "{} = load i64, i64* %r{}", t, r);
+        t
+    }
+
+    fn store_reg(&mut self, r: usize, val: &str) {
+        emit!(self.out, "store i64 {}, i64* %r{}", val, r);
+    }
+
+    // r = r <insn> rhs
+    fn binop(&mut self, r: usize, insn: &str, rhs: &str) {
+        let lhs = self.load_reg(r);
+        let t = self.tmp();
+        emit!(self.out, "{} = {} i64 {}, {}", t, insn, lhs, rhs);
+        self.store_reg(r, &t);
+    }
+
+    fn binop_reg(&mut self, lhs: usize, insn: &str, rhs: usize) {
+        let rhs = self.load_reg(rhs);
+        self.binop(lhs, insn, &rhs);
+    }
+
+    fn cmp(&mut self, lhs: usize, cond: &str, rhs: usize) {
+        let a = self.load_reg(lhs);
+        let b = self.load_reg(rhs);
+        let c = self.tmp();
+        emit!(self.out, "{} = icmp {} i64 {}, {}", c, cond, a, b);
+        let t = self.tmp();
+        emit!(self.out, "{} = zext i1 {} to i64", t, c);
+        self.store_reg(lhs, &t);
+    }
+
+    // Converts an address held in an i64 to a pointer to iN.
+    fn int_to_ptr(&mut self, addr: &str, size: u8) -> String {
+        let p = self.tmp();
+        emit!(
+            self.out,
+            "{} = inttoptr i64 {} to {}*",
+            p,
+            addr,
+            int_type(size)
+        );
+        p
+    }
+
+    // Truncates an i64 to `size` bytes.
+    fn trunc(&mut self, val: &str, size: u8) -> String {
+        if size == 8 {
+            return val.to_string();
+        }
+        let t = self.tmp();
+        emit!(self.out, "{} = trunc i64 {} to {}", t, val, int_type(size));
+        t
+    }
+
+    // Sign-extends a `size`-byte integer to i64.
+    fn sext(&mut self, val: &str, size: u8) -> String {
+        if size == 8 {
+            return val.to_string();
+        }
+        let t = self.tmp();
+        emit!(self.out, "{} = sext {} {} to i64", t, int_type(size), val);
+        t
+    }
+
+    fn store(&mut self, addr: &str, val: &str, size: u8) {
+        let p = self.int_to_ptr(addr, size);
+        let val = self.trunc(val, size);
+        emit!(
+            self.out,
+            "store {} {}, {}* {}",
+            int_type(size),
+            val,
+            int_type(size),
+            p
+        );
+    }
+
+    // Loads the `size` (at most 8) bytes at addr+off and zero-extends
+    // them to i64.
+    fn load_piece(&mut self, addr: &str, off: usize, size: usize) -> String {
+        let ty = format!("i{}", size * 8);
+        let a = self.tmp();
+        emit!(self.out, "{} = add i64 {}, {}", a, addr, off);
+        let p = self.tmp();
+        emit!(self.out, "{} = inttoptr i64 {} to {}*", p, a, ty);
+        let v = self.tmp();
+        emit!(self.out, "{} = load {}, {}* {}, align 1", v, ty, ty, p);
+        if size == 8 {
+            return v;
+        }
+        let t = self.tmp();
+        emit!(self.out, "{} = zext {} {} to i64", t, ty, v);
+        t
+    }
+
+    // Stores the low `size` (at most 8) bytes of val to addr+off.
+    fn store_piece(&mut self, addr: &str, off: usize, val: &str, size: usize) {
+        let ty = format!("i{}", size * 8);
+        let a = self.tmp();
+        emit!(self.out, "{} = add i64 {}, {}", a, addr, off);
+        let p = self.tmp();
+        emit!(self.out, "{} = inttoptr i64 {} to {}*", p, a, ty);
+        let mut val = val.to_string();
+        if size != 8 {
+            let t = self.tmp();
+            emit!(self.out, "{} = trunc i64 {} to {}", t, val, ty);
+            val = t;
+        }
+        emit!(self.out, "store {} {}, {}* {}, align 1", ty, val, ty, p);
+    }
+
+    fn memcpy(&mut self, dst: &str, src: &str, size: usize) {
+        let d = self.tmp();
+        emit!(self.out, "{} = inttoptr i64 {} to i8*", d, dst);
+        let s = self.tmp();
+        emit!(self.out, "{} = inttoptr i64 {} to i8*", s, src);
+        emit!(
+            self.out,
+            "call void @llvm.memcpy.p0i8.p0i8.i64(i8* {}, i8* {}, i64 {}, i1 false)",
+            d,
+            s,
+            size
+        );
+    }
+
+    // Returns the address of the next variadic argument in the System V
+    // va_list at `ap`, advancing it. The argument is in the register
+    // save area if there are enough registers left for it, and in the
+    // overflow area otherwise.
+    fn va_arg_sysv(&mut self, ap: &str, size: Option<usize>) -> String {
+        let size = size.unwrap_or(8);
+        let step = roundup(size, 8);
+        let stack = self.load_piece(ap, 8, 8);
+        let next = self.tmp();
+        emit!(self.out, "{} = add i64 {}, {}", next, stack, step);
+        if size > 16 {
+            self.store_piece(ap, 8, &next, 8);
+            return stack;
+        }
+
+        // Use selects rather than branches, so as not to split the block.
+        let gp = self.load_piece(ap, 0, 4);
+        let fits = self.tmp();
+        emit!(self.out, "{} = icmp ule i64 {}, {}", fits, gp, 48 - step);
+        let area = self.load_piece(ap, 16, 8);
+        let reg = self.tmp();
+        emit!(self.out, "{} = add i64 {}, {}", reg, area, gp);
+        let addr = self.tmp();
+        emit!(
+            self.out,
+            "{} = select i1 {}, i64 {}, i64 {}",
+            addr,
+            fits,
+            reg,
+            stack
+        );
+        let gp2 = self.tmp();
+        emit!(self.out, "{} = add i64 {}, {}", gp2, gp, step);
+        let gp3 = self.tmp();
+        emit!(
+            self.out,
+            "{} = select i1 {}, i64 {}, i64 {}",
+            gp3,
+            fits,
+            gp2,
+            gp
+        );
+        self.store_piece(ap, 0, &gp3, 4);
+        let next2 = self.tmp();
+        emit!(
+            self.out,
+            "{} = select i1 {}, i64 {}, i64 {}",
+            next2,
+            fits,
+            stack,
+            next
+        );
+        self.store_piece(ap, 8, &next2, 8);
+        addr
+    }
+
+    // Same as va_arg_sysv(), for ABIs where a va_list is a pointer to
+    // the next argument. Pointers aren't necessarily 64-bit, so they
+    // are loaded and stored as such.
+    fn va_arg_ptr(&mut self, ap: &str, size: Option<usize>) -> String {
+        let (align, step, by_ref) = self.abi.va_slot(size);
+        let pp = self.tmp();
+        emit!(self.out, "{} = inttoptr i64 {} to i8**", pp, ap);
+        let cur = self.load_ptr(&pp);
+        let t = self.tmp();
+        emit!(self.out, "{} = add i64 {}, {}", t, cur, align - 1);
+        let slot = self.tmp();
+        emit!(self.out, "{} = and i64 {}, {}", slot, t, -(align as i64));
+        let next = self.tmp();
+        emit!(self.out, "{} = add i64 {}, {}", next, slot, step);
+        let p = self.tmp();
+        emit!(self.out, "{} = inttoptr i64 {} to i8*", p, next);
+        emit!(self.out, "store i8* {}, i8** {}", p, pp);
+        if !by_ref {
+            return slot;
+        }
+        let sp = self.tmp();
+        emit!(self.out, "{} = inttoptr i64 {} to i8**", sp, slot);
+        self.load_ptr(&sp)
+    }
+
+    // Loads the pointer at `pp` (an i8**) as an i64 address.
+    fn load_ptr(&mut self, pp: &str) -> String {
+        let p = self.tmp();
+        emit!(self.out, "{} = load i8*, i8** {}", p, pp);
+        self.ptr_param("i8*", &p)
+    }
+
+    // Converts a pointer parameter to an i64 address.
+    fn ptr_param(&mut self, ty: &str, name: &str) -> String {
+        let t = self.tmp();
+        emit!(self.out, "{} = ptrtoint {} {} to i64", t, ty, name);
+        t
+    }
+
+    fn gen(&mut self, f: Function) {
+        ice::set_function(&f.name);
+        use self::IROp::*;
+
+        let ret_ty = ret_type(self.abi, f.struct_ret);
+        let mut regs = ArgRegs::new(self.abi);
+        let mut params = vec![];
+        if ret_ty == "void" {
+            let size = f.struct_ret.unwrap();
+            regs.scalar();
+            params.push(format!("[{} x i8]* sret([{} x i8]) %sret", size, size));
+        }
+        // Whether each struct parameter came in registers.
+        let mut struct_regs = vec![];
+        for ir in &f.ir {
+            let i = ir.rhs.unwrap_or(0);
+            match ir.op {
+                StoreArg(_) => {
+                    regs.scalar();
+                    params.push(format!("i64 %a{}", i));
+                }
+                StoreStructArg(size) => {
+                    let in_regs = regs.struct_arg(size);
+                    if in_regs {
+                        for k in 0..pieces(size).len() {
+                            params.push(format!("i64 %a{}.{}", i, k));
+                        }
+                    } else {
+                        params.push(format!("{} %a{}", self.abi.mem_arg(size), i));
+                    }
+                    struct_regs.push(in_regs);
+                }
+                _ => (),
+            }
+        }
+        let mut struct_regs = struct_regs.into_iter();
+        // Let LLVM insert the canary. sspstrong protects any function
+        // with a local array, which is what we asked for.
+        let mut attrs = if f.stack_protector { " sspstrong" } else { "" }.to_string();
+        // mcount finds its caller's caller through the frame pointer.
+        if f.profile {
+            attrs.push_str(" \"frame-pointer\"=\"all\"");
+        }
+        // It never ran in the profile (-fprofile-use).
+        if f.entry_count() == Some(0) {
+            attrs.push_str(" cold");
+        }
+        if f.variadic {
+            params.push("...".to_string());
+        }
+        let linkage = if f.is_static { "internal " } else { "" };
+        let _ = writeln!(
+            self.out,
+            "define {}{} @{}({}){} {{",
+            linkage,
+            ret_ty,
+            f.name,
+            params.join(", "),
+            attrs
+        );
+
+        // This is what LLVM's own -pg instrumentation inserts.
+        if f.profile {
+            emit!(self.out, "call void @mcount()");
+        }
+        let stacksize = roundup(f.stacksize, 16);
+        emit!(self.out, "%frame = alloca [{} x i8], align 16", stacksize);
+        for i in 0..self.nregs {
+            emit!(self.out, "%r{} = alloca i64", i);
+        }
+        emit!(
+            self.out,
+            "%end = getelementptr [{} x i8], [{} x i8]* %frame, i64 0, i64 {}",
+            stacksize,
+            stacksize,
+            stacksize
+        );
+        emit!(self.out, "%fp = ptrtoint i8* %end to i64");
+
+        // Copies of structs passed by reference have to be allocated
+        // up front, so that calls in loops don't grow the stack.
+        let mut copies = vec![];
+        for ir in &f.ir {
+            if let Call(_, ref args, _) = ir.op {
+                for arg in args {
+                    if let CallArg::Struct(_, size) = *arg {
+                        if !self.abi.in_regs(size) && self.abi != Abi::SysV {
+                            let t = self.tmp();
+                            emit!(self.out, "{} = alloca [{} x i8], align 16", t, size);
+                            let addr = self.tmp();
+                            emit!(
+                                self.out,
+                                "{} = ptrtoint [{} x i8]* {} to i64",
+                                addr,
+                                size,
+                                t
+                            );
+                            copies.push(addr);
+                        }
+                    }
+                }
+            }
+        }
+        let mut copies = copies.into_iter();
+        let sret = if ret_ty == "void" {
+            let size = f.struct_ret.unwrap();
+            Some(self.ptr_param(&format!("[{} x i8]*", size), "%sret"))
+        } else {
+            None
+        };
+
+        for (i, ir) in f.ir.into_iter().enumerate() {
+            ice::set_position("IR instruction", i);
+            let lhs = ir.lhs.unwrap_or(0);
+            let rhs = ir.rhs.unwrap_or(0);
+            match ir.op {
+                Imm => self.store_reg(lhs, &(rhs as i64).to_string()),
+                Mov => {
+                    let t = self.load_reg(rhs);
+                    self.store_reg(lhs, &t);
+                }
+                Return => {
+                    let t = self.load_reg(lhs);
+                    emit!(self.out, "ret i64 {}", t);
+                    self.new_block();
+                }
+                ReturnStruct => {
+                    let addr = self.load_reg(lhs);
+                    let size = f.struct_ret.unwrap();
+                    match sret {
+                        Some(ref dst) => {
+                            self.memcpy(dst, &addr, size);
+                            emit!(self.out, "ret void");
+                        }
+                        None => {
+                            let mut val = "undef".to_string();
+                            let mut off = 0;
+                            for (k, n) in pieces(size).into_iter().enumerate() {
+                                let v = self.load_piece(&addr, off, n);
+                                if ret_ty == "i64" {
+                                    val = v;
+                                } else {
+                                    let t = self.tmp();
+                                    emit!(
+                                        self.out,
+                                        "{} = insertvalue {} {}, i64 {}, {}",
+                                        t,
+                                        ret_ty,
+                                        val,
+                                        v,
+                                        k
+                                    );
+                                    val = t;
+                                }
+                                off += n;
+                            }
+                            emit!(self.out, "ret {} {}", ret_ty, val);
+                        }
+                    }
+                    self.new_block();
+                }
+                Call(name, args, _) => {
+                    let mut regs = ArgRegs::new(self.abi);
+                    let mut vals = vec![];
+                    let mut types = vec![]; // Of the parameters, for a call through a pointer
+                    let mut ret_buf = None;
+                    for arg in args {
+                        match arg {
+                            CallArg::Val(r) => {
+                                regs.scalar();
+                                vals.push(format!("i64 {}", self.load_reg(r)));
+                                types.push("i64".to_string());
+                            }
+                            CallArg::Float(_) => unreachable!(),
+                            CallArg::Ret(r, size) => {
+                                let addr = self.load_reg(r);
+                                if self.abi.ret_type(size).is_none() {
+                                    regs.scalar();
+                                    let p = self.tmp();
+                                    let ty = format!("[{} x i8]", size);
+                                    emit!(self.out, "{} = inttoptr i64 {} to {}*", p, addr, ty);
+                                    vals.push(format!("{}* sret({}) {}", ty, ty, p));
+                                    types.push(format!("{}*", ty));
+                                }
+                                ret_buf = Some((addr, size));
+                            }
+                            CallArg::Struct(r, size) => {
+                                let addr = self.load_reg(r);
+                                if regs.struct_arg(size) {
+                                    let mut off = 0;
+                                    for n in pieces(size) {
+                                        let v = self.load_piece(&addr, off, n);
+                                        vals.push(format!("i64 {}", v));
+                                        types.push("i64".to_string());
+                                        off += n;
+                                    }
+                                } else if self.abi == Abi::SysV {
+                                    let p = self.tmp();
+                                    emit!(
+                                        self.out,
+                                        "{} = inttoptr i64 {} to [{} x i8]*",
+                                        p,
+                                        addr,
+                                        size
+                                    );
+                                    vals.push(format!("{} {}", self.abi.mem_arg(size), p));
+                                    types.push(self.abi.mem_arg_type(size));
+                                } else {
+                                    let copy = copies.next().unwrap();
+                                    self.memcpy(&copy, &addr, size);
+                                    let p = self.tmp();
+                                    emit!(self.out, "{} = inttoptr i64 {} to i8*", p, copy);
+                                    vals.push(format!("i8* {}", p));
+                                    types.push("i8*".to_string());
+                                }
+                            }
+                        }
+                    }
+                    let ret_size = ret_buf.as_ref().map(|(_, size)| *size);
+                    let ret_ty = ret_type(self.abi, ret_size);
+                    let mut callee = format!("@{}", name);
+                    let ty = if let Some(r) = ir.rhs {
+                        let ty = format!("{} ({})", ret_ty, types.join(", "));
+                        let addr = self.load_reg(r);
+                        callee = self.tmp();
+                        emit!(self.out, "{} = inttoptr i64 {} to {}*", callee, addr, ty);
+                        ty
+                    } else if let Some(ty) = self.variadic.get(&name) {
+                        ty.clone()
+                    } else if self.funcs.contains(&name) {
+                        ret_ty.to_string()
+                    } else {
+                        let (ret_ty, params) = undef_func_sig(self.abi, ret_size);
+                        format!("{} {}", ret_ty, params)
+                    };
+                    if ret_ty == "void" {
+                        emit!(self.out, "call {} {}({})", ty, callee, vals.join(", "));
+                        continue;
+                    }
+                    let t = self.tmp();
+                    emit!(
+                        self.out,
+                        "{} = call {} {}({})",
+                        t,
+                        ty,
+                        callee,
+                        vals.join(", ")
+                    );
+                    match ret_buf {
+                        Some((addr, size)) => {
+                            let mut off = 0;
+                            for (k, n) in pieces(size).into_iter().enumerate() {
+                                let v = if ret_ty == "i64" {
+                                    t.clone()
+                                } else {
+                                    let v = self.tmp();
+                                    emit!(self.out, "{} = extractvalue {} {}, {}", v, ret_ty, t, k);
+                                    v
+                                };
+                                self.store_piece(&addr, off, &v, n);
+                                off += n;
+                            }
+                        }
+                        None => self.store_reg(lhs, &t),
+                    }
+                }
+                Label => {
+                    // Fall through into the labeled block.
+                    emit!(self.out, "br label %L{}", lhs);
+                    let _ = writeln!(self.out, "L{}:", lhs);
+                }
+                LabelAddr(name) | TlsAddr(name) => {
+                    let t = self.tmp();
+                    emit!(
+                        self.out,
+                        "{} = ptrtoint {}* @{} to i64",
+                        t,
+                        self.globals[&name],
+                        name
+                    );
+                    self.store_reg(lhs, &t);
+                }
+                Neg => {
+                    let v = self.load_reg(lhs);
+                    let t = self.tmp();
+                    emit!(self.out, "{} = sub i64 0, {}", t, v);
+                    self.store_reg(lhs, &t);
+                }
+                Not => {
+                    let v = self.load_reg(lhs);
+                    let t = self.tmp();
+                    emit!(self.out, "{} = xor i64 {}, -1", t, v);
+                    self.store_reg(lhs, &t);
+                }
+                Sext(size) | Zext(size) => {
+                    let v = self.load_reg(lhs);
+                    let t = self.tmp();
+                    let u = self.tmp();
+                    let ty = int_type(size);
+                    let ext = if let Sext(_) = ir.op { "sext" } else { "zext" };
+                    emit!(self.out, "{} = trunc i64 {} to {}", t, v, ty);
+                    emit!(self.out, "{} = {} {} {} to i64", u, ext, ty, t);
+                    self.store_reg(lhs, &u);
+                }
+                EQ => self.cmp(lhs, "eq", rhs),
+                NE => self.cmp(lhs, "ne", rhs),
+                LT => self.cmp(lhs, "slt", rhs),
+                LE => self.cmp(lhs, "sle", rhs),
+                ULT => self.cmp(lhs, "ult", rhs),
+                ULE => self.cmp(lhs, "ule", rhs),
+                AND => self.binop_reg(lhs, "and", rhs),
+                OR => self.binop_reg(lhs, "or", rhs),
+                XOR => self.binop_reg(lhs, "xor", rhs),
+                SHL => self.binop_reg(lhs, "shl", rhs),
+                SHR => self.binop_reg(lhs, "lshr", rhs),
+                SAR => self.binop_reg(lhs, "ashr", rhs),
+                Mod => self.binop_reg(lhs, "srem", rhs),
+                UMod => self.binop_reg(lhs, "urem", rhs),
+                Jmp => {
+                    emit!(self.out, "br label %L{}", lhs);
+                    self.new_block();
+                }
+                If | Unless => {
+                    let v = self.load_reg(lhs);
+                    let c = self.tmp();
+                    let cond = if ir.op == If { "ne" } else { "eq" };
+                    emit!(self.out, "{} = icmp {} i64 {}, 0", c, cond, v);
+                    emit!(
+                        self.out,
+                        "br i1 {}, label %L{}, label %B{}",
+                        c,
+                        rhs,
+                        self.nblock + 1
+                    );
+                    self.new_block();
+                }
+                JumpTable(table) => {
+                    let v = self.load_reg(lhs);
+                    let cases = table
+                        .iter()
+                        .enumerate()
+                        .map(|(i, x)| format!("i64 {}, label %L{}", i, x))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    emit!(self.out, "switch i64 {}, label %L{} [{}]", v, rhs, cases);
+                    self.new_block();
+                }
+                Load(size) => {
+                    let addr = self.load_reg(rhs);
+                    let p = self.int_to_ptr(&addr, size);
+                    let v = self.tmp();
+                    let ty = int_type(size);
+                    emit!(self.out, "{} = load {}, {}* {}", v, ty, ty, p);
+                    if size == 8 {
+                        self.store_reg(lhs, &v);
+                    } else {
+                        let ext = if size == 1 { "zext" } else { "sext" };
+                        let t = self.tmp();
+                        emit!(self.out, "{} = {} {} {} to i64", t, ext, ty, v);
+                        self.store_reg(lhs, &t);
+                    }
+                }
+                Copy(size) => {
+                    let dst = self.load_reg(lhs);
+                    let src = self.load_reg(rhs);
+                    self.memcpy(&dst, &src, size);
+                }
+                Store(size) => {
+                    let addr = self.load_reg(lhs);
+                    let val = self.load_reg(rhs);
+                    self.store(&addr, &val, size);
+                }
+                AtomicLoad(size) => {
+                    let addr = self.load_reg(rhs);
+                    let p = self.int_to_ptr(&addr, size);
+                    let v = self.tmp();
+                    let ty = int_type(size);
+                    emit!(
+                        self.out,
+                        "{} = load atomic {}, {}* {} seq_cst, align {}",
+                        v,
+                        ty,
+                        ty,
+                        p,
+                        size
+                    );
+                    let v = self.sext(&v, size);
+                    self.store_reg(lhs, &v);
+                }
+                AtomicStore(size) => {
+                    let addr = self.load_reg(lhs);
+                    let p = self.int_to_ptr(&addr, size);
+                    let val = self.load_reg(rhs);
+                    let val = self.trunc(&val, size);
+                    let ty = int_type(size);
+                    emit!(
+                        self.out,
+                        "store atomic {} {}, {}* {} seq_cst, align {}",
+                        ty,
+                        val,
+                        ty,
+                        p,
+                        size
+                    );
+                }
+                AtomicAdd(size) => {
+                    let addr = self.load_reg(lhs);
+                    let p = self.int_to_ptr(&addr, size);
+                    let val = self.load_reg(rhs);
+                    let val = self.trunc(&val, size);
+                    let ty = int_type(size);
+                    let old = self.tmp();
+                    emit!(
+                        self.out,
+                        "{} = atomicrmw add {}* {}, {} {} seq_cst",
+                        old,
+                        ty,
+                        p,
+                        ty,
+                        val
+                    );
+                    let old = self.sext(&old, size);
+                    self.store_reg(lhs, &old);
+                }
+                StoreArg(size) => {
+                    let addr = self.tmp();
+                    emit!(self.out, "{} = sub i64 %fp, {}", addr, lhs);
+                    self.store(&addr, &format!("%a{}", rhs), size);
+                }
+                StoreStructArg(size) => {
+                    let addr = self.tmp();
+                    emit!(self.out, "{} = sub i64 %fp, {}", addr, lhs);
+                    if struct_regs.next().unwrap() {
+                        let mut off = 0;
+                        for (k, n) in pieces(size).into_iter().enumerate() {
+                            self.store_piece(&addr, off, &format!("%a{}.{}", rhs, k), n);
+                            off += n;
+                        }
+                    } else {
+                        let ty = self.abi.mem_arg_type(size);
+                        let src = self.ptr_param(&ty, &format!("%a{}", rhs));
+                        self.memcpy(&addr, &src, size);
+                    }
+                }
+                Add => self.binop_reg(lhs, "add", rhs),
+                AddImm => self.binop(lhs, "add", &(rhs as i32).to_string()),
+                Sub => self.binop_reg(lhs, "sub", rhs),
+                SubImm => self.binop(lhs, "sub", &(rhs as i32).to_string()),
+                Alloca => {
+                    let n = self.load_reg(rhs);
+                    let p = self.tmp();
+                    emit!(self.out, "{} = alloca i8, i64 {}, align 16", p, n);
+                    let t = self.tmp();
+                    emit!(self.out, "{} = ptrtoint i8* {} to i64", t, p);
+                    self.store_reg(lhs, &t);
+                }
+                Bprel => {
+                    let t = self.tmp();
+                    emit!(self.out, "{} = sub i64 %fp, {}", t, rhs);
+                    self.store_reg(lhs, &t);
+                }
+                Mul => self.binop_reg(lhs, "mul", rhs),
+                MulImm => self.binop(lhs, "mul", &(rhs as i32).to_string()),
+                Div => self.binop_reg(lhs, "sdiv", rhs),
+                UDiv => self.binop_reg(lhs, "udiv", rhs),
+                Asm(text) => {
+                    // `$` introduces operands in LLVM's asm strings.
+                    let text = text.replace('$', "$$");
+                    emit!(
+                        self.out,
+                        "call void asm sideeffect {}\"{}\", \"\"()",
+                        self.asm_dialect,
+                        hex_escape(text.as_bytes(), text.len())
+                    );
+                }
+                VaStart => {
+                    let ap = self.load_reg(lhs);
+                    let p = self.tmp();
+                    emit!(self.out, "{} = inttoptr i64 {} to i8*", p, ap);
+                    emit!(self.out, "call void @llvm.va_start(i8* {})", p);
+                }
+                VaArg(size) => {
+                    let ap = self.load_reg(rhs);
+                    let addr = if self.abi == Abi::SysV {
+                        self.va_arg_sysv(&ap, size)
+                    } else {
+                        self.va_arg_ptr(&ap, size)
+                    };
+                    self.store_reg(lhs, &addr);
+                }
+                Comment(text) => emit!(self.out, "; {}", text),
+                // LLVM wants debug info as metadata, which we don't
+                // emit; -g is ignored.
+                Loc(_, _) => (),
+                Trap => {
+                    emit!(self.out, "call void @llvm.trap()");
+                    emit!(self.out, "unreachable");
+                    self.new_block();
+                }
+                Unreachable => {
+                    emit!(self.out, "unreachable");
+                    self.new_block();
+                }
+                Nop | Kill | Count(_) => (),
+                // compile_nodes rejects floating point for this backend.
+                FAdd | FSub | FMul | FDiv | FEQ | FNE | FLT | FLE | I2F | F2I | FExt | FTrunc
+                | StoreFloatArg(_) | VaArgFloat => unreachable!(),
+            }
+        }
+
+        // Falling off the end of the function returns 0.
+        match ret_ty {
+            "void" => emit!(self.out, "ret void"),
+            "i64" => emit!(self.out, "ret i64 0"),
+            _ => emit!(self.out, "ret {} zeroinitializer", ret_ty),
+        }
+        self.out.push_str("}\n\n");
+    }
+}
+
+// `fns` must have been register-allocated for `target`.
+pub fn gen_llvm(
+    ctx: &mut CodegenContext,
+    globals: Vec<Var>,
+    fns: Vec<Function>,
+    target: &dyn Target,
+) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "target triple = \"{}\"\n", target.llvm_triple());
+
+    let mut types = HashMap::new();
+    // Globals with addresses in them, defined once the types of what
+    // they point to are known.
+    let mut packed = vec![];
+    for var in globals {
+        if let Scope::Global(ref data, len, is_extern, is_tls, is_static) = var.scope {
+            let tls = if is_tls {
+                "thread_local(initialexec) "
+            } else {
+                ""
+            };
+            if is_extern {
+                let _ = writeln!(out, "@{} = external {}global i8", var.name, tls);
+                types.insert(var.name, "i8".to_string());
+                continue;
+            }
+            if !var.relocs.is_empty() {
+                let ty = packed_type(len, &var.relocs, target.ptr_size());
+                types.insert(var.name.clone(), ty.clone());
+                let linkage = if is_static {
+                    "internal global"
+                } else {
+                    "global"
+                };
+                packed.push((var, tls, linkage, ty));
+                continue;
+            }
+            let ty = format!("[{} x i8]", len);
+            let init = if data.is_empty() {
+                "zeroinitializer".to_string()
+            } else {
+                format!("c\"{}\"", hex_escape(data, len))
+            };
+            // String literals are read-only, so LLVM may put them in
+            // .rodata and merge identical ones.
+            let linkage = if var.name.starts_with(".L") {
+                "private unnamed_addr constant"
+            } else if is_static {
+                "internal global"
+            } else {
+                "global"
+            };
+            let _ = writeln!(
+                out,
+                "@{} = {}{} {} {}, align {}",
+                var.name,
+                tls,
+                linkage,
+                ty,
+                init,
+                var.ty.align.max(1)
+            );
+            types.insert(var.name, ty);
+            continue;
+        }
+        unreachable!();
+    }
+    out.push('\n');
+
+    let funcs = fns.iter().map(|f| f.name.clone()).collect::<HashSet<_>>();
+
+    let abi = Abi::new(target.llvm_triple());
+    let variadic = fns
+        .iter()
+        .filter(|f| f.variadic)
+        .map(|f| {
+            let mut types = param_types(abi, f);
+            types.push("...".to_string());
+            let ty = format!("{} ({})", ret_type(abi, f.struct_ret), types.join(", "));
+            (f.name.clone(), ty)
+        })
+        .collect::<HashMap<_, _>>();
+
+    // Functions are globals too, whose addresses may be taken.
+    for f in &fns {
+        let ty = variadic.get(&f.name).cloned().unwrap_or_else(|| {
+            let params = param_types(abi, f).join(", ");
+            format!("{} ({})", ret_type(abi, f.struct_ret), params)
+        });
+        types.insert(f.name.clone(), ty);
+    }
+
+    // Declare everything we call or take the address of but don't
+    // define.
+    let mut decls: Vec<(String, Option<usize>)> = vec![];
+    let mut uses_memcpy = false;
+    for f in &fns {
+        for ir in &f.ir {
+            match ir.op {
+                IROp::LabelAddr(ref name)
+                    if !types.contains_key(name) && !decls.iter().any(|(n, _)| n == name) =>
+                {
+                    decls.push((name.clone(), None));
+                }
+                IROp::Call(ref name, ref args, _) => {
+                    let mut ret = None;
+                    for arg in args {
+                        match *arg {
+                            CallArg::Ret(_, size) => {
+                                ret = Some(size);
+                                uses_memcpy = true;
+                            }
+                            CallArg::Struct(_, _) => uses_memcpy = true,
+                            CallArg::Val(_) | CallArg::Float(_) => (),
+                        }
+                    }
+                    if ir.rhs.is_none()
+                        && !funcs.contains(name)
+                        && !decls.iter().any(|(n, _)| n == name)
+                    {
+                        decls.push((name.clone(), ret));
+                    }
+                }
+                IROp::StoreStructArg(_) | IROp::ReturnStruct | IROp::Copy(_) => uses_memcpy = true,
+                _ => (),
+            }
+        }
+    }
+    for var in &packed {
+        for reloc in &var.0.relocs {
+            if !types.contains_key(&reloc.name) && !decls.iter().any(|(n, _)| *n == reloc.name) {
+                decls.push((reloc.name.clone(), None));
+            }
+        }
+    }
+    for (name, ret) in decls {
+        let (ret_ty, params) = undef_func_sig(abi, ret);
+        let _ = writeln!(out, "declare {} @{}{}", ret_ty, name, params);
+        types
+            .entry(name)
+            .or_insert_with(|| format!("{} {}", ret_ty, params));
+    }
+    for (var, tls, linkage, ty) in packed {
+        if let Scope::Global(ref data, len, ..) = var.scope {
+            let _ = writeln!(
+                out,
+                "@{} = {}{} {} {}, align {}",
+                var.name,
+                tls,
+                linkage,
+                ty,
+                packed_init(data, len, &var.relocs, target.ptr_size(), &types),
+                var.ty.align.max(1)
+            );
+        }
+    }
+    if uses_memcpy {
+        out.push_str("declare void @llvm.memcpy.p0i8.p0i8.i64(i8*, i8*, i64, i1)\n");
+    }
+    if fns
+        .iter()
+        .any(|f| f.ir.iter().any(|ir| ir.op == IROp::Trap))
+    {
+        out.push_str("declare void @llvm.trap()\n");
+    }
+    if fns
+        .iter()
+        .any(|f| f.ir.iter().any(|ir| ir.op == IROp::VaStart))
+    {
+        out.push_str("declare void @llvm.va_start(i8*)\n");
+    }
+    if fns.iter().any(|f| f.profile) {
+        out.push_str("declare void @mcount()\n");
+    }
+    out.push('\n');
+
+    // Our x86 backends use Intel syntax, so inline asm written for them
+    // does too.
+    let asm_dialect = if target.llvm_triple().starts_with("x86_64") {
+        "inteldialect "
+    } else {
+        ""
+    };
+    // Temporaries and blocks are numbered within each function, so
+    // functions can be emitted on their own.
+    let bodies = parallel::map(fns, ctx.jobs, |f| {
+        let mut g = Gen {
+            out: String::new(),
+            globals: &types,
+            funcs: &funcs,
+            variadic: &variadic,
+            nregs: target.regs().len(),
+            asm_dialect,
+            abi,
+            ntmp: 0,
+            nblock: 0,
+        };
+        g.gen(f);
+        g.out
+    });
+    ctx.append_functions(&mut out, bodies);
+    out
+}
@@ -0,0 +1,563 @@
+use crate::diagnostic;
+use crate::gen_ir::{
+    debug_file, debug_files, CallArg, CodegenContext, FnLabels, Function, IROp, COLD_SECTION,
+};
+use crate::ice;
+use crate::parallel;
+use crate::target::Target;
+use crate::util::{emit, emit_data, roundup};
+use crate::{Scope, Var, REGS_N};
+
+use std::fmt::Write;
+
+// This pass generates RV64I (plus the M extension for mul/div/rem and
+// the A extension for atomics) assembly from IR, following the
+// standard LP64 calling convention.
+//
+// IR registers are mapped to the callee-saved registers s1-s7, so
+// their values survive function calls without any extra spilling.
+// s0 doubles as the frame pointer, and t0 is used as a scratch
+// register for immediates that don't fit in 12 bits.
+//
+// Structs of up to 16 bytes are passed and returned in one or two
+// registers. Larger ones are passed as a pointer to a copy made by the
+// caller, and returned to memory whose address the caller passes in a0.
+//
+// Variadic arguments are passed just like named ones. A variadic
+// function spills a0-a7 right below the arguments passed on the stack,
+// so that va_list can simply walk up the stack 8 bytes at a time.
+//
+// Stack frame layout:
+//
+//   +-------------------+ <- old sp
+//   | saved a0-a7       | (only if the function is variadic)
+//   +-------------------+
+//   | ra                |
+//   | old s0            |
+//   +-------------------+ <- s0
+//   | struct return ptr | (only if the function returns a large struct)
+//   | local variables   |
+//   +-------------------+
+//   | saved s1-s7       |
+//   +-------------------+ <- sp
+
+const REGS: [&str; REGS_N] = ["s1", "s2", "s3", "s4", "s5", "s6", "s7"];
+const ARGREGS: [&str; 8] = ["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+
+fn is_imm12(x: i64) -> bool {
+    (-2048..2048).contains(&x)
+}
+
+// dst = src + imm
+fn emit_addi(out: &mut String, dst: &str, src: &str, imm: i64) {
+    if is_imm12(imm) {
+        emit!(out, "addi {}, {}, {}", dst, src, imm);
+    } else {
+        emit!(out, "li t0, {}", imm);
+        emit!(out, "add {}, {}, t0", dst, src);
+    }
+}
+
+fn load_insn(size: u8) -> &'static str {
+    match size {
+        1 => "lbu",
+        4 => "lw",
+        8 => "ld",
+        _ => unreachable!(),
+    }
+}
+
+fn store_insn(size: u8) -> &'static str {
+    match size {
+        1 => "sb",
+        4 => "sw",
+        8 => "sd",
+        _ => unreachable!(),
+    }
+}
+
+// Suffix of the AMO instructions operating on `size` bytes.
+fn amo_suffix(size: u8) -> &'static str {
+    match size {
+        4 => "w",
+        8 => "d",
+        _ => unreachable!(),
+    }
+}
+
+// The IR doesn't tell us how structs are aligned, so the following
+// helpers access them byte by byte rather than risk misaligned loads
+// and stores.
+
+// Loads the `size` (at most 8) bytes at off(base) into dst, using t2
+// as a scratch register.
+fn load_bytes(out: &mut String, dst: &str, base: &str, off: usize, size: usize) {
+    emit!(out, "lbu {}, {}({})", dst, off + size - 1, base);
+    for i in (off..off + size - 1).rev() {
+        emit!(out, "slli {}, {}, 8", dst, dst);
+        emit!(out, "lbu t2, {}({})", i, base);
+        emit!(out, "or {}, {}, t2", dst, dst);
+    }
+}
+
+// Stores the low `size` (at most 8) bytes of src to off(base),
+// clobbering src.
+fn store_bytes(out: &mut String, base: &str, off: usize, src: &str, size: usize) {
+    for i in 0..size {
+        if i > 0 {
+            emit!(out, "srli {}, {}, 8", src, src);
+        }
+        emit!(out, "sb {}, {}({})", src, off + i, base);
+    }
+}
+
+// Copies `size` bytes from src to dst through t1.
+fn copy_bytes(out: &mut String, dst: &str, doff: usize, src: &str, size: usize) {
+    for i in 0..size {
+        emit!(out, "lbu t1, {}({})", i, src);
+        emit!(out, "sb t1, {}({})", doff + i, dst);
+    }
+}
+
+// Number of argument registers a struct of `size` bytes takes.
+fn struct_regs(size: usize) -> usize {
+    if size <= 16 {
+        size.div_ceil(8)
+    } else {
+        1
+    }
+}
+
+// Emits `f`, returning the bytes of stack it uses, down to the deepest
+// call's copies of struct arguments.
+fn gen(out: &mut String, f: Function, files: &[String]) -> usize {
+    use self::IROp::*;
+    ice::set_function(&f.name);
+    let mut labels = FnLabels::new(".L", &f.name);
+    let ret = labels.new_label();
+    let mut tables = vec![];
+
+    let sret = f.struct_ret.is_some_and(|size| size > 16);
+    let base = if sret { 8 } else { 0 };
+
+    // Map parameters to their first argument register.
+    let mut params = vec![];
+    let mut next = if sret { 1 } else { 0 };
+    for ir in &f.ir {
+        let n = match ir.op {
+            StoreArg(_) => 1,
+            StoreStructArg(size) => struct_regs(size),
+            _ => continue,
+        };
+        if next + n > ARGREGS.len() {
+            diagnostic::error(format!("{}: too many arguments", f.name), None);
+        }
+        params.push(next);
+        next += n;
+    }
+
+    if f.entry_count() == Some(0) {
+        out.push_str(COLD_SECTION);
+    } else {
+        out.push_str(".text\n");
+    }
+    if !f.is_static {
+        let _ = writeln!(out, ".global {}", f.name);
+    }
+    emit!(out, ".type {}, @function", f.name);
+    let _ = writeln!(out, "{}:", f.name);
+    let varargs = if f.variadic { 64 } else { 0 };
+    if f.variadic {
+        emit!(out, "addi sp, sp, -{}", varargs);
+        for (i, r) in ARGREGS.iter().enumerate() {
+            emit!(out, "sd {}, {}(sp)", r, i * 8);
+        }
+    }
+    emit!(out, "addi sp, sp, -16");
+    emit!(out, "sd ra, 8(sp)");
+    emit!(out, "sd s0, 0(sp)");
+    emit!(out, "mv s0, sp");
+    let frame = roundup(f.stacksize + base, 16) as i64 + 64;
+    emit_addi(out, "sp", "sp", -frame);
+    for (i, r) in REGS.iter().enumerate() {
+        emit!(out, "sd {}, {}(sp)", r, i * 8);
+    }
+    let alloca = f.ir.iter().any(|ir| ir.op == Alloca);
+    let mut calls = 0;
+    if sret {
+        emit!(out, "sd a0, -8(s0)");
+    }
+
+    for (i, ir) in f.ir.into_iter().enumerate() {
+        ice::set_position("IR instruction", i);
+        let lhs = ir.lhs.unwrap_or(0);
+        let rhs = ir.rhs.unwrap_or(0);
+        match ir.op {
+            Imm => emit!(out, "li {}, {}", REGS[lhs], rhs as i64),
+            Mov => emit!(out, "mv {}, {}", REGS[lhs], REGS[rhs]),
+            Return => {
+                emit!(out, "mv a0, {}", REGS[lhs]);
+                emit!(out, "j {}", ret);
+            }
+            ReturnStruct => {
+                let size = f.struct_ret.unwrap();
+                if sret {
+                    emit!(out, "ld a0, -8(s0)");
+                    copy_bytes(out, "a0", 0, REGS[lhs], size);
+                } else {
+                    load_bytes(out, "a0", REGS[lhs], 0, size.min(8));
+                    if size > 8 {
+                        load_bytes(out, "a1", REGS[lhs], 8, size - 8);
+                    }
+                }
+                emit!(out, "j {}", ret);
+            }
+            Call(name, args, _) => {
+                // Large structs are copied below the stack pointer.
+                let mut copies = vec![];
+                let mut frame = 0;
+                for arg in &args {
+                    copies.push(frame);
+                    if let CallArg::Struct(_, size) = *arg {
+                        if size > 16 {
+                            frame += roundup(size, 16);
+                        }
+                    }
+                }
+                calls = calls.max(frame);
+                if frame > 0 {
+                    emit_addi(out, "sp", "sp", -(frame as i64));
+                }
+
+                let mut ret_buf = None;
+                let mut next = 0;
+                for (i, arg) in args.iter().enumerate() {
+                    match *arg {
+                        CallArg::Val(r) => {
+                            emit!(out, "mv {}, {}", ARGREGS[next], REGS[r]);
+                            next += 1;
+                        }
+                        CallArg::Float(_) => unreachable!(),
+                        CallArg::Ret(r, size) => {
+                            ret_buf = Some((r, size));
+                            if size > 16 {
+                                emit!(out, "mv a0, {}", REGS[r]);
+                                next += 1;
+                            }
+                        }
+                        CallArg::Struct(r, size) if size > 16 => {
+                            copy_bytes(out, "sp", copies[i], REGS[r], size);
+                            emit!(out, "addi {}, sp, {}", ARGREGS[next], copies[i]);
+                            next += 1;
+                        }
+                        CallArg::Struct(r, size) => {
+                            for off in (0..size).step_by(8) {
+                                let n = (size - off).min(8);
+                                load_bytes(out, ARGREGS[next], REGS[r], off, n);
+                                next += 1;
+                            }
+                        }
+                    }
+                    if next > ARGREGS.len() {
+                        diagnostic::error(format!("too many arguments to {}", name), None);
+                    }
+                }
+                match ir.rhs {
+                    Some(callee) => emit!(out, "jalr {}", REGS[callee]),
+                    None => emit!(out, "call {}", name),
+                }
+                if frame > 0 {
+                    emit_addi(out, "sp", "sp", frame as i64);
+                }
+                emit!(out, "mv {}, a0", REGS[lhs]);
+                if let Some((r, size)) = ret_buf {
+                    if size <= 16 {
+                        store_bytes(out, REGS[r], 0, "a0", size.min(8));
+                        if size > 8 {
+                            store_bytes(out, REGS[r], 8, "a1", size - 8);
+                        }
+                    }
+                }
+            }
+            Label => {
+                let _ = writeln!(out, ".L{}:", lhs);
+            }
+            LabelAddr(name) => emit!(out, "la {}, {}", REGS[lhs], name),
+            // Initial-exec model: the GOT has the variable's offset from
+            // the thread pointer.
+            TlsAddr(name) => {
+                emit!(out, "la.tls.ie t0, {}", name);
+                emit!(out, "add {}, t0, tp", REGS[lhs]);
+            }
+            Neg => emit!(out, "neg {}, {}", REGS[lhs], REGS[lhs]),
+            Not => emit!(out, "not {}, {}", REGS[lhs], REGS[lhs]),
+            Sext(4) => emit!(out, "sext.w {}, {}", REGS[lhs], REGS[lhs]),
+            Sext(size) => {
+                let shift = 64 - size * 8;
+                emit!(out, "slli {}, {}, {}", REGS[lhs], REGS[lhs], shift);
+                emit!(out, "srai {}, {}, {}", REGS[lhs], REGS[lhs], shift);
+            }
+            Zext(size) => {
+                let shift = 64 - size * 8;
+                emit!(out, "slli {}, {}, {}", REGS[lhs], REGS[lhs], shift);
+                emit!(out, "srli {}, {}, {}", REGS[lhs], REGS[lhs], shift);
+            }
+            EQ => {
+                emit!(out, "sub {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]);
+                emit!(out, "seqz {}, {}", REGS[lhs], REGS[lhs]);
+            }
+            NE => {
+                emit!(out, "sub {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]);
+                emit!(out, "snez {}, {}", REGS[lhs], REGS[lhs]);
+            }
+            LT => emit!(out, "slt {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            LE => {
+                // a <= b is !(b < a)
+                emit!(out, "slt {}, {}, {}", REGS[lhs], REGS[rhs], REGS[lhs]);
+                emit!(out, "xori {}, {}, 1", REGS[lhs], REGS[lhs]);
+            }
+            ULT => emit!(out, "sltu {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            ULE => {
+                emit!(out, "sltu {}, {}, {}", REGS[lhs], REGS[rhs], REGS[lhs]);
+                emit!(out, "xori {}, {}, 1", REGS[lhs], REGS[lhs]);
+            }
+            AND => emit!(out, "and {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            OR => emit!(out, "or {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            XOR => emit!(out, "xor {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            SHL => emit!(out, "sll {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            SHR => emit!(out, "srl {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            SAR => emit!(out, "sra {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            Mod => emit!(out, "rem {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            UMod => emit!(out, "remu {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            Jmp => emit!(out, "j .L{}", lhs),
+            If => emit!(out, "bnez {}, .L{}", REGS[lhs], rhs),
+            Unless => emit!(out, "beqz {}, .L{}", REGS[lhs], rhs),
+            JumpTable(table) => {
+                emit!(out, "li t0, {}", table.len());
+                emit!(out, "bgeu {}, t0, .L{}", REGS[lhs], rhs);
+                let name = labels.new_label();
+                emit!(out, "la t0, {}", name);
+                emit!(out, "slli t1, {}, 3", REGS[lhs]);
+                emit!(out, "add t0, t0, t1");
+                emit!(out, "ld t0, 0(t0)");
+                emit!(out, "jr t0");
+                tables.push((name, table));
+            }
+            Load(size) => emit!(out, "{} {}, 0({})", load_insn(size), REGS[lhs], REGS[rhs]),
+            Store(size) => emit!(out, "{} {}, 0({})", store_insn(size), REGS[rhs], REGS[lhs]),
+            Copy(size) => copy_bytes(out, REGS[lhs], 0, REGS[rhs], size),
+            // The fences make a plain load sequentially consistent.
+            AtomicLoad(size) => {
+                emit!(out, "fence rw, rw");
+                emit!(out, "{} {}, 0({})", load_insn(size), REGS[lhs], REGS[rhs]);
+                emit!(out, "fence r, rw");
+            }
+            AtomicStore(size) => emit!(
+                out,
+                "amoswap.{}.aqrl zero, {}, ({})",
+                amo_suffix(size),
+                REGS[rhs],
+                REGS[lhs]
+            ),
+            AtomicAdd(size) => {
+                emit!(
+                    out,
+                    "amoadd.{}.aqrl t0, {}, ({})",
+                    amo_suffix(size),
+                    REGS[rhs],
+                    REGS[lhs]
+                );
+                emit!(out, "mv {}, t0", REGS[lhs]);
+            }
+            StoreArg(size) => {
+                emit_addi(out, "t0", "s0", -((lhs + base) as i64));
+                emit!(out, "{} {}, 0(t0)", store_insn(size), ARGREGS[params[rhs]]);
+            }
+            StoreStructArg(size) => {
+                emit_addi(out, "t0", "s0", -((lhs + base) as i64));
+                let reg = params[rhs];
+                if size > 16 {
+                    copy_bytes(out, "t0", 0, ARGREGS[reg], size);
+                } else {
+                    for (k, off) in (0..size).step_by(8).enumerate() {
+                        store_bytes(out, "t0", off, ARGREGS[reg + k], (size - off).min(8));
+                    }
+                }
+            }
+            VaStart => {
+                // Skip the saved a-registers that hold named arguments.
+                emit!(out, "addi t0, s0, {}", 16 + next * 8);
+                emit!(out, "sd t0, 0({})", REGS[lhs]);
+            }
+            VaArg(size) => {
+                // Structs of up to 16 bytes take one or two slots, and
+                // larger ones are passed by reference.
+                let slot = match size {
+                    Some(size) if size <= 16 => roundup(size, 8),
+                    _ => 8,
+                };
+                emit!(out, "ld {}, 0({})", REGS[lhs], REGS[rhs]);
+                emit!(out, "addi t0, {}, {}", REGS[lhs], slot);
+                emit!(out, "sd t0, 0({})", REGS[rhs]);
+                if size.is_some_and(|size| size > 16) {
+                    emit!(out, "ld {}, 0({})", REGS[lhs], REGS[lhs]);
+                }
+            }
+            Add => emit!(out, "add {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            AddImm => emit_addi(out, REGS[lhs], REGS[lhs], rhs as i32 as i64),
+            Sub => emit!(out, "sub {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            SubImm => emit_addi(out, REGS[lhs], REGS[lhs], -(rhs as i32 as i64)),
+            Bprel => emit_addi(out, REGS[lhs], "s0", -((rhs + base) as i64)),
+            Mul => emit!(out, "mul {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            MulImm => {
+                emit!(out, "li t0, {}", rhs as i32);
+                emit!(out, "mul {}, {}, t0", REGS[lhs], REGS[lhs]);
+            }
+            Div => emit!(out, "div {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            UDiv => emit!(out, "divu {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            Asm(text) => {
+                for line in text.lines() {
+                    emit!(out, "{}", line.trim());
+                }
+            }
+            Comment(text) => emit!(out, "# {}", text),
+            Loc(file, line) => emit!(out, ".loc {} {}", debug_file(files, &file), line),
+            Trap | Unreachable => emit!(out, "ebreak"),
+            // Rounding the size up keeps sp aligned.
+            Alloca => {
+                emit!(out, "addi t0, {}, 15", REGS[rhs]);
+                emit!(out, "andi t0, t0, -16");
+                emit!(out, "sub sp, sp, t0");
+                emit!(out, "mv {}, sp", REGS[lhs]);
+            }
+            Nop | Kill | Count(_) => (),
+            // compile_nodes rejects floating point for this backend.
+            FAdd | FSub | FMul | FDiv | FEQ | FNE | FLT | FLE | I2F | F2I | FExt | FTrunc
+            | StoreFloatArg(_) | VaArgFloat => unreachable!(),
+        }
+    }
+
+    let _ = writeln!(out, "{}:", ret);
+    // alloca moved sp, so the saved registers are found from s0.
+    if alloca {
+        emit_addi(out, "sp", "s0", -frame);
+    }
+    for (i, r) in REGS.iter().enumerate() {
+        emit!(out, "ld {}, {}(sp)", r, i * 8);
+    }
+    emit!(out, "mv sp, s0");
+    emit!(out, "ld s0, 0(sp)");
+    emit!(out, "ld ra, 8(sp)");
+    emit!(out, "addi sp, sp, {}", 16 + varargs);
+    emit!(out, "ret");
+    emit!(out, ".size {}, .-{}", f.name, f.name);
+
+    for (name, table) in tables {
+        out.push_str(".section .rodata\n");
+        emit!(out, ".balign 8");
+        let _ = writeln!(out, "{}:", name);
+        for x in table {
+            emit!(out, ".dword .L{}", x);
+        }
+    }
+    varargs + 16 + frame as usize + calls
+}
+
+pub struct Riscv64;
+
+impl Target for Riscv64 {
+    fn name(&self) -> &'static str {
+        "riscv64"
+    }
+
+    fn regs(&self) -> &'static [&'static str] {
+        &REGS
+    }
+
+    fn arg_regs(&self) -> &'static [&'static str] {
+        &ARGREGS
+    }
+
+    fn macros(&self) -> &'static [(&'static str, i32)] {
+        &[
+            ("__riscv", 1),
+            ("__riscv_xlen", 64),
+            ("__riscv_mul", 1),
+            ("__riscv_atomic", 1),
+            ("__LP64__", 1),
+            ("_LP64", 1),
+            ("__ELF__", 1),
+            ("__unix__", 1),
+            ("__linux__", 1),
+        ]
+    }
+
+    fn llvm_triple(&self) -> &'static str {
+        "riscv64-unknown-linux-gnu"
+    }
+
+    fn debug_info(&self) -> bool {
+        true
+    }
+
+    fn thread_local(&self) -> bool {
+        true
+    }
+
+    fn gen(&self, ctx: &mut CodegenContext, globals: Vec<Var>, fns: Vec<Function>) -> String {
+        gen_riscv(ctx, globals, fns)
+    }
+}
+
+pub fn gen_riscv(ctx: &mut CodegenContext, globals: Vec<Var>, fns: Vec<Function>) -> String {
+    let mut out = String::new();
+    for var in globals {
+        if let Scope::Global(data, len, is_extern, is_tls, is_static) = var.scope {
+            if is_extern {
+                continue;
+            }
+            // String literals are read-only, and zero-initialized
+            // variables take no space in the object file.
+            let is_str = var.name.starts_with(".L");
+            if is_str {
+                out.push_str(".section .rodata\n");
+            } else if is_tls && data.is_empty() {
+                out.push_str(".section .tbss,\"awT\",@nobits\n");
+            } else if is_tls {
+                out.push_str(".section .tdata,\"awT\",@progbits\n");
+            } else if data.is_empty() {
+                out.push_str(".bss\n");
+            } else {
+                out.push_str(".data\n");
+            }
+            // Misaligned accesses may trap, so honor the type's alignment.
+            emit!(out, ".balign {}", var.ty.align.max(1));
+            if !is_str {
+                if !is_static {
+                    let _ = writeln!(out, ".global {}", var.name);
+                }
+                let ty = if is_tls { "@tls_object" } else { "@object" };
+                emit!(out, ".type {}, {}", var.name, ty);
+                emit!(out, ".size {}, {}", var.name, len);
+            }
+            let _ = writeln!(out, "{}:", var.name);
+            emit_data(&mut out, &data, len, &var.relocs, str::to_string);
+            continue;
+        }
+        unreachable!();
+    }
+
+    let files = debug_files(&fns);
+    for (i, file) in files.iter().enumerate() {
+        emit!(out, ".file {} \"{}\"", i + 1, file);
+    }
+    let bodies = parallel::map(fns, ctx.jobs, |f| {
+        let mut out = String::new();
+        let stack = gen(&mut out, f, &files);
+        (out, stack)
+    });
+    ctx.append_framed(&mut out, bodies);
+    // Without this, the linker takes the stack to need to be executable.
+    out.push_str(".section .note.GNU-stack,\"\",@progbits\n");
+    out
+}
@@ -0,0 +1,783 @@
+use crate::diagnostic;
+use crate::gen_ir::{CodegenContext, Function, IROp};
+use crate::ice;
+use crate::matches;
+use crate::parallel;
+use crate::target::Target;
+use crate::util::{emit, hex_escape, roundup};
+use crate::{Scope, Var, REGS_N};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+// This pass generates a WebAssembly module in the text format (WAT)
+// from IR. The module imports undefined functions and extern globals
+// from "env", and exports its linear memory and every function that
+// isn't static.
+//
+// IR registers become i64 locals $r0-$r6. Pointers are 4 bytes in
+// memory, but IR registers are 64-bit, so they are kept as i64 too and
+// wrapped to i32 right before each memory access.
+//
+// Exported functions take and return i32s for ints, longs and
+// pointers, as the C ABI for wasm32 has it, so that a host sees
+// numbers rather than BigInts. Each is a wrapper that extends its
+// arguments and calls the function the module calls, which is all i64.
+//
+// Local variables live on a shadow stack in linear memory that grows
+// downward from the top of the stack area. $fp plays the role of the
+// frame pointer (rbp on x86), so `Bprel` is simply `$fp - offset`.
+//
+// A function has no address in linear memory. A pointer to one is its
+// index in the module's table instead, which `call_indirect` calls
+// through. Only functions the module defines are in the table.
+//
+// Structs are passed by address, and the callee copies them into its
+// own frame. A function returning a struct takes the address to store
+// it to as a hidden first parameter.
+//
+// A wasm function has a fixed number of parameters, so variadic
+// arguments are passed the way clang does it: the caller stores them
+// in 8-byte slots on the shadow stack, and passes the address of the
+// first one as a hidden last parameter, $va.
+//
+// WebAssembly has no goto, but our IR is a flat list of labels and
+// jumps. We recover structured control flow with a dispatch loop: each
+// label starts a basic block, all blocks are nested `block`s inside a
+// `loop`, and a `br_table` on $pc at the top jumps into the right one.
+// A jump sets $pc and branches back to the loop header, while falling
+// off the end of one block enters the next, just like in assembly.
+//
+// Linear memory layout:
+//
+//   +-------------------+ 0
+//   | (unused)          |
+//   +-------------------+ DATA_BASE
+//   | global variables  |
+//   +-------------------+
+//   | stack             |
+//   +-------------------+ <- initial __stack_pointer
+//   | (free for host)   |
+//   +-------------------+ end of memory
+
+const REGS: [&str; REGS_N] = ["$r0", "$r1", "$r2", "$r3", "$r4", "$r5", "$r6"];
+const ARGREGS: [&str; 6] = ["$a0", "$a1", "$a2", "$a3", "$a4", "$a5"];
+
+const PTR_SIZE: u8 = 4;
+const DATA_BASE: usize = 1024; // Keeps address 0 free for NULL.
+const STACK_SIZE: usize = 64 * 1024;
+const PAGE_SIZE: usize = 64 * 1024;
+
+// Where a global symbol lives.
+enum Symbol {
+    Data(usize), // address in linear memory
+    Import,      // extern variable, address provided by the host
+}
+
+struct Module {
+    symbols: HashMap<String, Symbol>,
+    funcs: HashMap<String, usize>,     // defined function -> #params
+    variadic: HashSet<String>,         // defined functions taking "..."
+    imports: HashSet<(String, usize)>, // called but undefined (name, #args)
+    table: HashMap<String, usize>,     // function whose address is taken -> index
+}
+
+fn load_insn(size: u8) -> &'static str {
+    match size {
+        1 => "i64.load8_u",
+        4 => "i64.load32_s",
+        8 => "i64.load",
+        _ => unreachable!(),
+    }
+}
+
+fn store_insn(size: u8) -> &'static str {
+    match size {
+        1 => "i64.store8",
+        4 => "i64.store32",
+        8 => "i64.store",
+        _ => unreachable!(),
+    }
+}
+
+// lhs = lhs <insn> rhs
+fn emit_binop(out: &mut String, lhs: usize, rhs: usize, insn: &str) {
+    emit!(out, "local.get {}", REGS[lhs]);
+    emit!(out, "local.get {}", REGS[rhs]);
+    emit!(out, "{}", insn);
+    emit!(out, "local.set {}", REGS[lhs]);
+}
+
+// lhs = lhs <insn> imm
+fn emit_binop_imm(out: &mut String, lhs: usize, imm: i64, insn: &str) {
+    emit!(out, "local.get {}", REGS[lhs]);
+    emit!(out, "i64.const {}", imm);
+    emit!(out, "{}", insn);
+    emit!(out, "local.set {}", REGS[lhs]);
+}
+
+// Comparisons produce an i32, which we widen back to 0 or 1.
+fn emit_cmp(out: &mut String, lhs: usize, rhs: usize, insn: &str) {
+    emit!(out, "local.get {}", REGS[lhs]);
+    emit!(out, "local.get {}", REGS[rhs]);
+    emit!(out, "{}", insn);
+    emit!(out, "i64.extend_i32_u");
+    emit!(out, "local.set {}", REGS[lhs]);
+}
+
+fn emit_jmp(out: &mut String, block: usize) {
+    emit!(out, "i32.const {}", block);
+    emit!(out, "local.set $pc");
+    emit!(out, "br $dispatch");
+}
+
+fn emit_epilogue(out: &mut String) {
+    emit!(out, "local.get $fp");
+    emit!(out, "i32.wrap_i64");
+    emit!(out, "global.set $__stack_pointer");
+}
+
+// Copies `size` bytes from the address in local src to the address in
+// local dst.
+fn emit_copy(out: &mut String, dst: &str, src: &str, size: usize) {
+    let mut i = 0;
+    while i < size {
+        let (n, load, store) = match size - i {
+            8.. => (8, "i64.load", "i64.store"),
+            4..=7 => (4, "i64.load32_u", "i64.store32"),
+            _ => (1, "i64.load8_u", "i64.store8"),
+        };
+        emit!(out, "local.get {}", dst);
+        emit!(out, "i32.wrap_i64");
+        emit!(out, "local.get {}", src);
+        emit!(out, "i32.wrap_i64");
+        emit!(out, "{} offset={}", load, i);
+        emit!(out, "{} offset={}", store, i);
+        i += n;
+    }
+}
+
+fn nparams(f: &Function) -> usize {
+    let sret = if f.struct_ret.is_some() { 1 } else { 0 };
+    sret + f
+        .ir
+        .iter()
+        .filter(|ir| matches!(ir.op, IROp::StoreArg(_)) || matches!(ir.op, IROp::StoreStructArg(_)))
+        .count()
+}
+
+// Emits the export of `f`, which converts its arguments and value
+// between i32 and the i64 that the module uses for them. Only a long
+// long stays an i64.
+fn emit_export(out: &mut String, f: &Function) {
+    let mut params = vec![];
+    if f.struct_ret.is_some() {
+        params.push("i32");
+    }
+    for ir in &f.ir {
+        match ir.op {
+            IROp::StoreArg(8) => params.push("i64"),
+            IROp::StoreArg(_) | IROp::StoreStructArg(_) => params.push("i32"),
+            _ => (),
+        }
+    }
+    if f.variadic {
+        params.push("i32");
+    }
+    let result = match f.ret_size {
+        _ if f.struct_ret.is_some() => Some("i32"),
+        0 => None,
+        8 => Some("i64"),
+        _ => Some("i32"),
+    };
+
+    let _ = write!(out, "(func (export \"{}\")", f.name);
+    for ty in &params {
+        let _ = write!(out, " (param {})", ty);
+    }
+    if let Some(ty) = result {
+        let _ = write!(out, " (result {})", ty);
+    }
+    out.push('\n');
+    for (i, &ty) in params.iter().enumerate() {
+        emit!(out, "local.get {}", i);
+        if ty == "i32" {
+            emit!(out, "i64.extend_i32_s");
+        }
+    }
+    emit!(out, "call ${}", f.name);
+    match result {
+        Some("i32") => emit!(out, "i32.wrap_i64"),
+        None => emit!(out, "drop"),
+        _ => (),
+    }
+    out.push_str(")\n");
+}
+
+// Emits `f`, returning the bytes of the stack in linear memory it uses,
+// down to the deepest call's variadic arguments.
+fn gen(out: &mut String, m: &Module, f: Function) -> usize {
+    use self::IROp::*;
+    ice::set_function(&f.name);
+
+    // Split the function into basic blocks at labels. Block 0 is the
+    // entry block, which has no label of its own.
+    let mut blocks = HashMap::new();
+    let mut nblocks = 1;
+    for ir in &f.ir {
+        if ir.op == Label {
+            blocks.insert(ir.lhs.unwrap(), nblocks);
+            nblocks += 1;
+        }
+    }
+
+    if !f.is_static {
+        emit_export(out, &f);
+    }
+    let _ = write!(out, "(func ${}", f.name);
+    for a in ARGREGS.iter().take(nparams(&f)) {
+        let _ = write!(out, " (param {} i64)", a);
+    }
+    if f.variadic {
+        out.push_str(" (param $va i64)");
+    }
+    out.push_str(" (result i64)\n");
+    emit!(out, "(local $fp i64)");
+    emit!(out, "(local $pc i32)");
+    emit!(out, "(local $tmp i64)");
+    for r in REGS.iter() {
+        emit!(out, "(local {} i64)", r);
+    }
+
+    // Allocate the stack frame.
+    let frame = roundup(f.stacksize, 16);
+    let mut calls = 0;
+    emit!(out, "global.get $__stack_pointer");
+    emit!(out, "i64.extend_i32_u");
+    emit!(out, "local.set $fp");
+    emit!(out, "global.get $__stack_pointer");
+    emit!(out, "i32.const {}", frame);
+    emit!(out, "i32.sub");
+    emit!(out, "global.set $__stack_pointer");
+
+    // Dispatch loop header.
+    emit!(out, "loop $dispatch");
+    for i in (0..nblocks).rev() {
+        emit!(out, "block $B{}", i);
+    }
+    emit!(out, "local.get $pc");
+    let targets = (0..nblocks)
+        .map(|i| format!("$B{}", i))
+        .collect::<Vec<_>>()
+        .join(" ");
+    emit!(out, "br_table {} $B0", targets);
+    emit!(out, "end");
+
+    // Parameters come after the struct return address, if any.
+    let first_param = if f.struct_ret.is_some() { 1 } else { 0 };
+
+    for (i, ir) in f.ir.into_iter().enumerate() {
+        ice::set_position("IR instruction", i);
+        let lhs = ir.lhs.unwrap_or(0);
+        let rhs = ir.rhs.unwrap_or(0);
+        match ir.op {
+            Imm => {
+                emit!(out, "i64.const {}", rhs as i64);
+                emit!(out, "local.set {}", REGS[lhs]);
+            }
+            Mov => {
+                emit!(out, "local.get {}", REGS[rhs]);
+                emit!(out, "local.set {}", REGS[lhs]);
+            }
+            Return => {
+                emit_epilogue(out);
+                emit!(out, "local.get {}", REGS[lhs]);
+                emit!(out, "return");
+            }
+            ReturnStruct => {
+                emit_copy(out, "$a0", REGS[lhs], f.struct_ret.unwrap());
+                emit_epilogue(out);
+                emit!(out, "local.get $a0");
+                emit!(out, "return");
+            }
+            Call(name, args, _) => {
+                // Structs are passed by address, whether named or not.
+                let named = if m.variadic.contains(&name) {
+                    m.funcs[&name]
+                } else {
+                    args.len()
+                };
+                let frame = roundup((args.len() - named) * 8, 16);
+                calls = calls.max(frame);
+                if frame > 0 {
+                    emit!(out, "global.get $__stack_pointer");
+                    emit!(out, "i32.const {}", frame);
+                    emit!(out, "i32.sub");
+                    emit!(out, "global.set $__stack_pointer");
+                    for (k, arg) in args[named..].iter().enumerate() {
+                        emit!(out, "global.get $__stack_pointer");
+                        emit!(out, "local.get {}", REGS[arg.reg()]);
+                        emit!(out, "i64.store offset={}", k * 8);
+                    }
+                }
+                for arg in &args[..named] {
+                    emit!(out, "local.get {}", REGS[arg.reg()]);
+                }
+                if m.variadic.contains(&name) {
+                    emit!(out, "global.get $__stack_pointer");
+                    emit!(out, "i64.extend_i32_u");
+                }
+                if let Some(callee) = ir.rhs {
+                    emit!(out, "local.get {}", REGS[callee]);
+                    emit!(out, "i32.wrap_i64");
+                    emit!(
+                        out,
+                        "call_indirect{} (result i64)",
+                        " (param i64)".repeat(args.len())
+                    );
+                } else if m.funcs.contains_key(&name) {
+                    emit!(out, "call ${}", name);
+                } else {
+                    emit!(out, "call ${}.{}", name, args.len());
+                }
+                if frame > 0 {
+                    emit!(out, "global.get $__stack_pointer");
+                    emit!(out, "i32.const {}", frame);
+                    emit!(out, "i32.add");
+                    emit!(out, "global.set $__stack_pointer");
+                }
+                emit!(out, "local.set {}", REGS[lhs]);
+            }
+            VaStart => {
+                emit!(out, "local.get {}", REGS[lhs]);
+                emit!(out, "i32.wrap_i64");
+                emit!(out, "local.get $va");
+                emit!(out, "{}", store_insn(PTR_SIZE));
+            }
+            // The va_list is a pointer to the next 8-byte slot.
+            VaArg(size) => {
+                emit!(out, "local.get {}", REGS[rhs]);
+                emit!(out, "i32.wrap_i64");
+                emit!(out, "{}", load_insn(PTR_SIZE));
+                emit!(out, "local.set {}", REGS[lhs]);
+                emit!(out, "local.get {}", REGS[rhs]);
+                emit!(out, "i32.wrap_i64");
+                emit!(out, "local.get {}", REGS[lhs]);
+                emit!(out, "i64.const 8");
+                emit!(out, "i64.add");
+                emit!(out, "{}", store_insn(PTR_SIZE));
+                // Structs are passed by address.
+                if size.is_some() {
+                    emit!(out, "local.get {}", REGS[lhs]);
+                    emit!(out, "i32.wrap_i64");
+                    emit!(out, "i64.load");
+                    emit!(out, "local.set {}", REGS[lhs]);
+                }
+            }
+            Label => {
+                // Close the previous block; this label's code follows.
+                emit!(out, "end");
+                let _ = writeln!(out, ";; .L{}", lhs);
+            }
+            // A module runs on a single thread, so thread-local variables
+            // are ordinary globals.
+            LabelAddr(name) | TlsAddr(name) => {
+                match m.symbols.get(&name) {
+                    Some(Symbol::Data(addr)) => emit!(out, "i64.const {}", addr),
+                    Some(Symbol::Import) => {
+                        emit!(out, "global.get ${}", name);
+                        emit!(out, "i64.extend_i32_u");
+                    }
+                    None => match m.table.get(&name) {
+                        Some(index) => emit!(out, "i64.const {}", index),
+                        None => diagnostic::error(
+                            format!("cannot take the address of undefined function {}", name),
+                            None,
+                        ),
+                    },
+                }
+                emit!(out, "local.set {}", REGS[lhs]);
+            }
+            Neg => {
+                emit!(out, "i64.const 0");
+                emit!(out, "local.get {}", REGS[lhs]);
+                emit!(out, "i64.sub");
+                emit!(out, "local.set {}", REGS[lhs]);
+            }
+            Not => {
+                emit!(out, "local.get {}", REGS[lhs]);
+                emit!(out, "i64.const -1");
+                emit!(out, "i64.xor");
+                emit!(out, "local.set {}", REGS[lhs]);
+            }
+            Sext(size) | Zext(size) => {
+                let shift = 64 - size * 8;
+                emit!(out, "local.get {}", REGS[lhs]);
+                emit!(out, "i64.const {}", shift);
+                emit!(out, "i64.shl");
+                emit!(out, "i64.const {}", shift);
+                if let Sext(_) = ir.op {
+                    emit!(out, "i64.shr_s");
+                } else {
+                    emit!(out, "i64.shr_u");
+                }
+                emit!(out, "local.set {}", REGS[lhs]);
+            }
+            EQ => emit_cmp(out, lhs, rhs, "i64.eq"),
+            NE => emit_cmp(out, lhs, rhs, "i64.ne"),
+            LT => emit_cmp(out, lhs, rhs, "i64.lt_s"),
+            LE => emit_cmp(out, lhs, rhs, "i64.le_s"),
+            ULT => emit_cmp(out, lhs, rhs, "i64.lt_u"),
+            ULE => emit_cmp(out, lhs, rhs, "i64.le_u"),
+            AND => emit_binop(out, lhs, rhs, "i64.and"),
+            OR => emit_binop(out, lhs, rhs, "i64.or"),
+            XOR => emit_binop(out, lhs, rhs, "i64.xor"),
+            SHL => emit_binop(out, lhs, rhs, "i64.shl"),
+            SHR => emit_binop(out, lhs, rhs, "i64.shr_u"),
+            SAR => emit_binop(out, lhs, rhs, "i64.shr_s"),
+            Mod => emit_binop(out, lhs, rhs, "i64.rem_s"),
+            UMod => emit_binop(out, lhs, rhs, "i64.rem_u"),
+            Jmp => emit_jmp(out, blocks[&lhs]),
+            If | Unless => {
+                emit!(out, "local.get {}", REGS[lhs]);
+                if ir.op == If {
+                    emit!(out, "i64.const 0");
+                    emit!(out, "i64.ne");
+                } else {
+                    emit!(out, "i64.eqz");
+                }
+                emit!(out, "if");
+                emit_jmp(out, blocks[&rhs]);
+                emit!(out, "end");
+            }
+            JumpTable(table) => {
+                // Out-of-range indices, including negative ones, go to
+                // the default label.
+                emit!(out, "local.get {}", REGS[lhs]);
+                emit!(out, "i64.const {}", table.len());
+                emit!(out, "i64.ge_u");
+                emit!(out, "if");
+                emit_jmp(out, blocks[&rhs]);
+                emit!(out, "end");
+
+                // br_table to the i-th of these nested blocks, each of
+                // which is followed by a jump to table[i].
+                for _ in 0..table.len() {
+                    emit!(out, "block");
+                }
+                emit!(out, "local.get {}", REGS[lhs]);
+                emit!(out, "i32.wrap_i64");
+                let depths = (0..table.len())
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                emit!(out, "br_table {} 0", depths);
+                for x in table {
+                    emit!(out, "end");
+                    emit_jmp(out, blocks[&x]);
+                }
+            }
+            Load(size) | AtomicLoad(size) => {
+                emit!(out, "local.get {}", REGS[rhs]);
+                emit!(out, "i32.wrap_i64");
+                emit!(out, "{}", load_insn(size));
+                emit!(out, "local.set {}", REGS[lhs]);
+            }
+            Copy(size) => emit_copy(out, REGS[lhs], REGS[rhs], size),
+            Store(size) | AtomicStore(size) => {
+                emit!(out, "local.get {}", REGS[lhs]);
+                emit!(out, "i32.wrap_i64");
+                emit!(out, "local.get {}", REGS[rhs]);
+                emit!(out, "{}", store_insn(size));
+            }
+            // Our modules don't use shared memory, so there is only one
+            // thread, and plain accesses are as good as atomic ones.
+            // The epilogue frees it, setting the stack pointer back to
+            // $fp.
+            Alloca => {
+                emit!(out, "global.get $__stack_pointer");
+                emit!(out, "local.get {}", REGS[rhs]);
+                emit!(out, "i32.wrap_i64");
+                emit!(out, "i32.const 15");
+                emit!(out, "i32.add");
+                emit!(out, "i32.const -16");
+                emit!(out, "i32.and");
+                emit!(out, "i32.sub");
+                emit!(out, "global.set $__stack_pointer");
+                emit!(out, "global.get $__stack_pointer");
+                emit!(out, "i64.extend_i32_u");
+                emit!(out, "local.set {}", REGS[lhs]);
+            }
+            AtomicAdd(size) => {
+                emit!(out, "local.get {}", REGS[lhs]);
+                emit!(out, "local.set $tmp");
+                emit!(out, "local.get $tmp");
+                emit!(out, "i32.wrap_i64");
+                emit!(out, "{}", load_insn(size));
+                emit!(out, "local.set {}", REGS[lhs]);
+                emit!(out, "local.get $tmp");
+                emit!(out, "i32.wrap_i64");
+                emit!(out, "local.get {}", REGS[lhs]);
+                emit!(out, "local.get {}", REGS[rhs]);
+                emit!(out, "i64.add");
+                emit!(out, "{}", store_insn(size));
+            }
+            StoreArg(size) => {
+                emit!(out, "local.get $fp");
+                emit!(out, "i64.const {}", lhs);
+                emit!(out, "i64.sub");
+                emit!(out, "i32.wrap_i64");
+                emit!(out, "local.get {}", ARGREGS[first_param + rhs]);
+                emit!(out, "{}", store_insn(size));
+            }
+            StoreStructArg(size) => {
+                emit!(out, "local.get $fp");
+                emit!(out, "i64.const {}", lhs);
+                emit!(out, "i64.sub");
+                emit!(out, "local.set $tmp");
+                emit_copy(out, "$tmp", ARGREGS[first_param + rhs], size);
+            }
+            Add => emit_binop(out, lhs, rhs, "i64.add"),
+            AddImm => emit_binop_imm(out, lhs, rhs as i32 as i64, "i64.add"),
+            Sub => emit_binop(out, lhs, rhs, "i64.sub"),
+            SubImm => emit_binop_imm(out, lhs, rhs as i32 as i64, "i64.sub"),
+            Bprel => {
+                emit!(out, "local.get $fp");
+                emit!(out, "i64.const {}", rhs);
+                emit!(out, "i64.sub");
+                emit!(out, "local.set {}", REGS[lhs]);
+            }
+            Mul => emit_binop(out, lhs, rhs, "i64.mul"),
+            MulImm => emit_binop_imm(out, lhs, rhs as i32 as i64, "i64.mul"),
+            Div => emit_binop(out, lhs, rhs, "i64.div_s"),
+            UDiv => emit_binop(out, lhs, rhs, "i64.div_u"),
+            // Instructions are spliced into the function body, so
+            // they must leave the operand stack balanced.
+            Asm(text) => {
+                for line in text.lines() {
+                    emit!(out, "{}", line.trim());
+                }
+            }
+            Comment(text) => emit!(out, ";; {}", text),
+            Loc(_, _) => unreachable!(),
+            Trap | Unreachable => emit!(out, "unreachable"),
+            Nop | Kill | Count(_) => (),
+            // compile_nodes rejects floating point for this backend.
+            FAdd | FSub | FMul | FDiv | FEQ | FNE | FLT | FLE | I2F | F2I | FExt | FTrunc
+            | StoreFloatArg(_) | VaArgFloat => unreachable!(),
+        }
+    }
+
+    // Falling off the end of the function returns 0.
+    emit!(out, "end");
+    emit_epilogue(out);
+    emit!(out, "i64.const 0");
+    out.push_str(")\n");
+    frame + calls
+}
+
+pub struct Wasm32;
+
+impl Target for Wasm32 {
+    fn name(&self) -> &'static str {
+        "wasm32"
+    }
+
+    fn regs(&self) -> &'static [&'static str] {
+        &REGS
+    }
+
+    fn arg_regs(&self) -> &'static [&'static str] {
+        &ARGREGS
+    }
+
+    fn macros(&self) -> &'static [(&'static str, i32)] {
+        &[
+            ("__wasm__", 1),
+            ("__wasm32__", 1),
+            ("__ILP32__", 1),
+            ("_ILP32", 1),
+        ]
+    }
+
+    fn ptr_size(&self) -> usize {
+        PTR_SIZE as usize
+    }
+
+    fn long_size(&self) -> usize {
+        4
+    }
+
+    fn llvm_triple(&self) -> &'static str {
+        "wasm32-unknown-unknown"
+    }
+
+    // The text module can be loaded directly by runtimes like wasmtime.
+    fn needs_linking(&self) -> bool {
+        false
+    }
+
+    fn thread_local(&self) -> bool {
+        true
+    }
+
+    fn gen(&self, ctx: &mut CodegenContext, globals: Vec<Var>, fns: Vec<Function>) -> String {
+        gen_wasm(ctx, globals, fns)
+    }
+}
+
+pub fn gen_wasm(ctx: &mut CodegenContext, globals: Vec<Var>, fns: Vec<Function>) -> String {
+    let mut m = Module {
+        symbols: HashMap::new(),
+        funcs: HashMap::new(),
+        variadic: HashSet::new(),
+        imports: HashSet::new(),
+        table: HashMap::new(),
+    };
+    for f in &fns {
+        m.funcs.insert(f.name.clone(), nparams(f));
+        if f.variadic {
+            m.variadic.insert(f.name.clone());
+        }
+    }
+
+    // Lay out global variables in linear memory. Memory starts out
+    // zeroed, so only initialized data needs a segment.
+    let mut segments = vec![];
+    let mut addr = DATA_BASE;
+    for var in globals {
+        if let Scope::Global(init, len, is_extern, ..) = var.scope {
+            if is_extern {
+                m.symbols.insert(var.name, Symbol::Import);
+                continue;
+            }
+            addr = roundup(addr, var.ty.align.max(1));
+            if !init.is_empty() {
+                segments.push((addr, init, len, var.relocs));
+            }
+            m.symbols.insert(var.name, Symbol::Data(addr));
+            addr += len;
+            continue;
+        }
+        unreachable!();
+    }
+    let stack_top = roundup(addr, 16) + STACK_SIZE;
+    let pages = roundup(stack_top, PAGE_SIZE) / PAGE_SIZE;
+
+    // Index 0 of the table is left empty for NULL.
+    let mut table = vec![];
+    for f in &fns {
+        for ir in &f.ir {
+            match ir.op {
+                IROp::Call(ref name, ref args, _)
+                    if ir.rhs.is_none() && !m.funcs.contains_key(name) =>
+                {
+                    m.imports.insert((name.clone(), args.len()));
+                }
+                IROp::LabelAddr(ref name)
+                    if m.funcs.contains_key(name) && !m.table.contains_key(name) =>
+                {
+                    table.push(format!("${}", name));
+                    m.table.insert(name.clone(), table.len());
+                }
+                _ => (),
+            }
+        }
+    }
+    for (_, _, _, relocs) in &segments {
+        for reloc in relocs {
+            if m.funcs.contains_key(&reloc.name) && !m.table.contains_key(&reloc.name) {
+                table.push(format!("${}", reloc.name));
+                m.table.insert(reloc.name.clone(), table.len());
+            }
+        }
+    }
+
+    // Addresses in initializers are known now, so they are written
+    // into the data.
+    let mut data = String::new();
+    for (addr, mut init, len, relocs) in segments {
+        init.resize(len, 0);
+        for reloc in relocs {
+            let base = match m.symbols.get(&reloc.name) {
+                Some(Symbol::Data(addr)) => *addr as i64,
+                Some(Symbol::Import) => diagnostic::error(
+                    format!(
+                        "the address of extern variable {} is not a constant",
+                        reloc.name
+                    ),
+                    None,
+                ),
+                None => match m.table.get(&reloc.name) {
+                    Some(index) => *index as i64,
+                    None => diagnostic::error(
+                        format!(
+                            "cannot take the address of undefined function {}",
+                            reloc.name
+                        ),
+                        None,
+                    ),
+                },
+            };
+            let val = base.wrapping_add(reloc.addend).to_le_bytes();
+            let size = PTR_SIZE as usize;
+            init[reloc.offset..reloc.offset + size].copy_from_slice(&val[..size]);
+        }
+        let _ = writeln!(
+            data,
+            "(data (i32.const {}) \"{}\")",
+            addr,
+            hex_escape(&init, len)
+        );
+    }
+
+    let mut out = String::new();
+    out.push_str("(module\n");
+
+    // Imports must come before any definitions. C functions such as
+    // printf may be called with different numbers of arguments, so we
+    // import one wasm function per arity.
+    let mut imports = m.imports.iter().collect::<Vec<_>>();
+    imports.sort();
+    for (name, nargs) in imports {
+        let _ = writeln!(
+            out,
+            "(import \"env\" \"{}\" (func ${}.{}{} (result i64)))",
+            name,
+            name,
+            nargs,
+            " (param i64)".repeat(*nargs)
+        );
+    }
+    let mut externs = m
+        .symbols
+        .iter()
+        .filter(|(_, sym)| matches!(sym, Symbol::Import))
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    externs.sort();
+    for name in externs {
+        let _ = writeln!(out, "(import \"env\" \"{}\" (global ${} i32))", name, name);
+    }
+
+    let _ = writeln!(out, "(memory (export \"memory\") {})", pages);
+    let _ = writeln!(
+        out,
+        "(global $__stack_pointer (mut i32) (i32.const {}))",
+        stack_top
+    );
+    out.push_str(&data);
+    if !table.is_empty() {
+        let _ = writeln!(out, "(table {} funcref)", table.len() + 1);
+        let _ = writeln!(out, "(elem (i32.const 1) func {})", table.join(" "));
+    }
+
+    let bodies = parallel::map(fns, ctx.jobs, |f| {
+        let mut out = String::new();
+        let stack = gen(&mut out, &m, f);
+        (out, stack)
+    });
+    ctx.append_framed(&mut out, bodies);
+    out.push_str(")\n");
+    out
+}
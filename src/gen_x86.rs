@@ -1,69 +1,94 @@
-use crate::gen_ir::{Function, IROp, IR};
-use crate::util::roundup;
-use crate::{Scope, Var, REGS_N};
+use crate::assemble::assemble;
+use crate::elf::Object;
+use crate::gen_ir::{
+    debug_file, debug_files, CallArg, CodegenContext, FnLabels, Function, IROp, COLD_SECTION, IR,
+};
+use crate::ice;
+use crate::matches;
+use crate::parallel;
+use crate::target::{Os, Target};
+use crate::util::{emit, emit_data, roundup};
+use crate::{Ctype, Scope, Type, Var, REGS_N};
+
+use std::fmt::Write;
+use std::rc::Rc;
 
 const REGS: [&str; REGS_N] = ["r10", "r11", "rbx", "r12", "r13", "r14", "r15"];
 const REGS8: [&str; REGS_N] = ["r10b", "r11b", "bl", "r12b", "r13b", "r14b", "r15b"];
 const REGS32: [&str; REGS_N] = ["r10d", "r11d", "ebx", "r12d", "r13d", "r14d", "r15d"];
 
-use std::sync::Mutex;
-
 // Quoted from 9cc
 // > This pass generates x86-64 assembly from IR.
 
-const ARGREGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
-const ARGREGS8: [&str; 6] = ["dil", "sil", "dl", "cl", "r8b", "r9b"];
-const ARGREGS32: [&str; 6] = ["edi", "esi", "edx", "ecx", "r8d", "r9d"];
-
-lazy_static! {
-    static ref LABEL: Mutex<usize> = Mutex::new(0);
-}
-
-fn backslash_escape(s: String, len: usize) -> String {
-    let mut sb = String::new();
-    for i in 0..len {
-        if let Some(c) = s.chars().collect::<Vec<char>>().get(i) {
-            // Issue: https://github.com/rust-lang/rfcs/issues/751
-            let escaped = match c {
-                // '\b' => Some('b'),
-                // '\f' => Some('f'),
-                '\n' => Some('n'),
-                '\r' => Some('r'),
-                '\t' => Some('t'),
-                '\\' => Some('\\'),
-                '\'' => Some('\''),
-                '\"' => Some('\"'),
-                _ => None,
-            };
-            if let Some(esc) = escaped {
-                sb.push('\\');
-                sb.push(esc);
-            } else if c.is_ascii_graphic() || c == &' ' {
-                sb.push(c.clone());
-            } else {
-                sb.push_str(&format!("\\{:o}", *c as i8));
-            }
-            if i == len - 1 {
-                sb.push_str("\\000");
-            }
-        } else {
-            sb.push_str("\\000");
-        }
-    }
-    sb
+// Calling conventions. IR registers are never used to pass arguments,
+// so moving arguments into place can't clobber them.
+struct Abi {
+    argregs: &'static [&'static str],
+    argregs8: &'static [&'static str],
+    argregs32: &'static [&'static str],
+    // Callee-saved registers the register allocator may hand out.
+    callee_saved: &'static [&'static str],
+    // Space the caller reserves above the return address for the
+    // callee to spill its register arguments to.
+    shadow_space: usize,
 }
 
-macro_rules! emit{
-    ($fmt:expr) => (print!(concat!("\t", $fmt, "\n")));
-    ($fmt:expr, $($arg:tt)*) => (print!(concat!("\t", $fmt, "\n"), $($arg)*));
+// System V AMD64 ABI, used by Linux and macOS.
+const SYSV: Abi = Abi {
+    argregs: &["rdi", "rsi", "rdx", "rcx", "r8", "r9"],
+    argregs8: &["dil", "sil", "dl", "cl", "r8b", "r9b"],
+    argregs32: &["edi", "esi", "edx", "ecx", "r8d", "r9d"],
+    callee_saved: &["rbx", "r12", "r13", "r14", "r15"],
+    shadow_space: 0,
+};
+
+// Microsoft x64 calling convention, used by MSVC and mingw.
+// Only four arguments are passed in registers.
+const WIN64: Abi = Abi {
+    argregs: &["rcx", "rdx", "r8", "r9"],
+    argregs8: &["cl", "dl", "r8b", "r9b"],
+    argregs32: &["ecx", "edx", "r8d", "r9d"],
+    callee_saved: &["rbx", "r12", "r13", "r14", "r15"],
+    shadow_space: 32,
+};
+
+fn emit_cmp(out: &mut String, ir: IR, insn: &'static str) {
+    let lhs = ir.lhs.unwrap_or(0);
+    let rhs = ir.rhs.unwrap();
+    emit!(out, "cmp {}, {}", REGS[lhs], REGS[rhs]);
+    emit!(out, "{} {}", insn, REGS8[lhs]);
+    emit!(out, "movzx {}, {}", REGS[lhs], REGS8[lhs]);
 }
 
-fn emit_cmp(ir: IR, insn: &'static str) {
-    let lhs = ir.lhs.unwrap();
+// Floating-point values are moved into xmm0 and xmm1 to compute with.
+// ucomisd sets CF and ZF the way an unsigned cmp would, and PF too if
+// either is a NaN, which compares unequal to anything.
+fn emit_fcmp(out: &mut String, ir: IR) {
+    let lhs = ir.lhs.unwrap_or(0);
     let rhs = ir.rhs.unwrap();
-    emit!("cmp {}, {}", REGS[lhs], REGS[rhs]);
-    emit!("{} {}", insn, REGS8[lhs]);
-    emit!("movzb {}, {}", REGS[lhs], REGS8[lhs]);
+    emit!(out, "movq xmm0, {}", REGS[lhs]);
+    emit!(out, "movq xmm1, {}", REGS[rhs]);
+    match ir.op {
+        // rhs > lhs and rhs >= lhs, which a NaN leaves false.
+        IROp::FLT | IROp::FLE => {
+            let insn = if ir.op == IROp::FLT { "seta" } else { "setae" };
+            emit!(out, "ucomisd xmm1, xmm0");
+            emit!(out, "{} {}", insn, REGS8[lhs]);
+        }
+        IROp::FEQ => {
+            emit!(out, "ucomisd xmm0, xmm1");
+            emit!(out, "sete {}", REGS8[lhs]);
+            emit!(out, "setnp al");
+            emit!(out, "and {}, al", REGS8[lhs]);
+        }
+        _ => {
+            emit!(out, "ucomisd xmm0, xmm1");
+            emit!(out, "setne {}", REGS8[lhs]);
+            emit!(out, "setp al");
+            emit!(out, "or {}, al", REGS8[lhs]);
+        }
+    }
+    emit!(out, "movzx {}, {}", REGS[lhs], REGS8[lhs]);
 }
 
 fn reg(r: usize, size: u8) -> &'static str {
@@ -75,154 +100,1046 @@ fn reg(r: usize, size: u8) -> &'static str {
     }
 }
 
-fn argreg(r: usize, size: u8) -> &'static str {
+fn argreg(abi: &Abi, r: usize, size: u8) -> &'static str {
+    match size {
+        1 => abi.argregs8[r],
+        4 => abi.argregs32[r],
+        8 => abi.argregs[r],
+        _ => unreachable!(),
+    }
+}
+
+// Returns the name of the low `size` bytes of a 64-bit register.
+fn subreg(r: &str, size: usize) -> String {
+    let base = &r[1..];
     match size {
-        1 => ARGREGS8[r],
-        4 => ARGREGS32[r],
-        8 => ARGREGS[r],
+        8 => r.to_string(),
+        4 if base.starts_with(|c: char| c.is_ascii_digit()) => format!("{}d", r),
+        1 if base.starts_with(|c: char| c.is_ascii_digit()) => format!("{}b", r),
+        4 => format!("e{}", base),
+        1 if base.ends_with('x') => format!("{}l", &base[..1]),
+        1 => format!("{}l", base),
         _ => unreachable!(),
     }
 }
 
-fn gen(f: Function) {
+// Formats the memory operand [base+off].
+fn mem(base: &str, off: i64) -> String {
+    match off {
+        0 => format!("[{}]", base),
+        _ if off < 0 => format!("[{}{}]", base, off),
+        _ => format!("[{}+{}]", base, off),
+    }
+}
+
+// Loads the `size` (at most 8) bytes at [base+off] into dst. Structs
+// don't have to be a multiple of 8 bytes long, so we must not read
+// past their end. Odd sizes are assembled byte by byte with `scratch`.
+fn load_bytes(out: &mut String, dst: &str, base: &str, off: i64, size: usize, scratch: &str) {
+    match size {
+        8 => emit!(out, "mov {}, {}", dst, mem(base, off)),
+        4 => emit!(out, "mov {}, DWORD PTR {}", subreg(dst, 4), mem(base, off)),
+        1 => emit!(out, "movzx {}, BYTE PTR {}", subreg(dst, 4), mem(base, off)),
+        _ => {
+            let last = off + size as i64 - 1;
+            emit!(
+                out,
+                "movzx {}, BYTE PTR {}",
+                subreg(dst, 4),
+                mem(base, last)
+            );
+            for i in (off..last).rev() {
+                emit!(out, "shl {}, 8", dst);
+                emit!(
+                    out,
+                    "movzx {}, BYTE PTR {}",
+                    subreg(scratch, 4),
+                    mem(base, i)
+                );
+                emit!(out, "or {}, {}", dst, scratch);
+            }
+        }
+    }
+}
+
+// Stores the low `size` (at most 8) bytes of src to [base+off]. For
+// odd sizes, src is clobbered.
+fn store_bytes(out: &mut String, base: &str, off: i64, src: &str, size: usize) {
+    match size {
+        8 | 4 | 1 => emit!(out, "mov {}, {}", mem(base, off), subreg(src, size)),
+        _ => {
+            for i in 0..size as i64 {
+                if i > 0 {
+                    emit!(out, "shr {}, 8", src);
+                }
+                emit!(
+                    out,
+                    "mov BYTE PTR {}, {}",
+                    mem(base, off + i),
+                    subreg(src, 1)
+                );
+            }
+        }
+    }
+}
+
+// Copies `size` bytes from [src+soff] to [dst+doff] through `scratch`.
+fn copy_bytes(
+    out: &mut String,
+    dst: &str,
+    doff: i64,
+    src: &str,
+    soff: i64,
+    size: usize,
+    scratch: &str,
+) {
+    let mut i = 0;
+    while i < size {
+        let n = match size - i {
+            8.. => 8,
+            4..=7 => 4,
+            _ => 1,
+        };
+        let r = subreg(scratch, n);
+        emit!(out, "mov {}, {}", r, mem(src, soff + i as i64));
+        emit!(out, "mov {}, {}", mem(dst, doff + i as i64), r);
+        i += n;
+    }
+}
+
+// What an argument is, as far as where it goes is concerned.
+#[derive(Debug, Clone, Copy)]
+enum Arg {
+    Int,
+    Float,
+    Struct(usize), // size
+}
+
+// Where an argument is passed.
+#[derive(Debug, Clone, Copy)]
+enum Place {
+    Reg(usize),   // abi.argregs[i], followed by more for a struct
+    Xmm(usize),   // xmm<i>
+    Stack(usize), // offset from the first stack argument
+}
+
+// Emits `f`, returning the bytes of stack it uses, from the return
+// address down to the deepest call's arguments.
+fn gen(out: &mut String, f: Function, t: &X86_64, files: &[String]) -> usize {
     use self::IROp::*;
-    let ret = format!(".Lend{}", *LABEL.lock().unwrap());
-    *LABEL.lock().unwrap() += 1;
-
-    println!(".text");
-    println!(".global {}", f.name);
-    println!("{}:", f.name);
-    emit!("push rbp");
-    emit!("mov rbp, rsp");
-    emit!("sub rsp, {}", roundup(f.stacksize, 16));
-    emit!("push r12");
-    emit!("push r13");
-    emit!("push r14");
-    emit!("push r15");
-
-    for ir in f.ir {
-        let lhs = ir.lhs.unwrap();
+    ice::set_function(&f.name);
+    let abi = t.abi();
+    let l = t.local_prefix();
+    let mut labels = FnLabels::new(l, &f.name);
+    let ret = labels.new_label();
+    let mut tables = vec![];
+
+    // With a stack protector, the canary lives right below the saved
+    // rbp and local variables are moved down to make room for it, so
+    // that overflowing an array clobbers the canary before it reaches
+    // the return address.
+    let guard = if f.stack_protector { 8 } else { 0 };
+
+    // A function returning a struct in memory gets the address to
+    // store it to as a hidden first argument, which we keep below the
+    // canary. It has to be returned in rax as well.
+    let sret = f.struct_ret.is_some_and(|size| t.sret(size));
+    let sret_slot = guard + 8;
+    let base = if sret { sret_slot } else { guard };
+
+    let params =
+        f.ir.iter()
+            .filter_map(|ir| match ir.op {
+                StoreArg(_) => Some(Arg::Int),
+                StoreFloatArg(_) => Some(Arg::Float),
+                StoreStructArg(size) => Some(Arg::Struct(size)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+    let (places, named_regs, named_xmm, named_stack) = t.classify(&params, sret);
+    // Skip the saved rbp, the return address and the shadow space to
+    // get to stack arguments.
+    let stack_arg = |off: usize| (16 + abi.shadow_space + off) as i64;
+
+    if t.os == Os::Linux && f.entry_count() == Some(0) {
+        out.push_str(COLD_SECTION);
+    } else {
+        out.push_str(t.text_section());
+    }
+    if !f.is_static {
+        let _ = writeln!(out, ".global {}", t.sym(&f.name));
+    }
+    t.func_type(out, &f.name);
+    let _ = writeln!(out, "{}:", t.sym(&f.name));
+    emit!(out, "push rbp");
+    emit!(out, "mov rbp, rsp");
+    // mcount finds the caller through rbp and the return address, and
+    // saves the argument registers itself.
+    if f.profile {
+        emit!(out, "call mcount");
+    }
+    // A variadic function spills its register arguments so that
+    // va_arg can find them in memory. System V wants them in a register
+    // save area, which we put below the local variables: the integer
+    // registers followed by xmm0-xmm7. On Win64, they go to the shadow
+    // space, right below the arguments passed on the stack.
+    let regsave = if f.variadic && t.os != Os::Windows {
+        176
+    } else {
+        0
+    };
+    let frame = roundup(f.stacksize + base, 16) + regsave;
+    emit!(out, "sub rsp, {}", frame);
+    if f.stack_protector {
+        t.load_canary(out, "rax");
+        emit!(out, "mov [rbp-8], rax");
+    }
+    if sret {
+        emit!(out, "mov [rbp-{}], {}", sret_slot, abi.argregs[0]);
+    }
+    if f.variadic {
+        let area = match t.os {
+            Os::Windows => 16,
+            _ => -(frame as i64),
+        };
+        for (i, r) in abi.argregs.iter().enumerate() {
+            emit!(out, "mov {}, {}", mem("rbp", area + i as i64 * 8), r);
+        }
+        if t.os != Os::Windows {
+            for i in 0..8 {
+                emit!(out, "movaps {}, xmm{}", mem("rbp", area + 48 + i * 16), i);
+            }
+        }
+    }
+    for r in abi.callee_saved {
+        emit!(out, "push {}", r);
+    }
+    // Keep rsp 16-byte aligned at call sites.
+    let pad = abi.callee_saved.len() % 2 * 8;
+    if pad > 0 {
+        emit!(out, "sub rsp, {}", pad);
+    }
+    let alloca = f.ir.iter().any(|ir| ir.op == Alloca);
+    let mut calls = 0;
+
+    for (i, ir) in f.ir.into_iter().enumerate() {
+        ice::set_position("IR instruction", i);
+        let lhs = ir.lhs.unwrap_or(0);
         let rhs = ir.rhs.unwrap_or(0);
         match ir.op {
-            Imm => emit!("mov {}, {}", REGS[lhs], rhs as i32),
-            Mov => emit!("mov {}, {}", REGS[lhs], REGS[rhs]),
+            Imm => emit!(out, "mov {}, {}", REGS[lhs], rhs as i64),
+            Mov => emit!(out, "mov {}, {}", REGS[lhs], REGS[rhs]),
+            Return if f.ret_float => {
+                emit!(out, "movq xmm0, {}", REGS[lhs]);
+                emit!(out, "jmp {}", ret);
+            }
             Return => {
-                emit!("mov rax, {}", REGS[lhs]);
-                emit!("jmp {}", ret);
-            }
-            Call(name, nargs, args) => {
-                for i in 0..nargs {
-                    emit!("mov {}, {}", ARGREGS[i], REGS[args[i]]);
-                }
-                emit!("push r10");
-                emit!("push r11");
-                emit!("mov rax, 0");
-                emit!("call {}", name);
-                emit!("pop r11");
-                emit!("pop r10");
-
-                emit!("mov {}, rax", REGS[lhs]);
-            }
-            Label => println!(".L{}:", lhs),
-            LabelAddr(name) => emit!("lea {}, {}", REGS[lhs], name),
-            Neg => emit!("neg {}", REGS[lhs]),
-            EQ => emit_cmp(ir, "sete"),
-            NE => emit_cmp(ir, "setne"),
-            LT => emit_cmp(ir, "setl"),
-            LE => emit_cmp(ir, "setle"),
-            AND => emit!("and {}, {}", REGS[lhs], REGS[rhs]),
-            OR => emit!("or {}, {}", REGS[lhs], REGS[rhs]),
-            XOR => emit!("xor {}, {}", REGS[lhs], REGS[rhs]),
+                emit!(out, "mov rax, {}", REGS[lhs]);
+                emit!(out, "jmp {}", ret);
+            }
+            ReturnStruct => {
+                let size = f.struct_ret.unwrap();
+                if sret {
+                    emit!(out, "mov rax, [rbp-{}]", sret_slot);
+                    copy_bytes(out, "rax", 0, REGS[lhs], 0, size, "rdx");
+                } else {
+                    load_bytes(out, "rax", REGS[lhs], 0, size.min(8), "rcx");
+                    if size > 8 {
+                        load_bytes(out, "rdx", REGS[lhs], 8, size - 8, "rcx");
+                    }
+                }
+                emit!(out, "jmp {}", ret);
+            }
+            Call(name, args, returns_float) => {
+                let ret_buf = args.iter().find_map(|arg| match *arg {
+                    CallArg::Ret(r, size) => Some((r, size)),
+                    _ => None,
+                });
+                let args = args
+                    .into_iter()
+                    .filter(|arg| !matches!(arg, CallArg::Ret(_, _)))
+                    .collect::<Vec<_>>();
+                let call_sret = ret_buf.is_some_and(|(_, size)| t.sret(size));
+                let kinds = args
+                    .iter()
+                    .map(|arg| match *arg {
+                        CallArg::Struct(_, size) => Arg::Struct(size),
+                        CallArg::Float(_) => Arg::Float,
+                        _ => Arg::Int,
+                    })
+                    .collect::<Vec<_>>();
+                let (places, _, xmm, stack) = t.classify(&kinds, call_sret);
+
+                // Arguments that don't fit in registers go above the
+                // shadow space, followed by the copies of structs
+                // passed by reference.
+                let mut copies = vec![];
+                let mut end = abi.shadow_space + stack;
+                for kind in &kinds {
+                    match *kind {
+                        Arg::Struct(size) if t.by_ref(size) => {
+                            copies.push(end);
+                            end += roundup(size, 16);
+                        }
+                        _ => copies.push(0),
+                    }
+                }
+                let frame = roundup(end, 16);
+                calls = calls.max(16 + frame);
+
+                emit!(out, "push r10");
+                emit!(out, "push r11");
+                if frame > 0 {
+                    emit!(out, "sub rsp, {}", frame);
+                }
+
+                // Fill in the stack first, since that takes rax as a
+                // scratch register.
+                for (i, arg) in args.iter().enumerate() {
+                    let (r, size) = match *arg {
+                        CallArg::Struct(r, size) => (r, size),
+                        CallArg::Val(r) | CallArg::Float(r) => {
+                            if let Place::Stack(off) = places[i] {
+                                let off = (abi.shadow_space + off) as i64;
+                                emit!(out, "mov {}, {}", mem("rsp", off), REGS[r]);
+                            }
+                            continue;
+                        }
+                        CallArg::Ret(_, _) => unreachable!(),
+                    };
+                    if t.by_ref(size) {
+                        copy_bytes(out, "rsp", copies[i] as i64, REGS[r], 0, size, "rax");
+                        if let Place::Stack(off) = places[i] {
+                            let off = (abi.shadow_space + off) as i64;
+                            emit!(out, "lea rax, [rsp+{}]", copies[i]);
+                            emit!(out, "mov {}, rax", mem("rsp", off));
+                        }
+                    } else if let Place::Stack(off) = places[i] {
+                        let off = (abi.shadow_space + off) as i64;
+                        copy_bytes(out, "rsp", off, REGS[r], 0, size, "rax");
+                    }
+                }
+
+                for (i, arg) in args.iter().enumerate() {
+                    let reg = match places[i] {
+                        Place::Reg(reg) => reg,
+                        // Win64 wants floating arguments in the integer
+                        // register too, in case the callee is variadic.
+                        Place::Xmm(x) => {
+                            emit!(out, "movq xmm{}, {}", x, REGS[arg.reg()]);
+                            if t.os == Os::Windows {
+                                emit!(out, "mov {}, {}", abi.argregs[x], REGS[arg.reg()]);
+                            }
+                            continue;
+                        }
+                        Place::Stack(_) => continue,
+                    };
+                    match *arg {
+                        CallArg::Val(r) => emit!(out, "mov {}, {}", abi.argregs[reg], REGS[r]),
+                        CallArg::Struct(_, size) if t.by_ref(size) => {
+                            emit!(out, "lea {}, [rsp+{}]", abi.argregs[reg], copies[i]);
+                        }
+                        CallArg::Struct(r, size) => {
+                            for (k, argreg) in abi.argregs[reg..].iter().enumerate() {
+                                let off = k * 8;
+                                if off >= size {
+                                    break;
+                                }
+                                let n = (size - off).min(8);
+                                load_bytes(out, argreg, REGS[r], off as i64, n, "rax");
+                            }
+                        }
+                        CallArg::Float(_) | CallArg::Ret(_, _) => unreachable!(),
+                    }
+                }
+                if let (true, Some((r, _))) = (call_sret, ret_buf) {
+                    emit!(out, "mov {}, {}", abi.argregs[0], REGS[r]);
+                }
+
+                // A variadic callee learns from al how many xmm
+                // registers hold arguments.
+                emit!(out, "mov rax, {}", xmm);
+                match ir.rhs {
+                    Some(callee) => emit!(out, "call {}", REGS[callee]),
+                    None => emit!(out, "call {}", t.sym(&name)),
+                }
+                if frame > 0 {
+                    emit!(out, "add rsp, {}", frame);
+                }
+                emit!(out, "pop r11");
+                emit!(out, "pop r10");
+
+                if returns_float {
+                    emit!(out, "movq {}, xmm0", REGS[lhs]);
+                } else {
+                    emit!(out, "mov {}, rax", REGS[lhs]);
+                }
+                match ret_buf {
+                    Some((r, size)) if !call_sret => {
+                        store_bytes(out, REGS[r], 0, "rax", size.min(8));
+                        if size > 8 {
+                            store_bytes(out, REGS[r], 8, "rdx", size - 8);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            Label => {
+                let _ = writeln!(out, "{}{}:", l, lhs);
+            }
+            LabelAddr(name) => {
+                if t.rip_relative() {
+                    emit!(out, "lea {}, [rip+{}]", REGS[lhs], t.sym(&name));
+                } else {
+                    emit!(out, "lea {}, {}", REGS[lhs], t.sym(&name));
+                }
+            }
+            // Initial-exec model: the GOT has the variable's offset from
+            // the thread pointer, which is at fs:0.
+            TlsAddr(name) => {
+                emit!(out, "mov {}, QWORD PTR fs:[0]", REGS[lhs]);
+                emit!(out, "add {}, QWORD PTR [rip+{}@gottpoff]", REGS[lhs], name);
+            }
+            Neg => emit!(out, "neg {}", REGS[lhs]),
+            Not => emit!(out, "not {}", REGS[lhs]),
+            Sext(4) => emit!(out, "movsxd {}, {}", REGS[lhs], REGS32[lhs]),
+            Sext(size) => emit!(out, "movsx {}, {}", REGS[lhs], reg(lhs, size)),
+            // Writing a 32-bit register clears the upper half.
+            Zext(4) => emit!(out, "mov {}, {}", REGS32[lhs], REGS32[lhs]),
+            Zext(size) => emit!(out, "movzx {}, {}", REGS[lhs], reg(lhs, size)),
+            FAdd | FSub | FMul | FDiv => {
+                let insn = match ir.op {
+                    FAdd => "addsd",
+                    FSub => "subsd",
+                    FMul => "mulsd",
+                    _ => "divsd",
+                };
+                emit!(out, "movq xmm0, {}", REGS[lhs]);
+                emit!(out, "movq xmm1, {}", REGS[rhs]);
+                emit!(out, "{} xmm0, xmm1", insn);
+                emit!(out, "movq {}, xmm0", REGS[lhs]);
+            }
+            FEQ | FNE | FLT | FLE => emit_fcmp(out, ir),
+            I2F => {
+                emit!(out, "cvtsi2sd xmm0, {}", REGS[lhs]);
+                emit!(out, "movq {}, xmm0", REGS[lhs]);
+            }
+            F2I => {
+                emit!(out, "movq xmm0, {}", REGS[lhs]);
+                emit!(out, "cvttsd2si {}, xmm0", REGS[lhs]);
+            }
+            FExt => {
+                emit!(out, "movd xmm0, {}", REGS32[lhs]);
+                emit!(out, "cvtss2sd xmm0, xmm0");
+                emit!(out, "movq {}, xmm0", REGS[lhs]);
+            }
+            FTrunc => {
+                emit!(out, "movq xmm0, {}", REGS[lhs]);
+                emit!(out, "cvtsd2ss xmm0, xmm0");
+                emit!(out, "movd {}, xmm0", REGS32[lhs]);
+            }
+            EQ => emit_cmp(out, ir, "sete"),
+            NE => emit_cmp(out, ir, "setne"),
+            LT => emit_cmp(out, ir, "setl"),
+            LE => emit_cmp(out, ir, "setle"),
+            ULT => emit_cmp(out, ir, "setb"),
+            ULE => emit_cmp(out, ir, "setbe"),
+            AND => emit!(out, "and {}, {}", REGS[lhs], REGS[rhs]),
+            OR => emit!(out, "or {}, {}", REGS[lhs], REGS[rhs]),
+            XOR => emit!(out, "xor {}, {}", REGS[lhs], REGS[rhs]),
             SHL => {
-                emit!("mov cl, {}", REGS8[rhs]);
-                emit!("shl {}, cl", REGS[lhs]);
+                emit!(out, "mov cl, {}", REGS8[rhs]);
+                emit!(out, "shl {}, cl", REGS[lhs]);
             }
             SHR => {
-                emit!("mov cl, {}", REGS8[rhs]);
-                emit!("shr {}, cl", REGS[lhs]);
-            }
-            Mod => {
-                /* Same meaning(?).
-                 * emit!("mov rdx, 0");
-                 * emit!("mov rax, {}", REGS[lhs]);
-                 */
-                emit!("mov rax, {}", REGS[lhs]);
-                emit!("cqo"); // rax -> rdx:rax
-                emit!("div {}", REGS[rhs]);
-                emit!("mov {}, rdx", REGS[lhs]);
-            }
-            Jmp => emit!("jmp .L{}", lhs),
+                emit!(out, "mov cl, {}", REGS8[rhs]);
+                emit!(out, "shr {}, cl", REGS[lhs]);
+            }
+            SAR => {
+                emit!(out, "mov cl, {}", REGS8[rhs]);
+                emit!(out, "sar {}, cl", REGS[lhs]);
+            }
+            Div | UDiv | Mod | UMod => {
+                emit!(out, "mov rax, {}", REGS[lhs]);
+                if ir.op == Div || ir.op == Mod {
+                    emit!(out, "cqo"); // rax -> rdx:rax
+                    emit!(out, "idiv {}", REGS[rhs]);
+                } else {
+                    emit!(out, "xor edx, edx");
+                    emit!(out, "div {}", REGS[rhs]);
+                }
+                let result = if ir.op == Div || ir.op == UDiv {
+                    "rax"
+                } else {
+                    "rdx"
+                };
+                emit!(out, "mov {}, {}", REGS[lhs], result);
+            }
+            Jmp => emit!(out, "jmp {}{}", l, lhs),
             If => {
-                emit!("cmp {}, 0", REGS[lhs]);
-                emit!("jne .L{}", rhs);
+                emit!(out, "cmp {}, 0", REGS[lhs]);
+                emit!(out, "jne {}{}", l, rhs);
             }
             Unless => {
-                emit!("cmp {}, 0", REGS[lhs]);
-                emit!("je .L{}", rhs);
-            }
-            Load(size) => {
-                emit!("mov {}, [{}]", reg(lhs, size), REGS[rhs]);
-                if size == 1 {
-                    emit!("movzb {}, {}", REGS[lhs], REGS8[lhs]);
-                }
-            }
-            Store(size) => emit!("mov [{}], {}", REGS[lhs], reg(rhs, size)),
-            StoreArg(size) => emit!("mov [rbp-{}], {}", lhs, argreg(rhs, size)),
-            Add => emit!("add {}, {}", REGS[lhs], REGS[rhs]),
-            AddImm => emit!("add {}, {}", REGS[lhs], rhs as i32),
-            Sub => emit!("sub {}, {}", REGS[lhs], REGS[rhs]),
-            SubImm => emit!("sub {}, {}", REGS[lhs], rhs as i32),
-            Bprel => emit!("lea {}, [rbp-{}]", REGS[lhs], rhs),
-            Mul => {
-                emit!("mov rax, {}", REGS[rhs]);
-                emit!("mul {}", REGS[lhs]);
-                emit!("mov {}, rax", REGS[lhs]);
+                emit!(out, "cmp {}, 0", REGS[lhs]);
+                emit!(out, "je {}{}", l, rhs);
+            }
+            JumpTable(table) => {
+                // Unsigned comparison, so that negative indices go to
+                // the default label as well.
+                emit!(out, "cmp {}, {}", REGS[lhs], table.len());
+                emit!(out, "jae {}{}", l, rhs);
+                let name = labels.new_label();
+                if t.rip_relative() {
+                    emit!(out, "lea rax, [rip+{}]", name);
+                } else {
+                    emit!(out, "lea rax, {}", name);
+                }
+                emit!(out, "jmp [rax+{}*8]", REGS[lhs]);
+                tables.push((name, table));
+            }
+            // Aligned loads are atomic, and sequential consistency only
+            // needs the stores to be fenced.
+            Load(size) | AtomicLoad(size) => match size {
+                1 => emit!(out, "movzx {}, BYTE PTR [{}]", REGS[lhs], REGS[rhs]),
+                4 => emit!(out, "movsxd {}, DWORD PTR [{}]", REGS[lhs], REGS[rhs]),
+                _ => emit!(out, "mov {}, [{}]", REGS[lhs], REGS[rhs]),
+            },
+            Store(size) => emit!(out, "mov [{}], {}", REGS[lhs], reg(rhs, size)),
+            Copy(size) => copy_bytes(out, REGS[lhs], 0, REGS[rhs], 0, size, "rax"),
+            // xchg with memory is implicitly locked, so it's a full barrier.
+            AtomicStore(size) => {
+                let rax = subreg("rax", size as usize);
+                emit!(out, "mov {}, {}", rax, reg(rhs, size));
+                emit!(out, "xchg [{}], {}", REGS[lhs], rax);
+            }
+            AtomicAdd(size) => {
+                let rax = subreg("rax", size as usize);
+                emit!(out, "mov {}, {}", rax, reg(rhs, size));
+                emit!(out, "lock xadd [{}], {}", REGS[lhs], rax);
+                if size == 4 {
+                    emit!(out, "movsxd {}, eax", REGS[lhs]);
+                } else {
+                    emit!(out, "mov {}, rax", REGS[lhs]);
+                }
+            }
+            StoreArg(size) => match places[rhs] {
+                Place::Reg(reg) => {
+                    emit!(out, "mov [rbp-{}], {}", lhs + base, argreg(abi, reg, size));
+                }
+                Place::Stack(off) => {
+                    emit!(out, "mov rax, {}", mem("rbp", stack_arg(off)));
+                    let rax = match size {
+                        1 => "al",
+                        4 => "eax",
+                        _ => "rax",
+                    };
+                    emit!(out, "mov [rbp-{}], {}", lhs + base, rax);
+                }
+                Place::Xmm(_) => unreachable!(),
+            },
+            StoreFloatArg(size) => {
+                match places[rhs] {
+                    Place::Xmm(x) => emit!(out, "movq rax, xmm{}", x),
+                    Place::Stack(off) => emit!(out, "mov rax, {}", mem("rbp", stack_arg(off))),
+                    Place::Reg(_) => unreachable!(),
+                }
+                let rax = if size == 4 { "eax" } else { "rax" };
+                emit!(out, "mov [rbp-{}], {}", lhs + base, rax);
+            }
+            StoreStructArg(size) => {
+                let dst = -((lhs + base) as i64);
+                if t.by_ref(size) {
+                    // No IR registers are live yet, so r11 is free.
+                    match places[rhs] {
+                        Place::Reg(reg) => emit!(out, "mov rax, {}", abi.argregs[reg]),
+                        Place::Stack(off) => emit!(out, "mov rax, {}", mem("rbp", stack_arg(off))),
+                        Place::Xmm(_) => unreachable!(),
+                    }
+                    copy_bytes(out, "rbp", dst, "rax", 0, size, "r11");
+                    continue;
+                }
+                match places[rhs] {
+                    Place::Reg(reg) => {
+                        for (k, argreg) in abi.argregs[reg..].iter().enumerate() {
+                            let off = k * 8;
+                            if off >= size {
+                                break;
+                            }
+                            store_bytes(out, "rbp", dst + off as i64, argreg, (size - off).min(8));
+                        }
+                    }
+                    Place::Stack(off) => {
+                        copy_bytes(out, "rbp", dst, "rbp", stack_arg(off), size, "rax")
+                    }
+                    Place::Xmm(_) => unreachable!(),
+                }
+            }
+            VaStart => {
+                let ap = REGS[lhs];
+                if t.os == Os::Windows {
+                    // Every argument takes one 8-byte slot, and the
+                    // ones passed in registers have been spilled to
+                    // the shadow space.
+                    let off = 16 + named_regs * 8 + named_stack;
+                    emit!(out, "lea rax, [rbp+{}]", off);
+                    emit!(out, "mov [{}], rax", ap);
+                    continue;
+                }
+                // gp_offset, fp_offset, overflow_arg_area, reg_save_area
+                emit!(out, "mov DWORD PTR [{}], {}", ap, named_regs * 8);
+                emit!(out, "mov DWORD PTR [{}+4], {}", ap, 48 + named_xmm * 16);
+                emit!(out, "lea rax, {}", mem("rbp", stack_arg(named_stack)));
+                emit!(out, "mov [{}+8], rax", ap);
+                emit!(out, "lea rax, [rbp-{}]", frame);
+                emit!(out, "mov [{}+16], rax", ap);
+            }
+            VaArg(_) | VaArgFloat => {
+                let size = match ir.op {
+                    VaArg(size) => size,
+                    _ => None,
+                };
+                let (dst, ap) = (REGS[lhs], REGS[rhs]);
+                if t.os == Os::Windows {
+                    emit!(out, "mov {}, [{}]", dst, ap);
+                    emit!(out, "lea rax, [{}+8]", dst);
+                    emit!(out, "mov [{}], rax", ap);
+                    if size.is_some_and(|size| t.by_ref(size)) {
+                        emit!(out, "mov {}, [{}]", dst, dst);
+                    }
+                    continue;
+                }
+                // Take the argument from the register save area if
+                // there are enough registers left, or from the stack
+                // otherwise. Structs larger than 16 bytes are always
+                // on the stack. A double comes from the xmm registers,
+                // 16 bytes apiece, which fp_offset counts from the
+                // start of the area.
+                let size = size.unwrap_or(8);
+                let stack = labels.new_label();
+                let end = labels.new_label();
+                let (field, limit, n) = match ir.op {
+                    VaArgFloat => (4, 176, 16),
+                    _ => (0, 48, size.div_ceil(8) * 8),
+                };
+                if size <= 16 {
+                    emit!(out, "mov eax, DWORD PTR {}", mem(ap, field));
+                    emit!(out, "cmp eax, {}", limit - n);
+                    emit!(out, "ja {}", stack);
+                    emit!(out, "mov {}, [{}+16]", dst, ap);
+                    emit!(out, "add {}, rax", dst);
+                    emit!(out, "add eax, {}", n);
+                    emit!(out, "mov DWORD PTR {}, eax", mem(ap, field));
+                    emit!(out, "jmp {}", end);
+                }
+                let _ = writeln!(out, "{}:", stack);
+                emit!(out, "mov {}, [{}+8]", dst, ap);
+                emit!(out, "lea rax, [{}+{}]", dst, roundup(size, 8));
+                emit!(out, "mov [{}+8], rax", ap);
+                let _ = writeln!(out, "{}:", end);
             }
+            Add => emit!(out, "add {}, {}", REGS[lhs], REGS[rhs]),
+            AddImm => emit!(out, "add {}, {}", REGS[lhs], rhs as i32),
+            Sub => emit!(out, "sub {}, {}", REGS[lhs], REGS[rhs]),
+            SubImm => emit!(out, "sub {}, {}", REGS[lhs], rhs as i32),
+            Bprel => emit!(out, "lea {}, [rbp-{}]", REGS[lhs], rhs + base),
+            // The low 64 bits of a product are the same signed or not.
+            Mul => emit!(out, "imul {}, {}", REGS[lhs], REGS[rhs]),
             MulImm => {
                 if rhs < 256 && rhs.count_ones() == 1 {
-                    emit!("shl {}, {}", REGS[lhs], rhs.trailing_zeros());
+                    emit!(out, "shl {}, {}", REGS[lhs], rhs.trailing_zeros());
                 } else {
-                    emit!("mov rax, {}", rhs as i32);
-                    emit!("mul {}", REGS[lhs]);
-                    emit!("mov {}, rax", REGS[lhs]);
+                    emit!(out, "mov rax, {}", rhs as i32);
+                    emit!(out, "imul {}, rax", REGS[lhs]);
                 }
             }
-            Div => {
-                emit!("mov rax, {}", REGS[lhs]);
-                emit!("cqo");
-                emit!("div {}", REGS[rhs]);
-                emit!("mov {}, rax", REGS[lhs]);
+            Asm(text) => {
+                for line in text.lines() {
+                    emit!(out, "{}", line.trim());
+                }
+            }
+            Comment(text) => emit!(out, "# {}", text),
+            Loc(file, line) => emit!(out, ".loc {} {}", debug_file(files, &file), line),
+            Trap | Unreachable => emit!(out, "ud2"),
+            // Rounding the size up keeps rsp aligned.
+            Alloca => {
+                emit!(out, "lea rax, [{}+15]", REGS[rhs]);
+                emit!(out, "and rax, -16");
+                emit!(out, "sub rsp, rax");
+                emit!(out, "mov {}, rsp", REGS[lhs]);
             }
-            Nop | Kill => (),
+            Nop | Kill | Count(_) => (),
+        }
+    }
+
+    let _ = writeln!(out, "{}:", ret);
+    if f.stack_protector {
+        // rax and rdx hold the return value, so use rcx.
+        t.load_canary(out, "rcx");
+        emit!(out, "cmp [rbp-8], rcx");
+        emit!(out, "jne {}_stack_chk_fail", ret);
+    }
+    // alloca moved rsp, so the saved registers are found from rbp.
+    if alloca {
+        emit!(out, "lea rsp, [rbp-{}]", frame + abi.callee_saved.len() * 8);
+    } else if pad > 0 {
+        emit!(out, "add rsp, {}", pad);
+    }
+    for r in abi.callee_saved.iter().rev() {
+        emit!(out, "pop {}", r);
+    }
+    emit!(out, "mov rsp, rbp");
+    emit!(out, "pop rbp");
+    emit!(out, "ret");
+
+    if f.stack_protector {
+        let _ = writeln!(out, "{}_stack_chk_fail:", ret);
+        emit!(out, "call {}", t.sym("__stack_chk_fail"));
+    }
+    if t.os == Os::Linux {
+        emit!(out, ".size {}, .-{}", f.name, f.name);
+    }
+
+    for (name, table) in tables {
+        out.push_str(t.rodata_section());
+        emit!(out, ".balign 8");
+        let _ = writeln!(out, "{}:", name);
+        for x in table {
+            emit!(out, ".quad {}{}", l, x);
         }
     }
+    16 + frame + abi.callee_saved.len() * 8 + pad + calls
+}
 
-    println!("{}:", ret);
-    emit!("pop r15");
-    emit!("pop r14");
-    emit!("pop r13");
-    emit!("pop r12");
-    emit!("mov rsp, rbp");
-    emit!("pop rbp");
-    emit!("ret");
+pub struct X86_64 {
+    pub os: Os,
 }
 
-pub fn gen_x86(globals: Vec<Var>, fns: Vec<Function>) {
-    println!(".intel_syntax noprefix");
-    println!(".data");
+impl X86_64 {
+    fn abi(&self) -> &'static Abi {
+        match self.os {
+            Os::Linux | Os::Darwin => &SYSV,
+            Os::Windows => &WIN64,
+        }
+    }
+
+    // Whether global addresses have to be RIP-relative. Windows images
+    // may be loaded above 4GiB, and Mach-O doesn't support absolute
+    // 32-bit relocations at all.
+    fn rip_relative(&self) -> bool {
+        self.os != Os::Linux
+    }
+
+    // Whether a returned struct of `size` bytes is stored to memory
+    // provided by the caller instead of coming back in rax (and rdx).
+    fn sret(&self, size: usize) -> bool {
+        match self.os {
+            Os::Windows => ![1, 2, 4, 8].contains(&size),
+            _ => size > 16,
+        }
+    }
+
+    // Win64 passes structs that aren't the size of an integer as a
+    // pointer to a copy made by the caller.
+    fn by_ref(&self, size: usize) -> bool {
+        self.os == Os::Windows && ![1, 2, 4, 8].contains(&size)
+    }
+
+    // Assigns arguments to registers and stack slots. Returns the
+    // places, the number of integer and xmm registers used and the
+    // number of bytes of stack arguments.
+    //
+    // System V passes a struct of up to 16 bytes in as many registers
+    // as it has eightbytes if there are enough of them left, and
+    // anything else on the stack. Floating arguments go in xmm0-xmm7,
+    // which are counted apart. Win64 uses one register or stack slot
+    // per argument, no matter what, and the nth argument goes in xmm<n>
+    // if it's floating.
+    fn classify(&self, args: &[Arg], sret: bool) -> (Vec<Place>, usize, usize, usize) {
+        let nregs = self.abi().argregs.len();
+        let mut next = if sret { 1 } else { 0 };
+        let mut xmm = 0;
+        let mut stack = 0;
+        let mut places = vec![];
+        for arg in args {
+            match *arg {
+                Arg::Float if self.os == Os::Windows && next < nregs => {
+                    places.push(Place::Xmm(next));
+                    next += 1;
+                    xmm += 1;
+                    continue;
+                }
+                Arg::Float if self.os != Os::Windows && xmm < 8 => {
+                    places.push(Place::Xmm(xmm));
+                    xmm += 1;
+                    continue;
+                }
+                _ => (),
+            }
+            let (n, in_regs) = match *arg {
+                Arg::Struct(size) if self.os != Os::Windows => (size.div_ceil(8), size <= 16),
+                Arg::Float => (1, false),
+                _ => (1, true),
+            };
+            if in_regs && next + n <= nregs {
+                places.push(Place::Reg(next));
+                next += n;
+            } else {
+                places.push(Place::Stack(stack));
+                stack += n * 8;
+            }
+        }
+        (places, next, xmm, stack)
+    }
+
+    // Assembler name of a C symbol. Mach-O prefixes C symbols with an
+    // underscore, and only treats labels starting with "L" as local.
+    fn sym(&self, name: &str) -> String {
+        if self.os != Os::Darwin {
+            return name.to_string();
+        }
+        match name.strip_prefix(".L") {
+            Some(rest) => format!("L{}", rest),
+            None => format!("_{}", name),
+        }
+    }
+
+    fn local_prefix(&self) -> &'static str {
+        match self.os {
+            Os::Darwin => "L",
+            _ => ".L",
+        }
+    }
+
+    // Loads the stack protector canary into `dst`. glibc keeps it in
+    // the thread control block, while mingw and macOS export a global
+    // variable.
+    fn load_canary(&self, out: &mut String, dst: &str) {
+        match self.os {
+            Os::Linux => emit!(out, "mov {}, QWORD PTR fs:[0x28]", dst),
+            Os::Windows => emit!(out, "mov {}, [rip+__stack_chk_guard]", dst),
+            Os::Darwin => {
+                emit!(out, "mov {}, [rip+___stack_chk_guard@GOTPCREL]", dst);
+                emit!(out, "mov {}, [{}]", dst, dst);
+            }
+        }
+    }
+
+    fn text_section(&self) -> &'static str {
+        match self.os {
+            Os::Darwin => ".section __TEXT,__text,regular,pure_instructions\n",
+            _ => ".text\n",
+        }
+    }
+
+    // Mach-O doesn't allow absolute addresses in read-only sections,
+    // but __DATA,__const is made read-only after relocation.
+    fn rodata_section(&self) -> &'static str {
+        match self.os {
+            Os::Linux => ".section .rodata\n",
+            Os::Windows => ".section .rdata,\"dr\"\n",
+            Os::Darwin => ".section __DATA,__const\n",
+        }
+    }
+
+    fn data_section(&self) -> &'static str {
+        match self.os {
+            Os::Darwin => ".section __DATA,__data\n",
+            _ => ".data\n",
+        }
+    }
+
+    fn bss_section(&self) -> &'static str {
+        match self.os {
+            Os::Darwin => ".section __DATA,__bss\n",
+            _ => ".bss\n",
+        }
+    }
+
+    // Marks `name` as a function for tools like nm and objdump. Mach-O
+    // symbols don't have a type.
+    fn func_type(&self, out: &mut String, name: &str) {
+        match self.os {
+            Os::Linux => emit!(out, ".type {}, @function", name),
+            // Storage class 2 is external, and type 32 is function.
+            Os::Windows => emit!(out, ".def {}; .scl 2; .type 32; .endef", name),
+            Os::Darwin => (),
+        }
+    }
+}
+
+impl Target for X86_64 {
+    fn name(&self) -> &'static str {
+        match self.os {
+            Os::Linux => "x86_64",
+            Os::Windows => "x86_64-windows",
+            Os::Darwin => "x86_64-darwin",
+        }
+    }
+
+    fn regs(&self) -> &'static [&'static str] {
+        &REGS
+    }
+
+    fn arg_regs(&self) -> &'static [&'static str] {
+        self.abi().argregs
+    }
+
+    // The rest are passed on the stack.
+    fn max_args(&self) -> usize {
+        6
+    }
+
+    fn macros(&self) -> &'static [(&'static str, i32)] {
+        match self.os {
+            Os::Linux => &[
+                ("__x86_64__", 1),
+                ("__x86_64", 1),
+                ("__amd64__", 1),
+                ("__LP64__", 1),
+                ("_LP64", 1),
+                ("__ELF__", 1),
+                ("__unix__", 1),
+                ("__linux__", 1),
+            ],
+            Os::Windows => &[
+                ("__x86_64__", 1),
+                ("__x86_64", 1),
+                ("__amd64__", 1),
+                ("_WIN32", 1),
+                ("_WIN64", 1),
+            ],
+            Os::Darwin => &[
+                ("__x86_64__", 1),
+                ("__x86_64", 1),
+                ("__amd64__", 1),
+                ("__LP64__", 1),
+                ("_LP64", 1),
+                ("__APPLE__", 1),
+                ("__MACH__", 1),
+            ],
+        }
+    }
+
+    // Windows is LLP64: only long long and pointers are 64-bit.
+    fn long_size(&self) -> usize {
+        match self.os {
+            Os::Windows => 4,
+            _ => 8,
+        }
+    }
+
+    fn llvm_triple(&self) -> &'static str {
+        match self.os {
+            Os::Linux => "x86_64-unknown-linux-gnu",
+            Os::Windows => "x86_64-w64-windows-gnu",
+            Os::Darwin => "x86_64-apple-macosx",
+        }
+    }
+
+    fn stack_protector(&self) -> bool {
+        true
+    }
+
+    // glibc's gcrt1.o provides mcount and writes gmon.out at exit.
+    fn profile(&self) -> bool {
+        self.os == Os::Linux
+    }
+
+    fn debug_info(&self) -> bool {
+        true
+    }
+
+    // Only the ELF flavor of thread-local storage is implemented.
+    fn thread_local(&self) -> bool {
+        self.os == Os::Linux
+    }
+
+    fn address_sanitizer(&self) -> bool {
+        self.os == Os::Linux
+    }
+
+    fn floating_point(&self) -> bool {
+        true
+    }
+
+    // System V's va_list is an array of one
+    // struct { unsigned gp_offset, fp_offset; void *overflow_arg_area, *reg_save_area; }.
+    fn va_list(&self) -> Rc<Type> {
+        if self.os == Os::Windows {
+            return Type::ptr_to(Type::char_ty());
+        }
+        let mut ty = Type::new(Ctype::Struct(vec![]), 24);
+        ty.align = 8;
+        Type::ary_of(Rc::new(ty), 1)
+    }
+
+    // The built-in assembler only writes ELF.
+    fn assemble(&self, asm: &str) -> Option<Object> {
+        match self.os {
+            Os::Linux => Some(assemble(asm)),
+            Os::Windows | Os::Darwin => None,
+        }
+    }
+
+    fn gen(&self, ctx: &mut CodegenContext, globals: Vec<Var>, fns: Vec<Function>) -> String {
+        gen_x86(ctx, globals, fns, self)
+    }
+}
+
+fn gen_x86(ctx: &mut CodegenContext, globals: Vec<Var>, fns: Vec<Function>, t: &X86_64) -> String {
+    let mut out = String::new();
+    out.push_str(".intel_syntax noprefix\n");
     for var in globals {
-        if let Scope::Global(data, len, is_extern) = var.scope {
+        if let Scope::Global(data, len, is_extern, is_tls, is_static) = var.scope {
             if is_extern {
                 continue;
             }
-            println!("{}:", var.name);
-            emit!(".ascii \"{}\"", backslash_escape(data, len));
+            // String literals are read-only, and zero-initialized
+            // variables take no space in the object file.
+            let is_str = var.name.starts_with(".L");
+            if is_str {
+                out.push_str(t.rodata_section());
+            } else if is_tls && data.is_empty() {
+                out.push_str(".section .tbss,\"awT\",@nobits\n");
+            } else if is_tls {
+                out.push_str(".section .tdata,\"awT\",@progbits\n");
+            } else if data.is_empty() {
+                out.push_str(t.bss_section());
+            } else {
+                out.push_str(t.data_section());
+            }
+            emit!(out, ".balign {}", var.ty.align.max(1));
+            if !is_str {
+                if !is_static {
+                    let _ = writeln!(out, ".global {}", t.sym(&var.name));
+                }
+                if t.os == Os::Linux {
+                    let ty = if is_tls { "@tls_object" } else { "@object" };
+                    emit!(out, ".type {}, {}", var.name, ty);
+                    emit!(out, ".size {}, {}", var.name, len);
+                }
+            }
+            let _ = writeln!(out, "{}:", t.sym(&var.name));
+            emit_data(&mut out, &data, len, &var.relocs, |name| t.sym(name));
             continue;
         }
         unreachable!();
     }
 
-    for f in fns {
-        gen(f);
+    let files = debug_files(&fns);
+    for (i, file) in files.iter().enumerate() {
+        emit!(out, ".file {} \"{}\"", i + 1, file);
+    }
+    let bodies = parallel::map(fns, ctx.jobs, |f| {
+        let mut out = String::new();
+        let stack = gen(&mut out, f, t, &files);
+        (out, stack)
+    });
+    ctx.append_framed(&mut out, bodies);
+    // Without this, the linker takes the stack to need to be executable.
+    if t.os == Os::Linux {
+        out.push_str(".section .note.GNU-stack,\"\",@progbits\n");
     }
+    out
 }
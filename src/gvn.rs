@@ -0,0 +1,359 @@
+// Global value numbering (-O2).
+//
+// Like licm.rs, it runs on the whole program after sema. An expression
+// computed again, from locals that nothing wrote since, has the value
+// it had before:
+//
+//   int x = a * b + c;
+//   if (c)
+//       return a * b - c;
+//
+// The first a * b is stored into a new local as it is computed, and the
+// second reads that local instead. What was computed before a statement
+// is available to the statements that always run after it: those that
+// follow it in its block, and those in the if, loop or switch it starts.
+// After an if or a loop, only what none of its statements write stays
+// available. A switch body, which case labels jump into the middle of,
+// is left alone.
+//
+// The expressions are those licm.rs moves, plus divisions and shifts,
+// since a computation that is reused has run already. Only scalar
+// locals whose address is never taken count, and an expression is only
+// reused by later statements, since the operands of one may be computed
+// in any order.
+
+use crate::licm::{is_assign, is_scalar, pinned, worth_moving, writes};
+use crate::parse::{Node, NodeType};
+use crate::util::roundup;
+use crate::{Ctype, Scope, TokenType};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::mem;
+
+// An expression computed before, and the local its value is in.
+#[derive(Clone)]
+struct Value {
+    var: Node,
+    reads: Vec<usize>, // The locals it is computed from
+    site: usize,       // Where it was computed (see Numbering)
+}
+
+type Table = HashMap<String, Value>;
+
+struct Numbering<'a> {
+    pinned: &'a HashSet<usize>,
+    // How often each expression occurs in the function. One that occurs
+    // once can't be reused.
+    counts: HashMap<String, usize>,
+    // Each place an expression that occurs more than once is computed
+    // without being available is a site, numbered in the order they are
+    // visited. Only the sites in `keep` store their value, or all of
+    // them if it's None.
+    keep: Option<HashSet<usize>>,
+    sites: usize,
+    // The sites whose value was reused.
+    used: HashSet<usize>,
+    stacksize: usize,
+    reused: usize,
+}
+
+// Whether `node` is computed from constants and locals only, without
+// side effects.
+fn is_pure(node: &Node, pinned: &HashSet<usize>) -> bool {
+    use crate::TokenType::*;
+    match node.op {
+        NodeType::Num(_) => true,
+        NodeType::Lvar(Scope::Local(offset)) => is_scalar(&node.ty) && !pinned.contains(&offset),
+        NodeType::BinOp(ref op, ref lhs, ref rhs) => {
+            matches!(
+                op,
+                Plus | Minus
+                    | Mul
+                    | Div
+                    | Mod
+                    | SHL
+                    | SHR
+                    | And
+                    | VerticalBar
+                    | Hat
+                    | EQ
+                    | NE
+                    | LE
+                    | GE
+                    | LeftAngleBracket
+                    | RightAngleBracket
+                    | Logand
+                    | Logor
+            ) && is_pure(lhs, pinned)
+                && is_pure(rhs, pinned)
+        }
+        NodeType::Neg(ref expr)
+        | NodeType::Tilde(ref expr)
+        | NodeType::Exclamation(ref expr)
+        | NodeType::Cast(ref expr) => is_scalar(&node.ty) && is_pure(expr, pinned),
+        _ => false,
+    }
+}
+
+// Writes what identifies the value of `node`, a pure expression, to
+// `out`, and adds the locals it reads to `reads`.
+fn describe(node: &Node, out: &mut String, reads: &mut Vec<usize>) {
+    let ty = &node.ty;
+    let is_ptr = matches!(ty.ty, Ctype::Ptr(_));
+    let _ = write!(out, "{}{}{}", ty.size, ty.is_unsigned as u8, is_ptr as u8);
+    match node.op {
+        NodeType::Num(val) => {
+            let _ = write!(out, "#{}", val);
+        }
+        NodeType::Lvar(Scope::Local(offset)) => {
+            let _ = write!(out, "L{}", offset);
+            reads.push(offset);
+        }
+        NodeType::BinOp(ref op, ref lhs, ref rhs) => {
+            let _ = write!(out, "({:?} ", op);
+            describe(lhs, out, reads);
+            out.push(' ');
+            describe(rhs, out, reads);
+            out.push(')');
+        }
+        NodeType::Neg(ref expr)
+        | NodeType::Tilde(ref expr)
+        | NodeType::Exclamation(ref expr)
+        | NodeType::Cast(ref expr) => {
+            let name = match node.op {
+                NodeType::Neg(_) => "neg",
+                NodeType::Tilde(_) => "not",
+                NodeType::Exclamation(_) => "lnot",
+                _ => "cast",
+            };
+            let _ = write!(out, "({} ", name);
+            describe(expr, out, reads);
+            out.push(')');
+        }
+        _ => unreachable!(),
+    }
+}
+
+// Kills the values computed from the locals in `written`.
+fn kill(table: &mut Table, written: &HashSet<usize>) {
+    table.retain(|_, value| !value.reads.iter().any(|r| written.contains(r)));
+}
+
+fn writes_of(nodes: &[&Node]) -> HashSet<usize> {
+    let mut written = HashSet::new();
+    for node in nodes {
+        writes(node, false, &mut written);
+    }
+    written
+}
+
+impl Numbering<'_> {
+    // The description of `node` and the locals it reads, if its value is
+    // worth keeping for later: a pure expression that computes something
+    // from a local. Chars are left out, as the store would truncate what
+    // the register has.
+    fn key(&self, node: &Node) -> Option<(String, Vec<usize>)> {
+        if !is_scalar(&node.ty)
+            || node.ty.size < 4
+            || !is_pure(node, self.pinned)
+            || !worth_moving(node)
+        {
+            return None;
+        }
+        let mut key = String::new();
+        let mut reads = vec![];
+        describe(node, &mut key, &mut reads);
+        Some((key, reads))
+    }
+
+    fn count(&mut self, node: &Node) {
+        if let Some((key, _)) = self.key(node) {
+            *self.counts.entry(key).or_insert(0) += 1;
+        }
+        for child in node.children() {
+            self.count(child);
+        }
+    }
+
+    // Reuses what `table` has in the parts of `node` that are always
+    // computed, adding what they compute to `new`.
+    fn expr(&mut self, node: &mut Node, table: &Table, new: &mut Vec<(String, Value)>) {
+        let (key, reads) = match self.key(node) {
+            Some(key) => key,
+            None => return self.operands(node, table, new),
+        };
+        if let Some(value) = table.get(&key) {
+            self.used.insert(value.site);
+            self.reused += 1;
+            *node = value.var.clone();
+            return;
+        }
+        self.operands(node, table, new);
+        if self.counts[&key] < 2 {
+            return;
+        }
+        let site = self.sites;
+        self.sites += 1;
+        if self.keep.as_ref().is_some_and(|keep| !keep.contains(&site)) {
+            return;
+        }
+        let ty = node.ty.clone();
+        self.stacksize = roundup(self.stacksize, ty.align) + ty.size;
+        let mut var = Node::new(NodeType::Lvar(Scope::Local(self.stacksize)));
+        var.ty = ty.clone();
+        let expr = mem::replace(node, Node::new(NodeType::Null));
+        *node = Node::new_binop(TokenType::Equal, var.clone(), expr);
+        node.ty = ty;
+        new.push((key, Value { var, reads, site }));
+    }
+
+    fn operands(&mut self, node: &mut Node, table: &Table, new: &mut Vec<(String, Value)>) {
+        match node.op {
+            NodeType::BinOp(TokenType::Logand, ref mut lhs, _)
+            | NodeType::BinOp(TokenType::Logor, ref mut lhs, _)
+            | NodeType::Ternary(ref mut lhs, ..) => self.expr(lhs, table, new),
+            NodeType::BinOp(ref op, ref mut lhs, ref mut rhs) if is_assign(op) => {
+                self.lvalue(lhs, table, new);
+                self.expr(rhs, table, new);
+            }
+            NodeType::BinOp(_, ref mut lhs, ref mut rhs) => {
+                self.expr(lhs, table, new);
+                self.expr(rhs, table, new);
+            }
+            NodeType::Deref(ref mut expr)
+            | NodeType::Neg(ref mut expr)
+            | NodeType::Tilde(ref mut expr)
+            | NodeType::Exclamation(ref mut expr)
+            | NodeType::Cast(ref mut expr) => self.expr(expr, table, new),
+            NodeType::Dot(..) => self.lvalue(node, table, new),
+            NodeType::PostInc(ref mut expr)
+            | NodeType::PostDec(ref mut expr)
+            | NodeType::Addr(ref mut expr) => self.lvalue(expr, table, new),
+            NodeType::Call(_, ref mut args) => {
+                for arg in args {
+                    self.expr(arg, table, new);
+                }
+            }
+            NodeType::CallPtr(ref mut callee, ref mut args) => {
+                self.expr(callee, table, new);
+                for arg in args {
+                    self.expr(arg, table, new);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Like expr, for what is computed to find the object `node`
+    // designates.
+    fn lvalue(&mut self, node: &mut Node, table: &Table, new: &mut Vec<(String, Value)>) {
+        match node.op {
+            NodeType::Deref(ref mut expr) => self.expr(expr, table, new),
+            NodeType::Dot(ref mut expr, ..) => self.lvalue(expr, table, new),
+            _ => (),
+        }
+    }
+
+    // Numbers an expression that is a statement of its own, or the
+    // condition of one. What it computes is available after it, unless
+    // it writes the locals that it is computed from.
+    fn eval(&mut self, node: &mut Node, table: &mut Table) {
+        let mut new = vec![];
+        self.expr(node, table, &mut new);
+        let written = writes_of(&[node]);
+        kill(table, &written);
+        for (key, value) in new {
+            if !value.reads.iter().any(|r| written.contains(r)) {
+                table.insert(key, value);
+            }
+        }
+    }
+
+    fn stmt(&mut self, node: &mut Node, table: &mut Table) {
+        match node.op {
+            NodeType::CompStmt(ref mut stmts) | NodeType::VecStmt(ref mut stmts) => {
+                for stmt in stmts {
+                    self.stmt(stmt, table);
+                }
+            }
+            NodeType::ExprStmt(ref mut expr) | NodeType::Return(ref mut expr) => {
+                self.eval(expr, table)
+            }
+            NodeType::Vardef(_, Some(ref mut init), Scope::Local(offset)) => {
+                self.eval(init, table);
+                kill(table, &HashSet::from([offset]));
+            }
+            NodeType::If(ref mut cond, ref mut then, ref mut els) => {
+                self.eval(cond, table);
+                self.stmt(then, &mut table.clone());
+                if let Some(els) = els {
+                    self.stmt(els, &mut table.clone());
+                }
+                let mut written = writes_of(&[then]);
+                if let Some(els) = els {
+                    writes(els, false, &mut written);
+                }
+                kill(table, &written);
+            }
+            // The condition is computed before the body and the
+            // increment on every iteration, but the body may not get
+            // to its end before the increment, nor the condition to
+            // the loop's end.
+            NodeType::For(ref mut init, ref mut cond, ref mut inc, ref mut body) => {
+                self.stmt(init, table);
+                kill(table, &writes_of(&[cond, inc, body]));
+                let mut inner = table.clone();
+                self.eval(cond, &mut inner);
+                self.stmt(body, &mut inner.clone());
+                self.stmt(inc, &mut inner);
+            }
+            NodeType::DoWhile(ref mut body, ref mut cond) => {
+                kill(table, &writes_of(&[body, cond]));
+                self.stmt(body, &mut table.clone());
+                self.eval(cond, &mut table.clone());
+            }
+            NodeType::Switch(ref mut cond, ref body, ..) => {
+                self.eval(cond, table);
+                kill(table, &writes_of(&[body]));
+            }
+            _ => kill(table, &writes_of(&[node])),
+        }
+    }
+}
+
+// Returns how many expressions were replaced by a value computed
+// before.
+pub fn number(nodes: &mut [Node]) -> usize {
+    let mut n = 0;
+    for node in nodes.iter_mut() {
+        if let NodeType::Func(_, ref args, ref mut body, ref mut stacksize, _) = node.op {
+            let mut locals = HashSet::new();
+            let known = args.iter().all(|arg| pinned(arg, false, &mut locals));
+            if !known || !pinned(body, false, &mut locals) {
+                continue;
+            }
+            let mut numbering = Numbering {
+                pinned: &locals,
+                counts: HashMap::new(),
+                keep: None,
+                sites: 0,
+                used: HashSet::new(),
+                stacksize: *stacksize,
+                reused: 0,
+            };
+            numbering.count(body);
+            // A first pass on a copy finds which values are reused, and
+            // the second one stores only those.
+            numbering.stmt(&mut body.clone(), &mut Table::new());
+            numbering.keep = Some(mem::take(&mut numbering.used));
+            numbering.sites = 0;
+            numbering.reused = 0;
+            numbering.stacksize = *stacksize;
+            numbering.stmt(body, &mut Table::new());
+            *stacksize = numbering.stacksize;
+            n += numbering.reused;
+        }
+    }
+    n
+}
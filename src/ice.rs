@@ -0,0 +1,150 @@
+// Internal compiler errors.
+//
+// Errors in the program that sema and the phases after it find are
+// reported by panicking with a Diagnostic (see diagnostic.rs). Any other panic is a bug in r9cc.
+// To make such bugs easy to report, each thread keeps track of what it
+// is compiling: the phase, which Timer::phase sets, the function, and
+// the token or IR instruction. compile() turns the panic into an Ice
+// carrying that context, instead of a bare Rust panic message.
+
+use crate::diagnostic::{Diagnostic, Severity, Stop};
+
+use std::any::Any;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::RefCell;
+use std::fmt;
+use std::panic::PanicHookInfo;
+
+// What a thread was doing.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub phase: Option<String>,
+    pub function: Option<String>,
+    pub position: Option<(&'static str, usize)>, // ("token" or "IR instruction", index)
+    // Where in r9cc's source the panic happened, and the backtrace if
+    // RUST_BACKTRACE asks for one. Only set if hook() is installed.
+    pub panic_location: Option<String>,
+    pub backtrace: Option<String>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+pub fn set_phase(name: &str) {
+    CONTEXT.with(|c| {
+        let mut c = c.borrow_mut();
+        c.phase = Some(name.into());
+        c.function = None;
+        c.position = None;
+    });
+}
+
+pub fn set_function(name: &str) {
+    CONTEXT.with(|c| {
+        let mut c = c.borrow_mut();
+        c.function = Some(name.into());
+        c.position = None;
+    });
+}
+
+pub fn set_position(what: &'static str, index: usize) {
+    CONTEXT.with(|c| c.borrow_mut().position = Some((what, index)));
+}
+
+// The current thread's context, and a way to carry it to another
+// thread, as parallel::map does for its workers.
+pub fn context() -> Context {
+    CONTEXT.with(|c| c.borrow().clone())
+}
+
+pub fn set_context(context: Context) {
+    CONTEXT.with(|c| *c.borrow_mut() = context);
+}
+
+// A panic hook that records where r9cc panicked in the context rather
+// than printing anything. The r9cc binary installs it.
+pub fn hook(info: &PanicHookInfo) {
+    if info.payload().is::<Diagnostic>() || info.payload().is::<Stop>() {
+        return;
+    }
+    let backtrace = Backtrace::capture();
+    CONTEXT.with(|c| {
+        let mut c = c.borrow_mut();
+        c.panic_location = info.location().map(|loc| loc.to_string());
+        c.backtrace = match backtrace.status() {
+            BacktraceStatus::Captured => Some(backtrace.to_string()),
+            _ => None,
+        };
+    });
+}
+
+// A bug in r9cc, found while compiling something.
+#[derive(Debug, Clone)]
+pub struct Ice {
+    pub message: String,
+    pub context: Context,
+}
+
+impl Ice {
+    // Turns the payload of a panic that isn't about the program into an
+    // Ice, with the context of the current thread.
+    pub fn from_panic(payload: Box<dyn Any + Send>) -> Self {
+        let message = if let Some(msg) = payload.downcast_ref::<&str>() {
+            msg.to_string()
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+            msg.clone()
+        } else {
+            "unknown error".into()
+        };
+        Ice {
+            message,
+            context: context(),
+        }
+    }
+
+    // Reports the bug like an error, with the context in notes:
+    //
+    //   r9cc: error: internal compiler error: index out of bounds
+    //   r9cc: note: in regalloc, function 'main', at IR instruction 12
+    //   r9cc: note: panicked at src/regalloc.rs:40:5
+    //   r9cc: note: please file a bug at https://github.com/...
+    pub fn diagnostic(&self) -> Diagnostic {
+        let c = &self.context;
+        let mut d = Diagnostic::new(
+            Severity::Error,
+            format!("internal compiler error: {}", self.message),
+            None,
+        );
+        let mut place = vec![];
+        if let Some(ref phase) = c.phase {
+            place.push(format!("in {}", phase));
+        }
+        if let Some(ref name) = c.function {
+            place.push(format!("function '{}'", name));
+        }
+        if let Some((what, index)) = c.position {
+            place.push(format!("at {} {}", what, index));
+        }
+        if !place.is_empty() {
+            d = d.with_note(place.join(", "));
+        }
+        if let Some(ref loc) = c.panic_location {
+            d = d.with_note(format!("panicked at {}", loc));
+        }
+        if let Some(ref bt) = c.backtrace {
+            d = d.with_note(format!("backtrace:\n{}", bt.trim_end()));
+        }
+        d.with_note(
+            "please file a bug at https://github.com/luojia65/r9cc/issues \
+             with the source file and the command line that trigger it"
+                .into(),
+        )
+    }
+}
+
+impl fmt::Display for Ice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.diagnostic().render(false).trim_end())
+    }
+}
@@ -0,0 +1,86 @@
+// What the r9cc binary and the benchmarks use of the compiler's
+// insides, which are otherwise private to the crate. This is no API:
+// it changes whenever they need something else. Tools should use the
+// functions at the top of the crate, or the frontend (see frontend.rs).
+
+pub mod cache {
+    pub use crate::cache::Stats;
+}
+
+pub mod conformance {
+    pub use crate::conformance::{read_xfail, run_suite};
+}
+
+pub mod coverage {
+    pub use crate::coverage::report;
+}
+
+pub mod diagnostic {
+    pub use crate::diagnostic::{Diagnostic, Severity, WARNINGS};
+}
+
+pub mod differential {
+    pub use crate::differential::{run_all, Program};
+}
+
+pub mod driver {
+    pub use crate::driver::build_executable;
+}
+
+pub mod elf {
+    pub use crate::elf::write_elf;
+}
+
+pub mod ice {
+    pub use crate::ice::{hook, set_context, set_phase, Ice};
+}
+
+pub mod jit {
+    pub use crate::jit::run;
+}
+
+pub mod repl {
+    pub use crate::repl::Session;
+}
+
+pub mod stats {
+    pub use crate::stats::{report, stack_usage_report};
+}
+
+pub mod target {
+    pub use crate::target::{host, lookup, Target, TARGETS};
+}
+
+pub mod timer {
+    pub use crate::timer::{report, CountingAllocator, Timer};
+}
+
+// The phases, each on its own, for the benchmarks.
+
+pub mod token {
+    pub use crate::token::{lex_str, tokenize_str};
+}
+
+pub mod preprocess {
+    pub use crate::preprocess::Preprocessor;
+}
+
+pub mod parse {
+    pub use crate::parse::{parse, MAX_DEPTH};
+}
+
+pub mod sema {
+    pub use crate::sema::sema;
+}
+
+pub mod gen_ir {
+    pub use crate::gen_ir::{gen_ir, CodegenContext};
+}
+
+pub mod opt {
+    pub use crate::opt::{optimize, Stats};
+}
+
+pub mod regalloc {
+    pub use crate::regalloc::alloc_regs;
+}
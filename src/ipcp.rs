@@ -0,0 +1,193 @@
+// Interprocedural constant propagation (-O2).
+//
+// It runs on the whole program after sema, where what calls a function
+// and what writes a variable are in plain sight:
+//
+//   static int scale(int x, int n) { return x * n; }
+//   ... scale(a, 4) ... scale(b, 4) ...
+//
+// Every call passes 4 for n, so n is 4 in the body. Likewise a static
+// global that nothing writes after its initializer is its initial
+// value wherever it is read. Only what is static qualifies, since code
+// in other files may call the function or write the variable, and only
+// as long as its address is never taken. Reads become constants, which
+// the IR optimizer then folds into the code that uses them.
+
+use crate::parse::{Node, NodeType};
+use crate::sema::eval;
+use crate::{Ctype, Scope, TokenType, Type, Var};
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+// What the calls of a function pass for a parameter.
+#[derive(Clone, Copy, PartialEq)]
+enum Passed {
+    Nothing, // No call seen yet
+    Constant(i64),
+    Varies,
+}
+
+struct Param {
+    offset: usize,
+    ty: Rc<Type>,
+    passed: Passed,
+}
+
+impl Param {
+    fn pass(&mut self, arg: Option<&Node>) {
+        let val = arg.and_then(eval).map(|val| wrap(val, &self.ty));
+        self.passed = match (self.passed, val) {
+            (Passed::Nothing, Some(val)) => Passed::Constant(val),
+            (Passed::Constant(x), Some(val)) if x == val => Passed::Constant(x),
+            _ => Passed::Varies,
+        };
+    }
+}
+
+fn is_integer(ty: &Type) -> bool {
+    matches!(ty.ty, Ctype::Int | Ctype::Char | Ctype::Long)
+}
+
+// `val` converted to `ty`, an integer type.
+fn wrap(val: i64, ty: &Type) -> i64 {
+    match (ty.size, ty.is_unsigned) {
+        (1, false) => val as i8 as i64,
+        (1, true) => val as u8 as i64,
+        (4, false) => val as i32 as i64,
+        (4, true) => val as u32 as i64,
+        _ => val,
+    }
+}
+
+fn is_assign(op: &TokenType) -> bool {
+    use crate::TokenType::*;
+    matches!(
+        op,
+        Equal | MulEQ | DivEQ | ModEQ | AddEQ | SubEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ
+    )
+}
+
+// Calls `f` on `node` and everything in it, with whether each one is
+// written to or has its address taken.
+fn visit(node: &mut Node, written: bool, f: &mut impl FnMut(&mut Node, bool)) {
+    f(node, written);
+    match node.op {
+        NodeType::BinOp(ref op, ref mut lhs, ref mut rhs) => {
+            visit(lhs, is_assign(op), f);
+            visit(rhs, false, f);
+        }
+        NodeType::Addr(ref mut expr)
+        | NodeType::PostInc(ref mut expr)
+        | NodeType::PostDec(ref mut expr) => visit(expr, true, f),
+        NodeType::Dot(ref mut expr, ..) => visit(expr, written, f),
+        _ => {
+            for child in node.children_mut() {
+                visit(child, false, f);
+            }
+        }
+    }
+}
+
+// Returns how many reads became constants.
+pub fn propagate(nodes: &mut [Node], globals: &[Var]) -> usize {
+    let mut params: HashMap<String, Vec<Param>> = HashMap::new();
+    for node in nodes.iter() {
+        if let NodeType::Func(name, ref args, _, _, true) = node.op {
+            let known = args.iter().filter_map(|arg| match arg.op {
+                NodeType::Vardef(_, _, Scope::Local(offset)) => Some(Param {
+                    offset,
+                    ty: arg.ty.clone(),
+                    passed: Passed::Nothing,
+                }),
+                _ => None,
+            });
+            params.insert(name.to_string(), known.collect());
+        }
+    }
+    let mut vars: HashMap<String, i64> = HashMap::new();
+    for var in globals {
+        if let Scope::Global(ref data, _, false, false, true) = var.scope {
+            if is_integer(&var.ty) {
+                let mut bytes = [0; 8];
+                bytes[..data.len()].copy_from_slice(data);
+                vars.insert(var.name.clone(), wrap(i64::from_le_bytes(bytes), &var.ty));
+            }
+        }
+    }
+
+    // What a global's initializer points to may be written, or called
+    // with anything, through the pointer.
+    for reloc in globals.iter().flat_map(|var| &var.relocs) {
+        vars.remove(&reloc.name);
+        if let Some(params) = params.get_mut(&reloc.name) {
+            for param in params {
+                param.passed = Passed::Varies;
+            }
+        }
+    }
+
+    // Parameters written to, as (function, offset).
+    let mut written_params: HashSet<(String, usize)> = HashSet::new();
+    for node in nodes.iter_mut() {
+        let func = match node.op {
+            NodeType::Func(name, ..) => name.to_string(),
+            _ => continue,
+        };
+        visit(node, false, &mut |node, written| match node.op {
+            NodeType::Call(callee, ref args) => {
+                if let Some(params) = params.get_mut(&*callee.as_str()) {
+                    for (i, param) in params.iter_mut().enumerate() {
+                        param.pass(args.get(i));
+                    }
+                }
+            }
+            NodeType::Gvar(ref name, ..) => {
+                if written {
+                    vars.remove(name);
+                }
+                // A function used as a value may be called through a
+                // pointer, with anything.
+                if let Some(params) = params.get_mut(name) {
+                    for param in params {
+                        param.passed = Passed::Varies;
+                    }
+                }
+            }
+            NodeType::Lvar(Scope::Local(offset)) if written => {
+                written_params.insert((func.clone(), offset));
+            }
+            _ => (),
+        });
+    }
+
+    let mut n = 0;
+    for node in nodes.iter_mut() {
+        let func = match node.op {
+            NodeType::Func(name, ..) => name.to_string(),
+            _ => continue,
+        };
+        let mut consts: HashMap<usize, i64> = HashMap::new();
+        for param in params.get(&func).into_iter().flatten() {
+            if let Passed::Constant(val) = param.passed {
+                if is_integer(&param.ty) && !written_params.contains(&(func.clone(), param.offset))
+                {
+                    consts.insert(param.offset, val);
+                }
+            }
+        }
+        visit(node, false, &mut |node, written| {
+            let val = match node.op {
+                _ if written => return,
+                NodeType::Lvar(Scope::Local(offset)) => consts.get(&offset),
+                NodeType::Gvar(ref name, ..) => vars.get(name),
+                _ => return,
+            };
+            if let Some(&val) = val {
+                node.op = NodeType::Num(val);
+                n += 1;
+            }
+        });
+    }
+    n
+}
@@ -1,4 +1,4 @@
-use crate::gen_ir::{Function, IROp, IRType, IR};
+use crate::gen_ir::{CallArg, Function, IROp, IRType, IR};
 
 use std::fmt;
 
@@ -20,37 +20,76 @@ impl<'a> From<&'a IROp> for IRInfo {
         match op {
             Add => IRInfo::new("ADD", IRType::RegReg),
             AddImm => IRInfo::new("ADD", IRType::RegImm),
-            Call(_, _, _) => IRInfo::new("CALL", IRType::Call),
+            Call(..) => IRInfo::new("CALL", IRType::Call),
             Div => IRInfo::new("DIV", IRType::RegReg),
+            UDiv => IRInfo::new("UDIV", IRType::RegReg),
             Imm => IRInfo::new("MOV", IRType::RegImm),
             Jmp => IRInfo::new("JMP", IRType::Jmp),
             Kill => IRInfo::new("KILL", IRType::Reg),
             Label => IRInfo::new("", IRType::Label),
             LabelAddr(_) => IRInfo::new("LABEL_ADDR", IRType::LabelAddr),
+            TlsAddr(_) => IRInfo::new("TLS_ADDR", IRType::LabelAddr),
             EQ => IRInfo::new("EQ", IRType::RegReg),
             NE => IRInfo::new("NE", IRType::RegReg),
             LE => IRInfo::new("LE", IRType::RegReg),
             LT => IRInfo::new("LT", IRType::RegReg),
+            ULE => IRInfo::new("ULE", IRType::RegReg),
+            ULT => IRInfo::new("ULT", IRType::RegReg),
             AND => IRInfo::new("AND", IRType::RegReg),
             OR => IRInfo::new("OR", IRType::RegReg),
             XOR => IRInfo::new("XOR", IRType::RegReg),
             SHL => IRInfo::new("SHL", IRType::RegReg),
             SHR => IRInfo::new("SHR", IRType::RegReg),
+            SAR => IRInfo::new("SAR", IRType::RegReg),
             Mod => IRInfo::new("MOD", IRType::RegReg),
+            UMod => IRInfo::new("UMOD", IRType::RegReg),
             Neg => IRInfo::new("NEG", IRType::Reg),
+            Not => IRInfo::new("NOT", IRType::Reg),
+            Sext(_) => IRInfo::new("SEXT", IRType::Reg),
+            Zext(_) => IRInfo::new("ZEXT", IRType::Reg),
             Load(_) => IRInfo::new("LOAD", IRType::Mem),
             Mov => IRInfo::new("MOV", IRType::RegReg),
             Mul => IRInfo::new("MUL", IRType::RegReg),
             MulImm => IRInfo::new("MUL", IRType::RegImm),
             Nop => IRInfo::new("NOP", IRType::Noarg),
+            Asm(_) => IRInfo::new("ASM", IRType::Noarg),
+            Comment(_) => IRInfo::new("COMMENT", IRType::Noarg),
+            Loc(_, _) => IRInfo::new("LOC", IRType::Noarg),
+            Count(_) => IRInfo::new("COUNT", IRType::Noarg),
+            Trap => IRInfo::new("TRAP", IRType::Noarg),
+            Unreachable => IRInfo::new("UNREACHABLE", IRType::Noarg),
             Return => IRInfo::new("RET", IRType::Reg),
+            ReturnStruct => IRInfo::new("RET_STRUCT", IRType::Reg),
             Store(_) => IRInfo::new("STORE", IRType::Mem),
+            Copy(_) => IRInfo::new("COPY", IRType::Mem),
             StoreArg(_) => IRInfo::new("STORE_ARG", IRType::StoreArg),
+            StoreStructArg(_) => IRInfo::new("STORE_STRUCT_ARG", IRType::StoreArg),
+            VaStart => IRInfo::new("VA_START", IRType::Reg),
+            VaArg(_) => IRInfo::new("VA_ARG", IRType::RegReg),
+            AtomicLoad(_) => IRInfo::new("ATOMIC_LOAD", IRType::RegReg),
+            AtomicStore(_) => IRInfo::new("ATOMIC_STORE", IRType::RegReg),
+            AtomicAdd(_) => IRInfo::new("ATOMIC_ADD", IRType::RegReg),
+            Alloca => IRInfo::new("ALLOCA", IRType::RegReg),
             Sub => IRInfo::new("SUB", IRType::RegReg),
             SubImm => IRInfo::new("SUB", IRType::RegImm),
             Bprel => IRInfo::new("BPREL", IRType::RegImm),
             If => IRInfo::new("IF", IRType::RegLabel),
             Unless => IRInfo::new("UNLESS", IRType::RegLabel),
+            JumpTable(_) => IRInfo::new("JUMP_TABLE", IRType::RegLabel),
+            FAdd => IRInfo::new("FADD", IRType::RegReg),
+            FSub => IRInfo::new("FSUB", IRType::RegReg),
+            FMul => IRInfo::new("FMUL", IRType::RegReg),
+            FDiv => IRInfo::new("FDIV", IRType::RegReg),
+            FEQ => IRInfo::new("FEQ", IRType::RegReg),
+            FNE => IRInfo::new("FNE", IRType::RegReg),
+            FLT => IRInfo::new("FLT", IRType::RegReg),
+            FLE => IRInfo::new("FLE", IRType::RegReg),
+            I2F => IRInfo::new("I2F", IRType::Reg),
+            F2I => IRInfo::new("F2I", IRType::Reg),
+            FExt => IRInfo::new("FEXT", IRType::Reg),
+            FTrunc => IRInfo::new("FTRUNC", IRType::Reg),
+            StoreFloatArg(_) => IRInfo::new("STORE_FLOAT_ARG", IRType::StoreArg),
+            VaArgFloat => IRInfo::new("VA_ARG_FLOAT", IRType::RegReg),
         }
     }
 }
@@ -61,14 +100,15 @@ impl fmt::Display for IR {
 
         let info = &IRInfo::from(&self.op);
 
-        let lhs = self.lhs.unwrap();
+        let lhs = self.lhs.unwrap_or(0);
         match info.ty {
             Label => write!(f, ".L{}:", lhs),
             LabelAddr => match self.op {
-                IROp::LabelAddr(ref name) => write!(f, "  {} r{}, {}", info.name, lhs, name),
+                IROp::LabelAddr(ref name) | IROp::TlsAddr(ref name) => {
+                    write!(f, "  {} r{}, {}", info.name, lhs, name)
+                }
                 _ => unreachable!(),
             },
-            Imm => write!(f, "  {} {}", info.name, lhs),
             Reg => write!(f, "  {} r{}", info.name, lhs),
             Jmp => write!(f, "  {} .L{}", info.name, lhs),
             RegReg => write!(f, "  {} r{}, r{}", info.name, lhs, self.rhs.unwrap()),
@@ -76,28 +116,74 @@ impl fmt::Display for IR {
                 IROp::Load(ref size) | IROp::Store(ref size) => {
                     write!(f, "  {}{} r{}, {}", info.name, size, lhs, self.rhs.unwrap())
                 }
-                IROp::StoreArg(ref size) => {
+                IROp::Copy(ref size) => {
+                    write!(
+                        f,
+                        "  {}{} r{}, r{}",
+                        info.name,
+                        size,
+                        lhs,
+                        self.rhs.unwrap()
+                    )
+                }
+                IROp::StoreArg(ref size) | IROp::StoreFloatArg(ref size) => {
+                    write!(f, "  {}{} {}, {}", info.name, size, lhs, self.rhs.unwrap())
+                }
+                IROp::StoreStructArg(ref size) => {
                     write!(f, "  {}{} {}, {}", info.name, size, lhs, self.rhs.unwrap())
                 }
                 _ => unreachable!(),
             },
             RegImm => write!(f, "  {} r{}, {}", info.name, lhs, self.rhs.unwrap() as i32),
-            RegLabel => write!(f, "  {} r{}, .L{}", info.name, lhs, self.rhs.unwrap()),
+            RegLabel => match self.op {
+                IROp::JumpTable(ref table) => {
+                    let labels = table
+                        .iter()
+                        .map(|l| format!(".L{}", l))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    write!(
+                        f,
+                        "  {} r{}, [{}], .L{}",
+                        info.name,
+                        lhs,
+                        labels,
+                        self.rhs.unwrap()
+                    )
+                }
+                _ => write!(f, "  {} r{}, .L{}", info.name, lhs, self.rhs.unwrap()),
+            },
             Call => match self.op {
-                IROp::Call(ref name, nargs, args) => {
-                    let mut sb: String = format!("  r{} = {}(", lhs, name);
-                    for (i, arg) in args.iter().enumerate().take(nargs) {
+                IROp::Call(ref name, ref args, _) => {
+                    let mut sb: String = match self.rhs {
+                        Some(callee) => format!("  r{} = *r{}(", lhs, callee),
+                        None => format!("  r{} = {}(", lhs, name),
+                    };
+                    for (i, arg) in args.iter().enumerate() {
                         if i != 0 {
-                            sb.push_str(&", ".to_string());
+                            sb.push_str(", ");
+                        }
+                        match *arg {
+                            CallArg::Val(r) => sb.push_str(&format!("r{}", r)),
+                            CallArg::Float(r) => sb.push_str(&format!("float r{}", r)),
+                            CallArg::Struct(r, size) => {
+                                sb.push_str(&format!("struct({}) r{}", size, r))
+                            }
+                            CallArg::Ret(r, size) => sb.push_str(&format!("ret({}) r{}", size, r)),
                         }
-                        sb.push_str(&format!("r{}", *arg));
                     }
-                    sb.push_str(")");
+                    sb.push(')');
                     write!(f, "{}", sb)
                 }
                 _ => unreachable!(),
             },
-            Noarg => write!(f, "  {}", info.name),
+            Noarg => match self.op {
+                IROp::Asm(ref text) => write!(f, "  {} {:?}", info.name, text),
+                IROp::Comment(ref text) => write!(f, "  {} {:?}", info.name, text),
+                IROp::Loc(ref file, line) => write!(f, "  {} {:?}:{}", info.name, file, line),
+                IROp::Count(n) => write!(f, "  {} {}", info.name, n),
+                _ => write!(f, "  {}", info.name),
+            },
         }
     }
 }
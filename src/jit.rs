@@ -0,0 +1,341 @@
+// Running programs in memory (--run), with no assembler or linker.
+//
+// The built-in assembler's object is loaded into memory mapped in the
+// low 2GB, where the absolute 32-bit addresses of switch jump tables
+// can reach it. Its relocations are applied there, and then main is
+// called like any function. What the program calls but doesn't define
+// comes from a small set of libc functions, through a stub next to the
+// code for each: libc is too far away for a call's 32-bit offset.
+
+// Elsewhere load() fails, leaving what it would use unused.
+#![cfg_attr(
+    not(all(target_arch = "x86_64", target_os = "linux")),
+    allow(dead_code)
+)]
+
+use crate::elf::{Object, RelocTarget, RelocType};
+use crate::target::Target;
+use crate::util::roundup;
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+// Whether programs compiled for `target` can run in memory here: the
+// host must be x86_64 Linux, and so must the target.
+pub fn supports(target: &dyn Target) -> bool {
+    cfg!(all(target_arch = "x86_64", target_os = "linux")) && target.name() == "x86_64"
+}
+
+const PAGE: usize = 4096;
+
+// jmp [rip+2]; two bytes of padding; the address to jump to.
+const STUB: [u8; 8] = [0xff, 0x25, 0x02, 0x00, 0x00, 0x00, 0x0f, 0x0b];
+const STUB_SIZE: usize = 16;
+
+// The libc functions a program run in memory can call.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn libc_function(name: &str) -> Option<usize> {
+    use libc::*;
+    let f: *const () = match name {
+        "printf" => printf as *const (),
+        "sprintf" => sprintf as *const (),
+        "snprintf" => snprintf as *const (),
+        "puts" => puts as *const (),
+        "putchar" => putchar as *const (),
+        "getchar" => getchar as *const (),
+        "exit" => exit as *const (),
+        "abort" => abort as *const (),
+        "malloc" => malloc as *const (),
+        "calloc" => calloc as *const (),
+        "realloc" => realloc as *const (),
+        "free" => free as *const (),
+        "memcpy" => memcpy as *const (),
+        "memmove" => memmove as *const (),
+        "memset" => memset as *const (),
+        "memcmp" => memcmp as *const (),
+        "strlen" => strlen as *const (),
+        "strcmp" => strcmp as *const (),
+        "strncmp" => strncmp as *const (),
+        "strcpy" => strcpy as *const (),
+        "strncpy" => strncpy as *const (),
+        "strcat" => strcat as *const (),
+        "strchr" => strchr as *const (),
+        "strrchr" => strrchr as *const (),
+        "strstr" => strstr as *const (),
+        "atoi" => atoi as *const (),
+        "strtol" => strtol as *const (),
+        "rand" => rand as *const (),
+        "srand" => srand as *const (),
+        _ => return None,
+    };
+    Some(f as usize)
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+fn libc_function(_name: &str) -> Option<usize> {
+    None
+}
+
+// Where each section of an object goes in memory, as offsets from the
+// start of the mapping. Sections start on a page of their own, so that
+// code and data can be protected differently.
+struct Layout {
+    sections: Vec<Option<usize>>,  // None if not loaded
+    externs: Vec<(String, usize)>, // Functions from libc, and their stubs
+    size: usize,
+}
+
+fn layout(obj: &Object) -> Result<Layout, String> {
+    let mut size = 0;
+    let mut sections = vec![];
+    for sec in &obj.sections {
+        if !sec.is_alloc() {
+            sections.push(None);
+            continue;
+        }
+        if sec.is_tls() && !sec.data.is_empty() {
+            return Err("thread-local variables are not supported by --run".into());
+        }
+        size = roundup(size, PAGE);
+        sections.push(Some(size));
+        size += sec.data.len();
+    }
+
+    let defined: HashSet<&str> = obj
+        .symbols
+        .iter()
+        .filter(|sym| sym.section.is_some())
+        .map(|sym| &*sym.name)
+        .collect();
+    let mut externs: Vec<String> = vec![];
+    for (i, sec) in obj.sections.iter().enumerate() {
+        if sections[i].is_none() {
+            continue;
+        }
+        for reloc in &sec.relocs {
+            if reloc.ty == RelocType::GotTpOff {
+                return Err("thread-local variables are not supported by --run".into());
+            }
+            if let RelocTarget::Symbol(ref name) = reloc.target {
+                if !defined.contains(&**name) && !externs.contains(name) {
+                    externs.push(name.clone());
+                }
+            }
+        }
+    }
+    size = roundup(size, PAGE);
+    let externs = externs
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| (name, size + i * STUB_SIZE))
+        .collect::<Vec<_>>();
+    size = roundup(size + externs.len() * STUB_SIZE, PAGE).max(PAGE);
+    Ok(Layout {
+        sections,
+        externs,
+        size,
+    })
+}
+
+// Fills `mem`, mapped at `base`, with the loaded object. What it
+// doesn't define is looked up with `resolve`, then in libc. Returns the
+// addresses of the global symbols it defines.
+fn relocate(
+    obj: &Object,
+    layout: &Layout,
+    base: usize,
+    mem: &mut [u8],
+    resolve: &dyn Fn(&str) -> Option<usize>,
+) -> Result<HashMap<String, usize>, String> {
+    let mut addrs: HashMap<&str, usize> = HashMap::new();
+    for sym in &obj.symbols {
+        if let Some(off) = sym.section.and_then(|i| layout.sections[i]) {
+            addrs.insert(&sym.name, base + off + sym.value);
+        }
+    }
+    for (name, off) in &layout.externs {
+        // What `resolve` finds was loaded before, near enough to need
+        // no stub.
+        if let Some(addr) = resolve(name) {
+            addrs.insert(name, addr);
+            continue;
+        }
+        let f = libc_function(name).ok_or_else(|| format!("undefined reference to '{}'", name))?;
+        mem[*off..*off + STUB.len()].copy_from_slice(&STUB);
+        mem[*off + STUB.len()..*off + STUB_SIZE].copy_from_slice(&(f as u64).to_le_bytes());
+        addrs.insert(name, base + off);
+    }
+
+    for (sec, off) in obj.sections.iter().zip(&layout.sections) {
+        let off = match off {
+            Some(off) => *off,
+            None => continue,
+        };
+        mem[off..off + sec.data.len()].copy_from_slice(&sec.data);
+        for reloc in &sec.relocs {
+            let target = match reloc.target {
+                RelocTarget::Section(i) => base + layout.sections[i].unwrap_or(0),
+                RelocTarget::Symbol(ref name) => addrs[&**name],
+            };
+            let value = (target as i64).wrapping_add(reloc.addend);
+            let at = off + reloc.offset;
+            let place = (base + at) as i64;
+            match reloc.ty {
+                RelocType::Abs64 => mem[at..at + 8].copy_from_slice(&value.to_le_bytes()),
+                RelocType::Pc32 | RelocType::Plt32 | RelocType::Abs32S => {
+                    let value = if reloc.ty.is_pc_relative() {
+                        value - place
+                    } else {
+                        value
+                    };
+                    let value = i32::try_from(value)
+                        .map_err(|_| format!("relocation out of range in {}", sec.name))?;
+                    mem[at..at + 4].copy_from_slice(&value.to_le_bytes());
+                }
+                RelocType::GotTpOff => unreachable!(),
+            }
+        }
+    }
+
+    Ok(obj
+        .symbols
+        .iter()
+        .filter(|sym| sym.global && sym.section.is_some())
+        .map(|sym| (sym.name.clone(), addrs[&*sym.name]))
+        .collect())
+}
+
+// An object loaded into memory, which stays there until this is
+// dropped.
+pub struct Image {
+    base: usize,
+    size: usize,
+    symbols: HashMap<String, usize>, // The global ones it defines
+}
+
+impl Image {
+    pub fn symbol(&self, name: &str) -> Option<usize> {
+        self.symbols.get(name).copied()
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.symbols
+            .iter()
+            .map(|(name, addr)| (name.as_str(), *addr))
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.size) };
+    }
+}
+
+// Loads `obj` into memory, ready to run. What it doesn't define is
+// looked up with `resolve`, which must give addresses in memory mapped
+// as this is, such as other images', and then in libc.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub fn load(obj: &Object, resolve: &dyn Fn(&str) -> Option<usize>) -> Result<Image, String> {
+    use std::io;
+    use std::ptr;
+
+    let layout = layout(obj)?;
+    let base = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            layout.size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_32BIT,
+            -1,
+            0,
+        )
+    };
+    if base == libc::MAP_FAILED {
+        return Err(format!("cannot map memory: {}", io::Error::last_os_error()));
+    }
+    let mut image = Image {
+        base: base as usize,
+        size: layout.size,
+        symbols: HashMap::new(),
+    };
+
+    let mem = unsafe { std::slice::from_raw_parts_mut(base as *mut u8, layout.size) };
+    image.symbols = relocate(obj, &layout, image.base, mem, resolve)?;
+
+    // Code, and the stubs, become executable and stop being writable.
+    let mut exec: Vec<(usize, usize)> = obj
+        .sections
+        .iter()
+        .zip(&layout.sections)
+        .filter_map(|(sec, off)| {
+            off.filter(|_| sec.is_exec())
+                .map(|off| (off, sec.data.len()))
+        })
+        .collect();
+    if let Some((_, stubs)) = layout.externs.first() {
+        exec.push((*stubs, layout.externs.len() * STUB_SIZE));
+    }
+    for (off, len) in exec.into_iter().filter(|(_, len)| *len > 0) {
+        let prot = libc::PROT_READ | libc::PROT_EXEC;
+        if unsafe { libc::mprotect(mem[off..].as_mut_ptr() as _, roundup(len, PAGE), prot) } != 0 {
+            return Err(format!("cannot map code: {}", io::Error::last_os_error()));
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+pub fn load(_obj: &Object, _resolve: &dyn Fn(&str) -> Option<usize>) -> Result<Image, String> {
+    Err("running programs in memory is only supported on x86_64 Linux".into())
+}
+
+/// Calls the function at `addr`, which takes no arguments and returns
+/// a `T`: an int as i32, or a pointer as usize.
+///
+/// # Safety
+///
+/// `addr` must be such a function, in an image that is still loaded.
+pub unsafe fn call<T>(addr: usize) -> T {
+    let f: extern "C" fn() -> T = std::mem::transmute(addr);
+    let result = f();
+    flush();
+    result
+}
+
+// The program's output is in libc's buffers, which are flushed at exit,
+// and r9cc doesn't exit through libc.
+fn flush() {
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    unsafe {
+        libc::fflush(std::ptr::null_mut());
+    }
+}
+
+/// Loads `obj` and runs its main with `args` as argv, returning what
+/// main does. A program that calls exit() ends the process.
+///
+/// # Safety
+///
+/// The program runs in this process, so it must be free of undefined
+/// behavior, such as writing past the end of an array.
+pub unsafe fn run(obj: &Object, args: &[String]) -> Result<i32, String> {
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int};
+
+    let image = load(obj, &|_| None)?;
+    let main = image
+        .symbol("main")
+        .ok_or_else(|| "undefined reference to 'main'".to_string())?;
+    let args = args
+        .iter()
+        .map(|arg| CString::new(arg.as_str()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "argument contains a NUL byte".to_string())?;
+    let mut argv: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+    argv.push(std::ptr::null());
+    let main: extern "C" fn(c_int, *const *const c_char) -> c_int = std::mem::transmute(main);
+    let status = main(args.len() as c_int, argv.as_ptr());
+    flush();
+    Ok(status)
+}
@@ -1,16 +1,73 @@
-pub mod gen_ir;
-pub mod gen_x86;
-pub mod irdump;
-pub mod parse;
-pub mod preprocess;
-pub mod regalloc;
-pub mod sema;
-pub mod token;
+pub(crate) mod assemble;
+pub(crate) mod cache;
+pub(crate) mod conformance;
+pub(crate) mod coverage;
+pub(crate) mod diagnostic;
+pub(crate) mod differential;
+pub(crate) mod dot;
+pub(crate) mod driver;
+pub(crate) mod elf;
+pub(crate) mod formatter;
+pub mod frontend;
+pub(crate) mod gen_ir;
+pub(crate) mod gen_llvm;
+pub(crate) mod gen_riscv;
+pub(crate) mod gen_wasm;
+pub(crate) mod gen_x86;
+pub(crate) mod gvn;
+pub(crate) mod ice;
+#[doc(hidden)]
+pub mod internals;
+pub(crate) mod ipcp;
+pub(crate) mod irdump;
+pub(crate) mod jit;
+pub(crate) mod licm;
+pub(crate) mod opt;
+pub(crate) mod parallel;
+pub(crate) mod parse;
+pub(crate) mod preprocess;
+pub(crate) mod pretty;
+pub(crate) mod regalloc;
+pub(crate) mod repl;
+pub(crate) mod sema;
+pub(crate) mod stats;
+pub(crate) mod symbol;
+pub(crate) mod target;
+pub(crate) mod timer;
+pub(crate) mod token;
 mod util;
+pub(crate) mod whole_program;
 
 #[macro_use]
 extern crate lazy_static;
 
+// The settings and errors that compile() and the rest take and give
+// are made of these.
+pub use diagnostic::Warnings;
+pub use gen_ir::Sanitize;
+pub use ice::Ice;
+
+use coverage::Profile;
+use diagnostic::{Diagnostic, Severity};
+use frontend::Phase;
+use gen_ir::{gen_ir, CodegenContext, Function, IROp};
+use gen_llvm::gen_llvm;
+use irdump::dump_ir;
+use parse::{parse, Node, ParseError};
+use preprocess::Preprocessor;
+use regalloc::alloc_regs;
+use sema::sema;
+use stats::{FunctionSize, MemoryStats, StackUsage};
+use symbol::Symbol;
+use target::Target;
+use timer::Timer;
+use token::{preprocess_str, tokenize_timed};
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::thread;
+
 const REGS_N: usize = 7;
 
 #[macro_export]
@@ -26,72 +83,104 @@ macro_rules! matches(
 // Token type
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
-    Num(i32),            // Number literal
-    Str(String, usize),  // String literal. (str, len)
-    CharLiteral(String), // Char literal.
-    Ident(String),       // Identifier
-    Param(usize),        // Function-like macro parameter
-    Arrow,               // ->
-    Extern,              // "extern"
-    Typedef,             // "typedef"
-    Int,                 // "int"
-    Char,                // "char"
-    Void,                // "void"
-    Struct,              // "struct"
-    Plus,                // +
-    Minus,               // -
-    Mul,                 // *
-    Div,                 // /
-    And,                 // &
-    Dot,                 // .
-    Comma,               // ,
-    Exclamation,         // !
-    Question,            // ?
-    VerticalBar,         // |
-    Hat,                 // ^
-    Colon,               // :
-    HashMark,            // #
-    If,                  // "if"
-    Else,                // "else"
-    For,                 // "for"
-    Do,                  // "do"
-    While,               // "while"
-    Break,               // "break"
-    EQ,                  // ==
-    NE,                  // !=
-    LE,                  // <=
-    GE,                  // >=
-    Semicolon,           // ;
-    LeftParen,           // (
-    RightParen,          // )
-    LeftBracket,         // [
-    RightBracket,        // ]
-    LeftBrace,           // {
-    RightBrace,          // }
-    LeftAngleBracket,    // <
-    RightAngleBracket,   // >
-    Equal,               // =
-    Logor,               // ||
-    Logand,              // &&
-    SHL,                 // <<
-    Inc,                 // ++
-    Dec,                 // --
-    MulEQ,               // *=
-    DivEQ,               // /=
-    ModEQ,               // %=
-    AddEQ,               // +=
-    SubEQ,               // -=
-    ShlEQ,               // <<=
-    ShrEQ,               // >>=
-    BitandEQ,            // &=
-    XorEQ,               // ^=
-    BitorEQ,             // |=
-    SHR,                 // >>
-    Mod,                 // %
-    Return,              // "return"
-    Sizeof,              // "sizeof"
-    Alignof,             // "_Alignof"
-    NewLine,             // preprocessor-only token
+    Num(i64, NumType), // Number literal
+    FloatNum,          // Floating constant, read by Token::float_value
+    Str,               // String literal, read by Token::str_value
+    Ident(Symbol),     // Identifier
+    Param(usize),      // Function-like macro parameter
+    Arrow,             // ->
+    Extern,            // "extern"
+    Auto,              // "auto"
+    Register,          // "register"
+    Static,            // "static"
+    Typedef,           // "typedef"
+    Int,               // "int"
+    Char,              // "char"
+    Long,              // "long"
+    Signed,            // "signed"
+    Unsigned,          // "unsigned"
+    Float,             // "float"
+    Double,            // "double"
+    Void,              // "void"
+    Struct,            // "struct"
+    Atomic,            // "_Atomic"
+    ThreadLocal,       // "_Thread_local"
+    Noreturn,          // "_Noreturn"
+    Plus,              // +
+    Minus,             // -
+    Mul,               // *
+    Div,               // /
+    And,               // &
+    Dot,               // .
+    Ellipsis,          // ...
+    Comma,             // ,
+    Exclamation,       // !
+    Tilde,             // ~
+    Question,          // ?
+    VerticalBar,       // |
+    Hat,               // ^
+    Colon,             // :
+    HashMark,          // #
+    If,                // "if"
+    Else,              // "else"
+    For,               // "for"
+    Do,                // "do"
+    While,             // "while"
+    Break,             // "break"
+    Switch,            // "switch"
+    Case,              // "case"
+    Default,           // "default"
+    EQ,                // ==
+    NE,                // !=
+    LE,                // <=
+    GE,                // >=
+    Semicolon,         // ;
+    LeftParen,         // (
+    RightParen,        // )
+    LeftBracket,       // [
+    RightBracket,      // ]
+    LeftBrace,         // {
+    RightBrace,        // }
+    LeftAngleBracket,  // <
+    RightAngleBracket, // >
+    Equal,             // =
+    Logor,             // ||
+    Logand,            // &&
+    SHL,               // <<
+    Inc,               // ++
+    Dec,               // --
+    MulEQ,             // *=
+    DivEQ,             // /=
+    ModEQ,             // %=
+    AddEQ,             // +=
+    SubEQ,             // -=
+    ShlEQ,             // <<=
+    ShrEQ,             // >>=
+    BitandEQ,          // &=
+    XorEQ,             // ^=
+    BitorEQ,           // |=
+    SHR,               // >>
+    Mod,               // %
+    Return,            // "return"
+    Sizeof,            // "sizeof"
+    Alignof,           // "_Alignof"
+    Asm,               // "asm"
+    NewLine,           // preprocessor-only token
+    Comment,           // Kept only for r9cc fmt, by lex_with_comments
+    Pragma,            // "#pragma" before one for the parser, made by the preprocessor
+    Eof,               // End of input, after the last token
+}
+
+// The type of a number literal, which its suffix and value decide.
+// Whether a long is wide enough for a long one is up to the target.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NumType {
+    Int,
+    UInt,
+    Long,
+    ULong,
+    LLong,
+    ULLong,
 }
 
 // Character Kind
@@ -127,6 +216,7 @@ impl TokenType {
             ',' => Some(Comma),
             '.' => Some(Dot),
             '!' => Some(Exclamation),
+            '~' => Some(Tilde),
             '?' => Some(Question),
             '|' => Some(VerticalBar),
             '^' => Some(Hat),
@@ -138,21 +228,19 @@ impl TokenType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum Ctype {
+    #[default]
     Int,
     Char,
+    Long, // long and long long, which are both 64 bits
+    Float,
+    Double, // double and long double, which are both 64 bits
     Void,
-    Ptr(Box<Type>),           // ptr of
-    Ary(Box<Type>, usize),    // ary of, len
-    Struct(Vec<parse::Node>), // members
-    Func(Box<Type>),
-}
-
-impl Default for Ctype {
-    fn default() -> Ctype {
-        Ctype::Int
-    }
+    Ptr(Rc<Type>),                             // ptr of
+    Ary(Rc<Type>, usize),                      // ary of, len
+    Struct(Vec<parse::Node>),                  // members
+    Func(Rc<Type>, Vec<Rc<Type>>, bool, bool), // returning, params, variadic, noreturn
 }
 
 #[derive(Debug, Clone)]
@@ -160,6 +248,11 @@ pub struct Type {
     pub ty: Ctype,
     pub size: usize,  // sizeof
     pub align: usize, // alignof
+    // Of an int, long or char. Plain char is unsigned, and signed char
+    // is the char that isn't.
+    pub is_unsigned: bool,
+    pub tag: Option<Symbol>, // Of a struct, if it has one
+    pub is_atomic: bool,     // Of an int, long or pointer declared _Atomic
 }
 
 impl Default for Type {
@@ -168,29 +261,731 @@ impl Default for Type {
             ty: Ctype::default(),
             size: 4,
             align: 4,
+            is_unsigned: false,
+            tag: None,
+            is_atomic: false,
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum Scope {
-    Local(usize),                // offset
-    Global(String, usize, bool), // data, len, is_extern
+    Local(usize),                             // offset
+    Global(Vec<u8>, usize, bool, bool, bool), // data, len, is_extern, is_tls, is_static
 }
 
 #[derive(Debug, Clone)]
 pub struct Var {
-    ty: Box<Type>,
+    pub ty: Rc<Type>,
     pub name: String,
     pub scope: Scope,
+    pub relocs: Vec<Reloc>, // Addresses in a global's initializer
+}
+
+// The address of `name`, plus `addend`, as a pointer at `offset` in a
+// global's data, such as `&x` in `int *p = &x;`. Its bytes in the data,
+// as many as the target's pointers have, are left zero.
+#[derive(Debug, Clone)]
+pub struct Reloc {
+    pub offset: usize,
+    pub name: String,
+    pub addend: i64,
 }
 
 impl Var {
-    fn new(ty: Box<Type>, name: String, scope: Scope) -> Self {
-        Var { ty, name, scope }
+    fn new(ty: Rc<Type>, name: String, scope: Scope) -> Self {
+        Var {
+            ty,
+            name,
+            scope,
+            relocs: vec![],
+        }
+    }
+
+    fn new_global(
+        ty: Rc<Type>,
+        name: String,
+        data: Vec<u8>,
+        len: usize,
+        is_extern: bool,
+        is_tls: bool,
+    ) -> Self {
+        Var::new(ty, name, Scope::Global(data, len, is_extern, is_tls, false))
+    }
+}
+
+// The C standard the program is written to (-std). r9cc accepts what
+// it supports of C11 in any case, but warns about what is newer than
+// the one chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Std {
+    C89,
+    C99,
+    C11,
+}
+
+impl Std {
+    // The standard -std=`name` picks.
+    pub fn lookup(name: &str) -> Option<Std> {
+        match name {
+            "c89" | "c90" | "iso9899:1990" => Some(Std::C89),
+            "c99" | "iso9899:1999" => Some(Std::C99),
+            "c11" | "iso9899:2011" => Some(Std::C11),
+            _ => None,
+        }
+    }
+
+    // The value of __STDC_VERSION__, which C89 doesn't define.
+    pub fn version(self) -> Option<i32> {
+        match self {
+            Std::C89 => None,
+            Std::C99 => Some(199901),
+            Std::C11 => Some(201112),
+        }
+    }
+
+    // Warns that `what`, at `loc`, is a feature of `since`, if that is
+    // newer than this standard.
+    pub fn require(self, since: Std, what: &str, loc: diagnostic::Location) {
+        if self >= since {
+            return;
+        }
+        let (name, code) = match since {
+            Std::C89 => return,
+            Std::C99 => ("C99", "c99-extensions"),
+            Std::C11 => ("C11", "c11-extensions"),
+        };
+        diagnostic::warn(
+            Diagnostic::new(
+                Severity::Warning,
+                format!("{} is a {} feature", what, name),
+                Some(loc),
+            )
+            .with_code(code),
+        );
+    }
+}
+
+// What to compile for, and how. The defaults match running the r9cc
+// binary without flags, which parses its command line into one of
+// these. Library users can set the fields directly or chain the
+// builder methods below:
+//
+//   let opts = Options::default().target("riscv64").opt_level(2).define("N", "10");
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub filename: String, // Name of the source, for diagnostics
+    pub target: String,   // A name or triple, as with --target
+    // Where the driver writes its output (-o). compile() returns it
+    // instead, so it doesn't look at this.
+    pub output: Option<String>,
+    pub include_dirs: Vec<String>,      // -I
+    pub nostdinc: bool,                 // Hide the bundled headers (-nostdinc)
+    pub defines: Vec<(String, String)>, // -D, as (name, body)
+    pub debug_info: bool,               // -g
+    pub emit_llvm: bool,                // Produce LLVM IR instead of assembly
+    pub stack_protector: bool,
+    pub verbose_asm: bool,
+    pub profile: bool, // -pg
+    pub sanitize: Sanitize,
+    pub coverage: bool,
+    // The counts a program built with coverage wrote, to optimize
+    // for (-fprofile-use).
+    pub profile_use: Option<String>,
+    pub opt_level: u8, // As with -O0, -O1 and -O2
+    // Print the IR before and after register allocation to stdout.
+    pub dump_ir1: bool,
+    pub dump_ir2: bool,
+    pub warnings: Warnings, // -W and -w
+    pub time_report: bool,  // -ftime-report
+    pub max_depth: usize,   // -fbracket-depth
+    pub max_errors: usize,  // -fmax-errors, or 0 for no limit
+    pub memory_stats: bool, // --stats=memory
+    pub stack_usage: bool,  // -fstack-usage
+    pub std: Std,           // -std
+    pub trigraphs: bool,    // -trigraphs
+    // Compile the program as a whole (--whole-program), so that nothing
+    // but main is used from outside it. See whole_program.rs.
+    pub whole_program: bool,
+    // Threads to optimize and emit functions on. The output doesn't
+    // depend on it.
+    pub jobs: usize,
+    // Where to keep compiled units, to reuse them when a file is
+    // compiled again unchanged (--cache-dir). See cache.rs.
+    pub cache_dir: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            filename: "-".into(),
+            target: target::host().into(),
+            output: None,
+            include_dirs: vec![],
+            nostdinc: false,
+            defines: vec![],
+            debug_info: false,
+            emit_llvm: false,
+            stack_protector: false,
+            verbose_asm: false,
+            profile: false,
+            sanitize: Sanitize::default(),
+            coverage: false,
+            profile_use: None,
+            opt_level: 0,
+            dump_ir1: false,
+            dump_ir2: false,
+            warnings: Warnings::default(),
+            time_report: false,
+            max_depth: parse::MAX_DEPTH,
+            max_errors: 0,
+            memory_stats: false,
+            stack_usage: false,
+            std: Std::C11,
+            trigraphs: false,
+            whole_program: false,
+            jobs: 1,
+            cache_dir: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = filename.into();
+        self
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = target.into();
+        self
+    }
+
+    pub fn output(mut self, path: impl Into<String>) -> Self {
+        self.output = Some(path.into());
+        self
+    }
+
+    pub fn include_dir(mut self, dir: impl Into<String>) -> Self {
+        self.include_dirs.push(dir.into());
+        self
+    }
+
+    pub fn define(mut self, name: impl Into<String>, body: impl Into<String>) -> Self {
+        self.defines.push((name.into(), body.into()));
+        self
+    }
+
+    pub fn debug_info(mut self, on: bool) -> Self {
+        self.debug_info = on;
+        self
+    }
+
+    pub fn opt_level(mut self, level: u8) -> Self {
+        self.opt_level = level;
+        self
+    }
+
+    pub fn warnings(mut self, warnings: Warnings) -> Self {
+        self.warnings = warnings;
+        self
     }
 
-    fn new_global(ty: Box<Type>, name: String, data: String, len: usize, is_extern: bool) -> Self {
-        Var::new(ty, name.clone(), Scope::Global(data, len, is_extern))
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    pub fn cache_dir(mut self, dir: impl Into<String>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum CompileError {
+    UnknownTarget(String),
+    Unsupported(String, &'static str), // (feature, target)
+    // The program is not valid C. The diagnostics are its errors and
+    // the warnings issued before the last one, in the order found.
+    Invalid(Vec<Diagnostic>),
+    // A bug in r9cc.
+    Internal(Box<Ice>),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::UnknownTarget(name) => write!(f, "unknown target: {}", name),
+            CompileError::Unsupported(feature, target) => {
+                write!(f, "{} is not supported for {}", feature, target)
+            }
+            CompileError::Invalid(diags) => {
+                let text: String = diags.iter().map(|d| d.render(false)).collect();
+                write!(f, "{}", text.trim_end())
+            }
+            CompileError::Internal(ice) => write!(f, "{}", ice),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// A compiled translation unit.
+#[derive(Debug, Clone)]
+pub struct Unit {
+    pub asm: String,
+    // Global symbols it defines, so that a driver linking several
+    // units together can tell which one provides what.
+    pub symbols: Vec<String>,
+    pub stats: opt::Stats,
+    pub warnings: Vec<Diagnostic>,
+    // The files it #includes, directly or not, as the preprocessor
+    // found them. A dependency file (-MD) lists them.
+    pub includes: Vec<String>,
+    // How long each phase took, if Options::time_report is set.
+    pub phases: Vec<timer::Phase>,
+    // Sizes of what it was made into, if Options::memory_stats is set.
+    pub memory: Option<MemoryStats>,
+    // The stack each function uses, if Options::stack_usage is set.
+    // LLVM IR has none: llc lays out its frames.
+    pub stack_usage: Vec<StackUsage>,
+    // Whether it came from Options::cache_dir, uncompiled.
+    pub cached: bool,
+}
+
+// Compiles the C program `source` into assembly, or a module for
+// targets such as wasm32 that need no assembling. It's what the r9cc
+// binary does, minus reading files and running the assembler and
+// linker.
+pub fn compile(source: &str, opts: &Options) -> Result<String, CompileError> {
+    compile_unit(source, opts).map(|unit| unit.asm)
+}
+
+// Like compile, but also returns the symbols the program defines.
+pub fn compile_unit(source: &str, opts: &Options) -> Result<Unit, CompileError> {
+    let unit = catch_errors(opts, || compile_impl(source, opts))?;
+    // -Werror makes warnings errors, which fail the compilation.
+    if unit.warnings.iter().any(|d| d.severity == Severity::Error) {
+        return Err(CompileError::Invalid(unit.warnings));
+    }
+    Ok(unit)
+}
+
+// Compiles several C files, as (name, source), into one unit, as if
+// they were one program with --whole-program: see whole_program.rs.
+pub fn compile_program(files: &[(String, String)], opts: &Options) -> Result<Unit, CompileError> {
+    let unit = catch_errors(opts, || compile_program_impl(files, opts))?;
+    if unit.warnings.iter().any(|d| d.severity == Severity::Error) {
+        return Err(CompileError::Invalid(unit.warnings));
+    }
+    Ok(unit)
+}
+
+// Runs only the preprocessor, returning the program as C source
+// without directives or macros, as `r9cc -E` prints it.
+pub fn preprocess(source: &str, opts: &Options) -> Result<String, CompileError> {
+    catch_errors(opts, || {
+        let target = target::lookup(&opts.target)
+            .ok_or_else(|| CompileError::UnknownTarget(opts.target.clone()))?;
+        ice::set_phase("preprocess");
+        let mut pp = preprocessor(&*target, opts);
+        let tokens = preprocess_str(opts.filename.clone(), source, &mut pp);
+        Ok(preprocess::to_source(&tokens))
+    })
+}
+
+// Parses the C program `source` and prints it back as C, as
+// `r9cc --dump-ast` does. See pretty.rs.
+pub fn dump_ast(source: &str, opts: &Options) -> Result<String, CompileError> {
+    frontend::analyze(source, opts, Phase::Parse, |a| pretty::print(&a.ast))
+}
+
+// Parses and checks the C program `source` and draws its functions'
+// trees as Graphviz graphs, as `r9cc --dump-ast=dot` does. See dot.rs.
+pub fn dump_ast_dot(source: &str, opts: &Options) -> Result<String, CompileError> {
+    frontend::analyze(source, opts, Phase::Sema, |a| dot::render(&a.checked))
+}
+
+// Formats the C program `source`, as `r9cc fmt` does. See formatter.rs.
+pub fn format(source: &str, opts: &Options) -> Result<String, CompileError> {
+    catch_errors(opts, || formatter::format(source, opts))
+}
+
+// Checks the C program `source` without compiling it, as
+// `r9cc -fsyntax-only` does: it is preprocessed, parsed and type
+// checked, and nothing more. Returns the warnings.
+pub fn check(source: &str, opts: &Options) -> Result<Vec<Diagnostic>, CompileError> {
+    frontend::analyze(source, opts, Phase::Sema, |a| a.warnings.clone())
+}
+
+/// Compiles the C program `source` and runs it in memory with `args` as
+/// its argv, as `r9cc --run` does, returning what main returns. The
+/// program can only call the libc functions jit.rs knows, and runs in
+/// this process: if it calls exit(), so does the caller.
+///
+/// # Safety
+///
+/// The program runs as part of the caller, with nothing between them.
+/// It must not write out of bounds, free what it didn't allocate or
+/// otherwise have undefined behavior, which would be the caller's too.
+/// To run a program that isn't trusted to, compile it with `compile`
+/// and run it in a process of its own.
+pub unsafe fn execute(source: &str, opts: &Options, args: &[String]) -> Result<i32, CompileError> {
+    let target = target::lookup(&opts.target)
+        .ok_or_else(|| CompileError::UnknownTarget(opts.target.clone()))?;
+    if !jit::supports(&*target) {
+        return Err(CompileError::Unsupported("--run".into(), target.name()));
+    }
+    let opts = Options {
+        emit_llvm: false,
+        ..opts.clone()
+    };
+    let asm = compile(source, &opts)?;
+    let obj = catch_errors(&opts, || {
+        ice::set_phase("assemble");
+        Ok(assemble::assemble(&asm))
+    })?;
+    jit::run(&obj, args)
+        .map_err(|e| CompileError::Invalid(vec![Diagnostic::new(Severity::Error, e, None)]))
+}
+
+// The frontend recurses once per level of nesting in the program,
+// which parse.rs limits to MAX_DEPTH levels. A debug build needs several
+// megabytes of stack for that, more than threads other than main get by
+// default, so compilations run on a thread of their own with this much,
+// or more if -fbracket-depth allows deeper nesting.
+const STACK_SIZE: usize = 64 << 20;
+
+// Sema and the phases after it report errors by panicking. Other
+// panics are bugs. The panic hook prints both unless it's ice::hook.
+fn catch_errors<T: Send>(
+    opts: &Options,
+    f: impl FnOnce() -> Result<T, CompileError> + Send,
+) -> Result<T, CompileError> {
+    thread::scope(|s| {
+        let run = move || {
+            diagnostic::reset(opts.max_errors);
+            ice::set_context(ice::Context::default());
+            let payload = match panic::catch_unwind(AssertUnwindSafe(f)) {
+                Ok(result) => return result,
+                Err(payload) => payload,
+            };
+            let payload = match payload.downcast::<Diagnostic>() {
+                Ok(d) => {
+                    let mut diags = opts.warnings.apply(diagnostic::take());
+                    diags.push(*d);
+                    return Err(CompileError::Invalid(diags));
+                }
+                Err(payload) => payload,
+            };
+            if payload.is::<diagnostic::Stop>() {
+                return Err(CompileError::Invalid(
+                    opts.warnings.apply(diagnostic::take()),
+                ));
+            }
+            match payload.downcast::<Ice>() {
+                Ok(ice) => Err(CompileError::Internal(ice)),
+                Err(payload) => Err(CompileError::Internal(Box::new(Ice::from_panic(payload)))),
+            }
+        };
+        thread::Builder::new()
+            .stack_size(STACK_SIZE.max(STACK_SIZE / parse::MAX_DEPTH * opts.max_depth))
+            .spawn_scoped(s, run)
+            .expect("cannot create a thread")
+            .join()
+            .unwrap_or_else(|e| panic::resume_unwind(e))
+    })
+}
+
+// The errors parse() returned, with the warnings before them, as
+// compile() returns them. Only the first -fmax-errors are kept.
+fn parse_failed(errors: Vec<ParseError>, opts: &Options) -> CompileError {
+    for e in errors {
+        if !diagnostic::report(e.diagnostic()) {
+            break;
+        }
+    }
+    CompileError::Invalid(opts.warnings.apply(diagnostic::take()))
+}
+
+// The preprocessor for `target`, with the macros and include
+// directories given in `opts`.
+fn preprocessor(target: &dyn target::Target, opts: &Options) -> Preprocessor {
+    let mut pp = Preprocessor::for_target(target);
+    pp.include_dirs = opts.include_dirs.clone();
+    pp.bundled_headers = !opts.nostdinc;
+    pp.set_std(opts.std);
+    pp.trigraphs = opts.trigraphs;
+    for (name, body) in &opts.defines {
+        pp.define_macro(name, body);
+    }
+    pp
+}
+
+fn compile_impl(source: &str, opts: &Options) -> Result<Unit, CompileError> {
+    let target = target::lookup(&opts.target)
+        .ok_or_else(|| CompileError::UnknownTarget(opts.target.clone()))?;
+    let mut timer = Timer::new(opts.time_report);
+
+    let mut pp = preprocessor(&*target, opts);
+    let tokens = tokenize_timed(opts.filename.clone(), source, &mut pp, &mut timer);
+    let cache = match &opts.cache_dir {
+        Some(dir) if cache::usable(opts) => Some((dir, cache::key(&tokens, opts))),
+        _ => None,
+    };
+    // The cache only saves time, so it failing to be read or written
+    // isn't an error.
+    if let Some((dir, key)) = &cache {
+        let unit = cache::load(dir, key, opts);
+        let _ = cache::Stats::count(dir, unit.is_some());
+        if let Some(unit) = unit {
+            return Ok(Unit {
+                includes: pp.includes,
+                warnings: opts.warnings.apply(diagnostic::take()),
+                ..unit
+            });
+        }
+    }
+    let nodes = timer
+        .phase("parse", || {
+            parse(&tokens, &*target, opts.max_depth, opts.std)
+        })
+        .map_err(|errors| parse_failed(errors, opts))?;
+    let unit = compile_nodes(nodes, pp.includes, &*target, opts, timer)?;
+    if let Some((dir, key)) = &cache {
+        let _ = cache::store(dir, key, &unit);
+    }
+    Ok(unit)
+}
+
+fn compile_program_impl(files: &[(String, String)], opts: &Options) -> Result<Unit, CompileError> {
+    let target = target::lookup(&opts.target)
+        .ok_or_else(|| CompileError::UnknownTarget(opts.target.clone()))?;
+    let mut timer = Timer::new(opts.time_report);
+
+    // Each file is preprocessed and parsed as it would be on its own.
+    let mut parsed = vec![];
+    let mut includes = vec![];
+    for (filename, source) in files {
+        let mut pp = preprocessor(&*target, opts);
+        let tokens = tokenize_timed(filename.clone(), source, &mut pp, &mut timer);
+        let nodes = timer
+            .phase("parse", || {
+                parse(&tokens, &*target, opts.max_depth, opts.std)
+            })
+            .map_err(|errors| parse_failed(errors, opts))?;
+        parsed.push((filename.clone(), nodes));
+        includes.append(&mut pp.includes);
+    }
+    let nodes = timer.phase("merge", || whole_program::merge(parsed));
+    let opts = Options {
+        whole_program: true,
+        ..opts.clone()
+    };
+    compile_nodes(nodes, includes, &*target, &opts, timer)
+}
+
+// The rest of compile_impl, from the syntax tree on, for callers such
+// as the REPL that build the tree themselves. `includes` are the files
+// the preprocessor read for it.
+fn compile_nodes(
+    nodes: Vec<Node>,
+    includes: Vec<String>,
+    target: &dyn Target,
+    opts: &Options,
+    mut timer: Timer,
+) -> Result<Unit, CompileError> {
+    let unsupported = |feature: &str| CompileError::Unsupported(feature.into(), target.name());
+    let mut memory = opts.memory_stats.then(|| MemoryStats {
+        nodes: nodes.iter().map(Node::count).sum(),
+        ..Default::default()
+    });
+    if opts.sanitize.address && !target.address_sanitizer() {
+        return Err(unsupported("-fsanitize=address"));
+    }
+
+    let (mut nodes, mut globals) = timer.phase("sema", || sema(nodes, opts.sanitize.address));
+    if opts.whole_program {
+        whole_program::internalize(&mut nodes, &mut globals);
+    }
+    let ipcp = (opts.opt_level >= 2)
+        .then(|| timer.phase("ipcp", || ipcp::propagate(&mut nodes, &globals)));
+    // After ipcp, so that what is inlined has its constants already.
+    // Static functions are inlined at -O2, and all of them with
+    // -fwhole-program, which makes them static.
+    let profile = opts
+        .profile_use
+        .as_ref()
+        .map(|path| Profile::read(path).unwrap_or_else(|e| diagnostic::error(e, None)));
+    let inline = (opts.opt_level >= 2 || (opts.whole_program && opts.opt_level >= 1)).then(|| {
+        timer.phase("inline", || {
+            whole_program::inline(&mut nodes, profile.as_ref())
+        })
+    });
+    let removed = (opts.whole_program && opts.opt_level >= 1).then(|| {
+        timer.phase("globaldce", || {
+            whole_program::remove_dead(&mut nodes, &mut globals)
+        })
+    });
+    // After inlining, so that loops get what was inlined into them out
+    // too. The moved code runs even when the loop runs no iterations,
+    // where -fsanitize=undefined could report an overflow that never
+    // happens.
+    let licm = (opts.opt_level >= 2 && !opts.sanitize.undefined())
+        .then(|| timer.phase("licm", || licm::hoist(&mut nodes)));
+    // After licm, whose new locals it may reuse, and which wouldn't
+    // move what it stores into a local.
+    let gvn = (opts.opt_level >= 2).then(|| timer.phase("gvn", || gvn::number(&mut nodes)));
+    let mut ctx = CodegenContext::new();
+    ctx.verbose_asm = opts.verbose_asm;
+    ctx.profile = opts.profile;
+    ctx.sanitize = opts.sanitize;
+    ctx.jobs = opts.jobs;
+    ctx.layout = opts.opt_level >= 2;
+    ctx.debug_info = opts.debug_info;
+    if opts.coverage {
+        ctx.coverage = Some(opts.filename.clone());
+    }
+    ctx.profile_use = profile;
+    let mut fns = timer.phase("irgen", || gen_ir(&mut ctx, nodes, opts.stack_protector));
+    globals.append(&mut ctx.globals);
+    let mut stats = opt::optimize(&mut fns, opts.opt_level, opts.jobs, &mut timer);
+    if let Some(n) = gvn {
+        stats.passes.insert(0, ("gvn", n));
+    }
+    if let Some(n) = licm {
+        stats.passes.insert(0, ("licm", n));
+    }
+    if let Some(n) = removed {
+        stats.passes.insert(0, ("globaldce", n));
+    }
+    if let Some(n) = inline {
+        stats.passes.insert(0, ("inline", n));
+    }
+    if let Some(n) = ipcp {
+        stats.passes.insert(0, ("ipcp", n));
+    }
+
+    if opts.dump_ir1 {
+        dump_ir(&fns);
+    }
+
+    timer.phase("regalloc", || alloc_regs(&mut fns, &*target, opts.jobs));
+    if let Some(memory) = &mut memory {
+        memory.functions = fns
+            .iter()
+            .map(|f| FunctionSize {
+                name: f.name.clone(),
+                ir: f.ir.len(),
+                asm: 0,
+            })
+            .collect();
+    }
+
+    if opts.dump_ir2 {
+        dump_ir(&fns);
+    }
+
+    // String literals and other compiler-generated data are local, as
+    // is what is static.
+    let mut symbols: Vec<String> = fns
+        .iter()
+        .filter(|f| !f.is_static)
+        .map(|f| f.name.clone())
+        .collect();
+    for var in &globals {
+        if let Scope::Global(_, _, false, _, false) = var.scope {
+            if !var.name.starts_with(".L") {
+                symbols.push(var.name.clone());
+            }
+        }
+    }
+
+    // Only the x86-64 backend has floating point, and gen_llvm passes
+    // every argument as an i64.
+    if fns.iter().any(Function::uses_float) {
+        if opts.emit_llvm {
+            return Err(CompileError::Unsupported(
+                "floating point".into(),
+                "--emit=llvm-ir",
+            ));
+        }
+        if !target.floating_point() {
+            return Err(unsupported("floating point"));
+        }
+    }
+
+    // LLVM IR is a complete, textual output like a WebAssembly module;
+    // feed it to llc or clang to get machine code.
+    if opts.emit_llvm {
+        let asm = timer.phase("codegen", || gen_llvm(&mut ctx, globals, fns, &*target));
+        return Ok(Unit {
+            asm,
+            symbols,
+            stats,
+            warnings: opts.warnings.apply(diagnostic::take()),
+            includes,
+            phases: timer.phases,
+            memory: finish_memory_stats(memory, &ctx),
+            stack_usage: vec![],
+            cached: false,
+        });
+    }
+
+    if !target.thread_local()
+        && globals
+            .iter()
+            .any(|var| matches!(var.scope, Scope::Global(_, _, _, true, _)))
+    {
+        return Err(unsupported("_Thread_local"));
+    }
+    if opts.profile && !target.profile() {
+        return Err(unsupported("-pg"));
+    }
+    if opts.stack_protector && !target.stack_protector() {
+        return Err(unsupported("-fstack-protector"));
+    }
+    if opts.debug_info && !target.debug_info() {
+        return Err(unsupported("-g"));
+    }
+
+    let mut stack_usage: Vec<StackUsage> = fns
+        .iter()
+        .filter(|_| opts.stack_usage)
+        .map(|f| StackUsage {
+            name: f.name.clone(),
+            span: f.span.clone(),
+            bytes: 0,
+            dynamic: f.ir.iter().any(|ir| ir.op == IROp::Alloca),
+        })
+        .collect();
+    let asm = timer.phase("codegen", || target.gen(&mut ctx, globals, fns));
+    for (f, &size) in stack_usage.iter_mut().zip(&ctx.frame_sizes) {
+        f.bytes = size;
+    }
+    Ok(Unit {
+        asm,
+        symbols,
+        stats,
+        warnings: opts.warnings.apply(diagnostic::take()),
+        includes,
+        phases: timer.phases,
+        memory: finish_memory_stats(memory, &ctx),
+        stack_usage,
+        cached: false,
+    })
+}
+
+// Fills in what is only known once the backend has run. The tables of
+// symbols and types are the compilation thread's, so this must run on
+// it too.
+fn finish_memory_stats(memory: Option<MemoryStats>, ctx: &CodegenContext) -> Option<MemoryStats> {
+    let mut memory = memory?;
+    memory.symbols = symbol::count();
+    memory.types = Type::interned();
+    for (f, &size) in memory.functions.iter_mut().zip(&ctx.asm_sizes) {
+        f.asm = size;
     }
+    Some(memory)
 }
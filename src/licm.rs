@@ -0,0 +1,212 @@
+// Loop-invariant code motion (-O2).
+//
+// It runs on the whole program after sema, where a loop and the locals
+// it writes are in plain sight:
+//
+//   for (int i = 0; i < n; i++)
+//       s += a * b;
+//
+// Nothing in the loop writes a or b, so a * b is the same on every
+// iteration. It is computed once, into a new local, before the loop,
+// which reads that local instead. The loop may run no iterations, so
+// only what is safe to compute anyway moves: arithmetic and comparisons
+// of constants and scalar locals, but not a load through a pointer, a
+// call, a division, which may trap, or a shift, which may be out of
+// range. And only locals whose address is never taken count, since
+// nothing else can write them.
+
+use crate::parse::{Node, NodeType};
+use crate::util::roundup;
+use crate::{Ctype, Scope, TokenType, Type};
+
+use std::collections::HashSet;
+use std::mem;
+
+pub fn is_assign(op: &TokenType) -> bool {
+    use crate::TokenType::*;
+    matches!(
+        op,
+        Equal | MulEQ | DivEQ | ModEQ | AddEQ | SubEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ
+    )
+}
+
+pub fn is_scalar(ty: &Type) -> bool {
+    matches!(
+        ty.ty,
+        Ctype::Int | Ctype::Char | Ctype::Long | Ctype::Ptr(_)
+    ) && !ty.is_atomic
+}
+
+// Adds the locals of a function that may change behind its code's back
+// to `out`: those whose address is taken, and those a builtin such as
+// va_start gets to write. Returns false if the function has inline
+// assembly, which may change any of them.
+pub fn pinned(node: &Node, under: bool, out: &mut HashSet<usize>) -> bool {
+    match node.op {
+        NodeType::Asm(_) => return false,
+        NodeType::Lvar(Scope::Local(offset)) if under => {
+            out.insert(offset);
+        }
+        _ => (),
+    }
+    let under = under || matches!(node.op, NodeType::Addr(_) | NodeType::Builtin(..));
+    node.children()
+        .into_iter()
+        .all(|child| pinned(child, under, out))
+}
+
+// Adds the locals that `node` writes to `out`.
+pub fn writes(node: &Node, written: bool, out: &mut HashSet<usize>) {
+    match node.op {
+        NodeType::Lvar(Scope::Local(offset)) if written => {
+            out.insert(offset);
+        }
+        NodeType::Vardef(_, _, Scope::Local(offset)) => {
+            out.insert(offset);
+        }
+        _ => (),
+    }
+    match node.op {
+        NodeType::BinOp(ref op, ref lhs, ref rhs) => {
+            writes(lhs, is_assign(op), out);
+            writes(rhs, false, out);
+        }
+        NodeType::PostInc(ref expr) | NodeType::PostDec(ref expr) => writes(expr, true, out),
+        NodeType::Dot(ref expr, ..) => writes(expr, written, out),
+        _ => {
+            for child in node.children() {
+                writes(child, false, out);
+            }
+        }
+    }
+}
+
+// Whether a case or default label in `node` belongs to a switch around
+// it, which may then jump into the middle of a loop in it.
+fn has_label(node: &Node) -> bool {
+    match node.op {
+        NodeType::Case(..) | NodeType::Default(_) => true,
+        NodeType::Switch(ref cond, ..) => has_label(cond),
+        _ => node.children().into_iter().any(has_label),
+    }
+}
+
+// Whether `node` has the same value whenever the loop computes it, with
+// `changed` the locals the loop may write.
+fn invariant(node: &Node, changed: &HashSet<usize>) -> bool {
+    use crate::TokenType::*;
+    match node.op {
+        NodeType::Num(_) => true,
+        NodeType::Lvar(Scope::Local(offset)) => is_scalar(&node.ty) && !changed.contains(&offset),
+        NodeType::BinOp(ref op, ref lhs, ref rhs) => {
+            matches!(
+                op,
+                Plus | Minus
+                    | Mul
+                    | And
+                    | VerticalBar
+                    | Hat
+                    | EQ
+                    | NE
+                    | LE
+                    | GE
+                    | LeftAngleBracket
+                    | RightAngleBracket
+                    | Logand
+                    | Logor
+            ) && invariant(lhs, changed)
+                && invariant(rhs, changed)
+        }
+        NodeType::Neg(ref expr)
+        | NodeType::Tilde(ref expr)
+        | NodeType::Exclamation(ref expr)
+        | NodeType::Cast(ref expr) => is_scalar(&node.ty) && invariant(expr, changed),
+        _ => false,
+    }
+}
+
+// Whether moving `node` saves the loop any work: it must compute
+// something, and from a local, as the IR optimizer folds constants.
+pub fn worth_moving(node: &Node) -> bool {
+    fn reads_local(node: &Node) -> bool {
+        matches!(node.op, NodeType::Lvar(_)) || node.children().into_iter().any(reads_local)
+    }
+    match node.op {
+        NodeType::Num(_) | NodeType::Lvar(_) => false,
+        NodeType::Cast(ref expr) => worth_moving(expr),
+        _ => reads_local(node),
+    }
+}
+
+// Replaces the largest invariant expressions in `node` with new locals,
+// adding the statements that assign them to `hoisted`.
+fn replace(
+    node: &mut Node,
+    changed: &HashSet<usize>,
+    stacksize: &mut usize,
+    hoisted: &mut Vec<Node>,
+) {
+    if is_scalar(&node.ty) && invariant(node, changed) && worth_moving(node) {
+        let ty = node.ty.clone();
+        *stacksize = roundup(*stacksize, ty.align) + ty.size;
+        let mut var = Node::new(NodeType::Lvar(Scope::Local(*stacksize)));
+        var.ty = ty.clone();
+        let expr = mem::replace(node, var.clone());
+        let mut assign = Node::new_binop(TokenType::Equal, var, expr);
+        assign.ty = ty;
+        hoisted.push(Node::new(NodeType::ExprStmt(Box::new(assign))));
+        return;
+    }
+    for child in node.children_mut() {
+        replace(child, changed, stacksize, hoisted);
+    }
+}
+
+// Moves what is invariant out of the loops in `node`, inner ones first,
+// so that what they moved may move on out of the loops around them.
+fn hoist_loops(node: &mut Node, pinned: &HashSet<usize>, stacksize: &mut usize, n: &mut usize) {
+    for child in node.children_mut() {
+        hoist_loops(child, pinned, stacksize, n);
+    }
+    if !matches!(node.op, NodeType::For(..) | NodeType::DoWhile(..)) || has_label(node) {
+        return;
+    }
+    let mut changed = pinned.clone();
+    let parts = match node.op {
+        // Hoisted code runs before init, so what init writes changes too.
+        NodeType::For(ref mut init, ref mut cond, ref mut inc, ref mut body) => {
+            writes(init, false, &mut changed);
+            vec![cond, inc, body]
+        }
+        NodeType::DoWhile(ref mut body, ref mut cond) => vec![body, cond],
+        _ => unreachable!(),
+    };
+    for part in &parts {
+        writes(part, false, &mut changed);
+    }
+    let mut hoisted = vec![];
+    for part in parts {
+        replace(part, &changed, stacksize, &mut hoisted);
+    }
+    if hoisted.is_empty() {
+        return;
+    }
+    *n += hoisted.len();
+    hoisted.push(mem::replace(node, Node::new(NodeType::Null)));
+    *node = Node::new(NodeType::CompStmt(hoisted));
+}
+
+// Returns how many expressions moved out of loops.
+pub fn hoist(nodes: &mut [Node]) -> usize {
+    let mut n = 0;
+    for node in nodes.iter_mut() {
+        if let NodeType::Func(_, ref args, ref mut body, ref mut stacksize, _) = node.op {
+            let mut locals = HashSet::new();
+            let known = args.iter().all(|arg| pinned(arg, false, &mut locals));
+            if known && pinned(body, false, &mut locals) {
+                hoist_loops(body, &locals, stacksize, &mut n);
+            }
+        }
+    }
+    n
+}
@@ -1,61 +1,1008 @@
 extern crate r9cc;
 
-use r9cc::gen_ir::gen_ir;
-use r9cc::gen_x86::gen_x86;
-use r9cc::irdump::dump_ir;
-use r9cc::parse::parse;
-use r9cc::preprocess::Preprocessor;
-use r9cc::regalloc::alloc_regs;
-use r9cc::sema::sema;
-use r9cc::token::tokenize;
+use r9cc::internals::cache;
+use r9cc::internals::conformance;
+use r9cc::internals::coverage;
+use r9cc::internals::diagnostic::{Diagnostic, Severity, WARNINGS};
+use r9cc::internals::differential::{self, Program};
+use r9cc::internals::driver::build_executable;
+use r9cc::internals::elf::write_elf;
+use r9cc::internals::ice::{self, Ice};
+use r9cc::internals::jit;
+use r9cc::internals::opt;
+use r9cc::internals::repl::Session;
+use r9cc::internals::stats;
+use r9cc::internals::target::{self, TARGETS};
+use r9cc::internals::timer::{self, CountingAllocator};
+use r9cc::{
+    check, compile_program, compile_unit, dump_ast, dump_ast_dot, preprocess, CompileError,
+    Options, Std, Unit,
+};
 
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::process;
+use std::thread;
+use std::vec;
+
+// Counts heap use for -ftime-report.
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Where to stop, as with gcc. The earliest stage asked for wins.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Stage {
+    Preprocess, // -E
+    Parse,      // --dump-ast
+    Check,      // -fsyntax-only
+    Assembly,   // -S
+    Object,     // -c
+    Executable,
+}
 
 fn usage() -> ! {
-    eprintln!("Usage: 9cc [-dump-ir1] [-dump-ir2] <file>");
-    process::exit(1)
+    eprintln!("Usage: 9cc [-dump-ir1] [-dump-ir2] [-E|-S|-c|--dump-ast[=dot]|-fsyntax-only] [-o <output>] [-I<dir>] [-nostdinc] [-D<name>[=<value>]] [-g] [-MD|-MMD] [-MF <file>] [-O<level>] [--stats[=memory]] [--target <triple>] [--emit=<kind>] [-fstack-protector] [-fstack-usage] [-fverbose-asm] [-pg] [-fsanitize=<checks>] [--coverage] [-fprofile-use[=<file>]] [-fdiagnostics-color[=<when>]] [--diagnostics-format=text|json] [-W<warning>] [-Werror[=<warning>]] [-w] [-fmax-errors=<n>] [-ftime-report] [-fbracket-depth=<n>] [-j<threads>] [-std=c89|c99|c11] [-trigraphs] [--whole-program] [--cache-dir <dir>] <file>...");
+    eprintln!("       9cc --run [-O<level>] [-I<dir>] [-D<name>[=<value>]] <file> [<arg>...]");
+    eprintln!("       9cc repl [-O<level>] [-I<dir>] [-D<name>[=<value>]]");
+    eprintln!("       9cc fmt [--check|-i] [-I<dir>] [-D<name>[=<value>]] [-o <output>] <file>...");
+    eprintln!("       9cc --coverage-report <r9cc.cov>");
+    eprintln!("       9cc --cache-stats --cache-dir <dir>");
+    eprintln!("       9cc --conformance <suite-dir> [--xfail <file>] [--write-xfail <file>] [-O<level>] [-j<threads>]");
+    eprintln!("       9cc --differential <compiler> [--generate <n>] [--seed <n>] [-O<level>] [-j<threads>] [<file>...]");
+    eprintln!(
+        "Targets: {}, or triples such as x86_64-linux-gnu (default: {})",
+        TARGETS.join(", "),
+        target::host()
+    );
+    eprintln!("Emit kinds: asm (default), llvm-ir");
+    let warnings: Vec<&str> = WARNINGS.iter().map(|(name, _)| *name).collect();
+    eprintln!("Warnings: {}", warnings.join(", "));
+    eprintln!(
+        "Sanitizers: address, undefined, signed-integer-overflow, integer-divide-by-zero, shift"
+    );
+    process::exit(EXIT_USAGE)
+}
+
+// Exit statuses. Scripts can tell a bad command line from a program
+// that doesn't compile (or files that can't be read or written), and
+// both from a bug in r9cc, which exits like gcc does after an internal
+// compiler error.
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_ICE: i32 = 4;
+
+fn error(msg: impl fmt::Display) -> ! {
+    eprintln!("r9cc: error: {}", msg);
+    process::exit(EXIT_ERROR)
+}
+
+fn usage_error(msg: impl fmt::Display) -> ! {
+    eprintln!("r9cc: error: {}", msg);
+    process::exit(EXIT_USAGE)
+}
+
+// The command line, parsed.
+struct Cli {
+    opts: Options, // Including -o
+    stage: Stage,
+    dot: bool, // --dump-ast=dot
+    paths: Vec<String>,
+    stats: bool,               // --stats
+    color: Option<bool>,       // -fdiagnostics-color, or None to decide by stderr
+    json: bool,                // --diagnostics-format=json
+    deps: bool,                // -MD or -MMD
+    deps_file: Option<String>, // -MF
+    coverage_report: Option<String>,
+    conformance: Option<String>, // The test suite's directory
+    xfail: Option<String>,
+    write_xfail: Option<String>,
+    differential: Option<String>, // The reference compiler
+    generate: u64,                // Random programs to compare
+    seed: u64,                    // Of the first one
+    run: bool,                    // --run
+    run_args: Vec<String>,        // What follows the file, for its argv
+    check_format: bool,           // fmt --check
+    in_place: bool,               // fmt -i
+    cache_stats: bool,            // --cache-stats
+}
+
+fn parse_args(mut args: Args) -> Cli {
+    let mut cli = Cli {
+        opts: Options::default().jobs(thread::available_parallelism().map_or(1, |n| n.get())),
+        stage: Stage::Executable,
+        dot: false,
+        paths: vec![],
+        stats: false,
+        color: None,
+        json: false,
+        deps: false,
+        deps_file: None,
+        coverage_report: None,
+        conformance: None,
+        xfail: None,
+        write_xfail: None,
+        differential: None,
+        generate: 0,
+        seed: 1,
+        run: false,
+        run_args: vec![],
+        check_format: false,
+        in_place: false,
+        cache_stats: false,
+    };
+    let opts = &mut cli.opts;
+
+    while let Some(arg) = args.next() {
+        // The value of an option such as -I, either attached to it or
+        // the next argument.
+        let mut value = |prefix: &str| match &arg[prefix.len()..] {
+            "" => args.next().unwrap_or_else(|| usage()),
+            val => val.to_string(),
+        };
+        match &*arg {
+            "-dump-ir1" => opts.dump_ir1 = true,
+            "-dump-ir2" => opts.dump_ir2 = true,
+            "-E" => cli.stage = cli.stage.min(Stage::Preprocess),
+            "-S" => cli.stage = cli.stage.min(Stage::Assembly),
+            "-c" => cli.stage = cli.stage.min(Stage::Object),
+            "--dump-ast" => cli.stage = cli.stage.min(Stage::Parse),
+            "--dump-ast=dot" => {
+                cli.stage = cli.stage.min(Stage::Parse);
+                cli.dot = true;
+            }
+            "-fsyntax-only" => cli.stage = cli.stage.min(Stage::Check),
+            "--run" => cli.run = true,
+            "--check" => cli.check_format = true,
+            "-i" => cli.in_place = true,
+            "-o" => opts.output = Some(value("-o")),
+            "--target" => set_target(opts, value("--target")),
+            "--emit=asm" => opts.emit_llvm = false,
+            "--emit=llvm-ir" => opts.emit_llvm = true,
+            "-g" => opts.debug_info = true,
+            "-g0" => opts.debug_info = false,
+            // The headers r9cc comes with aren't files, so -MD leaves
+            // them out as -MMD leaves out system headers.
+            "-MD" | "-MMD" => cli.deps = true,
+            // The bundled headers are for freestanding programs, but
+            // one that says so brings its own.
+            "-nostdinc" | "-ffreestanding" => opts.nostdinc = true,
+            "-fstack-protector" => opts.stack_protector = true,
+            "-fno-stack-protector" => opts.stack_protector = false,
+            "-fstack-usage" => opts.stack_usage = true,
+            "--whole-program" => opts.whole_program = true,
+            "-trigraphs" => opts.trigraphs = true,
+            "-fverbose-asm" => opts.verbose_asm = true,
+            "-fno-verbose-asm" => opts.verbose_asm = false,
+            "-pg" => opts.profile = true,
+            "-O0" => opts.opt_level = 0,
+            "-O" | "-O1" => opts.opt_level = 1,
+            "-O2" => opts.opt_level = 2,
+            "--stats" => cli.stats = true,
+            "--stats=memory" => opts.memory_stats = true,
+            "--cache-dir" => opts.cache_dir = Some(value("--cache-dir")),
+            "--cache-stats" => cli.cache_stats = true,
+            "-ftime-report" => opts.time_report = true,
+            "--coverage" => opts.coverage = true,
+            "-fprofile-use" => opts.profile_use = Some("r9cc.cov".into()),
+            "-fdiagnostics-color" | "-fdiagnostics-color=always" => cli.color = Some(true),
+            "-fno-diagnostics-color" | "-fdiagnostics-color=never" => cli.color = Some(false),
+            "-fdiagnostics-color=auto" => cli.color = None,
+            "--diagnostics-format=text" => cli.json = false,
+            "--diagnostics-format=json" => cli.json = true,
+            "--coverage-report" => cli.coverage_report = Some(value("--coverage-report")),
+            "--conformance" => cli.conformance = Some(value("--conformance")),
+            "--xfail" => cli.xfail = Some(value("--xfail")),
+            "--write-xfail" => cli.write_xfail = Some(value("--write-xfail")),
+            "--differential" => cli.differential = Some(value("--differential")),
+            "--generate" | "--seed" => {
+                let n = value(&arg)
+                    .parse()
+                    .unwrap_or_else(|_| usage_error(format!("bad number for {}", arg)));
+                if arg == "--generate" {
+                    cli.generate = n;
+                } else {
+                    cli.seed = n;
+                }
+            }
+            _ if arg.starts_with("--cache-dir=") => opts.cache_dir = Some(value("--cache-dir=")),
+            _ if arg.starts_with("--target=") => set_target(opts, value("--target=")),
+            _ if arg.starts_with("-std=") => match Std::lookup(&arg["-std=".len()..]) {
+                Some(std) => opts.std = std,
+                None => usage_error(format!("unknown standard: {}", arg)),
+            },
+            _ if arg.starts_with("-MF") => cli.deps_file = Some(value("-MF")),
+            _ if arg.starts_with("-I") => opts.include_dirs.push(value("-I")),
+            _ if arg.starts_with("-D") => {
+                let def = value("-D");
+                let (name, body) = def.split_once('=').unwrap_or((&def, "1"));
+                opts.defines.push((name.into(), body.into()));
+            }
+            _ if arg.starts_with("-fsanitize=") || arg.starts_with("-fno-sanitize=") => {
+                let (list, on) = match arg.strip_prefix("-fsanitize=") {
+                    Some(list) => (list, true),
+                    None => (&arg["-fno-sanitize=".len()..], false),
+                };
+                if let Err(e) = opts.sanitize.set(list, on) {
+                    usage_error(e);
+                }
+            }
+            _ if arg.starts_with("-fprofile-use=") => {
+                opts.profile_use = Some(value("-fprofile-use="))
+            }
+            _ if arg.starts_with("-fmax-errors=") => match value("-fmax-errors=").parse() {
+                Ok(n) => opts.max_errors = n,
+                _ => usage_error(format!("bad number of errors: {}", arg)),
+            },
+            _ if arg.starts_with("-fbracket-depth=") => match value("-fbracket-depth=").parse() {
+                Ok(n) if n > 0 => opts.max_depth = n,
+                _ => usage_error(format!("bad nesting depth: {}", arg)),
+            },
+            _ if arg.starts_with("-j") => match arg[2..].parse() {
+                Ok(n) if n > 0 => opts.jobs = n,
+                _ => usage_error(format!("bad number of threads: {}", arg)),
+            },
+            _ if arg.starts_with("-W") || arg == "-w" => {
+                if let Err(e) = opts.warnings.set(&arg) {
+                    usage_error(e);
+                }
+            }
+            _ if !arg.starts_with('-') || arg == "-" => {
+                cli.paths.push(arg);
+                // As with tcc -run, the rest of the command line is the
+                // program's.
+                if cli.run {
+                    args.expand = false;
+                    cli.run_args = args.by_ref().collect();
+                }
+            }
+            _ => usage_error(format!("unknown option: {}", arg)),
+        }
+    }
+    cli
+}
+
+// Splits `s` into arguments, for R9CC_FLAGS and response files. As in
+// GCC's response files, whitespace separates them, except inside
+// single or double quotes, and a backslash takes the next character as
+// it is.
+fn split_args(s: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut arg = None;
+    let mut quote = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let arg = arg.get_or_insert_with(String::new);
+                arg.extend(chars.next());
+            }
+            _ if Some(c) == quote => quote = None,
+            _ if quote.is_some() => arg.get_or_insert_with(String::new).push(c),
+            '\'' | '"' => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            _ if c.is_whitespace() => args.extend(arg.take()),
+            _ => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        usage_error("missing closing quote in arguments");
+    }
+    args.extend(arg);
+    args
+}
+
+// The arguments of r9cc, with each `@file` replaced by the arguments
+// in the file, which may name response files of their own. As with
+// GCC, an `@file` that can't be read stays as it is.
+struct Args {
+    files: Vec<(vec::IntoIter<String>, usize)>, // With how deeply nested
+    expand: bool,                               // Not for the arguments of a program to --run
+}
+
+impl Args {
+    fn new(args: Vec<String>) -> Self {
+        Args {
+            files: vec![(args.into_iter(), 0)],
+            expand: true,
+        }
+    }
+
+    // Reads `args` before the rest, expanding them as well.
+    fn prepend(&mut self, args: Vec<String>) {
+        self.files.push((args.into_iter(), 0));
+    }
+}
+
+impl Iterator for Args {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            let (args, depth) = self.files.last_mut()?;
+            let Some(arg) = args.next() else {
+                self.files.pop();
+                continue;
+            };
+            let depth = *depth;
+            let text = match arg.strip_prefix('@') {
+                Some(path) if self.expand => match fs::read_to_string(path) {
+                    Ok(text) => text,
+                    Err(_) => return Some(arg),
+                },
+                _ => return Some(arg),
+            };
+            if depth == 16 {
+                usage_error(format!("{}: response files nested too deeply", &arg[1..]));
+            }
+            self.files.push((split_args(&text).into_iter(), depth + 1));
+        }
+    }
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let repl = args.first().is_some_and(|arg| arg == "repl");
+    let fmt = args.first().is_some_and(|arg| arg == "fmt");
+    if repl || fmt {
+        args.remove(0);
+    }
+    let mut args = Args::new(args);
+    // Flags from the environment come before those on the command
+    // line, which can override them.
+    let flags = env::var("R9CC_FLAGS").unwrap_or_default();
+    args.prepend(split_args(&flags));
+    let Cli {
+        mut opts,
+        mut stage,
+        dot,
+        paths,
+        stats,
+        color,
+        json,
+        deps,
+        deps_file,
+        coverage_report,
+        conformance,
+        xfail,
+        write_xfail,
+        differential,
+        generate,
+        seed,
+        run,
+        run_args,
+        check_format,
+        in_place,
+        cache_stats,
+    } = parse_args(args);
+
+    if let Some(dump) = coverage_report {
+        let result = fs::read_to_string(&dump)
+            .map_err(|e| format!("cannot read {}: {}", dump, e))
+            .and_then(|data| coverage::report(&data));
+        match result {
+            Ok(report) => print!("{}", report),
+            Err(e) => error(e),
+        }
+        return;
+    }
+    if cache_stats {
+        match &opts.cache_dir {
+            Some(dir) => print!("{}", cache::Stats::read(dir).report(dir)),
+            None => usage_error("--cache-stats needs --cache-dir"),
+        }
+        return;
+    }
+    if let Some(dir) = conformance {
+        run_conformance(&dir, &opts, xfail, write_xfail);
+    }
+    if let Some(reference) = differential {
+        run_differential(&reference, &paths, seed..seed + generate, &opts);
+    }
+    if repl {
+        run_repl(opts, color, json);
+    }
+    if !fmt && (check_format || in_place) {
+        usage_error("--check and -i are only for r9cc fmt");
+    }
+    if fmt {
+        run_fmt(&paths, opts, check_format, in_place, color, json);
+    }
+    if paths.is_empty() {
         usage();
     }
+    // --stats reports on the optimizer, which doesn't run for a unit
+    // taken from the cache.
+    if stats {
+        opts.cache_dir = None;
+    }
+    let target = target::lookup(&opts.target).unwrap();
+    if run && (stage != Stage::Executable || opts.emit_llvm || opts.output.is_some()) {
+        usage_error(
+            "--run cannot be used with -E, -S, -c, --dump-ast, -fsyntax-only, --emit=llvm-ir or -o",
+        );
+    }
+    // Their runtimes are linked into executables, and not into r9cc.
+    if run && (opts.sanitize.undefined() || opts.sanitize.address || opts.coverage || opts.profile)
+    {
+        usage_error("--run cannot be used with -fsanitize, --coverage or -pg");
+    }
+    if opts.emit_llvm && stage == Stage::Object {
+        usage_error("-c cannot be used with --emit=llvm-ir");
+    }
+    if opts.emit_llvm && opts.stack_usage {
+        usage_error("-fstack-usage cannot be used with --emit=llvm-ir");
+    }
+    // Outputs such as WebAssembly modules and LLVM IR are used as is,
+    // so there is nothing to assemble or link.
+    if (opts.emit_llvm || !target.needs_linking()) && stage == Stage::Executable {
+        stage = Stage::Assembly;
+    }
+    let output = opts.output.clone();
+    // --whole-program makes one output of all the files.
+    let outputs = if opts.whole_program && stage >= Stage::Assembly {
+        1
+    } else {
+        paths.len()
+    };
+    if outputs > 1 && output.is_some() && stage != Stage::Executable && stage != Stage::Check {
+        usage_error("cannot specify -o with -E, -S, -c or --dump-ast and multiple files");
+    }
+    if deps && stage <= Stage::Check {
+        usage_error("-MD cannot be used with -E, --dump-ast or -fsyntax-only");
+    }
+    if deps && paths.len() > 1 && deps_file.is_some() {
+        usage_error("cannot specify -MF with multiple files");
+    }
+    // Where -MD writes the dependency file of `path`: -MF, or next to
+    // the output if -o names it, or "foo.d" in the current directory.
+    let dep_file = |path: &str, output: Option<&str>| match (&deps_file, output) {
+        (Some(name), _) => name.clone(),
+        (None, Some(out)) if out != "-" => Path::new(out)
+            .with_extension("d")
+            .to_string_lossy()
+            .into_owned(),
+        _ => output_name(path, "d"),
+    };
+    // Where -fstack-usage writes the report of `path`: next to the
+    // output if -o names it, or "foo.su" in the current directory.
+    let su_file = |path: &str, output: Option<&str>| match output {
+        Some(out) if out != "-" => Path::new(out)
+            .with_extension("su")
+            .to_string_lossy()
+            .into_owned(),
+        _ => output_name(path, "su"),
+    };
+
+    // The library turns the frontend's panics into errors, which we
+    // report ourselves, and other panics into internal compiler errors,
+    // for which the hook notes where they happened. Each file is
+    // compiled on its own, like with gcc, and nothing is written unless
+    // all of them compile.
+    panic::set_hook(Box::new(ice::hook));
+    let color =
+        color.unwrap_or_else(|| io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none());
+    let print = |d: &Diagnostic| {
+        if json {
+            eprintln!("{}", d.to_json());
+        } else {
+            eprint!("{}", d.render(color));
+        }
+    };
+    let mut failed = false;
+    let mut report = |diags: Vec<Diagnostic>| {
+        for d in diags {
+            print(&d);
+            failed |= d.severity == Severity::Error;
+        }
+    };
+
+    if stage <= Stage::Parse {
+        let run = if stage == Stage::Preprocess {
+            preprocess
+        } else if dot {
+            dump_ast_dot
+        } else {
+            dump_ast
+        };
+        let mut out = String::new();
+        for path in &paths {
+            match read_file(path, &mut opts)
+                .map_err(error_diagnostic)
+                .and_then(|source| run(&source, &opts).map_err(|e| diagnostics(e, &print)))
+            {
+                Ok(source) => out.push_str(&source),
+                Err(e) => report(e),
+            }
+        }
+        if failed {
+            process::exit(EXIT_ERROR);
+        }
+        write_output(output.as_deref().unwrap_or("-"), out.as_bytes());
+        return;
+    }
+
+    // Nothing is written, and the exit status tells whether the files
+    // compile.
+    if stage == Stage::Check {
+        for path in &paths {
+            match read_file(path, &mut opts)
+                .map_err(error_diagnostic)
+                .and_then(|source| check(&source, &opts).map_err(|e| diagnostics(e, &print)))
+            {
+                Ok(warnings) => report(warnings),
+                Err(e) => report(e),
+            }
+        }
+        if failed {
+            process::exit(EXIT_ERROR);
+        }
+        return;
+    }
+
+    // With --whole-program, the files are compiled together, as one
+    // named after the first.
+    let groups: Vec<&[String]> = if opts.whole_program {
+        vec![&paths]
+    } else {
+        paths.chunks(1).collect()
+    };
+    let mut units = vec![];
+    for group in groups {
+        let path = &group[0];
+        match read_files(group, &mut opts)
+            .map_err(error_diagnostic)
+            .and_then(|files| {
+                let unit = if opts.whole_program {
+                    compile_program(&files, &opts)
+                } else {
+                    compile_unit(&files[0].1, &opts)
+                };
+                unit.map_err(|e| diagnostics(e, &print))
+            }) {
+            Ok(mut unit) => {
+                report(std::mem::take(&mut unit.warnings));
+                if stats {
+                    print_stats(path, &unit.stats);
+                }
+                if opts.time_report {
+                    eprint!("{}", timer::report(path, &unit.phases));
+                }
+                if let Some(memory) = &unit.memory {
+                    eprint!("{}", stats::report(path, memory));
+                }
+                units.push((path.clone(), unit));
+            }
+            Err(e) => report(e),
+        }
+    }
+    if failed {
+        process::exit(EXIT_ERROR);
+    }
 
-    let mut dump_ir1 = false;
-    let mut dump_ir2 = false;
-    let path;
+    if run {
+        let (path, unit) = &units[0];
+        let obj = catch_ice("assemble", || target.assemble(&unit.asm), &print)
+            .unwrap_or_else(|| error(format!("--run is not supported for {}", target.name())));
+        let mut argv = vec![path.clone()];
+        argv.extend(run_args);
+        // --run is asked to run the program as part of r9cc.
+        match unsafe { jit::run(&obj, &argv) } {
+            Ok(status) => process::exit(status),
+            Err(e) => {
+                error_diagnostic(e).iter().for_each(print);
+                process::exit(EXIT_ERROR);
+            }
+        }
+    }
+
+    if stage == Stage::Executable {
+        if let Err(e) = check_symbols(&units) {
+            error_diagnostic(e).iter().for_each(print);
+            process::exit(EXIT_ERROR);
+        }
+        let output = output.unwrap_or_else(|| "a.out".into());
+        let mut reports: Vec<(String, String)> = units
+            .iter()
+            .filter(|_| deps)
+            .map(|(path, unit)| (dep_file(path, None), dependencies(&output, path, unit)))
+            .collect();
+        reports.extend(
+            units
+                .iter()
+                .filter(|_| opts.stack_usage)
+                .map(|(path, unit)| {
+                    (
+                        su_file(path, None),
+                        stats::stack_usage_report(&unit.stack_usage),
+                    )
+                }),
+        );
+        let asms: Vec<String> = units.into_iter().map(|(_, unit)| unit.asm).collect();
+        if let Err(e) = build_executable(&asms, &output, opts.sanitize, opts.coverage, opts.profile)
+        {
+            error(e);
+        }
+        for (name, data) in reports {
+            write_output(&name, data.as_bytes());
+        }
+        return;
+    }
+
+    let ext = if stage == Stage::Object {
+        "o"
+    } else if opts.emit_llvm {
+        "ll"
+    } else if !target.needs_linking() {
+        "wat"
+    } else {
+        "s"
+    };
+    let mut files = vec![];
+    for (path, unit) in units {
+        let name = output.clone().unwrap_or_else(|| output_name(&path, ext));
+        if deps {
+            let d = dependencies(&name, &path, &unit);
+            files.push((dep_file(&path, Some(&name)), d.into_bytes()));
+        }
+        if opts.stack_usage {
+            let su = stats::stack_usage_report(&unit.stack_usage);
+            files.push((su_file(&path, Some(&name)), su.into_bytes()));
+        }
+        let data = if stage == Stage::Object {
+            // Write a relocatable object file using the built-in assembler.
+            let obj = catch_ice("assemble", || target.assemble(&unit.asm), &print)
+                .unwrap_or_else(|| error(format!("-c is not supported for {}", target.name())));
+            write_elf(&obj)
+        } else {
+            unit.asm.into_bytes()
+        };
+        files.push((name, data));
+    }
+    for (name, data) in files {
+        write_output(&name, &data);
+    }
+}
+
+fn error_diagnostic(msg: String) -> Vec<Diagnostic> {
+    vec![Diagnostic::new(Severity::Error, msg, None)]
+}
+
+// The warnings and error that made a file fail to compile. A bug in
+// r9cc stops everything.
+fn diagnostics(e: CompileError, print: &impl Fn(&Diagnostic)) -> Vec<Diagnostic> {
+    match e {
+        CompileError::Invalid(diags) => diags,
+        CompileError::Internal(ice) => internal_error(&ice, print),
+        e => error_diagnostic(e.to_string()),
+    }
+}
+
+fn internal_error(ice: &Ice, print: &impl Fn(&Diagnostic)) -> ! {
+    print(&ice.diagnostic());
+    process::exit(EXIT_ICE)
+}
+
+// Runs a step that the library doesn't, catching a bug in it like
+// compile() does.
+fn catch_ice<T>(phase: &str, f: impl FnOnce() -> T, print: &impl Fn(&Diagnostic)) -> T {
+    ice::set_context(Default::default());
+    ice::set_phase(phase);
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|e| internal_error(&Ice::from_panic(e), print))
+}
+
+fn set_target(opts: &mut Options, name: String) {
+    if target::lookup(&name).is_none() {
+        usage_error(format!("unknown target: {}", name));
+    }
+    opts.target = name;
+}
+
+fn print_stats(path: &str, stats: &opt::Stats) {
+    eprintln!("{}: -O{}", path, stats.level);
+    for (pass, n) in &stats.passes {
+        eprintln!("  {:<16}{:>6} changed", pass, n);
+    }
+}
+
+// Reads the file to compile, naming it in `opts` for diagnostics and
+// __FILE__ as it was given on the command line, like gcc does.
+fn read_file(path: &str, opts: &mut Options) -> Result<String, String> {
+    let source = read_source(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    opts.filename = match path {
+        "-" => "<stdin>".into(),
+        _ => path.into(),
+    };
+    Ok(source)
+}
+
+// Reads the files to compile together, as (name, source). They are
+// named in `opts` after the first.
+fn read_files(paths: &[String], opts: &mut Options) -> Result<Vec<(String, String)>, String> {
+    let mut files = vec![];
+    for path in paths.iter().rev() {
+        let source = read_file(path, opts)?;
+        files.insert(0, (opts.filename.clone(), source));
+    }
+    Ok(files)
+}
+
+// Two units defining the same symbol can't be linked, and the linker
+// wouldn't tell which files they came from.
+fn check_symbols(units: &[(String, Unit)]) -> Result<(), String> {
+    let mut defined: HashMap<&str, usize> = HashMap::new();
+    for (i, (path, unit)) in units.iter().enumerate() {
+        for symbol in &unit.symbols {
+            match defined.insert(symbol, i) {
+                Some(j) if j != i => {
+                    return Err(format!(
+                        "multiple definition of '{}' in {} and {}",
+                        symbol, units[j].0, path
+                    ));
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
 
-    if args.len() == 3 && args[1] == "-dump-ir1" {
-        dump_ir1 = true;
-        path = args[2].clone();
-    } else if args.len() == 3 && args[1] == "-dump-ir2" {
-        dump_ir2 = true;
-        path = args[2].clone();
+// Runs the tests of a conformance suite (see conformance.rs) and prints
+// the report. Fails if a test not in the xfail list does. With
+// --write-xfail, the tests that failed are written to a file, which can
+// be passed to --xfail later.
+fn run_conformance(
+    dir: &str,
+    opts: &Options,
+    xfail: Option<String>,
+    write_xfail: Option<String>,
+) -> ! {
+    let r9cc = env::current_exe().unwrap_or_else(|e| error(format!("cannot find r9cc: {}", e)));
+    let xfail = match xfail {
+        Some(path) => match fs::read_to_string(&path) {
+            Ok(text) => conformance::read_xfail(&text),
+            Err(e) => error(format!("cannot read {}: {}", path, e)),
+        },
+        None => Default::default(),
+    };
+    let flags = [format!("-O{}", opts.opt_level)];
+    let report = conformance::run_suite(&r9cc, &flags, Path::new(dir), xfail, opts.jobs)
+        .unwrap_or_else(|e| error(e));
+    print!("{}", report.format());
+    if let Some(path) = write_xfail {
+        let names: String = report
+            .failures()
+            .map(|name| format!("{}\n", name))
+            .collect();
+        if let Err(e) = fs::write(&path, names) {
+            error(format!("cannot write {}: {}", path, e));
+        }
+    }
+    process::exit(if report.regressions() > 0 {
+        EXIT_ERROR
     } else {
-        if args.len() != 2 {
-            usage();
+        0
+    })
+}
+
+// Reads C from stdin a line at a time, or more while a brace is open,
+// and evaluates it in a session (see repl.rs), printing the values of
+// expressions. Errors are reported, and the session goes on.
+fn run_repl(opts: Options, color: Option<bool>, json: bool) -> ! {
+    panic::set_hook(Box::new(ice::hook));
+    let color =
+        color.unwrap_or_else(|| io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none());
+    let print = |d: &Diagnostic| {
+        if json {
+            eprintln!("{}", d.to_json());
+        } else {
+            eprint!("{}", d.render(color));
+        }
+    };
+    let mut session = Session::new(opts).unwrap_or_else(|e| error(e));
+    let interactive = io::stdin().is_terminal();
+    let mut input = String::new();
+    loop {
+        if interactive {
+            print!("{}", if input.is_empty() { "> " } else { "... " });
+            let _ = io::stdout().flush();
+        }
+        match io::stdin().read_line(&mut input) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(e) => error(format!("cannot read stdin: {}", e)),
         }
-        path = args[1].clone();
+        if input.matches('{').count() > input.matches('}').count() {
+            continue;
+        }
+        if !input.trim().is_empty() {
+            match session.eval(&input) {
+                Ok(out) => {
+                    out.warnings.iter().for_each(print);
+                    if let Some(value) = out.value {
+                        println!("{}", value);
+                    }
+                }
+                Err(e) => diagnostics(e, &print).iter().for_each(print),
+            }
+        }
+        input.clear();
     }
+    process::exit(0)
+}
 
-    // Tokenize and parse.
-    let tokens = tokenize(path, &mut Preprocessor::new());
+// Formats the files (see formatter.rs), printing them, or writing them
+// back with -i. With --check, only lists those that would change, and
+// fails if there are any.
+fn run_fmt(
+    paths: &[String],
+    mut opts: Options,
+    check: bool,
+    in_place: bool,
+    color: Option<bool>,
+    json: bool,
+) -> ! {
+    panic::set_hook(Box::new(ice::hook));
+    let color =
+        color.unwrap_or_else(|| io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none());
+    let print = |d: &Diagnostic| {
+        if json {
+            eprintln!("{}", d.to_json());
+        } else {
+            eprint!("{}", d.render(color));
+        }
+    };
+    if paths.is_empty() {
+        usage();
+    }
+    if check && in_place {
+        usage_error("cannot specify both --check and -i");
+    }
+    if in_place && paths.iter().any(|path| path == "-") {
+        usage_error("cannot format stdin in place");
+    }
+    if paths.len() > 1 && opts.output.is_some() {
+        usage_error("cannot specify -o with multiple files");
+    }
+    let output = opts.output.clone();
+    let mut failed = false;
+    let mut unformatted = false;
+    let mut out = String::new();
+    for path in paths {
+        let result = read_file(path, &mut opts)
+            .map_err(error_diagnostic)
+            .and_then(|source| {
+                r9cc::format(&source, &opts)
+                    .map(|formatted| (source, formatted))
+                    .map_err(|e| diagnostics(e, &print))
+            });
+        let (source, formatted) = match result {
+            Ok(result) => result,
+            Err(diags) => {
+                diags.iter().for_each(print);
+                failed = true;
+                continue;
+            }
+        };
+        if check {
+            if source != formatted {
+                println!("{}", path);
+                unformatted = true;
+            }
+        } else if in_place {
+            if source != formatted {
+                write_output(path, formatted.as_bytes());
+            }
+        } else {
+            out.push_str(&formatted);
+        }
+    }
+    if failed {
+        process::exit(EXIT_ERROR);
+    }
+    if !check && !in_place {
+        write_output(output.as_deref().unwrap_or("-"), out.as_bytes());
+    }
+    process::exit(if unformatted { 1 } else { 0 })
+}
 
-    let nodes = parse(&tokens);
-    let (nodes, globals) = sema(nodes);
-    let mut fns = gen_ir(nodes);
+// Compares r9cc with the compiler `reference` on the programs in
+// `paths` and random ones from the seeds in `seeds` (see
+// differential.rs), and prints the report. Fails if any program behaves
+// differently or doesn't compile. Generated programs that do are saved
+// in the current directory as diff-<seed>.c, to look into.
+fn run_differential(reference: &str, paths: &[String], seeds: Range<u64>, opts: &Options) -> ! {
+    let r9cc = env::current_exe().unwrap_or_else(|e| error(format!("cannot find r9cc: {}", e)));
+    let mut programs = vec![];
+    for path in paths {
+        let source =
+            read_source(path).unwrap_or_else(|e| error(format!("cannot read {}: {}", path, e)));
+        programs.push(Program {
+            name: path.clone(),
+            source,
+            seed: None,
+        });
+    }
+    programs.extend(seeds.map(Program::generated));
+    if programs.is_empty() {
+        usage();
+    }
 
-    if dump_ir1 {
-        dump_ir(&fns);
+    let flags = [format!("-O{}", opts.opt_level)];
+    let report = differential::run_all(&r9cc, &flags, reference, programs, opts.jobs)
+        .unwrap_or_else(|e| error(e));
+    print!("{}", report.format());
+    let mut failed = false;
+    for (program, _) in report.failures() {
+        failed = true;
+        if let Some(seed) = program.seed {
+            let name = format!("diff-{}.c", seed);
+            if let Err(e) = fs::write(&name, &program.source) {
+                error(format!("cannot write {}: {}", name, e));
+            }
+        }
     }
+    process::exit(if failed { EXIT_ERROR } else { 0 })
+}
+
+// A dependency file for -MD, in make format: `output` depends on the
+// file compiled and everything it includes.
+//
+//   foo.o: foo.c \
+//    foo.h
+fn dependencies(output: &str, path: &str, unit: &Unit) -> String {
+    // Escape what make would read as a separator, variable or comment.
+    let escape = |name: &str| {
+        name.replace('$', "$$")
+            .replace(' ', "\\ ")
+            .replace('#', "\\#")
+    };
+    let sources = Some(path).filter(|&p| p != "-");
+    let mut out = format!("{}:", escape(output));
+    for (i, dep) in sources
+        .into_iter()
+        .chain(unit.includes.iter().map(|s| s.as_str()))
+        .enumerate()
+    {
+        out.push_str(if i == 0 { " " } else { " \\\n " });
+        out.push_str(&escape(dep));
+    }
+    out.push('\n');
+    out
+}
 
-    alloc_regs(&mut fns);
+// The file "foo.c" compiles to "foo.<ext>" in the current directory.
+fn output_name(path: &str, ext: &str) -> String {
+    let stem = Path::new(path).file_stem().unwrap().to_string_lossy();
+    format!("{}.{}", stem, ext)
+}
 
-    if dump_ir2 {
-        dump_ir(&fns);
+// Reads the file to compile, or stdin if the path is "-".
+fn read_source(path: &str) -> io::Result<String> {
+    if path == "-" {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source)?;
+        return Ok(source);
     }
+    fs::read_to_string(path)
+}
 
-    gen_x86(globals, fns);
+// Writes an output file, or stdout if the path is "-".
+fn write_output(path: &str, data: &[u8]) {
+    let result = if path == "-" {
+        io::stdout().write_all(data)
+    } else {
+        fs::write(path, data)
+    };
+    if let Err(e) = result {
+        error(format!("cannot write {}: {}", path, e));
+    }
 }
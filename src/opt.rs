@@ -0,0 +1,392 @@
+use crate::gen_ir::{Function, IROp, IRType, IR};
+use crate::ice;
+use crate::irdump::IRInfo;
+use crate::parallel;
+use crate::timer::Timer;
+
+use std::collections::BTreeMap;
+use std::mem;
+
+// IR optimizer. It runs between gen_ir and register allocation, one
+// function at a time (several at once with `jobs` threads), and -O
+// picks the passes:
+//
+//   -O0  none
+//   -O1  copy propagation, folding constants into instructions and
+//        branches, and comparisons of addresses with null, and
+//        removing dead code
+//   -O2  -O1, plus removing jumps to the next instruction
+//
+// At -O2, before IR generation, constants are also propagated across
+// functions (see ipcp.rs), small static functions are inlined (see
+// whole_program.rs), loop-invariant arithmetic moves out of loops
+// (see licm.rs), and what was computed before is reused (see gvn.rs). And gen_ir lays out code for the likely path, since
+// it knows which branches are loops and what hints they have: loops
+// test their condition at the bottom, and unlikely branches go to the
+// end of the function.
+//
+// The IR is not in SSA form: a register may be assigned more than once
+// (see Logand in gen_ir.rs), and two-address instructions update their
+// left operand in place. So a pass only rewrites a register after
+// looking at every instruction it occurs in. It also never removes a
+// KILL, since the register allocator needs it to free the register.
+
+pub struct Pass {
+    pub name: &'static str,
+    // Rewrites a function, returning how many instructions changed.
+    run: fn(&mut Function) -> usize,
+}
+
+const O1: &[Pass] = &[
+    Pass {
+        name: "copy-prop",
+        run: copy_prop,
+    },
+    Pass {
+        name: "fold-imm",
+        run: fold_imm,
+    },
+    Pass {
+        name: "fold-null",
+        run: fold_null,
+    },
+    Pass {
+        name: "fold-branches",
+        run: fold_branches,
+    },
+    Pass {
+        name: "dead-imm",
+        run: dead_imm,
+    },
+    Pass {
+        name: "unreachable",
+        run: unreachable_code,
+    },
+];
+
+const O2: &[Pass] = &[Pass {
+    name: "jumps",
+    run: jumps,
+}];
+
+pub fn passes(level: u8) -> Vec<&'static Pass> {
+    let mut v = vec![];
+    if level >= 1 {
+        v.extend(O1);
+    }
+    if level >= 2 {
+        v.extend(O2);
+    }
+    v
+}
+
+// What the optimizer did, for --stats.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub level: u8,
+    pub passes: Vec<(&'static str, usize)>, // (name, instructions changed)
+}
+
+pub fn optimize(fns: &mut Vec<Function>, level: u8, jobs: usize, timer: &mut Timer) -> Stats {
+    let mut stats = Stats {
+        level,
+        passes: vec![],
+    };
+    for pass in passes(level) {
+        let n = timer.phase(pass.name, || {
+            let run = |mut f: Function| {
+                ice::set_function(&f.name);
+                let n = (pass.run)(&mut f);
+                (f, n)
+            };
+            let (done, counts): (Vec<Function>, Vec<usize>) =
+                parallel::map(mem::take(fns), jobs, run).into_iter().unzip();
+            *fns = done;
+            counts.into_iter().sum()
+        });
+        stats.passes.push((pass.name, n));
+    }
+    for f in fns {
+        f.ir.retain(|ir| ir.op != IROp::Nop);
+    }
+    stats
+}
+
+// The registers an instruction reads or writes.
+fn regs(ir: &IR) -> Vec<usize> {
+    use self::IRType::*;
+    match IRInfo::from(&ir.op).ty {
+        Reg | RegImm | RegLabel | LabelAddr => vec![ir.lhs.unwrap()],
+        Mem | RegReg => vec![ir.lhs.unwrap(), ir.rhs.unwrap()],
+        Call => match ir.op {
+            IROp::Call(_, ref args, _) => {
+                let mut v = vec![ir.lhs.unwrap()];
+                v.extend(ir.rhs);
+                v.extend(args.iter().map(|arg| arg.reg()));
+                v
+            }
+            _ => unreachable!(),
+        },
+        _ => vec![],
+    }
+}
+
+// For each register, the indices of the instructions it occurs in, in
+// order. An instruction using a register twice appears twice.
+fn occurrences(irv: &[IR]) -> BTreeMap<usize, Vec<usize>> {
+    let mut map: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (i, ir) in irv.iter().enumerate() {
+        for r in regs(ir) {
+            map.entry(r).or_default().push(i);
+        }
+    }
+    map
+}
+
+fn is_jump(op: &IROp) -> bool {
+    matches!(
+        op,
+        IROp::Jmp | IROp::If | IROp::Unless | IROp::JumpTable(_) | IROp::Label
+    )
+}
+
+// An immediate that backends can put in an instruction, which they
+// read as an i32.
+fn small_imm(val: usize) -> bool {
+    val as i32 as usize == val
+}
+
+//   MOV r2, r1; ...; KILL r1  =>  ...
+//
+// if r2 first appears at the MOV and r1 is only killed after it. The
+// code up to the KILL must be straight-line, or the MOV might run again
+// once r1 holds something else.
+fn copy_prop(f: &mut Function) -> usize {
+    let mut n = 0;
+    // Each rewrite renames registers, so start over after one.
+    while let Some((i, k)) = find_copy(&f.ir) {
+        let (dst, src) = (f.ir[i].lhs.unwrap(), f.ir[i].rhs.unwrap());
+        for ir in f.ir[i + 1..].iter_mut() {
+            rename(ir, dst, src);
+        }
+        f.ir[i].op = IROp::Nop;
+        f.ir[k].op = IROp::Nop;
+        n += 1;
+    }
+    n
+}
+
+// Returns the indices of a MOV that copy_prop can remove and of the
+// KILL of its source.
+fn find_copy(irv: &[IR]) -> Option<(usize, usize)> {
+    let occ = occurrences(irv);
+    for (i, ir) in irv.iter().enumerate() {
+        if ir.op != IROp::Mov {
+            continue;
+        }
+        let (dst, src) = (ir.lhs.unwrap(), ir.rhs.unwrap());
+        if dst == src || occ[&dst][0] != i {
+            continue;
+        }
+        let uses = &occ[&src];
+        let k = match uses.iter().position(|&j| j == i) {
+            Some(p) if uses.len() == p + 2 => uses[p + 1],
+            _ => continue,
+        };
+        if irv[k].op == IROp::Kill && !irv[i + 1..k].iter().any(|ir| is_jump(&ir.op)) {
+            return Some((i, k));
+        }
+    }
+    None
+}
+
+fn rename(ir: &mut IR, from: usize, to: usize) {
+    use self::IRType::*;
+    let (lhs, rhs) = match IRInfo::from(&ir.op).ty {
+        Reg | RegImm | RegLabel | LabelAddr => (true, false),
+        Mem | RegReg | Call => (true, true),
+        _ => (false, false),
+    };
+    if lhs && ir.lhs == Some(from) {
+        ir.lhs = Some(to);
+    }
+    if rhs && ir.rhs == Some(from) {
+        ir.rhs = Some(to);
+    }
+    if let IROp::Call(_, ref mut args, _) = ir.op {
+        for arg in args.iter_mut() {
+            if arg.reg() == from {
+                *arg = arg.with_reg(to);
+            }
+        }
+    }
+}
+
+//   MOV r2, 3; ADD r1, r2; KILL r2  =>  ADD r1, 3
+fn fold_imm(f: &mut Function) -> usize {
+    let mut n = 0;
+    for (r, v) in occurrences(&f.ir) {
+        let (def, use_, k) = match v[..] {
+            [def, use_, k] => (def, use_, k),
+            _ => continue,
+        };
+        if f.ir[def].op != IROp::Imm || f.ir[k].op != IROp::Kill || f.ir[use_].rhs != Some(r) {
+            continue;
+        }
+        let val = f.ir[def].rhs.unwrap();
+        if !small_imm(val) {
+            continue;
+        }
+        let op = match f.ir[use_].op {
+            IROp::Add => IROp::AddImm,
+            IROp::Sub => IROp::SubImm,
+            IROp::Mul => IROp::MulImm,
+            _ => continue,
+        };
+        f.ir[use_] = IR {
+            op,
+            lhs: f.ir[use_].lhs,
+            rhs: Some(val),
+        };
+        f.ir[def].op = IROp::Nop;
+        f.ir[k].op = IROp::Nop;
+        n += 1;
+    }
+    n
+}
+
+//   MOV r1, 0; UNLESS r1, .L1; KILL r1  =>  JMP .L1
+//   MOV r1, 1; UNLESS r1, .L1; KILL r1  =>  (nothing)
+fn fold_branches(f: &mut Function) -> usize {
+    let mut n = 0;
+    for (_, v) in occurrences(&f.ir) {
+        let (def, use_, k) = match v[..] {
+            [def, use_, k] => (def, use_, k),
+            _ => continue,
+        };
+        if f.ir[def].op != IROp::Imm || f.ir[k].op != IROp::Kill {
+            continue;
+        }
+        let taken = match f.ir[use_].op {
+            IROp::If => f.ir[def].rhs != Some(0),
+            IROp::Unless => f.ir[def].rhs == Some(0),
+            _ => continue,
+        };
+        f.ir[use_] = if taken {
+            IR {
+                op: IROp::Jmp,
+                lhs: f.ir[use_].rhs,
+                rhs: None,
+            }
+        } else {
+            IR {
+                op: IROp::Nop,
+                lhs: None,
+                rhs: None,
+            }
+        };
+        f.ir[def].op = IROp::Nop;
+        f.ir[k].op = IROp::Nop;
+        n += 1;
+    }
+    n
+}
+
+//   LABEL_ADDR r1, x; MOV r2, 0; EQ r1, r2; KILL r2  =>  MOV r1, 0
+//
+// The address of a global or of a local (BPREL) is never null, so it
+// compares unequal to 0, on either side of EQ or NE.
+fn fold_null(f: &mut Function) -> usize {
+    let occ = occurrences(&f.ir);
+    let is_addr = |ir: &IR| matches!(ir.op, IROp::LabelAddr(_)) || ir.op == IROp::Bprel;
+    let is_null = |ir: &IR| ir.op == IROp::Imm && ir.rhs == Some(0);
+    let mut folds = vec![];
+    for (i, ir) in f.ir.iter().enumerate() {
+        if ir.op != IROp::EQ && ir.op != IROp::NE {
+            continue;
+        }
+        // The left operand becomes the result, and the right one dies.
+        let (l, r) = (&occ[&ir.lhs.unwrap()], &occ[&ir.rhs.unwrap()]);
+        if l.len() < 2 || l[1] != i || r.len() != 3 || r[1] != i || f.ir[r[2]].op != IROp::Kill {
+            continue;
+        }
+        let (dl, dr) = (&f.ir[l[0]], &f.ir[r[0]]);
+        if !(is_addr(dl) && is_null(dr) || is_null(dl) && is_addr(dr)) {
+            continue;
+        }
+        let start = l[0].min(r[0]);
+        if f.ir[start..i].iter().any(|ir| is_jump(&ir.op)) {
+            continue;
+        }
+        folds.push((l[0], i, r[0], r[2]));
+    }
+    for &(def, cmp, other, k) in &folds {
+        f.ir[def] = IR {
+            op: IROp::Imm,
+            lhs: f.ir[def].lhs,
+            rhs: Some((f.ir[cmp].op == IROp::NE) as usize),
+        };
+        for i in [cmp, other, k] {
+            f.ir[i].op = IROp::Nop;
+        }
+    }
+    folds.len()
+}
+
+//   MOV r1, 3; KILL r1  =>  (nothing)
+fn dead_imm(f: &mut Function) -> usize {
+    let mut n = 0;
+    for (_, v) in occurrences(&f.ir) {
+        if let [def, k] = v[..] {
+            if f.ir[def].op == IROp::Imm && f.ir[k].op == IROp::Kill {
+                f.ir[def].op = IROp::Nop;
+                f.ir[k].op = IROp::Nop;
+                n += 1;
+            }
+        }
+    }
+    n
+}
+
+// Removes the code between a jump or return and the next label, which
+// no path reaches.
+fn unreachable_code(f: &mut Function) -> usize {
+    let mut n = 0;
+    let mut dead = false;
+    for ir in f.ir.iter_mut() {
+        match ir.op {
+            IROp::Label => dead = false,
+            IROp::Kill | IROp::Comment(_) | IROp::Loc(_, _) | IROp::Count(_) | IROp::Nop => (),
+            _ if dead => {
+                ir.op = IROp::Nop;
+                n += 1;
+            }
+            IROp::Jmp | IROp::Return | IROp::ReturnStruct | IROp::Unreachable => dead = true,
+            _ => (),
+        }
+    }
+    n
+}
+
+//   JMP .L1; .L1:  =>  .L1:
+fn jumps(f: &mut Function) -> usize {
+    let mut n = 0;
+    for i in 0..f.ir.len() {
+        if f.ir[i].op != IROp::Jmp {
+            continue;
+        }
+        let next = f.ir[i + 1..].iter().find(|ir| {
+            !matches!(
+                ir.op,
+                IROp::Kill | IROp::Nop | IROp::Comment(_) | IROp::Loc(_, _) | IROp::Count(_)
+            )
+        });
+        if let Some(ir) = next {
+            if ir.op == IROp::Label && ir.lhs == f.ir[i].lhs {
+                f.ir[i].op = IROp::Nop;
+                n += 1;
+            }
+        }
+    }
+    n
+}
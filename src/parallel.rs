@@ -0,0 +1,56 @@
+// Running per-function work on several threads.
+//
+// After sema, functions are independent of each other: optimizing,
+// allocating registers for and emitting one doesn't look at the
+// others. map() splits them among `jobs` threads and returns the
+// results in the original order, so the output is the same however
+// many threads there are. IR generation still runs on one thread, as
+// it numbers labels and --coverage counters across the whole program.
+
+use crate::diagnostic::Diagnostic;
+use crate::ice::{self, Ice};
+
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+pub fn map<T: Send, U: Send>(items: Vec<T>, jobs: usize, f: impl Fn(T) -> U + Sync) -> Vec<U> {
+    if jobs <= 1 || items.len() <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+
+    let size = items.len().div_ceil(jobs);
+    let mut chunks = vec![];
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        chunks.push(items.by_ref().take(size).collect::<Vec<T>>());
+    }
+
+    let f = &f;
+    let context = ice::context();
+    thread::scope(|s| {
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| {
+                let context = context.clone();
+                s.spawn(move || {
+                    ice::set_context(context);
+                    let run = || chunk.into_iter().map(f).collect::<Vec<U>>();
+                    // A bug takes the context from this thread, which
+                    // goes away with it.
+                    panic::catch_unwind(AssertUnwindSafe(run)).unwrap_or_else(|e| {
+                        if e.is::<Diagnostic>() || e.is::<Ice>() {
+                            panic::resume_unwind(e);
+                        }
+                        panic::resume_unwind(Box::new(Ice::from_panic(e)))
+                    })
+                })
+            })
+            .collect();
+        // The frontend reports errors by panicking, so pass a panic on
+        // to the caller as it is.
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
@@ -1,8 +1,131 @@
-use crate::token::Token;
-use crate::util::roundup;
-use crate::{Ctype, Scope, TokenType, Type};
+use crate::diagnostic::{self, Diagnostic, Location, Severity};
+use crate::ice;
+use crate::symbol::{self, Symbol};
+use crate::target::Target;
+use crate::token::{self, Pos, Span, Token};
+use crate::util::{closest, roundup};
+use crate::{Ctype, NumType, Scope, Std, TokenType, Type};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+// How deeply expressions, statements and declarators may nest, unless
+// -fbracket-depth says otherwise. The parser and the passes after it
+// recurse once for each level, and would run out of stack on deeper
+// input.
+pub const MAX_DEPTH: usize = 256;
+
+// A syntax error. The parser returns these rather than stopping at the
+// first one: it gets past the declaration or statement that had it and
+// goes on, to find the errors after it too.
+#[derive(Debug, Clone)]
+pub enum ParseError {
+    // `expected` is missing before `found`. `similar` is a keyword or a
+    // type name that the identifier starting the statement looks like a
+    // misspelling of, as in `retrun x;`.
+    Expected {
+        expected: TokenType,
+        found: Box<Found>,
+        similar: Option<String>,
+    },
+    // Expressions, statements or declarators nest more than `limit`
+    // levels deep.
+    TooDeep {
+        found: Box<Found>,
+        limit: usize,
+    },
+    // Anything else, such as "typename expected" or "stray case".
+    Invalid {
+        message: String,
+        found: Box<Found>,
+    },
+}
+
+// The token a ParseError is at.
+#[derive(Debug, Clone)]
+pub struct Found {
+    pub ty: TokenType,
+    pub text: String, // As written
+    pub loc: Location,
+}
+
+impl Found {
+    fn new(t: &Token) -> Self {
+        Found {
+            ty: t.ty.clone(),
+            text: t.tokstr(),
+            loc: t.location(),
+        }
+    }
+
+    // Where something was expected, for the error saying so.
+    fn before(&self) -> String {
+        match self.ty {
+            TokenType::Eof => "at end of input".into(),
+            _ => format!("before '{}'", self.text),
+        }
+    }
+}
+
+impl ParseError {
+    pub fn found(&self) -> &Found {
+        match self {
+            ParseError::Expected { found, .. }
+            | ParseError::TooDeep { found, .. }
+            | ParseError::Invalid { found, .. } => found,
+        }
+    }
+
+    // The error as compile() reports it.
+    pub fn diagnostic(&self) -> Diagnostic {
+        let loc = Some(self.found().loc.clone());
+        match self {
+            ParseError::Expected {
+                expected,
+                found,
+                similar,
+            } => Diagnostic::new(
+                Severity::Error,
+                format!("{} expected {}", token::spelling(expected), found.before()),
+                loc,
+            )
+            .with_code("expected-token")
+            .with_suggestion(similar.clone()),
+            ParseError::TooDeep { limit, .. } => {
+                Diagnostic::new(Severity::Error, "too deeply nested".into(), loc)
+                    .with_code("nesting-too-deep")
+                    .with_note(format!(
+                        "the limit is {} levels; use -fbracket-depth=<n> to change it",
+                        limit
+                    ))
+            }
+            ParseError::Invalid { message, .. } => {
+                Diagnostic::new(Severity::Error, message.clone(), loc)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.diagnostic().render(false).trim_end())
+    }
+}
+
+impl std::error::Error for ParseError {}
 
-use std::collections::HashMap;
+type Result<T> = std::result::Result<T, ParseError>;
+
+// An error at `t` that `message` describes.
+fn invalid<T>(t: &Token, message: &str) -> Result<T> {
+    Err(ParseError::Invalid {
+        message: message.into(),
+        found: Box::new(Found::new(t)),
+    })
+}
 
 // Quoted from 9cc
 // > This is a recursive-descendent parser which constructs abstract
@@ -23,31 +146,72 @@ int main() {     ; +-+                        int   []         2
 }                ; +-+                  return        []      +->primary
                                                                  0
 */
-pub fn parse(tokens: &Vec<Token>) -> Vec<Node> {
-    let mut parser = Parser::new(tokens);
+pub fn parse(
+    tokens: &[Token],
+    target: &dyn Target,
+    max_depth: usize,
+    std: Std,
+) -> std::result::Result<Vec<Node>, Vec<ParseError>> {
+    parse_with_typedefs(tokens, target, max_depth, std).map(|(nodes, _)| nodes)
+}
+
+// Like parse, but also returns the names that typedefs at file scope
+// define, which r9cc fmt needs to tell declarations from expressions.
+// The errors are in the order found.
+pub fn parse_with_typedefs(
+    tokens: &[Token],
+    target: &dyn Target,
+    max_depth: usize,
+    std: Std,
+) -> std::result::Result<(Vec<Node>, Vec<Symbol>), Vec<ParseError>> {
+    Type::set_target(target);
+    let mut parser = Parser::new(tokens, max_depth);
+    parser.std = std;
+    parser
+        .env
+        .typedefs
+        .insert(symbol::intern("__builtin_va_list"), target.va_list());
 
     let mut v = vec![];
-    while tokens.len() != parser.pos {
-        if let Some(node) = parser.toplevel() {
-            v.push(node);
+    while parser.cur.peek().ty != TokenType::Eof {
+        ice::set_position("token", parser.cur.pos);
+        parser.depth = 0;
+        let at = parser.checkpoint();
+        match parser.toplevel() {
+            Ok(Some(node)) => v.push(node),
+            Ok(None) => {}
+            Err(e) => {
+                parser.errors.push(e);
+                parser.recover(at);
+            }
         }
     }
-    v
+    if !parser.errors.is_empty() {
+        return Err(parser.errors);
+    }
+    Ok((v, parser.env.typedefs.keys().copied().collect()))
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct Env {
-    tags: HashMap<String, Type>,
-    typedefs: HashMap<String, Type>,
+    tags: HashMap<Symbol, Rc<Type>>,
+    typedefs: HashMap<Symbol, Rc<Type>>,
+    registers: HashMap<Symbol, bool>, // Local variables, and if they are `register`
     next: Option<Box<Env>>,
 }
 
 impl Env {
+    // How many scopes there are, this one and those it is in.
+    fn depth(&self) -> usize {
+        1 + self.next.as_ref().map_or(0, |next| next.depth())
+    }
+
     pub fn new(next: Option<Box<Env>>) -> Self {
         Env {
             next,
             tags: HashMap::new(),
             typedefs: HashMap::new(),
+            registers: HashMap::new(),
         }
     }
 }
@@ -58,81 +222,369 @@ macro_rules! new_expr(
     )
 );
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    Alloca,      // __builtin_alloca(n)
+    Expect,      // __builtin_expect(val, expected)
+    Memcpy,      // __builtin_memcpy(dst, src, n)
+    Memset,      // __builtin_memset(dst, c, n)
+    Trap,        // __builtin_trap()
+    Unreachable, // __builtin_unreachable()
+    VaStart,     // __builtin_va_start(ap, last), with the address of ap
+    VaArg,       // __builtin_va_arg(ap, type), with the address of ap
+    AtomicLoad,  // __atomic_load_n(p, order), without the order
+    AtomicStore, // __atomic_store_n(p, val, order), without the order
+    AtomicAdd,   // __atomic_fetch_add(p, val, order), without the order
+}
+
 #[derive(Debug, Clone)]
 pub enum NodeType {
-    Num(i32),                                        // Number literal
+    Num(i64),                                        // Number literal
+    FNum(f64),                                       // Floating constant
     Str(String, usize),                              // String literal, (data, len)
-    Ident(String),                                   // Identifier
-    Decl(String),                                    // declaration
-    Vardef(String, Option<Box<Node>>, Scope),        // Variable definition, name = init
+    Ident(Symbol),                                   // Identifier
+    Decl(Symbol),                                    // declaration
+    Vardef(Symbol, Option<Box<Node>>, Scope),        // Variable definition, name = init
     Lvar(Scope),                                     // Variable reference
-    Gvar(String, String, usize),                     // Variable reference, (name, data, len)
-    BinOp(TokenType, Box<Node>, Box<Node>),          // left-hand, right-hand
-    If(Box<Node>, Box<Node>, Option<Box<Node>>),     // "if" ( cond ) then "else" els
-    Ternary(Box<Node>, Box<Node>, Box<Node>),        // cond ? then : els
+    Gvar(String, Vec<u8>, usize, bool), // Variable reference, (name, data, len, is_tls)
+    BinOp(TokenType, Box<Node>, Box<Node>), // left-hand, right-hand
+    If(Box<Node>, Box<Node>, Option<Box<Node>>), // "if" ( cond ) then "else" els
+    Ternary(Box<Node>, Box<Node>, Box<Node>), // cond ? then : els
     For(Box<Node>, Box<Node>, Box<Node>, Box<Node>), // "for" ( init; cond; inc ) body
     Break,
+    Switch(Box<Node>, Box<Node>, Vec<Node>, bool), // "switch" ( cond ) body, case values, has default
+    Case(usize, Box<Node>), // "case" val: stmt, index into the switch's case values
+    Default(Box<Node>),     // "default": stmt
     DoWhile(Box<Node>, Box<Node>), // do { body } while(cond)
-    Addr(Box<Node>),               // address-of operator("&"), expr
-    Deref(Box<Node>),              // pointer dereference ("*"), expr
-    Dot(Box<Node>, String, usize), // Struct member accessm, (expr, name, offset)
-    Exclamation(Box<Node>),        // !, expr
-    Neg(Box<Node>),                // -
-    PostInc(Box<Node>),            // post ++
-    PostDec(Box<Node>),            // post --
-    Return(Box<Node>),             // "return", stmt
-    Sizeof(Box<Node>),             // "sizeof", expr
-    Alignof(Box<Node>),            // "_Alignof", expr
-    Call(String, Vec<Node>),       // Function call(name, args)
-    Func(String, Vec<Node>, Box<Node>, usize), // Function definition(name, args, body, stacksize)
-    CompStmt(Vec<Node>),           // Compound statement
-    VecStmt(Vec<Node>),            // For the purpose of assign a value when initializing an array.
-    ExprStmt(Box<Node>),           // Expression statement
-    StmtExpr(Box<Node>),           // Statement expression (GNU extn.)
+    Addr(Box<Node>),        // address-of operator("&"), expr
+    Deref(Box<Node>),       // pointer dereference ("*"), expr
+    Dot(Box<Node>, Symbol, usize), // Struct member accessm, (expr, name, offset)
+    Exclamation(Box<Node>), // !, expr
+    Tilde(Box<Node>),       // ~, expr
+    Neg(Box<Node>),         // -
+    Cast(Box<Node>),        // Conversion to the node's type, written or made by sema
+    PostInc(Box<Node>),     // post ++
+    PostDec(Box<Node>),     // post --
+    Return(Box<Node>),      // "return", stmt
+    Sizeof(Box<Node>),      // "sizeof", expr
+    Alignof(Box<Node>),     // "_Alignof", expr
+    Offsetof(Box<Node>),    // "__builtin_offsetof" of a member of the node's type, such as `a.b[1]`
+    Call(Symbol, Vec<Node>), // Function call(name, args)
+    CallPtr(Box<Node>, Vec<Node>), // Call of anything else, as through a pointer, (callee, args)
+    Func(Symbol, Vec<Node>, Box<Node>, usize, bool), // Function definition(name, args, body, stacksize, is_static)
+    CompStmt(Vec<Node>),                             // Compound statement
+    VecStmt(Vec<Node>), // A local's definition and the stores that initialize it, made by sema
+    InitList(Vec<Node>), // Brace-enclosed initializer, whose items may be lists too
+    // An item of an initializer list for the elements of an array,
+    // "[" first ("..." last)? "]" "=" init
+    Designator(Box<Node>, Option<Box<Node>>, Box<Node>),
+    ExprStmt(Box<Node>),         // Expression statement
+    StmtExpr(Box<Node>),         // Statement expression (GNU extn.)
+    Asm(String),                 // Basic inline assembly (GNU extn.)
+    Builtin(Builtin, Vec<Node>), // Compiler builtin expanded inline, (kind, args)
     Null,
 }
 
 #[derive(Debug, Clone)]
 pub struct Node {
-    pub op: NodeType,  // Node type
-    pub ty: Box<Type>, // C type
+    pub op: NodeType,       // Node type
+    pub ty: Rc<Type>,       // C type
+    pub span: Option<Span>, // Where a statement starts
+    pub pos: Option<Pos>,   // Where an expression is
 }
 
 impl Node {
     pub fn new(op: NodeType) -> Self {
         Self {
             op,
-            ty: Box::new(Type::default()),
+            ty: Type::int_ty(),
+            span: None,
+            pos: None,
         }
     }
 
+    // `self`, with diagnostics about it pointing at `t`.
+    pub fn at(mut self, t: &Token) -> Self {
+        self.pos = Some(t.pos());
+        self
+    }
+
     pub fn new_int(val: i32) -> Self {
-        Node::new(NodeType::Num(val))
+        Node::new(NodeType::Num(val as i64))
     }
 
-    pub fn scale_ptr(node: Box<Node>, ty: &Type) -> Self {
-        match ty.ty {
-            Ctype::Ptr(ref ptr_to) => {
-                Node::new_binop(TokenType::Mul, *node, Node::new_int(ptr_to.size as i32))
-            }
-            _ => panic!("expect ptr type"),
-        }
+    // `node` times `size`, the size of what the pointer type `ty`
+    // points to. The product is a byte offset, not an int, so that
+    // -fsanitize doesn't check it for signed overflow.
+    pub fn scale_ptr(node: Node, ty: &Rc<Type>, size: usize) -> Self {
+        let mut node = Node::new_binop(TokenType::Mul, node, Node::new_int(size as i32));
+        node.ty = ty.clone();
+        node
     }
 
     pub fn new_binop(ty: TokenType, lhs: Node, rhs: Node) -> Self {
         Node::new(NodeType::BinOp(ty, Box::new(lhs), Box::new(rhs)))
     }
 
-    pub fn new_num(val: i32) -> Self {
+    pub fn new_num(val: i64) -> Self {
         Node::new(NodeType::Num(val))
     }
 
     pub fn is_null(&self) -> bool {
+        matches!(self.op, NodeType::Null)
+    }
+
+    // The number of nodes in the tree, this one included.
+    pub fn count(&self) -> usize {
+        use self::NodeType::*;
+        let below = match self.op {
+            Num(_) | FNum(_) | Str(..) | Ident(_) | Decl(_) | Lvar(_) | Gvar(..) | Break
+            | Asm(_) | Null => 0,
+            Vardef(_, ref init, _) => init.as_ref().map_or(0, |n| n.count()),
+            BinOp(_, ref lhs, ref rhs) => lhs.count() + rhs.count(),
+            If(ref cond, ref then, ref els) => {
+                cond.count() + then.count() + els.as_ref().map_or(0, |n| n.count())
+            }
+            Ternary(ref a, ref b, ref c) => a.count() + b.count() + c.count(),
+            For(ref init, ref cond, ref inc, ref body) => {
+                init.count() + cond.count() + inc.count() + body.count()
+            }
+            Switch(ref cond, ref body, ..) | DoWhile(ref body, ref cond) => {
+                cond.count() + body.count()
+            }
+            Case(_, ref n) | Default(ref n) | Addr(ref n) | Deref(ref n) | Dot(ref n, ..) => {
+                n.count()
+            }
+            Exclamation(ref n) | Tilde(ref n) | Neg(ref n) | Cast(ref n) | PostInc(ref n)
+            | PostDec(ref n) => n.count(),
+            Return(ref n) | Sizeof(ref n) | Alignof(ref n) | ExprStmt(ref n) | StmtExpr(ref n) => {
+                n.count()
+            }
+            Offsetof(ref n) => n.count(),
+            Designator(ref first, ref last, ref init) => {
+                first.count() + last.as_ref().map_or(0, |n| n.count()) + init.count()
+            }
+            Func(_, ref args, ref body, ..) => {
+                args.iter().map(Node::count).sum::<usize>() + body.count()
+            }
+            CallPtr(ref callee, ref nodes) => {
+                callee.count() + nodes.iter().map(Node::count).sum::<usize>()
+            }
+            Call(_, ref nodes)
+            | CompStmt(ref nodes)
+            | VecStmt(ref nodes)
+            | InitList(ref nodes)
+            | Builtin(_, ref nodes) => nodes.iter().map(Node::count).sum(),
+        };
+        1 + below
+    }
+
+    // The nodes directly below this one, in the order they are written.
+    pub fn children(&self) -> Vec<&Node> {
+        use self::NodeType::*;
         match self.op {
-            NodeType::Null => true,
-            _ => false,
+            Num(_) | FNum(_) | Str(..) | Ident(_) | Decl(_) | Lvar(_) | Gvar(..) | Break
+            | Asm(_) | Null => {
+                vec![]
+            }
+            Vardef(_, ref init, _) => init.iter().map(|n| &**n).collect(),
+            BinOp(_, ref lhs, ref rhs) => vec![lhs, rhs],
+            If(ref cond, ref then, ref els) => {
+                let mut v = vec![&**cond, &**then];
+                v.extend(els.iter().map(|n| &**n));
+                v
+            }
+            Ternary(ref a, ref b, ref c) => vec![a, b, c],
+            For(ref init, ref cond, ref inc, ref body) => {
+                vec![init, cond, inc, body]
+            }
+            Switch(ref cond, ref body, ..) => vec![cond, body],
+            DoWhile(ref body, ref cond) => vec![body, cond],
+            Case(_, ref n) | Default(ref n) | Addr(ref n) | Deref(ref n) => vec![n],
+            Dot(ref n, ..) | Exclamation(ref n) | Tilde(ref n) | Neg(ref n) | Cast(ref n) => {
+                vec![n]
+            }
+            PostInc(ref n) | PostDec(ref n) | Return(ref n) | Sizeof(ref n) => {
+                vec![n]
+            }
+            Alignof(ref n) | ExprStmt(ref n) | StmtExpr(ref n) | Offsetof(ref n) => {
+                vec![n]
+            }
+            Designator(ref first, ref last, ref init) => {
+                let mut v = vec![&**first];
+                v.extend(last.iter().map(|n| &**n));
+                v.push(init);
+                v
+            }
+            Func(_, ref args, ref body, ..) => {
+                let mut v: Vec<&Node> = args.iter().collect();
+                v.push(body);
+                v
+            }
+            CallPtr(ref callee, ref nodes) => {
+                let mut v = vec![&**callee];
+                v.extend(nodes.iter());
+                v
+            }
+            Call(_, ref nodes)
+            | CompStmt(ref nodes)
+            | VecStmt(ref nodes)
+            | InitList(ref nodes)
+            | Builtin(_, ref nodes) => nodes.iter().collect(),
         }
     }
+
+    // Like children, but mutable.
+    pub fn children_mut(&mut self) -> Vec<&mut Node> {
+        use self::NodeType::*;
+        match self.op {
+            Num(_) | FNum(_) | Str(..) | Ident(_) | Decl(_) | Lvar(_) | Gvar(..) | Break
+            | Asm(_) | Null => {
+                vec![]
+            }
+            Vardef(_, ref mut init, _) => init.iter_mut().map(|n| &mut **n).collect(),
+            BinOp(_, ref mut lhs, ref mut rhs) => vec![lhs, rhs],
+            If(ref mut cond, ref mut then, ref mut els) => {
+                let mut v = vec![&mut **cond, &mut **then];
+                v.extend(els.iter_mut().map(|n| &mut **n));
+                v
+            }
+            Ternary(ref mut a, ref mut b, ref mut c) => vec![a, b, c],
+            For(ref mut init, ref mut cond, ref mut inc, ref mut body) => {
+                vec![init, cond, inc, body]
+            }
+            Switch(ref mut cond, ref mut body, ..) => vec![cond, body],
+            DoWhile(ref mut body, ref mut cond) => vec![body, cond],
+            Case(_, ref mut n) | Default(ref mut n) | Addr(ref mut n) | Deref(ref mut n) => vec![n],
+            Dot(ref mut n, ..)
+            | Exclamation(ref mut n)
+            | Tilde(ref mut n)
+            | Neg(ref mut n)
+            | Cast(ref mut n) => {
+                vec![n]
+            }
+            PostInc(ref mut n) | PostDec(ref mut n) | Return(ref mut n) | Sizeof(ref mut n) => {
+                vec![n]
+            }
+            Alignof(ref mut n) | ExprStmt(ref mut n) | StmtExpr(ref mut n)
+            | Offsetof(ref mut n) => {
+                vec![n]
+            }
+            Designator(ref mut first, ref mut last, ref mut init) => {
+                let mut v = vec![&mut **first];
+                v.extend(last.iter_mut().map(|n| &mut **n));
+                v.push(init);
+                v
+            }
+            Func(_, ref mut args, ref mut body, ..) => {
+                let mut v: Vec<&mut Node> = args.iter_mut().collect();
+                v.push(body);
+                v
+            }
+            CallPtr(ref mut callee, ref mut nodes) => {
+                let mut v = vec![&mut **callee];
+                v.extend(nodes.iter_mut());
+                v
+            }
+            Call(_, ref mut nodes)
+            | CompStmt(ref mut nodes)
+            | VecStmt(ref mut nodes)
+            | InitList(ref mut nodes)
+            | Builtin(_, ref mut nodes) => nodes.iter_mut().collect(),
+        }
+    }
+}
+
+// Types are shared between the nodes that have them, and the common
+// ones are made once per compilation: the arithmetic types and void, and pointers
+// to those and to such pointers. The table is per thread, like the one
+// of symbols.
+//
+// Their sizes are the target's (see Type::set_target). Where a long is
+// 4 bytes, it is an int, and a long long is the 8-byte Ctype::Long.
+struct Types {
+    int: Rc<Type>,
+    uint: Rc<Type>,
+    char: Rc<Type>,
+    schar: Rc<Type>,
+    long: Rc<Type>,
+    ulong: Rc<Type>,
+    llong: Rc<Type>,
+    ullong: Rc<Type>,
+    float: Rc<Type>,
+    double: Rc<Type>,
+    void: Rc<Type>,
+    ptr_size: usize,
+    // By the address of the type pointed to, which they keep alive.
+    ptrs: HashMap<*const Type, Rc<Type>>,
+    // The definitions of struct tags that were used before them, as in
+    // `struct node { struct node *next; };`, by the address of the
+    // incomplete type they were used as, which is kept alive too.
+    completions: HashMap<*const Type, (Rc<Type>, Rc<Type>)>,
+}
+
+impl Types {
+    fn new(ptr_size: usize, long_size: usize, int_max_align: usize) -> Self {
+        let int = Rc::new(Type::new(Ctype::Int, 4));
+        let uint = Rc::new(Type::new_unsigned(Ctype::Int, 4));
+        let llong = Rc::new(Type {
+            align: int_max_align,
+            ..Type::new(Ctype::Long, 8)
+        });
+        let ullong = Rc::new(Type {
+            align: int_max_align,
+            ..Type::new_unsigned(Ctype::Long, 8)
+        });
+        let (long, ulong) = match long_size {
+            4 => (int.clone(), uint.clone()),
+            _ => (llong.clone(), ullong.clone()),
+        };
+        Types {
+            int,
+            uint,
+            char: Rc::new(Type::new_unsigned(Ctype::Char, 1)),
+            schar: Rc::new(Type::new(Ctype::Char, 1)),
+            long,
+            ulong,
+            llong,
+            ullong,
+            float: Rc::new(Type::new(Ctype::Float, 4)),
+            double: Rc::new(Type {
+                align: int_max_align,
+                ..Type::new(Ctype::Double, 8)
+            }),
+            void: Rc::new(Type::new(Ctype::Void, 0)),
+            ptr_size,
+            ptrs: HashMap::new(),
+            completions: HashMap::new(),
+        }
+    }
+
+    fn is_shared(&self, ty: &Rc<Type>) -> bool {
+        if let Ctype::Ptr(ref base) = ty.ty {
+            return self
+                .ptrs
+                .get(&Rc::as_ptr(base))
+                .is_some_and(|p| Rc::ptr_eq(p, ty));
+        }
+        [
+            &self.int,
+            &self.uint,
+            &self.char,
+            &self.schar,
+            &self.llong,
+            &self.ullong,
+            &self.float,
+            &self.double,
+            &self.void,
+        ]
+        .iter()
+        .any(|t| Rc::ptr_eq(ty, t))
+    }
+}
+
+thread_local! {
+    static TYPES: RefCell<Types> = RefCell::new(Types::new(8, 8, 8));
 }
 
 impl Type {
@@ -141,430 +593,1053 @@ impl Type {
             ty,
             size,
             align: size,
+            is_unsigned: false,
+            tag: None,
+            is_atomic: false,
+        }
+    }
+
+    fn new_unsigned(ty: Ctype, size: usize) -> Self {
+        Type {
+            is_unsigned: true,
+            ..Type::new(ty, size)
         }
     }
 
-    pub fn void_ty() -> Self {
-        Type::new(Ctype::Void, 0)
+    pub fn void_ty() -> Rc<Type> {
+        TYPES.with(|t| t.borrow().void.clone())
+    }
+
+    pub fn char_ty() -> Rc<Type> {
+        TYPES.with(|t| t.borrow().char.clone())
     }
 
-    pub fn char_ty() -> Self {
-        Type::new(Ctype::Char, 1)
+    pub fn int_ty() -> Rc<Type> {
+        TYPES.with(|t| t.borrow().int.clone())
     }
 
-    pub fn int_ty() -> Self {
-        Type::new(Ctype::Int, 4)
+    pub fn long_ty() -> Rc<Type> {
+        TYPES.with(|t| t.borrow().long.clone())
     }
 
-    pub fn ptr_to(base: Box<Type>) -> Self {
-        Type::new(Ctype::Ptr(base), 8)
+    pub fn uint_ty() -> Rc<Type> {
+        TYPES.with(|t| t.borrow().uint.clone())
     }
 
-    pub fn ary_of(base: Box<Type>, len: usize) -> Self {
+    pub fn ulong_ty() -> Rc<Type> {
+        TYPES.with(|t| t.borrow().ulong.clone())
+    }
+
+    pub fn schar_ty() -> Rc<Type> {
+        TYPES.with(|t| t.borrow().schar.clone())
+    }
+
+    pub fn llong_ty() -> Rc<Type> {
+        TYPES.with(|t| t.borrow().llong.clone())
+    }
+
+    pub fn ullong_ty() -> Rc<Type> {
+        TYPES.with(|t| t.borrow().ullong.clone())
+    }
+
+    pub fn float_ty() -> Rc<Type> {
+        TYPES.with(|t| t.borrow().float.clone())
+    }
+
+    pub fn double_ty() -> Rc<Type> {
+        TYPES.with(|t| t.borrow().double.clone())
+    }
+
+    // The unsigned integer as wide as a pointer, which sizeof and
+    // offsetof give.
+    pub fn size_t() -> Rc<Type> {
+        TYPES.with(|t| {
+            let t = t.borrow();
+            match t.ptr_size {
+                4 => t.uint.clone(),
+                _ => t.ullong.clone(),
+            }
+        })
+    }
+
+    pub fn ptr_size() -> usize {
+        TYPES.with(|t| t.borrow().ptr_size)
+    }
+
+    // Sizes the types for `target`, which starts the table over if it
+    // was for another one.
+    pub fn set_target(target: &dyn Target) {
+        let layout = (
+            target.ptr_size(),
+            target.long_size(),
+            target.int_max_align(),
+        );
+        TYPES.with(|t| {
+            let mut t = t.borrow_mut();
+            if (t.ptr_size, t.long.size, t.llong.align) != layout {
+                *t = Types::new(layout.0, layout.1, layout.2);
+            }
+        })
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(self.ty, Ctype::Int | Ctype::Char | Ctype::Long)
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self.ty, Ctype::Float | Ctype::Double)
+    }
+
+    pub fn is_arithmetic(&self) -> bool {
+        self.is_integer() || self.is_float()
+    }
+
+    // The number of types made once and shared, so far.
+    pub fn interned() -> usize {
+        TYPES.with(|t| 9 + t.borrow().ptrs.len())
+    }
+
+    pub fn ptr_to(base: Rc<Type>) -> Rc<Type> {
+        TYPES.with(|t| {
+            let mut t = t.borrow_mut();
+            let size = t.ptr_size;
+            if !t.is_shared(&base) {
+                return Rc::new(Type::new(Ctype::Ptr(base), size));
+            }
+            t.ptrs
+                .entry(Rc::as_ptr(&base))
+                .or_insert_with(|| Rc::new(Type::new(Ctype::Ptr(base.clone()), size)))
+                .clone()
+        })
+    }
+
+    // Records that the incomplete struct `ty` is defined as `def`.
+    fn complete(ty: &Rc<Type>, def: Rc<Type>) {
+        TYPES.with(|t| {
+            let mut t = t.borrow_mut();
+            t.completions.insert(Rc::as_ptr(ty), (ty.clone(), def));
+        })
+    }
+
+    // The definition of the incomplete struct `ty`, if it has one by
+    // now.
+    pub fn definition(ty: &Type) -> Option<Rc<Type>> {
+        TYPES.with(|t| {
+            let t = t.borrow();
+            t.completions
+                .get(&(ty as *const Type))
+                .map(|(_, def)| def.clone())
+        })
+    }
+
+    // `ty`, or a pointer to it, with an incomplete struct replaced by
+    // its definition.
+    pub fn completed(ty: &Rc<Type>) -> Rc<Type> {
+        match ty.ty {
+            Ctype::Struct(ref members) if members.is_empty() => Type::definition(ty),
+            Ctype::Ptr(ref base) => Type::definition(base).map(Type::ptr_to),
+            _ => None,
+        }
+        .unwrap_or_else(|| ty.clone())
+    }
+
+    // `ty` qualified with _Atomic. Only the ints, longs and pointers
+    // that the __atomic builtins take are accessed atomically, and the
+    // qualifier changes nothing for other types.
+    fn atomic(ty: Rc<Type>) -> Rc<Type> {
+        if !matches!(ty.ty, Ctype::Int | Ctype::Long | Ctype::Ptr(_)) {
+            return ty;
+        }
+        Rc::new(Type {
+            is_atomic: true,
+            ..(*ty).clone()
+        })
+    }
+
+    pub fn ary_of(base: Rc<Type>, len: usize) -> Rc<Type> {
         let align = base.align;
         let size = base.size * len;
         let mut ty = Type::new(Ctype::Ary(base, len), size);
         ty.align = align;
-        ty
+        Rc::new(ty)
     }
 }
 
-pub struct Parser<'a> {
-    tokens: &'a Vec<Token>,
+// Case labels of a switch statement, collected while parsing its body.
+#[derive(Default)]
+struct SwitchCases {
+    vals: Vec<Node>, // Constant expressions, which sema evaluates
+    has_default: bool,
+}
+
+// A position in the token stream. Tokens are borrowed from the stream,
+// not from the parser, so a token can be held on to while parsing what
+// follows it, and nothing is copied out of it until a node needs it.
+struct Cursor<'a> {
+    tokens: &'a [Token], // Ends with an Eof token
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> &'a Token {
+        &self.tokens[self.pos]
+    }
+
+    fn prev(&self) -> &'a Token {
+        &self.tokens[self.pos - 1]
+    }
+
+    fn bump(&mut self) -> &'a Token {
+        let t = self.peek();
+        self.pos += 1;
+        t
+    }
+
+    fn back(&mut self) {
+        self.pos -= 1;
+    }
+}
+
+// Where the parser was, to go back to the state it was in after an
+// error.
+struct Checkpoint {
     pos: usize,
+    scopes: usize,
+    switches: usize,
+}
+
+pub struct Parser<'a> {
+    cur: Cursor<'a>,
     env: Env,
+    switches: Vec<SwitchCases>,
+    variadic: bool, // Whether the function being parsed takes "..."
+    depth: usize,   // Of nesting, see MAX_DEPTH
+    max_depth: usize,
+    pack: Option<usize>,       // The most that struct members are aligned to
+    packs: Vec<Option<usize>>, // Saved by "#pragma pack(push)"
+    // What is declared static at file scope. A later declaration of
+    // the same name is static too, with or without the keyword.
+    statics: HashSet<Symbol>,
+    std: Std, // What to warn about
+    errors: Vec<ParseError>,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(tokens: &'a Vec<Token>) -> Self {
+    pub fn new(tokens: &'a [Token], max_depth: usize) -> Self {
         Parser {
-            tokens,
-            pos: 0,
+            cur: Cursor { tokens, pos: 0 },
             env: Env::new(None),
+            switches: vec![],
+            variadic: false,
+            depth: 0,
+            max_depth,
+            pack: None,
+            packs: vec![],
+            statics: HashSet::new(),
+            std: Std::C11,
+            errors: vec![],
         }
     }
 
-    fn find_tag(&self, name: &str) -> Option<Type> {
-        let mut next: &Option<Box<Env>> = &Some(Box::new(self.env.clone()));
+    // Parses something nested in what is being parsed, such as an
+    // operand or a statement in a block.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let depth = self.depth;
+        self.deeper()?;
+        let x = f(self);
+        self.depth = depth;
+        x
+    }
+
+    // Goes one level deeper, such as to an operator taking the result
+    // of the previous one in `a + b + c`.
+    fn deeper(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(ParseError::TooDeep {
+                found: Box::new(Found::new(self.cur.peek())),
+                limit: self.max_depth,
+            });
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            pos: self.cur.pos,
+            scopes: self.env.depth(),
+            switches: self.switches.len(),
+        }
+    }
+
+    // Gets past the declaration or statement that started at `at` and
+    // had an error, to parse what follows it. It ends with the semicolon
+    // or the closing brace at its own level, or before a closing brace
+    // of the block it is in. Returns false if the input ends first.
+    fn recover(&mut self, at: Checkpoint) -> bool {
+        while self.env.depth() > at.scopes {
+            self.leave_scope();
+        }
+        self.switches.truncate(at.switches);
+        self.depth = 0;
+        // The error may have been at the end, having read past it.
+        self.cur.pos = self.cur.pos.min(self.cur.tokens.len() - 1);
+        let in_block = at.scopes > 1;
+        let mut braces = self.cur.tokens[at.pos..self.cur.pos]
+            .iter()
+            .map(|t| match t.ty {
+                TokenType::LeftBrace => 1,
+                TokenType::RightBrace => -1,
+                _ => 0,
+            })
+            .sum::<i32>()
+            .max(0);
         loop {
-            if let Some(ref e) = next {
-                let ty = e.tags.get(name);
-                if ty.is_some() {
-                    return ty.cloned();
+            let t = self.cur.bump();
+            match t.ty {
+                TokenType::Eof => {
+                    self.cur.back();
+                    return false;
                 }
-                next = &e.next;
-            } else {
-                return None;
+                TokenType::Semicolon if braces == 0 => return true,
+                TokenType::LeftBrace => braces += 1,
+                TokenType::RightBrace if braces == 0 => {
+                    if in_block {
+                        self.cur.back();
+                    }
+                    return true;
+                }
+                TokenType::RightBrace => {
+                    braces -= 1;
+                    if braces == 0 {
+                        return true;
+                    }
+                }
+                _ => {}
             }
         }
     }
 
-    fn find_typedef(&self, name: &str) -> Option<Type> {
-        let mut next: &Option<Box<Env>> = &Some(Box::new(self.env.clone()));
-        loop {
-            if let Some(ref e) = next {
-                let ty = e.typedefs.get(name);
-                if ty.is_some() {
-                    return ty.cloned();
-                }
-                next = &e.next;
-            } else {
-                return None;
+    fn enter_scope(&mut self) {
+        let env = mem::replace(&mut self.env, Env::new(None));
+        self.env = Env::new(Some(Box::new(env)));
+    }
+
+    fn leave_scope(&mut self) {
+        let next = self.env.next.take().unwrap();
+        self.env = *next;
+    }
+
+    fn find_tag(&self, name: Symbol) -> Option<&Rc<Type>> {
+        let mut env = Some(&self.env);
+        while let Some(e) = env {
+            if let Some(ty) = e.tags.get(&name) {
+                return Some(ty);
             }
+            env = e.next.as_deref();
         }
+        None
     }
 
-    fn expect(&mut self, ty: TokenType) {
-        let t = &self.tokens[self.pos];
+    fn find_typedef(&self, name: Symbol) -> Option<&Rc<Type>> {
+        let mut env = Some(&self.env);
+        while let Some(e) = env {
+            if let Some(ty) = e.typedefs.get(&name) {
+                return Some(ty);
+            }
+            env = e.next.as_deref();
+        }
+        None
+    }
+
+    // `ty` is always a punctuator or a keyword, which hold no data, so
+    // comparing against it allocates nothing.
+    fn expect(&mut self, ty: TokenType) -> Result<()> {
+        let t = self.cur.peek();
         if t.ty != ty {
-            t.bad_token(&format!("{:?} expected", ty));
+            return Err(ParseError::Expected {
+                expected: ty,
+                found: Box::new(Found::new(t)),
+                similar: None,
+            });
         }
-        self.pos += 1;
+        self.cur.bump();
+        Ok(())
     }
 
     fn consume(&mut self, ty: TokenType) -> bool {
-        let t = &self.tokens[self.pos];
-        if t.ty != ty {
+        if self.cur.peek().ty != ty {
             return false;
         }
-        self.pos += 1;
+        self.cur.bump();
         true
     }
 
     fn is_typename(&self, t: &Token) -> bool {
         use self::TokenType::*;
-        if let TokenType::Ident(ref name) = t.ty {
+        if let TokenType::Ident(name) = t.ty {
             return self.find_typedef(name).is_some();
         }
-        t.ty == Int || t.ty == Char || t.ty == Void || t.ty == Struct
+        t.ty == Int
+            || t.ty == Char
+            || t.ty == Long
+            || t.ty == Signed
+            || t.ty == Unsigned
+            || t.ty == Float
+            || t.ty == Double
+            || t.ty == Void
+            || t.ty == Struct
+            || t.ty == Atomic
+    }
+
+    fn is_local_declaration(&self) -> bool {
+        let t = self.cur.peek();
+        t.ty == TokenType::Auto
+            || t.ty == TokenType::Register
+            || t.ty == TokenType::Static
+            || self.is_typename(t)
     }
 
-    fn set_offset(members: &mut Vec<Node>) -> (usize, usize) {
+    // Each member is aligned to at most the bytes its `packs` entry
+    // says, if any. The members are Vardefs, as decl_specifiers checks.
+    fn set_offset(members: &mut [Node], packs: &[Option<usize>]) -> (usize, usize) {
         let mut off = 0;
         let mut align = 0;
-        for node in members {
+        for (node, pack) in members.iter_mut().zip(packs) {
             if let NodeType::Vardef(_, _, Scope::Local(offset)) = &mut node.op {
                 let t = &node.ty;
-                off = roundup(off, t.align);
+                let member_align = pack.map_or(t.align, |n| t.align.min(n));
+                off = roundup(off, member_align);
                 *offset = off;
                 off += t.size;
 
-                if align < t.align {
-                    align = t.align;
+                if align < member_align {
+                    align = member_align;
                 }
-            } else {
-                panic!();
             }
         }
         (off, align)
     }
 
-    fn add_member(ty: &mut Type, mut members: Vec<Node>) {
-        let (off, align) = Self::set_offset(&mut members);
+    fn add_member(ty: &mut Type, mut members: Vec<Node>, packs: &[Option<usize>]) {
+        let (off, align) = Self::set_offset(&mut members, packs);
         if let Ctype::Struct(ref mut members2) = ty.ty {
             *members2 = members;
         }
-        ty.size = roundup(off, align);
+        ty.align = align.max(1);
+        ty.size = roundup(off, ty.align);
     }
 
-    fn decl_specifiers(&mut self) -> Option<Type> {
-        let t = &self.tokens[self.pos];
-        self.pos += 1;
-        match t.ty {
-            TokenType::Ident(ref name) => {
+    fn incomplete_struct(tag: Symbol) -> Rc<Type> {
+        let mut ty = Type::new(Ctype::Struct(vec![]), 10);
+        ty.tag = Some(tag);
+        Rc::new(ty)
+    }
+
+    fn decl_specifiers(&mut self) -> Result<Option<Rc<Type>>> {
+        let t = self.cur.bump();
+        Ok(match t.ty {
+            TokenType::Ident(name) => {
                 if let Some(ty) = self.find_typedef(name) {
-                    return Some(ty.clone());
+                    Some(ty.clone())
                 } else {
-                    self.pos -= 1;
-                    return None;
+                    self.cur.back();
+                    None
                 }
             }
             TokenType::Int => Some(Type::int_ty()),
             TokenType::Char => Some(Type::char_ty()),
+            // long, long long, long int and long long int, and long
+            // double, which is a double
+            TokenType::Long => {
+                if self.cur.peek().ty == TokenType::Double {
+                    return self.decl_specifiers();
+                }
+                let ty = if self.consume(TokenType::Long) {
+                    Type::llong_ty()
+                } else {
+                    Type::long_ty()
+                };
+                self.consume(TokenType::Int);
+                Some(ty)
+            }
+            // Alone, or before char, int or one of the longs.
+            TokenType::Signed | TokenType::Unsigned => {
+                let unsigned = t.ty == TokenType::Unsigned;
+                let ty = match self.cur.peek().ty {
+                    TokenType::Char | TokenType::Int | TokenType::Long => {
+                        self.decl_specifiers_or_error()?
+                    }
+                    _ => Type::int_ty(),
+                };
+                Some(match (&ty.ty, unsigned) {
+                    (Ctype::Char, false) => Type::schar_ty(),
+                    (Ctype::Int, true) => Type::uint_ty(),
+                    (Ctype::Long, true) => Type::ullong_ty(),
+                    _ => ty,
+                })
+            }
             TokenType::Void => Some(Type::void_ty()),
+            TokenType::Float => Some(Type::float_ty()),
+            TokenType::Double => Some(Type::double_ty()),
+            // Loads, stores, ++, -- and += of an _Atomic object are
+            // lowered to the ops of the __atomic builtins (see gen_ir).
+            TokenType::Atomic => {
+                if self.consume(TokenType::LeftParen) {
+                    let ty = self.nested(Self::ctype)?;
+                    self.expect(TokenType::RightParen)?;
+                    Some(Type::atomic(ty))
+                } else {
+                    self.nested(Self::decl_specifiers)?.map(Type::atomic)
+                }
+            }
             TokenType::Struct => {
-                let mut tag_may: Option<String> = None;
-                let t = &self.tokens[self.pos];
-                if let TokenType::Ident(ref name) = t.ty {
-                    self.pos += 1;
-                    tag_may = Some(name.clone())
+                let mut tag_may: Option<Symbol> = None;
+                let t = self.cur.peek();
+                if let TokenType::Ident(name) = t.ty {
+                    self.cur.bump();
+                    tag_may = Some(name)
+                }
+
+                // The tag names an incomplete type until the members
+                // are read, so that they may point to it.
+                let mut incomplete = None;
+                if let Some(tag) = tag_may {
+                    if self.cur.peek().ty == TokenType::LeftBrace {
+                        let ty = match self.env.tags.get(&tag) {
+                            Some(ty) if matches!(ty.ty, Ctype::Struct(ref m) if m.is_empty()) => {
+                                ty.clone()
+                            }
+                            _ => Self::incomplete_struct(tag),
+                        };
+                        self.env.tags.insert(tag, ty.clone());
+                        incomplete = Some(ty);
+                    } else if self.find_tag(tag).is_none() {
+                        self.env.tags.insert(tag, Self::incomplete_struct(tag));
+                    }
                 }
 
                 let mut members = vec![];
+                let mut packs = vec![];
                 if self.consume(TokenType::LeftBrace) {
                     while !self.consume(TokenType::RightBrace) {
-                        members.push(self.declaration())
+                        if self.pragma()? {
+                            continue;
+                        }
+                        let t = self.cur.peek();
+                        let node = self.nested(Self::declaration)?;
+                        if !matches!(node.op, NodeType::Vardef(..)) {
+                            return invalid(t, "member declaration expected");
+                        }
+                        members.push(node);
+                        packs.push(self.pack);
                     }
                 }
 
-                let mut ty_may: Option<Type> = None;
-                if let Some(ref tag) = tag_may {
+                let mut ty_may: Option<Rc<Type>> = None;
+                if let Some(tag) = tag_may {
                     if members.is_empty() {
-                        ty_may = self.find_tag(&tag);
+                        ty_may = self.find_tag(tag).cloned();
                     }
                 }
-                let mut ty = ty_may.unwrap_or(Type::new(Ctype::Struct(vec![]), 10));
+                let mut ty =
+                    ty_may.unwrap_or_else(|| Rc::new(Type::new(Ctype::Struct(vec![]), 10)));
 
                 if !members.is_empty() {
-                    Self::add_member(&mut ty, members);
+                    Self::add_member(Rc::make_mut(&mut ty), members, &packs);
+                    Rc::make_mut(&mut ty).tag = tag_may;
                     if let Some(tag) = tag_may {
                         self.env.tags.insert(tag, ty.clone());
                     }
+                    if let Some(incomplete) = incomplete {
+                        Type::complete(&incomplete, ty.clone());
+                    }
                 }
-                Some(ty.clone())
+                Some(ty)
             }
-            _ => t.bad_token("typename expected"),
+            _ => return invalid(t, "typename expected"),
+        })
+    }
+
+    fn decl_specifiers_or_error(&mut self) -> Result<Rc<Type>> {
+        let t = self.cur.peek();
+        match self.decl_specifiers()? {
+            Some(ty) => Ok(ty),
+            None => invalid(t, "typename expected"),
         }
     }
 
-    fn ident(&mut self) -> String {
-        let t = &self.tokens[self.pos];
-        if let TokenType::Ident(ref name) = t.ty {
-            self.pos += 1;
-            name.clone()
+    fn ident(&mut self) -> Result<Symbol> {
+        let t = self.cur.peek();
+        if let TokenType::Ident(name) = t.ty {
+            self.cur.bump();
+            Ok(name)
         } else {
-            t.bad_token("variable name expected");
+            invalid(t, "variable name expected")
         }
     }
 
-    fn primary(&mut self) -> Node {
-        let t = &self.tokens[self.pos];
-        self.pos += 1;
-        match t.ty {
-            TokenType::Num(val) => Node::new_num(val),
-            TokenType::Str(ref str, len) => {
-                let mut node = Node::new(NodeType::Str(str.clone(), len));
-                node.ty = Box::new(Type::ary_of(Box::new(Type::char_ty()), len));
-                node
+    // Reads the string literal `t` and any that follow it, which are
+    // concatenated.
+    fn string_literal(&mut self, t: &Token) -> String {
+        let mut s = t.str_value();
+        while self.cur.peek().ty == TokenType::Str {
+            s.push_str(&self.cur.bump().str_value());
+        }
+        s
+    }
+
+    fn primary(&mut self) -> Result<Node> {
+        let t = self.cur.bump();
+        Ok(match t.ty {
+            TokenType::Num(val, ty) => {
+                let mut node = Node::new_num(val);
+                node.ty = match ty {
+                    NumType::Int => Type::int_ty(),
+                    NumType::UInt => Type::uint_ty(),
+                    // A long constant that a 4-byte long can't hold is
+                    // a long long.
+                    NumType::Long if val as i32 as i64 == val => Type::long_ty(),
+                    NumType::ULong if val as u32 as i64 == val => Type::ulong_ty(),
+                    NumType::Long | NumType::LLong => Type::llong_ty(),
+                    NumType::ULong | NumType::ULLong => Type::ullong_ty(),
+                };
+                node.at(t)
+            }
+            TokenType::FloatNum => {
+                let (val, is_float) = match t.float_value() {
+                    Some(value) => value,
+                    None => {
+                        return invalid(t, "hexadecimal floating constant requires an exponent")
+                    }
+                };
+                let mut node = Node::new(NodeType::FNum(val));
+                node.ty = if is_float {
+                    Type::float_ty()
+                } else {
+                    Type::double_ty()
+                };
+                node.at(t)
             }
-            TokenType::Ident(ref name) => {
+            TokenType::Str => {
+                let str = self.string_literal(t);
+                let len = str.len() + 1; // With the terminating '\0'
+                let mut node = Node::new(NodeType::Str(str, len));
+                node.ty = Type::ary_of(Type::char_ty(), len);
+                node.at(t)
+            }
+            TokenType::Ident(name) => {
                 if !self.consume(TokenType::LeftParen) {
-                    return Node::new(NodeType::Ident(name.clone()));
+                    return Ok(Node::new(NodeType::Ident(name)).at(t));
                 }
 
-                let mut args = vec![];
-                if self.consume(TokenType::RightParen) {
-                    return Node::new(NodeType::Call(name.clone(), args));
+                // The second argument is a type name.
+                if name == "__builtin_va_arg" {
+                    let ap = self.nested(Self::assign)?;
+                    self.expect(TokenType::Comma)?;
+                    let ty = self.ctype()?;
+                    self.expect(TokenType::RightParen)?;
+                    let mut node = Node::new(NodeType::Call(name, vec![ap]));
+                    node.ty = ty;
+                    return Ok(node.at(t));
                 }
-
-                args.push(self.assign());
-                while self.consume(TokenType::Comma) {
-                    args.push(self.assign());
+                // A type, then a member of it.
+                if name == "__builtin_offsetof" {
+                    let ty = self.ctype()?;
+                    self.expect(TokenType::Comma)?;
+                    let member = self.member_designator()?;
+                    self.expect(TokenType::RightParen)?;
+                    let mut node = new_expr!(NodeType::Offsetof, member);
+                    node.ty = ty;
+                    return Ok(node.at(t));
+                }
+                if name == "__builtin_va_start" && !self.variadic {
+                    return invalid(t, "va_start used in function with fixed args");
                 }
-                self.expect(TokenType::RightParen);
-                Node::new(NodeType::Call(name.clone(), args))
+
+                Node::new(NodeType::Call(name, self.call_args()?)).at(t)
             }
             TokenType::LeftParen => {
                 if self.consume(TokenType::LeftBrace) {
-                    let stmt = Box::new(self.compound_stmt());
-                    self.expect(TokenType::RightParen);
-                    return Node::new(NodeType::StmtExpr(stmt));
+                    let stmt = Box::new(self.nested(Self::compound_stmt)?);
+                    self.expect(TokenType::RightParen)?;
+                    return Ok(Node::new(NodeType::StmtExpr(stmt)).at(t));
                 }
-                let node = self.expr();
-                self.expect(TokenType::RightParen);
+                let node = self.nested(Self::expr)?;
+                self.expect(TokenType::RightParen)?;
                 node
             }
-            _ => t.bad_token("number expected"),
+            _ => return invalid(t, "number expected"),
+        })
+    }
+
+    // A member of a struct and the members and elements within it, as
+    // `a.b[1].c`, which is an expression of them as if `a` were a
+    // variable.
+    fn member_designator(&mut self) -> Result<Node> {
+        let mut node = Node::new(NodeType::Ident(self.ident()?));
+        loop {
+            if self.consume(TokenType::Dot) {
+                node = Node::new(NodeType::Dot(Box::new(node), self.ident()?, 0));
+            } else if self.consume(TokenType::LeftBracket) {
+                node = new_expr!(
+                    NodeType::Deref,
+                    Node::new_binop(TokenType::Plus, node, self.nested(Self::expr)?)
+                );
+                self.expect(TokenType::RightBracket)?;
+            } else {
+                return Ok(node);
+            }
         }
     }
 
-    fn postfix(&mut self) -> Node {
-        let mut lhs = self.primary();
+    // The arguments of a call, after its `(`.
+    fn call_args(&mut self) -> Result<Vec<Node>> {
+        let mut args = vec![];
+        if self.consume(TokenType::RightParen) {
+            return Ok(args);
+        }
+
+        args.push(self.nested(Self::assign)?);
+        while self.consume(TokenType::Comma) {
+            args.push(self.nested(Self::assign)?);
+        }
+        self.expect(TokenType::RightParen)?;
+        Ok(args)
+    }
+
+    fn postfix(&mut self) -> Result<Node> {
+        let mut lhs = self.primary()?;
+        let depth = self.depth;
 
         loop {
+            self.deeper()?;
+            let t = self.cur.peek();
             if self.consume(TokenType::Inc) {
-                lhs = new_expr!(NodeType::PostInc, lhs);
+                lhs = new_expr!(NodeType::PostInc, lhs).at(t);
                 continue;
             }
 
             if self.consume(TokenType::Dec) {
-                lhs = new_expr!(NodeType::PostDec, lhs);
+                lhs = new_expr!(NodeType::PostDec, lhs).at(t);
                 continue;
             }
 
             if self.consume(TokenType::Dot) {
                 // TODO: Use new_expr!
-                lhs = Node::new(NodeType::Dot(Box::new(lhs), self.ident(), 0));
+                lhs = Node::new(NodeType::Dot(Box::new(lhs), self.ident()?, 0)).at(t);
                 continue;
             }
 
             if self.consume(TokenType::Arrow) {
                 lhs = Node::new(NodeType::Dot(
-                    Box::new(new_expr!(NodeType::Deref, lhs)),
-                    self.ident(),
+                    Box::new(new_expr!(NodeType::Deref, lhs).at(t)),
+                    self.ident()?,
                     0,
-                ));
+                ))
+                .at(t);
                 continue;
             }
 
             if self.consume(TokenType::LeftBracket) {
                 lhs = new_expr!(
                     NodeType::Deref,
-                    Node::new_binop(TokenType::Plus, lhs, self.assign())
-                );
-                self.expect(TokenType::RightBracket);
+                    Node::new_binop(TokenType::Plus, lhs, self.nested(Self::assign)?).at(t)
+                )
+                .at(t);
+                self.expect(TokenType::RightBracket)?;
                 continue;
             }
-            return lhs;
+
+            // A call of a name is made by primary().
+            if self.consume(TokenType::LeftParen) {
+                lhs = Node::new(NodeType::CallPtr(Box::new(lhs), self.call_args()?)).at(t);
+                continue;
+            }
+            self.depth = depth;
+            return Ok(lhs);
         }
     }
 
-    fn unary(&mut self) -> Node {
+    fn unary(&mut self) -> Result<Node> {
+        let op = self.cur.peek();
+        // `+x` is `0 + x`, which promotes x and isn't an lvalue.
+        if self.consume(TokenType::Plus) {
+            return Ok(Node::new_binop(
+                TokenType::Plus,
+                Node::new_num(0),
+                self.nested(Self::unary)?,
+            )
+            .at(op));
+        }
         if self.consume(TokenType::Minus) {
-            return new_expr!(NodeType::Neg, self.unary());
+            return Ok(new_expr!(NodeType::Neg, self.nested(Self::unary)?).at(op));
         }
         if self.consume(TokenType::Mul) {
-            return new_expr!(NodeType::Deref, self.unary());
+            return Ok(new_expr!(NodeType::Deref, self.nested(Self::unary)?).at(op));
         }
         if self.consume(TokenType::And) {
-            return new_expr!(NodeType::Addr, self.unary());
+            let t = self.cur.peek();
+            let expr = self.nested(Self::unary)?;
+            if let NodeType::Ident(name) = expr.op {
+                if self.is_register(name) {
+                    return invalid(
+                        t,
+                        &format!("address of register variable '{}' requested", name),
+                    );
+                }
+            }
+            return Ok(new_expr!(NodeType::Addr, expr).at(op));
         }
         if self.consume(TokenType::Exclamation) {
-            return new_expr!(NodeType::Exclamation, self.unary());
+            return Ok(new_expr!(NodeType::Exclamation, self.nested(Self::unary)?).at(op));
+        }
+        if self.consume(TokenType::Tilde) {
+            return Ok(new_expr!(NodeType::Tilde, self.nested(Self::unary)?).at(op));
         }
         if self.consume(TokenType::Sizeof) {
-            return new_expr!(NodeType::Sizeof, self.unary());
+            if let Some(ty) = self.type_operand("sizeof")? {
+                return Ok(Node::new_int(ty.size as i32).at(op));
+            }
+            return Ok(new_expr!(NodeType::Sizeof, self.nested(Self::unary)?).at(op));
         }
         if self.consume(TokenType::Alignof) {
-            return new_expr!(NodeType::Alignof, self.unary());
+            if let Some(ty) = self.type_operand("_Alignof")? {
+                return Ok(Node::new_int(ty.align as i32).at(op));
+            }
+            return Ok(new_expr!(NodeType::Alignof, self.nested(Self::unary)?).at(op));
+        }
+
+        // A cast, `(type-name) expr`.
+        if self.consume(TokenType::LeftParen) {
+            if self.is_typename(self.cur.peek()) {
+                let ty = self.ctype()?;
+                self.expect(TokenType::RightParen)?;
+                let mut node = new_expr!(NodeType::Cast, self.nested(Self::unary)?);
+                node.ty = ty;
+                return Ok(node.at(op));
+            }
+            self.cur.back();
         }
 
         if self.consume(TokenType::Inc) {
-            return Node::new_binop(TokenType::AddEQ, self.unary(), Node::new_num(1));
+            return Ok(Node::new_binop(
+                TokenType::AddEQ,
+                self.nested(Self::unary)?,
+                Node::new_num(1),
+            )
+            .at(op));
         }
         if self.consume(TokenType::Dec) {
-            return Node::new_binop(TokenType::SubEQ, self.unary(), Node::new_num(1));
+            return Ok(Node::new_binop(
+                TokenType::SubEQ,
+                self.nested(Self::unary)?,
+                Node::new_num(1),
+            )
+            .at(op));
         }
 
         self.postfix()
     }
 
-    fn mul(&mut self) -> Node {
-        let mut lhs = self.unary();
+    // The `(type-name)` operand of sizeof or _Alignof, if it has one
+    // rather than an expression. The type must be complete.
+    fn type_operand(&mut self, op: &str) -> Result<Option<Rc<Type>>> {
+        if !self.consume(TokenType::LeftParen) {
+            return Ok(None);
+        }
+        let t = self.cur.peek();
+        if !self.is_typename(t) {
+            self.cur.back();
+            return Ok(None);
+        }
+        let ty = self.ctype()?;
+        let ty = Type::completed(&self.read_array(ty)?);
+        if let Ctype::Struct(ref members) = ty.ty {
+            if members.is_empty() && ty.tag.is_some() {
+                return invalid(
+                    t,
+                    &format!("invalid application of '{}' to incomplete type", op),
+                );
+            }
+        }
+        self.expect(TokenType::RightParen)?;
+        Ok(Some(ty))
+    }
+
+    fn mul(&mut self) -> Result<Node> {
+        let mut lhs = self.unary()?;
+        let depth = self.depth;
 
         loop {
+            self.deeper()?;
+            let t = self.cur.peek();
             if self.consume(TokenType::Mul) {
-                lhs = Node::new_binop(TokenType::Mul, lhs, self.unary());
+                lhs = Node::new_binop(TokenType::Mul, lhs, self.unary()?).at(t);
             } else if self.consume(TokenType::Div) {
-                lhs = Node::new_binop(TokenType::Div, lhs, self.unary());
+                lhs = Node::new_binop(TokenType::Div, lhs, self.unary()?).at(t);
             } else if self.consume(TokenType::Mod) {
-                lhs = Node::new_binop(TokenType::Mod, lhs, self.unary());
+                lhs = Node::new_binop(TokenType::Mod, lhs, self.unary()?).at(t);
             } else {
-                return lhs;
+                break;
             }
         }
+        self.depth = depth;
+        Ok(lhs)
     }
 
-    fn add(&mut self) -> Node {
-        let mut lhs = self.mul();
+    fn add(&mut self) -> Result<Node> {
+        let mut lhs = self.mul()?;
+        let depth = self.depth;
 
         loop {
+            self.deeper()?;
+            let t = self.cur.peek();
             if self.consume(TokenType::Plus) {
-                lhs = Node::new_binop(TokenType::Plus, lhs, self.mul());
+                lhs = Node::new_binop(TokenType::Plus, lhs, self.mul()?).at(t);
             } else if self.consume(TokenType::Minus) {
-                lhs = Node::new_binop(TokenType::Minus, lhs, self.mul());
+                lhs = Node::new_binop(TokenType::Minus, lhs, self.mul()?).at(t);
             } else {
-                return lhs;
+                break;
             }
         }
+        self.depth = depth;
+        Ok(lhs)
     }
 
-    fn shift(&mut self) -> Node {
-        let mut lhs = self.add();
+    fn shift(&mut self) -> Result<Node> {
+        let mut lhs = self.add()?;
+        let depth = self.depth;
         loop {
+            self.deeper()?;
+            let t = self.cur.peek();
             if self.consume(TokenType::SHL) {
-                lhs = Node::new_binop(TokenType::SHL, lhs, self.add());
+                lhs = Node::new_binop(TokenType::SHL, lhs, self.add()?).at(t);
             } else if self.consume(TokenType::SHR) {
-                lhs = Node::new_binop(TokenType::SHR, lhs, self.add());
+                lhs = Node::new_binop(TokenType::SHR, lhs, self.add()?).at(t);
             } else {
-                return lhs;
+                break;
             }
         }
+        self.depth = depth;
+        Ok(lhs)
     }
 
-    fn relational(&mut self) -> Node {
-        let mut lhs = self.shift();
+    fn relational(&mut self) -> Result<Node> {
+        let mut lhs = self.shift()?;
+        let depth = self.depth;
         loop {
+            self.deeper()?;
+            let t = self.cur.peek();
             if self.consume(TokenType::LeftAngleBracket) {
-                lhs = Node::new_binop(TokenType::LeftAngleBracket, lhs, self.shift());
+                lhs = Node::new_binop(TokenType::LeftAngleBracket, lhs, self.shift()?).at(t);
             } else if self.consume(TokenType::RightAngleBracket) {
-                lhs = Node::new_binop(TokenType::LeftAngleBracket, self.shift(), lhs);
+                lhs = Node::new_binop(TokenType::RightAngleBracket, lhs, self.shift()?).at(t);
             } else if self.consume(TokenType::LE) {
-                lhs = Node::new_binop(TokenType::LE, lhs, self.shift())
+                lhs = Node::new_binop(TokenType::LE, lhs, self.shift()?).at(t);
             } else if self.consume(TokenType::GE) {
-                lhs = Node::new_binop(TokenType::LE, self.shift(), lhs);
+                lhs = Node::new_binop(TokenType::GE, lhs, self.shift()?).at(t);
             } else {
-                return lhs;
+                break;
             }
         }
+        self.depth = depth;
+        Ok(lhs)
     }
 
-    fn equality(&mut self) -> Node {
-        let mut lhs = self.relational();
+    fn equality(&mut self) -> Result<Node> {
+        let mut lhs = self.relational()?;
+        let depth = self.depth;
         loop {
+            self.deeper()?;
+            let t = self.cur.peek();
             if self.consume(TokenType::EQ) {
-                lhs = Node::new_binop(TokenType::EQ, lhs, self.relational());
+                lhs = Node::new_binop(TokenType::EQ, lhs, self.relational()?).at(t);
             } else if self.consume(TokenType::NE) {
-                lhs = Node::new_binop(TokenType::NE, lhs, self.relational());
+                lhs = Node::new_binop(TokenType::NE, lhs, self.relational()?).at(t);
             } else {
-                return lhs;
+                break;
             }
         }
+        self.depth = depth;
+        Ok(lhs)
     }
 
-    fn bit_and(&mut self) -> Node {
-        let mut lhs = self.equality();
+    fn bit_and(&mut self) -> Result<Node> {
+        let mut lhs = self.equality()?;
+        let depth = self.depth;
         while self.consume(TokenType::And) {
-            lhs = Node::new_binop(TokenType::And, lhs, self.equality());
+            let t = self.cur.prev();
+            self.deeper()?;
+            lhs = Node::new_binop(TokenType::And, lhs, self.equality()?).at(t);
         }
-        lhs
+        self.depth = depth;
+        Ok(lhs)
     }
 
-    fn bit_xor(&mut self) -> Node {
-        let mut lhs = self.bit_and();
+    fn bit_xor(&mut self) -> Result<Node> {
+        let mut lhs = self.bit_and()?;
+        let depth = self.depth;
         while self.consume(TokenType::Hat) {
-            lhs = Node::new_binop(TokenType::Hat, lhs, self.bit_and());
+            let t = self.cur.prev();
+            self.deeper()?;
+            lhs = Node::new_binop(TokenType::Hat, lhs, self.bit_and()?).at(t);
         }
-        lhs
+        self.depth = depth;
+        Ok(lhs)
     }
 
-    fn bit_or(&mut self) -> Node {
-        let mut lhs = self.bit_xor();
+    fn bit_or(&mut self) -> Result<Node> {
+        let mut lhs = self.bit_xor()?;
+        let depth = self.depth;
         while self.consume(TokenType::VerticalBar) {
-            lhs = Node::new_binop(TokenType::VerticalBar, lhs, self.bit_xor());
+            let t = self.cur.prev();
+            self.deeper()?;
+            lhs = Node::new_binop(TokenType::VerticalBar, lhs, self.bit_xor()?).at(t);
         }
-        lhs
+        self.depth = depth;
+        Ok(lhs)
     }
 
-    fn logand(&mut self) -> Node {
-        let mut lhs = self.bit_or();
+    fn logand(&mut self) -> Result<Node> {
+        let mut lhs = self.bit_or()?;
+        let depth = self.depth;
         while self.consume(TokenType::Logand) {
-            lhs = Node::new_binop(TokenType::Logand, lhs, self.logand());
+            let t = self.cur.prev();
+            self.deeper()?;
+            lhs = Node::new_binop(TokenType::Logand, lhs, self.bit_or()?).at(t);
         }
-        lhs
+        self.depth = depth;
+        Ok(lhs)
     }
 
-    fn logor(&mut self) -> Node {
-        let mut lhs = self.logand();
+    fn logor(&mut self) -> Result<Node> {
+        let mut lhs = self.logand()?;
+        let depth = self.depth;
         while self.consume(TokenType::Logor) {
-            lhs = Node::new_binop(TokenType::Logor, lhs, self.logand());
+            let t = self.cur.prev();
+            self.deeper()?;
+            lhs = Node::new_binop(TokenType::Logor, lhs, self.logand()?).at(t);
         }
-        lhs
+        self.depth = depth;
+        Ok(lhs)
     }
 
-    fn conditional(&mut self) -> Node {
-        let cond = self.logor();
+    fn conditional(&mut self) -> Result<Node> {
+        let cond = self.logor()?;
+        let t = self.cur.peek();
         if !self.consume(TokenType::Question) {
-            return cond;
+            return Ok(cond);
         }
-        let then = self.expr();
-        self.expect(TokenType::Colon);
-        let els = self.conditional();
-        Node::new(NodeType::Ternary(
+        let then = self.nested(Self::expr)?;
+        self.expect(TokenType::Colon)?;
+        let els = self.nested(Self::conditional)?;
+        Ok(Node::new(NodeType::Ternary(
             Box::new(cond),
             Box::new(then),
             Box::new(els),
         ))
+        .at(t))
     }
 
     fn assign_op(ty: &TokenType) -> Option<&TokenType> {
@@ -576,336 +1651,837 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn assign(&mut self) -> Node {
-        let lhs = self.conditional();
-        if let Some(op) = Self::assign_op(&self.tokens[self.pos].ty) {
-            self.pos += 1;
-            Node::new_binop(op.clone(), lhs, self.assign())
+    fn assign(&mut self) -> Result<Node> {
+        let lhs = self.conditional()?;
+        let t = self.cur.peek();
+        if let Some(op) = Self::assign_op(&t.ty) {
+            self.cur.bump();
+            Ok(Node::new_binop(op.clone(), lhs, self.nested(Self::assign)?).at(t))
         } else {
-            lhs
+            Ok(lhs)
         }
     }
 
-    fn expr(&mut self) -> Node {
-        let lhs = self.assign();
-        if !self.consume(TokenType::Comma) {
-            return lhs;
+    fn expr(&mut self) -> Result<Node> {
+        let mut lhs = self.assign()?;
+        let depth = self.depth;
+        while self.consume(TokenType::Comma) {
+            let t = self.cur.prev();
+            self.deeper()?;
+            lhs = Node::new_binop(TokenType::Comma, lhs, self.assign()?).at(t);
         }
-        Node::new_binop(TokenType::Comma, lhs, self.expr())
+        self.depth = depth;
+        Ok(lhs)
     }
 
-    fn ctype(&mut self) -> Type {
-        let t = &self.tokens[self.pos];
-        if let Some(mut ty) = self.decl_specifiers() {
+    fn ctype(&mut self) -> Result<Rc<Type>> {
+        let t = self.cur.peek();
+        if let Some(mut ty) = self.decl_specifiers()? {
+            let depth = self.depth;
             while self.consume(TokenType::Mul) {
-                ty = Type::ptr_to(Box::new(ty));
+                self.deeper()?;
+                ty = Type::ptr_to(ty);
             }
-            ty
+            self.depth = depth;
+            Ok(ty)
         } else {
-            t.bad_token("typename expected");
+            invalid(t, "typename expected")
         }
     }
 
-    fn read_array(&mut self, mut ty: Box<Type>) -> Type {
+    fn read_array(&mut self, mut ty: Rc<Type>) -> Result<Rc<Type>> {
         let mut v: Vec<usize> = vec![];
+        let depth = self.depth;
         while self.consume(TokenType::LeftBracket) {
+            self.deeper()?;
             if self.consume(TokenType::RightBracket) {
+                // Only the outermost size can be left out.
+                if !v.is_empty() {
+                    return invalid(self.cur.prev(), "array type has incomplete element type");
+                }
                 v.push(0); // temporary value
                 continue;
             }
 
-            let len = self.expr();
+            let t = self.cur.peek();
+            let len = self.expr()?;
             if let NodeType::Num(n) = len.op {
+                if n < 0 {
+                    return invalid(t, "array size is negative");
+                }
                 v.push(n as usize);
-                self.expect(TokenType::RightBracket);
+                self.expect(TokenType::RightBracket)?;
             } else {
-                panic!("number expected");
+                return invalid(t, "number expected");
             }
         }
 
+        self.depth = depth;
         v.reverse();
         for val in v {
-            ty = Box::new(Type::ary_of(ty, val));
+            // Backends address memory with 32-bit offsets.
+            if ty.size.saturating_mul(val) > i32::MAX as usize {
+                return invalid(self.cur.prev(), "array is too large");
+            }
+            ty = Type::ary_of(ty, val);
         }
-        *ty
+        Ok(ty)
     }
 
-    fn array_init_rval(&mut self, ident: Node) -> Node {
-        let mut init = vec![];
-        let mut i = 0;
-        loop {
-            let val = self.primary();
-            let node = new_expr!(
-                NodeType::Deref,
-                Node::new_binop(TokenType::Plus, ident.clone(), Node::new(NodeType::Num(i)))
-            );
-            init.push(Node::new(NodeType::ExprStmt(Box::new(Node::new_binop(
-                TokenType::Equal,
-                node,
-                val,
-            )))));
+    // An initializer: an expression, or a list of them in braces,
+    // which may nest and may end with a comma.
+    fn initializer(&mut self) -> Result<Node> {
+        if !self.consume(TokenType::LeftBrace) {
+            return self.assign();
+        }
+        let mut items = vec![];
+        while !self.consume(TokenType::RightBrace) {
+            items.push(self.nested(|p| p.designation())?);
             if !self.consume(TokenType::Comma) {
+                self.expect(TokenType::RightBrace)?;
                 break;
             }
-            i += 1;
         }
-        self.expect(TokenType::RightBrace);
-        Node::new(NodeType::VecStmt(init))
+        Ok(Node::new(NodeType::InitList(items)))
     }
 
-    fn update_ptr_to(&mut self, src: &mut Box<Type>, dst: Box<Type>) {
-        match src.ty {
-            Ctype::Ptr(ref mut ptr_to) => self.update_ptr_to(ptr_to, dst),
-            _ => *src = dst,
+    // An item of an initializer list, with an array index or a GNU
+    // range of them before it or not.
+    fn designation(&mut self) -> Result<Node> {
+        let t = self.cur.peek();
+        if !self.consume(TokenType::LeftBracket) {
+            return self.initializer();
         }
+        self.std
+            .require(Std::C99, "a designated initializer", t.location());
+        let first = self.conditional()?;
+        let mut last = None;
+        if self.consume(TokenType::Ellipsis) {
+            last = Some(Box::new(self.conditional()?));
+        }
+        self.expect(TokenType::RightBracket)?;
+        self.expect(TokenType::Equal)?;
+        let init = self.initializer()?;
+        Ok(Node::new(NodeType::Designator(
+            Box::new(first),
+            last,
+            Box::new(init),
+        )))
+    }
+
+    // Puts `dst` where the innermost type of `src` is, under its
+    // pointers and arrays, as in `(*fp[2])` of `int (*fp[2])(int)`.
+    fn update_ptr_to(&mut self, src: &mut Rc<Type>, dst: Rc<Type>) {
+        *src = match src.ty {
+            Ctype::Ptr(ref ptr_to) => {
+                let mut ptr_to = ptr_to.clone();
+                self.update_ptr_to(&mut ptr_to, dst);
+                Type::ptr_to(ptr_to)
+            }
+            Ctype::Ary(ref ary_of, len) => {
+                let mut ary_of = ary_of.clone();
+                self.update_ptr_to(&mut ary_of, dst);
+                Type::ary_of(ary_of, len)
+            }
+            _ => dst,
+        }
+    }
+
+    // The type of a function returning `ret`, after the `(` of its
+    // parameters, whose names may be left out. `(void)` takes none.
+    fn func_type(&mut self, ret: Rc<Type>) -> Result<Rc<Type>> {
+        let mut params = vec![];
+        let mut variadic = false;
+        if !self.consume(TokenType::RightParen) {
+            loop {
+                if self.consume(TokenType::Ellipsis) {
+                    variadic = true;
+                    break;
+                }
+                let ty = self.ctype()?;
+                if let TokenType::Ident(_) = self.cur.peek().ty {
+                    self.cur.bump();
+                }
+                let ty = self.read_array(ty)?;
+                match ty.ty {
+                    Ctype::Ary(ref ary_of, _) => params.push(Type::ptr_to(ary_of.clone())),
+                    Ctype::Void => (),
+                    _ => params.push(ty),
+                }
+                if !self.consume(TokenType::Comma) {
+                    break;
+                }
+            }
+            self.expect(TokenType::RightParen)?;
+        }
+        Ok(Rc::new(Type::new(
+            Ctype::Func(ret, params, variadic, false),
+            0,
+        )))
     }
 
-    fn direct_decl(&mut self, ty: Box<Type>) -> Node {
-        let t = &self.tokens[self.pos];
-        let mut placeholder = Box::new(Type::default());
+    fn direct_decl(&mut self, ty: Rc<Type>) -> Result<Node> {
+        let t = self.cur.peek();
+        let mut placeholder = Type::int_ty();
         let mut node;
 
         if let TokenType::Ident(_) = t.ty {
-            node = Node::new(NodeType::Vardef(self.ident(), None, Scope::Local(0)));
+            node = Node::new(NodeType::Vardef(self.ident()?, None, Scope::Local(0)));
         } else if self.consume(TokenType::LeftParen) {
-            node = self.declarator(&mut placeholder);
-            self.expect(TokenType::RightParen);
+            node = self.nested(|p| p.declarator(&mut placeholder))?;
+            // The initializer comes after the parentheses.
+            if !matches!(node.op, NodeType::Vardef(_, None, _)) {
+                return invalid(t, "bad direct-declarator");
+            }
+            self.expect(TokenType::RightParen)?;
         } else {
-            t.bad_token("bad direct-declarator");
+            return invalid(t, "bad direct-declarator");
         }
 
-        // Read the second half of type name (e.g. `[3][5]`).
-        let ty = self.read_array(ty);
-        self.update_ptr_to(&mut node.ty, Box::new(ty));
+        // Read the second half of type name (e.g. `[3][5]`), or the
+        // parameters of the function a declarator in parentheses
+        // points to (e.g. `(int, char *)`).
+        let ty = if t.ty == TokenType::LeftParen && self.consume(TokenType::LeftParen) {
+            self.func_type(ty)?
+        } else {
+            self.read_array(ty)?
+        };
+        self.update_ptr_to(&mut node.ty, ty);
 
         // Read an initializer.
-        let init: Option<Box<Node>>;
         if self.consume(TokenType::Equal) {
-            // Assign a value when initializing an array.
-            if let TokenType::Ident(ref name) = t.ty {
-                if self.consume(TokenType::LeftBrace) {
-                    let mut stmts = vec![];
-                    let mut ary_declaration =
-                        Node::new(NodeType::Vardef(name.clone(), None, Scope::Local(0)));
-                    ary_declaration.ty = node.ty;
-                    stmts.push(ary_declaration);
-                    let init_ary = self.array_init_rval(Node::new(NodeType::Ident(name.clone())));
-                    stmts.push(init_ary);
-                    return Node::new(NodeType::VecStmt(stmts));
-                }
-            }
-
-            init = Some(Box::new(self.assign()));
+            let init = Some(Box::new(self.initializer()?));
             match node.op {
                 NodeType::Vardef(_, ref mut init2, _) => *init2 = init,
                 _ => unreachable!(),
             }
         }
-        node
+        Ok(node)
     }
 
-    fn declarator(&mut self, ty: &mut Type) -> Node {
+    fn declarator(&mut self, ty: &mut Rc<Type>) -> Result<Node> {
+        let depth = self.depth;
         while self.consume(TokenType::Mul) {
-            *ty = Type::ptr_to(Box::new(ty.clone()));
+            self.deeper()?;
+            *ty = Type::ptr_to(ty.clone());
         }
-        self.direct_decl(Box::new(ty.clone()))
+        let node = self.direct_decl(ty.clone())?;
+        self.depth = depth;
+        Ok(node)
     }
 
-    fn declaration(&mut self) -> Node {
-        let mut ty = self.decl_specifiers().unwrap();
-        let node = self.declarator(&mut ty);
-        self.expect(TokenType::Semicolon);
-        node
+    fn declaration(&mut self) -> Result<Node> {
+        let mut ty = self.decl_specifiers_or_error()?;
+        let node = self.declarator(&mut ty)?;
+        self.expect(TokenType::Semicolon)?;
+        Ok(node)
     }
 
-    fn param_declaration(&mut self) -> Node {
-        let mut ty = self.decl_specifiers().unwrap();
-        let mut node = self.declarator(&mut ty);
-        if let Ctype::Ary(ary_of, _) = node.ty.ty {
-            node.ty = Box::new(Type::ptr_to(ary_of));
+    // A declaration in a block, which may be `auto` or `register`.
+    fn local_declaration(&mut self) -> Result<Node> {
+        let t = self.cur.peek();
+        if t.ty == TokenType::Static {
+            return invalid(t, "static local variables are not supported");
         }
-        node
+        let register = self.consume(TokenType::Register);
+        let has_storage = register || self.consume(TokenType::Auto);
+        let mut ty = self.storage_type(has_storage)?;
+        if self.tag_declaration(&ty) {
+            return Ok(Node::new(NodeType::Null));
+        }
+        let node = self.declarator(&mut ty)?;
+        self.expect(TokenType::Semicolon)?;
+        self.declare_local(&node, register);
+        Ok(node)
+    }
+
+    fn param_declaration(&mut self) -> Result<Node> {
+        let register = self.consume(TokenType::Register);
+        let mut ty = self.storage_type(register)?;
+        let mut node = self.declarator(&mut ty)?;
+        if let Ctype::Ary(ref ary_of, _) = node.ty.ty {
+            node.ty = Type::ptr_to(ary_of.clone());
+        }
+        self.declare_local(&node, register);
+        Ok(node)
+    }
+
+    // The type a declaration starts with. As in old C, one with a
+    // storage class may leave it out, as in `register i;`, to mean int.
+    fn storage_type(&mut self, has_storage: bool) -> Result<Rc<Type>> {
+        if has_storage && !self.is_typename(self.cur.peek()) {
+            return Ok(Type::int_ty());
+        }
+        self.decl_specifiers_or_error()
     }
 
-    fn expr_stmt(&mut self) -> Node {
-        let expr = self.expr();
+    // Whether the declaration of `ty` ends without a declarator, as
+    // `struct point { int x; int y; };` does, only declaring the tag.
+    fn tag_declaration(&mut self, ty: &Type) -> bool {
+        matches!(ty.ty, Ctype::Struct(_)) && self.consume(TokenType::Semicolon)
+    }
+
+    fn declare_local(&mut self, node: &Node, register: bool) {
+        if let NodeType::Vardef(name, ..) = node.op {
+            self.env.registers.insert(name, register);
+        }
+    }
+
+    // Whether `name` is a `register` variable. Another local of the
+    // same name in an inner scope hides it.
+    fn is_register(&self, name: Symbol) -> bool {
+        let mut env = Some(&self.env);
+        while let Some(e) = env {
+            if let Some(&register) = e.registers.get(&name) {
+                return register;
+            }
+            env = e.next.as_deref();
+        }
+        false
+    }
+
+    fn expr_stmt(&mut self) -> Result<Node> {
+        let first = self.cur.peek().ty.clone();
+        let expr = self.expr()?;
         let node = new_expr!(NodeType::ExprStmt, expr);
-        self.expect(TokenType::Semicolon);
-        node
+        let t = self.cur.peek();
+        if t.ty != TokenType::Semicolon {
+            if let TokenType::Ident(name) = first {
+                return Err(ParseError::Expected {
+                    expected: TokenType::Semicolon,
+                    found: Box::new(Found::new(t)),
+                    similar: self.misspelled(name),
+                });
+            }
+        }
+        self.expect(TokenType::Semicolon)?;
+        Ok(node)
     }
 
-    fn stmt(&mut self) -> Node {
-        let t = &self.tokens[self.pos];
-        self.pos += 1;
+    // A statement that starts with an identifier didn't end where it
+    // should, as in `retrun x;` or `itn x;`. Returns the keyword or the
+    // type name the identifier looks like, if any.
+    fn misspelled(&self, name: Symbol) -> Option<String> {
+        let mut names: Vec<Rc<str>> = token::keywords().map(Rc::from).collect();
+        let mut env = Some(&self.env);
+        while let Some(e) = env {
+            names.extend(e.typedefs.keys().map(|n| n.as_str()));
+            env = e.next.as_deref();
+        }
+        closest(&name.as_str(), names)
+    }
 
-        match t.ty {
+    fn stmt(&mut self) -> Result<Node> {
+        let span = self.cur.peek().span();
+        let mut node = self.nested(Self::stmt_kind)?;
+        if node.span.is_none() {
+            node.span = Some(span);
+        }
+        Ok(node)
+    }
+
+    fn stmt_kind(&mut self) -> Result<Node> {
+        let t = self.cur.bump();
+
+        Ok(match t.ty {
             TokenType::Typedef => {
-                let node = self.declaration();
-                if let NodeType::Vardef(name, _, _) = node.op {
-                    self.env.typedefs.insert(name, *node.ty);
-                    return Node::new(NodeType::Null);
+                let node = self.declaration()?;
+                if let NodeType::Vardef(name, None, _) = node.op {
+                    self.env.typedefs.insert(name, node.ty);
+                    Node::new(NodeType::Null)
                 } else {
-                    unreachable!();
+                    return invalid(t, "typedef cannot have an initializer");
                 }
             }
             TokenType::If => {
                 let mut els = None;
-                self.expect(TokenType::LeftParen);
-                let cond = self.expr();
-                self.expect(TokenType::RightParen);
-                let then = self.stmt();
+                self.expect(TokenType::LeftParen)?;
+                let cond = self.expr()?;
+                self.expect(TokenType::RightParen)?;
+                let then = self.stmt()?;
                 if self.consume(TokenType::Else) {
-                    els = Some(Box::new(self.stmt()));
+                    els = Some(Box::new(self.stmt()?));
                 }
                 Node::new(NodeType::If(Box::new(cond), Box::new(then), els))
             }
             TokenType::For => {
-                self.expect(TokenType::LeftParen);
+                self.expect(TokenType::LeftParen)?;
 
-                let init: Box<Node> = if self.is_typename(&self.tokens[self.pos]) {
-                    Box::new(self.declaration())
+                let init: Box<Node> = if self.is_local_declaration() {
+                    let t = self.cur.peek();
+                    self.std
+                        .require(Std::C99, "a declaration in a 'for' loop", t.location());
+                    Box::new(self.local_declaration()?)
                 } else if self.consume(TokenType::Semicolon) {
                     Box::new(Node::new(NodeType::Null))
                 } else {
-                    Box::new(self.expr_stmt())
+                    Box::new(self.expr_stmt()?)
                 };
 
                 let cond;
                 if !self.consume(TokenType::Semicolon) {
-                    cond = Box::new(self.expr());
-                    self.expect(TokenType::Semicolon);
+                    cond = Box::new(self.expr()?);
+                    self.expect(TokenType::Semicolon)?;
                 } else {
                     cond = Box::new(Node::new(NodeType::Null))
                 }
 
                 let inc;
                 if !self.consume(TokenType::RightParen) {
-                    inc = Box::new(new_expr!(NodeType::ExprStmt, self.expr()));
-                    self.expect(TokenType::RightParen);
+                    inc = Box::new(new_expr!(NodeType::ExprStmt, self.expr()?));
+                    self.expect(TokenType::RightParen)?;
                 } else {
                     inc = Box::new(Node::new(NodeType::Null))
                 }
 
-                let body = Box::new(self.stmt());
+                let body = Box::new(self.stmt()?);
                 Node::new(NodeType::For(init, cond, inc, body))
             }
             TokenType::While => {
-                self.expect(TokenType::LeftParen);
+                self.expect(TokenType::LeftParen)?;
                 let init = Box::new(Node::new(NodeType::Null));
                 let inc = Box::new(Node::new(NodeType::Null));
-                let cond = Box::new(self.expr());
-                self.expect(TokenType::RightParen);
-                let body = Box::new(self.stmt());
+                let cond = Box::new(self.expr()?);
+                self.expect(TokenType::RightParen)?;
+                let body = Box::new(self.stmt()?);
                 Node::new(NodeType::For(init, cond, inc, body))
             }
             TokenType::Do => {
-                let body = Box::new(self.stmt());
-                self.expect(TokenType::While);
-                self.expect(TokenType::LeftParen);
-                let cond = Box::new(self.expr());
-                self.expect(TokenType::RightParen);
-                self.expect(TokenType::Semicolon);
+                let body = Box::new(self.stmt()?);
+                self.expect(TokenType::While)?;
+                self.expect(TokenType::LeftParen)?;
+                let cond = Box::new(self.expr()?);
+                self.expect(TokenType::RightParen)?;
+                self.expect(TokenType::Semicolon)?;
                 Node::new(NodeType::DoWhile(body, cond))
             }
-            TokenType::Break => Node::new(NodeType::Break),
+            TokenType::Break => {
+                self.expect(TokenType::Semicolon)?;
+                Node::new(NodeType::Break)
+            }
+            TokenType::Switch => {
+                self.expect(TokenType::LeftParen)?;
+                let cond = self.expr()?;
+                self.expect(TokenType::RightParen)?;
+
+                self.switches.push(SwitchCases::default());
+                let body = self.stmt()?;
+                let cases = self.switches.pop().unwrap();
+                Node::new(NodeType::Switch(
+                    Box::new(cond),
+                    Box::new(body),
+                    cases.vals,
+                    cases.has_default,
+                ))
+            }
+            TokenType::Case => {
+                let start = self.cur.peek();
+                let val = self.conditional()?.at(start);
+                self.expect(TokenType::Colon)?;
+                let cases = match self.switches.last_mut() {
+                    Some(cases) => cases,
+                    None => return invalid(t, "stray case"),
+                };
+                cases.vals.push(val);
+                let idx = cases.vals.len() - 1;
+                Node::new(NodeType::Case(idx, Box::new(self.stmt()?)))
+            }
+            TokenType::Default => {
+                self.expect(TokenType::Colon)?;
+                let cases = match self.switches.last_mut() {
+                    Some(cases) => cases,
+                    None => return invalid(t, "stray default"),
+                };
+                if cases.has_default {
+                    return invalid(t, "multiple default labels in one switch");
+                }
+                cases.has_default = true;
+                Node::new(NodeType::Default(Box::new(self.stmt()?)))
+            }
+            TokenType::Asm => {
+                // Only basic asm is supported: the string is copied to
+                // the output as is.
+                let t = self.cur.peek();
+                if t.is_ident("volatile") || t.is_ident("__volatile__") {
+                    self.cur.bump();
+                }
+                self.expect(TokenType::LeftParen)?;
+                let t = self.cur.bump();
+                if t.ty != TokenType::Str {
+                    return invalid(t, "string expected");
+                }
+                let text = self.string_literal(t);
+                self.expect(TokenType::RightParen)?;
+                self.expect(TokenType::Semicolon)?;
+                Node::new(NodeType::Asm(text))
+            }
             TokenType::Return => {
-                let expr = self.expr();
-                self.expect(TokenType::Semicolon);
+                let expr = self.expr()?;
+                self.expect(TokenType::Semicolon)?;
                 Node::new(NodeType::Return(Box::new(expr)))
             }
             TokenType::LeftBrace => {
                 let mut stmts = vec![];
                 while !self.consume(TokenType::RightBrace) {
-                    stmts.push(self.stmt());
+                    stmts.push(self.stmt()?);
                 }
                 Node::new(NodeType::CompStmt(stmts))
             }
             TokenType::Semicolon => Node::new(NodeType::Null),
             _ => {
-                self.pos -= 1;
-                if self.is_typename(&self.tokens[self.pos]) {
-                    return self.declaration();
+                self.cur.back();
+                if self.is_local_declaration() {
+                    return self.local_declaration();
                 }
-                self.expr_stmt()
+                return self.expr_stmt();
             }
+        })
+    }
+
+    // "#pragma pack", which the preprocessor passed on. `pack(n)` aligns
+    // the struct members declared after it to at most n bytes,
+    // `pack()` goes back to their own alignment, and `pack(push, n)`
+    // and `pack(pop)` save the setting and restore it. Returns whether
+    // there was one.
+    fn pragma(&mut self) -> Result<bool> {
+        if !self.consume(TokenType::Pragma) {
+            return Ok(false);
+        }
+        self.cur.bump(); // "pack"
+        self.expect(TokenType::LeftParen)?;
+        let t = self.cur.peek();
+        match t.ty {
+            TokenType::Ident(name) if name == "push" => {
+                self.cur.bump();
+                self.packs.push(self.pack);
+                if self.consume(TokenType::Comma) {
+                    self.pack = Some(self.pack_value()?);
+                }
+            }
+            TokenType::Ident(name) if name == "pop" => {
+                self.cur.bump();
+                match self.packs.pop() {
+                    Some(pack) => self.pack = pack,
+                    None => {
+                        return invalid(t, "'#pragma pack(pop)' without a '#pragma pack(push)'")
+                    }
+                }
+            }
+            TokenType::Num(..) => self.pack = Some(self.pack_value()?),
+            TokenType::RightParen => self.pack = None,
+            _ => return invalid(t, "malformed '#pragma pack'"),
+        }
+        self.expect(TokenType::RightParen)?;
+        Ok(true)
+    }
+
+    fn pack_value(&mut self) -> Result<usize> {
+        let t = self.cur.bump();
+        match t.ty {
+            TokenType::Num(n, _) if [1, 2, 4, 8, 16].contains(&n) => Ok(n as usize),
+            _ => invalid(t, "alignment must be a small power of two"),
         }
     }
 
-    fn compound_stmt(&mut self) -> Node {
+    fn compound_stmt(&mut self) -> Result<Node> {
         let mut stmts = vec![];
 
-        let new_env = Env::new(Some(Box::new(self.env.clone())));
-        self.env = new_env;
+        self.enter_scope();
+        let mut after_stmt = false;
         while !self.consume(TokenType::RightBrace) {
-            stmts.push(self.stmt());
+            if self.pragma()? {
+                continue;
+            }
+            let t = self.cur.peek();
+            if !self.is_local_declaration() {
+                after_stmt = true;
+            } else if after_stmt {
+                self.std
+                    .require(Std::C99, "a declaration after a statement", t.location());
+            }
+            let at = self.checkpoint();
+            match self.stmt() {
+                Ok(stmt) => stmts.push(stmt),
+                // With nothing after it, the error ends the block too.
+                Err(e) if !self.recover(at) => return Err(e),
+                Err(e) => self.errors.push(e),
+            }
         }
-        let next = self.env.next.clone();
-        self.env = *next.unwrap();
-        Node::new(NodeType::CompStmt(stmts))
+        self.leave_scope();
+        Ok(Node::new(NodeType::CompStmt(stmts)))
     }
 
-    fn toplevel(&mut self) -> Option<Node> {
+    fn toplevel(&mut self) -> Result<Option<Node>> {
+        if self.pragma()? {
+            return Ok(None);
+        }
+        let t = self.cur.peek();
+        if t.ty == TokenType::Auto || t.ty == TokenType::Register {
+            return invalid(t, "illegal storage class at file scope");
+        }
         let is_typedef = self.consume(TokenType::Typedef);
-        let is_extern = self.consume(TokenType::Extern);
+        let mut is_extern = self.consume(TokenType::Extern);
+        let mut is_static = self.consume(TokenType::Static);
+        let is_tls = self.consume(TokenType::ThreadLocal);
+        is_extern |= self.consume(TokenType::Extern);
+        is_static |= self.consume(TokenType::Static);
+        if is_extern && is_static {
+            return invalid(t, "both 'extern' and 'static' in declaration");
+        }
+        let mut noreturn = self.consume(TokenType::Noreturn);
+        noreturn |= self.attributes()?;
 
-        let mut ty = self.ctype();
-        let t = &self.tokens[self.pos];
-        let name: String;
-        if let TokenType::Ident(ref name2) = t.ty {
-            name = name2.clone();
+        let mut ty = self.ctype()?;
+        if !is_typedef && self.tag_declaration(&ty) {
+            return Ok(None);
+        }
+        let t = self.cur.peek();
+        let name: Symbol;
+        if t.ty == TokenType::LeftParen {
+            if let Some(node) =
+                self.returns_pointer(ty.clone(), is_typedef, is_static, is_tls, noreturn)?
+            {
+                return Ok(node);
+            }
+            if noreturn {
+                return invalid(t, "'_Noreturn' in a non-function declaration");
+            }
+            // A declarator in parentheses, as in `int (*fp)(int);`,
+            // which declares a variable.
+            let node = self.direct_decl(ty)?;
+            match node.op {
+                NodeType::Vardef(name, init, _) => {
+                    if is_static {
+                        self.statics.insert(name);
+                    }
+                    return self.global(name, node.ty, init, is_typedef, is_extern, is_tls);
+                }
+                _ => return invalid(t, "bad direct-declarator"),
+            }
+        } else if let TokenType::Ident(name2) = t.ty {
+            name = name2;
         } else {
-            t.bad_token("function or variable name expected");
+            return invalid(t, "function or variable name expected");
+        }
+        self.cur.bump();
+        if is_static {
+            self.statics.insert(name);
         }
-        self.pos += 1;
 
         // Function
         if self.consume(TokenType::LeftParen) {
-            let mut args = vec![];
-            if !self.consume(TokenType::RightParen) {
-                args.push(self.param_declaration());
-                while self.consume(TokenType::Comma) {
-                    args.push(self.param_declaration());
+            let (args, variadic) = self.params()?;
+            noreturn |= self.attributes()?;
+            let params = args.iter().map(|arg| arg.ty.clone()).collect();
+            let ty = Rc::new(Type::new(Ctype::Func(ty, params, variadic, noreturn), 0));
+            return self.function(name, t.span(), args, ty, is_typedef, is_tls);
+        }
+
+        if noreturn {
+            return invalid(t, "'_Noreturn' in a non-function declaration");
+        }
+        ty = self.read_array(ty)?;
+        let init = if self.consume(TokenType::Equal) {
+            // Errors in it point at the initializer.
+            let span = self.cur.peek().span();
+            let mut init = self.initializer()?;
+            init.span = Some(span);
+            Some(Box::new(init))
+        } else {
+            None
+        };
+        self.global(name, ty, init, is_typedef, is_extern, is_tls)
+    }
+
+    // The parameters of a function being declared or defined, after
+    // their `(`, and whether it takes "...". They are in the scope of
+    // the body, which function() leaves.
+    fn params(&mut self) -> Result<(Vec<Node>, bool)> {
+        self.enter_scope();
+        let mut args = vec![];
+        let mut variadic = false;
+        if !self.consume(TokenType::RightParen) {
+            args.push(self.param_declaration()?);
+            while self.consume(TokenType::Comma) {
+                if self.consume(TokenType::Ellipsis) {
+                    variadic = true;
+                    break;
                 }
-                self.expect(TokenType::RightParen);
+                args.push(self.param_declaration()?);
             }
+            self.expect(TokenType::RightParen)?;
+        }
+        Ok((args, variadic))
+    }
 
-            if self.consume(TokenType::Semicolon) {
-                let mut node = Node::new(NodeType::Decl(name));
-                node.ty = Box::new(Type::new(Ctype::Func(Box::new(ty)), 0));
-                return Some(node);
-            }
+    // The declaration or the body of a function of type `ty`, after
+    // its parameters. `span` is where its name is.
+    fn function(
+        &mut self,
+        name: Symbol,
+        span: Span,
+        args: Vec<Node>,
+        ty: Rc<Type>,
+        is_typedef: bool,
+        is_tls: bool,
+    ) -> Result<Option<Node>> {
+        if self.consume(TokenType::Semicolon) {
+            self.leave_scope();
+            let mut node = Node::new(NodeType::Decl(name));
+            node.ty = ty;
+            return Ok(Some(node));
+        }
+
+        let t = self.cur.peek();
+        self.expect(TokenType::LeftBrace)?;
+        if is_typedef {
+            return invalid(t, "typedef {} has function definition");
+        }
+        if is_tls {
+            return invalid(t, "_Thread_local function");
+        }
+        if let Ctype::Func(_, _, variadic, _) = ty.ty {
+            self.variadic = variadic;
+        }
+        let body = self.compound_stmt()?;
+        self.leave_scope();
+
+        let is_static = self.statics.contains(&name);
+        let mut node = Node::new(NodeType::Func(name, args, Box::new(body), 0, is_static));
+        node.ty = ty;
+        node.span = Some(span);
+        Ok(Some(node))
+    }
 
-            let t = &self.tokens[self.pos];
-            self.expect(TokenType::LeftBrace);
-            if is_typedef {
-                t.bad_token("typedef {} has function definition");
+    // A function returning a pointer to a function or an array, such
+    // as `int (*f(int x))(int)`, whose name and parameters are in the
+    // parentheses at the cursor. None if they hold something else,
+    // which is left unread.
+    fn returns_pointer(
+        &mut self,
+        mut ret: Rc<Type>,
+        is_typedef: bool,
+        is_static: bool,
+        is_tls: bool,
+        noreturn: bool,
+    ) -> Result<Option<Option<Node>>> {
+        let at = self.cur.pos;
+        self.expect(TokenType::LeftParen)?;
+        let mut stars = 0;
+        while self.consume(TokenType::Mul) {
+            stars += 1;
+        }
+        let t = self.cur.bump();
+        let name = match t.ty {
+            TokenType::Ident(name) if self.consume(TokenType::LeftParen) => name,
+            _ => {
+                self.cur.pos = at;
+                return Ok(None);
             }
-            let body = self.compound_stmt();
+        };
+
+        if is_static {
+            self.statics.insert(name);
+        }
+        let (args, variadic) = self.params()?;
+        self.expect(TokenType::RightParen)?;
+        ret = if self.consume(TokenType::LeftParen) {
+            self.func_type(ret)?
+        } else {
+            self.read_array(ret)?
+        };
+        for _ in 0..stars {
+            ret = Type::ptr_to(ret);
+        }
+        let params = args.iter().map(|arg| arg.ty.clone()).collect();
+        let ty = Rc::new(Type::new(Ctype::Func(ret, params, variadic, noreturn), 0));
+        self.function(name, t.span(), args, ty, is_typedef, is_tls)
+            .map(Some)
+    }
 
-            let mut node = Node::new(NodeType::Func(name, args, Box::new(body), 0));
-            node.ty = Box::new(Type::new(Ctype::Func(Box::new(ty)), 0));
-            return Some(node);
+    // GNU attributes, `__attribute__((name, name(args)))`, which may
+    // come before a declaration at file scope or after the parameters
+    // of a function. Returns whether one of them is noreturn, the only
+    // one that does anything.
+    fn attributes(&mut self) -> Result<bool> {
+        let mut noreturn = false;
+        while self.cur.peek().ty == TokenType::Ident(symbol::intern("__attribute__")) {
+            self.cur.bump();
+            self.expect(TokenType::LeftParen)?;
+            self.expect(TokenType::LeftParen)?;
+            while let TokenType::Ident(name) = self.cur.peek().ty {
+                let t = self.cur.bump();
+                if name == "noreturn" || name == "__noreturn__" {
+                    noreturn = true;
+                } else {
+                    diagnostic::warn(
+                        Diagnostic::new(
+                            Severity::Warning,
+                            format!("'{}' attribute ignored", name),
+                            Some(t.location()),
+                        )
+                        .with_code("attributes"),
+                    );
+                }
+                // Skip the arguments.
+                if self.consume(TokenType::LeftParen) {
+                    let mut depth = 1;
+                    while depth > 0 {
+                        match self.cur.bump().ty {
+                            TokenType::LeftParen => depth += 1,
+                            TokenType::RightParen => depth -= 1,
+                            TokenType::Eof => return invalid(self.cur.prev(), "')' expected"),
+                            _ => {}
+                        }
+                    }
+                }
+                if !self.consume(TokenType::Comma) {
+                    break;
+                }
+            }
+            self.expect(TokenType::RightParen)?;
+            self.expect(TokenType::RightParen)?;
         }
+        Ok(noreturn)
+    }
 
-        ty = self.read_array(Box::new(ty));
-        self.expect(TokenType::Semicolon);
+    // A variable or typedef at file scope, whose declarator and
+    // initializer have been read. sema makes the initializer the
+    // variable's data.
+    fn global(
+        &mut self,
+        name: Symbol,
+        ty: Rc<Type>,
+        init: Option<Box<Node>>,
+        is_typedef: bool,
+        is_extern: bool,
+        is_tls: bool,
+    ) -> Result<Option<Node>> {
+        let t = self.cur.peek();
+        self.expect(TokenType::Semicolon)?;
 
         if is_typedef {
-            self.env.typedefs.insert(name.clone(), ty.clone());
-            return None;
+            if init.is_some() {
+                return invalid(t, &format!("typedef {} is initialized", name));
+            }
+            self.env.typedefs.insert(name, ty.clone());
+            return Ok(None);
         }
 
-        // Global variable
+        // Global variable. One with an initializer is defined here
+        // even if it is declared extern.
+        let is_extern = is_extern && init.is_none();
+        let is_static = self.statics.contains(&name);
         let mut node = Node::new(NodeType::Vardef(
             name,
-            None,
+            init,
             if is_extern {
-                Scope::Global(String::new(), 0, true)
+                Scope::Global(vec![], 0, true, is_tls, is_static)
             } else {
-                Scope::Global(String::new(), ty.size, false)
+                Scope::Global(vec![], ty.size, false, is_tls, is_static)
             },
         ));
-        node.ty = Box::new(ty);
-        Some(node)
+        node.ty = ty;
+        Ok(Some(node))
     }
 }
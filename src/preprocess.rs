@@ -1,35 +1,75 @@
 // C preprocessor
 
-use crate::token::{tokenize, Token};
-use crate::TokenType;
+use crate::diagnostic::{self, Diagnostic, Level, Pragma, Severity};
+use crate::symbol::{self, Symbol};
+use crate::target::Target;
+use crate::token::{lex_str, preprocess_file, preprocess_str, string_token, Token};
+use crate::util::closest;
+use crate::{NumType, Std, TokenType};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::mem;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 pub fn preprocess(tokens: Vec<Token>, ctx: &mut Preprocessor) -> Vec<Token> {
     ctx.preprocess_impl(tokens)
 }
 
-#[derive(Clone)]
+// Prints preprocessed tokens as C source, for -E. Tokens are separated
+// by a space and lines end where they did in the source, with each
+// directive leaving an empty line.
+pub fn to_source(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    let mut bol = true;
+    for t in tokens {
+        if t.ty == TokenType::NewLine {
+            out.push('\n');
+            bol = true;
+            continue;
+        }
+        if !bol {
+            out.push(' ');
+        }
+        bol = false;
+        // Numbers may come from __LINE__, which has no text in the
+        // source.
+        match t.ty {
+            TokenType::Num(val, NumType::ULong) => out.push_str(&format!("{}UL", val as u64)),
+            TokenType::Num(val, NumType::ULLong) => out.push_str(&format!("{}ULL", val as u64)),
+            TokenType::Num(val, ty) => {
+                let suffix = match ty {
+                    NumType::UInt => "U",
+                    NumType::Long => "L",
+                    NumType::LLong => "LL",
+                    _ => "",
+                };
+                out.push_str(&format!("{}{}", val, suffix))
+            }
+            _ => out.push_str(&t.tokstr()),
+        }
+    }
+    if !bol {
+        out.push('\n');
+    }
+    out
+}
+
+#[derive(Clone, Default)]
 pub struct Env {
     input: Vec<Token>,
     output: Vec<Token>,
     pos: usize,
+    // What macros were replaced by, to be read before the rest of the
+    // input, last first.
+    pending: Vec<Token>,
+    conds: Vec<Cond>, // The #ifs in the file whose #endif isn't read yet
+    // Where the last token read ended, for errors at the end of input.
+    end: Token,
     next: Option<Box<Env>>,
 }
 
-impl Default for Env {
-    fn default() -> Env {
-        Env {
-            input: vec![],
-            output: vec![],
-            pos: 0,
-            next: None,
-        }
-    }
-}
-
 impl Env {
     pub fn new(input: Vec<Token>, next: Option<Box<Env>>) -> Self {
         Env {
@@ -40,10 +80,18 @@ impl Env {
     }
 }
 
+// An #if, #ifdef or #ifndef.
+#[derive(Clone)]
+struct Cond {
+    at: Token,     // Its '#'
+    taken: bool,   // Whether one of its groups was included
+    in_else: bool, // Whether its #else was read
+}
+
 #[derive(Debug, Clone)]
 enum MacroType {
     Objlike,
-    Funclike(Vec<String>),
+    Funclike(Vec<Symbol>),
 }
 
 #[derive(Debug, Clone)]
@@ -61,16 +109,15 @@ impl Macro {
         match self.ty {
             MacroType::Funclike(ref params) => {
                 let mut map = HashMap::new();
-                for (i, item) in params.iter().enumerate() {
-                    let name = item.clone();
-                    map.insert(name, i);
+                for (i, name) in params.iter().enumerate() {
+                    map.insert(*name, i);
                 }
 
                 for i in 0..self.tokens.len() {
                     let t = &self.tokens[i].clone();
                     match t.ty {
-                        TokenType::Ident(ref name) => {
-                            if let Some(n) = map.get(name) {
+                        TokenType::Ident(name) => {
+                            if let Some(n) = map.get(&name) {
                                 if let Some(elem) = self.tokens.get_mut(i) {
                                     *elem = Token::new(
                                         TokenType::Param(*n),
@@ -127,63 +174,278 @@ impl Macro {
     }
 }
 
+// A file, however it was named: "a.h", "./a.h" and "dir/../a.h" are the
+// same one.
+fn file_id(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.into())
+}
+
+// The macro of a classic include guard, if the file whose tokens are
+// `tokens` is all one #ifndef group:
+//
+//   #ifndef FOO_H
+//   #define FOO_H
+//   ...
+//   #endif
+//
+// Including it again adds nothing while FOO_H is defined.
+fn include_guard(tokens: &[Token]) -> Option<Symbol> {
+    let mut lines = tokens
+        .split(|t| t.ty == TokenType::NewLine)
+        .filter(|line| !line.is_empty());
+    let guard = match lines.next()? {
+        [hash, d, name] if hash.ty == TokenType::HashMark && d.is_ident("ifndef") => {
+            match name.ty {
+                TokenType::Ident(name) => name,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let mut depth = 0;
+    while let Some(line) = lines.next() {
+        let d = match line {
+            [hash, d, ..] if hash.ty == TokenType::HashMark => d,
+            _ => continue,
+        };
+        if d.ty == TokenType::If || d.is_ident("ifdef") || d.is_ident("ifndef") {
+            depth += 1;
+        } else if d.is_ident("endif") && depth > 0 {
+            depth -= 1;
+        } else if d.is_ident("endif") {
+            return lines.next().is_none().then_some(guard);
+        } else if depth == 0 && (d.ty == TokenType::Else || d.is_ident("elif")) {
+            return None;
+        }
+    }
+    None
+}
+
+// The headers r9cc comes with, which #include finds when no file of
+// the name is found first: the freestanding ones that programs need
+// without a C library, as (name, source). See include/.
+const HEADERS: &[(&str, &str)] = &[
+    ("limits.h", include_str!("../include/limits.h")),
+    ("stdarg.h", include_str!("../include/stdarg.h")),
+    ("stdbool.h", include_str!("../include/stdbool.h")),
+    ("stddef.h", include_str!("../include/stddef.h")),
+];
+
+// How deeply #include may nest, like in gcc. A file including itself
+// would go on forever otherwise.
+const MAX_INCLUDE_DEPTH: usize = 200;
+
 pub struct Preprocessor {
-    macros: HashMap<String, Macro>,
+    macros: HashMap<Symbol, Macro>,
     pub env: Box<Env>,
+    // Where to look for included files that aren't found relative to
+    // the current directory (-I).
+    pub include_dirs: Vec<String>,
+    // Whether #include finds the headers in HEADERS (not -nostdinc).
+    pub bundled_headers: bool,
+    // Every file #included so far, in the order first opened, for
+    // dependency files (-MD).
+    pub includes: Vec<String>,
+    // The files that said "#pragma once", which are not read again.
+    once: HashSet<PathBuf>,
+    // The files wrapped in an include guard, and its macro. They are
+    // not read again while it is defined.
+    guards: HashMap<PathBuf, Symbol>,
+    depth: usize, // Of #include
+    // The token in the source that the macro being expanded came from.
+    origin: Token,
+    pub std: Std, // What the lexer warns about
+    pub trigraphs: bool,
+}
+
+impl Default for Preprocessor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Preprocessor {
     pub fn new() -> Self {
-        Preprocessor {
+        let mut pp = Preprocessor {
             macros: HashMap::new(),
             env: Box::new(Env::new(vec![], None)),
+            include_dirs: vec![],
+            bundled_headers: true,
+            includes: vec![],
+            once: HashSet::new(),
+            guards: HashMap::new(),
+            depth: 0,
+            origin: Token::default(),
+            std: Std::C11,
+            trigraphs: false,
+        };
+        pp.predefine("__STDC__", 1);
+        pp.set_std(Std::C11);
+        // Memory orders for the __atomic builtins, numbered as in GCC.
+        let orders = [
+            "__ATOMIC_RELAXED",
+            "__ATOMIC_CONSUME",
+            "__ATOMIC_ACQUIRE",
+            "__ATOMIC_RELEASE",
+            "__ATOMIC_ACQ_REL",
+            "__ATOMIC_SEQ_CST",
+        ];
+        for (i, name) in orders.iter().enumerate() {
+            pp.predefine(name, i as i32);
+        }
+        pp
+    }
+
+    // Like new, with the macros that describe the target predefined.
+    pub fn for_target(target: &dyn Target) -> Self {
+        let mut pp = Self::new();
+        pp.predefine("__SIZEOF_INT__", 4);
+        pp.predefine("__SIZEOF_LONG__", target.long_size() as i32);
+        pp.predefine("__SIZEOF_LONG_LONG__", 8);
+        pp.predefine("__SIZEOF_POINTER__", target.ptr_size() as i32);
+        for &(name, val) in target.macros() {
+            pp.predefine(name, val);
+        }
+        pp
+    }
+
+    // Selects the standard, which __STDC_VERSION__ gives.
+    pub fn set_std(&mut self, std: Std) {
+        self.std = std;
+        match std.version() {
+            Some(version) => self.predefine("__STDC_VERSION__", version),
+            None => {
+                self.macros.remove(&symbol::intern("__STDC_VERSION__"));
+            }
         }
     }
 
+    // Defines an object-like macro expanding to a number, as if by
+    // "#define name val".
+    pub fn predefine(&mut self, name: &str, val: i32) {
+        let mut m = Macro::new(MacroType::Objlike);
+        m.tokens.push(Token::new(
+            TokenType::Num(val as i64, NumType::Int),
+            0,
+            Rc::new(String::new()),
+            Rc::new(vec![]),
+        ));
+        self.macros.insert(symbol::intern(name), m);
+    }
+
+    // Defines an object-like macro as if by "#define name body", as -D
+    // does.
+    pub fn define_macro(&mut self, name: &str, body: &str) {
+        let mut m = Macro::new(MacroType::Objlike);
+        m.tokens = lex_str("<command line>".into(), body);
+        self.macros.insert(symbol::intern(name), m);
+    }
+
+    // Finds the file `#include "path"` refers to.
+    // The file #include names, in a file named `from`. Unless it's
+    // <angled>, it's looked for next to that file, then in the current
+    // directory. Then it's looked for in the -I directories.
+    fn find_include(&self, path: &str, angled: bool, from: &str) -> Option<String> {
+        if !angled {
+            let dir = Path::new(from).parent().unwrap_or(Path::new(""));
+            if let Some(found) = [dir.join(path), path.into()].iter().find(|p| p.exists()) {
+                return Some(found.to_string_lossy().into_owned());
+            }
+        }
+        self.include_dirs
+            .iter()
+            .map(|dir| Path::new(dir).join(path))
+            .find(|p| p.exists())
+            .map(|p| p.to_string_lossy().into_owned())
+    }
+
+    // The source of the header r9cc comes with named `name`, if there
+    // is one and -nostdinc doesn't hide them.
+    fn bundled_header(&self, name: &str) -> Option<&'static str> {
+        HEADERS
+            .iter()
+            .find(|(n, _)| *n == name && self.bundled_headers)
+            .map(|(_, source)| *source)
+    }
+
+    // Whether including the file named `path` again would add nothing,
+    // so that it needn't be read.
+    fn is_included_once(&self, path: &str) -> bool {
+        let id = file_id(path);
+        self.once.contains(&id)
+            || self
+                .guards
+                .get(&id)
+                .is_some_and(|guard| self.macros.contains_key(guard))
+    }
+
+    // Includes a bundled header. It isn't a file, so dependency files
+    // don't list it.
+    fn include_bundled(&mut self, name: &str, source: &str) {
+        let filename = format!("<r9cc>/{}", name);
+        if self.is_included_once(&filename) {
+            return;
+        }
+        self.depth += 1;
+        let mut v = preprocess_str(filename, source, self);
+        self.depth -= 1;
+        self.env.output.append(&mut v);
+    }
+
     fn next(&mut self) -> Option<Token> {
-        if self.eof() {
-            return None;
+        let t = match self.env.pending.pop() {
+            Some(t) => t,
+            None if self.env.pos == self.env.input.len() => return None,
+            None => {
+                self.env.pos += 1;
+                mem::take(&mut self.env.input[self.env.pos - 1])
+            }
+        };
+        self.env.end = Token::new(TokenType::NewLine, t.end, t.filename.clone(), t.buf.clone());
+        Some(t)
+    }
+
+    // Like next, but the input must not end here.
+    fn next_or(&mut self, msg: &str) -> Token {
+        match self.next() {
+            Some(t) => t,
+            None => self.env.end.bad_token(msg),
         }
-        let pos = self.env.pos;
-        let t = Some(mem::replace(&mut self.env.input[pos], Token::default()));
-        self.env.pos += 1;
-        t
     }
 
     fn eof(&self) -> bool {
-        self.env.pos == self.env.input.len()
+        self.env.pending.is_empty() && self.env.pos == self.env.input.len()
     }
 
     fn get(&mut self, ty: TokenType, msg: &str) -> Token {
-        let t = self.next().expect(msg);
+        let t = self.next_or(msg);
         if t.ty != ty {
             t.bad_token(msg);
         }
         t
     }
 
-    fn ident(&mut self, msg: &str) -> String {
-        let t = self.next().expect(msg);
+    fn ident(&mut self, msg: &str) -> Symbol {
+        let t = self.next_or(msg);
         match t.ty {
-            TokenType::Ident(s) | TokenType::Str(s, _) => s,
+            TokenType::Ident(name) => name,
             _ => t.bad_token(msg),
         }
     }
 
     fn peek(&self) -> Option<&Token> {
-        self.env.input.get(self.env.pos)
+        self.env
+            .pending
+            .last()
+            .or_else(|| self.env.input.get(self.env.pos))
     }
 
     fn consume(&mut self, ty: TokenType) -> bool {
-        if let Some(t) = self.peek() {
-            if t.ty != ty {
-                return false;
-            }
-        } else {
-            return false;
+        if self.peek().is_some_and(|t| t.ty == ty) {
+            self.next();
+            return true;
         }
-        self.env.pos += 1;
-        true
+        false
     }
 
     fn read_until_eol(&mut self) -> Vec<Token> {
@@ -200,15 +462,15 @@ impl Preprocessor {
     fn read_one_arg(&mut self) -> Vec<Token> {
         let mut v = vec![];
         let msg = "unclosed macro argument";
-        let start = self.peek().expect(msg).clone();
+        let start = match self.peek() {
+            Some(t) => t.clone(),
+            None => self.env.end.bad_token(msg),
+        };
         let mut level = 0;
 
-        while !self.eof() {
-            let t = self.peek().expect(msg).clone();
-            if level == 0 {
-                if t.ty == TokenType::RightParen || t.ty == TokenType::Comma {
-                    return v;
-                }
+        while let Some(t) = self.peek().cloned() {
+            if level == 0 && (t.ty == TokenType::RightParen || t.ty == TokenType::Comma) {
+                return v;
             }
 
             self.next();
@@ -235,7 +497,7 @@ impl Preprocessor {
         v
     }
 
-    fn stringize(tokens: &[Token], filename: Rc<String>, buf: Rc<Vec<char>>) -> Token {
+    fn stringize(tokens: &[Token], filename: Rc<String>) -> Token {
         let mut sb = String::new();
         for (i, t) in tokens.iter().enumerate() {
             if i != 0 {
@@ -244,74 +506,122 @@ impl Preprocessor {
             sb.push_str(&t.tokstr());
         }
 
-        let len = sb.len();
-        Token::new(TokenType::Str(sb, len), 0, filename, buf)
+        string_token(&sb, filename)
     }
 
-    fn add_special_macro(&mut self, t: &Token) -> bool {
+    // __LINE__ and __FILE__ expand to the location of `at`, the token
+    // in the source file that the expansion started from.
+    fn special_macro(t: &Token, at: &Token) -> Option<Token> {
         if t.is_ident("__LINE__") {
-            self.env.output.push(Token::new(
-                TokenType::Num(t.get_line_number() as i32),
-                0,
-                t.filename.clone(),
-                t.buf.clone(),
-            ));
-            true
+            let line = TokenType::Num(at.span().line as i64, NumType::Int);
+            Some(Token::new(
+                line,
+                at.start,
+                at.filename.clone(),
+                at.buf.clone(),
+            ))
+        } else if t.is_ident("__FILE__") {
+            Some(string_token(&at.filename, at.filename.clone()))
         } else {
-            false
+            None
         }
     }
 
-    fn apply_objlike(&mut self, tokens: Vec<Token>) {
-        for t in tokens {
-            if self.add_special_macro(&t) {
-                continue;
-            } else {
-                self.env.output.push(t);
+    // Expands `t` if it names a macro, and returns whether it did. What
+    // the macro is replaced by is read again, so that the macros in it
+    // expand too, except for those it came from: `#define foo foo + 1`
+    // makes `foo` `foo + 1`.
+    fn expand(&mut self, t: &Token) -> bool {
+        let name = match t.ty {
+            TokenType::Ident(name) if !t.hideset.contains(&name) => name,
+            _ => return false,
+        };
+        let m = match self.macros.get(&name) {
+            Some(m) => m.clone(),
+            None => return false,
+        };
+        let tokens = match m.ty {
+            MacroType::Objlike => m.tokens,
+            // Without arguments, the name of a function-like macro is
+            // only a name.
+            MacroType::Funclike(ref params) => {
+                if !self.consume(TokenType::LeftParen) {
+                    return false;
+                }
+                let mut args = self.read_args();
+                // `F()` passes one empty argument to a macro that has
+                // one parameter.
+                if params.len() == 1 && args.is_empty() {
+                    args.push(vec![]);
+                }
+                if params.len() != args.len() {
+                    t.bad_token("number of parameter does not match");
+                }
+                self.substitute(m.tokens, args)
             }
+        };
+        if t.hideset.is_empty() {
+            self.origin = t.clone();
         }
-    }
-
-    fn apply_funclike(&mut self, tokens: Vec<Token>, params: &[String], start: &Token) {
-        self.get(TokenType::LeftParen, "comma expected");
-        let mut args = self.read_args();
-        if params.len() != args.len() {
-            start.bad_token("number of parameter does not match");
-        }
-
-        for t in tokens {
-            if self.add_special_macro(&t) {
-                continue;
+        let mut hideset = t.hideset.clone();
+        hideset.push(name);
+        for mut u in tokens.into_iter().rev() {
+            if let Some(special) = Self::special_macro(&u, &self.origin) {
+                u = special;
+            }
+            for &n in &hideset {
+                if !u.hideset.contains(&n) {
+                    u.hideset.push(n);
+                }
             }
+            self.env.pending.push(u);
+        }
+        true
+    }
 
+    // The body of a function-like macro with its parameters replaced by
+    // `args`, each with its macros expanded first, unless it's
+    // stringized by `#`.
+    fn substitute(&mut self, body: Vec<Token>, args: Vec<Vec<Token>>) -> Vec<Token> {
+        let expanded: Vec<Vec<Token>> = args.iter().map(|a| self.expand_all(a.clone())).collect();
+        let mut v = vec![];
+        for t in body {
             match t.ty {
-                TokenType::Param(val) => {
-                    if t.stringize {
-                        self.env
-                            .output
-                            .push(Self::stringize(&args[val], t.filename, t.buf));
-                    } else {
-                        self.env.output.append(&mut args[val].clone());
-                    }
+                TokenType::Param(val) if t.stringize => {
+                    v.push(Self::stringize(&args[val], t.filename));
                 }
-                _ => self.env.output.push(t),
+                TokenType::Param(val) => v.extend(expanded[val].iter().cloned()),
+                _ => v.push(t),
             }
         }
+        v
     }
 
-    fn apply(&mut self, m: Macro, start: &Token) {
-        match m.ty {
-            MacroType::Objlike => self.apply_objlike(m.tokens),
-            MacroType::Funclike(ref params) => self.apply_funclike(m.tokens, params, start),
+    // Expands the macros in `tokens` alone, as in a macro argument.
+    fn expand_all(&mut self, tokens: Vec<Token>) -> Vec<Token> {
+        let parent = mem::replace(&mut *self.env, Env::new(tokens, None));
+        self.env.next = Some(Box::new(parent));
+        while let Some(t) = self.next() {
+            if !self.expand(&t) {
+                match Self::special_macro(&t, &t) {
+                    Some(special) => self.env.output.push(special),
+                    None => self.env.output.push(t),
+                }
+            }
         }
+        let output = mem::take(&mut self.env.output);
+        self.env = self.env.next.take().unwrap();
+        output
     }
 
-    fn funclike_macro(&mut self, name: String) {
+    fn funclike_macro(&mut self, name: Symbol) {
         let mut params = vec![];
-        params.push(self.ident("parameter name expected"));
-        while !self.consume(TokenType::RightParen) {
-            self.get(TokenType::Comma, "comma expected");
+        if !self.consume(TokenType::RightParen) {
             params.push(self.ident("parameter name expected"));
+            while !self.consume(TokenType::RightParen) {
+                self.get(TokenType::Comma, "comma expected");
+                params.push(self.ident("parameter name expected"));
+            }
         }
 
         let mut m = Macro::new(MacroType::Funclike(params));
@@ -320,7 +630,7 @@ impl Preprocessor {
         self.macros.insert(name, m);
     }
 
-    fn objlike_macro(&mut self, name: String) {
+    fn objlike_macro(&mut self, name: Symbol) {
         let mut m = Macro::new(MacroType::Objlike);
         m.tokens = self.read_until_eol();
         self.macros.insert(name, m);
@@ -328,55 +638,238 @@ impl Preprocessor {
 
     fn define(&mut self) {
         let name = self.ident("macro name expected");
-        if self.consume(TokenType::LeftParen) {
+        // Only a parenthesis right after the name starts parameters:
+        // `#define NULL ((void *)0)` has none.
+        let name_end = self.env.end.start;
+        if self.peek().is_some_and(|t| t.start == name_end) && self.consume(TokenType::LeftParen) {
             return self.funclike_macro(name);
         }
         self.objlike_macro(name);
     }
 
+    fn undef(&mut self) {
+        let name = self.ident("macro name expected");
+        if let Some(t) = self.next() {
+            if t.ty != TokenType::NewLine {
+                t.bad_token("newline expected");
+            }
+        }
+        self.macros.remove(&name);
+    }
+
     fn include(&mut self) {
-        let path = self.ident("string expected");
-        let t = self.next().expect("newline expected");
-        if t.ty != TokenType::NewLine {
-            t.bad_token("newline expected");
+        let expected = "\"FILENAME\" or <FILENAME> expected";
+        let start = self.next_or(expected);
+        // <FILENAME> is written as it is, and isn't looked for next to
+        // the file or in the current directory.
+        let (path, angled) = match start.ty {
+            TokenType::Str => (start.str_value(), false),
+            TokenType::LeftAngleBracket => {
+                let mut end = self.next_or("'>' expected");
+                while end.ty != TokenType::RightAngleBracket {
+                    if end.ty == TokenType::NewLine {
+                        end.bad_token("'>' expected");
+                    }
+                    end = self.next_or("'>' expected");
+                }
+                (start.buf[start.end..end.start].iter().collect(), true)
+            }
+            _ => start.bad_token(expected),
+        };
+        if let Some(t) = self.next() {
+            if t.ty != TokenType::NewLine {
+                t.bad_token("newline expected");
+            }
+        }
+        if self.depth == MAX_INCLUDE_DEPTH {
+            diagnostic::error_with_code(
+                "nesting-too-deep",
+                "#include nested too deeply".into(),
+                Some(start.location()),
+            );
+        }
+        let path = match self.find_include(&path, angled, &start.filename) {
+            Some(path) => path,
+            None => match self.bundled_header(&path) {
+                Some(source) => return self.include_bundled(&path, source),
+                None if angled => {
+                    diagnostic::error(format!("cannot find <{}>", path), Some(start.location()))
+                }
+                None => path,
+            },
+        };
+        if !self.includes.contains(&path) {
+            self.includes.push(path.clone());
         }
-        let mut v = tokenize(path, self);
+        if self.is_included_once(&path) {
+            return;
+        }
+        self.depth += 1;
+        let mut v = preprocess_file(path, self);
+        self.depth -= 1;
         self.env.output.append(&mut v);
     }
 
+    // #pragma pack is passed on to the parser, which lays out structs,
+    // and stays in the output of -E. #pragma once keeps the file from
+    // being included again, and #pragma GCC diagnostic changes how the
+    // warnings after it are reported. Other pragmas are ignored.
+    fn pragma(&mut self, hash: &Token) {
+        let mut pragma = Token::new(
+            TokenType::Pragma,
+            hash.start,
+            hash.filename.clone(),
+            hash.buf.clone(),
+        );
+        pragma.end = self.env.end.start;
+        let line = self.read_until_eol();
+        match line.first().map(|t| &t.ty) {
+            Some(TokenType::Ident(name)) if *name == "pack" => {
+                self.env.output.push(pragma);
+                self.env.output.extend(line);
+            }
+            Some(TokenType::Ident(name)) if *name == "once" && line.len() == 1 => {
+                self.once.insert(file_id(&hash.filename));
+            }
+            Some(TokenType::Ident(name))
+                if ["GCC", "clang", "r9cc"].contains(&&*name.as_str())
+                    && matches!(line.get(1).map(|t| &t.ty), Some(TokenType::Ident(d)) if *d == "diagnostic") =>
+            {
+                self.diagnostic_pragma(hash, &line);
+            }
+            _ => {
+                let text = line.iter().map(Token::tokstr).collect::<Vec<_>>();
+                diagnostic::warn(
+                    Diagnostic::new(
+                        Severity::Warning,
+                        format!("ignoring '#pragma {}'", text.join(" ")),
+                        Some(hash.location()),
+                    )
+                    .with_code("unknown-pragmas"),
+                );
+            }
+        }
+    }
+
+    // "#pragma GCC diagnostic", which clang and r9cc may stand for GCC
+    // in: `push` and `pop` save how warnings are reported and restore
+    // it, and `ignored`, `warning` or `error` and a flag such as
+    // "-Wint-conversion" drop the warning, leave it to the -W flags or
+    // make it an error. "-Wall" stands for every warning.
+    fn diagnostic_pragma(&mut self, hash: &Token, line: &[Token]) {
+        let loc = hash.location();
+        let what = format!("'#pragma {} diagnostic'", line[0].tokstr());
+        let ignore = |message: String| {
+            diagnostic::warn(
+                Diagnostic::new(Severity::Warning, message, Some(hash.location()))
+                    .with_code("unknown-pragmas"),
+            )
+        };
+        let kind = match line.get(2).map(|t| &t.ty) {
+            Some(TokenType::Ident(kind)) => kind.to_string(),
+            _ => return ignore(format!("ignoring {} without a kind", what)),
+        };
+        let level = match (kind.as_str(), line.get(3)) {
+            ("push", None) => return diagnostic::pragma(&loc, Pragma::Push),
+            ("pop", None) => return diagnostic::pragma(&loc, Pragma::Pop),
+            ("ignored", Some(_)) => Level::Ignored,
+            ("warning", Some(_)) => Level::Warning,
+            ("error", Some(_)) => Level::Error,
+            _ => return ignore(format!("ignoring malformed {}", what)),
+        };
+        let flag = match &line[3..] {
+            [t] if t.ty == TokenType::Str => t.str_value(),
+            _ => return ignore(format!("ignoring malformed {}", what)),
+        };
+        let name = flag.strip_prefix("-W").unwrap_or("");
+        if ["all", "extra"].contains(&name) {
+            for (name, _) in diagnostic::WARNINGS {
+                diagnostic::pragma(&loc, Pragma::Set(name, level));
+            }
+            return;
+        }
+        match diagnostic::warning(name) {
+            Some(name) => diagnostic::pragma(&loc, Pragma::Set(name, level)),
+            None => {
+                let names = diagnostic::WARNINGS.iter().map(|(n, _)| format!("-W{}", n));
+                diagnostic::warn(
+                    Diagnostic::new(
+                        Severity::Warning,
+                        format!("unknown warning '{}' in {}", flag, what),
+                        Some(line[3].location()),
+                    )
+                    .with_code("unknown-pragmas")
+                    .with_suggestion(closest(&flag, names)),
+                )
+            }
+        }
+    }
+
     fn preprocess_impl(&mut self, tokens: Vec<Token>) -> Vec<Token> {
-        self.env = Box::new(Env::new(tokens, Some(self.env.clone())));
+        if let Some(guard) = include_guard(&tokens) {
+            self.guards.insert(file_id(&tokens[0].filename), guard);
+        }
+        *self.env = Env::new(tokens, Some(self.env.clone()));
 
         while !self.eof() {
             let t = self.next().unwrap();
-            let macro_name;
-            if let TokenType::Ident(ref name) = t.ty {
-                macro_name = Some(name.clone());
-            } else {
-                macro_name = None;
-            }
-            if let Some(name) = macro_name {
-                if let Some(m) = self.macros.get(&name).cloned() {
-                    self.apply(m, &t);
-                } else {
-                    self.env.output.push(t);
-                }
+            if self.expand(&t) {
+                continue;
+            }
+            if let Some(special) = Self::special_macro(&t, &t) {
+                self.env.output.push(special);
                 continue;
             }
 
-            if t.ty != TokenType::HashMark {
+            // A `#` that a macro expanded to starts no directive.
+            if t.ty != TokenType::HashMark || !t.hideset.is_empty() {
                 self.env.output.push(t);
                 continue;
             }
 
-            let ident = self.ident("identifier expected");
-            if &*ident == "define" {
-                self.define();
-            } else if &*ident == "include" {
-                self.include();
-            } else {
-                t.bad_token("unknown directive");
+            // `if` and `else` are keywords to the tokenizer.
+            let d = self.next_or("identifier expected");
+            match d.ty {
+                TokenType::If => {
+                    let cond = self.if_expr(&t);
+                    self.cond_incl(&t, cond);
+                }
+                TokenType::Else => self.else_directive(&t),
+                TokenType::Ident(name) if name == "ifdef" || name == "ifndef" => {
+                    let name2 = self.ident("macro name expected");
+                    self.end_of_directive();
+                    let cond = self.is_defined(name2) == (name == "ifdef");
+                    self.cond_incl(&t, cond);
+                }
+                TokenType::Ident(name) if name == "elif" => self.elif(&t),
+                TokenType::Ident(name) if name == "endif" => {
+                    self.end_of_directive();
+                    if self.env.conds.pop().is_none() {
+                        t.bad_token("#endif without #if");
+                    }
+                }
+                TokenType::Ident(name) if name == "define" => self.define(),
+                TokenType::Ident(name) if name == "undef" => self.undef(),
+                TokenType::Ident(name) if name == "include" => self.include(),
+                TokenType::Ident(name) if name == "pragma" => self.pragma(&t),
+                TokenType::Ident(_) => diagnostic::error_with_code(
+                    "unknown-directive",
+                    "unknown directive".into(),
+                    Some(t.location()),
+                ),
+                _ => d.bad_token("identifier expected"),
             }
+            // Directives end with the newline, which -E still prints.
+            self.env.output.push(Token::new(
+                TokenType::NewLine,
+                t.start,
+                t.filename.clone(),
+                t.buf.clone(),
+            ));
+        }
+
+        if let Some(cond) = self.env.conds.first() {
+            cond.at.bad_token("unterminated conditional directive");
         }
 
         let mut output = vec![];
@@ -384,4 +877,270 @@ impl Preprocessor {
         self.env = self.env.next.take().unwrap();
         output
     }
+
+    fn end_of_directive(&mut self) {
+        if let Some(t) = self.next() {
+            if t.ty != TokenType::NewLine {
+                t.bad_token("newline expected");
+            }
+        }
+    }
+
+    // Whether `name` is a macro, for #ifdef and `defined`.
+    fn is_defined(&self, name: Symbol) -> bool {
+        self.macros.contains_key(&name) || name == "__LINE__" || name == "__FILE__"
+    }
+
+    // Starts the group of an #if, #ifdef or #ifndef at `hash`, which is
+    // skipped unless `cond` holds.
+    fn cond_incl(&mut self, hash: &Token, cond: bool) {
+        self.env.conds.push(Cond {
+            at: hash.clone(),
+            taken: cond,
+            in_else: false,
+        });
+        if !cond {
+            self.skip_group();
+        }
+    }
+
+    fn elif(&mut self, hash: &Token) {
+        match self.env.conds.last() {
+            Some(cond) if cond.in_else => hash.bad_token("#elif after #else"),
+            Some(cond) if cond.taken => {
+                self.read_until_eol();
+                self.skip_group();
+            }
+            Some(_) => {
+                let cond = self.if_expr(hash);
+                self.env.conds.last_mut().unwrap().taken = cond;
+                if !cond {
+                    self.skip_group();
+                }
+            }
+            None => hash.bad_token("#elif without #if"),
+        }
+    }
+
+    fn else_directive(&mut self, hash: &Token) {
+        self.end_of_directive();
+        let cond = match self.env.conds.last_mut() {
+            Some(cond) if cond.in_else => hash.bad_token("#else after #else"),
+            Some(cond) => cond,
+            None => hash.bad_token("#else without #if"),
+        };
+        cond.in_else = true;
+        if cond.taken {
+            self.skip_group();
+        } else {
+            cond.taken = true;
+        }
+    }
+
+    // Skips what a false condition leaves out, up to the #elif, #else or
+    // #endif that ends it, which is read next. The #ifs in it are
+    // skipped whole. Its lines are left empty, as -E prints them.
+    fn skip_group(&mut self) {
+        let mut depth = 0;
+        let mut bol = true;
+        while let Some(t) = self.next() {
+            if t.ty == TokenType::NewLine {
+                self.env.output.push(t);
+                bol = true;
+                continue;
+            }
+            if !bol || t.ty != TokenType::HashMark {
+                bol = false;
+                continue;
+            }
+            bol = false;
+            let d = match self.peek() {
+                Some(d) => d,
+                None => return,
+            };
+            let name = match d.ty {
+                TokenType::If => "if".to_string(),
+                TokenType::Else => "else".to_string(),
+                TokenType::Ident(name) => name.to_string(),
+                _ => continue,
+            };
+            match &*name {
+                "if" | "ifdef" | "ifndef" => depth += 1,
+                "endif" if depth > 0 => depth -= 1,
+                "elif" | "else" | "endif" if depth == 0 => {
+                    self.env.pending.push(t);
+                    return;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // The value of the rest of the line as the condition of the #if or
+    // #elif at `hash`. `defined` is read before macros expand, and the
+    // names left after they do are 0.
+    fn if_expr(&mut self, hash: &Token) -> bool {
+        let line = self.read_until_eol();
+        let mut tokens = vec![];
+        let mut it = line.into_iter();
+        while let Some(t) = it.next() {
+            if !t.is_ident("defined") {
+                tokens.push(t);
+                continue;
+            }
+            let mut name = it.next();
+            let paren = name.as_ref().is_some_and(|n| n.ty == TokenType::LeftParen);
+            if paren {
+                name = it.next();
+            }
+            let defined = match name.as_ref().map(|n| &n.ty) {
+                Some(&TokenType::Ident(name)) => self.is_defined(name),
+                _ => t.bad_token("macro name expected after 'defined'"),
+            };
+            if paren && !it.next().is_some_and(|t| t.ty == TokenType::RightParen) {
+                t.bad_token("')' expected after 'defined('");
+            }
+            let val = TokenType::Num(defined as i64, NumType::Int);
+            tokens.push(Token::new(val, t.start, t.filename.clone(), t.buf.clone()));
+        }
+        let tokens = self.expand_all(tokens);
+        let mut expr = CondExpr {
+            tokens: &tokens,
+            pos: 0,
+            at: hash,
+        };
+        let val = expr.ternary(true);
+        if let Some(t) = tokens.get(expr.pos) {
+            t.bad_token("missing binary operator in #if");
+        }
+        val != 0
+    }
+}
+
+// A constant expression in #if, evaluated as it's read. What && and ||
+// and ?: don't evaluate isn't `live`, and may divide by zero.
+struct CondExpr<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    at: &'a Token, // The '#', for errors at the end of the line
+}
+
+impl<'a> CondExpr<'a> {
+    fn next(&mut self) -> &'a Token {
+        match self.tokens.get(self.pos) {
+            Some(t) => {
+                self.pos += 1;
+                t
+            }
+            None => self.at.bad_token("#if expression ended early"),
+        }
+    }
+
+    fn consume(&mut self, ty: TokenType) -> bool {
+        if self.tokens.get(self.pos).is_some_and(|t| t.ty == ty) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn ternary(&mut self, live: bool) -> i64 {
+        let cond = self.binary(0, live);
+        if !self.consume(TokenType::Question) {
+            return cond;
+        }
+        let then = self.ternary(live && cond != 0);
+        if !self.consume(TokenType::Colon) {
+            self.next().bad_token("':' expected in #if");
+        }
+        let els = self.ternary(live && cond == 0);
+        if cond != 0 {
+            then
+        } else {
+            els
+        }
+    }
+
+    // The binary operators that bind tighter than `prec`, by precedence
+    // climbing.
+    fn binary(&mut self, prec: u8, live: bool) -> i64 {
+        use self::TokenType::*;
+        let mut lhs = self.unary(live);
+        loop {
+            let op = match self.tokens.get(self.pos) {
+                Some(t) => t.ty.clone(),
+                None => return lhs,
+            };
+            let op_prec = match op {
+                Logor => 1,
+                Logand => 2,
+                VerticalBar => 3,
+                Hat => 4,
+                And => 5,
+                EQ | NE => 6,
+                LeftAngleBracket | RightAngleBracket | LE | GE => 7,
+                SHL | SHR => 8,
+                Plus | Minus => 9,
+                Mul | Div | Mod => 10,
+                _ => return lhs,
+            };
+            if op_prec <= prec {
+                return lhs;
+            }
+            let t = self.next();
+            let rhs_live = match op {
+                Logor => live && lhs == 0,
+                Logand => live && lhs != 0,
+                _ => live,
+            };
+            let rhs = self.binary(op_prec, rhs_live);
+            lhs = match op {
+                Logor => (lhs != 0 || rhs != 0) as i64,
+                Logand => (lhs != 0 && rhs != 0) as i64,
+                VerticalBar => lhs | rhs,
+                Hat => lhs ^ rhs,
+                And => lhs & rhs,
+                EQ => (lhs == rhs) as i64,
+                NE => (lhs != rhs) as i64,
+                LeftAngleBracket => (lhs < rhs) as i64,
+                RightAngleBracket => (lhs > rhs) as i64,
+                LE => (lhs <= rhs) as i64,
+                GE => (lhs >= rhs) as i64,
+                SHL => lhs.wrapping_shl(rhs as u32),
+                SHR => lhs.wrapping_shr(rhs as u32),
+                Plus => lhs.wrapping_add(rhs),
+                Minus => lhs.wrapping_sub(rhs),
+                Mul => lhs.wrapping_mul(rhs),
+                Div | Mod if rhs == 0 => {
+                    if live {
+                        t.bad_token("division by zero in #if");
+                    }
+                    0
+                }
+                Div => lhs.wrapping_div(rhs),
+                Mod => lhs.wrapping_rem(rhs),
+                _ => unreachable!(),
+            };
+        }
+    }
+
+    fn unary(&mut self, live: bool) -> i64 {
+        let t = self.next();
+        match t.ty {
+            TokenType::Num(val, _) => val,
+            TokenType::Ident(_) => 0,
+            TokenType::Plus => self.unary(live),
+            TokenType::Minus => self.unary(live).wrapping_neg(),
+            TokenType::Exclamation => (self.unary(live) == 0) as i64,
+            TokenType::Tilde => !self.unary(live),
+            TokenType::LeftParen => {
+                let val = self.ternary(live);
+                if !self.consume(TokenType::RightParen) {
+                    self.next().bad_token("')' expected in #if");
+                }
+                val
+            }
+            _ => t.bad_token("token is not valid in #if"),
+        }
+    }
 }
@@ -0,0 +1,631 @@
+// Printing the AST back as C (--dump-ast).
+//
+// print() takes the nodes parse() returns and writes a program that
+// parses to the same tree again. Parentheses go only where the
+// precedence of C's operators needs them, so that a parser that gets
+// precedence or associativity wrong reads the output differently: the
+// fuzz target `roundtrip` checks that printing what was parsed from the
+// output gives the output back.
+//
+// What the parser rewrites is printed as rewritten: `a > b` as
+// `b < a`, `++x` as `x += 1`, `while` as `for` when that is what the
+// tree says, and typedef names and struct tags as the types they name.
+
+use crate::parse::{Node, NodeType};
+use crate::util::roundup;
+use crate::{Ctype, Scope, TokenType, Type};
+
+use std::fmt::Write;
+use std::rc::Rc;
+
+// How tightly each kind of expression binds, loosest first.
+const COMMA: u8 = 1;
+const ASSIGN: u8 = 2;
+const TERNARY: u8 = 3;
+const UNARY: u8 = 14;
+const POSTFIX: u8 = 15;
+const PRIMARY: u8 = 16;
+
+// The spelling and precedence of a binary operator.
+pub(crate) fn binop(op: &TokenType) -> (&'static str, u8) {
+    use self::TokenType::*;
+    match op {
+        Comma => (",", COMMA),
+        Equal => ("=", ASSIGN),
+        MulEQ => ("*=", ASSIGN),
+        DivEQ => ("/=", ASSIGN),
+        ModEQ => ("%=", ASSIGN),
+        AddEQ => ("+=", ASSIGN),
+        SubEQ => ("-=", ASSIGN),
+        ShlEQ => ("<<=", ASSIGN),
+        ShrEQ => (">>=", ASSIGN),
+        BitandEQ => ("&=", ASSIGN),
+        XorEQ => ("^=", ASSIGN),
+        BitorEQ => ("|=", ASSIGN),
+        Logor => ("||", 4),
+        Logand => ("&&", 5),
+        VerticalBar => ("|", 6),
+        Hat => ("^", 7),
+        And => ("&", 8),
+        EQ => ("==", 9),
+        NE => ("!=", 9),
+        LeftAngleBracket => ("<", 10),
+        RightAngleBracket => (">", 10),
+        LE => ("<=", 10),
+        GE => (">=", 10),
+        SHL => ("<<", 11),
+        SHR => (">>", 11),
+        Plus => ("+", 12),
+        Minus => ("-", 12),
+        Mul => ("*", 13),
+        Div => ("/", 13),
+        Mod => ("%", 13),
+        _ => panic!("not a binary operator: {:?}", op),
+    }
+}
+
+fn precedence(node: &Node) -> u8 {
+    match node.op {
+        NodeType::BinOp(ref op, ..) => binop(op).1,
+        NodeType::Ternary(..) => TERNARY,
+        NodeType::Neg(_)
+        | NodeType::Cast(_)
+        | NodeType::Addr(_)
+        | NodeType::Exclamation(_)
+        | NodeType::Tilde(_)
+        | NodeType::Sizeof(_)
+        | NodeType::Alignof(_) => UNARY,
+        // Printed as `a[i]`.
+        NodeType::Deref(ref expr) if is_index(expr) => POSTFIX,
+        NodeType::Deref(_) => UNARY,
+        NodeType::PostInc(_) | NodeType::PostDec(_) | NodeType::Dot(..) | NodeType::CallPtr(..) => {
+            POSTFIX
+        }
+        _ => PRIMARY,
+    }
+}
+
+// `a[i]` is `*(a + i)`, and prints as the former.
+fn is_index(expr: &Node) -> bool {
+    matches!(expr.op, NodeType::BinOp(TokenType::Plus, ..))
+}
+
+// Returns the declaration of `name` as a `ty`, such as `int *x[3]` for
+// an array of pointers. An empty name gives a type name.
+pub fn declaration(ty: &Type, name: &str) -> String {
+    declare(ty, name, &mut vec![])
+}
+
+// `structs` are the structs whose members are being printed, which
+// their tags stand for in them.
+fn declare(ty: &Type, name: &str, structs: &mut Vec<*const Type>) -> String {
+    let mut decl = name.to_string();
+    let mut ty = ty;
+    loop {
+        if ty.is_atomic {
+            let unqualified = Type {
+                is_atomic: false,
+                ..ty.clone()
+            };
+            let spec = declare(&unqualified, "", structs);
+            return format!("_Atomic({}) {}", spec, decl).trim_end().into();
+        }
+        if is_va_list(ty) {
+            return format!("__builtin_va_list {}", decl).trim_end().into();
+        }
+        match ty.ty {
+            Ctype::Ptr(ref base) => {
+                decl.insert(0, '*');
+                ty = base;
+            }
+            Ctype::Ary(ref base, len) => {
+                if decl.starts_with('*') {
+                    decl = format!("({})", decl);
+                }
+                let _ = write!(decl, "[{}]", len);
+                ty = base;
+            }
+            // The parameters' names aren't kept. "..." needs one before
+            // it.
+            Ctype::Func(ref ret, ref params, variadic, _) => {
+                if decl.starts_with('*') {
+                    decl = format!("({})", decl);
+                }
+                let mut list: Vec<String> =
+                    params.iter().map(|p| declare(p, "", structs)).collect();
+                if variadic {
+                    if list.is_empty() {
+                        list.push("int".into());
+                    }
+                    list.push("...".into());
+                }
+                let _ = write!(decl, "({})", list.join(", "));
+                ty = ret;
+            }
+            _ => break,
+        }
+    }
+    let spec = specifier(ty, structs);
+    if decl.is_empty() {
+        spec
+    } else {
+        format!("{} {}", spec, decl)
+    }
+}
+
+// A target's va_list may be a struct with nothing the program can name
+// in it, such as x86-64's, which has no C spelling but the builtin's.
+fn is_va_list(ty: &Type) -> bool {
+    match ty.ty {
+        Ctype::Ary(ref base, 1) => {
+            matches!(base.ty, Ctype::Struct(ref members) if members.is_empty())
+                && base.size > 0
+                && base.tag.is_none()
+        }
+        _ => false,
+    }
+}
+
+// The 8-byte integer is a long long where a long is narrower (see
+// parse::Types).
+fn long_long() -> &'static str {
+    if Type::long_ty().size < 8 {
+        "long long"
+    } else {
+        "long"
+    }
+}
+
+fn specifier(ty: &Type, structs: &mut Vec<*const Type>) -> String {
+    match ty.ty {
+        Ctype::Int if ty.is_unsigned => "unsigned int".into(),
+        Ctype::Int => "int".into(),
+        Ctype::Char if ty.is_unsigned => "char".into(),
+        Ctype::Char => "signed char".into(),
+        Ctype::Long if ty.is_unsigned => format!("unsigned {}", long_long()),
+        Ctype::Long => long_long().into(),
+        Ctype::Float => "float".into(),
+        Ctype::Double => "double".into(),
+        Ctype::Void => "void".into(),
+        // A struct used before it was defined is printed as defined,
+        // unless that is in its own members.
+        Ctype::Struct(ref members) if members.is_empty() && ty.tag.is_some() => {
+            match Type::definition(ty) {
+                Some(def) if !structs.contains(&Rc::as_ptr(&def)) => specifier(&def, structs),
+                _ => format!("struct {}", ty.tag.unwrap()),
+            }
+        }
+        Ctype::Struct(ref members) => {
+            let pack = packing(ty, members);
+            let mut s = match ty.tag {
+                Some(tag) => format!("struct {} {{", tag),
+                None => String::from("struct {"),
+            };
+            if let Some(n) = pack {
+                let _ = write!(s, "\n#pragma pack(push, {})\n", n);
+            }
+            structs.push(ty);
+            for m in members {
+                if let NodeType::Vardef(name, ..) = m.op {
+                    let _ = write!(s, " {};", declare(&m.ty, &name.as_str(), structs));
+                }
+            }
+            structs.pop();
+            if pack.is_some() {
+                s.push_str("\n#pragma pack(pop)\n");
+            }
+            s.push_str(" }");
+            s
+        }
+        Ctype::Ptr(_) | Ctype::Ary(..) | Ctype::Func(..) => unreachable!(),
+    }
+}
+
+// The "#pragma pack" that lays out the members of a struct where they
+// are, or None if they are where their own alignment puts them.
+fn packing(ty: &Type, members: &[Node]) -> Option<usize> {
+    let fits = |pack: Option<usize>| {
+        let (mut off, mut align) = (0, 1);
+        for m in members {
+            let member_align = pack.map_or(m.ty.align, |n| m.ty.align.min(n));
+            off = roundup(off, member_align);
+            if !matches!(m.op, NodeType::Vardef(_, _, Scope::Local(offset)) if offset == off) {
+                return false;
+            }
+            off += m.ty.size;
+            align = align.max(member_align);
+        }
+        align == ty.align && roundup(off, align) == ty.size
+    };
+    if fits(None) {
+        return None;
+    }
+    [16, 8, 4, 2, 1].iter().copied().find(|&n| fits(Some(n)))
+}
+
+// Escapes the characters the tokenizer would otherwise read differently.
+fn string_literal(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Printer {
+    out: String,
+    indent: usize,
+    // The case values of the switches being printed, innermost last.
+    cases: Vec<Vec<Node>>,
+}
+
+impl Printer {
+    fn line(&mut self) {
+        self.out.push('\n');
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+    }
+
+    // Prints `node` in parentheses unless it binds at least as tightly
+    // as `min`.
+    fn expr(&mut self, node: &Node, min: u8) {
+        if precedence(node) < min {
+            self.out.push('(');
+            self.expr(node, COMMA);
+            self.out.push(')');
+            return;
+        }
+
+        match node.op {
+            // A literal the tokenizer would read as negative after a
+            // minus sign can only come from one too big for a long.
+            NodeType::Num(val) if val < 0 => {
+                let _ = write!(self.out, "{:#x}UL", val as u64);
+            }
+            NodeType::Num(val) => {
+                let _ = write!(self.out, "{}", val);
+                if node.ty.is_unsigned {
+                    self.out.push('U');
+                }
+                if let Ctype::Long = node.ty.ty {
+                    self.out
+                        .push_str(if long_long() == "long" { "L" } else { "LL" });
+                }
+            }
+            // Rust's shortest spelling reads back as the same value, but
+            // that of infinity isn't C's.
+            NodeType::FNum(val) => {
+                if val.is_infinite() {
+                    self.out.push_str("1e999");
+                } else {
+                    let _ = write!(self.out, "{:?}", val);
+                }
+                if let Ctype::Float = node.ty.ty {
+                    self.out.push('f');
+                }
+            }
+            NodeType::Str(ref s, _) => string_literal(&mut self.out, s),
+            NodeType::Cast(ref expr) => {
+                let _ = write!(self.out, "({})", declaration(&node.ty, ""));
+                self.expr(expr, UNARY);
+            }
+            NodeType::InitList(ref items) => {
+                self.out.push('{');
+                self.args(items);
+                self.out.push('}');
+            }
+            NodeType::Designator(ref first, ref last, ref init) => {
+                self.out.push('[');
+                self.expr(first, TERNARY);
+                if let Some(last) = last {
+                    self.out.push_str(" ... ");
+                    self.expr(last, TERNARY);
+                }
+                self.out.push_str("] = ");
+                self.expr(init, ASSIGN);
+            }
+            NodeType::Ident(name) => {
+                let _ = write!(self.out, "{}", name);
+            }
+            NodeType::BinOp(ref op, ref lhs, ref rhs) => {
+                let (text, prec) = binop(op);
+                // Assignments group to the right, and the others to the
+                // left. The left of an assignment is a conditional.
+                let (left, right) = if prec == ASSIGN {
+                    (TERNARY, ASSIGN)
+                } else {
+                    (prec, prec + 1)
+                };
+                self.expr(lhs, left);
+                if prec == COMMA {
+                    self.out.push_str(", ");
+                } else {
+                    let _ = write!(self.out, " {} ", text);
+                }
+                self.expr(rhs, right);
+            }
+            NodeType::Ternary(ref cond, ref then, ref els) => {
+                self.expr(cond, TERNARY + 1);
+                self.out.push_str(" ? ");
+                self.expr(then, COMMA);
+                self.out.push_str(" : ");
+                self.expr(els, TERNARY);
+            }
+            NodeType::Neg(ref expr) => self.prefix("-", expr),
+            NodeType::Addr(ref expr) => self.prefix("&", expr),
+            NodeType::Exclamation(ref expr) => self.prefix("!", expr),
+            NodeType::Tilde(ref expr) => self.prefix("~", expr),
+            NodeType::Sizeof(ref expr) => self.prefix("sizeof ", expr),
+            NodeType::Alignof(ref expr) => self.prefix("_Alignof ", expr),
+            NodeType::Deref(ref expr) => match expr.op {
+                NodeType::BinOp(TokenType::Plus, ref ary, ref idx) => {
+                    self.expr(ary, POSTFIX);
+                    self.out.push('[');
+                    self.expr(idx, ASSIGN);
+                    self.out.push(']');
+                }
+                _ => self.prefix("*", expr),
+            },
+            NodeType::PostInc(ref expr) => {
+                self.expr(expr, POSTFIX);
+                self.out.push_str("++");
+            }
+            NodeType::PostDec(ref expr) => {
+                self.expr(expr, POSTFIX);
+                self.out.push_str("--");
+            }
+            NodeType::Dot(ref expr, name, _) => match expr.op {
+                NodeType::Deref(ref ptr) if !is_index(ptr) => {
+                    self.expr(ptr, POSTFIX);
+                    let _ = write!(self.out, "->{}", name);
+                }
+                _ => {
+                    self.expr(expr, POSTFIX);
+                    let _ = write!(self.out, ".{}", name);
+                }
+            },
+            NodeType::Call(name, ref args) => {
+                let _ = write!(self.out, "{}(", name);
+                self.args(args);
+                // The type __builtin_va_arg reads is the node's.
+                if name == "__builtin_va_arg" {
+                    let _ = write!(self.out, ", {}", declaration(&node.ty, ""));
+                }
+                self.out.push(')');
+            }
+            NodeType::Offsetof(ref member) => {
+                let _ = write!(
+                    self.out,
+                    "__builtin_offsetof({}, ",
+                    declaration(&node.ty, "")
+                );
+                self.expr(member, ASSIGN);
+                self.out.push(')');
+            }
+            NodeType::CallPtr(ref callee, ref args) => {
+                self.expr(callee, POSTFIX);
+                self.out.push('(');
+                self.args(args);
+                self.out.push(')');
+            }
+            NodeType::StmtExpr(ref body) => {
+                self.out.push('(');
+                self.stmt(body);
+                self.out.push(')');
+            }
+            _ => panic!("not an expression: {:?}", node.op),
+        }
+    }
+
+    fn args(&mut self, args: &[Node]) {
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.expr(arg, ASSIGN);
+        }
+    }
+
+    fn prefix(&mut self, op: &str, expr: &Node) {
+        self.out.push_str(op);
+        // Keep `- -x` and `& &x` from becoming `--x` and `&&x`.
+        let start = self.out.len();
+        self.expr(expr, UNARY);
+        if self.out[start..].starts_with(op) {
+            self.out.insert(start, ' ');
+        }
+    }
+
+    // Prints a statement, which starts at the current position and
+    // doesn't end the line.
+    fn stmt(&mut self, node: &Node) {
+        match node.op {
+            NodeType::Null => self.out.push(';'),
+            NodeType::Vardef(name, ref init, _) => {
+                self.out.push_str(&declaration(&node.ty, &name.as_str()));
+                if let Some(init) = init {
+                    self.out.push_str(" = ");
+                    self.expr(init, ASSIGN);
+                }
+                self.out.push(';');
+            }
+            NodeType::ExprStmt(ref expr) => {
+                self.expr(expr, COMMA);
+                self.out.push(';');
+            }
+            NodeType::CompStmt(ref stmts) => {
+                self.out.push('{');
+                self.indent += 1;
+                for stmt in stmts {
+                    self.line();
+                    self.stmt(stmt);
+                }
+                self.indent -= 1;
+                self.line();
+                self.out.push('}');
+            }
+            NodeType::If(ref cond, ref then, ref els) => {
+                self.out.push_str("if (");
+                self.expr(cond, COMMA);
+                self.out.push_str(") ");
+                self.stmt(then);
+                if let Some(els) = els {
+                    self.out.push_str(" else ");
+                    self.stmt(els);
+                }
+            }
+            NodeType::For(ref init, ref cond, ref inc, ref body) => {
+                if init.is_null() && inc.is_null() && !cond.is_null() {
+                    self.out.push_str("while (");
+                    self.expr(cond, COMMA);
+                } else {
+                    self.out.push_str("for (");
+                    self.stmt(init);
+                    if !cond.is_null() {
+                        self.out.push(' ');
+                        self.expr(cond, COMMA);
+                    }
+                    self.out.push(';');
+                    if let NodeType::ExprStmt(ref inc) = inc.op {
+                        self.out.push(' ');
+                        self.expr(inc, COMMA);
+                    }
+                }
+                self.out.push_str(") ");
+                self.stmt(body);
+            }
+            NodeType::DoWhile(ref body, ref cond) => {
+                self.out.push_str("do ");
+                self.stmt(body);
+                self.out.push_str(" while (");
+                self.expr(cond, COMMA);
+                self.out.push_str(");");
+            }
+            NodeType::Switch(ref cond, ref body, ref vals, _) => {
+                self.out.push_str("switch (");
+                self.expr(cond, COMMA);
+                self.out.push_str(") ");
+                self.cases.push(vals.clone());
+                self.stmt(body);
+                self.cases.pop();
+            }
+            NodeType::Case(idx, ref stmt) => {
+                let val = self.cases.last().unwrap()[idx].clone();
+                self.out.push_str("case ");
+                self.expr(&val, TERNARY);
+                self.out.push_str(": ");
+                self.stmt(stmt);
+            }
+            NodeType::Default(ref stmt) => {
+                self.out.push_str("default: ");
+                self.stmt(stmt);
+            }
+            NodeType::Break => self.out.push_str("break;"),
+            NodeType::Return(ref expr) => {
+                self.out.push_str("return ");
+                self.expr(expr, COMMA);
+                self.out.push(';');
+            }
+            NodeType::Asm(ref text) => {
+                self.out.push_str("asm(");
+                string_literal(&mut self.out, text);
+                self.out.push_str(");");
+            }
+            _ => panic!("not a statement: {:?}", node.op),
+        }
+    }
+
+    fn toplevel(&mut self, node: &Node) {
+        match node.op {
+            NodeType::Func(name, ref params, ref body, _, is_static) => {
+                let mut decl = format!("{}(", name);
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        decl.push_str(", ");
+                    }
+                    if let NodeType::Vardef(name, ..) = param.op {
+                        decl.push_str(&declaration(&param.ty, &name.as_str()));
+                    }
+                }
+                if let Ctype::Func(ref ret, _, variadic, noreturn) = node.ty.ty {
+                    if variadic {
+                        decl.push_str(", ...");
+                    }
+                    decl.push(')');
+                    if is_static {
+                        self.out.push_str("static ");
+                    }
+                    if noreturn {
+                        self.out.push_str("_Noreturn ");
+                    }
+                    self.out.push_str(&declaration(ret, &decl));
+                }
+                self.out.push(' ');
+                self.stmt(body);
+            }
+            // The parser keeps only the types of a declared function's
+            // parameters, which are named by their position. "..."
+            // needs one before it.
+            NodeType::Decl(name) => {
+                if let Ctype::Func(ref ret, ref params, variadic, noreturn) = node.ty.ty {
+                    let mut list: Vec<String> = params
+                        .iter()
+                        .enumerate()
+                        .map(|(i, p)| declaration(p, &format!("p{}", i)))
+                        .collect();
+                    if variadic {
+                        if list.is_empty() {
+                            list.push("int p".into());
+                        }
+                        list.push("...".into());
+                    }
+                    let decl = format!("{}({})", name, list.join(", "));
+                    if noreturn {
+                        self.out.push_str("_Noreturn ");
+                    }
+                    let _ = write!(self.out, "{};", declaration(ret, &decl));
+                }
+            }
+            NodeType::Vardef(name, ref init, Scope::Global(_, _, is_extern, is_tls, is_static)) => {
+                if is_extern {
+                    self.out.push_str("extern ");
+                }
+                if is_static {
+                    self.out.push_str("static ");
+                }
+                if is_tls {
+                    self.out.push_str("_Thread_local ");
+                }
+                self.out.push_str(&declaration(&node.ty, &name.as_str()));
+                if let Some(init) = init {
+                    self.out.push_str(" = ");
+                    self.expr(init, ASSIGN);
+                }
+                self.out.push(';');
+            }
+            _ => panic!("not a definition: {:?}", node.op),
+        }
+        self.out.push('\n');
+    }
+}
+
+// Prints the program `nodes` as parse() returns them, before sema.
+pub fn print(nodes: &[Node]) -> String {
+    let mut p = Printer {
+        out: String::new(),
+        indent: 0,
+        cases: vec![],
+    };
+    for node in nodes {
+        p.toplevel(node);
+    }
+    p.out
+}
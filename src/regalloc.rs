@@ -1,8 +1,12 @@
-use crate::gen_ir::{Function, IROp, IRType, IR};
+use crate::diagnostic;
+use crate::gen_ir::{CallArg, Function, IROp, IRType, IR};
+use crate::ice;
 use crate::irdump::IRInfo;
-use crate::REGS_N;
+use crate::matches;
+use crate::parallel;
+use crate::target::Target;
 
-use std::sync::Mutex;
+use std::mem;
 
 // Quoted from 9cc
 // > Register allocator.
@@ -11,97 +15,104 @@ use std::sync::Mutex;
 // > registers. This pass maps them to a finite number of registers.
 // > We actually have only 7 registers.
 //
+// How many registers there are is up to the target.
+//
 // > We allocate registers only within a single expression. In other
 // > words, there are no registers that live beyond semicolons.
 // > This design choice simplifies the implementation a lot, since
 // > practically we don't have to think about the case in which
 // > registers are exhausted and need to be spilled to memory.
 
-lazy_static! {
-    static ref USED: Mutex<[bool; REGS_N]> = Mutex::new([false; REGS_N]);
-    static ref REG_MAP: Mutex<[Option<usize>; 8192]> = Mutex::new([None; 8192]);
-}
-
-fn used_get(i: usize) -> bool {
-    USED.lock().unwrap()[i]
+// The state of allocating registers for one function, so that functions
+// can be allocated at the same time. IR registers are numbered across
+// the whole program, so a function's can be anywhere in reg_map.
+struct RegAlloc {
+    used: Vec<bool>,
+    reg_map: Vec<Option<usize>>,
 }
 
-fn used_set(i: usize, val: bool) {
-    USED.lock().unwrap()[i] = val;
-}
-
-fn reg_map_get(i: usize) -> Option<usize> {
-    REG_MAP.lock().unwrap().get(i).cloned().unwrap()
-}
-
-fn reg_map_set(i: usize, val: usize) {
-    REG_MAP.lock().unwrap()[i] = Some(val);
-}
-
-fn alloc(ir_reg: usize) -> usize {
-    if REG_MAP.lock().unwrap().len() <= ir_reg {
-        panic!("program too big");
-    }
+impl RegAlloc {
+    fn alloc(&mut self, ir_reg: usize) -> usize {
+        if self.reg_map.len() <= ir_reg {
+            self.reg_map.resize(ir_reg + 1, None);
+        }
 
-    if let Some(r) = reg_map_get(ir_reg) {
-        assert!(used_get(r));
-        return r;
-    }
+        if let Some(r) = self.reg_map[ir_reg] {
+            assert!(self.used[r]);
+            return r;
+        }
 
-    for i in 0..REGS_N {
-        if used_get(i) {
-            continue;
+        for i in 0..self.used.len() {
+            if self.used[i] {
+                continue;
+            }
+            self.reg_map[ir_reg] = Some(i);
+            self.used[i] = true;
+            return i;
         }
-        reg_map_set(ir_reg, i);
-        used_set(i, true);
-        return i;
+        // There is no spilling, so an expression can only keep as many
+        // values at once as there are registers.
+        diagnostic::error("expression too complex: out of registers".into(), None)
     }
-    panic!("register exhauseted: {}", ir_reg);
-}
 
-fn visit(irv: &mut Vec<IR>) {
-    use self::IRType::*;
+    fn visit(&mut self, irv: &mut [IR], max_args: usize) {
+        use self::IRType::*;
 
-    for item in irv {
-        let mut ir = item.clone();
-        let info = &IRInfo::from(&ir.op);
+        for (i, item) in irv.iter_mut().enumerate() {
+            ice::set_position("IR instruction", i);
+            let mut ir = item.clone();
+            let info = &IRInfo::from(&ir.op);
 
-        match info.ty {
-            Reg | RegImm | RegLabel | LabelAddr => ir.lhs = Some(alloc(ir.lhs.unwrap())),
-            Mem | RegReg => {
-                ir.lhs = Some(alloc(ir.lhs.unwrap()));
-                ir.rhs = Some(alloc(ir.rhs.unwrap()));
-            }
-            Call => {
-                ir.lhs = Some(alloc(ir.lhs.unwrap()));
-                match ir.op {
-                    IROp::Call(name, nargs, args) => {
-                        let mut args_new: [usize; 6] = [0; 6];
-                        for i in 0..nargs {
-                            args_new[i] = alloc(args[i]);
+            match info.ty {
+                Reg | RegImm | RegLabel | LabelAddr => ir.lhs = Some(self.alloc(ir.lhs.unwrap())),
+                Mem | RegReg => {
+                    ir.lhs = Some(self.alloc(ir.lhs.unwrap()));
+                    ir.rhs = Some(self.alloc(ir.rhs.unwrap()));
+                }
+                Call => {
+                    ir.lhs = Some(self.alloc(ir.lhs.unwrap()));
+                    ir.rhs = ir.rhs.map(|r| self.alloc(r));
+                    match ir.op {
+                        IROp::Call(name, args, returns_float) => {
+                            // The buffer for a returned struct doesn't count.
+                            let nargs = args
+                                .iter()
+                                .filter(|arg| !matches!(arg, CallArg::Ret(_, _)))
+                                .count();
+                            if nargs > max_args {
+                                panic!("too many arguments to {}: {}", name, nargs);
+                            }
+                            let args_new = args
+                                .iter()
+                                .map(|arg| arg.with_reg(self.alloc(arg.reg())))
+                                .collect();
+                            ir.op = IROp::Call(name, args_new, returns_float);
                         }
-                        ir.op = IROp::Call(name, nargs, args_new);
+                        _ => unreachable!(),
                     }
-                    _ => unreachable!(),
                 }
+                _ => (),
             }
-            _ => (),
-        }
 
-        if ir.op == IROp::Kill {
-            let lhs = ir.lhs.unwrap();
-            assert!(used_get(lhs));
-            used_set(lhs, false);
-            ir.op = IROp::Nop;
+            if ir.op == IROp::Kill {
+                let lhs = ir.lhs.unwrap();
+                assert!(self.used[lhs]);
+                self.used[lhs] = false;
+                ir.op = IROp::Nop;
+            }
+            *item = ir;
         }
-        *item = ir;
     }
 }
 
-pub fn alloc_regs(fns: &mut Vec<Function>) {
-    for f in fns {
-        *USED.lock().unwrap() = [false; REGS_N];
-
-        visit(&mut f.ir);
-    }
+pub fn alloc_regs(fns: &mut Vec<Function>, target: &dyn Target, jobs: usize) {
+    *fns = parallel::map(mem::take(fns), jobs, |mut f| {
+        ice::set_function(&f.name);
+        let mut ra = RegAlloc {
+            used: vec![false; target.regs().len()],
+            reg_map: vec![None; 8192],
+        };
+        ra.visit(&mut f.ir, target.max_args());
+        f
+    });
 }
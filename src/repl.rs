@@ -0,0 +1,332 @@
+// An interactive session (r9cc repl).
+//
+// Each line typed is compiled on its own and run in memory (see jit.rs).
+// A line is tried as declarations at file scope, then as an expression
+// whose value is printed, then as statements, which become the body of
+// a function that is called right away. Variables those statements
+// declare are made global, so that they outlive the call.
+//
+// Later lines see earlier ones through `decls`: the types, prototypes
+// and extern declarations of what they defined, and the preprocessor
+// directives typed, which every line is compiled after. The code and data stay loaded, and later lines are
+// linked against them.
+
+use crate::assemble::assemble;
+use crate::diagnostic::{self, Diagnostic, Severity};
+use crate::elf::Object;
+use crate::jit::{self, Image};
+use crate::parse::{parse, Node, NodeType};
+use crate::pretty;
+use crate::sema::sema;
+use crate::target;
+use crate::timer::Timer;
+use crate::token::{tokenize_str, Token};
+use crate::{catch_errors, compile_nodes, parse_failed, preprocessor, CompileError, Options};
+use crate::{Ctype, Scope, TokenType, Type};
+
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+pub struct Session {
+    opts: Options,
+    decls: String,
+    images: Vec<Image>,
+    symbols: HashMap<String, usize>, // What the images define
+    lines: usize,
+}
+
+// What a line did: the value of an expression, and the warnings it got.
+pub struct Output {
+    pub value: Option<String>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+// What an expression's function returns.
+enum Value {
+    Int,
+    UInt,
+    Long,
+    ULong,
+    Double,
+    Pointer(String), // The type, to print with the address
+}
+
+// A line, compiled.
+struct Compiled {
+    obj: Object,
+    decls: String,
+    call: Option<(String, Option<Value>)>, // The function to run
+    warnings: Vec<Diagnostic>,
+}
+
+impl Session {
+    pub fn new(opts: Options) -> Result<Self, CompileError> {
+        let target = target::lookup(&opts.target)
+            .ok_or_else(|| CompileError::UnknownTarget(opts.target.clone()))?;
+        if !jit::supports(&*target) {
+            return Err(CompileError::Unsupported("repl".into(), target.name()));
+        }
+        Ok(Session {
+            opts,
+            decls: String::new(),
+            images: vec![],
+            symbols: HashMap::new(),
+            lines: 0,
+        })
+    }
+
+    pub fn eval(&mut self, line: &str) -> Result<Output, CompileError> {
+        self.lines += 1;
+        let name = format!("__repl_{}", self.lines);
+        let compiled = catch_errors(&self.opts, || {
+            compile_line(&self.decls, line, &name, &self.opts)
+        })?;
+
+        let symbols = &self.symbols;
+        let image = jit::load(&compiled.obj, &|name| symbols.get(name).copied())
+            .map_err(|e| CompileError::Invalid(vec![Diagnostic::new(Severity::Error, e, None)]))?;
+        for (name, addr) in image.symbols() {
+            self.symbols.insert(name.into(), addr);
+        }
+        self.decls.push_str(&compiled.decls);
+
+        let mut value = None;
+        if let Some((name, ret)) = compiled.call {
+            let addr = image.symbol(&name).unwrap();
+            // The function was compiled to return what `ret` says.
+            unsafe {
+                value = match ret {
+                    Some(Value::Int) => Some(jit::call::<i32>(addr).to_string()),
+                    Some(Value::UInt) => Some(jit::call::<u32>(addr).to_string()),
+                    Some(Value::Long) => Some(jit::call::<i64>(addr).to_string()),
+                    Some(Value::ULong) => Some(jit::call::<u64>(addr).to_string()),
+                    Some(Value::Double) => Some(format!("{:?}", jit::call::<f64>(addr))),
+                    Some(Value::Pointer(ty)) => {
+                        Some(format!("({}) {:#x}", ty, jit::call::<usize>(addr)))
+                    }
+                    None => {
+                        jit::call::<()>(addr);
+                        None
+                    }
+                };
+            }
+        }
+        self.images.push(image);
+        Ok(Output {
+            value,
+            warnings: compiled.warnings,
+        })
+    }
+}
+
+// Compiles `line` as `name` after `decls`, on a compilation thread.
+fn compile_line(
+    decls: &str,
+    line: &str,
+    name: &str,
+    opts: &Options,
+) -> Result<Compiled, CompileError> {
+    let target = target::lookup(&opts.target).unwrap();
+    let mut pp = preprocessor(&*target, opts);
+    let mut tokenize = |filename: &str, source: &str| {
+        let mut tokens = tokenize_str(filename.into(), source, &mut pp);
+        tokens.pop(); // EOF
+        tokens
+    };
+    let decl_tokens = tokenize("<repl>", decls);
+    let line_tokens = tokenize("<stdin>", line);
+    let head = tokenize("<repl>", &format!("void {}() {{", name));
+    let semicolon = tokenize("<repl>", ";");
+    let tail = tokenize("<repl>", "}");
+    let eof = tokenize_str("<repl>".into(), "", &mut pp);
+    let program = |parts: &[&[Token]]| -> Vec<Token> {
+        let mut tokens: Vec<Token> = decl_tokens.clone();
+        for part in parts {
+            tokens.extend_from_slice(part);
+        }
+        tokens.extend_from_slice(&eof);
+        tokens
+    };
+    let known = parse(&program(&[]), &*target, opts.max_depth, opts.std)
+        .map_err(|errors| parse_failed(errors, opts))?
+        .len();
+
+    let ends_statement = line.trim_end().ends_with([';', '}']);
+    let is_directive = line.trim_start().starts_with('#');
+    let mut new_decls = vec![];
+    let mut call = None;
+    let mut nodes = None;
+    if ends_statement || is_directive {
+        nodes = parse(
+            &program(&[&line_tokens]),
+            &*target,
+            opts.max_depth,
+            opts.std,
+        )
+        .ok();
+        if nodes.is_none() {
+            // What it warned about was a line it isn't.
+            diagnostic::take();
+        }
+        if let Some(ref nodes) = nodes {
+            new_decls = nodes[known..].iter().map(declaration).collect();
+        }
+    }
+
+    if nodes.is_none() {
+        let mut parsed = if ends_statement {
+            parse(
+                &program(&[&head, &line_tokens, &tail]),
+                &*target,
+                opts.max_depth,
+                opts.std,
+            )
+        } else {
+            parse(
+                &program(&[&head, &line_tokens, &semicolon, &tail]),
+                &*target,
+                opts.max_depth,
+                opts.std,
+            )
+        }
+        .map_err(|errors| parse_failed(errors, opts))?;
+        let mut func = parsed.pop().unwrap();
+        let ret = if ends_statement {
+            new_decls = make_global(&mut func, &mut parsed);
+            None
+        } else {
+            return_value(&mut func, &parsed)
+        };
+        parsed.push(func);
+        nodes = Some(parsed);
+        call = Some((name.to_string(), ret));
+    }
+
+    let nodes = nodes.unwrap();
+    let mut decls = pretty::print(&new_decls);
+    // Typedefs, struct tags and preprocessor directives leave nothing
+    // in the tree, so a line of them is kept as it was typed.
+    if call.is_none() && nodes.len() == known {
+        decls = format!("{}\n", line.trim());
+    }
+
+    let unit = compile_nodes(nodes, vec![], &*target, opts, Timer::new(false))?;
+    if unit.warnings.iter().any(|d| d.severity == Severity::Error) {
+        return Err(CompileError::Invalid(unit.warnings));
+    }
+    Ok(Compiled {
+        obj: assemble(&unit.asm),
+        decls,
+        call,
+        warnings: unit.warnings,
+    })
+}
+
+// What later lines need to know of a definition at file scope.
+fn declaration(node: &Node) -> Node {
+    let mut decl = node.clone();
+    match node.op {
+        NodeType::Func(name, ..) => decl.op = NodeType::Decl(name),
+        NodeType::Vardef(name, _, Scope::Global(_, _, _, is_tls, is_static)) => {
+            decl.op = NodeType::Vardef(
+                name,
+                None,
+                Scope::Global(vec![], 0, true, is_tls, is_static),
+            )
+        }
+        _ => {}
+    }
+    decl
+}
+
+// Moves the variables declared in the body of `func` to file scope,
+// in `globals`, leaving their initializers behind as assignments.
+// Arrays and brace-enclosed lists can't be assigned, so those stay
+// with the variable, whose data they become. Returns the declarations
+// for later lines.
+fn make_global(func: &mut Node, globals: &mut Vec<Node>) -> Vec<Node> {
+    let stmts = match func.op {
+        NodeType::Func(_, _, ref mut body, ..) => match body.op {
+            NodeType::CompStmt(ref mut stmts) => stmts,
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    };
+    let mut decls = vec![];
+    for stmt in stmts.iter_mut() {
+        let (name, init) = match stmt.op {
+            NodeType::Vardef(name, ref mut init, _) => (name, init.take()),
+            _ => continue,
+        };
+        let mut var = mem::replace(stmt, Node::new(NodeType::Null));
+        let data = match init {
+            Some(init)
+                if matches!(var.ty.ty, Ctype::Ary(..))
+                    || matches!(init.op, NodeType::InitList(_)) =>
+            {
+                Some(init)
+            }
+            Some(init) => {
+                let lhs = Node::new(NodeType::Ident(name));
+                *stmt = Node::new(NodeType::ExprStmt(Box::new(Node::new_binop(
+                    TokenType::Equal,
+                    lhs,
+                    *init,
+                ))));
+                None
+            }
+            None => None,
+        };
+        var.op = NodeType::Vardef(
+            name,
+            data,
+            Scope::Global(vec![], var.ty.size, false, false, false),
+        );
+        decls.push(declaration(&var));
+        globals.push(var);
+    }
+    decls
+}
+
+// Makes `func`, whose body is an expression, return its value, if it
+// has one that can be printed. `program` is what comes before it.
+fn return_value(func: &mut Node, program: &[Node]) -> Option<Value> {
+    let mut whole = program.to_vec();
+    whole.push(func.clone());
+    let (checked, _) = sema(whole, false);
+    // Warnings come again when the line is compiled.
+    diagnostic::take();
+    let ty = match checked.last().unwrap().op {
+        NodeType::Func(_, _, ref body, ..) => match body.op {
+            NodeType::CompStmt(ref stmts) => match stmts.first().map(|s| &s.op) {
+                Some(NodeType::ExprStmt(expr)) => expr.ty.clone(),
+                _ => return None,
+            },
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let (ret, value) = match ty.ty {
+        Ctype::Int if ty.is_unsigned => (Type::uint_ty(), Value::UInt),
+        Ctype::Int | Ctype::Char => (Type::int_ty(), Value::Int),
+        Ctype::Long if ty.is_unsigned => (Type::ullong_ty(), Value::ULong),
+        Ctype::Long => (Type::llong_ty(), Value::Long),
+        Ctype::Float | Ctype::Double => (Type::double_ty(), Value::Double),
+        Ctype::Ptr(_) | Ctype::Ary(..) => (
+            Type::ptr_to(Type::char_ty()),
+            Value::Pointer(pretty::declaration(&ty, "")),
+        ),
+        _ => return None,
+    };
+    if let NodeType::Func(_, _, ref mut body, ..) = func.op {
+        if let NodeType::CompStmt(ref mut stmts) = body.op {
+            if let NodeType::ExprStmt(expr) = stmts.remove(0).op {
+                stmts.push(Node::new(NodeType::Return(expr)));
+            }
+        }
+    }
+    func.ty = Rc::new(Type::new(Ctype::Func(ret, vec![], false, false), 0));
+    Some(value)
+}
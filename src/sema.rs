@@ -1,11 +1,16 @@
+use crate::diagnostic::{self, Diagnostic, Location, Severity};
+use crate::ice;
 use crate::matches;
-use crate::parse::{Node, NodeType};
-use crate::util::roundup;
-use crate::{Ctype, Scope, TokenType, Type, Var};
+use crate::parse::{Builtin, Node, NodeType};
+use crate::pretty;
+use crate::symbol::{self, Symbol};
+use crate::token::{Pos, Span};
+use crate::util::{closest, roundup};
+use crate::{Ctype, Reloc, Scope, TokenType, Type, Var};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::mem;
-use std::sync::Mutex;
+use std::rc::Rc;
 
 // Quoted from 9cc
 // > Semantics analyzer. This pass plays a few important roles as shown
@@ -32,16 +37,12 @@ fn swap(p: &mut Node, q: &mut Node) {
     mem::swap(p, q);
 }
 
-lazy_static! {
-    static ref GLOBALS: Mutex<Vec<Var>> = Mutex::new(vec![]);
-    static ref ENV: Mutex<Env> = Mutex::new(Env::new(None));
-    static ref STRLABEL: Mutex<usize> = Mutex::new(0);
-    static ref STACKSIZE: Mutex<usize> = Mutex::new(0);
-}
+// Size of the poisoned areas around local arrays (-fsanitize=address).
+pub const REDZONE: usize = 32;
 
 #[derive(Debug, Clone)]
 struct Env {
-    vars: HashMap<String, Var>,
+    vars: HashMap<Symbol, Var>,
     next: Option<Box<Env>>,
 }
 
@@ -52,326 +53,1819 @@ impl Env {
             next,
         }
     }
+
+    fn depth(&self) -> usize {
+        1 + self.next.as_ref().map_or(0, |next| next.depth())
+    }
+}
+
+// The state of checking one program. It's kept here rather than in
+// globals so that compilations don't interfere with each other.
+struct Sema {
+    globals: Vec<Var>,
+    env: Env,
+    strlabel: usize,
+    stacksize: usize,
+    redzones: bool,
+    // The statement being checked, and the expression in it, which
+    // errors point at.
+    span: Option<Span>,
+    pos: Option<Pos>,
+    // What the function being checked returns, and whether it is
+    // _Noreturn.
+    returning: Rc<Type>,
+    noreturn: bool,
+}
+
+fn maybe_decay(base: Node, decay: bool) -> Node {
+    if !decay {
+        return base;
+    }
+
+    match base.ty.ty.clone() {
+        Ctype::Ary(ary_of, _) => {
+            let mut node = Node::new(NodeType::Addr(Box::new(base)));
+            node.ty = Type::ptr_to(ary_of.clone());
+            node
+        }
+        // A function becomes its address.
+        Ctype::Func(..) => {
+            let ty = Type::ptr_to(base.ty.clone());
+            let mut node = Node::new(NodeType::Addr(Box::new(base)));
+            node.ty = ty;
+            node
+        }
+        _ => base,
+    }
+}
+
+fn is_func_ptr(ty: &Type) -> bool {
+    match ty.ty {
+        Ctype::Ptr(ref ptr_to) => matches!(ptr_to.ty, Ctype::Func(..)),
+        _ => false,
+    }
+}
+
+// Converts `node` to `ty` where both are ints or longs, signed or not.
+// An int in a register is extended from its low 32 bits the way its
+// type says, sign-extended if signed and zero-extended if not, so a
+// conversion between them extends the narrower type's bits that way.
+// A char is extended already, and a conversion to char is left to the
+// store that truncates it. One to or from a float or a double is made
+// by convert_float.
+pub fn convert(mut node: Node, ty: &Rc<Type>) -> Node {
+    let from = node.ty.clone();
+    if (from.is_float() || ty.is_float()) && from.is_arithmetic() && ty.is_arithmetic() {
+        return convert_float(node, ty);
+    }
+    let is_wide = |t: &Type| t.is_integer() && t.size >= 4;
+    if !is_wide(&from) || !is_wide(ty) || (from.size, from.is_unsigned) == (ty.size, ty.is_unsigned)
+    {
+        return node;
+    }
+    if let NodeType::Num(ref mut val) = node.op {
+        let narrower = if ty.size <= from.size { ty } else { &from };
+        if narrower.size == 4 {
+            *val = if narrower.is_unsigned {
+                *val as u32 as i64
+            } else {
+                *val as i32 as i64
+            };
+        }
+        node.ty = ty.clone();
+        return node;
+    }
+    let pos = node.pos.clone();
+    let mut cast = Node::new(NodeType::Cast(Box::new(node)));
+    cast.ty = ty.clone();
+    cast.pos = pos;
+    cast
+}
+
+// Converts `node` to `ty` where one of them is a float or a double, and
+// the other one is arithmetic. A constant is converted here, so that
+// `(int)1.5` is an integer constant and `1` a double one where one is
+// expected.
+fn convert_float(node: Node, ty: &Rc<Type>) -> Node {
+    if mem::discriminant(&node.ty.ty) == mem::discriminant(&ty.ty) {
+        return node;
+    }
+    let pos = node.pos.clone();
+    let mut node = match eval_float(&node) {
+        Some(val) if ty.is_float() => Node::new(NodeType::FNum(val)),
+        // Rust's conversion saturates what doesn't fit, which C leaves
+        // undefined.
+        Some(val) if ty.is_unsigned => Node::new_num(val as u64 as i64),
+        Some(val) => Node::new_num(val as i64),
+        None => Node::new(NodeType::Cast(Box::new(node))),
+    };
+    node.ty = ty.clone();
+    node.pos = pos;
+    match node.op {
+        NodeType::Num(_) => node.op = NodeType::Num(eval(&node).unwrap()),
+        NodeType::FNum(_) => node.op = NodeType::FNum(eval_float(&node).unwrap()),
+        _ => (),
+    }
+    node
+}
+
+// Whether `a` and `b` are the same struct type. They are compared by
+// their members, since a struct that --dump-ast prints is declared
+// anew wherever it is used.
+fn same_struct(a: &Type, b: &Type) -> bool {
+    let layout = |members: &[Node]| -> Vec<_> {
+        members
+            .iter()
+            .map(|m| match m.op {
+                NodeType::Vardef(name, _, Scope::Local(offset)) => (name, offset, m.ty.size),
+                _ => unreachable!(),
+            })
+            .collect()
+    };
+    match (&a.ty, &b.ty) {
+        (Ctype::Struct(x), Ctype::Struct(y)) => a.size == b.size && layout(x) == layout(y),
+        _ => false,
+    }
 }
 
-fn into_new_range<T: Sized>(param: T, f: Box<Fn(T) -> T>) -> T {
-    let env = ENV.lock().unwrap().clone();
-    *ENV.lock().unwrap() = Env::new(Some(Box::new(env)));
-    let ret = f(param);
-    // Rollback
-    let env = ENV.lock().unwrap().clone();
-    *ENV.lock().unwrap() = *env.next.unwrap();
-    ret
+fn is_char_array(ty: &Type) -> bool {
+    match ty.ty {
+        Ctype::Ary(ref base, _) => matches!(base.ty, Ctype::Char),
+        _ => false,
+    }
 }
 
-fn find_var(name: &str) -> Option<Var> {
-    let env = ENV.lock().unwrap().clone();
-    let mut next: &Option<Box<Env>> = &Some(Box::new(env));
-    loop {
-        if let Some(ref e) = next {
-            let var = e.vars.get(name);
-            if var.is_some() {
-                return var.cloned();
+// What an initializer stores: a scalar or a whole struct, by its offset
+// in the object initialized.
+type Init = (usize, Rc<Type>, Node);
+
+// The value of `node` if it is an integer constant expression, such as
+// `-1` or `2 * 3 + 1`, with the width and signedness of its type.
+pub fn eval(node: &Node) -> Option<i64> {
+    use self::NodeType::*;
+    let val = match node.op {
+        Num(val) => val,
+        Neg(ref expr) => eval(expr)?.wrapping_neg(),
+        Exclamation(ref expr) => (eval(expr)? == 0) as i64,
+        Tilde(ref expr) => !eval(expr)?,
+        Cast(ref expr) => eval(expr)?,
+        Ternary(ref cond, ref then, ref els) => {
+            if eval(cond)? != 0 {
+                eval(then)?
+            } else {
+                eval(els)?
             }
-            next = &e.next;
+        }
+        BinOp(ref op, ref lhs, ref rhs) => {
+            let unsigned = lhs.ty.is_unsigned;
+            let (l, r) = (eval(lhs)?, eval(rhs)?);
+            match op {
+                TokenType::Plus => l.wrapping_add(r),
+                TokenType::Minus => l.wrapping_sub(r),
+                TokenType::Mul => l.wrapping_mul(r),
+                TokenType::Div | TokenType::Mod if r == 0 => return None,
+                TokenType::Div if unsigned => ((l as u64) / (r as u64)) as i64,
+                TokenType::Div => l.wrapping_div(r),
+                TokenType::Mod if unsigned => ((l as u64) % (r as u64)) as i64,
+                TokenType::Mod => l.wrapping_rem(r),
+                TokenType::And => l & r,
+                TokenType::VerticalBar => l | r,
+                TokenType::Hat => l ^ r,
+                TokenType::SHL => l.wrapping_shl(r as u32),
+                TokenType::SHR if unsigned => ((l as u64).wrapping_shr(r as u32)) as i64,
+                TokenType::SHR => l.wrapping_shr(r as u32),
+                TokenType::EQ => (l == r) as i64,
+                TokenType::NE => (l != r) as i64,
+                TokenType::LE if unsigned => ((l as u64) <= (r as u64)) as i64,
+                TokenType::LE => (l <= r) as i64,
+                TokenType::LeftAngleBracket if unsigned => ((l as u64) < (r as u64)) as i64,
+                TokenType::LeftAngleBracket => (l < r) as i64,
+                TokenType::Logand => (l != 0 && r != 0) as i64,
+                TokenType::Logor => (l != 0 || r != 0) as i64,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(match (node.ty.size, node.ty.is_unsigned) {
+        (1, false) => val as i8 as i64,
+        (1, true) => val as u8 as i64,
+        (4, false) => val as i32 as i64,
+        (4, true) => val as u32 as i64,
+        _ => val,
+    })
+}
+
+// Like eval, for an arithmetic constant expression such as `1.0 / 3`,
+// whose value is a double, that of a float rounded to float.
+pub fn eval_float(node: &Node) -> Option<f64> {
+    use self::NodeType::*;
+    if node.ty.is_integer() {
+        let val = eval(node)?;
+        return Some(if node.ty.is_unsigned && node.ty.size == 8 {
+            val as u64 as f64
         } else {
-            return None;
+            val as f64
+        });
+    }
+    let val = match node.op {
+        FNum(val) => val,
+        Neg(ref expr) => -eval_float(expr)?,
+        Cast(ref expr) => eval_float(expr)?,
+        BinOp(ref op, ref lhs, ref rhs) => {
+            let (l, r) = (eval_float(lhs)?, eval_float(rhs)?);
+            match op {
+                TokenType::Plus => l + r,
+                TokenType::Minus => l - r,
+                TokenType::Mul => l * r,
+                TokenType::Div => l / r,
+                _ => return None,
+            }
         }
+        _ => return None,
+    };
+    match node.ty.ty {
+        Ctype::Float => Some(val as f32 as f64),
+        Ctype::Double => Some(val),
+        _ => None,
     }
 }
 
-fn maybe_decay(base: Node, decay: bool) -> Node {
-    if !decay {
-        return base;
+// The symbol and offset that `node` points to, if it is an address
+// constant such as `&x`, `a + 1`, `&s.b` or a string literal. Thread
+// local variables have no address until run time.
+fn eval_addr(node: &Node) -> Option<(String, i64)> {
+    use self::NodeType::*;
+    match node.op {
+        Addr(ref expr) => lval_addr(expr),
+        Cast(ref expr) if matches!(node.ty.ty, Ctype::Ptr(_)) => eval_addr(expr),
+        BinOp(TokenType::Plus, ref lhs, ref rhs) => {
+            let (name, off) = eval_addr(lhs)?;
+            Some((name, off.wrapping_add(eval(rhs)?)))
+        }
+        BinOp(TokenType::Minus, ref lhs, ref rhs) => {
+            let (name, off) = eval_addr(lhs)?;
+            Some((name, off.wrapping_sub(eval(rhs)?)))
+        }
+        _ => None,
     }
+}
 
-    if let Ctype::Ary(ary_of, _) = base.ty.ty.clone() {
-        let mut node = Node::new(NodeType::Addr(Box::new(base)));
-        node.ty = Box::new(Type::ptr_to(ary_of.clone()));
-        node
-    } else {
-        base
+// Like eval_addr, for the object that the lvalue `node` is.
+fn lval_addr(node: &Node) -> Option<(String, i64)> {
+    use self::NodeType::*;
+    match node.op {
+        Gvar(ref name, _, _, false) => Some((name.clone(), 0)),
+        Deref(ref expr) => eval_addr(expr),
+        Dot(ref expr, _, offset) => {
+            let (name, off) = lval_addr(expr)?;
+            Some((name, off + offset as i64))
+        }
+        _ => None,
     }
 }
 
-fn check_lval(node: &Node) {
-    let op = &node.op;
-    if !matches!(op, NodeType::Lvar(_))
-        && !matches!(op, NodeType::Gvar(_, _, _))
-        && !matches!(op, NodeType::Deref(_))
-        && !matches!(op, NodeType::Dot(_, _, _))
-    {
-        panic!("not an lvalue: {:?}", node.op);
+// Whether the next item of an initializer list has a designator.
+fn is_designated(items: &VecDeque<Node>) -> bool {
+    items
+        .front()
+        .is_some_and(|item| matches!(item.op, NodeType::Designator(..)))
+}
+
+// Functions of the C library that don't return, which are taken to be
+// _Noreturn however they are declared.
+const NORETURN_LIBRARY: &[&str] = &["abort", "exit", "_Exit", "quick_exit"];
+
+// A call to a function that doesn't return, followed by
+// __builtin_unreachable() so that what comes after it is dead.
+fn noreturn_call(call: Node) -> Node {
+    let ty = call.ty.clone();
+    let mut unreachable = Node::new(NodeType::Builtin(Builtin::Unreachable, vec![]));
+    unreachable.ty = Type::void_ty();
+    let mut node = Node::new_binop(TokenType::Comma, call, unreachable);
+    node.ty = ty;
+    node
+}
+
+// Whether `expr` doesn't return, as a call to a _Noreturn function.
+fn terminates(expr: &Node) -> bool {
+    match expr.op {
+        NodeType::Builtin(Builtin::Trap, _) | NodeType::Builtin(Builtin::Unreachable, _) => true,
+        NodeType::BinOp(TokenType::Comma, ref lhs, ref rhs) => terminates(lhs) || terminates(rhs),
+        _ => false,
     }
 }
 
-fn walk(mut node: Node, decay: bool) -> Node {
+// Whether a loop with the condition `cond` only ends with a break.
+fn is_forever(cond: &Node) -> bool {
+    cond.is_null() || eval(cond).is_some_and(|val| val != 0)
+}
+
+// Whether `stmt` has a break out of the loop or switch it is in.
+fn has_break(stmt: &Node) -> bool {
     use self::NodeType::*;
-    let op = node.op.clone();
-    match op {
-        Num(_) | Null | Break => (),
-        Str(data, len) => {
-            // Quoted from 9cc
-            // > A string literal is converted to a reference to an anonymous
-            // > global variable of type char array.
-            let name = format!(".L.str{}", *STRLABEL.lock().unwrap());
-            *STRLABEL.lock().unwrap() += 1;
-            let var = Var::new_global(node.ty.clone(), name, data, len, false);
-            let name = var.name.clone();
-            GLOBALS.lock().unwrap().push(var);
-
-            let mut ret = Node::new(NodeType::Gvar(name, "".into(), len));
-            ret.ty = node.ty;
-            return maybe_decay(ret, decay);
-        }
-        Ident(ref name) => {
-            if let Some(var) = find_var(name) {
-                match var.scope {
-                    Scope::Local(offset) => {
-                        let mut ret = Node::new(NodeType::Lvar(Scope::Local(offset)));
-                        ret.ty = var.ty.clone();
-                        return maybe_decay(ret, decay);
+    match stmt.op {
+        Break => true,
+        CompStmt(ref stmts) | VecStmt(ref stmts) => stmts.iter().any(has_break),
+        If(_, ref then, ref els) => has_break(then) || els.as_deref().is_some_and(has_break),
+        Case(_, ref stmt) | Default(ref stmt) => has_break(stmt),
+        _ => false,
+    }
+}
+
+// Whether control can reach the end of `stmt`, a checked statement.
+// Only constant loop conditions are looked at, so a loop that never
+// ends without one is taken to end.
+fn falls_through(stmt: &Node) -> bool {
+    use self::NodeType::*;
+    match stmt.op {
+        Return(_) => false,
+        ExprStmt(ref expr) => !terminates(expr),
+        // A case label can be reached whether what comes before it
+        // ends or not.
+        CompStmt(ref stmts) | VecStmt(ref stmts) => stmts.iter().fold(true, |reached, stmt| {
+            let labeled = matches!(stmt.op, Case(..)) || matches!(stmt.op, Default(_));
+            (reached || labeled) && falls_through(stmt)
+        }),
+        If(_, ref then, Some(ref els)) => falls_through(then) || falls_through(els),
+        For(_, ref cond, _, ref body) => !is_forever(cond) || has_break(body),
+        DoWhile(ref body, ref cond) => {
+            has_break(body) || (falls_through(body) && !is_forever(cond))
+        }
+        Switch(_, ref body, _, has_default) => {
+            !has_default || falls_through(body) || has_break(body)
+        }
+        Case(_, ref stmt) | Default(ref stmt) => falls_through(stmt),
+        _ => true,
+    }
+}
+
+fn is_ptr(ty: &Type) -> bool {
+    matches!(ty.ty, Ctype::Ptr(_))
+}
+
+// Whether `node` is a null pointer constant: an integer constant
+// expression that is 0, or one converted to `void *`.
+fn is_null_ptr(node: &Node) -> bool {
+    let is_void_ptr = match node.ty.ty {
+        Ctype::Ptr(ref ptr_to) => matches!(ptr_to.ty, Ctype::Void),
+        _ => false,
+    };
+    (node.ty.is_integer() || is_void_ptr) && eval(node) == Some(0)
+}
+
+// The type C's usual arithmetic conversions give two integers: chars
+// become ints, and then the wider type wins, or the unsigned one of two
+// as wide. A long holds any unsigned int, so it wins over one. A double
+// wins over anything else, and a float over integers.
+fn common_type(a: &Rc<Type>, b: &Rc<Type>) -> Rc<Type> {
+    if a.is_float() || b.is_float() {
+        return match (&a.ty, &b.ty) {
+            (Ctype::Double, _) | (_, Ctype::Double) => Type::double_ty(),
+            _ => Type::float_ty(),
+        };
+    }
+    let promote = |t: &Rc<Type>| {
+        if t.size < 4 {
+            Type::int_ty()
+        } else {
+            t.clone()
+        }
+    };
+    let (a, b) = (promote(a), promote(b));
+    if a.size != b.size {
+        return if a.size > b.size { a } else { b };
+    }
+    if a.is_unsigned {
+        a
+    } else {
+        b
+    }
+}
+
+// Converts the operands of a binary operator to their common type, if
+// both are arithmetic, and returns the type of the result with them.
+fn usual_conversions(lhs: Node, rhs: Node) -> (Node, Node, Rc<Type>) {
+    if !lhs.ty.is_arithmetic() || !rhs.ty.is_arithmetic() {
+        let ty = lhs.ty.clone();
+        return (lhs, rhs, ty);
+    }
+    let ty = common_type(&lhs.ty, &rhs.ty);
+    (convert(lhs, &ty), convert(rhs, &ty), ty)
+}
+
+// `node` as the condition of an if, a loop or ?:, an operand of && or
+// ||, or that of !. Backends test the register a condition is in for
+// zero, which the bits of -0.0 aren't, so a floating one is compared
+// with 0.
+fn condition(node: Node) -> Node {
+    if !node.ty.is_float() {
+        return node;
+    }
+    let mut zero = Node::new(NodeType::FNum(0.0));
+    zero.ty = node.ty.clone();
+    let mut cmp = Node::new_binop(TokenType::NE, node, zero);
+    cmp.ty = Type::int_ty();
+    cmp
+}
+
+impl Sema {
+    fn new(redzones: bool) -> Self {
+        Sema {
+            globals: vec![],
+            env: Env::new(None),
+            strlabel: 0,
+            stacksize: 0,
+            redzones,
+            span: None,
+            pos: None,
+            returning: Type::int_ty(),
+            noreturn: false,
+        }
+    }
+
+    fn location(&self) -> Option<Location> {
+        match self.pos {
+            Some(ref pos) => Some(pos.location()),
+            None => self.span.as_ref().map(Span::location),
+        }
+    }
+
+    fn error(&self, msg: String) -> ! {
+        diagnostic::error(msg, self.location())
+    }
+
+    fn enter_scope(&mut self) {
+        let env = mem::replace(&mut self.env, Env::new(None));
+        self.env = Env::new(Some(Box::new(env)));
+    }
+
+    fn leave_scope(&mut self) {
+        let next = self.env.next.take().unwrap();
+        self.env = *next;
+    }
+
+    fn find_var(&self, name: Symbol) -> Option<Var> {
+        let mut env = Some(&self.env);
+        while let Some(e) = env {
+            if let Some(var) = e.vars.get(&name) {
+                return Some(var.clone());
+            }
+            env = e.next.as_deref();
+        }
+        None
+    }
+
+    // The name in scope most like `name`, to suggest for it: of a
+    // function if `func`, and of a variable if not.
+    fn similar(&self, name: Symbol, func: bool) -> Option<String> {
+        let mut names = vec![];
+        let mut env = Some(&self.env);
+        while let Some(e) = env {
+            for (n, var) in &e.vars {
+                if matches!(var.ty.ty, Ctype::Func(..)) == func {
+                    names.push(n.as_str());
+                }
+            }
+            env = e.next.as_deref();
+        }
+        closest(&name.as_str(), names)
+    }
+
+    fn check_lval(&self, node: &Node) {
+        let op = &node.op;
+        if !matches!(op, NodeType::Lvar(_))
+            && !matches!(op, NodeType::Gvar(_, _, _, _))
+            && !matches!(op, NodeType::Deref(_))
+            && !matches!(op, NodeType::Dot(_, _, _))
+        {
+            self.error(format!("not an lvalue: {:?}", node.op));
+        }
+    }
+
+    // A struct can only be assigned one of the same type, and only
+    // with `=`.
+    fn check_struct_assign(&self, op: &TokenType, to: &Rc<Type>, from: &Rc<Type>) {
+        let is_struct = |t: &Type| matches!(t.ty, Ctype::Struct(_));
+        if !is_struct(to) && !is_struct(from) {
+            return;
+        }
+        if *op != TokenType::Equal {
+            self.error("invalid operands to a compound assignment of a struct".into());
+        }
+        if !same_struct(to, from) {
+            self.error("incompatible types in struct assignment".into());
+        }
+    }
+
+    // Warns that the end of the body of the function `name` is
+    // reached, which it shouldn't be if the function is _Noreturn or
+    // returns a value. main returns 0 there.
+    fn check_end(&self, name: Symbol) {
+        let (msg, code) = if self.noreturn {
+            ("'_Noreturn' function does return", "invalid-noreturn")
+        } else if !matches!(self.returning.ty, Ctype::Void) && name != "main" {
+            ("control reaches end of non-void function", "return-type")
+        } else {
+            return;
+        };
+        diagnostic::warn(
+            Diagnostic::new(Severity::Warning, msg.into(), self.location()).with_code(code),
+        );
+    }
+
+    // Warns of a conversion between a pointer and an integer that has
+    // no cast, in `what`, such as an assignment. A null pointer
+    // constant converts to any pointer.
+    fn check_int_conversion(&self, what: &str, to: &Type, from: &Node) {
+        let msg = if is_ptr(to) && from.ty.is_integer() && !is_null_ptr(from) {
+            "makes pointer from integer"
+        } else if to.is_integer() && is_ptr(&from.ty) {
+            "makes integer from pointer"
+        } else {
+            return;
+        };
+        self.warn_int_conversion(&format!("{} {} without a cast", what, msg));
+    }
+
+    // A float or a double only converts to and from arithmetic types.
+    fn check_float_conversion(&self, to: &Type, from: &Type) {
+        let bad = |a: &Type, b: &Type| a.is_float() && !b.is_arithmetic();
+        if !matches!(to.ty, Ctype::Void) && (bad(to, from) || bad(from, to)) {
+            self.error(format!(
+                "cannot convert '{}' to '{}'",
+                pretty::declaration(from, ""),
+                pretty::declaration(to, "")
+            ));
+        }
+    }
+
+    // Errors if `op`, which only takes integers, has a floating operand.
+    fn check_integer_operands(&self, op: &TokenType, lhs: &Type, rhs: &Type) {
+        if lhs.is_float() || rhs.is_float() {
+            self.error(format!(
+                "invalid operands to binary {}",
+                pretty::binop(op).0
+            ));
+        }
+    }
+
+    // Converts the arguments of a call to the types of the parameters
+    // that `params` has for them, if a float or a double is involved. The
+    // others are promoted: a float becomes a double. An integer goes in
+    // a register as it is, extended the way its type says, which is how
+    // an integer parameter reads it (see convert).
+    fn convert_args(&self, args: Vec<Node>, params: &[Rc<Type>]) -> Vec<Node> {
+        args.into_iter()
+            .enumerate()
+            .map(|(i, arg)| match params.get(i) {
+                Some(param) if param.is_float() || arg.ty.is_float() => {
+                    self.check_float_conversion(param, &arg.ty);
+                    convert(arg, param)
+                }
+                None if matches!(arg.ty.ty, Ctype::Float) => convert(arg, &Type::double_ty()),
+                _ => arg,
+            })
+            .collect()
+    }
+
+    fn warn_int_conversion(&self, msg: &str) {
+        diagnostic::warn(
+            Diagnostic::new(Severity::Warning, msg.into(), self.location())
+                .with_code("int-conversion"),
+        );
+    }
+
+    // An explicit conversion of `expr` to `ty`, a scalar type or void.
+    // Integer constants are converted here, so that `(void *)0` is a
+    // null pointer constant.
+    fn cast(&self, expr: Node, ty: &Rc<Type>) -> Node {
+        if let Ctype::Void = ty.ty {
+            let mut node = Node::new_binop(TokenType::Comma, expr, Node::new_num(0));
+            node.ty = ty.clone();
+            return node;
+        }
+        let is_scalar = |t: &Type| t.is_arithmetic() || is_ptr(t);
+        if !is_scalar(ty) || !is_scalar(&expr.ty) {
+            self.error(format!(
+                "cannot convert '{}' to '{}'",
+                pretty::declaration(&expr.ty, ""),
+                pretty::declaration(ty, "")
+            ));
+        }
+        if ty.is_float() || expr.ty.is_float() {
+            self.check_float_conversion(ty, &expr.ty);
+            return convert(expr, ty);
+        }
+        let mut node = match eval(&expr) {
+            Some(val) if expr.ty.is_integer() => Node::new_num(val),
+            _ => Node::new(NodeType::Cast(Box::new(expr))),
+        };
+        node.ty = ty.clone();
+        if let NodeType::Num(_) = node.op {
+            node.op = NodeType::Num(eval(&node).unwrap());
+        }
+        node
+    }
+
+    // Reserves stack space for a local variable of type `ty` and returns
+    // its offset from the base pointer.
+    //
+    // With redzones, an array gets REDZONE bytes on either side, and it
+    // starts on an 8-byte boundary so that each shadow byte describes
+    // one array's memory only.
+    // The type and offset of the member `name` of a struct of type `ty`.
+    fn member(&self, ty: &Rc<Type>, name: Symbol) -> (Rc<Type>, usize) {
+        let ty = Type::completed(ty);
+        let members = match ty.ty {
+            Ctype::Struct(ref members) => members,
+            _ => self.error("struct expected before '.'".into()),
+        };
+        if members.is_empty() {
+            self.error("incomplete type".into());
+        }
+        let found = members.iter().find_map(|m| match m.op {
+            NodeType::Vardef(m_name, _, Scope::Local(offset)) if m_name == name => {
+                Some((Type::completed(&m.ty), offset))
+            }
+            _ => None,
+        });
+        if let Some(found) = found {
+            return found;
+        }
+        let names = members.iter().filter_map(|m| match m.op {
+            NodeType::Vardef(name, ..) => Some(name.as_str()),
+            _ => None,
+        });
+        diagnostic::raise(
+            Diagnostic::new(
+                Severity::Error,
+                format!("member missing: {}", name),
+                self.location(),
+            )
+            .with_code("no-member")
+            .with_suggestion(closest(&name.as_str(), names)),
+        );
+    }
+
+    // The offset and type of `member`, a designator of a member of a
+    // `ty` made by the parser for __builtin_offsetof. Array indices must
+    // be constant.
+    fn offsetof(&mut self, ty: &Rc<Type>, member: Node) -> (usize, Rc<Type>) {
+        match member.op {
+            NodeType::Ident(name) => {
+                let (ty, offset) = self.member(ty, name);
+                (offset, ty)
+            }
+            NodeType::Dot(expr, name, _) => {
+                let (base, outer) = self.offsetof(ty, *expr);
+                let (ty, offset) = self.member(&outer, name);
+                (base + offset, ty)
+            }
+            NodeType::Deref(index) => {
+                let (ary, idx) = match index.op {
+                    NodeType::BinOp(_, ary, idx) => (ary, idx),
+                    _ => unreachable!(),
+                };
+                let (base, outer) = self.offsetof(ty, *ary);
+                let elem = match outer.ty {
+                    Ctype::Ary(ref elem, _) => elem.clone(),
+                    _ => self.error("subscripted value is not an array".into()),
+                };
+                let idx = self.walk(*idx, true);
+                let idx = match eval(&idx) {
+                    Some(idx) => idx,
+                    None => self.error("array index in offsetof is not a constant".into()),
+                };
+                let offset = (base as i64).wrapping_add(idx.wrapping_mul(elem.size as i64));
+                (offset as usize, elem)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn alloc_local(&mut self, ty: &Type) -> usize {
+        let stacksize = self.stacksize;
+        if self.redzones && matches!(ty.ty, Ctype::Ary(_, _)) {
+            let offset = roundup(stacksize, 8) + REDZONE + roundup(ty.size, 8);
+            self.stacksize = offset + REDZONE;
+            return offset;
+        }
+        self.stacksize = roundup(stacksize, ty.align) + ty.size;
+        self.stacksize
+    }
+
+    fn walk(&mut self, node: Node, decay: bool) -> Node {
+        let outer = self.pos.clone();
+        if let Some(ref span) = node.span {
+            self.span = Some(span.clone());
+            self.pos = None;
+        } else if node.pos.is_some() {
+            self.pos = node.pos.clone();
+        }
+        let pos = node.pos.clone();
+        let mut node = self.walk_node(node, decay);
+        // What the expression became is where it was.
+        if node.pos.is_none() {
+            node.pos = pos;
+        }
+        self.pos = outer;
+        node
+    }
+
+    fn walk_node(&mut self, mut node: Node, decay: bool) -> Node {
+        use self::NodeType::*;
+        let op = node.op.clone();
+        match op {
+            Num(_) | FNum(_) | Null | Break | Asm(_) => (),
+            Str(data, len) => {
+                // Quoted from 9cc
+                // > A string literal is converted to a reference to an anonymous
+                // > global variable of type char array.
+                let name = format!(".L.str{}", self.strlabel);
+                self.strlabel += 1;
+                let var =
+                    Var::new_global(node.ty.clone(), name, data.into_bytes(), len, false, false);
+                let name = var.name.clone();
+                self.globals.push(var);
+
+                let mut ret = Node::new(NodeType::Gvar(name, vec![], len, false));
+                ret.ty = node.ty;
+                return maybe_decay(ret, decay);
+            }
+            Ident(name) => {
+                if let Some(var) = self.find_var(name) {
+                    match var.scope {
+                        Scope::Local(offset) => {
+                            let mut ret = Node::new(NodeType::Lvar(Scope::Local(offset)));
+                            ret.ty = Type::completed(&var.ty);
+                            return maybe_decay(ret, decay);
+                        }
+                        Scope::Global(ref data, len, _, is_tls, _) => {
+                            let mut ret = Node::new(NodeType::Gvar(
+                                var.name.clone(),
+                                data.clone(),
+                                len,
+                                is_tls,
+                            ));
+                            ret.ty = Type::completed(&var.ty);
+                            return maybe_decay(ret, decay);
+                        }
                     }
-                    Scope::Global(ref data, len, _) => {
-                        let mut ret =
-                            Node::new(NodeType::Gvar(var.name.clone(), data.clone(), len));
-                        ret.ty = var.ty.clone();
-                        return maybe_decay(ret, decay);
+                } else {
+                    diagnostic::raise(
+                        Diagnostic::new(
+                            Severity::Error,
+                            format!("undefined variable: {}", name),
+                            self.location(),
+                        )
+                        .with_code("undeclared-identifier")
+                        .with_suggestion(self.similar(name, false)),
+                    );
+                }
+            }
+            // A brace-enclosed list, or a string for a char array,
+            // becomes a store for each thing it initializes.
+            Vardef(name, Some(init), _)
+                if matches!(init.op, InitList(_))
+                    || (is_char_array(&node.ty) && matches!(init.op, Str(..))) =>
+            {
+                let (ty, inits) = self.lower_init(&node.ty, *init);
+                node.ty = ty;
+                let offset = self.alloc_local(&node.ty);
+                self.env.vars.insert(
+                    name,
+                    Var::new(node.ty.clone(), name.to_string(), Scope::Local(offset)),
+                );
+                let mut stmts = self.init_local(&node, offset, inits);
+                let mut def = node.clone();
+                def.op = Vardef(name, None, Scope::Local(offset));
+                stmts.insert(0, def);
+                node.op = VecStmt(stmts);
+            }
+            Vardef(name, init_may, _) => {
+                let offset = self.alloc_local(&node.ty);
+
+                self.env.vars.insert(
+                    name,
+                    Var::new(node.ty.clone(), name.to_string(), Scope::Local(offset)),
+                );
+
+                let mut init = None;
+                if let Some(init2) = init_may {
+                    if let Ctype::Ary(..) = node.ty.ty {
+                        self.error(format!("invalid initializer for array: {}", name));
                     }
+                    let init2 = self.walk(*init2, true);
+                    self.check_struct_assign(&TokenType::Equal, &node.ty, &init2.ty);
+                    self.check_float_conversion(&node.ty, &init2.ty);
+                    self.check_int_conversion("initialization", &node.ty, &init2);
+                    init = Some(Box::new(convert(init2, &node.ty)));
                 }
-            } else {
-                panic!("undefined variable: {}", name);
-            }
-        }
-        Vardef(name, init_may, _) => {
-            let stacksize = *STACKSIZE.lock().unwrap();
-            *STACKSIZE.lock().unwrap() = roundup(stacksize, node.ty.align);
-            *STACKSIZE.lock().unwrap() += node.ty.size;
-            let offset = *STACKSIZE.lock().unwrap();
-
-            ENV.lock().unwrap().vars.insert(
-                name.clone(),
-                Var::new(node.ty.clone(), name.clone(), Scope::Local(offset)),
-            );
-
-            let mut init = None;
-            if let Some(init2) = init_may {
-                init = Some(Box::new(walk(*init2, true)));
-            }
-            node.op = Vardef(name, init, Scope::Local(offset));
-        }
-        If(mut cond, mut then, els_may) => {
-            cond = Box::new(walk(*cond, true));
-            then = Box::new(walk(*then, true));
-            let mut new_els = None;
-            if let Some(els) = els_may {
-                new_els = Some(Box::new(walk(*els, true)));
-            }
-            node.op = If(cond, then, new_els);
-        }
-        Ternary(mut cond, mut then, mut els) => {
-            cond = Box::new(walk(*cond, true));
-            then = Box::new(walk(*then, true));
-            els = Box::new(walk(*els, true));
-            node.ty = then.ty.clone();
-            node.op = Ternary(cond, then, els);
-        }
-        For(init, cond, inc, body) => {
-            let f = |(init, cond, inc, body)| -> (Node, Node, Node, Node) {
-                (
-                    walk(init, true),
-                    walk(cond, true),
-                    walk(inc, true),
-                    walk(body, true),
-                )
-            };
-            let (init, cond, inc, body) = into_new_range((*init, *cond, *inc, *body), Box::new(f));
-            node.op = For(
-                Box::new(init),
-                Box::new(cond),
-                Box::new(inc),
-                Box::new(body),
-            );
-        }
-        DoWhile(body, cond) => {
-            node.op = DoWhile(Box::new(walk(*body, true)), Box::new(walk(*cond, true)));
-        }
-        Dot(mut expr, name, _) => {
-            expr = Box::new(walk(*expr, true));
-            let offset;
-            if let Ctype::Struct(ref members) = expr.ty.ty {
-                if members.is_empty() {
-                    panic!("incomplete type");
+                node.op = Vardef(name, init, Scope::Local(offset));
+            }
+            If(mut cond, mut then, els_may) => {
+                cond = Box::new(condition(self.walk(*cond, true)));
+                then = Box::new(self.walk(*then, true));
+                let mut new_els = None;
+                if let Some(els) = els_may {
+                    new_els = Some(Box::new(self.walk(*els, true)));
+                }
+                node.op = If(cond, then, new_els);
+            }
+            Ternary(mut cond, mut then, mut els) => {
+                cond = Box::new(condition(self.walk(*cond, true)));
+                let (l, r, mut ty) =
+                    usual_conversions(self.walk(*then, true), self.walk(*els, true));
+                // A pointer and a null pointer constant give the pointer.
+                if is_ptr(&r.ty) && l.ty.is_integer() {
+                    if !is_null_ptr(&l) {
+                        self.warn_int_conversion(
+                            "pointer/integer type mismatch in conditional expression",
+                        );
+                    }
+                    ty = r.ty.clone();
+                } else if is_ptr(&l.ty) && r.ty.is_integer() && !is_null_ptr(&r) {
+                    self.warn_int_conversion(
+                        "pointer/integer type mismatch in conditional expression",
+                    );
                 }
-                let m_may = members.iter().find(|m| {
-                    if let NodeType::Vardef(ref m_name, _, _) = m.op {
-                        if m_name != &name {
-                            return false;
+                then = Box::new(l);
+                els = Box::new(r);
+                node.ty = ty;
+                node.op = Ternary(cond, then, els);
+            }
+            For(init, cond, inc, body) => {
+                self.enter_scope();
+                let init = self.walk(*init, true);
+                let cond = condition(self.walk(*cond, true));
+                let inc = self.walk(*inc, true);
+                let body = self.walk(*body, true);
+                self.leave_scope();
+                node.op = For(
+                    Box::new(init),
+                    Box::new(cond),
+                    Box::new(inc),
+                    Box::new(body),
+                );
+            }
+            Switch(cond, body, cases, has_default) => {
+                let cond = self.walk(*cond, true);
+                if !cond.ty.is_integer() {
+                    self.error("switch quantity not an integer".into());
+                }
+                // Case values are converted to the promoted type of the
+                // controlling expression, as it is compared in.
+                let ty = if cond.ty.size < 4 {
+                    Type::int_ty()
+                } else {
+                    cond.ty.clone()
+                };
+                let mut vals = vec![];
+                let cases = cases
+                    .into_iter()
+                    .map(|case| {
+                        let pos = case.pos.clone();
+                        let case = self.walk(case, true);
+                        let error = |msg: &str| {
+                            diagnostic::error(msg.into(), pos.as_ref().map(Pos::location))
+                        };
+                        if !case.ty.is_integer() || eval(&case).is_none() {
+                            error("constant expression expected");
+                        }
+                        let case = self.cast(case, &ty);
+                        let val = eval(&case).unwrap();
+                        if vals.contains(&val) {
+                            error("duplicate case value");
+                        }
+                        vals.push(val);
+                        case
+                    })
+                    .collect();
+                node.op = Switch(
+                    Box::new(cond),
+                    Box::new(self.walk(*body, true)),
+                    cases,
+                    has_default,
+                );
+            }
+            Case(idx, body) => node.op = Case(idx, Box::new(self.walk(*body, true))),
+            Default(body) => node.op = Default(Box::new(self.walk(*body, true))),
+            DoWhile(body, cond) => {
+                node.op = DoWhile(
+                    Box::new(self.walk(*body, true)),
+                    Box::new(condition(self.walk(*cond, true))),
+                );
+            }
+            Dot(mut expr, name, _) => {
+                expr = Box::new(self.walk(*expr, true));
+                let (ty, offset) = self.member(&expr.ty, name);
+                node.ty = ty;
+                node.op = NodeType::Dot(expr, name, offset);
+                return maybe_decay(node, decay);
+            }
+            BinOp(token_type, mut lhs, mut rhs) => {
+                use self::TokenType::*;
+                match token_type {
+                    Plus | Minus => {
+                        *lhs = self.walk(*lhs, true);
+                        rhs = Box::new(self.walk(*rhs, true));
+
+                        if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
+                            swap(&mut lhs, &mut rhs);
+                        }
+                        if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
+                            self.error(format!("'pointer {:?} pointer' is not defined", node.op))
                         }
-                        return true;
+
+                        if let Ctype::Ptr(ref ptr_to) = lhs.ty.ty {
+                            if rhs.ty.is_float() {
+                                self.error(format!(
+                                    "invalid operands to binary {}",
+                                    pretty::binop(&token_type).0
+                                ));
+                            }
+                            rhs = Box::new(Node::scale_ptr(*rhs, &lhs.ty, ptr_to.size));
+                            node.ty = lhs.ty.clone();
+                        } else {
+                            let (l, r, ty) = usual_conversions(*lhs, *rhs);
+                            *lhs = l;
+                            *rhs = r;
+                            node.ty = ty;
+                        }
+                        node.op = BinOp(token_type, lhs, rhs);
                     }
-                    false
-                });
+                    AddEQ | SubEQ => {
+                        *lhs = self.walk(*lhs, false);
+                        self.check_lval(&lhs);
+                        rhs = Box::new(self.walk(*rhs, true));
+                        self.check_struct_assign(&token_type, &lhs.ty, &rhs.ty);
+                        if lhs.ty.is_atomic && rhs.ty.is_float() {
+                            self.error(
+                                "only integer += and -= of an _Atomic object are supported".into(),
+                            );
+                        }
 
-                if let Some(m) = m_may {
-                    if let NodeType::Vardef(_, _, Scope::Local(offset2)) = m.op {
-                        node.ty = m.ty.clone();
-                        offset = offset2;
+                        if let Ctype::Ptr(ref ptr_to) = lhs.ty.ty {
+                            if rhs.ty.is_float() {
+                                self.error(format!(
+                                    "invalid operands to binary {}",
+                                    pretty::binop(&token_type).0
+                                ));
+                            }
+                            rhs = Box::new(Node::scale_ptr(*rhs, &lhs.ty, ptr_to.size));
+                        } else if lhs.ty.is_float() || rhs.ty.is_float() {
+                            // Added in their common type, which gen_ir
+                            // converts the result from.
+                            self.check_float_conversion(&lhs.ty, &rhs.ty);
+                            let ty = common_type(&lhs.ty, &rhs.ty);
+                            *rhs = convert(*rhs, &ty);
+                        } else {
+                            *rhs = convert(*rhs, &lhs.ty);
+                        }
+                        node.op = BinOp(token_type, lhs.clone(), rhs);
+                        node.ty = lhs.ty;
+                    }
+                    Equal | MulEQ | DivEQ | ModEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ => {
+                        *lhs = self.walk(*lhs, false);
+                        self.check_lval(&lhs);
+                        let rhs = self.walk(*rhs, true);
+                        self.check_struct_assign(&token_type, &lhs.ty, &rhs.ty);
+                        // Only an add has an atomic instruction; the rest
+                        // would take a compare-and-swap loop.
+                        if token_type != Equal && lhs.ty.is_atomic {
+                            self.error("only += and -= of an _Atomic object are supported".into());
+                        }
+                        if token_type == Equal {
+                            self.check_float_conversion(&lhs.ty, &rhs.ty);
+                            self.check_int_conversion("assignment", &lhs.ty, &rhs);
+                        } else if token_type != MulEQ && token_type != DivEQ {
+                            self.check_integer_operands(&token_type, &lhs.ty, &rhs.ty);
+                        }
+                        // A multiplication or a division with a float or
+                        // a double is made in their common type, which
+                        // gen_ir converts the result from.
+                        let rhs = if token_type != Equal && (lhs.ty.is_float() || rhs.ty.is_float())
+                        {
+                            self.check_float_conversion(&lhs.ty, &rhs.ty);
+                            let ty = common_type(&lhs.ty, &rhs.ty);
+                            convert(rhs, &ty)
+                        } else {
+                            convert(rhs, &lhs.ty)
+                        };
+                        node.op = BinOp(token_type, lhs.clone(), Box::new(rhs));
+                        node.ty = lhs.ty;
+                    }
+                    Comma => {
+                        *lhs = self.walk(*lhs, true);
+                        rhs = Box::new(self.walk(*rhs, true));
+                        node.op = BinOp(token_type, lhs.clone(), rhs);
+                        node.ty = lhs.ty;
+                    }
+                    // The shift count doesn't change the type.
+                    SHL | SHR => {
+                        *lhs = self.walk(*lhs, true);
+                        rhs = Box::new(self.walk(*rhs, true));
+                        self.check_integer_operands(&token_type, &lhs.ty, &rhs.ty);
+                        node.ty = if lhs.ty.is_integer() {
+                            common_type(&lhs.ty, &lhs.ty)
+                        } else {
+                            lhs.ty.clone()
+                        };
+                        node.op = BinOp(token_type, lhs, rhs);
+                    }
+                    // Comparisons are ints, whatever they compare.
+                    EQ | NE | LE | GE | LeftAngleBracket | RightAngleBracket => {
+                        let (l, r, _) =
+                            usual_conversions(self.walk(*lhs, true), self.walk(*rhs, true));
+                        let mixed = |a: &Node, b: &Node| {
+                            is_ptr(&a.ty) && b.ty.is_integer() && !is_null_ptr(b)
+                        };
+                        if mixed(&l, &r) || mixed(&r, &l) {
+                            self.warn_int_conversion("comparison between pointer and integer");
+                        }
+                        if l.ty.is_float() != r.ty.is_float() {
+                            self.error(format!(
+                                "invalid operands to binary {}",
+                                pretty::binop(&token_type).0
+                            ));
+                        }
+                        // `a > b` is `b < a`, and `a >= b` is `b <= a`.
+                        node.op = match token_type {
+                            RightAngleBracket => BinOp(LeftAngleBracket, Box::new(r), Box::new(l)),
+                            GE => BinOp(LE, Box::new(r), Box::new(l)),
+                            _ => BinOp(token_type, Box::new(l), Box::new(r)),
+                        };
+                        node.ty = Type::int_ty();
+                    }
+                    Logand | Logor => {
+                        *lhs = condition(self.walk(*lhs, true));
+                        rhs = Box::new(condition(self.walk(*rhs, true)));
+                        node.op = BinOp(token_type, lhs, rhs);
+                        node.ty = Type::int_ty();
+                    }
+                    _ => {
+                        let (l, r, ty) =
+                            usual_conversions(self.walk(*lhs, true), self.walk(*rhs, true));
+                        if token_type != Mul && token_type != Div {
+                            self.check_integer_operands(&token_type, &l.ty, &r.ty);
+                        }
+                        node.op = BinOp(token_type, Box::new(l), Box::new(r));
+                        node.ty = ty;
+                    }
+                }
+            }
+            PostInc(mut expr) => {
+                expr = Box::new(self.walk(*expr, true));
+                self.check_lval(&expr);
+                node.ty = expr.ty.clone();
+                node.op = PostInc(expr);
+            }
+            PostDec(mut expr) => {
+                expr = Box::new(self.walk(*expr, true));
+                self.check_lval(&expr);
+                node.ty = expr.ty.clone();
+                node.op = PostDec(expr);
+            }
+            Neg(mut expr) => {
+                expr = Box::new(self.walk(*expr, true));
+                node.ty = if expr.ty.is_integer() {
+                    common_type(&expr.ty, &expr.ty)
+                } else {
+                    expr.ty.clone()
+                };
+                node.op = Neg(expr);
+            }
+            Tilde(mut expr) => {
+                expr = Box::new(self.walk(*expr, true));
+                if !expr.ty.is_integer() {
+                    self.error("wrong type argument to bit-complement".into());
+                }
+                node.ty = common_type(&expr.ty, &expr.ty);
+                node.op = Tilde(expr);
+            }
+            Exclamation(mut expr) => {
+                expr = Box::new(condition(self.walk(*expr, true)));
+                node.ty = Type::int_ty();
+                node.op = Exclamation(expr);
+            }
+            Addr(mut expr) => {
+                expr = Box::new(self.walk(*expr, true));
+                // A function is its address already.
+                if let Addr(ref func) = expr.op {
+                    if let Ctype::Func(..) = func.ty.ty {
+                        return *expr;
+                    }
+                }
+                self.check_lval(&expr);
+                node.ty = Type::ptr_to(expr.ty.clone());
+                node.op = Addr(expr);
+            }
+            Deref(mut expr) => {
+                expr = Box::new(self.walk(*expr, true));
+                match expr.ty.ty {
+                    Ctype::Ptr(ref ptr_to) => node.ty = Type::completed(ptr_to),
+                    Ctype::Void => self.error("cannot dereference void pointer".into()),
+                    _ => self.error("operand must be a pointer".into()),
+                }
+                node.op = Deref(expr);
+                return maybe_decay(node, decay);
+            }
+            Return(expr) => {
+                if self.noreturn {
+                    diagnostic::warn(
+                        Diagnostic::new(
+                            Severity::Warning,
+                            "function declared '_Noreturn' has a 'return' statement".into(),
+                            self.location(),
+                        )
+                        .with_code("invalid-noreturn"),
+                    );
+                }
+                let expr = self.walk(*expr, true);
+                self.check_float_conversion(&self.returning, &expr.ty);
+                self.check_int_conversion("return", &self.returning, &expr);
+                let expr = convert(expr, &self.returning);
+                node.op = Return(Box::new(expr));
+            }
+            ExprStmt(expr) => node.op = ExprStmt(Box::new(self.walk(*expr, true))),
+            Cast(expr) => {
+                let expr = self.walk(*expr, true);
+                return self.cast(expr, &node.ty);
+            }
+            Sizeof(mut expr) => {
+                *expr = self.walk(*expr, false);
+                node = Node::new_int(expr.ty.size as i32)
+            }
+            Alignof(mut expr) => {
+                *expr = self.walk(*expr, false);
+                node = Node::new_int(expr.ty.align as i32)
+            }
+            Offsetof(member) => {
+                let (offset, _) = self.offsetof(&node.ty, *member);
+                node = Node::new_num(offset as i64);
+                node.ty = Type::size_t();
+            }
+            Call(name, mut args) => {
+                let builtin = name.as_str();
+                if builtin.starts_with("__builtin_") || builtin.starts_with("__atomic_") {
+                    return self.walk_builtin(node, builtin, args);
+                }
+                let mut noreturn = NORETURN_LIBRARY.contains(&&*builtin);
+                let mut params = vec![];
+                if let Some(var) = self.find_var(name) {
+                    if let Ctype::Func(ref returning, ref types, _, is_noreturn) = var.ty.ty {
+                        node.ty = returning.clone();
+                        params = types.clone();
+                        noreturn |= is_noreturn;
+                    } else if is_func_ptr(&var.ty) {
+                        let callee = Box::new(Node::new(Ident(name)));
+                        node.op = CallPtr(callee, args);
+                        return self.walk(node, decay);
                     } else {
-                        unreachable!()
+                        diagnostic::warn(
+                            Diagnostic::new(
+                                Severity::Warning,
+                                format!("called object '{}' is not a function", name),
+                                self.location(),
+                            )
+                            .with_code("not-a-function"),
+                        );
                     }
                 } else {
-                    panic!("member missing: {}", name);
+                    diagnostic::warn(
+                        Diagnostic::new(
+                            Severity::Warning,
+                            format!("implicit declaration of function '{}'", name),
+                            self.location(),
+                        )
+                        .with_code("implicit-function-declaration")
+                        .with_note(format!("assuming '{}' returns int", name))
+                        .with_suggestion(self.similar(name, true)),
+                    );
                 }
-            } else {
-                panic!("struct expected before '.'");
+
+                args = args.into_iter().map(|arg| self.walk(arg, true)).collect();
+                node.op = Call(name, self.convert_args(args, &params));
+                let call = self.struct_return(node);
+                return if noreturn { noreturn_call(call) } else { call };
             }
+            CallPtr(callee, args) => {
+                let callee = self.walk(*callee, true);
+                let (noreturn, params) = match callee.ty.ty {
+                    Ctype::Ptr(ref ptr_to) => match ptr_to.ty {
+                        Ctype::Func(ref returning, ref params, _, noreturn) => {
+                            node.ty = returning.clone();
+                            (noreturn, params.clone())
+                        }
+                        _ => self.error("called object is not a function".into()),
+                    },
+                    _ => self.error("called object is not a function".into()),
+                };
+                let args = args.into_iter().map(|arg| self.walk(arg, true)).collect();
+                node.op = CallPtr(Box::new(callee), self.convert_args(args, &params));
+                let call = self.struct_return(node);
+                return if noreturn { noreturn_call(call) } else { call };
+            }
+            CompStmt(mut stmts) => {
+                self.enter_scope();
+                // A statement with an error is dropped, to check the rest.
+                let scopes = self.env.depth();
+                stmts = stmts
+                    .into_iter()
+                    .map(|stmt| {
+                        diagnostic::recover(|| self.walk(stmt, true)).unwrap_or_else(|| {
+                            while self.env.depth() > scopes {
+                                self.leave_scope();
+                            }
+                            Node::new(NodeType::Null)
+                        })
+                    })
+                    .collect();
+                self.leave_scope();
+                node.op = CompStmt(stmts);
+            }
+            VecStmt(mut stmts) => {
+                stmts = stmts
+                    .into_iter()
+                    .map(|stmt| self.walk(stmt, true))
+                    .collect();
+                node.op = VecStmt(stmts);
+            }
+            // The value is that of the last statement if it is an
+            // expression. A `return` in the body gives an int, as in 9cc.
+            StmtExpr(body) => {
+                let returning = mem::replace(&mut self.returning, Type::int_ty());
+                let body = self.walk(*body, true);
+                self.returning = returning;
+                node.ty = match body.op {
+                    CompStmt(ref stmts) => match stmts.last().map(|s| &s.op) {
+                        Some(ExprStmt(expr)) => expr.ty.clone(),
+                        _ => Type::int_ty(),
+                    },
+                    _ => Type::int_ty(),
+                };
+                node.op = StmtExpr(Box::new(body));
+            }
+            _ => self.error("unknown node type".into()),
+        };
+        node
+    }
 
-            node.op = NodeType::Dot(expr, name, offset);
-            return maybe_decay(node, decay);
+    // A struct is returned into memory provided by the caller. Its
+    // address is passed as a hidden first argument of `call`, and the
+    // value of the call is that temporary.
+    fn struct_return(&mut self, mut call: Node) -> Node {
+        if !matches!(call.ty.ty, Ctype::Struct(_)) {
+            return call;
+        }
+        let ty = call.ty.clone();
+        let mut tmp = Node::new(NodeType::Lvar(Scope::Local(self.alloc_local(&ty))));
+        tmp.ty = ty.clone();
+        let mut addr = Node::new(NodeType::Addr(Box::new(tmp.clone())));
+        addr.ty = Type::ptr_to(ty.clone());
+        match call.op {
+            NodeType::Call(_, ref mut args) | NodeType::CallPtr(_, ref mut args) => {
+                args.insert(0, addr)
+            }
+            _ => unreachable!(),
         }
-        BinOp(token_type, mut lhs, mut rhs) => {
-            use self::TokenType::*;
-            match token_type {
-                Plus | Minus => {
-                    lhs = Box::new(walk(*lhs, true));
-                    rhs = Box::new(walk(*rhs, true));
 
-                    if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
-                        swap(&mut lhs, &mut rhs);
-                    }
-                    if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
-                        panic!("'pointer {:?} pointer' is not defined", node.op)
-                    }
+        let mut ret = Node::new_binop(TokenType::Comma, call, tmp);
+        ret.ty = ty;
+        ret
+    }
 
-                    if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
-                        rhs = Box::new(Node::scale_ptr(rhs, &lhs.ty));
-                    }
+    // The items of a brace-enclosed initializer. Expressions are checked
+    // here, except string literals, which may initialize a char array.
+    fn init_list(&mut self, items: Vec<Node>) -> VecDeque<Node> {
+        items
+            .into_iter()
+            .map(|item| self.init_list_item(item))
+            .collect()
+    }
+
+    fn init_list_item(&mut self, item: Node) -> Node {
+        match item.op {
+            NodeType::InitList(_) | NodeType::Str(..) => item,
+            NodeType::Designator(first, last, init) => {
+                let first = Box::new(self.walk(*first, true));
+                let last = last.map(|last| Box::new(self.walk(*last, true)));
+                let init = Box::new(self.init_list_item(*init));
+                Node::new(NodeType::Designator(first, last, init))
+            }
+            _ => self.walk(item, true),
+        }
+    }
+
+    fn excess_initializers(&self, msg: &str) {
+        diagnostic::warn(
+            Diagnostic::new(Severity::Warning, msg.into(), self.location())
+                .with_code("excess-initializers"),
+        );
+    }
 
-                    node.op = BinOp(token_type, lhs.clone(), rhs);
-                    node.ty = lhs.ty;
+    // Lowers the initializer of an object of type `ty` to what it
+    // stores. An array whose length is left out gets it from the
+    // initializer, and the type returned is the one completed.
+    fn lower_init(&mut self, ty: &Rc<Type>, init: Node) -> (Rc<Type>, Vec<Init>) {
+        let mut inits = vec![];
+        let mut ty = ty.clone();
+        if let Ctype::Ary(ref base, 0) = ty.ty {
+            match init.op {
+                NodeType::Str(_, len) if matches!(base.ty, Ctype::Char) => {
+                    ty = Type::ary_of(base.clone(), len);
                 }
-                AddEQ | SubEQ => {
-                    lhs = Box::new(walk(*lhs, false));
-                    check_lval(&*lhs);
-                    rhs = Box::new(walk(*rhs, true));
+                NodeType::InitList(items) => {
+                    let mut items = self.init_list(items);
+                    let len = self.init_array(base, None, 0, &mut items, &mut inits, true);
+                    return (Type::ary_of(base.clone(), len), inits);
+                }
+                _ => {}
+            }
+        }
+        let mut items = self.init_list(vec![init]);
+        self.init_item(&ty, 0, &mut items, &mut inits);
+        (ty, inits)
+    }
 
-                    if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
-                        rhs = Box::new(Node::scale_ptr(rhs, &lhs.ty));
-                    }
-                    node.op = BinOp(token_type, lhs.clone(), rhs);
-                    node.ty = lhs.ty;
+    // Initializes an object of type `ty` at `offset` from the front of
+    // `items`. Without braces of its own, an array or a struct takes as
+    // many items as it has elements or members, following C's rules of
+    // brace elision, and is zeroed past the items it gets.
+    fn init_item(
+        &mut self,
+        ty: &Rc<Type>,
+        offset: usize,
+        items: &mut VecDeque<Node>,
+        inits: &mut Vec<Init>,
+    ) {
+        let item = match items.front() {
+            Some(item) => item,
+            None => return,
+        };
+        let is_aggregate = matches!(ty.ty, Ctype::Ary(..)) || matches!(ty.ty, Ctype::Struct(_));
+        match item.op {
+            NodeType::InitList(_) => {
+                let list = match items.pop_front().unwrap().op {
+                    NodeType::InitList(list) => list,
+                    _ => unreachable!(),
+                };
+                let mut list = self.init_list(list);
+                if is_aggregate {
+                    self.init_aggregate(ty, offset, &mut list, inits, true);
+                } else {
+                    self.init_item(ty, offset, &mut list, inits);
                 }
-                Equal | MulEQ | DivEQ | ModEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ => {
-                    lhs = Box::new(walk(*lhs, false));
-                    check_lval(&*lhs);
-                    node.op = BinOp(token_type, lhs.clone(), Box::new(walk(*rhs, true)));
-                    node.ty = lhs.ty;
+                if !list.is_empty() {
+                    self.excess_initializers("excess elements in initializer");
+                }
+            }
+            NodeType::Str(ref data, _) if is_char_array(ty) => {
+                let mut bytes = data.clone().into_bytes();
+                bytes.push(0);
+                // The terminating NUL is left out if there is no room.
+                if bytes.len() > ty.size + 1 {
+                    self.excess_initializers("initializer-string for char array is too long");
+                }
+                bytes.truncate(ty.size);
+                for (i, b) in bytes.into_iter().enumerate() {
+                    let mut val = Node::new_num(b as i8 as i64);
+                    val.ty = Type::char_ty();
+                    inits.push((offset + i, Type::char_ty(), val));
+                }
+                items.pop_front();
+            }
+            NodeType::Designator(..) => {
+                self.error("array index in non-array initializer".into());
+            }
+            _ if is_aggregate && !same_struct(ty, &item.ty) => {
+                self.init_aggregate(ty, offset, items, inits, false);
+            }
+            _ => {
+                let mut val = items.pop_front().unwrap();
+                if let NodeType::Str(..) = val.op {
+                    val = self.walk(val, true);
                 }
-                _ => {
-                    lhs = Box::new(walk(*lhs, true));
-                    rhs = Box::new(walk(*rhs, true));
-                    node.op = BinOp(token_type, lhs.clone(), rhs);
-                    node.ty = lhs.ty;
+                if matches!(ty.ty, Ctype::Ary(..)) {
+                    self.error("invalid initializer for array".into());
                 }
+                self.check_struct_assign(&TokenType::Equal, ty, &val.ty);
+                self.check_float_conversion(ty, &val.ty);
+                self.check_int_conversion("initialization", ty, &val);
+                inits.push((offset, ty.clone(), convert(val, ty)));
             }
         }
-        PostInc(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            node.ty = expr.ty.clone();
-            node.op = PostInc(expr);
-        }
-        PostDec(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            node.ty = expr.ty.clone();
-            node.op = PostDec(expr);
-        }
-        Neg(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            node.ty = expr.ty.clone();
-            node.op = Neg(expr);
-        }
-        Exclamation(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            node.ty = expr.ty.clone();
-            node.op = Exclamation(expr);
-        }
-        Addr(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            check_lval(&*expr);
-            node.ty = Box::new(Type::ptr_to(expr.ty.clone()));
-            node.op = Addr(expr);
-        }
-        Deref(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            match expr.ty.ty {
-                Ctype::Ptr(ref ptr_to) => node.ty = ptr_to.clone(),
-                Ctype::Void => panic!("cannot dereference void pointer"),
-                _ => panic!("operand must be a pointer"),
-            }
-            node.op = Deref(expr);
-            return maybe_decay(node, decay);
-        }
-        Return(expr) => node.op = Return(Box::new(walk(*expr, true))),
-        ExprStmt(expr) => node.op = ExprStmt(Box::new(walk(*expr, true))),
-        Sizeof(mut expr) => {
-            expr = Box::new(walk(*expr, false));
-            node = Node::new_int(expr.ty.size as i32)
-        }
-        Alignof(mut expr) => {
-            expr = Box::new(walk(*expr, false));
-            node = Node::new_int(expr.ty.align as i32)
-        }
-        Call(name, mut args) => {
-            if let Some(var) = find_var(&name) {
-                if let Ctype::Func(returning) = var.ty.ty {
-                    node.ty = returning;
-                } else {
-                    eprint!("bad function: {}", name);
+    }
+
+    // `braced` says whether `items` are the aggregate's own list, in
+    // braces, or what is left of the list of one it is in. A designator
+    // belongs to the list it is written in, so it ends the latter.
+    fn init_aggregate(
+        &mut self,
+        ty: &Rc<Type>,
+        offset: usize,
+        items: &mut VecDeque<Node>,
+        inits: &mut Vec<Init>,
+        braced: bool,
+    ) {
+        match ty.ty {
+            Ctype::Ary(ref base, len) => {
+                self.init_array(base, Some(len), offset, items, inits, braced);
+            }
+            Ctype::Struct(ref members) => {
+                for member in members {
+                    if !braced && is_designated(items) {
+                        break;
+                    }
+                    if let NodeType::Vardef(_, _, Scope::Local(off)) = member.op {
+                        self.init_item(&member.ty, offset + off, items, inits);
+                    }
                 }
-            } else {
-                eprint!("bad function: {}", name);
             }
+            _ => unreachable!(),
+        }
+    }
+
+    // Initializes the elements of an array of `base` at `offset`, up
+    // to `len` of them if it is known, and returns how many there were.
+    // `[i] = x` moves on to element i, and the GNU `[first ... last] = x`
+    // initializes each element in the range to x, then moves on past
+    // them.
+    fn init_array(
+        &mut self,
+        base: &Rc<Type>,
+        len: Option<usize>,
+        offset: usize,
+        items: &mut VecDeque<Node>,
+        inits: &mut Vec<Init>,
+        braced: bool,
+    ) -> usize {
+        let mut i = 0;
+        let mut count = 0;
+        while !items.is_empty() {
+            if is_designated(items) {
+                if !braced {
+                    break;
+                }
+                let (first, last, init) = match items.pop_front().unwrap().op {
+                    NodeType::Designator(first, last, init) => (first, last, init),
+                    _ => unreachable!(),
+                };
+                let first = self.array_index(&first, len);
+                let last = last.map_or(first, |last| self.array_index(&last, len));
+                if last < first {
+                    self.error("empty index range in initializer".into());
+                }
+                let (mut val, rest) = self.range_value(*init, last > first);
+                for j in first..last {
+                    let mut item = VecDeque::from(vec![val]);
+                    self.init_item(base, offset + j * base.size, &mut item, inits);
+                    val = rest.clone();
+                }
+                items.push_front(val);
+                i = last;
+            } else if len.is_some_and(|len| i >= len) {
+                break;
+            }
+            self.init_item(base, offset + i * base.size, items, inits);
+            i += 1;
+            count = count.max(i);
+        }
+        count
+    }
+
+    // What the first element of a range gets, and what the others do.
+    // A value that isn't constant is evaluated once, into a temporary
+    // that the others are copied from.
+    fn range_value(&mut self, init: Node, several: bool) -> (Node, Node) {
+        if !several
+            || matches!(init.op, NodeType::InitList(_))
+            || matches!(init.op, NodeType::Str(..))
+            || eval(&init).is_some()
+            || eval_addr(&init).is_some()
+        {
+            return (init.clone(), init);
+        }
+        let ty = init.ty.clone();
+        let mut tmp = Node::new(NodeType::Lvar(Scope::Local(self.alloc_local(&ty))));
+        tmp.ty = ty.clone();
+        tmp.pos = init.pos.clone();
+        let mut assign = Node::new_binop(TokenType::Equal, tmp.clone(), init);
+        assign.ty = ty;
+        assign.pos = tmp.pos.clone();
+        (assign, tmp)
+    }
 
-            args = args.into_iter().map(|arg| walk(arg, true)).collect();
-            node.op = Call(name, args);
+    // The value of an array index in a designator, which must be a
+    // constant within the array.
+    fn array_index(&self, node: &Node, len: Option<usize>) -> usize {
+        let idx = match eval(node) {
+            Some(idx) => idx,
+            None => self.error("nonconstant array index in initializer".into()),
+        };
+        if idx < 0 || len.is_some_and(|len| idx as usize >= len) {
+            self.error("array index in initializer exceeds array bounds".into());
         }
-        CompStmt(mut stmts) => {
-            let f = |stmts: Vec<Node>| -> Vec<Node> {
-                stmts.into_iter().map(|stmt| walk(stmt, true)).collect()
+        idx as usize
+    }
+
+    // The statements that initialize the local `node`, which has been
+    // given `offset`. Bytes the initializer doesn't store to are
+    // zeroed first: in words where they are aligned, since some targets
+    // fault on unaligned stores, or all at once if there are many.
+    fn init_local(&mut self, node: &Node, offset: usize, inits: Vec<Init>) -> Vec<Node> {
+        let store = |off: usize, ty: &Rc<Type>, val: Node| {
+            let mut var = Node::new(NodeType::Lvar(Scope::Local(offset - off)));
+            var.ty = ty.clone();
+            let mut assign = Node::new_binop(TokenType::Equal, var, val);
+            assign.ty = ty.clone();
+            Node::new(NodeType::ExprStmt(Box::new(assign)))
+        };
+        let zero = |ty: &Rc<Type>| {
+            let mut val = Node::new_num(0);
+            val.ty = ty.clone();
+            val
+        };
+
+        let mut stmts = vec![];
+        let mut stored = vec![false; node.ty.size];
+        for (off, ty, _) in &inits {
+            stored[*off..*off + ty.size].fill(true);
+        }
+        if stored.iter().filter(|&&b| !b).count() > 64 {
+            let mut var = Node::new(NodeType::Lvar(Scope::Local(offset)));
+            var.ty = node.ty.clone();
+            let mut addr = Node::new(NodeType::Addr(Box::new(var)));
+            addr.ty = Type::ptr_to(node.ty.clone());
+            let mut size = Node::new_num(node.ty.size as i64);
+            size.ty = Type::size_t();
+            let args = vec![addr, zero(&Type::int_ty()), size];
+            let mut memset = Node::new(NodeType::Builtin(Builtin::Memset, args));
+            memset.ty = Type::ptr_to(node.ty.clone());
+            stmts.push(Node::new(NodeType::ExprStmt(Box::new(memset))));
+        } else {
+            let mut off = 0;
+            while off < stored.len() {
+                if stored[off] {
+                    off += 1;
+                    continue;
+                }
+                let ty = vec![Type::llong_ty(), Type::int_ty(), Type::char_ty()]
+                    .into_iter()
+                    .find(|ty| {
+                        (offset - off).is_multiple_of(ty.size)
+                            && stored
+                                .get(off..off + ty.size)
+                                .is_some_and(|s| !s.contains(&true))
+                    })
+                    .unwrap();
+                stmts.push(store(off, &ty, zero(&ty)));
+                off += ty.size;
+            }
+        }
+        for (off, ty, val) in inits {
+            stmts.push(store(off, &ty, val));
+        }
+        stmts
+    }
+
+    // The bytes of a global that `inits` initialize, which must be
+    // constants, and the addresses that go in them.
+    fn global_data(&self, ty: &Type, inits: Vec<Init>) -> (Vec<u8>, Vec<Reloc>) {
+        let mut data = vec![0; ty.size];
+        let mut relocs = vec![];
+        for (off, ty, val) in inits {
+            // A later initializer of the same bytes overrides.
+            let ptr_size = Type::ptr_size();
+            relocs.retain(|r: &Reloc| r.offset + ptr_size <= off || off + ty.size <= r.offset);
+            if ty.size == ptr_size {
+                if let Some((name, addend)) = eval_addr(&val) {
+                    data[off..off + ptr_size].fill(0);
+                    relocs.push(Reloc {
+                        offset: off,
+                        name,
+                        addend,
+                    });
+                    continue;
+                }
+            }
+            let bits = match ty.ty {
+                Ctype::Float => eval_float(&val).map(|v| (v as f32).to_bits() as i64),
+                Ctype::Double => eval_float(&val).map(|v| v.to_bits() as i64),
+                _ => eval(&val),
             };
-            stmts = into_new_range(stmts, Box::new(f));
-            node.op = CompStmt(stmts);
+            let val = match bits {
+                Some(val) if ty.size <= 8 => val,
+                _ => diagnostic::error(
+                    "initializer element is not constant".into(),
+                    val.pos
+                        .as_ref()
+                        .map(Pos::location)
+                        .or_else(|| self.location()),
+                ),
+            };
+            data[off..off + ty.size].copy_from_slice(&val.to_le_bytes()[..ty.size]);
         }
-        VecStmt(mut stmts) => {
-            stmts = stmts.into_iter().map(|stmt| walk(stmt, true)).collect();
-            node.op = VecStmt(stmts);
+        // A global that is all zeros is left for the backend to zero.
+        if relocs.is_empty() && data.iter().all(|&b| b == 0) {
+            data.clear();
         }
-        StmtExpr(body) => {
-            node.op = StmtExpr(Box::new(walk(*body, true)));
-            node.ty = Box::new(Type::int_ty())
+        relocs.sort_by_key(|r| r.offset);
+        (data, relocs)
+    }
+
+    // Builtins are recognized by name and expanded inline by gen_ir
+    // instead of being called.
+    fn walk_builtin(&mut self, mut node: Node, name: Rc<str>, args: Vec<Node>) -> Node {
+        if name.starts_with("__builtin_va_") {
+            return self.walk_va_builtin(node, name, args);
         }
-        _ => panic!("unknown node type"),
-    };
-    node
+        if name.starts_with("__atomic_") {
+            return self.walk_atomic_builtin(node, name, args);
+        }
+
+        let (kind, nargs) = match &*name {
+            "__builtin_alloca" => (Builtin::Alloca, 1),
+            "__builtin_expect" => (Builtin::Expect, 2),
+            "__builtin_memcpy" => (Builtin::Memcpy, 3),
+            "__builtin_memset" => (Builtin::Memset, 3),
+            "__builtin_trap" => (Builtin::Trap, 0),
+            "__builtin_unreachable" => (Builtin::Unreachable, 0),
+            _ => self.error(format!("unknown builtin: {}", name)),
+        };
+        if args.len() != nargs {
+            self.error(format!("{}: {} arguments expected", name, nargs));
+        }
+
+        let mut args: Vec<Node> = args.into_iter().map(|arg| self.walk(arg, true)).collect();
+        // The size is a size_t, whose upper bits must be right.
+        if kind == Builtin::Alloca {
+            let n = args.pop().unwrap();
+            self.check_int_conversion("argument", &Type::size_t(), &n);
+            args.push(convert(n, &Type::size_t()));
+        }
+        node.ty = match kind {
+            // The value, with a hint of what it usually is for laying
+            // out the branches it decides.
+            Builtin::Expect | Builtin::Memcpy | Builtin::Memset => args[0].ty.clone(),
+            Builtin::Trap | Builtin::Unreachable => Type::void_ty(),
+            Builtin::Alloca => Type::ptr_to(Type::void_ty()),
+            _ => unreachable!(),
+        };
+        node.op = NodeType::Builtin(kind, args);
+        node
+    }
+
+    // Returns the address of the va_list `ap`. On x86-64 System V,
+    // va_list is an array, so it's passed around by reference already:
+    // a local one decays to its address, and a parameter is a pointer.
+    // Elsewhere it's a plain pointer, and we take its address.
+    fn va_list_addr(&mut self, ap: Node) -> Node {
+        let ap = self.walk(ap, true);
+        if let Ctype::Ptr(ref ty) = ap.ty.ty {
+            if let Ctype::Struct(_) = ty.ty {
+                return ap;
+            }
+        }
+        self.check_lval(&ap);
+        let ty = Type::ptr_to(ap.ty.clone());
+        let mut node = Node::new(NodeType::Addr(Box::new(ap)));
+        node.ty = ty;
+        node
+    }
+
+    fn walk_va_builtin(&mut self, mut node: Node, name: Rc<str>, args: Vec<Node>) -> Node {
+        let nargs = match &*name {
+            "__builtin_va_start" | "__builtin_va_copy" => 2,
+            "__builtin_va_arg" | "__builtin_va_end" => 1,
+            _ => self.error(format!("unknown builtin: {}", name)),
+        };
+        if args.len() != nargs {
+            self.error(format!("{}: {} arguments expected", name, nargs));
+        }
+
+        let mut args = args.into_iter();
+        let ap = self.va_list_addr(args.next().unwrap());
+        match &*name {
+            // The backend knows where the named parameters end, so the
+            // second argument is of no use to us.
+            "__builtin_va_start" => {
+                node.op = NodeType::Builtin(Builtin::VaStart, vec![ap]);
+                node.ty = Type::void_ty();
+            }
+            // The parser has set the type to read.
+            "__builtin_va_arg" => node.op = NodeType::Builtin(Builtin::VaArg, vec![ap]),
+            "__builtin_va_copy" => {
+                let size = match ap.ty.ty {
+                    Ctype::Ptr(ref ty) => ty.size,
+                    _ => unreachable!(),
+                };
+                let src = self.va_list_addr(args.next().unwrap());
+                let n = Node::new_int(size as i32);
+                node.op = NodeType::Builtin(Builtin::Memcpy, vec![ap, src, n]);
+                node.ty = Type::void_ty();
+            }
+            // None of our targets has anything to clean up.
+            _ => {
+                node = ap;
+                node.ty = Type::void_ty();
+            }
+        }
+        node
+    }
+
+    // Every atomic operation is sequentially consistent, so the memory
+    // order arguments are ignored.
+    fn walk_atomic_builtin(&mut self, mut node: Node, name: Rc<str>, args: Vec<Node>) -> Node {
+        let (kind, nargs) = match &*name {
+            "__atomic_load_n" => (Builtin::AtomicLoad, 2),
+            "__atomic_store_n" => (Builtin::AtomicStore, 3),
+            "__atomic_fetch_add" => (Builtin::AtomicAdd, 3),
+            // The generic versions pass values through pointers.
+            "__atomic_load" | "__atomic_store" => {
+                if args.len() != 3 {
+                    self.error(format!("{}: 3 arguments expected", name));
+                }
+                let mut args = args.into_iter();
+                let p = args.next().unwrap();
+                let val = Node::new(NodeType::Deref(Box::new(args.next().unwrap())));
+                let order = args.next().unwrap();
+                let expr = if &*name == "__atomic_load" {
+                    let load = Node::new(NodeType::Call(
+                        symbol::intern("__atomic_load_n"),
+                        vec![p, order],
+                    ));
+                    Node::new_binop(TokenType::Equal, val, load)
+                } else {
+                    Node::new(NodeType::Call(
+                        symbol::intern("__atomic_store_n"),
+                        vec![p, val, order],
+                    ))
+                };
+                return self.walk(expr, true);
+            }
+            _ => self.error(format!("unknown builtin: {}", name)),
+        };
+        if args.len() != nargs {
+            self.error(format!("{}: {} arguments expected", name, nargs));
+        }
+
+        let args: Vec<Node> = args
+            .into_iter()
+            .take(nargs - 1)
+            .map(|arg| self.walk(arg, true))
+            .collect();
+        let ty = match args[0].ty.ty {
+            Ctype::Ptr(ref ty) => match ty.ty {
+                Ctype::Int | Ctype::Long | Ctype::Ptr(_) => ty.clone(),
+                _ => self.error(format!("{}: pointer to int or pointer expected", name)),
+            },
+            _ => self.error(format!("{}: pointer to int or pointer expected", name)),
+        };
+        node.ty = if kind == Builtin::AtomicStore {
+            Type::void_ty()
+        } else {
+            ty
+        };
+        node.op = NodeType::Builtin(kind, args);
+        node
+    }
 }
 
-pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
+// With `redzones`, local arrays are laid out for -fsanitize=address.
+pub fn sema(nodes: Vec<Node>, redzones: bool) -> (Vec<Node>, Vec<Var>) {
+    let mut sema = Sema::new(redzones);
     let mut new_nodes = vec![];
 
     for mut node in nodes {
-        if let NodeType::Vardef(name, _, Scope::Global(data, len, is_extern)) = node.op {
-            let var = Var::new_global(node.ty, name.clone(), data, len, is_extern);
-            GLOBALS.lock().unwrap().push(var.clone());
-            ENV.lock().unwrap().vars.insert(name, var);
+        if let NodeType::Vardef(
+            name,
+            init,
+            Scope::Global(mut data, mut len, is_extern, is_tls, is_static),
+        ) = node.op
+        {
+            let mut relocs = vec![];
+            if let Some(init) = init {
+                sema.span = init.span.clone();
+                let (ty, inits) = sema.lower_init(&node.ty, *init);
+                (data, relocs) = sema.global_data(&ty, inits);
+                len = ty.size;
+                node.ty = ty;
+            }
+            let scope = Scope::Global(data, len, is_extern, is_tls, is_static);
+            let mut var = Var::new(node.ty, name.to_string(), scope);
+            var.relocs = relocs;
+            sema.globals.push(var.clone());
+            sema.env.vars.insert(name, var);
             continue;
         }
 
         let var;
         match &node.op {
-            NodeType::Func(name, _, _, _) | NodeType::Decl(name) => {
-                var = Var::new_global(node.ty.clone(), name.clone(), "".into(), 0, false);
-                ENV.lock().unwrap().vars.insert(name.clone(), var);
+            NodeType::Func(name, ..) | NodeType::Decl(name) => {
+                var = Var::new_global(node.ty.clone(), name.to_string(), vec![], 0, false, false);
+                sema.env.vars.insert(*name, var);
             }
             _ => unreachable!(),
         }
@@ -380,21 +1874,30 @@ pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
             continue;
         }
 
-        if let NodeType::Func(name, args, body, _) = node.op {
+        if let NodeType::Func(name, args, body, _, is_static) = node.op {
+            ice::set_function(&name.as_str());
+            sema.span = node.span.clone();
+            if let Ctype::Func(ref returning, _, _, noreturn) = node.ty.ty {
+                sema.returning = returning.clone();
+                sema.noreturn = noreturn;
+            }
+            // Parameters are local to the function.
+            sema.enter_scope();
             let mut args2 = vec![];
             for arg in args {
-                args2.push(walk(arg, true));
-            }
-            let body2 = walk(*body, true);
-            node.op = NodeType::Func(
-                name.clone(),
-                args2,
-                Box::new(body2),
-                *STACKSIZE.lock().unwrap(),
-            );
-            *STACKSIZE.lock().unwrap() = 0;
+                args2.push(sema.walk(arg, true));
+            }
+            let body2 = sema.walk(*body, true);
+            sema.leave_scope();
+            if falls_through(&body2) {
+                sema.span = node.span.clone();
+                sema.check_end(name);
+            }
+            node.op = NodeType::Func(name, args2, Box::new(body2), sema.stacksize, is_static);
+            sema.stacksize = 0;
             new_nodes.push(node);
         }
     }
-    (new_nodes, GLOBALS.lock().unwrap().clone())
+    diagnostic::stop_if_errors();
+    (new_nodes, sema.globals)
 }
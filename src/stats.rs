@@ -0,0 +1,83 @@
+// Sizes for --stats=memory and -fstack-usage.
+//
+// How much the compiler makes of a program at each step: the nodes of
+// its syntax tree, the names and types it interns, the IR instructions
+// of each function and the bytes of assembly they become. They grow
+// with what the compiler supports, so they show what a feature costs.
+//
+// How much stack the program's functions use is for its own users,
+// such as those fitting it into a small embedded stack.
+
+use crate::token::Span;
+
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStats {
+    pub nodes: usize,   // AST nodes, as parsed
+    pub symbols: usize, // Interned identifiers
+    pub types: usize,   // Interned types
+    pub functions: Vec<FunctionSize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionSize {
+    pub name: String,
+    pub ir: usize,  // Instructions, after register allocation
+    pub asm: usize, // Bytes of assembly or other output
+}
+
+// Formats the statistics as a table:
+//
+//   Memory report for foo.c:
+//     AST nodes                1234
+//     symbols                    56
+//     types                      12
+//     function             IR insns   asm bytes
+//     main                       78        1234
+//     ...
+//     total                     345        5678
+pub fn report(filename: &str, stats: &MemoryStats) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Memory report for {}:", filename);
+    let _ = writeln!(out, "  {:<16}{:>12}", "AST nodes", stats.nodes);
+    let _ = writeln!(out, "  {:<16}{:>12}", "symbols", stats.symbols);
+    let _ = writeln!(out, "  {:<16}{:>12}", "types", stats.types);
+    let _ = writeln!(
+        out,
+        "  {:<16}{:>12}{:>12}",
+        "function", "IR insns", "asm bytes"
+    );
+    for f in &stats.functions {
+        let _ = writeln!(out, "  {:<16}{:>12}{:>12}", f.name, f.ir, f.asm);
+    }
+    let ir: usize = stats.functions.iter().map(|f| f.ir).sum();
+    let asm: usize = stats.functions.iter().map(|f| f.asm).sum();
+    let _ = writeln!(out, "  {:<16}{:>12}{:>12}", "total", ir, asm);
+    out
+}
+
+// The stack a function uses, as the backend laid out its frame.
+#[derive(Debug, Clone)]
+pub struct StackUsage {
+    pub name: String,
+    pub span: Option<Span>, // Where it is defined
+    pub bytes: usize,
+    pub dynamic: bool, // Whether it also allocates with alloca
+}
+
+// Formats the usage as gcc's .su files do, a line for each function:
+//
+//   foo.c:3:5:main	48	static
+//   foo.c:9:6:grow	32	dynamic
+pub fn stack_usage_report(usage: &[StackUsage]) -> String {
+    let mut out = String::new();
+    for f in usage {
+        if let Some(span) = &f.span {
+            let _ = write!(out, "{}:{}:{}:", span.filename, span.line, span.col);
+        }
+        let kind = if f.dynamic { "dynamic" } else { "static" };
+        let _ = writeln!(out, "{}\t{}\t{}", f.name, f.bytes, kind);
+    }
+    out
+}
@@ -0,0 +1,71 @@
+// Interned identifiers.
+//
+// The frontend refers to identifiers by Symbol, a small id that is
+// cheap to copy, hash and compare, instead of by String. Each name is
+// stored once, in a table that lives as long as the thread: every
+// compilation runs on a thread of its own (see catch_errors in lib.rs),
+// so the table belongs to one compilation. gen_ir turns symbols back
+// into strings for the IR, since backends run on other threads.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Interner {
+    names: Vec<Rc<str>>,
+    ids: HashMap<Rc<str>, Symbol>,
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::default();
+}
+
+// Returns the symbol for `name`, the same one each time.
+pub fn intern(name: &str) -> Symbol {
+    INTERNER.with(|i| {
+        let mut i = i.borrow_mut();
+        if let Some(&sym) = i.ids.get(name) {
+            return sym;
+        }
+        let sym = Symbol(i.names.len() as u32);
+        let name: Rc<str> = name.into();
+        i.names.push(name.clone());
+        i.ids.insert(name, sym);
+        sym
+    })
+}
+
+// The number of names interned so far.
+pub fn count() -> usize {
+    INTERNER.with(|i| i.borrow().names.len())
+}
+
+impl Symbol {
+    pub fn as_str(self) -> Rc<str> {
+        INTERNER.with(|i| i.borrow().names[self.0 as usize].clone())
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.as_str() == *other
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.as_str())
+    }
+}
+
+// Like a String's, so that dumps of tokens and nodes show names.
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", &*self.as_str())
+    }
+}
@@ -0,0 +1,164 @@
+use crate::elf::Object;
+use crate::gen_ir::{CodegenContext, Function};
+use crate::gen_riscv::Riscv64;
+use crate::gen_wasm::Wasm32;
+use crate::gen_x86::X86_64;
+use crate::{Type, Var};
+
+use std::rc::Rc;
+
+// Everything up to register allocation is machine independent. A
+// Target describes what the remaining passes need to know about a
+// machine: which registers the allocator may hand out, how arguments
+// are passed, and how the allocated IR is turned into text. Each
+// backend implements it next to its register tables, so adding a new
+// architecture or ABI doesn't touch the others.
+
+// Targets are shared by the threads that emit functions in parallel.
+pub trait Target: Sync {
+    // Name used with --target.
+    fn name(&self) -> &'static str;
+
+    // Registers available to the register allocator. IR register i is
+    // mapped to regs()[i].
+    fn regs(&self) -> &'static [&'static str];
+
+    // Registers used to pass the first arguments of a call, in order.
+    fn arg_regs(&self) -> &'static [&'static str];
+
+    // Maximum number of arguments a call may have. By default, only
+    // register arguments are supported.
+    fn max_args(&self) -> usize {
+        self.arg_regs().len()
+    }
+
+    // Macros the preprocessor predefines, with their values, such as
+    // __x86_64__ and __linux__.
+    fn macros(&self) -> &'static [(&'static str, i32)];
+
+    // Sizes of a pointer and of a long, in bytes. A long long is 8
+    // bytes everywhere.
+    fn ptr_size(&self) -> usize {
+        8
+    }
+
+    fn long_size(&self) -> usize {
+        8
+    }
+
+    // Alignment of the widest integer, long long, which some ABIs
+    // align less than its size.
+    fn int_max_align(&self) -> usize {
+        8
+    }
+
+    // Target triple written into --emit=llvm-ir output.
+    fn llvm_triple(&self) -> &'static str;
+
+    // Whether gen() produces assembly that has to be assembled and
+    // linked, as opposed to a module that can be loaded as is.
+    fn needs_linking(&self) -> bool {
+        true
+    }
+
+    // Whether gen() honors Function::stack_protector.
+    fn stack_protector(&self) -> bool {
+        false
+    }
+
+    // Whether gen() honors Function::profile.
+    fn profile(&self) -> bool {
+        false
+    }
+
+    // Whether gen() emits Loc as line information for the debugger (-g).
+    fn debug_info(&self) -> bool {
+        false
+    }
+
+    // Whether gen() supports _Thread_local variables.
+    fn thread_local(&self) -> bool {
+        false
+    }
+
+    // Whether gen() implements the floating-point IR operations.
+    fn floating_point(&self) -> bool {
+        false
+    }
+
+    // Whether runtime/asan.c can map shadow memory for -fsanitize=address
+    // at gen_ir::ASAN_SHADOW_OFFSET on this target.
+    fn address_sanitizer(&self) -> bool {
+        false
+    }
+
+    // Type of __builtin_va_list. Most ABIs use a plain pointer to the
+    // next variadic argument.
+    fn va_list(&self) -> Rc<Type> {
+        Type::ptr_to(Type::char_ty())
+    }
+
+    // Built-in assembler used for -c, if the target has one.
+    fn assemble(&self, _asm: &str) -> Option<Object> {
+        None
+    }
+
+    fn gen(&self, ctx: &mut CodegenContext, globals: Vec<Var>, fns: Vec<Function>) -> String;
+}
+
+// Operating systems, for targets whose conventions differ between them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Os {
+    Linux,
+    Windows,
+    Darwin,
+}
+
+// Short names for the targets. --target also takes triples as GCC and
+// LLVM name them; see lookup.
+pub const TARGETS: [&str; 5] = [
+    "x86_64",
+    "x86_64-windows",
+    "x86_64-darwin",
+    "riscv64",
+    "wasm32",
+];
+
+// Finds the target named by a triple such as x86_64-pc-linux-gnu. The
+// architecture picks the backend and the OS picks the ABI; vendor and
+// environment are ignored, and the OS defaults to Linux, which is what
+// makes the short names work too.
+pub fn lookup(name: &str) -> Option<Box<dyn Target>> {
+    let mut parts = name.split('-');
+    let arch = parts.next()?;
+    let mut os = Os::Linux;
+    for part in parts {
+        os = match part {
+            "unknown" | "pc" | "linux" | "gnu" | "elf" => os,
+            "windows" | "w64" | "mingw32" | "msvc" => Os::Windows,
+            "apple" => Os::Darwin,
+            _ if part.starts_with("darwin") || part.starts_with("macos") => Os::Darwin,
+            _ => return None,
+        };
+    }
+    match arch {
+        "x86_64" | "amd64" => Some(Box::new(X86_64 { os })),
+        "riscv64" | "riscv64gc" if os == Os::Linux => Some(Box::new(Riscv64)),
+        "wasm32" if os == Os::Linux => Some(Box::new(Wasm32)),
+        _ => None,
+    }
+}
+
+// The target r9cc runs on, which is the default. Hosts without a
+// backend get x86_64 Linux.
+pub fn host() -> &'static str {
+    if cfg!(all(target_arch = "x86_64", windows)) {
+        "x86_64-windows"
+    } else if cfg!(all(target_arch = "x86_64", target_os = "macos")) {
+        "x86_64-darwin"
+    } else if cfg!(target_arch = "riscv64") {
+        "riscv64"
+    } else {
+        "x86_64"
+    }
+}
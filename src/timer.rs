@@ -0,0 +1,118 @@
+// Phase timing for -ftime-report.
+//
+// compile_impl() runs each phase of a compilation through Timer::phase,
+// which records how long it took and, if the program uses
+// CountingAllocator, the most heap memory in use during it. The r9cc
+// binary installs the allocator; library users who want memory figures
+// can do the same.
+
+use crate::ice;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+// The system allocator, keeping count of the bytes in use.
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = System.alloc(layout);
+        if !p.is_null() {
+            INSTALLED.store(true, Ordering::Relaxed);
+            let now = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, p: *mut u8, layout: Layout) {
+        System.dealloc(p, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Phase {
+    pub name: String,
+    pub time: Duration,
+    // Most bytes of heap in use at once, or None without
+    // CountingAllocator.
+    pub peak_memory: Option<usize>,
+}
+
+pub struct Timer {
+    enabled: bool,
+    pub phases: Vec<Phase>,
+}
+
+impl Timer {
+    pub fn new(enabled: bool) -> Self {
+        Timer {
+            enabled,
+            phases: vec![],
+        }
+    }
+
+    // Runs one phase of the compilation, recording it if enabled. The
+    // phase is also named in internal compiler errors.
+    pub fn phase<T>(&mut self, name: &str, f: impl FnOnce() -> T) -> T {
+        ice::set_phase(name);
+        if !self.enabled {
+            return f();
+        }
+        PEAK.store(CURRENT.load(Ordering::Relaxed), Ordering::Relaxed);
+        let start = Instant::now();
+        let result = f();
+        let time = start.elapsed();
+        let peak_memory = if INSTALLED.load(Ordering::Relaxed) {
+            Some(PEAK.load(Ordering::Relaxed))
+        } else {
+            None
+        };
+        self.phases.push(Phase {
+            name: name.into(),
+            time,
+            peak_memory,
+        });
+        result
+    }
+}
+
+// Formats phases as a table, like gcc's -ftime-report:
+//
+//   Time report for foo.c:
+//     phase                       wall    peak heap
+//     lex                     0.051 ms       40 KiB
+//     ...
+//     total                   1.234 ms      512 KiB
+pub fn report(filename: &str, phases: &[Phase]) -> String {
+    let kib = |bytes: Option<usize>| match bytes {
+        Some(n) => format!("{} KiB", n.div_ceil(1024)),
+        None => "-".into(),
+    };
+    let row = |name: &str, time: Duration, peak: Option<usize>| {
+        format!(
+            "  {:<16}{:>12.3} ms{:>13}\n",
+            name,
+            time.as_secs_f64() * 1000.0,
+            kib(peak)
+        )
+    };
+
+    let mut out = String::new();
+    let _ = writeln!(out, "Time report for {}:", filename);
+    let _ = writeln!(out, "  {:<16}{:>15}{:>13}", "phase", "wall", "peak heap");
+    for p in phases {
+        out.push_str(&row(&p.name, p.time, p.peak_memory));
+    }
+    let total = phases.iter().map(|p| p.time).sum();
+    let peak = phases.iter().filter_map(|p| p.peak_memory).max();
+    out.push_str(&row("total", total, peak));
+    out
+}
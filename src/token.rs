@@ -1,43 +1,138 @@
+use crate::diagnostic::{self, Location};
 use crate::preprocess;
-use crate::CharacterType;
-use crate::TokenType;
+use crate::symbol;
+use crate::timer::Timer;
+use crate::{CharacterType, NumType, Std, TokenType};
 
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::mem;
 use std::rc::Rc;
 
-pub fn tokenize(path: String, ctx: &mut preprocess::Preprocessor) -> Vec<Token> {
-    let mut tokenizer = Tokenizer::new(Rc::new(path));
-    tokenizer.canonicalize_newline();
-    tokenizer.remove_backslash_newline();
-    tokenizer.scan(&keyword_map());
+// Tokenizes source code that is already in memory. `filename` is only
+// used in diagnostics.
+pub fn tokenize_str(
+    filename: String,
+    source: &str,
+    ctx: &mut preprocess::Preprocessor,
+) -> Vec<Token> {
+    tokenize_timed(filename, source, ctx, &mut Timer::new(false))
+}
+
+// Like tokenize_str, timing lexing and preprocessing separately for
+// -ftime-report. Files included by the program are lexed as part of
+// preprocessing.
+pub fn tokenize_timed(
+    filename: String,
+    source: &str,
+    ctx: &mut preprocess::Preprocessor,
+    timer: &mut Timer,
+) -> Vec<Token> {
+    let (std, trigraphs) = (ctx.std, ctx.trigraphs);
+    let mut tokenizer = timer.phase("lex", || {
+        let mut tokenizer = Tokenizer::new(Rc::new(filename), source);
+        tokenizer.std = std;
+        tokenizer.trigraphs = trigraphs;
+        tokenizer.lex();
+        tokenizer
+    });
+    let tokens = mem::take(&mut tokenizer.tokens);
+    tokenizer.tokens = timer.phase("preprocess", || preprocess::preprocess(tokens, ctx));
+    tokenizer.strip_newlines_tokens();
+    let mut eof = tokenizer.new_token(TokenType::Eof);
+    eof.start = tokenizer.p.len();
+    eof.end = eof.start;
+    tokenizer.tokens.push(eof);
+    tokenizer.tokens
+}
 
-    tokenizer.tokens = preprocess::preprocess(tokenizer.tokens, ctx);
+// Splits source code into tokens without preprocessing it, such as
+// the body of a macro defined with -D.
+pub fn lex_str(filename: String, source: &str) -> Vec<Token> {
+    let mut tokenizer = Tokenizer::new(Rc::new(filename), source);
+    tokenizer.lex();
     tokenizer.strip_newlines_tokens();
-    tokenizer.join_string_literals();
     tokenizer.tokens
 }
 
+// Like lex_str, but keeps the newline tokens, and the comments as
+// tokens of their own, for r9cc fmt (see formatter.rs).
+pub fn lex_with_comments(filename: String, source: &str, trigraphs: bool) -> Vec<Token> {
+    let mut tokenizer = Tokenizer::new(Rc::new(filename), source);
+    tokenizer.trigraphs = trigraphs;
+    tokenizer.keep_comments = true;
+    tokenizer.lex();
+    tokenizer.tokens
+}
+
+// Like tokenize_str, for a file, but stops after preprocessing and
+// keeps the newline tokens. Used for #include, whose tokens become part
+// of the including file's.
+pub fn preprocess_file(path: String, ctx: &mut preprocess::Preprocessor) -> Vec<Token> {
+    let source = Tokenizer::read_file(&path);
+    preprocess_str(path, &source, ctx)
+}
+
+// Like preprocess_file, but for source code that is already in memory.
+pub fn preprocess_str(
+    filename: String,
+    source: &str,
+    ctx: &mut preprocess::Preprocessor,
+) -> Vec<Token> {
+    let mut tokenizer = Tokenizer::new(Rc::new(filename), source);
+    tokenizer.std = ctx.std;
+    tokenizer.trigraphs = ctx.trigraphs;
+    tokenizer.preprocess(ctx);
+    tokenizer.tokens
+}
+
+const KEYWORDS: &[(&str, TokenType)] = &[
+    ("_Alignof", TokenType::Alignof),
+    ("_Atomic", TokenType::Atomic),
+    ("_Noreturn", TokenType::Noreturn),
+    ("_Thread_local", TokenType::ThreadLocal),
+    ("__asm__", TokenType::Asm),
+    ("asm", TokenType::Asm),
+    ("auto", TokenType::Auto),
+    ("break", TokenType::Break),
+    ("case", TokenType::Case),
+    ("char", TokenType::Char),
+    ("void", TokenType::Void),
+    ("default", TokenType::Default),
+    ("do", TokenType::Do),
+    ("double", TokenType::Double),
+    ("else", TokenType::Else),
+    ("extern", TokenType::Extern),
+    ("float", TokenType::Float),
+    ("for", TokenType::For),
+    ("if", TokenType::If),
+    ("int", TokenType::Int),
+    ("long", TokenType::Long),
+    ("register", TokenType::Register),
+    ("return", TokenType::Return),
+    ("signed", TokenType::Signed),
+    ("sizeof", TokenType::Sizeof),
+    ("static", TokenType::Static),
+    ("struct", TokenType::Struct),
+    ("switch", TokenType::Switch),
+    ("typedef", TokenType::Typedef),
+    ("unsigned", TokenType::Unsigned),
+    ("while", TokenType::While),
+];
+
 fn keyword_map() -> HashMap<String, TokenType> {
-    let mut map = HashMap::new();
-    map.insert("_Alignof".into(), TokenType::Alignof);
-    map.insert("break".into(), TokenType::Break);
-    map.insert("char".into(), TokenType::Char);
-    map.insert("void".into(), TokenType::Void);
-    map.insert("do".into(), TokenType::Do);
-    map.insert("else".into(), TokenType::Else);
-    map.insert("extern".into(), TokenType::Extern);
-    map.insert("for".into(), TokenType::For);
-    map.insert("if".into(), TokenType::If);
-    map.insert("int".into(), TokenType::Int);
-    map.insert("return".into(), TokenType::Return);
-    map.insert("sizeof".into(), TokenType::Sizeof);
-    map.insert("struct".into(), TokenType::Struct);
-    map.insert("typedef".into(), TokenType::Typedef);
-    map.insert("while".into(), TokenType::While);
-    map
+    KEYWORDS
+        .iter()
+        .map(|(name, ty)| (name.to_string(), ty.clone()))
+        .collect()
+}
+
+// The keywords' spellings, for suggestions.
+pub fn keywords() -> impl Iterator<Item = &'static str> {
+    KEYWORDS.iter().map(|(name, _)| *name)
 }
 
 #[derive(Debug, Clone)]
@@ -46,6 +141,8 @@ pub struct Token {
 
     // For preprocessor
     pub stringize: bool,
+    // The macros it was expanded from, which don't expand in it again.
+    pub hideset: Vec<symbol::Symbol>,
 
     // For error reporting
     pub buf: Rc<Vec<char>>,
@@ -63,6 +160,7 @@ impl Default for Token {
             start: 0,
             end: 0,
             stringize: false,
+            hideset: vec![],
         }
     }
 }
@@ -79,21 +177,89 @@ impl Token {
     }
 
     pub fn bad_token(&self, msg: &str) -> ! {
-        print_line(&*self.buf, &*self.filename, self.start);
-        panic!("{}", msg);
+        diagnostic::error(msg.to_string(), Some(self.location()))
+    }
+
+    pub fn location(&self) -> Location {
+        let len = self.end.saturating_sub(self.start);
+        location(&self.buf, &self.filename, self.start, len)
+    }
+
+    pub fn pos(&self) -> Pos {
+        Pos {
+            buf: self.buf.clone(),
+            filename: self.filename.clone(),
+            start: self.start,
+            end: self.end,
+        }
     }
 
     pub fn tokstr(&self) -> String {
         self.buf[self.start..self.end].iter().collect()
     }
 
-    pub fn get_line_number(&self) -> usize {
-        self.buf[..self.end].iter().filter(|c| *c == &'\n').count()
+    pub fn span(&self) -> Span {
+        let pos = self.start.min(self.buf.len());
+        let start = self.buf[..pos]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |i| i + 1);
+        let end = self.buf[pos..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(self.buf.len(), |i| pos + i);
+        Span {
+            filename: self.filename.to_string(),
+            line: self.buf[..start].iter().filter(|c| **c == '\n').count() + 1,
+            col: pos - start + 1,
+            text: self.buf[start..end].iter().collect(),
+        }
+    }
+
+    // The value of a string literal, with escape sequences replaced.
+    pub fn str_value(&self) -> String {
+        let mut s = String::new();
+        let mut chars = self.buf[self.start + 1..self.end - 1].iter();
+        while let Some(&c) = chars.next() {
+            if c != '\\' {
+                s.push(c);
+                continue;
+            }
+            let c = *chars.next().unwrap();
+            s.push(Tokenizer::escaped(c).unwrap_or(c));
+        }
+        s
+    }
+
+    // The value of a floating constant, and whether an f suffix makes it
+    // a float. One with an l suffix is a long double, which is a double.
+    // None if it is hexadecimal without the exponent it requires.
+    pub fn float_value(&self) -> Option<(f64, bool)> {
+        let mut s = self.tokstr();
+        let hex = s.starts_with("0x") || s.starts_with("0X");
+        if hex && !s.contains(['p', 'P']) {
+            return None;
+        }
+        let suffix = s.chars().last().filter(|c| "fFlL".contains(*c));
+        if suffix.is_some() {
+            s.pop();
+        }
+        let is_float = matches!(suffix, Some('f') | Some('F'));
+        let hex = s.get(2..).filter(|_| hex);
+        let val = match hex {
+            Some(digits) => hex_float(digits),
+            None if is_float => s.parse::<f32>().unwrap() as f64,
+            None => s.parse().unwrap(),
+        };
+        match hex {
+            Some(_) if is_float => Some((val as f32 as f64, true)),
+            _ => Some((val, is_float)),
+        }
     }
 
     pub fn is_ident(&self, s: &str) -> bool {
         match self.ty {
-            TokenType::Ident(ref name) => name == s,
+            TokenType::Ident(name) => name == s,
             _ => false,
         }
     }
@@ -114,6 +280,7 @@ impl Symbol {
 lazy_static! {
     static ref SYMBOLS: Vec<Symbol> = [
         Symbol::new("<<=", TokenType::ShlEQ),
+        Symbol::new("...", TokenType::Ellipsis),
         Symbol::new(">>=", TokenType::ShrEQ),
         Symbol::new("!=", TokenType::NE),
         Symbol::new("&&", TokenType::Logand),
@@ -134,10 +301,58 @@ lazy_static! {
         Symbol::new("&=", TokenType::BitandEQ),
         Symbol::new("^=", TokenType::XorEQ),
         Symbol::new("|=", TokenType::BitorEQ),
+        // Digraphs, for keyboards without these characters. %:%: is
+        // two of %:, as ## is two of #.
+        Symbol::new("<%", TokenType::LeftBrace),
+        Symbol::new("%>", TokenType::RightBrace),
+        Symbol::new("<:", TokenType::LeftBracket),
+        Symbol::new(":>", TokenType::RightBracket),
+        Symbol::new("%:", TokenType::HashMark),
     ]
     .to_vec();
 }
 
+// How a token of type `ty` is written, quoted, for errors about one
+// that wasn't found: ';' rather than Semicolon.
+pub fn spelling(ty: &TokenType) -> String {
+    let text = "+-*/&;=()[]{}<>,.!?|^%:#"
+        .chars()
+        .find(|&c| TokenType::new_single_letter(c).as_ref() == Some(ty))
+        .map(String::from)
+        .or_else(|| SYMBOLS.iter().find(|s| s.ty == *ty).map(|s| s.name.into()))
+        .or_else(|| {
+            KEYWORDS
+                .iter()
+                .find(|(_, t)| t == ty)
+                .map(|(name, _)| (*name).into())
+        });
+    match text {
+        Some(text) => format!("'{}'", text),
+        None => format!("{:?}", ty),
+    }
+}
+
+// A string literal token for `value`, which has no text in any source
+// file, such as one made by the # operator. The token gets a buffer of
+// its own holding the literal.
+pub fn string_token(value: &str, filename: Rc<String>) -> Token {
+    let mut lit = vec!['"'];
+    for c in value.chars() {
+        match c {
+            '"' => lit.extend(['\\', '"']),
+            '\\' => lit.extend(['\\', '\\']),
+            '\n' => lit.extend(['\\', 'n']),
+            '\r' => lit.extend(['\\', 'r']),
+            '\t' => lit.extend(['\\', 't']),
+            _ => lit.push(c),
+        }
+    }
+    lit.push('"');
+    let mut t = Token::new(TokenType::Str, 0, filename, Rc::new(lit));
+    t.end = t.buf.len();
+    t
+}
+
 // Tokenizer
 struct Tokenizer {
     p: Rc<Vec<char>>,
@@ -146,32 +361,51 @@ struct Tokenizer {
 
     // Error reporting
     filename: Rc<String>,
+    std: Std, // What to warn about
+    trigraphs: bool,
+    keep_comments: bool,
 }
 
 impl Tokenizer {
-    fn new(filename: Rc<String>) -> Self {
+    fn new(filename: Rc<String>, source: &str) -> Self {
         Tokenizer {
-            p: Rc::new(Self::read_file(&filename).chars().collect()),
+            p: Rc::new(source.chars().collect()),
             filename,
             pos: 0,
             tokens: vec![],
+            std: Std::C11,
+            trigraphs: false,
+            keep_comments: false,
         }
     }
 
     fn read_file(filename: &str) -> String {
         let mut input = String::new();
-        let mut fp = io::stdin();
-        if filename != &"-".to_string() {
-            let mut fp = File::open(filename).expect("file not found");
-            fp.read_to_string(&mut input)
-                .expect("something went wrong reading the file");
-            return input;
-        }
-        fp.read_to_string(&mut input)
-            .expect("something went wrong reading the file");
+        let result = if filename == "-" {
+            io::stdin().read_to_string(&mut input)
+        } else {
+            File::open(filename).and_then(|mut fp| fp.read_to_string(&mut input))
+        };
+        if let Err(e) = result {
+            diagnostic::error(format!("cannot read {}: {}", filename, e), None);
+        }
         input
     }
 
+    fn preprocess(&mut self, ctx: &mut preprocess::Preprocessor) {
+        self.lex();
+        self.tokens = preprocess::preprocess(mem::take(&mut self.tokens), ctx);
+    }
+
+    fn lex(&mut self) {
+        self.canonicalize_newline();
+        if self.trigraphs {
+            self.replace_trigraphs();
+        }
+        self.remove_backslash_newline();
+        self.scan(&keyword_map());
+    }
+
     fn new_token(&self, ty: TokenType) -> Token {
         Token::new(ty, self.pos, self.filename.clone(), self.p.clone())
     }
@@ -203,8 +437,13 @@ impl Tokenizer {
                     self.tokens.push(t);
                 }
                 CharacterType::Whitespace => self.pos += 1,
-                CharacterType::Alphabetic => self.ident(&keywords),
+                CharacterType::Alphabetic => self.ident(keywords),
                 CharacterType::Digit => self.number(),
+                CharacterType::NonAlphabetic('.')
+                    if self.p.get(self.pos + 1).is_some_and(char::is_ascii_digit) =>
+                {
+                    self.number()
+                }
 
                 CharacterType::NonAlphabetic('\'') => self.char_literal(),
                 CharacterType::NonAlphabetic('\"') => self.string_literal(),
@@ -254,7 +493,11 @@ impl Tokenizer {
                         self.tokens.push(t);
                         continue 'outer;
                     }
-                    self.bad_position("Unknown symbol.");
+                    diagnostic::error_with_code(
+                        "stray-character",
+                        "Unknown symbol.".into(),
+                        Some(location(&self.p, &self.filename, self.pos, 1)),
+                    );
                 }
                 CharacterType::Unknown(_) => self.bad_position("Unknwon character type."),
             }
@@ -264,18 +507,27 @@ impl Tokenizer {
     }
 
     fn line_comment(&mut self) {
-        while self.p.get(self.pos) != Some(&'\n') {
+        // Finding the line is slow, so it's done only to warn.
+        if self.std < Std::C99 {
+            let loc = location(&self.p, &self.filename, self.pos, 2);
+            self.std.require(Std::C99, "a '//' comment", loc);
+        }
+        let start = self.pos;
+        while !matches!(self.p.get(self.pos), Some('\n') | None) {
             self.pos += 1;
         }
+        self.comment(start);
     }
 
     fn block_comment(&mut self) {
+        let start = self.pos;
         self.pos += 2;
         loop {
             if let Some(two_char) = self.p.get(self.pos..self.pos + 2) {
                 self.pos += 1;
                 if two_char == ['*', '/'] {
                     self.pos += 1;
+                    self.comment(start);
                     return;
                 }
             } else {
@@ -284,6 +536,20 @@ impl Tokenizer {
         }
     }
 
+    // Keeps the comment that ends here, if asked to.
+    fn comment(&mut self, start: usize) {
+        if self.keep_comments {
+            let mut t = Token::new(
+                TokenType::Comment,
+                start,
+                self.filename.clone(),
+                self.p.clone(),
+            );
+            t.end = self.pos;
+            self.tokens.push(t);
+        }
+    }
+
     fn escaped(c: char) -> Option<char> {
         // Issue: https://github.com/rust-lang/rfcs/issues/751
         match c {
@@ -299,15 +565,22 @@ impl Tokenizer {
     }
 
     fn char_literal(&mut self) {
+        let start = self.pos;
         self.pos += 1;
         let result: char;
-        let c = self.p.get(self.pos).expect("premature end of input");
+        let c = match self.p.get(self.pos) {
+            Some(c) => c,
+            None => self.bad_position("unclosed character literal"),
+        };
         if c != &'\\' {
             result = *c;
             self.pos += 1;
         } else {
             self.pos += 1;
-            let c2 = self.p.get(self.pos).unwrap();
+            let c2 = match self.p.get(self.pos) {
+                Some(c) => c,
+                None => self.bad_position("unclosed character literal"),
+            };
             result = if let Some(esc) = Self::escaped(*c2) {
                 esc
             } else {
@@ -317,46 +590,32 @@ impl Tokenizer {
         }
 
         if self.p.get(self.pos) != Some(&'\'') {
-            panic!("unclosed character literal");
+            self.bad_position("unclosed character literal");
         }
 
-        let mut t = self.new_token(TokenType::Num(result as u8 as i32));
+        let mut t = self.new_token(TokenType::Num(result as u8 as i64, NumType::Int));
         self.pos += 1;
-        t.end = self.pos + 1;
+        t.start = start;
+        t.end = self.pos;
         self.tokens.push(t);
     }
 
+    // Only finds where the literal ends. Its value is read from the
+    // source when it is needed, by Token::str_value.
     fn string_literal(&mut self) {
-        self.pos += 1;
-        let mut sb = String::new();
-        let mut len = 0;
+        let mut t = self.new_token(TokenType::Str);
+        let mut end = self.pos + 1;
         loop {
-            let mut c2 = self.p.get(self.pos + len).expect("PREMATURE end of input");
-            if c2 == &'"' {
-                len += 1;
-                self.pos += len;
-                let mut t = self.new_token(TokenType::Str(sb, len));
-                t.start = self.pos - len - 1;
-                t.end = self.pos + 1;
-                self.tokens.push(t);
-                return;
+            match self.p.get(end) {
+                Some('"') => break,
+                Some('\\') => end += 2,
+                Some(_) => end += 1,
+                None => self.bad_position("unclosed string literal"),
             }
-
-            if c2 != &'\\' {
-                len += 1;
-                sb.push(c2.clone());
-                continue;
-            }
-
-            len += 1;
-            c2 = self.p.get(self.pos + len).unwrap();
-            if let Some(esc) = Self::escaped(*c2) {
-                sb.push(esc);
-            } else {
-                sb.push(c2.clone());
-            }
-            len += 1;
         }
+        self.pos = end + 1;
+        t.end = self.pos;
+        self.tokens.push(t);
     }
 
     fn ident(&mut self, keywords: &HashMap<String, TokenType>) {
@@ -372,9 +631,14 @@ impl Tokenizer {
         let name: String = self.p[self.pos..self.pos + len].iter().collect();
         let mut t;
         if let Some(keyword) = keywords.get(&name) {
+            use crate::TokenType::*;
+            if self.std < Std::C11 && matches!(keyword, Alignof | Atomic | Noreturn | ThreadLocal) {
+                let loc = location(&self.p, &self.filename, self.pos, len);
+                self.std.require(Std::C11, &format!("'{}'", name), loc);
+            }
             t = self.new_token(keyword.clone());
         } else {
-            t = self.new_token(TokenType::Ident(name.clone()));
+            t = self.new_token(TokenType::Ident(symbol::intern(&name)));
         }
         self.pos += len;
         t.end = self.pos;
@@ -382,31 +646,131 @@ impl Tokenizer {
     }
 
     fn number(&mut self) {
+        if let Some(len) = self.float_len() {
+            let mut t = self.new_token(TokenType::FloatNum);
+            self.pos += len;
+            t.end = self.pos;
+            self.tokens.push(t);
+            return;
+        }
+        let start = self.pos;
         match self.p.get(self.pos..self.pos + 2) {
             Some(&['0', 'x']) | Some(&['0', 'X']) => {
                 self.pos += 2;
-                self.parse_number(16);
+                self.parse_number(16, start);
             }
             Some(&['0', _]) => {
-                self.parse_number(8);
+                self.parse_number(8, start);
             }
-            _ => self.parse_number(10),
+            _ => self.parse_number(10, start),
         }
     }
 
-    fn parse_number(&mut self, base: u32) {
-        let mut sum: i32 = 0;
+    // The length of the floating constant here, such as 1.5, 1e9, .5f
+    // or 0x1.8p3, if this is one.
+    fn float_len(&self) -> Option<usize> {
+        let p = &self.p[self.pos..];
+        let hex = matches!(p.get(..2), Some(['0', 'x']) | Some(['0', 'X']));
+        let (mut len, exponent) = if hex {
+            (2, ['p', 'P'])
+        } else {
+            (0, ['e', 'E'])
+        };
+        let digit = |c: &char| {
+            if hex {
+                c.is_ascii_hexdigit()
+            } else {
+                c.is_ascii_digit()
+            }
+        };
+        let skip_digits = |mut len| {
+            while p.get(len).is_some_and(digit) {
+                len += 1;
+            }
+            len
+        };
+
+        len = skip_digits(len);
+        let mut is_float = false;
+        if p.get(len) == Some(&'.') {
+            is_float = true;
+            len = skip_digits(len + 1);
+        }
+        // The exponent's digits are decimal even in a hexadecimal one.
+        if p.get(len).is_some_and(|c| exponent.contains(c)) {
+            let mut n = len + 1;
+            if matches!(p.get(n), Some('+') | Some('-')) {
+                n += 1;
+            }
+            if p.get(n).is_some_and(char::is_ascii_digit) {
+                is_float = true;
+                len = n;
+                while p.get(len).is_some_and(char::is_ascii_digit) {
+                    len += 1;
+                }
+            }
+        }
+        if !is_float {
+            return None;
+        }
+        if matches!(p.get(len), Some('f') | Some('F') | Some('l') | Some('L')) {
+            len += 1;
+        }
+        Some(len)
+    }
+
+    // Reads the digits here, of a number whose token starts at `start`,
+    // before any 0x.
+    fn parse_number(&mut self, base: u32, start: usize) {
+        let mut sum: u64 = 0;
         let mut len = 0;
         for c in self.p[self.pos..].iter() {
             if let Some(val) = c.to_digit(base) {
-                sum = sum * base as i32 + val as i32;
+                // Too big a number wraps around, as it would in a long.
+                sum = sum.wrapping_mul(base as u64).wrapping_add(val as u64);
                 len += 1;
             } else {
                 break;
             }
         }
-        let mut t = self.new_token(TokenType::Num(sum as i32));
+        // A u suffix and an l or ll suffix, in either order.
+        let (mut unsigned, mut long, mut long_long) = (false, false, false);
+        for _ in 0..2 {
+            match self.p.get(self.pos + len) {
+                Some('u') | Some('U') if !unsigned => {
+                    unsigned = true;
+                    len += 1;
+                }
+                Some(&l @ 'l') | Some(&l @ 'L') if !long => {
+                    long = true;
+                    len += 1;
+                    if self.p.get(self.pos + len) == Some(&l) {
+                        long_long = true;
+                        len += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        // The first of the types the suffix allows that the value fits
+        // in. Only octal and hexadecimal numbers may become unsigned
+        // without a u.
+        let fits_int = sum <= i32::MAX as u64;
+        let fits_uint = sum <= u32::MAX as u64;
+        let fits_long = sum <= i64::MAX as u64;
+        let ty = match (unsigned, long) {
+            (false, false) if fits_int => NumType::Int,
+            (false, false) if fits_uint && base != 10 => NumType::UInt,
+            (false, _) if fits_long && long_long => NumType::LLong,
+            (false, _) if fits_long => NumType::Long,
+            (true, false) if fits_uint => NumType::UInt,
+            _ if long_long => NumType::ULLong,
+            _ => NumType::ULong,
+        };
+        let mut t = self.new_token(TokenType::Num(sum as i64, ty));
         self.pos += len;
+        t.start = start;
         t.end = self.pos;
         self.tokens.push(t);
     }
@@ -414,7 +778,7 @@ impl Tokenizer {
     fn canonicalize_newline(&mut self) {
         let mut pos = 0;
         while pos < self.p.len() {
-            if self.p[pos] == '\r' && self.p[pos + 1] == '\n' {
+            if self.p[pos] == '\r' && self.p.get(pos + 1) == Some(&'\n') {
                 Rc::get_mut(&mut self.p).unwrap().remove(pos);
                 Rc::get_mut(&mut self.p).unwrap().remove(pos);
             }
@@ -422,6 +786,42 @@ impl Tokenizer {
         }
     }
 
+    // Replaces the trigraphs, such as ??( for [, with the characters
+    // they stand for, before anything else looks at them: ??/ at the
+    // end of a line continues it as \ does.
+    fn replace_trigraphs(&mut self) {
+        let mut out = Vec::with_capacity(self.p.len());
+        let mut pos = 0;
+        while pos < self.p.len() {
+            let c = match self.p[pos..] {
+                ['?', '?', c, ..] => match c {
+                    '=' => Some('#'),
+                    '(' => Some('['),
+                    '/' => Some('\\'),
+                    ')' => Some(']'),
+                    '\'' => Some('^'),
+                    '<' => Some('{'),
+                    '!' => Some('|'),
+                    '>' => Some('}'),
+                    '-' => Some('~'),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match c {
+                Some(c) => {
+                    out.push(c);
+                    pos += 3;
+                }
+                None => {
+                    out.push(self.p[pos]);
+                    pos += 1;
+                }
+            }
+        }
+        self.p = Rc::new(out);
+    }
+
     // Quoted from 9cc
     // > Concatenates continuation lines. We keep the total number of
     // > newline characters the same to keep the line counter sane.
@@ -429,7 +829,7 @@ impl Tokenizer {
         let mut pos = 0;
         let mut cnt = 0;
         while pos < self.p.len() {
-            if self.p[pos] == '\\' && self.p[pos + 1] == '\n' {
+            if self.p[pos] == '\\' && self.p.get(pos + 1) == Some(&'\n') {
                 cnt += 1;
                 Rc::get_mut(&mut self.p).unwrap().remove(pos);
                 Rc::get_mut(&mut self.p).unwrap().remove(pos);
@@ -447,39 +847,6 @@ impl Tokenizer {
         }
     }
 
-    fn append(&mut self, x_str: &str, y_str: &str, start: usize) -> Token {
-        let concated = format!("{}{}", x_str, y_str);
-        let l = concated.len() + 1; // Because `+1` has `\0`.
-        Token::new(
-            TokenType::Str(concated, l),
-            start,
-            self.filename.clone(),
-            self.p.clone(),
-        )
-    }
-
-    fn join_string_literals(&mut self) {
-        let mut v = vec![];
-        let mut last_may: Option<Token> = None;
-
-        for t in self.tokens.clone().into_iter() {
-            if let Some(ref last) = last_may {
-                if let (TokenType::Str(ref last_str, _), TokenType::Str(ref t_str, _)) =
-                    (&last.ty, &t.ty)
-                {
-                    let new = self.append(last_str, t_str, last.start);
-                    v.pop();
-                    v.push(new);
-                    continue;
-                }
-            }
-
-            last_may = Some(t.clone());
-            v.push(t);
-        }
-        self.tokens = v;
-    }
-
     fn strip_newlines_tokens(&mut self) {
         self.tokens = self
             .tokens
@@ -489,47 +856,124 @@ impl Tokenizer {
             .collect()
     }
 
-    fn bad_position(&self, msg: &'static str) {
-        print_line(&self.p, &self.filename, self.pos);
-        panic!(msg);
+    fn bad_position(&self, msg: &'static str) -> ! {
+        diagnostic::error(
+            msg.to_string(),
+            Some(location(&self.p, &self.filename, self.pos, 1)),
+        )
     }
 }
 
-// Finds a line pointed by a given pointer from the input file
-// to print it out.
-fn print_line(buf: &[char], path: &str, pos: usize) {
-    let mut p = 0;
-    let mut start = 0;
-    let mut line = 0;
-    let mut col = 0;
-    for c in buf.iter() {
-        if c == &'\n' {
-            start = pos + 1;
-            line += 1;
-            col = 0;
-            p += 1;
-            continue;
-        }
+// Where a statement or definition starts in the source, for sema's
+// diagnostics and -fverbose-asm. The text is copied out because nodes
+// outlive the token buffer in sema's global tables, which must be Send.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub filename: String,
+    pub line: usize,  // 1-based
+    pub col: usize,   // 1-based
+    pub text: String, // The whole line
+}
 
-        if p != pos {
-            col += 1;
-            p += 1;
-            continue;
+impl Span {
+    // Where the span is, for a diagnostic about what starts there.
+    pub fn location(&self) -> Location {
+        Location {
+            filename: self.filename.clone(),
+            line: self.line,
+            col: Some(self.col),
+            len: 1,
+            text: self.text.clone(),
         }
+    }
+}
 
-        print!("error at {}:{}:{}\n\n", path, line + 1, col);
-        break;
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.filename, self.line, self.text.trim())
     }
+}
 
-    for p in buf[start..].iter() {
-        if p == &'\n' {
-            break;
+// The token an expression starts with, or its operator, for sema's
+// diagnostics about it. Unlike a Span, it is cheap to keep for each
+// node: where in the file it is is only worked out for a diagnostic.
+#[derive(Clone)]
+pub struct Pos {
+    buf: Rc<Vec<char>>,
+    filename: Rc<String>,
+    start: usize,
+    end: usize,
+}
+
+impl Pos {
+    pub fn location(&self) -> Location {
+        let len = self.end.saturating_sub(self.start);
+        location(&self.buf, &self.filename, self.start, len)
+    }
+}
+
+// Node's Debug output is in some messages, where the whole file would
+// be too much.
+impl fmt::Debug for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{}", self.filename, self.start)
+    }
+}
+
+// Where the `len` characters at `pos` in the file are, for diagnostics.
+// The value of the digits of a hexadecimal floating constant after its
+// 0x, such as 1.8p3. The digits that don't fit in 64 bits only count
+// for rounding, as a bit that's set if any of them is nonzero.
+fn hex_float(s: &str) -> f64 {
+    let (digits, exp) = s.split_once(['p', 'P']).unwrap();
+    let mut exp: i64 = exp.parse().unwrap_or(i64::MAX / 2);
+    let mut mantissa: u64 = 0;
+    let mut sticky = false;
+    let mut point = false;
+    for c in digits.chars() {
+        if c == '.' {
+            point = true;
+            continue;
+        }
+        let d = c.to_digit(16).unwrap() as u64;
+        if mantissa >> 60 == 0 {
+            mantissa = mantissa << 4 | d;
+            if point {
+                exp -= 4;
+            }
+        } else {
+            sticky |= d != 0;
+            if !point {
+                exp += 4;
+            }
         }
-        print!("{}", p);
     }
-    println!();
-    for _ in 0..col - 1 {
-        print!(" ");
+    let mut val = (mantissa | sticky as u64) as f64;
+    // Scaling by at most 2^1000 at a time is exact until the result
+    // overflows or becomes subnormal.
+    while exp != 0 && val != 0.0 && val.is_finite() {
+        let step = exp.clamp(-1000, 1000);
+        val *= 2f64.powi(step as i32);
+        exp -= step;
+    }
+    val
+}
+
+fn location(buf: &[char], filename: &str, pos: usize, len: usize) -> Location {
+    let pos = pos.min(buf.len());
+    let start = buf[..pos]
+        .iter()
+        .rposition(|c| *c == '\n')
+        .map_or(0, |i| i + 1);
+    let end = buf[pos..]
+        .iter()
+        .position(|c| *c == '\n')
+        .map_or(buf.len(), |i| pos + i);
+    Location {
+        filename: filename.to_string(),
+        line: buf[..start].iter().filter(|c| **c == '\n').count() + 1,
+        col: Some(pos - start + 1),
+        len: len.min(end - pos).max(1),
+        text: buf[start..end].iter().collect(),
     }
-    print!("^\n\n");
 }
@@ -1,3 +1,169 @@
+use crate::Reloc;
+use std::fmt::Write;
+
+// Writes a line of assembly, indented, to `out`. It formats straight
+// into the output, with no String per instruction.
+macro_rules! emit {
+    ($out:expr, $fmt:expr) => ($out.push_str(concat!("\t", $fmt, "\n")));
+    ($out:expr, $fmt:expr, $($arg:tt)*) => ({ let _ = writeln!($out, concat!("\t", $fmt), $($arg)*); });
+}
+pub(crate) use emit;
+
 pub fn roundup(x: usize, align: usize) -> usize {
     (x + align - 1) & !(align - 1)
 }
+
+// Escapes `data` for an .ascii directive, padded with NULs to `len`
+// bytes. Other bytes are written in octal with three digits, so that a
+// digit after them isn't taken as part of the escape.
+pub fn backslash_escape(data: &[u8], len: usize) -> String {
+    let mut sb = String::new();
+    for i in 0..len {
+        let b = data.get(i).copied().unwrap_or(0);
+        let escaped = match b {
+            b'\n' => Some('n'),
+            b'\r' => Some('r'),
+            b'\t' => Some('t'),
+            b'\\' => Some('\\'),
+            b'\'' => Some('\''),
+            b'"' => Some('"'),
+            _ => None,
+        };
+        if let Some(esc) = escaped {
+            sb.push('\\');
+            sb.push(esc);
+        } else if b.is_ascii_graphic() || b == b' ' {
+            sb.push(b as char);
+        } else {
+            let _ = write!(sb, "\\{:03o}", b);
+        }
+    }
+    sb
+}
+
+// Writes the initial value of a global for an assembler: `data`,
+// padded with NULs to `len` bytes, with a `.quad` for each of `relocs`.
+// `sym` gives the assembler's name for a symbol.
+pub fn emit_data(
+    out: &mut String,
+    data: &[u8],
+    len: usize,
+    relocs: &[Reloc],
+    sym: impl Fn(&str) -> String,
+) {
+    if data.is_empty() && relocs.is_empty() {
+        emit!(out, ".zero {}", len);
+        return;
+    }
+    let mut pos = 0;
+    for reloc in relocs {
+        if pos < reloc.offset {
+            let bytes = data.get(pos..).unwrap_or(&[]);
+            emit!(
+                out,
+                ".ascii \"{}\"",
+                backslash_escape(bytes, reloc.offset - pos)
+            );
+        }
+        match reloc.addend {
+            0 => emit!(out, ".quad {}", sym(&reloc.name)),
+            addend => emit!(out, ".quad {}{:+}", sym(&reloc.name), addend),
+        }
+        pos = reloc.offset + 8;
+    }
+    if pos < len {
+        let bytes = data.get(pos..).unwrap_or(&[]);
+        emit!(out, ".ascii \"{}\"", backslash_escape(bytes, len - pos));
+    }
+}
+
+// Like backslash_escape, but writes every unprintable byte as \hh, which
+// is the only escape both WebAssembly text and LLVM IR accept.
+pub fn hex_escape(data: &[u8], len: usize) -> String {
+    let mut bytes = data.to_vec();
+    bytes.resize(len, 0);
+
+    let mut sb = String::new();
+    for b in bytes {
+        if (b.is_ascii_graphic() || b == b' ') && b != b'"' && b != b'\\' {
+            sb.push(b as char);
+        } else {
+            let _ = write!(sb, "\\{:02x}", b);
+        }
+    }
+    sb
+}
+
+// Quotes a string for JSON.
+pub fn json_string(s: &str) -> String {
+    let mut sb = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => sb.push_str("\\\""),
+            '\\' => sb.push_str("\\\\"),
+            '\n' => sb.push_str("\\n"),
+            '\t' => sb.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(sb, "\\u{:04x}", c as u32);
+            }
+            c => sb.push(c),
+        }
+    }
+    sb.push('"');
+    sb
+}
+
+// The number of characters to insert, delete or replace, or of pairs
+// of neighbors to swap, to turn `a` into `b` (the optimal string
+// alignment distance). Swaps are common typos, as in "retrun".
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, d) in d[0].iter_mut().enumerate() {
+        *d = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+// The candidate most like `name`, to suggest for a misspelling of it,
+// if one is close enough: a third of its characters may be wrong, so
+// names shorter than three get none. Ties go to the first in
+// alphabetical order, so that the suggestion doesn't depend on the
+// order of `candidates`.
+pub fn closest<I, S>(name: &str, candidates: I) -> Option<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let limit = name.chars().count() / 3;
+    let mut best: Option<(usize, String)> = None;
+    for c in candidates {
+        let c = c.as_ref();
+        let d = edit_distance(name, c);
+        if d == 0 || d > limit {
+            continue;
+        }
+        if best
+            .as_ref()
+            .is_none_or(|(bd, bc)| (d, c) < (*bd, bc.as_str()))
+        {
+            best = Some((d, c.to_string()));
+        }
+    }
+    best.map(|(_, c)| c)
+}
@@ -0,0 +1,423 @@
+// Whole-program optimization (--whole-program).
+//
+// Every file on the command line is parsed on its own, as it would be
+// compiled, and the trees are then merged into one program that goes
+// through the rest of the compiler together:
+//
+//   r9cc --whole-program -O2 main.c util.c
+//
+// As with gcc -fwhole-program, that is taken to be the whole program:
+// nothing outside it calls its functions or uses its variables, except
+// main. So everything else is made static, which lets ipcp.rs see every
+// call of a function wherever it is, small functions be inlined into
+// their callers in other files, and what is no longer used be dropped.
+
+use crate::coverage::Profile;
+use crate::diagnostic;
+use crate::parse::{Node, NodeType};
+use crate::sema::convert;
+use crate::symbol::{self, Symbol};
+use crate::util::roundup;
+use crate::{Ctype, Scope, TokenType, Var};
+
+use std::collections::{HashMap, HashSet};
+
+// Functions with bodies of at most this many nodes are inlined, and
+// at call sites that -fprofile-use says are hot, at most HOT_INLINE_LIMIT.
+const INLINE_LIMIT: usize = 40;
+const HOT_INLINE_LIMIT: usize = 160;
+
+// The name a top-level node defines or declares, and whether it is
+// static and a definition.
+fn toplevel(node: &Node) -> Option<(Symbol, bool, bool)> {
+    match node.op {
+        NodeType::Func(name, .., is_static) => Some((name, is_static, true)),
+        NodeType::Decl(name) => Some((name, false, false)),
+        NodeType::Vardef(name, _, Scope::Global(_, _, is_extern, _, is_static)) => {
+            Some((name, is_static, !is_extern))
+        }
+        _ => None,
+    }
+}
+
+// Renames whatever is called `from` in a file to `to`. A local of that
+// name is renamed along with it, which changes nothing.
+fn rename(node: &mut Node, from: Symbol, to: Symbol) {
+    match node.op {
+        NodeType::Ident(ref mut name)
+        | NodeType::Decl(ref mut name)
+        | NodeType::Vardef(ref mut name, ..)
+        | NodeType::Call(ref mut name, _)
+        | NodeType::Func(ref mut name, ..)
+            if *name == from =>
+        {
+            *name = to
+        }
+        _ => (),
+    }
+    for child in node.children_mut() {
+        rename(child, from, to);
+    }
+}
+
+// Merges the parsed files, as (name, nodes), into one program. What two
+// files define as static may have the same name, so such names get the
+// number of the file: `count` in the second becomes `count.1`.
+pub fn merge(files: Vec<(String, Vec<Node>)>) -> Vec<Node> {
+    let mut names: HashMap<Symbol, HashSet<usize>> = HashMap::new();
+    let mut defined: HashMap<Symbol, usize> = HashMap::new();
+    for (i, (path, nodes)) in files.iter().enumerate() {
+        for (name, is_static, is_definition) in nodes.iter().filter_map(toplevel) {
+            names.entry(name).or_default().insert(i);
+            if is_static || !is_definition {
+                continue;
+            }
+            match defined.insert(name, i) {
+                Some(j) if j != i => diagnostic::error(
+                    format!(
+                        "multiple definition of '{}' in {} and {}",
+                        name, files[j].0, path
+                    ),
+                    None,
+                ),
+                _ => (),
+            }
+        }
+    }
+
+    let mut program = vec![];
+    for (i, (_, mut nodes)) in files.into_iter().enumerate() {
+        let statics: Vec<Symbol> = nodes
+            .iter()
+            .filter_map(toplevel)
+            .filter(|&(name, is_static, _)| is_static && names[&name].len() > 1)
+            .map(|(name, ..)| name)
+            .collect();
+        for name in statics {
+            let to = symbol::intern(&format!("{}.{}", name, i));
+            for node in nodes.iter_mut() {
+                rename(node, name, to);
+            }
+        }
+        program.append(&mut nodes);
+    }
+    program
+}
+
+// Makes every function and variable that the program defines static,
+// except main. An extern declaration of one another file defines goes,
+// leaving the definition.
+pub fn internalize(nodes: &mut [Node], globals: &mut Vec<Var>) {
+    for node in nodes {
+        if let NodeType::Func(name, .., ref mut is_static) = node.op {
+            *is_static |= &*name.as_str() != "main";
+        }
+    }
+    let defined: HashSet<String> = globals
+        .iter()
+        .filter(|var| matches!(var.scope, Scope::Global(_, _, false, ..)))
+        .map(|var| var.name.clone())
+        .collect();
+    globals.retain(|var| match var.scope {
+        Scope::Global(_, _, true, ..) => !defined.contains(&var.name),
+        _ => true,
+    });
+    for var in globals {
+        if let Scope::Global(_, _, false, _, ref mut is_static) = var.scope {
+            *is_static = true;
+        }
+    }
+}
+
+// Calls `f` on `node` and everything in it.
+fn walk_mut(node: &mut Node, f: &mut impl FnMut(&mut Node)) {
+    f(node);
+    for child in node.children_mut() {
+        walk_mut(child, f);
+    }
+}
+
+// A function that can be inlined: its parameters, as their
+// definitions, and its body, with the stack it needs for its locals.
+struct Callee {
+    params: Vec<Node>,
+    body: Node,
+    stacksize: usize,
+    size: usize, // Nodes in the body
+}
+
+fn inlinable(node: &Node) -> Option<Callee> {
+    let (name, args, body, stacksize) = match node.op {
+        NodeType::Func(name, ref args, ref body, stacksize, true) => (name, args, body, stacksize),
+        _ => return None,
+    };
+    match node.ty.ty {
+        Ctype::Func(ref ret, _, false, _) if !matches!(ret.ty, Ctype::Struct(_)) => (),
+        _ => return None,
+    }
+    let scalar = |arg: &Node| {
+        matches!(arg.op, NodeType::Vardef(_, _, Scope::Local(_)))
+            && !matches!(arg.ty.ty, Ctype::Struct(_))
+    };
+    let size = body.count();
+    if !args.iter().all(scalar) || size > HOT_INLINE_LIMIT {
+        return None;
+    }
+    // Nor what depends on having a frame of its own, or calls itself.
+    let mut body = (**body).clone();
+    let mut ok = true;
+    walk_mut(&mut body, &mut |node| match node.op {
+        NodeType::Asm(_) => ok = false,
+        NodeType::Builtin(ref kind, _) => {
+            use crate::parse::Builtin::*;
+            ok &= !matches!(kind, Alloca | VaStart | VaArg);
+        }
+        NodeType::Call(callee, _) => ok &= callee != name,
+        _ => (),
+    });
+    ok.then(|| Callee {
+        params: args.clone(),
+        body,
+        stacksize,
+        size,
+    })
+}
+
+// Moves the locals of an inlined body down by `base` bytes in the
+// frame of its caller.
+fn relocate(node: &mut Node, base: usize) {
+    walk_mut(node, &mut |node| match node.op {
+        NodeType::Lvar(Scope::Local(ref mut offset))
+        | NodeType::Vardef(_, _, Scope::Local(ref mut offset)) => *offset += base,
+        _ => (),
+    });
+}
+
+// A call of `callee` as a statement expression, which defines its
+// parameters with the arguments and returns to it like a function. Its
+// locals get their own part of the caller's frame, which grows by
+// `stacksize`.
+fn expand(callee: &Callee, call: &mut Node, stacksize: &mut usize) {
+    let args = match call.op {
+        NodeType::Call(_, ref mut args) => std::mem::take(args),
+        _ => unreachable!(),
+    };
+    let base = roundup(*stacksize, 16);
+    *stacksize = base + callee.stacksize;
+
+    let mut stmts = vec![];
+    for (param, arg) in callee.params.iter().zip(args) {
+        let mut def = param.clone();
+        relocate(&mut def, base);
+        if let NodeType::Vardef(_, ref mut init, _) = def.op {
+            *init = Some(Box::new(convert(arg, &param.ty)));
+        }
+        stmts.push(def);
+    }
+    let mut body = callee.body.clone();
+    relocate(&mut body, base);
+    stmts.push(body);
+    // What falls off the end returns nothing in particular.
+    stmts.push(Node::new(NodeType::Return(Box::new(Node::new_num(0)))));
+
+    let mut expr = Node::new(NodeType::StmtExpr(Box::new(Node::new(NodeType::CompStmt(
+        stmts,
+    )))));
+    expr.ty = call.ty.clone();
+    *call = expr;
+}
+
+// The call that `stmt` makes of its value, if it makes one, or assigns
+// to something or returns. Only such calls are inlined: the registers
+// live around a statement expression stay so over its whole body, so
+// one in the middle of a larger expression would leave its code fewer.
+fn call_in(stmt: &mut Node) -> Option<&mut Node> {
+    let mut expr = match stmt.op {
+        NodeType::ExprStmt(ref mut expr) | NodeType::Return(ref mut expr) => &mut **expr,
+        NodeType::Vardef(_, Some(ref mut expr), _) => &mut **expr,
+        _ => return None,
+    };
+    if let NodeType::BinOp(TokenType::Equal, _, ref mut rhs) = expr.op {
+        expr = &mut **rhs;
+    }
+    if let NodeType::Cast(ref mut inner) = expr.op {
+        expr = &mut **inner;
+    }
+    match expr.op {
+        NodeType::Call(..) => Some(expr),
+        _ => None,
+    }
+}
+
+// How big a callee may be inlined at a statement. With -fprofile-use,
+// a statement that ran at least a hundredth as often as the busiest
+// block of the program is hot and may inline more, and one that never
+// ran inlines nothing, as its code would only grow.
+struct Limits<'a> {
+    profile: Option<&'a Profile>,
+    hot: u64,
+    keys: HashMap<String, String>, // The profile's names of files
+}
+
+impl<'a> Limits<'a> {
+    fn new(profile: Option<&'a Profile>) -> Self {
+        Limits {
+            profile,
+            hot: profile.map_or(0, |p| p.max() / 100).max(1),
+            keys: HashMap::new(),
+        }
+    }
+
+    fn at(&mut self, stmt: &Node) -> usize {
+        let (profile, span) = match (self.profile, &stmt.span) {
+            (Some(profile), Some(span)) => (profile, span),
+            _ => return INLINE_LIMIT,
+        };
+        let key = self
+            .keys
+            .entry(span.filename.clone())
+            .or_insert_with(|| Profile::key(&span.filename));
+        match profile.count(key, span.line) {
+            Some(0) => 0,
+            Some(n) if n >= self.hot => HOT_INLINE_LIMIT,
+            _ => INLINE_LIMIT,
+        }
+    }
+}
+
+fn inline_calls(
+    node: &mut Node,
+    callees: &HashMap<Symbol, Callee>,
+    limits: &mut Limits,
+    stacksize: &mut usize,
+    n: &mut usize,
+) {
+    for child in node.children_mut() {
+        inline_calls(child, callees, limits, stacksize, n);
+    }
+    let limit = limits.at(node);
+    if let Some(call) = call_in(node) {
+        if let NodeType::Call(name, ref args) = call.op {
+            match callees.get(&name) {
+                Some(callee) if callee.params.len() == args.len() && callee.size <= limit => {
+                    expand(callee, call, stacksize);
+                    *n += 1;
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+// The functions of `nodes` in an order where those a function calls
+// come before it, as far as they don't call it back.
+fn callees_first(nodes: &mut [Node]) -> Vec<usize> {
+    let index: HashMap<Symbol, usize> = nodes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, node)| match node.op {
+            NodeType::Func(name, ..) => Some((name, i)),
+            _ => None,
+        })
+        .collect();
+    let calls: Vec<Vec<usize>> = nodes
+        .iter_mut()
+        .map(|node| {
+            let mut calls = vec![];
+            walk_mut(node, &mut |node| {
+                if let NodeType::Call(name, _) = node.op {
+                    calls.extend(index.get(&name));
+                }
+            });
+            calls
+        })
+        .collect();
+
+    fn visit(i: usize, calls: &[Vec<usize>], seen: &mut [bool], order: &mut Vec<usize>) {
+        if !seen[i] {
+            seen[i] = true;
+            for &callee in &calls[i] {
+                visit(callee, calls, seen, order);
+            }
+            order.push(i);
+        }
+    }
+    let mut seen = vec![false; nodes.len()];
+    let mut order = vec![];
+    for i in 0..nodes.len() {
+        visit(i, &calls, &mut seen, &mut order);
+    }
+    order
+}
+
+// Inlines the calls of small static functions, which at -O2 are those
+// of any file and with --whole-program all of them. Callees get theirs
+// inlined first, so what they call is inlined with them if they stay
+// small enough. `profile` is from -fprofile-use, if given (see Limits).
+// Returns how many calls were.
+pub fn inline(nodes: &mut [Node], profile: Option<&Profile>) -> usize {
+    let mut callees: HashMap<Symbol, Callee> = HashMap::new();
+    let mut limits = Limits::new(profile);
+    let mut n = 0;
+    for i in callees_first(nodes) {
+        let node = &mut nodes[i];
+        if let NodeType::Func(name, _, ref mut body, ref mut stacksize, _) = node.op {
+            inline_calls(body, &callees, &mut limits, stacksize, &mut n);
+            if let Some(callee) = inlinable(node) {
+                callees.insert(name, callee);
+            }
+        }
+    }
+    n
+}
+
+// Removes the static functions and variables that nothing outside the
+// program can reach, through main or what else isn't static. Returns
+// how many were.
+pub fn remove_dead(nodes: &mut Vec<Node>, globals: &mut Vec<Var>) -> usize {
+    // What each function refers to.
+    let mut refs: HashMap<String, Vec<String>> = HashMap::new();
+    for node in nodes.iter_mut() {
+        if let NodeType::Func(name, ..) = node.op {
+            let mut names = vec![];
+            walk_mut(node, &mut |node| match node.op {
+                NodeType::Call(callee, _) => names.push(callee.to_string()),
+                NodeType::Gvar(ref name, ..) => names.push(name.clone()),
+                _ => (),
+            });
+            refs.insert(name.to_string(), names);
+        }
+    }
+    for var in globals.iter() {
+        let names = var.relocs.iter().map(|reloc| reloc.name.clone());
+        refs.insert(var.name.clone(), names.collect());
+    }
+    let mut live: HashSet<String> = HashSet::new();
+    let mut work: Vec<String> = vec![];
+    for node in nodes.iter() {
+        if let NodeType::Func(name, .., false) = node.op {
+            work.push(name.to_string());
+        }
+    }
+    for var in globals.iter() {
+        if let Scope::Global(_, _, _, _, false) = var.scope {
+            if !var.name.starts_with(".L") {
+                work.push(var.name.clone());
+            }
+        }
+    }
+    while let Some(name) = work.pop() {
+        if !live.insert(name.clone()) {
+            continue;
+        }
+        work.extend(refs.get(&name).into_iter().flatten().cloned());
+    }
+
+    let before = nodes.len() + globals.len();
+    nodes.retain(|node| match node.op {
+        NodeType::Func(name, ..) => live.contains(&*name.as_str()),
+        _ => true,
+    });
+    globals.retain(|var| live.contains(&var.name));
+    before - nodes.len() - globals.len()
+}
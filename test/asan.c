@@ -0,0 +1,31 @@
+// Each argument makes a different out-of-bounds access; see the
+// Makefile.
+
+int get(int *p, int i) { return p[i]; }
+
+int set(char *p, int i) {
+  p[i] = 1;
+  return 0;
+}
+
+int main(int argc, char **argv) {
+  int n = argv[1][0] - 48;
+  int a[4];
+  char c[5];
+  int *p = a;
+  a[3] = 7;
+  c[4] = 1;
+  if (n == 1)
+    return get(a, 4);
+  if (n == 2)
+    return get(a, -1);
+  if (n == 3)
+    return set(c, 5);
+  if (n == 4)
+    return set(c, 8);
+  if (n == 5) {
+    p[4] += 1;
+    return 0;
+  }
+  return get(a, 3) + c[4] - 8;
+}
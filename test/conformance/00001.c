@@ -0,0 +1,6 @@
+int printf();
+
+int main() {
+  printf("hello\n");
+  return 0;
+}
@@ -0,0 +1,24 @@
+int printf();
+
+int fib(int n) {
+  if (n < 2)
+    return n;
+  return fib(n - 1) + fib(n - 2);
+}
+
+void swap(int *a, int *b) {
+  int t = *a;
+  *a = *b;
+  *b = t;
+}
+
+int main() {
+  int x[2];
+  x[0] = fib(10);
+  x[1] = fib(12);
+  swap(&x[0], &x[1]);
+  for (int i = 0; i < 3; i++)
+    printf("%d ", i);
+  printf("%d %d\n", x[0], x[1]);
+  return 0;
+}
@@ -0,0 +1,8 @@
+int printf();
+
+#if 1
+int main() {
+  printf("ok\n");
+  return 0;
+}
+#endif
@@ -0,0 +1,7 @@
+int printf();
+
+int main() {
+  float f = 1.5;
+  printf("%d\n", (int)(f * 2));
+  return 0;
+}
@@ -0,0 +1,9 @@
+int printf();
+
+int main() {
+  int n = 3;
+  int a[n];
+  a[2] = 4;
+  printf("%d\n", a[2]);
+  return 0;
+}
@@ -0,0 +1,16 @@
+// Compiled with --coverage; the Makefile checks the report.
+
+int odd(int x) { return x & 1; }
+
+int never() { return 0; }
+
+int main() {
+  int n = 0;
+  for (int i = 0; i < 10; i++) {
+    if (odd(i))
+      n++;
+  }
+  if (n == 0)
+    return never();
+  return n - 5;
+}
@@ -1,3 +1,91 @@
 // This file is compiled by gcc.
 
+#include <pthread.h>
+#include <stdarg.h>
+
  int global_arr[1] = {5};
+
+typedef struct { char a; char b; char c; } Chars;
+typedef struct { int a; int b; } Pair;
+typedef struct { int a; int b; int c; } Tri;
+typedef struct { int a; int b; int c; int d; int e; } Big;
+
+Chars gcc_chars(int a, int b, int c) { Chars x = {a, b, c}; return x; }
+Tri gcc_tri(int a, int b, int c) { Tri t = {a, b, c}; return t; }
+Big gcc_big(int a, int e) { Big b = {a, 0, 0, 0, e}; return b; }
+int gcc_sum_tri(Tri t) { return t.a + t.b + t.c; }
+int gcc_sum_big(Big b) { return b.a + b.e; }
+int gcc_spill(Tri t, Tri u, Tri v, Pair p, int x) { return t.a + u.b + v.c + p.b + x; }
+
+// Calls functions compiled by r9cc.
+Chars make_chars(int a, int b, int c);
+Pair make_pair(int a, int b);
+Big make_big(int a, int e);
+int sum_chars(Chars x);
+int sum_big(Big b);
+int mixed(int a, Tri t, int b, Big g, Pair p);
+int spill(Tri t, Tri u, Tri v, Pair p, int x);
+
+int gcc_call_r9cc() {
+  Chars c = make_chars(1, 2, 3);
+  Pair p = make_pair(3, 4);
+  Big g = make_big(8, 9);
+  Tri t = {1, 2, 3};
+  Tri u = {0, 2, 0};
+  Tri v = {0, 0, 3};
+  return c.c == 3 && p.b == 4 && g.e == 9 && sum_chars(c) == 6 &&
+         sum_big(g) == 17 && mixed(4, t, 3, g, p) == 23 &&
+         spill(t, u, v, p, 10) == 20;
+}
+
+// Reads arguments from a va_list started by r9cc.
+int gcc_vsum(int n, va_list ap) {
+  int s = 0;
+  for (int i = 0; i < n; i++)
+    s += va_arg(ap, int);
+  return s;
+}
+
+// Calls variadic functions compiled by r9cc.
+int sum(int n, ...);
+int sum_structs(int n, ...);
+int skip_ints(int n, ...);
+
+int gcc_call_sum() {
+  Pair p = {1, 2};
+  Tri t = {1, 2, 3};
+  Big g = {5, 0, 0, 0, 14};
+  return sum(3, 1, 2, 3) == 6 && sum(9, 1, 2, 3, 4, 5, 6, 7, 8, 9) == 45 &&
+         sum_structs(1, p, t, 4, g) == 24 &&
+         skip_ints(4, 1, 2, 3, 4, t) == 3 && skip_ints(5, 1, 2, 3, 4, 5, t) == 3;
+}
+
+_Thread_local int gcc_tls = 3;
+int tls_get();
+
+static void *read_tls(void *ret) {
+  *(int *)ret = tls_get();
+  return NULL;
+}
+
+// Reads an r9cc thread-local variable from a new thread.
+int gcc_tls_in_thread() {
+  pthread_t t;
+  int ret = -1;
+  pthread_create(&t, NULL, read_tls, &ret);
+  pthread_join(t, NULL);
+  return ret;
+}
+
+// Floating-point arguments and return values, both ways.
+double gcc_mix(int a, float b, double c, long d) { return a + b * c - d; }
+float gcc_half(float x) { return x / 2; }
+
+double scale(double x, int n, float f);
+float to_float(double x);
+double dsum(int n, ...);
+
+int gcc_call_float() {
+  return scale(1.5, 3, 0.5f) == 2.25 && to_float(0.1) == 0.1f &&
+         dsum(3, 1.0, 2.5, 0.25) == 3.75 && dsum(9, 1., 1., 1., 1., 1., 1., 1., 1., 1.) == 9;
+}
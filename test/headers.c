@@ -0,0 +1,27 @@
+// The headers r9cc comes with (see include/).
+#include <stddef.h>
+#include <stdarg.h>
+#include <stdbool.h>
+#include <limits.h>
+#include <stddef.h>
+typedef struct s {
+  int a;
+  long b;
+} S;
+int sum(int n, ...) {
+  va_list ap;
+  va_start(ap, n);
+  int s = 0;
+  for (int i = 0; i < n; i++) s += va_arg(ap, int);
+  va_end(ap);
+  return s;
+}
+int main() {
+  S v;
+  size_t n = sizeof(v);
+  bool ok = true;
+  int *p = NULL;
+  if (INT_MIN + INT_MAX != -1 || LONG_MAX != 9223372036854775807L || UINT_MAX != 4294967295U) return 1;
+  if (offsetof(struct s, b) != 8 || n != 16 || p) return 2;
+  return ok && sum(3, 1, 2, 3) == 6 ? 0 : 3;
+}
@@ -0,0 +1,13 @@
+// Linked with test/multi2.c; the Makefile checks that they link.
+
+extern int counter;
+int bump(int n);
+
+// test/multi2.c has a static of this name too.
+static int step = 3;
+
+int main() {
+  bump(step);
+  bump(step + 1);
+  return counter - 7;
+}
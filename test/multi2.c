@@ -0,0 +1,12 @@
+// Defines what test/multi1.c uses.
+
+int counter;
+
+static int step(int n) {
+  return n;
+}
+
+int bump(int n) {
+  counter = counter + step(n);
+  return counter;
+}
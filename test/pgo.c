@@ -0,0 +1,31 @@
+// Compiled with --coverage, then with -fprofile-use at -O2; the
+// Makefile checks which calls are inlined, and that the branch in main
+// that ran less is laid out after the one that ran more.
+
+static int big(int x) {
+  int y = x * 3 + 1;
+  if (y % 2)
+    y = y * 5 + x - 7;
+  else
+    y = y / 2 + x * x - 3;
+  if (y > 100)
+    y = y - 100 + x % 7;
+  return y & 255;
+}
+
+static int small(int x) { return x + 1; }
+
+int main() {
+  int n = 0;
+  for (int i = 0; i < 100; i++) {
+    int y = big(i);
+    if (i % 10 == 0)
+      n = n + 1111;
+    else
+      n = n + 2222;
+    n = n + y;
+  }
+  if (n == 0)
+    return small(n);
+  return n == 223477 ? 0 : 1;
+}
@@ -0,0 +1,10 @@
+// Arithmetic, comparisons and logical operators on locals.
+int main() {
+  int a = 7;
+  int b = 3;
+  int c = a * b + a / b - a % b;
+  c = c << 2 | a & b ^ 1;
+  if (a < b || b >= 3 && !(a == b))
+    c = -c;
+  return c;
+}
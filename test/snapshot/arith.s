@@ -0,0 +1,125 @@
+.intel_syntax noprefix
+.text
+.global main
+	.type main, @function
+main:
+	push rbp
+	mov rbp, rsp
+	sub rsp, 16
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+	sub rsp, 8
+	mov r10, 7
+	lea r11, [rbp-4]
+	mov [r11], r10d
+	mov r10, 3
+	lea r11, [rbp-8]
+	mov [r11], r10d
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	lea r11, [rbp-8]
+	movsxd r11, DWORD PTR [r11]
+	imul r10, r11
+	lea r11, [rbp-4]
+	movsxd r11, DWORD PTR [r11]
+	lea rbx, [rbp-8]
+	movsxd rbx, DWORD PTR [rbx]
+	mov rax, r11
+	cqo
+	idiv rbx
+	mov r11, rax
+	add r10, r11
+	lea r11, [rbp-4]
+	movsxd r11, DWORD PTR [r11]
+	lea rbx, [rbp-8]
+	movsxd rbx, DWORD PTR [rbx]
+	mov rax, r11
+	cqo
+	idiv rbx
+	mov r11, rdx
+	sub r10, r11
+	lea r11, [rbp-12]
+	mov [r11], r10d
+	lea r10, [rbp-12]
+	movsxd r10, DWORD PTR [r10]
+	mov r11, 2
+	mov cl, r11b
+	shl r10, cl
+	lea r11, [rbp-4]
+	movsxd r11, DWORD PTR [r11]
+	lea rbx, [rbp-8]
+	movsxd rbx, DWORD PTR [rbx]
+	and r11, rbx
+	mov rbx, 1
+	xor r11, rbx
+	or r10, r11
+	lea r11, [rbp-12]
+	mov [r11], r10d
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	lea r11, [rbp-8]
+	movsxd r11, DWORD PTR [r11]
+	cmp r10, r11
+	setl r10b
+	movzx r10, r10b
+	cmp r10, 0
+	je .L2
+	mov r10, 1
+	jmp .L3
+.L2:
+	mov r11, 3
+	lea rbx, [rbp-8]
+	movsxd rbx, DWORD PTR [rbx]
+	cmp r11, rbx
+	setle r11b
+	movzx r11, r11b
+	cmp r11, 0
+	je .L4
+	lea rbx, [rbp-4]
+	movsxd rbx, DWORD PTR [rbx]
+	lea r12, [rbp-8]
+	movsxd r12, DWORD PTR [r12]
+	cmp rbx, r12
+	sete bl
+	movzx rbx, bl
+	mov r12, 0
+	cmp rbx, r12
+	sete bl
+	movzx rbx, bl
+	mov r11, rbx
+	cmp r11, 0
+	je .L4
+	mov r11, 1
+.L4:
+	mov r10, r11
+	cmp r10, 0
+	je .L3
+	mov r10, 1
+.L3:
+	cmp r10, 0
+	je .L1
+	lea r10, [rbp-12]
+	movsxd r10, DWORD PTR [r10]
+	neg r10
+	lea r11, [rbp-12]
+	mov [r11], r10d
+.L1:
+	lea r10, [rbp-12]
+	movsxd r10, DWORD PTR [r10]
+	mov rax, r10
+	jmp .Lmain.0
+.Lmain.0:
+	add rsp, 8
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
+	mov rsp, rbp
+	pop rbp
+	ret
+	.size main, .-main
+.section .note.GNU-stack,"",@progbits
@@ -0,0 +1,15 @@
+// Calls, parameters and recursion.
+int add(int a, int b) { return a + b; }
+
+int fib(int n) {
+  if (n < 2)
+    return n;
+  return fib(n - 1) + fib(n - 2);
+}
+
+int many(int a, int b, int c, int d) { return a - b + c - d; }
+
+int main() {
+  int x = fib(10);
+  return add(x, many(1, 2, 3, 4));
+}
@@ -0,0 +1,204 @@
+.intel_syntax noprefix
+.text
+.global add
+	.type add, @function
+add:
+	push rbp
+	mov rbp, rsp
+	sub rsp, 16
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+	sub rsp, 8
+	mov [rbp-4], edi
+	mov [rbp-8], esi
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	lea r11, [rbp-8]
+	movsxd r11, DWORD PTR [r11]
+	add r10, r11
+	mov rax, r10
+	jmp .Ladd.0
+.Ladd.0:
+	add rsp, 8
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
+	mov rsp, rbp
+	pop rbp
+	ret
+	.size add, .-add
+.text
+.global fib
+	.type fib, @function
+fib:
+	push rbp
+	mov rbp, rsp
+	sub rsp, 16
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+	sub rsp, 8
+	mov [rbp-4], edi
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	mov r11, 2
+	cmp r10, r11
+	setl r10b
+	movzx r10, r10b
+	cmp r10, 0
+	je .L1
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	mov rax, r10
+	jmp .Lfib.0
+.L1:
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	mov r11, 1
+	sub r10, r11
+	push r10
+	push r11
+	mov rdi, r10
+	mov rax, 0
+	call fib
+	pop r11
+	pop r10
+	mov r11, rax
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	mov rbx, 2
+	sub r10, rbx
+	push r10
+	push r11
+	mov rdi, r10
+	mov rax, 0
+	call fib
+	pop r11
+	pop r10
+	mov rbx, rax
+	add r11, rbx
+	mov rax, r11
+	jmp .Lfib.0
+.Lfib.0:
+	add rsp, 8
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
+	mov rsp, rbp
+	pop rbp
+	ret
+	.size fib, .-fib
+.text
+.global many
+	.type many, @function
+many:
+	push rbp
+	mov rbp, rsp
+	sub rsp, 16
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+	sub rsp, 8
+	mov [rbp-4], edi
+	mov [rbp-8], esi
+	mov [rbp-12], edx
+	mov [rbp-16], ecx
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	lea r11, [rbp-8]
+	movsxd r11, DWORD PTR [r11]
+	sub r10, r11
+	lea r11, [rbp-12]
+	movsxd r11, DWORD PTR [r11]
+	add r10, r11
+	lea r11, [rbp-16]
+	movsxd r11, DWORD PTR [r11]
+	sub r10, r11
+	mov rax, r10
+	jmp .Lmany.0
+.Lmany.0:
+	add rsp, 8
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
+	mov rsp, rbp
+	pop rbp
+	ret
+	.size many, .-many
+.text
+.global main
+	.type main, @function
+main:
+	push rbp
+	mov rbp, rsp
+	sub rsp, 16
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+	sub rsp, 8
+	mov r10, 10
+	push r10
+	push r11
+	mov rdi, r10
+	mov rax, 0
+	call fib
+	pop r11
+	pop r10
+	mov r11, rax
+	lea r10, [rbp-4]
+	mov [r10], r11d
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	mov r11, 1
+	mov rbx, 2
+	mov r12, 3
+	mov r13, 4
+	push r10
+	push r11
+	mov rdi, r11
+	mov rsi, rbx
+	mov rdx, r12
+	mov rcx, r13
+	mov rax, 0
+	call many
+	pop r11
+	pop r10
+	mov r14, rax
+	push r10
+	push r11
+	mov rdi, r10
+	mov rsi, r14
+	mov rax, 0
+	call add
+	pop r11
+	pop r10
+	mov r11, rax
+	mov rax, r11
+	jmp .Lmain.0
+.Lmain.0:
+	add rsp, 8
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
+	mov rsp, rbp
+	pop rbp
+	ret
+	.size main, .-main
+.section .note.GNU-stack,"",@progbits
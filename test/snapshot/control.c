@@ -0,0 +1,23 @@
+// Loops and branches.
+int main() {
+  int sum = 0;
+  for (int i = 0; i < 10; i++) {
+    if (i == 7)
+      break;
+    sum = sum + i;
+  }
+  int j = 0;
+  while (j < 5)
+    j++;
+  do {
+    j--;
+  } while (j > 0);
+  switch (sum) {
+  case 1:
+    return 1;
+  case 21:
+    return 2;
+  default:
+    return sum ? 3 : 4;
+  }
+}
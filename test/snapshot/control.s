@@ -0,0 +1,139 @@
+.intel_syntax noprefix
+.text
+.global main
+	.type main, @function
+main:
+	push rbp
+	mov rbp, rsp
+	sub rsp, 16
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+	sub rsp, 8
+	mov r10, 0
+	lea r11, [rbp-4]
+	mov [r11], r10d
+	mov r10, 0
+	lea r11, [rbp-8]
+	mov [r11], r10d
+.L1:
+	lea r10, [rbp-8]
+	movsxd r10, DWORD PTR [r10]
+	mov r11, 10
+	cmp r10, r11
+	setl r10b
+	movzx r10, r10b
+	cmp r10, 0
+	je .L2
+	lea r10, [rbp-8]
+	movsxd r10, DWORD PTR [r10]
+	mov r11, 7
+	cmp r10, r11
+	sete r10b
+	movzx r10, r10b
+	cmp r10, 0
+	je .L4
+	jmp .L3
+.L4:
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	lea r11, [rbp-8]
+	movsxd r11, DWORD PTR [r11]
+	add r10, r11
+	lea r11, [rbp-4]
+	mov [r11], r10d
+	lea r10, [rbp-8]
+	movsxd r11, DWORD PTR [r10]
+	add r11, 1
+	mov [r10], r11d
+	sub r11, 1
+	jmp .L1
+.L2:
+.L3:
+	mov r10, 0
+	lea r11, [rbp-12]
+	mov [r11], r10d
+.L5:
+	lea r10, [rbp-12]
+	movsxd r10, DWORD PTR [r10]
+	mov r11, 5
+	cmp r10, r11
+	setl r10b
+	movzx r10, r10b
+	cmp r10, 0
+	je .L6
+	lea r10, [rbp-12]
+	movsxd r11, DWORD PTR [r10]
+	add r11, 1
+	mov [r10], r11d
+	sub r11, 1
+	jmp .L5
+.L6:
+.L7:
+.L8:
+	lea r10, [rbp-12]
+	movsxd r11, DWORD PTR [r10]
+	add r11, -1
+	mov [r10], r11d
+	sub r11, -1
+	mov r10, 0
+	lea r11, [rbp-12]
+	movsxd r11, DWORD PTR [r11]
+	cmp r10, r11
+	setl r10b
+	movzx r10, r10b
+	cmp r10, 0
+	jne .L8
+.L9:
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	mov r11, 1
+	cmp r11, r10
+	sete r11b
+	movzx r11, r11b
+	cmp r11, 0
+	jne .L10
+	mov r11, 21
+	cmp r11, r10
+	sete r11b
+	movzx r11, r11b
+	cmp r11, 0
+	jne .L11
+	jmp .L13
+.L10:
+	mov r10, 1
+	mov rax, r10
+	jmp .Lmain.0
+.L11:
+	mov r10, 2
+	mov rax, r10
+	jmp .Lmain.0
+.L13:
+	lea r10, [rbp-4]
+	movsxd r10, DWORD PTR [r10]
+	cmp r10, 0
+	je .L14
+	mov r11, 3
+	mov r10, r11
+	jmp .L15
+.L14:
+	mov r11, 4
+	mov r10, r11
+.L15:
+	mov rax, r10
+	jmp .Lmain.0
+.L12:
+.Lmain.0:
+	add rsp, 8
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
+	mov rsp, rbp
+	pop rbp
+	ret
+	.size main, .-main
+.section .note.GNU-stack,"",@progbits
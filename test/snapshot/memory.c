@@ -0,0 +1,23 @@
+// Arrays, pointers, structs and globals.
+int counter;
+char *greeting;
+
+typedef struct {
+  int x;
+  char tag;
+  int y;
+} point;
+
+int main() {
+  int ary[4];
+  int *p = ary;
+  for (int i = 0; i < 4; i++)
+    *(p + i) = i * i;
+  point pt;
+  pt.x = ary[2];
+  pt.y = ary[3];
+  point *pp = &pt;
+  greeting = "hello\n";
+  counter = pp->x + pt.y + greeting[1];
+  return counter;
+}
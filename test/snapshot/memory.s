@@ -0,0 +1,126 @@
+.intel_syntax noprefix
+.bss
+	.balign 4
+.global counter
+	.type counter, @object
+	.size counter, 4
+counter:
+	.zero 4
+.bss
+	.balign 8
+.global greeting
+	.type greeting, @object
+	.size greeting, 8
+greeting:
+	.zero 8
+.section .rodata
+	.balign 1
+.L.str0:
+	.ascii "hello\n\000"
+.text
+.global main
+	.type main, @function
+main:
+	push rbp
+	mov rbp, rsp
+	sub rsp, 48
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+	sub rsp, 8
+	lea r10, [rbp-16]
+	lea r11, [rbp-24]
+	mov [r11], r10
+	mov r10, 0
+	lea r11, [rbp-28]
+	mov [r11], r10d
+.L1:
+	lea r10, [rbp-28]
+	movsxd r10, DWORD PTR [r10]
+	mov r11, 4
+	cmp r10, r11
+	setl r10b
+	movzx r10, r10b
+	cmp r10, 0
+	je .L2
+	lea r10, [rbp-28]
+	movsxd r10, DWORD PTR [r10]
+	lea r11, [rbp-28]
+	movsxd r11, DWORD PTR [r11]
+	imul r10, r11
+	lea r11, [rbp-24]
+	mov r11, [r11]
+	lea rbx, [rbp-28]
+	movsxd rbx, DWORD PTR [rbx]
+	mov r12, 4
+	imul rbx, r12
+	add r11, rbx
+	mov [r11], r10d
+	lea r10, [rbp-28]
+	movsxd r11, DWORD PTR [r10]
+	add r11, 1
+	mov [r10], r11d
+	sub r11, 1
+	jmp .L1
+.L2:
+.L3:
+	lea r10, [rbp-16]
+	mov r11, 2
+	mov rbx, 4
+	imul r11, rbx
+	add r10, r11
+	movsxd r10, DWORD PTR [r10]
+	lea r11, [rbp-40]
+	add r11, 0
+	mov [r11], r10d
+	lea r10, [rbp-16]
+	mov r11, 3
+	mov rbx, 4
+	imul r11, rbx
+	add r10, r11
+	movsxd r10, DWORD PTR [r10]
+	lea r11, [rbp-40]
+	add r11, 8
+	mov [r11], r10d
+	lea r10, [rbp-40]
+	lea r11, [rbp-48]
+	mov [r11], r10
+	lea r10, .L.str0
+	lea r11, greeting
+	mov [r11], r10
+	lea r10, [rbp-48]
+	mov r10, [r10]
+	add r10, 0
+	movsxd r10, DWORD PTR [r10]
+	lea r11, [rbp-40]
+	add r11, 8
+	movsxd r11, DWORD PTR [r11]
+	add r10, r11
+	lea r11, greeting
+	mov r11, [r11]
+	mov rbx, 1
+	mov r12, 1
+	imul rbx, r12
+	add r11, rbx
+	movzx r11, BYTE PTR [r11]
+	add r10, r11
+	lea r11, counter
+	mov [r11], r10d
+	lea r10, counter
+	movsxd r10, DWORD PTR [r10]
+	mov rax, r10
+	jmp .Lmain.0
+.Lmain.0:
+	add rsp, 8
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
+	mov rsp, rbp
+	pop rbp
+	ret
+	.size main, .-main
+.section .note.GNU-stack,"",@progbits
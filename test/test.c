@@ -25,13 +25,419 @@ int add(int a, int b, int c, int d, int e, int f) { return a+b+c+d+e+f; }
 int add2(int (*a)[2]) { return a[0][0] + a[1][0]; }
 int add3(int a[][2]) { return a[0][0] + a[1][0]; }
 int add4(int a[2][2]) { return a[0][0] + a[1][0]; }
+
+int dense(int x) {
+  switch (x) {
+  case 0: return 10;
+  case 1: return 11;
+  case 2: return 12;
+  case 4: return 14;
+  case 5: return 15;
+  default: return -1;
+  }
+}
+
+int sparse(int x) {
+  switch (x) {
+  case -100: return 1;
+  case 3: return 2;
+  case 50: return 3;
+  case 1000: return 4;
+  case 4096: return 5;
+  }
+  return 0;
+}
+
+int fallthrough(int x) {
+  int n = 0;
+  switch (x) {
+  case 1: n += 1;
+  case 2: n += 2; break;
+  case 3: n += 4;
+  }
+  return n;
+}
 void nop() {}
 
+typedef struct { char a; char b; char c; } Chars;
+typedef struct { int a; int b; } Pair;
+typedef struct { int a; int b; int c; } Tri;
+typedef struct { int a; int b; int c; int d; int e; } Big;
+
+Chars make_chars(int a, int b, int c) { Chars x; x.a = a; x.b = b; x.c = c; return x; }
+Pair make_pair(int a, int b) { Pair p; p.a = a; p.b = b; return p; }
+Tri make_tri(int a, int b, int c) { Tri t; t.a = a; t.b = b; t.c = c; return t; }
+Big make_big(int a, int e) { Big b; b.a = a; b.b = 0; b.c = 0; b.d = 0; b.e = e; return b; }
+int sum_chars(Chars x) { return x.a + x.b + x.c; }
+int sum_tri(Tri t) { return t.a + t.b + t.c; }
+int sum_big(Big b) { return b.a + b.e; }
+int mixed(int a, Tri t, int b, Big g, Pair p) { return a + t.c + b + g.e + p.b; }
+int spill(Tri t, Tri u, Tri v, Pair p, int x) { return t.a + u.b + v.c + p.b + x; }
+
+Chars gcc_chars(int a, int b, int c);
+Tri gcc_tri(int a, int b, int c);
+Big gcc_big(int a, int e);
+int gcc_sum_tri(Tri t);
+int gcc_sum_big(Big b);
+int gcc_spill(Tri t, Tri u, Tri v, Pair p, int x);
+int gcc_call_r9cc();
+
+int sum(int n, ...) {
+  __builtin_va_list ap;
+  __builtin_va_start(ap, n);
+  int s = 0;
+  for (int i = 0; i < n; i++)
+    s += __builtin_va_arg(ap, int);
+  __builtin_va_end(ap);
+  return s;
+}
+
+int sum_twice(int n, ...) {
+  __builtin_va_list ap;
+  __builtin_va_list aq;
+  __builtin_va_start(ap, n);
+  __builtin_va_copy(aq, ap);
+  int s = 0;
+  for (int i = 0; i < n; i++)
+    s += __builtin_va_arg(ap, int);
+  for (int i = 0; i < n; i++)
+    s += __builtin_va_arg(aq, int);
+  return s;
+}
+
+int sum_structs(int n, ...) {
+  __builtin_va_list ap;
+  __builtin_va_start(ap, n);
+  int s = __builtin_va_arg(ap, Pair).b;
+  s += __builtin_va_arg(ap, Tri).c;
+  s += __builtin_va_arg(ap, int);
+  s += __builtin_va_arg(ap, Big).e;
+  return s + n;
+}
+
+// Reads a struct that doesn't fit in the registers left.
+int skip_ints(int n, ...) {
+  __builtin_va_list ap;
+  __builtin_va_start(ap, n);
+  for (int i = 0; i < n; i++)
+    __builtin_va_arg(ap, int);
+  return __builtin_va_arg(ap, Tri).c;
+}
+
+// A tiny sprintf() that understands %d and %s.
+int format(char *buf, char *fmt, ...) {
+  __builtin_va_list ap;
+  __builtin_va_start(ap, fmt);
+  int i = 0;
+  for (; *fmt; fmt++) {
+    if (*fmt != '%') {
+      buf[i++] = *fmt;
+    } else if (*++fmt == 's') {
+      for (char *s = __builtin_va_arg(ap, char *); *s; s++)
+        buf[i++] = *s;
+    } else {
+      int n = __builtin_va_arg(ap, int);
+      if (n < 0) {
+        buf[i++] = '-';
+        n = -n;
+      }
+      char digits[10];
+      int len = 0;
+      do {
+        digits[len++] = '0' + n - n / 10 * 10;
+        n = n / 10;
+      } while (n);
+      while (len)
+        buf[i++] = digits[--len];
+    }
+  }
+  buf[i] = 0;
+  return i;
+}
+
+_Atomic int counter;
+int bump(int n) {
+  for (int i = 0; i < n; i++)
+    __atomic_fetch_add(&counter, 1, __ATOMIC_SEQ_CST);
+  return __atomic_load_n(&counter, __ATOMIC_SEQ_CST);
+}
+
+_Thread_local int tls_var;
+extern _Thread_local int gcc_tls;
+int gcc_tls_in_thread();
+int tls_get() { return tls_var; }
+
+#define HERE __LINE__
+char *file() { return __FILE__; }
+int line() { return __LINE__; }
+int here() { return HERE; }
+
+Tri global_tri;
+int pointer_size() { return __SIZEOF_POINTER__; }
+int is_x86_64() { return __x86_64__; }
+
+int gcc_vsum(int n, __builtin_va_list ap);
+int gcc_call_sum();
+int sum_by_gcc(int n, ...) {
+  __builtin_va_list ap;
+  __builtin_va_start(ap, n);
+  return gcc_vsum(n, ap);
+}
+
 int var1;
 int var2[5];
 extern int global_arr[1];
 typedef int myint;
 
+long long_id(long x) { return x; }
+int long_to_int(long x) { return x; }
+long int_to_long(int x) { return x + 1; }
+
+#define MAX(a, b) ({ int _a = (a); int _b = (b); _a > _b ? _a : _b; })
+int max(int a, int b) { return MAX(a, b); }
+int max_inc(int i) { return MAX(i++, i); }
+long long_stmt_expr() { return ({ long x = 5000000000; x; }); }
+
+#define TEN 10
+#define TWENTY (TEN * 2)
+#define TWICE(x) ((x) * 2)
+#define CALL(f, x) f(x)
+#define STR(x) #x
+#define XSTR(x) STR(x)
+#define SEVEN() 7
+#define OR_ONE(x) (x 1)
+#define ADD(a, b) (a 1 b)
+int self = 3;
+#define self (self + 1)
+int ten() { return TEN; }
+#undef TEN
+#define TEN 11
+
+#ifdef TEN
+#if TEN == 11 && defined(TWICE) && !defined UNDEFINED && (UNDEFINED || 1) && ~TEN == -12
+int cond_incl() { return 1; }
+#elif 1
+int cond_incl() { return 2; }
+#endif
+#else
+#bogus directive, skipped
+int cond_incl() { return 3; }
+#endif
+#if 0
+#elif TWICE(2) == 5
+#else
+int cond_elif() { return TWICE(2); }
+#endif
+
+int register_sum(register int n, register step) {
+  register i;
+  auto int sum = 0;
+  for (register int j = 0; j < n; j++)
+    sum = sum + step;
+  i = sum;
+  { int i = 1; int *p = &i; sum = sum + *p; }
+  return i + sum;
+}
+
+typedef int (*binop)(int, int);
+typedef struct { int tag; int (*handler)(int, int); } Handler;
+int (*global_op)(int, int);
+int apply(binop op, int x, int y) { return op(x, y); }
+binop pick_op(int i) { return i ? mul : plus; }
+
+int init_grid[2][3] = {{1, 2}, {3}};
+int init_flat[] = {1, 2, 3, 4, -5,};
+char init_str[] = "hello";
+Tri init_tris[2] = {1, 2, 3, {4}};
+long init_expr = 1 + 2 * 3 - (4 << 2);
+int init_zero[4];
+int *init_null = (void *)0;
+
+_Noreturn void fail(int code) { exit(code); }
+void fail_attr(int code) __attribute__((noreturn));
+void fail_attr(int code) { fail(code); }
+int sign(int x) { if (x < 0) return -1; if (x > 0) return 1; if (x == 0) return 0; fail(2); }
+int sign_attr(int x) { if (x) return x < 0 ? -1 : 1; else return 0; fail_attr(3); }
+int classify(int x) { switch (x) { case 0: return 10; default: return 20; } }
+int spin(int x) { for (;;) if (x++ > 4) return x; }
+
+#define offsetof(type, member) __builtin_offsetof(type, member)
+typedef struct { char tag; Tri tris[3]; long n; } Layout;
+long layout_n = offsetof(Layout, n);
+
+int init_range[10] = {[2 ... 5] = 7, 1, [0] = 3};
+int init_desig[] = {[4] = 1, [1 ... 2] = 9};
+Pair init_pairs[4] = {[1 ... 2] = {1, 2}, [3] = 4, 5};
+int range_calls;
+int range_next() { return ++range_calls; }
+
+int *addr_var = &var1;
+int *addr_elem = var2 + 3;
+int *addr_member = &init_pairs[3].b;
+char *addr_str = "hi";
+binop addr_table[3] = {[0 ... 2] = plus, [1] = mul};
+Handler addr_handlers[] = {{1, plus}, {2, mul}};
+
+int alloca_sum(int n) {
+  int *a = __builtin_alloca(n * 4);
+  char *c = __builtin_alloca(1);
+  for (int i = 0; i < n; i++)
+    a[i] = plus(i, 1);
+  *c = 0;
+  int sum = 0;
+  for (int i = 0; i < n; i++)
+    sum = sum + a[i];
+  return sum + *c + ((long)a & 15) + ((long)c & 15);
+}
+
+#pragma pack(push, 1)
+typedef struct { char tag; int n; long l; } Packed;
+#pragma pack(2)
+typedef struct { char tag; int n; } Packed2;
+#pragma pack(pop)
+typedef struct { char tag; int n; } Unpacked;
+typedef struct { char c; Packed p; } HasPacked;
+Packed packed = {1, 2, 3};
+
+struct point { char tag; int x; int y; };
+struct point origin;
+
+struct node { int v; struct node *next; };
+
+int list_sum(struct node *n) {
+  int sum = 0;
+  for (; n; n = n->next)
+    sum += n->v;
+  return sum;
+}
+
+struct later;
+struct later *later_ptr;
+struct later { char c; long l; };
+
+int packed_local() {
+#pragma pack(push, 1)
+  struct { char c; long l; } s;
+#pragma pack(pop)
+  struct { char c; long l; } t;
+  return sizeof(s) * 100 + sizeof(t);
+}
+
+int expect_branches(int x) {
+  int n = 0;
+  if (__builtin_expect(x < 0, 0))
+    return -1;
+  if (__builtin_expect(x > 100, 0))
+    n = 100;
+  else
+    n = x;
+  if (__builtin_expect(x != 3, 1))
+    n++;
+  else
+    n = ({ int y = 30; if (__builtin_expect(x, 0)) y++; y; });
+  for (int i = 0; i < x; i++)
+    n += 2;
+  return n;
+}
+
+static int static_limit = 3;
+static int static_counter;
+static int static_scale(int x, int n) { return x * n; }
+static int static_count(int n) {
+  static_counter += n;
+  return static_counter;
+}
+
+// At -O2, a * b is computed before the loops that use it, unless they
+// write a or may be entered in the middle.
+int invariant_sum(int a, int b) {
+  int s = 0;
+  for (int i = 0; i < 3; i++) {
+    int k = 0;
+    do {
+      s = s + a * b + i;
+      k++;
+    } while (k < 2);
+  }
+  return s;
+}
+
+int invariant_until_written(int a) {
+  int s = 0;
+  for (int i = 0; i < 3; i++) {
+    s = s + a * 2;
+    a = 5;
+  }
+  return s;
+}
+
+int invariant_past_case(int a, int n) {
+  int s = 0;
+  int k = 0;
+  switch (n) {
+  case 0:
+    do {
+      s = s + a * 3;
+    case 1:
+      k++;
+    } while (k < 3);
+  }
+  return s;
+}
+
+// At -O2, what a statement computed is reused by the statements after
+// it, until a local it was computed from is written.
+int reused_product(int a, int b) {
+  int x = a * b + 1;
+  int y = a * b + 2;
+  if (x > 10)
+    a = a + 1;
+  int z = a * b;
+  for (int i = 0; i < 2; i++)
+    b = b + a * b;
+  return x + y + z + b + a / b + a / b;
+}
+
+int reused_in_branches(int a, int n) {
+  int s = 0;
+  if (n)
+    s = a << n;
+  else
+    a = 3;
+  s = s + (a << n);
+  int *p = &n;
+  *p = 1;
+  return s + (a << n);
+}
+
+long reused_long(long a, int n) {
+  long s = 0;
+  do {
+    s = s + (a >> 1) * n;
+    n = n - 1;
+  } while (n > 0);
+  return s + (a >> 1) * 2 + ((a >> 1) && n);
+}
+
+// A float is rounded to what a float holds after every operation, and
+// floating arguments and return values go in xmm registers.
+double scale(double x, int n, float f) { return x * n * f; }
+float to_float(double x) { return x; }
+double dsum(int n, ...) {
+  __builtin_va_list ap;
+  __builtin_va_start(ap, n);
+  double s = 0;
+  for (int i = 0; i < n; i++)
+    s += __builtin_va_arg(ap, double);
+  return s;
+}
+
+double gcc_mix(int a, float b, double c, long d);
+float gcc_half(float x);
+int gcc_call_float();
+double halves[3] = {0.5, 1.5, -2.5};
+float tenth = 0.1f;
+
 // Single-line comment test
 
 /***************************
@@ -74,6 +480,13 @@ int main() {
   EXPECT(0, 1 && 0);
   EXPECT(0, 0 && 1);
   EXPECT(1, 1 && 1);
+  EXPECT(1, ({ long x = 1L << 32; int y = 3; return x && y; }));
+  EXPECT(1, ({ long x = 1L << 32; int y = 0; return y || x; }));
+  EXPECT(12, ({ int x = 5; int y = 0; return (x && 7) + (y || -3) * 11; }));
+  EXPECT(0, ({ int n = 0; int x = n && (n = 2); return x + n; }));
+  EXPECT(10, ({ int n = 1; int x = n || (n = 5); return x * 10 + n - 1; }));
+  EXPECT(21, ({ int n = 0; int x = (n = 2) && (n = 0) || (n = 3); return x * 20 + n - 2; }));
+  EXPECT(0, ({ char *p = 0; return p && *p; }));
 
   EXPECT(0, 0 < 0);
   EXPECT(0, 1 < 0);
@@ -105,6 +518,16 @@ int main() {
 
   EXPECT(0, !1);
   EXPECT(1, !0);
+  EXPECT(1, !!7);
+  EXPECT(1, ({ int x = 0; return !x; }));
+  EXPECT(0, ({ int x = 0; int *p = &x; return !p; }));
+  EXPECT(3, +3);
+  EXPECT(-3, +-3);
+  EXPECT(8, - -8);
+  EXPECT(4, ({ int x = 5; return +x - 1; }));
+  EXPECT(-5, ({ int x = 5; return -x; }));
+  EXPECT(1, ({ char c = 1; return sizeof(+c) == 4; }));
+  EXPECT(2, ({ int a[2] = {1, 2}; int *p = a; return *+p + +*p; }));
 
   EXPECT(3, ({ int i = 3; return i++; }));
   EXPECT(4, ({ int i = 3; return ++i; }));
@@ -121,6 +544,13 @@ int main() {
   EXPECT(5, 6 ^ 3);
   EXPECT(2, 6 & 3);
   EXPECT(0, 6 & 0);
+  EXPECT(-1, ~0);
+  EXPECT(-6, ~5);
+  EXPECT(5, ~~5);
+  EXPECT(2, ({ int x = 5; return ~x & 6; }));
+  EXPECT(1, ({ unsigned x = 0; return ~x == 4294967295L; }));
+  EXPECT(1, ({ char c = 0; return sizeof(~c) == 4; }));
+  EXPECT(1, ({ long x = 0; return ~x >> 32 == -1; }));
 
   EXPECT(45, ({ int x=0; int y=0; do { y=y+x; x=x+1; } while (x < 10); return y; }));
 
@@ -132,6 +562,14 @@ int main() {
   EXPECT(1, ({ int i=1; for (int i = 5; i < 10; i++); return i; }));
   EXPECT(5, ({ int i=0; for (0; i < 10; i++) if (i==5) break; return i; }));
   EXPECT(10, ({ int i=0; for (;;) { i++; if (i==10) break; } return i; }));
+  EXPECT(42, invariant_sum(2, 3));
+  EXPECT(22, invariant_until_written(1));
+  EXPECT(12, invariant_past_case(2, 1));
+  EXPECT(48, reused_product(2, 3));
+  EXPECT(312, reused_product(7, 3));
+  EXPECT(50, reused_in_branches(5, 2));
+  EXPECT(9, reused_in_branches(5, 0));
+  EXPECT(32, reused_long(9, 3));
 
   EXPECT(45, ({ int i=0; int j=0; while (i<10) { j=j+i; i=i+1; } return j;}));
 
@@ -203,6 +641,32 @@ int main() {
 	return x.a[0].b + x.a[0].c[1];
       }));
 
+  EXPECT(12, sizeof(struct point));
+  EXPECT(4, _Alignof(struct point));
+  EXPECT(24, sizeof(struct point[2]));
+  EXPECT(7, ({ origin.x = 3; origin.y = 4; return origin.x + origin.y; }));
+  EXPECT(9, ({ struct point p; p.tag = 2; p.y = 7; return p.tag + p.y; }));
+  EXPECT(8, ({ struct node a; struct node b; a.v = 2; a.next = &b; b.v = 5; b.next = 0; a.next->v += 1; return list_sum(&a); }));
+  EXPECT(7, ({ struct node a; struct node b; a.next = &b; b.next = &a; b.v = 7; return a.next->next->next->v; }));
+  EXPECT(16, sizeof(*later_ptr));
+  EXPECT(16, ({ struct later x[2]; later_ptr = x; x[1].l = 16; return (later_ptr + 1)->l; }));
+  EXPECT(8, ({ struct pair { char a; long b; }; struct pair q; q.b = 8; return sizeof(q) == 16 ? q.b : 0; }));
+
+  EXPECT(1, cond_incl());
+  EXPECT(4, cond_elif());
+  EXPECT(10, ten());
+  EXPECT(22, TWENTY);
+  EXPECT(44, TWICE(TWENTY));
+  EXPECT(7, SEVEN());
+  EXPECT(1, OR_ONE());
+  EXPECT(3, ADD(, + 2));
+  EXPECT(3, ADD(2 +, ));
+  EXPECT(88, TWICE(TWICE(TWENTY)));
+  EXPECT(44, CALL(TWICE, TWENTY));
+  EXPECT(4, self);
+  EXPECT(4, sizeof(STR(TEN)));
+  EXPECT(3, sizeof(XSTR(TEN)));
+
   EXPECT(3, ({ typedef int foo; foo x = 3; return x; }));
   EXPECT(4, ({ myint foo = 3; return sizeof(foo); }));
 
@@ -218,9 +682,324 @@ int main() {
   EXPECT(1, ({ int i=5; i&=3; return i; }));
   EXPECT(6, ({ int i=5; i^=3; return i; }));
   EXPECT(7, ({ int i=5; i|=3; return i; }));
+  EXPECT(8, ({ int i=5; return i+=3; }));
+  EXPECT(14, ({ int i=10; int j = (i-=3) * 2; return i + j - 7; }));
+  EXPECT(11, ({ int a[2]={1,2}; int i=0; a[i++] += 10; return a[0] + a[1] - 2 + i - 1; }));
+  EXPECT(1, ({ int a[2]={1,2}; int i=0; a[i++] += 10; return i; }));
+  EXPECT(443, ({ int a[3]={1,2,3}; int *p=a; *p++ *= 4; *p++ <<= 1; return a[0] * 100 + a[1] * 10 + *p; }));
+  EXPECT(3, ({ int a[3]={1,2,3}; int *p=a; p += 2; return *p; }));
+  EXPECT(2, ({ int a[3]={1,2,3}; int *p=a+2; p -= 1; return *p; }));
+  EXPECT(44, ({ char c=200; c+=100; return c; }));
 
   EXPECT(11, ({ int x[3] = {10, 11, 12}; return x[1]; }));
 
+  EXPECT(3, ({ asm("nop"); return 3; }));
+  EXPECT(5, ({ int x = 5; __asm__ volatile ("nop\n\tnop"); return x; }));
+
+  EXPECT(3, ({ char a[4]; char b[4]; b[0]=1; b[1]=2; b[2]=3; b[3]=4; __builtin_memcpy(a, b, 4); return a[2]; }));
+  EXPECT(7, ({ char a[4]; __builtin_memset(a, 7, 4); return a[3]; }));
+  EXPECT(1, ({ char a[4]; return __builtin_memset(a, 0, 4) == a; }));
+  EXPECT(5, __builtin_expect(5, 1));
+
+  EXPECT(10, dense(0));
+  EXPECT(12, dense(2));
+  EXPECT(-1, dense(3));
+  EXPECT(15, dense(5));
+  EXPECT(-1, dense(6));
+  EXPECT(-1, dense(-1));
+  EXPECT(1, sparse(-100));
+  EXPECT(2, sparse(3));
+  EXPECT(4, sparse(1000));
+  EXPECT(5, sparse(4096));
+  EXPECT(0, sparse(4));
+  EXPECT(3, fallthrough(1));
+  EXPECT(2, fallthrough(2));
+  EXPECT(4, fallthrough(3));
+  EXPECT(0, fallthrough(4));
+  EXPECT(6, ({ int n = 0; for (int i = 0; i < 3; i++) switch (i) { case 0: n += 1; break; default: n += 2; } return n + 1; }));
+  EXPECT(3, ({ int n = 0; switch (16) { case 1 << 4: n = 3; break; case 'a' + 1: n = 4; } return n; }));
+  EXPECT(4, ({ int n = 0; switch ('b') { case 1 << 4: n = 3; break; case 'a' + 1: n = 4; } return n; }));
+  EXPECT(5, ({ int n = 0; switch (TEN * 2) { case TEN: n = 1; break; case TEN * 2: n = 5; } return n; }));
+  EXPECT(2, ({ long x = 0x100000001; int n = 0; switch (x) { case 1: n = 1; break; case 0x100000001: n = 2; } return n; }));
+  EXPECT(1, ({ long x = 1; int n = 0; switch (x) { case 1: n = 1; break; case 0x100000001: n = 2; } return n; }));
+  EXPECT(4, ({ unsigned x = -1; int n = 0; switch (x) { case 0: case 1: case 2: n = 1; break; case -1: n = 4; } return n; }));
+
+  EXPECT(3, make_chars(1, 2, 3).c);
+  EXPECT(4, make_pair(3, 4).b);
+  EXPECT(7, make_tri(5, 6, 7).c);
+  EXPECT(9, make_big(8, 9).e);
+  EXPECT(6, sum_chars(make_chars(1, 2, 3)));
+  EXPECT(18, sum_tri(make_tri(5, 6, 7)));
+  EXPECT(17, sum_big(make_big(8, 9)));
+  EXPECT(25, ({ Tri t; t.a = 1; t.b = 2; t.c = 3; Big g; g.e = 10; Pair p; p.b = 5; return mixed(4, t, 3, g, p); }));
+  EXPECT(3, ({ Tri t; t.a = 1; t.b = 2; t.c = 3; sum_tri(t); return t.c; }));
+  EXPECT(20, spill(make_tri(1, 0, 0), make_tri(0, 2, 0), make_tri(0, 0, 3), make_pair(0, 4), 10));
+  EXPECT(7, make_tri(1, 2, 3).a + make_tri(4, 5, 6).a + make_chars(1, 1, 2).a + make_big(1, 0).a);
+
+  EXPECT(3, gcc_chars(1, 2, 3).c);
+  EXPECT(7, gcc_tri(5, 6, 7).c);
+  EXPECT(9, gcc_big(8, 9).e);
+  EXPECT(18, gcc_sum_tri(make_tri(5, 6, 7)));
+  EXPECT(17, gcc_sum_big(make_big(8, 9)));
+  EXPECT(20, gcc_spill(make_tri(1, 0, 0), make_tri(0, 2, 0), make_tri(0, 0, 3), make_pair(0, 4), 10));
+  EXPECT(1, gcc_call_r9cc());
+
+  EXPECT(0, sum(0));
+  EXPECT(6, sum(3, 1, 2, 3));
+  EXPECT(15, sum(5, 1, 2, 3, 4, 5));
+  EXPECT(12, sum_twice(3, 1, 2, 3));
+  EXPECT(30, sum_twice(5, 1, 2, 3, 4, 5));
+  EXPECT(24, ({ Pair p; p.b = 2; Tri t; t.c = 3; Big g; g.e = 14; return sum_structs(1, p, t, 4, g); }));
+  EXPECT(3, ({ Tri t; t.c = 3; return skip_ints(4, 1, 2, 3, 4, t); }));
+  EXPECT(9, ({ char buf[20]; return format(buf, "a%db%sc%d", 42, "xy", -7); }));
+  EXPECT(120, ({ char buf[20]; format(buf, "a%db%sc%d", 42, "xy", -7); return buf[4]; }));
+  EXPECT(45, ({ char buf[20]; format(buf, "a%db%sc%d", 42, "xy", -7); return buf[7]; }));
+  EXPECT(48, ({ char buf[20]; format(buf, "%d", 0); return buf[0]; }));
+  EXPECT(6, sum_by_gcc(3, 1, 2, 3));
+  EXPECT(15, sum_by_gcc(5, 1, 2, 3, 4, 5));
+  EXPECT(1, gcc_call_sum());
+
+  EXPECT(4, ({ _Atomic int x; return sizeof(x); }));
+  EXPECT(8, ({ _Atomic(int *) p; return sizeof(p); }));
+  EXPECT(3, ({ _Atomic int x = 1; __atomic_fetch_add(&x, 2, 5); return x; }));
+  EXPECT(1, ({ int x = 1; return __atomic_fetch_add(&x, 2, 0); }));
+  EXPECT(-1, ({ int x = -1; return __atomic_fetch_add(&x, -2, 0); }));
+  EXPECT(7, ({ int x; __atomic_store_n(&x, 7, 3); return __atomic_load_n(&x, 2); }));
+  EXPECT(5, ({ int x = 5; int y; __atomic_load(&x, &y, 5); return y; }));
+  EXPECT(6, ({ int x; int y = 6; __atomic_store(&x, &y, 5); return x; }));
+  EXPECT(1, ({ int a[2]; int *p = a; __atomic_fetch_add(&p, 4, 5); return p == a + 1; }));
+  EXPECT(2, ({ _Atomic int x = 1; return ++x; }));
+  EXPECT(1, ({ _Atomic int x = 1; return x++; }));
+  EXPECT(0, ({ _Atomic int x = 1; x--; return x; }));
+  EXPECT(8, ({ _Atomic long x = 5; return x += 3; }));
+  EXPECT(3, ({ _Atomic int x = 5; x -= 2; return x; }));
+  EXPECT(0, ({ _Atomic unsigned x = 0; x--; return x < 4294967295u; }));
+  EXPECT(1, ({ int a[3]; _Atomic(int *) p = a; p++; p += 1; return p == a + 2; }));
+  EXPECT(10, bump(10));
+  EXPECT(15, bump(5));
+  EXPECT(0, tls_var);
+  EXPECT(7, ({ tls_var = 7; return tls_get(); }));
+  EXPECT(3, gcc_tls);
+  EXPECT(0, gcc_tls_in_thread());
+  EXPECT(7, tls_var);
+  EXPECT(4, ({ gcc_tls = 4; return gcc_tls; }));
+
+  EXPECT(172, line());
+  EXPECT(173, here());
+  EXPECT(116, file()[0]);
+  EXPECT(99, file()[10]);
+  EXPECT(0, file()[11]);
+  EXPECT(8, pointer_size());
+  EXPECT(1, is_x86_64());
+
+  EXPECT(8, ({ long x; return sizeof(x); }));
+  EXPECT(8, ({ long long int x; return sizeof(x); }));
+  EXPECT(4, sizeof(2147483647));
+  EXPECT(8, sizeof(2147483648));
+  EXPECT(8, sizeof(1L));
+  EXPECT(8, sizeof(1ll));
+  EXPECT(8, sizeof(1 + 1L));
+  EXPECT(4, sizeof(1L < 2L));
+  EXPECT(1, 5000000000 > 4000000000);
+  EXPECT(0, 5000000000 == 705032704);
+  EXPECT(1, -1L < 1);
+  EXPECT(1, long_id(5000000000) == 5000000000);
+  EXPECT(2, long_id(5000000000) / 2500000000);
+  EXPECT(3, ({ long long x = 0x300000000; return x >> 32; }));
+  EXPECT(1, ({ long x = 1; return (x << 40) == 1099511627776; }));
+  EXPECT(1, ({ long x = 3000000000; return x * 3 - 9000000000 == 0; }));
+  EXPECT(705032704, long_to_int(5000000000));
+  EXPECT(705032704, ({ int x = 5000000000; return x; }));
+  EXPECT(1, int_to_long(-2) == -1L);
+  EXPECT(1, ({ int i = -5; long l = i; return l < 0; }));
+  EXPECT(1, ({ int i = -1; long l = 4294967296; return l + i == 4294967295; }));
+
+  EXPECT(-3, -7 / 2);
+  EXPECT(-1, -7 % 2);
+  EXPECT(-1, ({ int a = -7; int b = 3; a % b; }));
+  EXPECT(1, ({ int a = 7; int b = -3; a % b; }));
+  EXPECT(7, ({ long a = 10000000007; a % 1000; }));
+  EXPECT(-7, ({ long a = -10000000007; long b = 1000; a % b; }));
+  EXPECT(3, ({ unsigned a = 4000000000; unsigned b = 7; a % b; }));
+  EXPECT(47, ({ int n = 0; for (int i = 1; i <= 100; i++) if (i % 3 == 0 || i % 5 == 0) n++; n; }));
+  EXPECT(-2, -8 >> 2);
+  EXPECT(4, sizeof(1u));
+  EXPECT(8, sizeof(1ul));
+  EXPECT(8, sizeof(1lu));
+  EXPECT(4, sizeof(0xffffffff));
+  EXPECT(8, sizeof(4294967295));
+  EXPECT(0, -1 < 1u);
+  EXPECT(1, -1 > 1u);
+  EXPECT(1, 0xffffffff > 0);
+  EXPECT(1, -1L < 1u);
+  EXPECT(0, -1L < 1ul);
+  EXPECT(1, ({ unsigned x = 0; return x - 1 == 4294967295L; }));
+  EXPECT(1, ({ unsigned int x = 4294967295; return x + 1 == 0; }));
+  EXPECT(2147483647, ({ unsigned x = -2; return x / 2; }));
+  EXPECT(15, ({ int x = -7; return (unsigned)x >> 28; }));
+  EXPECT(2147483644, ({ int x = -7; return x / 2u; }));
+  EXPECT(1, ({ int x = -7; return (unsigned)x == 4294967289L; }));
+  EXPECT(1, ({ unsigned x = 4294967295; return (int)x == -1L; }));
+  EXPECT(15, (unsigned)-7 >> 28);
+  EXPECT(3, ({ unsigned x = -1; return x % 7; }));
+  EXPECT(1073741823, ({ unsigned x = -1; return x >> 2; }));
+  EXPECT(-1, ({ int x = -1; return x >> 2; }));
+  EXPECT(1, ({ unsigned long x = -1; return x >> 63; }));
+  EXPECT(1, ({ unsigned long x = -1; return x / 3 == 6148914691236517205; }));
+  EXPECT(1, ({ unsigned x = 3; unsigned y = 5; return x - y > 0; }));
+  EXPECT(1, ({ unsigned x = 2; return x <= 3 && !(x >= 3); }));
+  EXPECT(0, ({ unsigned x = 1; int y = -1; return x < y == 0; }));
+  EXPECT(-1, ({ signed char c = 255; return c; }));
+  EXPECT(255, ({ char c = 255; return c; }));
+  EXPECT(-128, ({ signed char c = 127; c++; return c; }));
+  EXPECT(1, ({ signed x = -1; return x < 0; }));
+
+  EXPECT(25, register_sum(4, 3));
+  EXPECT(3, ({ register int x = 3; return x; }));
+  EXPECT(7, ({ auto x = 7; return x; }));
+
+  EXPECT(5, max(3, 5));
+  EXPECT(5, max(5, -3));
+  EXPECT(4, max_inc(3));
+  EXPECT(1, long_stmt_expr() == 5000000000);
+  EXPECT(2, ({ 1; 2; }));
+  EXPECT(10, ({ 1; 2; }) + ({ int z = 4; z * 2; }));
+  EXPECT(8, sizeof(({ long y = 1; y; })));
+  EXPECT(1, sizeof(({ char c = 1; c; })));
+  EXPECT(1, ({ long x = 5000000000; x; }) == 5000000000);
+  EXPECT(99, ({ "abc"; })[2]);
+  EXPECT(3, ({ int x = 1; x = 3; }));
+  EXPECT(6, ({ int s = 0; for (int i = 0; i < 4; i++) s = s + i; s; }));
+
+  EXPECT(5, ({ Pair p; Pair q; p.a = 2; p.b = 3; q = p; return q.a + q.b; }));
+  EXPECT(3, ({ Tri t = make_tri(1, 2, 3); Tri u; u = t; t.c = 9; return u.c; }));
+  EXPECT(14, ({ Big b = make_big(4, 14); Big c = b; return c.e; }));
+  EXPECT(6, ({ Chars x = make_chars(1, 2, 3); Chars y; y = x; return sum_chars(y); }));
+  EXPECT(8, ({ Pair p; Pair q; Pair r; p.a = 4; p.b = 4; r = q = p; return r.a + r.b; }));
+  EXPECT(6, ({ global_tri = make_tri(1, 2, 3); return sum_tri(global_tri); }));
+  EXPECT(7, ({ Tri t; Tri *p = &t; *p = make_tri(7, 0, 0); return t.a; }));
+  EXPECT(9, ({ Big b; b = make_big(4, 5); Big a[2]; a[1] = b; return a[1].a + a[1].e; }));
+
+  EXPECT(7, ({ int (*fp)(int, int) = plus; fp(3, 4); }));
+  EXPECT(12, ({ int (*fp)(int, int) = &mul; (*fp)(3, 4); }));
+  EXPECT(10, ({ binop ops[2]; ops[0] = plus; ops[1] = mul; ops[0](1, 2) + ops[1](1, 7); }));
+  EXPECT(15, ({ Handler h; h.handler = mul; Handler *p = &h; h.handler(3, 2) + p->handler(3, 3); }));
+  EXPECT(20, pick_op(1)(4, 5));
+  EXPECT(9, apply(plus, 4, 5));
+  EXPECT(5, ({ global_op = plus; global_op(2, 3); }));
+  EXPECT(4, ({ Tri (*mk)(int, int, int) = make_tri; mk(1, 2, 3).c + mk(3, 2, 1).c; }));
+  EXPECT(1, ({ binop fp = plus; fp == plus; }));
+
+  EXPECT(29, ({ int a[2][3] = {{1, 2}, {3}}; a[0][0] + a[0][1] * 2 + a[0][2] * 4 + a[1][0] * 8 + a[1][1] + a[1][2]; }));
+  EXPECT(10, ({ int a[2][2] = {1, 2, 3}; a[0][0] + a[0][1] * 3 + a[1][0] + a[1][1]; }));
+  EXPECT(24, ({ int a[] = {1, 2, 3,}; sizeof(a) * 2; }));
+  EXPECT(4, ({ char s[] = "abc"; sizeof(s); }));
+  EXPECT(99, ({ char s[] = "abc"; s[2]; }));
+  EXPECT(0, ({ char s[8] = "xy"; s[2] + s[7]; }));
+  EXPECT(7, ({ Tri t[2] = {{1, 2}, 4}; t[0].a + t[0].b + t[0].c + t[1].a + t[1].b + t[1].c; }));
+  EXPECT(6, ({ Tri t = make_tri(1, 2, 3); Tri u[2] = {t, {0}}; u[0].a + u[0].b + u[0].c + u[1].c; }));
+  EXPECT(1, ({ int big[40] = {1}; big[0] + big[20] + big[39]; }));
+  EXPECT(7, ({ int x = {7}; x; }));
+  EXPECT(3, init_grid[1][0] + init_grid[1][2]);
+  EXPECT(20, sizeof(init_flat));
+  EXPECT(-5, init_flat[4]);
+  EXPECT(6, sizeof(init_str));
+  EXPECT(108, init_str[3]);
+  EXPECT(8, init_tris[0].a + init_tris[0].b + init_tris[0].c + init_tris[1].a - 2 + init_tris[1].c);
+  EXPECT(-9, init_expr);
+  EXPECT(0, init_zero[3]);
+
+  EXPECT(1, ({ int *p = 0; p == 0; }));
+  EXPECT(1, ({ int *p = (void *)0; !p; }));
+  EXPECT(1, init_null == 0);
+  EXPECT(8, ({ char *s = "abc"; sizeof(1 ? s : 0); }));
+  EXPECT(0, ({ int x; &x == 0; }));
+  EXPECT(1, ({ int x; 0 != &x; }));
+  EXPECT(44, (char)300);
+  EXPECT(200, (int)(unsigned char)200);
+  EXPECT(1, (unsigned char)-1 == 255);
+  EXPECT(-1, (int)4294967295);
+  EXPECT(1, (unsigned)-1 > 0);
+  EXPECT(7, ({ int x = 7; long l = (long)&x; *(int *)l; }));
+  EXPECT(-1, sign(-5));
+  EXPECT(0, sign(0));
+  EXPECT(1, sign_attr(9));
+  EXPECT(10, classify(0));
+  EXPECT(20, classify(3));
+  EXPECT(6, spin(0));
+  EXPECT(0, __builtin_offsetof(Tri, a));
+  EXPECT(8, __builtin_offsetof(Tri, c));
+  EXPECT(4, __builtin_offsetof(Layout, tris));
+  EXPECT(32, __builtin_offsetof(Layout, tris[2].b));
+  EXPECT(28, __builtin_offsetof(Layout, tris[1 + 1]));
+  EXPECT(40, layout_n);
+  EXPECT(8, sizeof(__builtin_offsetof(Layout, n)));
+  EXPECT(55, alloca_sum(10));
+  EXPECT(5050, ({ int t = 0; for (int i = 1; i <= 100; i++) t = t + alloca_sum(i) - alloca_sum(i - 1); t; }));
+  EXPECT(8, sizeof(__builtin_alloca(3)));
+  EXPECT(3, init_range[0]);
+  EXPECT(7, init_range[5]);
+  EXPECT(1, init_range[6]);
+  EXPECT(0, init_range[7]);
+  EXPECT(20, sizeof(init_desig));
+  EXPECT(9, init_desig[2]);
+  EXPECT(1, init_desig[4]);
+  EXPECT(0, init_pairs[0].b);
+  EXPECT(2, init_pairs[2].b);
+  EXPECT(5, init_pairs[3].b);
+  EXPECT(9, ({ var1 = 9; *addr_var; }));
+  EXPECT(4, ({ var2[3] = 4; *addr_elem; }));
+  EXPECT(5, *addr_member);
+  EXPECT(105, addr_str[1]);
+  EXPECT(5, addr_table[0](2, 3));
+  EXPECT(6, addr_table[1](2, 3));
+  EXPECT(5, addr_table[2](2, 3));
+  EXPECT(12, addr_handlers[1].handler(3, 4) * addr_handlers[0].tag);
+  EXPECT(7, ({ int a[8] = {[1 ... 6] = 3, [3] = 1}; a[1] + a[3] + a[6] + a[7]; }));
+  EXPECT(5, ({ int a[2][2] = {1, [1] = 3, 4}; a[0][0] + a[1][1]; }));
+  EXPECT(1, ({ int a[30] = {[0 ... 29] = range_next()}; a[29] + range_calls - 1; }));
+  EXPECT(13, sizeof(packed));
+  EXPECT(1, __builtin_offsetof(Packed, n));
+  EXPECT(5, __builtin_offsetof(Packed, l));
+  EXPECT(3, packed.l);
+  EXPECT(253, ({ Packed p; p.n = 258; p.l = -5; p.n + p.l; }));
+  EXPECT(6, ({ Packed2 p; sizeof(p); }));
+  EXPECT(8, ({ Unpacked u; sizeof(u); }));
+  EXPECT(1, __builtin_offsetof(HasPacked, p));
+  EXPECT(14, ({ HasPacked h; sizeof(h); }));
+  EXPECT(916, packed_local());
+  EXPECT(-1, expect_branches(-5));
+  EXPECT(1, expect_branches(0));
+  EXPECT(37, expect_branches(3));
+  EXPECT(151, expect_branches(50));
+  EXPECT(301, expect_branches(100));
+  EXPECT(523, expect_branches(211));
+  EXPECT(12, static_scale(3, 4));
+  EXPECT(20, static_scale(5, 4));
+  EXPECT(3, static_limit);
+  EXPECT(2, static_count(2));
+  EXPECT(4, static_count(2));
+  EXPECT(12, 0x1.8p3);
+  EXPECT(8, sizeof(long double));
+  EXPECT(4, sizeof(1.5f));
+  EXPECT(-2, -2.7);
+  EXPECT(1, 0.1 + 0.2 != 0.3);
+  EXPECT(1, 0.1f + 0.2f == 0.3f);
+  EXPECT(0, ({ float f = 0; for (int i = 0; i < 10; i++) f += 0.1f; f == 1; }));
+  EXPECT(7, ({ int i = 5; i *= 1.5; i; }));
+  EXPECT(3, ({ double d = 1.5; d++; d + 0.5; }));
+  EXPECT(1, ({ double d = 1.5; d-- == 1.5 && d == 0.5; }));
+  EXPECT(1, (unsigned long)1e19 == 10000000000000000000UL);
+  EXPECT(1, (double)18446744073709551615UL == 18446744073709551616.0);
+  EXPECT(1, ({ double nan = 0.0 / 0; nan != nan && !(nan == nan) && !(nan < 1); }));
+  EXPECT(225, scale(1.5, 3, 0.5f) * 100);
+  EXPECT(1, to_float(0.1) == tenth);
+  EXPECT(375, dsum(3, 1.0, 2.5, 0.25) * 100);
+  EXPECT(-5, halves[2] * 2);
+  EXPECT(4, gcc_mix(1, 2.5f, 2, 2));
+  EXPECT(1, gcc_half(3) == 1.5);
+  EXPECT(1, gcc_call_float());
+
   printf("OK\n");
   return 0;
  }
@@ -0,0 +1,29 @@
+// Each argument trips a different -fsanitize check; see the Makefile.
+
+int id(int x) { return x; }
+
+int main(int argc, char **argv) {
+  int n = argv[1][0] - 48;
+  int max = id(2147483647);
+  int min = -max - 1;
+  if (n == 1)
+    return max + 1;
+  if (n == 2)
+    return min - 1;
+  if (n == 3)
+    return max * 2;
+  if (n == 4)
+    return 1 / id(0);
+  if (n == 5)
+    return min / id(-1);
+  if (n == 6)
+    return 1 << id(32);
+  if (n == 7)
+    return -min;
+  if (n == 8) {
+    int y = 65536;
+    y *= y;
+    return y;
+  }
+  return 0;
+}
@@ -0,0 +1,84 @@
+// Printing a program with --dump-ast and parsing the output must give
+// the tree the program parsed to: pretty.rs has to put back the
+// parentheses and braces that the tree needs, and keep the operators
+// as they were written.
+
+use proptest::prelude::*;
+use r9cc::frontend::{analyze, Node, Phase};
+use r9cc::{dump_ast, CompileError, Options};
+
+const BINOPS: &[&str] = &[
+    "+", "-", "*", "/", "%", "<<", ">>", "<", ">", "<=", ">=", "==", "!=", "&", "^", "|", "&&",
+    "||", ",",
+];
+
+// Expressions of the parameters a, b and c, parenthesized here and
+// there, so that operators of every precedence nest in each other.
+fn expr() -> impl Strategy<Value = String> {
+    let leaf = prop_oneof![
+        (0..100i64).prop_map(|n| n.to_string()),
+        prop::sample::select(&["a", "b", "c"][..]).prop_map(String::from),
+    ];
+    leaf.prop_recursive(6, 48, 3, |inner| {
+        prop_oneof![
+            (inner.clone(), prop::sample::select(BINOPS), inner.clone())
+                .prop_map(|(l, op, r)| format!("{} {} {}", l, op, r)),
+            (
+                prop::sample::select(&["-", "!", "~", "+"][..]),
+                inner.clone()
+            )
+                .prop_map(|(op, e)| format!("{}{}", op, e)),
+            (inner.clone(), inner.clone(), inner.clone())
+                .prop_map(|(c, t, e)| format!("{} ? {} : {}", c, t, e)),
+            inner.prop_map(|e| format!("({})", e)),
+        ]
+    })
+}
+
+// Statements, including an else that could be taken for an inner if's.
+fn stmt() -> impl Strategy<Value = String> {
+    let leaf = prop_oneof![
+        expr().prop_map(|e| format!("return {};", e)),
+        expr().prop_map(|e| format!("{};", e)),
+    ];
+    leaf.prop_recursive(4, 16, 2, |inner| {
+        prop_oneof![
+            (expr(), inner.clone()).prop_map(|(c, s)| format!("if ({}) {}", c, s)),
+            (expr(), inner.clone(), inner.clone())
+                .prop_map(|(c, t, e)| format!("if ({}) {} else {}", c, t, e)),
+            (expr(), inner.clone()).prop_map(|(c, s)| format!("while ({}) {}", c, s)),
+            prop::collection::vec(inner, 0..3).prop_map(|s| format!("{{ {} }}", s.join(" "))),
+        ]
+    })
+}
+
+fn forget_positions(node: &mut Node) {
+    node.span = None;
+    node.pos = None;
+    for child in node.children_mut() {
+        forget_positions(child);
+    }
+}
+
+// The tree `source` parses to, without where its nodes are, which
+// printing moves.
+fn tree(source: &str) -> Result<String, CompileError> {
+    analyze(source, &Options::default(), Phase::Parse, |a| {
+        let mut ast = a.ast.clone();
+        ast.iter_mut().for_each(forget_positions);
+        format!("{:?}", ast)
+    })
+}
+
+proptest! {
+    #[test]
+    fn printed_program_parses_the_same(body in stmt()) {
+        let source = format!("int f(int a, int b, int c) {{ {} return 0; }}\n", body);
+        // Some programs, such as `--1`, aren't C.
+        let parsed = tree(&source);
+        prop_assume!(parsed.is_ok());
+        let printed = dump_ast(&source, &Options::default()).unwrap();
+        let reparsed = tree(&printed).map_err(|e| TestCaseError::fail(format!("{}\n{}", e, printed)))?;
+        prop_assert_eq!(parsed.unwrap(), reparsed, "printed as:\n{}", printed);
+    }
+}